@@ -0,0 +1,137 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::time::Instant;
+
+use clap::Parser;
+use clap::ValueEnum;
+use crabml::backends::cpu::quantize::quantize_gguf_with_options;
+use crabml::backends::cpu::quantize::QuantizeOptions;
+use crabml::backends::cpu::ImportanceMatrix;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::gguf::GGUFFileLoader;
+
+/// Requantize the weights of a GGUF model into another type, like F16 or Q8_0 into Q4_0
+#[derive(Parser, Debug)]
+struct CommandArgs {
+    /// The GGUF file to read
+    input: String,
+
+    /// The GGUF file to write
+    output: String,
+
+    /// The type of the 2d weights, the 1d tensors like the norms are kept
+    #[arg(short = 't', long = "type", default_value_t = QuantizeType::Q4_0)]
+    typ: QuantizeType,
+
+    /// Mix the types by the sensitivity of the tensors like the _M types of llama.cpp: output
+    /// and token_embd in q6_k or q8_0, attn_v and some of the ffn_down layers one level higher
+    #[arg(long, default_value_t = false)]
+    mixed: bool,
+
+    /// An importance matrix file of llama.cpp, which picks the ffn_down layers taking more
+    /// bits in the --mixed types
+    #[arg(long)]
+    imatrix: Option<String>,
+}
+
+#[derive(Clone, Debug, ValueEnum)]
+enum QuantizeType {
+    F32,
+    F16,
+    #[value(name = "q8_0")]
+    Q8_0,
+    #[value(name = "q4_0")]
+    Q4_0,
+    #[value(name = "q4_1")]
+    Q4_1,
+    #[value(name = "q5_0")]
+    Q5_0,
+    #[value(name = "q5_1")]
+    Q5_1,
+    #[value(name = "q2_k")]
+    Q2K,
+    #[value(name = "q3_k")]
+    Q3K,
+    #[value(name = "q4_k")]
+    Q4K,
+    #[value(name = "q5_k")]
+    Q5K,
+    #[value(name = "q6_k")]
+    Q6K,
+}
+
+impl std::fmt::Display for QuantizeType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+impl From<QuantizeType> for GGMLType {
+    fn from(t: QuantizeType) -> Self {
+        match t {
+            QuantizeType::F32 => GGMLType::F32,
+            QuantizeType::F16 => GGMLType::F16,
+            QuantizeType::Q8_0 => GGMLType::Q8_0,
+            QuantizeType::Q4_0 => GGMLType::Q4_0,
+            QuantizeType::Q4_1 => GGMLType::Q4_1,
+            QuantizeType::Q5_0 => GGMLType::Q5_0,
+            QuantizeType::Q5_1 => GGMLType::Q5_1,
+            QuantizeType::Q2K => GGMLType::Q2K,
+            QuantizeType::Q3K => GGMLType::Q3K,
+            QuantizeType::Q4K => GGMLType::Q4K,
+            QuantizeType::Q5K => GGMLType::Q5K,
+            QuantizeType::Q6K => GGMLType::Q6K,
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let args = CommandArgs::parse();
+    let start_time = Instant::now();
+
+    let mut options = QuantizeOptions::new(args.typ.into()).with_mixed(args.mixed);
+    if let Some(path) = &args.imatrix {
+        options = options.with_imatrix(ImportanceMatrix::load(path)?);
+    }
+
+    let gl = GGUFFileLoader::new(&args.input)?;
+    let gf = gl.open()?;
+    let file = File::create(&args.output).map_err(|err| Error {
+        kind: ErrorKind::IOError,
+        message: format!("failed to create the file: {}", args.output),
+        cause: Some(Box::new(err)),
+    })?;
+
+    println!(
+        "{:<32} {:<16} {:>6} {:>6} {:>12} {:>12}",
+        "tensor", "dimensions", "from", "to", "rmse", "max_err"
+    );
+    let (mut src_bytes, mut dst_bytes) = (0, 0);
+    quantize_gguf_with_options(&gf, BufWriter::new(file), &options, |r| {
+        src_bytes += r.src_bytes;
+        dst_bytes += r.dst_bytes;
+        println!(
+            "{:<32} {:<16} {:>6} {:>6} {:>12.6} {:>12.6}",
+            r.name,
+            format!("{:?}", r.dimensions),
+            r.src_typ.to_string(),
+            r.dst_typ.to_string(),
+            r.rmse,
+            r.max_abs_error
+        );
+    })?;
+
+    println!(
+        "{:.2}MB -> {:.2}MB, {}ms",
+        src_bytes as f64 / 1024.0 / 1024.0,
+        dst_bytes as f64 / 1024.0 / 1024.0,
+        start_time.elapsed().as_millis()
+    );
+    Ok(())
+}
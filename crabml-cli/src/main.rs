@@ -1,22 +1,63 @@
+#[cfg(not(target_os = "wasi"))]
 extern crate jemallocator;
 
 use std::io::Write;
+use std::time::Duration;
 use std::time::Instant;
 
 use clap::Parser;
 use clap::ValueEnum;
+use crabml::backends::cpu::AttentionAccumulation;
 use crabml::backends::cpu::CpuTensorDevice;
+use crabml::backends::cpu::CpuTensorDeviceOptions;
+use crabml::backends::cpu::ThreadPools;
+#[cfg(not(target_os = "wasi"))]
+use crabml::backends::wgpu::WgpuSampler;
+#[cfg(not(target_os = "wasi"))]
 use crabml::backends::wgpu::WgpuTensorDevice;
+#[cfg(not(target_os = "wasi"))]
 use crabml::backends::wgpu::WgpuTensorDeviceOptions;
+use crabml::error::ErrorKind;
 use crabml::error::Result;
+use crabml::gguf::GGMLType;
 use crabml::gguf::GGUFFileLoader;
+use crabml::gguf::GGUFLoadMode;
+use crabml::sampler::Dist;
+use crabml::sampler::Grammar;
+use crabml::sampler::MirostatV2;
+use crabml::sampler::Penalties;
+use crabml::sampler::SamplerChain;
+use crabml::sampler::Temperature;
+use crabml::sampler::TopK;
+use crabml::sampler::TopP;
+use crabml::tensor::OpProfiler;
+use crabml::tensor::TaskTag;
 use crabml::tensor::Tensor;
 use crabml::tensor::TensorMetrics;
+use crabml::tensor::TraceRecorder;
+use crabml::tokenizer::BpeTokenizer;
+use crabml::tokenizer::TOKENIZER_SELF_TEST_CORPUS;
 use crabml_llama2::llama2::Llama2Runner;
-use crabml_llama2::sampler::Llama2Sampler;
+use crabml_llama2::llama2::TokenSampler;
+use crabml_llama2::ChatMessage;
+use crabml_llama2::ChatTemplate;
 use crabml_llama2::CpuLlama2Model;
+use crabml_llama2::ImatrixOptions;
+use crabml_llama2::LoraAdapter;
+use crabml_llama2::LoraMode;
+use crabml_llama2::MedusaDecoder;
+use crabml_llama2::MedusaHeads;
+use crabml_llama2::MedusaOptions;
+use crabml_llama2::MemoryOptions;
+use crabml_llama2::ModelLoadOptions;
+use crabml_llama2::PerplexityOptions;
+use crabml_llama2::SpeculativeDecoder;
+use crabml_llama2::TruncationOptions;
+use crabml_llama2::TruncationPolicy;
+#[cfg(not(target_os = "wasi"))]
 use crabml_llama2::WgpuLlama2Model;
 
+#[cfg(not(target_os = "wasi"))]
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
@@ -37,17 +78,215 @@ struct CommandArgs {
     #[arg(short, long, default_value_t = 1.0)]
     temperature: f32,
 
+    /// Sample from the k most likely tokens, 0 means no limit
+    #[arg(long, default_value_t = 0)]
+    top_k: usize,
+
+    /// Penalize the tokens repeated in the last n tokens, 1.0 means disabled
+    #[arg(long, default_value_t = 1.0)]
+    repeat_penalty: f32,
+
+    #[arg(long, default_value_t = 0.0)]
+    frequency_penalty: f32,
+
+    #[arg(long, default_value_t = 0.0)]
+    presence_penalty: f32,
+
+    /// The window of the tokens to penalize, 0 disables the penalties and -1 takes the whole
+    /// context
+    #[arg(long, default_value_t = 64, allow_negative_numbers = true)]
+    repeat_last_n: i64,
+
+    /// Use mirostat v2 sampling with the target surprise tau, instead of top-k/top-p
+    #[arg(long)]
+    mirostat_tau: Option<f32>,
+
+    /// The learning rate of mirostat
+    #[arg(long, default_value_t = 0.1)]
+    mirostat_eta: f32,
+
+    /// The seed of the sampling, random if not set. the seed in use is printed after the
+    /// generation, to reproduce the output
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Take the logits within epsilon of the highest one as a tie on the greedy choice, the
+    /// lowest token id wins, so the output is stable across the simd kernels
+    #[arg(long, default_value_t = 0.0)]
+    tie_epsilon: f32,
+
+    /// Constrain the output to the GBNF grammar in the file, in the llama.cpp format
+    #[arg(long, conflicts_with = "json_schema_file")]
+    grammar_file: Option<String>,
+
+    /// Constrain the output to the JSON documents valid in the JSON schema in the file
+    #[arg(long)]
+    json_schema_file: Option<String>,
+
     #[arg(short, long, default_value_t = false)]
     verbose: bool,
 
     #[arg(short = 'T', long, default_value_t = 2)]
     threads: usize,
 
+    /// Let the worker threads spin up to this many microseconds between the ops instead of
+    /// parking, which cuts the jitter of the per-token latency on the dedicated cores, cpu only
+    #[arg(long)]
+    busy_poll_us: Option<u64>,
+
+    /// Forward the prompt on a separate pool of this many threads, the tokens are generated on
+    /// the --threads pool, cpu only
+    #[arg(long)]
+    prefill_threads: Option<usize>,
+
     /// The prompt
-    prompt: String,
+    #[arg(required_unless_present_any = ["tokenizer_self_test", "perplexity_file", "imatrix_file"])]
+    prompt: Option<String>,
+
+    /// Format the prompt as a user message by the chat template of the model
+    #[arg(long, default_value_t = false)]
+    chat: bool,
+
+    /// The system prompt of the chat, with --chat
+    #[arg(long, requires = "chat")]
+    system: Option<String>,
+
+    /// Take the tokenizer and the chat template from the metadata of this GGUF file instead
+    /// of the model's, like a sidecar file fixing a broken chat template
+    #[arg(long)]
+    tokenizer_metadata: Option<String>,
 
     #[arg(short = 'D', long, default_value_t = DeviceType::Cpu)]
     device: DeviceType,
+
+    /// The dtype of the kv cache, only f32 is supported on wgpu
+    #[arg(long, default_value_t = KvCacheDType::F16)]
+    kv_cache_dtype: KvCacheDType,
+
+    /// Limit the kv cache and the scratch of a forward pass to this many megabytes, it fails
+    /// with an out of memory error when they do not fit, cpu only
+    #[arg(long)]
+    memory_budget: Option<usize>,
+
+    /// Fall back to a q8_0 kv cache and smaller prefill batches instead of failing when out of
+    /// the memory
+    #[arg(long, default_value_t = false)]
+    oom_fallback: bool,
+
+    /// Run the matmuls in a fixed order without fma, so the logits are the same bits on all
+    /// the platforms, slower, cpu only
+    #[arg(long, default_value_t = false)]
+    deterministic: bool,
+
+    /// Keep the hidden states in f16 through the forward pass, halving the activation memory
+    /// at a small loss of precision, cpu only
+    #[arg(long, default_value_t = false)]
+    f16_activations: bool,
+
+    /// Keep the attention sums over the f16 kv cache from overflowing into inf or stalling
+    /// on the long contexts, by saturating the scores or also summing up in f32, cpu only
+    #[arg(long, default_value_t = Accumulation::Plain)]
+    attention_accumulation: Accumulation,
+
+    /// Read the whole model into the memory on loading, instead of mapping the file and
+    /// reading the tensors on their first access
+    #[arg(long, default_value_t = false)]
+    no_mmap: bool,
+
+    /// Pin the model in the memory so it's never swapped out
+    #[arg(long, default_value_t = false)]
+    mlock: bool,
+
+    /// Fail unless all the weights stay in the mapped file, so the processes serving the same
+    /// model share one copy of them in the page cache
+    #[arg(long, default_value_t = false, conflicts_with = "no_mmap")]
+    shared_weights: bool,
+
+    /// Keep only a sliding window of n layers' weights in the memory, reading the next layers
+    /// from the file while computing, to run a model larger than the ram slowly
+    #[arg(long, value_name = "WINDOW", conflicts_with_all = ["no_mmap", "mlock"])]
+    stream_layers: Option<usize>,
+
+    /// Load and run only the first n transformer layers of the model, all by default
+    #[arg(long)]
+    n_layers: Option<usize>,
+
+    /// Apply a LoRA adapter in gguf or safetensors, with an optional scale like
+    /// `adapter.gguf:0.5`. it can be repeated to stack the adapters
+    #[arg(long)]
+    lora: Vec<String>,
+
+    /// Apply the LoRA adapters on the fly instead of merging them into the weights, which
+    /// keeps the quantized weights as they are
+    #[arg(long, default_value_t = false)]
+    lora_fused: bool,
+
+    /// Sample on the gpu and read back only the token id instead of the logits, wgpu only.
+    /// the penalties and mirostat are not supported on the gpu
+    #[arg(long, default_value_t = false)]
+    sample_on_device: bool,
+
+    /// Write the spans of the layers, ops and sampling to the file in the chrome trace json
+    /// format, which can be opened in https://ui.perfetto.dev
+    #[arg(long)]
+    trace: Option<String>,
+
+    /// A small model sharing the tokenizer to draft the tokens for speculative decoding, the
+    /// target model verifies the drafts in one pass, cpu only
+    #[arg(long)]
+    draft_model: Option<String>,
+
+    /// The number of tokens to draft on each step of the speculative decoding
+    #[arg(long, default_value_t = 4)]
+    n_draft: usize,
+
+    /// A gguf file of the Medusa heads named like medusa.0.fc.weight, the heads guess the
+    /// next tokens and the model verifies the most likely paths through them in one pass,
+    /// cpu only
+    #[arg(long)]
+    medusa_heads: Option<String>,
+
+    /// The number of paths through the guesses of the Medusa heads verified on each step
+    #[arg(long, default_value_t = 8)]
+    medusa_paths: usize,
+
+    /// Print the wall time, calls, FLOPs and bytes moved of every op after the generation,
+    /// cpu only. the ops are also traced with their FLOPs and bytes on --trace
+    #[arg(long, default_value_t = false)]
+    profile: bool,
+
+    /// How to fit a prompt longer than the context, the steps to generate are reserved up to
+    /// half of the context
+    #[arg(long, default_value_t = Truncate::Error)]
+    truncate: Truncate,
+
+    /// The number of tokens after the BOS which are never truncated, like a system prompt
+    #[arg(long, default_value_t = 0)]
+    keep: usize,
+
+    /// Check the tokenizer of the model round trips a corpus of tricky strings, and exit
+    #[arg(long, default_value_t = false)]
+    tokenizer_self_test: bool,
+
+    /// Print the perplexity of the model on the text in the file instead of generating, to
+    /// compare the quantizations
+    #[arg(long)]
+    perplexity_file: Option<String>,
+
+    /// The tokens of each chunk scored on --perplexity-file or --imatrix-file, the context
+    /// of the model by default
+    #[arg(long)]
+    ppl_n_ctx: Option<usize>,
+
+    /// Run the calibration text in the file through the model and write the importance
+    /// matrix of the weights in the imatrix format of llama.cpp, for crabml-quantize
+    /// --imatrix
+    #[arg(long, conflicts_with = "perplexity_file")]
+    imatrix_file: Option<String>,
+
+    /// The file the importance matrix of --imatrix-file is written into
+    #[arg(long, default_value_t = format!("imatrix.dat"))]
+    imatrix_out: String,
 }
 
 #[derive(Clone, Debug, ValueEnum)]
@@ -65,14 +304,98 @@ impl std::fmt::Display for DeviceType {
     }
 }
 
+#[derive(Clone, Debug, ValueEnum)]
+enum Truncate {
+    Error,
+    Left,
+    Middle,
+}
+
+impl std::fmt::Display for Truncate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Truncate::Error => write!(f, "error"),
+            Truncate::Left => write!(f, "left"),
+            Truncate::Middle => write!(f, "middle"),
+        }
+    }
+}
+
+impl From<Truncate> for TruncationPolicy {
+    fn from(truncate: Truncate) -> Self {
+        match truncate {
+            Truncate::Error => TruncationPolicy::Error,
+            Truncate::Left => TruncationPolicy::Left,
+            Truncate::Middle => TruncationPolicy::Middle,
+        }
+    }
+}
+
+#[derive(Clone, Debug, ValueEnum)]
+enum Accumulation {
+    Plain,
+    Saturate,
+    Rescale,
+}
+
+impl std::fmt::Display for Accumulation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Accumulation::Plain => write!(f, "plain"),
+            Accumulation::Saturate => write!(f, "saturate"),
+            Accumulation::Rescale => write!(f, "rescale"),
+        }
+    }
+}
+
+impl From<Accumulation> for AttentionAccumulation {
+    fn from(accumulation: Accumulation) -> Self {
+        match accumulation {
+            Accumulation::Plain => AttentionAccumulation::Plain,
+            Accumulation::Saturate => AttentionAccumulation::Saturate,
+            Accumulation::Rescale => AttentionAccumulation::Rescale,
+        }
+    }
+}
+
+#[derive(Clone, Debug, ValueEnum)]
+enum KvCacheDType {
+    F32,
+    F16,
+    #[value(name = "q8_0")]
+    Q8_0,
+}
+
+impl std::fmt::Display for KvCacheDType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KvCacheDType::F32 => write!(f, "f32"),
+            KvCacheDType::F16 => write!(f, "f16"),
+            KvCacheDType::Q8_0 => write!(f, "q8_0"),
+        }
+    }
+}
+
+impl From<KvCacheDType> for GGMLType {
+    fn from(dtype: KvCacheDType) -> Self {
+        match dtype {
+            KvCacheDType::F32 => GGMLType::F32,
+            KvCacheDType::F16 => GGMLType::F16,
+            KvCacheDType::Q8_0 => GGMLType::Q8_0,
+        }
+    }
+}
+
 fn run<U: Tensor>(
     args: &CommandArgs,
     runner: &mut Llama2Runner<U>,
-    sampler: &mut Llama2Sampler,
+    sampler: &mut impl TokenSampler<U>,
+    seed: u64,
     metrics: &TensorMetrics,
 ) -> Result<()> {
+    let prompt = args.prompt.as_deref().unwrap_or_default();
     let prefill_started_at = Instant::now();
-    let (prefill_pos, prev_token, token) = runner.prefill(&args.prompt, sampler)?;
+    let (prefill_pos, prev_token, token) = runner.prefill(prompt, sampler)?;
     let prefill_elapsed = prefill_started_at.elapsed();
     if args.verbose {
         dump_metrics(metrics);
@@ -83,7 +406,7 @@ fn run<U: Tensor>(
     let mut generated_tokens = 0;
     let generation_started_at = Instant::now();
 
-    print!("{}", &args.prompt);
+    print!("{}", prompt);
     loop {
         let _t = metrics.total_walltime.track();
         match output.next() {
@@ -113,13 +436,224 @@ fn run<U: Tensor>(
         prefill_elapsed.as_millis()
     );
     println!(
-        "{} tokens/s, {} threads",
-        generated_tokens_per_second, args.threads
+        "{} tokens/s, {} threads, seed: {}",
+        generated_tokens_per_second, args.threads, seed
+    );
+
+    Ok(())
+}
+
+fn run_perplexity<U: Tensor>(
+    args: &CommandArgs,
+    runner: &mut Llama2Runner<U>,
+    tokenizer: &BpeTokenizer,
+    path: &str,
+) -> Result<()> {
+    let tokens = tokenizer.encode(&read_file(path)?, false, false)?;
+    let mut options = PerplexityOptions::new();
+    if let Some(n_ctx) = args.ppl_n_ctx {
+        options = options.with_n_ctx(n_ctx);
+    }
+
+    let started_at = Instant::now();
+    let ppl = runner.perplexity(&tokens, &options, |done, n_chunks, ppl| {
+        eprint!("\r[{}/{}] perplexity: {:.4}", done, n_chunks, ppl);
+    })?;
+    eprintln!();
+    println!(
+        "perplexity: {:.4}, {} tokens scored, {:.1} tokens/s",
+        ppl.perplexity(),
+        ppl.n_scored(),
+        tokens.len() as f64 / started_at.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
+
+fn run_imatrix<U: Tensor>(
+    args: &CommandArgs,
+    runner: &mut Llama2Runner<U>,
+    tokenizer: &BpeTokenizer,
+    path: &str,
+) -> Result<()> {
+    let tokens = tokenizer.encode(&read_file(path)?, false, false)?;
+    let mut options = ImatrixOptions::new();
+    if let Some(n_ctx) = args.ppl_n_ctx {
+        options = options.with_n_ctx(n_ctx);
+    }
+
+    let started_at = Instant::now();
+    let mut imatrix = runner.importance_matrix(&tokens, &options, |done, n_chunks| {
+        eprint!("\r[{}/{}] computing the importance matrix", done, n_chunks);
+    })?;
+    eprintln!();
+    imatrix.dataset = path.to_string();
+    imatrix.save(&args.imatrix_out)?;
+    println!(
+        "imatrix: {} weights written into {}, {} tokens, {:.1} tokens/s",
+        imatrix.len(),
+        args.imatrix_out,
+        tokens.len(),
+        tokens.len() as f64 / started_at.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
+
+fn run_speculative<U: Tensor>(
+    args: &CommandArgs,
+    target: &mut Llama2Runner<U>,
+    draft: &mut Llama2Runner<U>,
+    sampler: &mut SamplerChain,
+    tokenizer: &BpeTokenizer,
+) -> Result<()> {
+    let prompt = args.prompt.as_deref().unwrap_or_default();
+    let mut decoder = SpeculativeDecoder::new(target, draft, args.n_draft)?;
+    if args.verbose && !decoder.vocab_mapping().is_identical() {
+        println!(
+            "the vocabularies differ by padding, drafting from the {} shared tokens",
+            decoder.vocab_mapping().n_shared()
+        );
+    }
+
+    print!("{}", prompt);
+    let started_at = Instant::now();
+    let tokens = decoder.generate(prompt, args.steps, sampler)?;
+    let elapsed = started_at.elapsed().as_secs_f64();
+    let mut prev_token = *tokenizer.encode(prompt, true, false)?.last().unwrap();
+    for token in tokens.iter() {
+        print!("{}", tokenizer.decode(prev_token, *token)?);
+        prev_token = *token;
+    }
+
+    let stats = decoder.stats();
+    println!();
+    println!(
+        "{} tokens/s, {} steps, {:.1}% of {} drafted tokens accepted, seed: {}",
+        tokens.len() as f64 / elapsed,
+        stats.steps,
+        stats.acceptance_rate() * 100.0,
+        stats.drafted,
+        sampler.seed()
     );
+    Ok(())
+}
+
+fn run_medusa<U: Tensor>(
+    args: &CommandArgs,
+    runner: &mut Llama2Runner<U>,
+    heads: &MedusaHeads<U>,
+    sampler: &mut SamplerChain,
+    tokenizer: &BpeTokenizer,
+) -> Result<()> {
+    let prompt = args.prompt.as_deref().unwrap_or_default();
+    let options = MedusaOptions {
+        n_paths: args.medusa_paths,
+        ..Default::default()
+    };
+    let mut decoder = MedusaDecoder::new(runner, heads, options)?;
+
+    print!("{}", prompt);
+    let started_at = Instant::now();
+    let tokens = decoder.generate(prompt, args.steps, sampler)?;
+    let elapsed = started_at.elapsed().as_secs_f64();
+    let mut prev_token = *tokenizer.encode(prompt, true, false)?.last().unwrap();
+    for token in tokens.iter() {
+        print!("{}", tokenizer.decode(prev_token, *token)?);
+        prev_token = *token;
+    }
 
+    let stats = decoder.stats();
+    println!();
+    println!(
+        "{} tokens/s, {} steps, {:.1}% of {} guessed tokens accepted, seed: {}",
+        tokens.len() as f64 / elapsed,
+        stats.steps,
+        stats.acceptance_rate() * 100.0,
+        stats.drafted,
+        sampler.seed()
+    );
     Ok(())
 }
 
+// PATH or PATH:SCALE
+fn load_lora(arg: &str) -> Result<LoraAdapter> {
+    if let Some((path, scale)) = arg.rsplit_once(':') {
+        if let Ok(scale) = scale.parse::<f32>() {
+            return Ok(LoraAdapter::load(path)?.with_scale(scale));
+        }
+    }
+    LoraAdapter::load(arg)
+}
+
+fn read_file(path: &str) -> Result<String> {
+    std::fs::read_to_string(path).map_err(|err| crabml::error::Error {
+        kind: ErrorKind::IOError,
+        message: format!("failed to read {}", path),
+        cause: Some(Box::new(err)),
+    })
+}
+
+fn build_sampler(args: &CommandArgs, tokenizer: &BpeTokenizer) -> Result<SamplerChain> {
+    let mut sampler = SamplerChain::new().with_tie_epsilon(args.tie_epsilon);
+    if let Some(seed) = args.seed {
+        sampler = sampler.with_seed(seed);
+    }
+    if let Some(path) = &args.grammar_file {
+        sampler = sampler.with_processor(Grammar::new(&read_file(path)?, tokenizer)?);
+    }
+    if let Some(path) = &args.json_schema_file {
+        sampler = sampler.with_processor(Grammar::from_json_schema(&read_file(path)?, tokenizer)?);
+    }
+    let repeat_last_n = match args.repeat_last_n {
+        n if n < 0 => Penalties::WHOLE_CONTEXT,
+        n => n as usize,
+    };
+    sampler = sampler.with(Penalties::new(
+        repeat_last_n,
+        args.repeat_penalty,
+        args.frequency_penalty,
+        args.presence_penalty,
+    ));
+
+    // an empty chain after the penalties is greedy
+    if args.temperature <= 0.0 {
+        return Ok(sampler);
+    }
+    if let Some(tau) = args.mirostat_tau {
+        return Ok(sampler
+            .with(Temperature::new(args.temperature))
+            .with(MirostatV2::new(tau, args.mirostat_eta)));
+    }
+    Ok(sampler
+        .with(TopK::new(args.top_k))
+        .with(Temperature::new(args.temperature))
+        .with(TopP::new(args.probability))
+        .with(Dist))
+}
+
+#[cfg(not(target_os = "wasi"))]
+fn build_wgpu_sampler(args: &CommandArgs) -> Result<WgpuSampler> {
+    if args.mirostat_tau.is_some()
+        || args.repeat_penalty != 1.0
+        || args.frequency_penalty != 0.0
+        || args.presence_penalty != 0.0
+        || args.grammar_file.is_some()
+        || args.json_schema_file.is_some()
+        || args.tie_epsilon > 0.0
+    {
+        return Err((
+            ErrorKind::BadInput,
+            "the penalties, mirostat, grammars and --tie-epsilon are not supported with --sample-on-device",
+        )
+            .into());
+    }
+
+    let mut sampler = WgpuSampler::new(args.temperature, args.top_k, args.probability);
+    if let Some(seed) = args.seed {
+        sampler = sampler.with_seed(seed);
+    }
+    Ok(sampler)
+}
+
 fn dump_metrics(metrics: &TensorMetrics) {
     println!();
     if metrics.forward_walltime.as_millis() == 0.0 {
@@ -133,33 +667,112 @@ fn dump_metrics(metrics: &TensorMetrics) {
 }
 
 fn main() -> Result<()> {
-    let args = CommandArgs::parse();
+    let mut args = CommandArgs::parse();
     let start_time = Instant::now();
 
-    // configure rayon
-    let mut threads = args.threads;
-    if threads == 0 {
-        threads = num_cpus::get();
+    // configure rayon, the kernels run serially on wasi
+    #[cfg(not(target_os = "wasi"))]
+    {
+        let mut threads = args.threads;
+        if threads == 0 {
+            threads = num_cpus::get();
+        }
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .unwrap();
     }
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(threads)
-        .build_global()
-        .unwrap();
 
-    let gl = GGUFFileLoader::new(&args.model)?;
+    let mut load_options = ModelLoadOptions::new()
+        .with_mlock(args.mlock)
+        .with_shared_weights(args.shared_weights);
+    if args.no_mmap {
+        load_options = load_options.with_load_mode(GGUFLoadMode::Buffered);
+    }
+    if args.verbose {
+        load_options = load_options.with_progress(|loaded, total| {
+            eprint!("\rloading {}%", loaded * 100 / total.max(1));
+            if loaded == total {
+                eprintln!();
+            }
+        });
+    }
+    if let Some(n_layers) = args.n_layers {
+        load_options = load_options.with_n_layers(n_layers);
+    }
+    if let Some(window) = args.stream_layers {
+        load_options = load_options.with_layer_streaming(window);
+    }
+    for lora in args.lora.iter() {
+        load_options = load_options.with_lora(load_lora(lora)?);
+    }
+    if args.lora_fused {
+        load_options = load_options.with_lora_mode(LoraMode::Fused);
+    }
+    let gl = load_options.open(&args.model)?;
     let gf = gl.open()?;
 
-    let metrics = TensorMetrics::default();
-    let device_cpu = CpuTensorDevice::new().with_metrics(metrics.clone());
-    let model_cpu = CpuLlama2Model::load(&gf, device_cpu.clone())?;
+    let mut metrics = TensorMetrics::default();
+    if args.trace.is_some() {
+        metrics = metrics.with_trace(TraceRecorder::new());
+    }
+    let mut thread_pools = ThreadPools::new();
+    if let Some(n_threads) = args.prefill_threads {
+        thread_pools = thread_pools.with_threads(TaskTag::Prefill, n_threads)?;
+    }
+    let mut device_cpu = CpuTensorDevice::with_options(CpuTensorDeviceOptions {
+        busy_poll: args.busy_poll_us.map(Duration::from_micros),
+        thread_pools,
+        deterministic: args.deterministic,
+        activation_dtype: if args.f16_activations {
+            GGMLType::F16
+        } else {
+            GGMLType::F32
+        },
+        attention_accumulation: args.attention_accumulation.clone().into(),
+        ..Default::default()
+    })
+    .with_metrics(metrics.clone());
+    if args.profile {
+        device_cpu = device_cpu.with_profiler(OpProfiler::new().with_trace(metrics.trace.clone()));
+    }
+    let mut model_cpu = CpuLlama2Model::load_with_options(&gf, device_cpu.clone(), load_options)?;
+    if let Some(path) = &args.tokenizer_metadata {
+        let sidecar = GGUFFileLoader::new(path)?;
+        model_cpu.reload_tokenizer(&sidecar.open()?)?;
+    }
     let conf = model_cpu.conf.clone();
+    // only profile the generation
+    device_cpu.profiler().reset();
 
-    let mut sampler = Llama2Sampler::new(
-        conf.vocab_size,
-        args.temperature,
-        args.probability,
-        device_cpu.exp_cache(),
-    );
+    if args.tokenizer_self_test {
+        let merges = gf.metadata().get_string_array("tokenizer.ggml.merges");
+        let report = model_cpu
+            .tokenizer
+            .self_test(TOKENIZER_SELF_TEST_CORPUS, merges)?;
+        println!("{}", report);
+        if !report.is_ok() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if args.chat {
+        let template = ChatTemplate::from_model(&conf, &model_cpu.tokenizer);
+        let mut messages = vec![];
+        if let Some(system) = &args.system {
+            messages.push(ChatMessage::system(system));
+        }
+        messages.push(ChatMessage::user(
+            args.prompt.as_deref().unwrap_or_default(),
+        ));
+        args.prompt = Some(template.render(&messages, None, true)?);
+    }
+
+    let mut sampler = build_sampler(&args, &model_cpu.tokenizer)?;
+    let truncation = TruncationOptions::new(args.truncate.clone().into())
+        .with_keep(args.keep)
+        .with_reserve(args.steps.min(conf.seq_len / 2));
 
     if args.verbose {
         for tensor in gf.tensor_infos() {
@@ -174,20 +787,101 @@ fn main() -> Result<()> {
 
     match args.device {
         DeviceType::Cpu => {
-            let mut runner = Llama2Runner::new(&model_cpu, metrics.clone(), conf.seq_len, true)?;
+            let mut memory = MemoryOptions::new()
+                .with_q8_0_kv_cache(args.oom_fallback)
+                .with_smaller_batch(args.oom_fallback);
+            if let Some(budget) = args.memory_budget {
+                memory = memory.with_budget(budget * 1024 * 1024);
+            }
+            let mut runner = Llama2Runner::new_with_memory(
+                &model_cpu,
+                metrics.clone(),
+                conf.seq_len,
+                args.kv_cache_dtype.clone().into(),
+                memory,
+            )?;
+            runner.set_truncation(truncation);
             println!("loaded model: {}ms", start_time.elapsed().as_millis());
-            run(&args, &mut runner, &mut sampler, &metrics)?;
+            if args.sample_on_device {
+                return Err((
+                    ErrorKind::BadInput,
+                    "--sample-on-device is only supported on wgpu",
+                )
+                    .into());
+            }
+            if let Some(path) = &args.perplexity_file {
+                run_perplexity(&args, &mut runner, &model_cpu.tokenizer, path)?;
+            } else if let Some(path) = &args.imatrix_file {
+                run_imatrix(&args, &mut runner, &model_cpu.tokenizer, path)?;
+            } else if let Some(draft_model) = &args.draft_model {
+                let gl_draft = GGUFFileLoader::new(draft_model)?;
+                let gf_draft = gl_draft.open()?;
+                let model_draft = CpuLlama2Model::load(&gf_draft, device_cpu.clone())?;
+                let mut draft = Llama2Runner::new(
+                    &model_draft,
+                    TensorMetrics::default(),
+                    conf.seq_len.min(model_draft.conf.seq_len),
+                    GGMLType::F32,
+                )?;
+                run_speculative(
+                    &args,
+                    &mut runner,
+                    &mut draft,
+                    &mut sampler,
+                    &model_cpu.tokenizer,
+                )?;
+            } else if let Some(medusa_heads) = &args.medusa_heads {
+                let gl_heads = GGUFFileLoader::new(medusa_heads)?;
+                let gf_heads = gl_heads.open()?;
+                let heads = MedusaHeads::load(&gf_heads, device_cpu.clone())?;
+                run_medusa(
+                    &args,
+                    &mut runner,
+                    &heads,
+                    &mut sampler,
+                    &model_cpu.tokenizer,
+                )?;
+            } else {
+                let seed = sampler.seed();
+                run(&args, &mut runner, &mut sampler, seed, &metrics)?;
+            }
+            if args.profile {
+                print!("{}", device_cpu.profile_report());
+            }
         }
+        #[cfg(target_os = "wasi")]
+        DeviceType::Wgpu => {
+            return Err((ErrorKind::NotImplemented, "wgpu is not available on wasi").into());
+        }
+        #[cfg(not(target_os = "wasi"))]
         DeviceType::Wgpu => {
             let device_wgpu = WgpuTensorDevice::new(
                 WgpuTensorDeviceOptions::new().with_staging_buf_bytes(conf.vocab_size * 4),
             );
             let model_wgpu = WgpuLlama2Model::from_cpu(&model_cpu, device_wgpu)?;
 
-            let mut runner = Llama2Runner::new(&model_wgpu, metrics.clone(), conf.seq_len, false)?;
-            run(&args, &mut runner, &mut sampler, &metrics)?;
+            let mut runner =
+                Llama2Runner::new(&model_wgpu, metrics.clone(), conf.seq_len, GGMLType::F32)?;
+            runner.set_truncation(truncation);
+            if let Some(path) = &args.perplexity_file {
+                run_perplexity(&args, &mut runner, &model_cpu.tokenizer, path)?;
+            } else if let Some(path) = &args.imatrix_file {
+                run_imatrix(&args, &mut runner, &model_cpu.tokenizer, path)?;
+            } else if args.sample_on_device {
+                let mut sampler = build_wgpu_sampler(&args)?;
+                let seed = sampler.seed();
+                run(&args, &mut runner, &mut sampler, seed, &metrics)?;
+            } else {
+                let seed = sampler.seed();
+                run(&args, &mut runner, &mut sampler, seed, &metrics)?;
+            }
         }
     }
 
+    if let Some(path) = &args.trace {
+        metrics.trace.write_chrome_trace(path)?;
+        println!("trace: {}", path);
+    }
+
     Ok(())
 }
@@ -0,0 +1,34 @@
+//! run with `cargo bench -p crabml --bench vec_dot`, the kernel is picked by the
+//! cpu features detected at runtime.
+
+use bencher::benchmark_group;
+use bencher::benchmark_main;
+use bencher::black_box;
+use bencher::Bencher;
+use crabml::backends::cpu::buf::QuantBufQ4_0;
+use crabml::backends::cpu::buf::QuantBufQ8_0;
+
+const LEN: usize = 4096;
+
+fn make_input(seed: usize) -> Vec<f32> {
+    (0..LEN)
+        .map(|i| ((i * 13 + seed) % 31) as f32 * 0.3 - 4.5)
+        .collect()
+}
+
+fn bench_vec_dot_q8_0_q8_0(b: &mut Bencher) {
+    let qa = QuantBufQ8_0::quantize(&make_input(5));
+    let qb = QuantBufQ8_0::quantize(&make_input(7));
+    b.iter(|| black_box(qa.vec_dot(0, &qb, 0, LEN)));
+    b.bytes = (LEN * 2) as u64;
+}
+
+fn bench_vec_dot_q4_0_q8_0(b: &mut Bencher) {
+    let qa = QuantBufQ4_0::quantize(&make_input(5));
+    let qb = QuantBufQ8_0::quantize(&make_input(7));
+    b.iter(|| black_box(qa.vec_dot(0, &qb, 0, LEN)));
+    b.bytes = (LEN + LEN / 2) as u64;
+}
+
+benchmark_group!(benches, bench_vec_dot_q8_0_q8_0, bench_vec_dot_q4_0_q8_0);
+benchmark_main!(benches);
@@ -1,10 +1,10 @@
 #![allow(clippy::missing_safety_doc)]
 use core::arch::aarch64::float32x4_t;
+use core::arch::aarch64::int32x4_t;
+use core::arch::aarch64::int8x16_t;
 use core::arch::aarch64::uint16x4_t;
 use core::arch::aarch64::uint16x8_t;
 use core::arch::asm;
-use std::arch::aarch64::int32x4_t;
-use std::arch::aarch64::int8x16_t;
 
 use half::f16;
 
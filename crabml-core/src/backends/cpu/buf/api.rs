@@ -1,4 +1,4 @@
-use std::borrow::Cow;
+use alloc::borrow::Cow;
 
 use half::f16;
 
@@ -7,6 +7,8 @@ use super::buf_f16::f16_buf_from_bytes;
 use super::buf_f16::quantize_f32_f16;
 use super::buf_f32::f32_buf_from_bytes;
 use super::buf_f32::vec_dot_f32_f32;
+use super::buf_int::i32_buf_from_bytes;
+use super::buf_int::i8_buf_from_bytes;
 use crate::backends::cpu::buf::buf_f16::vec_dot_f16_f16;
 use crate::backends::cpu::buf::QuantBufQ2K;
 use crate::backends::cpu::buf::QuantBufQ3K;
@@ -20,9 +22,11 @@ use crate::backends::cpu::buf::QuantBufQ6K;
 use crate::backends::cpu::buf::QuantBufQ8K;
 use crate::backends::cpu::buf::QuantBufQ8_0;
 use crate::backends::cpu::buf::QuantBufQ8_1;
+use crate::backends::cpu::buf::QuantBufTQ2_0;
 use crate::error::ErrorKind;
 use crate::error::Result;
-use crate::gguf::GGMLType;
+use crate::ggml_type::GGMLType;
+use crate::kernels::prelude::*;
 
 /// All the quantized tensor are read-only.
 #[derive(Debug)]
@@ -30,6 +34,8 @@ use crate::gguf::GGMLType;
 pub enum CpuTensorBuf<'a> {
     F32(Cow<'a, [f32]>),
     F16(Cow<'a, [f16]>),
+    I8(Cow<'a, [i8]>),
+    I32(Cow<'a, [i32]>),
     Q2K(QuantBufQ2K<'a>),
     Q3K(QuantBufQ3K<'a>),
     Q8_0(QuantBufQ8_0<'a>),
@@ -42,6 +48,7 @@ pub enum CpuTensorBuf<'a> {
     Q5_1(QuantBufQ5_1<'a>),
     Q5K(QuantBufQ5K<'a>),
     Q6K(QuantBufQ6K<'a>),
+    TQ2_0(QuantBufTQ2_0<'a>),
 }
 
 impl<'a> CpuTensorBuf<'a> {
@@ -49,6 +56,8 @@ impl<'a> CpuTensorBuf<'a> {
         match typ {
             GGMLType::F32 => Ok(CpuTensorBuf::F32(f32_buf_from_bytes(buf))),
             GGMLType::F16 => Ok(CpuTensorBuf::F16(f16_buf_from_bytes(buf))),
+            GGMLType::I8 => Ok(CpuTensorBuf::I8(i8_buf_from_bytes(buf))),
+            GGMLType::I32 => Ok(CpuTensorBuf::I32(i32_buf_from_bytes(buf))),
             GGMLType::Q2K => Ok(CpuTensorBuf::Q2K(QuantBufQ2K::from_bytes(buf))),
             GGMLType::Q3K => Ok(CpuTensorBuf::Q3K(QuantBufQ3K::from_bytes(buf))),
             GGMLType::Q8_0 => Ok(CpuTensorBuf::Q8_0(QuantBufQ8_0::from_bytes(buf))),
@@ -61,25 +70,44 @@ impl<'a> CpuTensorBuf<'a> {
             GGMLType::Q5_1 => Ok(CpuTensorBuf::Q5_1(QuantBufQ5_1::from_bytes(buf))),
             GGMLType::Q5K => Ok(CpuTensorBuf::Q5K(QuantBufQ5K::from_bytes(buf))),
             GGMLType::Q6K => Ok(CpuTensorBuf::Q6K(QuantBufQ6K::from_bytes(buf))),
-            _ => unimplemented!(),
+            GGMLType::TQ2_0 => Ok(CpuTensorBuf::TQ2_0(QuantBufTQ2_0::from_bytes(buf))),
+            _ => Err((
+                ErrorKind::TensorError,
+                format!("loading tensor of {:?} is not supported", typ),
+            )
+                .into()),
         }
     }
 
     pub fn is_owned(&self) -> bool {
         matches!(
             self,
-            CpuTensorBuf::F32(Cow::Owned(_)) | CpuTensorBuf::F16(Cow::Owned(_))
+            CpuTensorBuf::F32(Cow::Owned(_))
+                | CpuTensorBuf::F16(Cow::Owned(_))
+                | CpuTensorBuf::I8(Cow::Owned(_))
+                | CpuTensorBuf::I32(Cow::Owned(_))
+                | CpuTensorBuf::Q8_0(QuantBufQ8_0 {
+                    blocks: Cow::Owned(_)
+                })
         )
     }
 
     pub fn is_quantized(&self) -> bool {
-        matches!(self, CpuTensorBuf::F32(_))
+        !matches!(
+            self,
+            CpuTensorBuf::F32(_)
+                | CpuTensorBuf::F16(_)
+                | CpuTensorBuf::I8(_)
+                | CpuTensorBuf::I32(_)
+        )
     }
 
     pub fn len(&self) -> usize {
         match self {
             CpuTensorBuf::F32(buf) => buf.len(),
             CpuTensorBuf::F16(buf) => buf.len(),
+            CpuTensorBuf::I8(buf) => buf.len(),
+            CpuTensorBuf::I32(buf) => buf.len(),
             CpuTensorBuf::Q2K(buf) => buf.len(),
             CpuTensorBuf::Q3K(buf) => buf.len(),
             CpuTensorBuf::Q8_0(buf) => buf.len(),
@@ -90,9 +118,9 @@ impl<'a> CpuTensorBuf<'a> {
             CpuTensorBuf::Q4_0(buf) => buf.len(),
             CpuTensorBuf::Q4_1(buf) => buf.len(),
             CpuTensorBuf::Q4K(buf) => buf.len(),
-            CpuTensorBuf::Q5_1(buf) => buf.len(),
             CpuTensorBuf::Q5K(buf) => buf.len(),
             CpuTensorBuf::Q6K(buf) => buf.len(),
+            CpuTensorBuf::TQ2_0(buf) => buf.len(),
         }
     }
 
@@ -100,10 +128,35 @@ impl<'a> CpuTensorBuf<'a> {
         self.len() == 0
     }
 
+    /// the raw bytes in the layout of the GGUF file, the quantized blocks are not decoded.
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            CpuTensorBuf::F32(buf) => slice_as_bytes(buf),
+            CpuTensorBuf::F16(buf) => slice_as_bytes(buf),
+            CpuTensorBuf::I8(buf) => slice_as_bytes(buf),
+            CpuTensorBuf::I32(buf) => slice_as_bytes(buf),
+            CpuTensorBuf::Q2K(buf) => slice_as_bytes(&buf.blocks),
+            CpuTensorBuf::Q3K(buf) => slice_as_bytes(&buf.blocks),
+            CpuTensorBuf::Q8_0(buf) => slice_as_bytes(&buf.blocks),
+            CpuTensorBuf::Q8_1(buf) => slice_as_bytes(&buf.blocks),
+            CpuTensorBuf::Q8K(buf) => slice_as_bytes(&buf.blocks),
+            CpuTensorBuf::Q4_0(buf) => slice_as_bytes(&buf.blocks),
+            CpuTensorBuf::Q4_1(buf) => slice_as_bytes(&buf.blocks),
+            CpuTensorBuf::Q4K(buf) => slice_as_bytes(&buf.blocks),
+            CpuTensorBuf::Q5_0(buf) => slice_as_bytes(&buf.blocks),
+            CpuTensorBuf::Q5_1(buf) => slice_as_bytes(&buf.blocks),
+            CpuTensorBuf::Q5K(buf) => slice_as_bytes(&buf.blocks),
+            CpuTensorBuf::Q6K(buf) => slice_as_bytes(&buf.blocks),
+            CpuTensorBuf::TQ2_0(buf) => slice_as_bytes(&buf.blocks),
+        }
+    }
+
     pub fn dtype(&self) -> GGMLType {
         match self {
             CpuTensorBuf::F32(_) => GGMLType::F32,
             CpuTensorBuf::F16(_) => GGMLType::F16,
+            CpuTensorBuf::I8(_) => GGMLType::I8,
+            CpuTensorBuf::I32(_) => GGMLType::I32,
             CpuTensorBuf::Q2K(_) => GGMLType::Q2K,
             CpuTensorBuf::Q3K(_) => GGMLType::Q3K,
             CpuTensorBuf::Q8_0(_) => GGMLType::Q8_0,
@@ -116,6 +169,7 @@ impl<'a> CpuTensorBuf<'a> {
             CpuTensorBuf::Q5_1(_) => GGMLType::Q5_1,
             CpuTensorBuf::Q5K(_) => GGMLType::Q5K,
             CpuTensorBuf::Q6K(_) => GGMLType::Q6K,
+            CpuTensorBuf::TQ2_0(_) => GGMLType::TQ2_0,
         }
     }
 
@@ -123,6 +177,8 @@ impl<'a> CpuTensorBuf<'a> {
         match self {
             CpuTensorBuf::F32(_) => GGMLType::F32,
             CpuTensorBuf::F16(_) => GGMLType::F16,
+            CpuTensorBuf::I8(_) => GGMLType::I8,
+            CpuTensorBuf::I32(_) => GGMLType::I32,
             CpuTensorBuf::Q2K(_) => GGMLType::Q8K,
             CpuTensorBuf::Q3K(_) => GGMLType::Q8K,
             CpuTensorBuf::Q8_0(_) => GGMLType::Q8_0,
@@ -133,15 +189,16 @@ impl<'a> CpuTensorBuf<'a> {
             CpuTensorBuf::Q4_0(_) => GGMLType::Q8_0,
             CpuTensorBuf::Q4_1(_) => GGMLType::Q8_1,
             CpuTensorBuf::Q4K(_) => GGMLType::Q8K,
-            CpuTensorBuf::Q5_1(_) => GGMLType::Q8_1,
             CpuTensorBuf::Q5K(_) => GGMLType::Q8K,
             CpuTensorBuf::Q6K(_) => GGMLType::Q8K,
+            CpuTensorBuf::TQ2_0(_) => GGMLType::Q8K,
         }
     }
 
     /// dequantize the quantized tensors to f32 or f16.
     /// f32 to f16 is not considered as dequantization, but it still will be supported to
-    /// simplify the conversion on half-precision activation is enabled.
+    /// simplify the conversion on half-precision activation is enabled. the integers are
+    /// converted by value, like turning a 0/1 mask into f32.
     pub fn dequantize(self, dtype: GGMLType) -> Result<Self> {
         if dtype != GGMLType::F32 && dtype != GGMLType::F16 {
             return Err((
@@ -155,6 +212,8 @@ impl<'a> CpuTensorBuf<'a> {
             GGMLType::F32 => Ok(CpuTensorBuf::F32(match self {
                 CpuTensorBuf::F32(buf) => buf,
                 CpuTensorBuf::F16(buf) => dequantize_f16_buf(&buf, 0).collect(),
+                CpuTensorBuf::I8(buf) => buf.iter().map(|v| *v as f32).collect(),
+                CpuTensorBuf::I32(buf) => buf.iter().map(|v| *v as f32).collect(),
                 CpuTensorBuf::Q2K(buf) => buf.dequantize(0).collect(),
                 CpuTensorBuf::Q3K(buf) => buf.dequantize(0).collect(),
                 CpuTensorBuf::Q8_0(buf) => buf.dequantize(0).collect(),
@@ -167,40 +226,51 @@ impl<'a> CpuTensorBuf<'a> {
                 CpuTensorBuf::Q5_1(buf) => buf.dequantize(0).collect(),
                 CpuTensorBuf::Q5K(buf) => buf.dequantize(0).collect(),
                 CpuTensorBuf::Q6K(buf) => buf.dequantize(0).collect(),
+                CpuTensorBuf::TQ2_0(buf) => buf.dequantize(0).collect(),
+            })),
+            GGMLType::F16 => Ok(CpuTensorBuf::F16(match self {
+                CpuTensorBuf::F16(buf) => buf,
+                buf => quantize_f32_f16(buf.dequantize(GGMLType::F32)?.as_f32_ref()),
             })),
-            GGMLType::F16 => unimplemented!(),
             _ => unreachable!(),
         }
     }
 
+    /// quantize the f32 or f16 values into the dtype, like the activations into the dtype
+    /// which vec_dot with the weights.
     pub fn quantize(&self, dtype: GGMLType) -> Result<Self> {
+        let buf_f32 = match self {
+            CpuTensorBuf::F32(buf) => Cow::Borrowed(&buf[..]),
+            // the f16 activations are kept as they are on vec_dot with the f16 weights
+            CpuTensorBuf::F16(buf) if dtype == GGMLType::F16 => {
+                return Ok(CpuTensorBuf::F16(Cow::Owned(buf.to_vec())));
+            }
+            CpuTensorBuf::F16(buf) => Cow::Owned(dequantize_f16_buf(buf, 0).collect::<Vec<_>>()),
+            _ => {
+                return Err((
+                    ErrorKind::TensorError,
+                    format!("quantize from {:?} is not supported", self.dtype()),
+                )
+                    .into());
+            }
+        };
+        let buf_f32 = &buf_f32[..];
         match dtype {
-            GGMLType::F32 => Ok(CpuTensorBuf::F32(self.as_f32_ref().to_vec().into())),
-            GGMLType::F16 => Ok(CpuTensorBuf::F16(quantize_f32_f16(self.as_f32_ref()))),
-            GGMLType::Q2K => Ok(CpuTensorBuf::Q2K(QuantBufQ2K::quantize(self.as_f32_ref()))),
-            GGMLType::Q3K => Ok(CpuTensorBuf::Q3K(QuantBufQ3K::quantize(self.as_f32_ref()))),
-            GGMLType::Q8_0 => Ok(CpuTensorBuf::Q8_0(QuantBufQ8_0::quantize(
-                self.as_f32_ref(),
-            ))),
-            GGMLType::Q8_1 => Ok(CpuTensorBuf::Q8_1(QuantBufQ8_1::quantize(
-                self.as_f32_ref(),
-            ))),
-            GGMLType::Q8K => Ok(CpuTensorBuf::Q8K(QuantBufQ8K::quantize(self.as_f32_ref()))),
-            GGMLType::Q4_0 => Ok(CpuTensorBuf::Q4_0(QuantBufQ4_0::quantize(
-                self.as_f32_ref(),
-            ))),
-            GGMLType::Q4_1 => Ok(CpuTensorBuf::Q4_1(QuantBufQ4_1::quantize(
-                self.as_f32_ref(),
-            ))),
-            GGMLType::Q4K => Ok(CpuTensorBuf::Q4K(QuantBufQ4K::quantize(self.as_f32_ref()))),
-            GGMLType::Q5_1 => Ok(CpuTensorBuf::Q5_1(QuantBufQ5_1::quantize(
-                self.as_f32_ref(),
-            ))),
-            GGMLType::Q5_0 => Ok(CpuTensorBuf::Q5_0(QuantBufQ5_0::quantize(
-                self.as_f32_ref(),
-            ))),
-            GGMLType::Q5K => Ok(CpuTensorBuf::Q5K(QuantBufQ5K::quantize(self.as_f32_ref()))),
-            GGMLType::Q6K => Ok(CpuTensorBuf::Q6K(QuantBufQ6K::quantize(self.as_f32_ref()))),
+            GGMLType::F32 => Ok(CpuTensorBuf::F32(buf_f32.to_vec().into())),
+            GGMLType::F16 => Ok(CpuTensorBuf::F16(quantize_f32_f16(buf_f32))),
+            GGMLType::Q2K => Ok(CpuTensorBuf::Q2K(QuantBufQ2K::quantize(buf_f32))),
+            GGMLType::Q3K => Ok(CpuTensorBuf::Q3K(QuantBufQ3K::quantize(buf_f32))),
+            GGMLType::Q8_0 => Ok(CpuTensorBuf::Q8_0(QuantBufQ8_0::quantize(buf_f32))),
+            GGMLType::Q8_1 => Ok(CpuTensorBuf::Q8_1(QuantBufQ8_1::quantize(buf_f32))),
+            GGMLType::Q8K => Ok(CpuTensorBuf::Q8K(QuantBufQ8K::quantize(buf_f32))),
+            GGMLType::Q4_0 => Ok(CpuTensorBuf::Q4_0(QuantBufQ4_0::quantize(buf_f32))),
+            GGMLType::Q4_1 => Ok(CpuTensorBuf::Q4_1(QuantBufQ4_1::quantize(buf_f32))),
+            GGMLType::Q4K => Ok(CpuTensorBuf::Q4K(QuantBufQ4K::quantize(buf_f32))),
+            GGMLType::Q5_1 => Ok(CpuTensorBuf::Q5_1(QuantBufQ5_1::quantize(buf_f32))),
+            GGMLType::Q5_0 => Ok(CpuTensorBuf::Q5_0(QuantBufQ5_0::quantize(buf_f32))),
+            GGMLType::Q5K => Ok(CpuTensorBuf::Q5K(QuantBufQ5K::quantize(buf_f32))),
+            GGMLType::Q6K => Ok(CpuTensorBuf::Q6K(QuantBufQ6K::quantize(buf_f32))),
+            GGMLType::TQ2_0 => Ok(CpuTensorBuf::TQ2_0(QuantBufTQ2_0::quantize(buf_f32))),
             _ => Err((
                 ErrorKind::TensorError,
                 format!("quantize to {:?} is not supported", dtype),
@@ -226,10 +296,40 @@ impl<'a> CpuTensorBuf<'a> {
             (Q5_1(a), Q8_1(b)) => a.vec_dot(a_offset, b, b_offset, len),
             (Q5K(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
             (Q6K(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
+            (TQ2_0(a), Q8K(b)) => a.vec_dot(a_offset, b, b_offset, len),
             _ => unreachable!(),
         }
     }
 
+    /// dequantize the len values from offset into out, the offset should be at a block
+    /// boundary. each value is converted on its own, the result is the same on all platforms.
+    pub fn dequantize_row(&self, offset: usize, out: &mut [f32]) {
+        use CpuTensorBuf::*;
+        let len = out.len();
+        let values: Box<dyn Iterator<Item = f32> + '_> = match self {
+            F32(buf) => Box::new(buf[offset..offset + len].iter().copied()),
+            F16(buf) => Box::new(dequantize_f16_buf(buf, offset)),
+            I8(buf) => Box::new(buf[offset..offset + len].iter().map(|v| *v as f32)),
+            I32(buf) => Box::new(buf[offset..offset + len].iter().map(|v| *v as f32)),
+            Q2K(buf) => Box::new(buf.dequantize(offset)),
+            Q3K(buf) => Box::new(buf.dequantize(offset)),
+            Q8_0(buf) => Box::new(buf.dequantize(offset)),
+            Q8_1(buf) => Box::new(buf.dequantize(offset)),
+            Q8K(buf) => Box::new(buf.dequantize(offset)),
+            Q4_0(buf) => Box::new(buf.dequantize(offset)),
+            Q4_1(buf) => Box::new(buf.dequantize(offset)),
+            Q4K(buf) => Box::new(buf.dequantize(offset)),
+            Q5_0(buf) => Box::new(buf.dequantize(offset)),
+            Q5_1(buf) => Box::new(buf.dequantize(offset)),
+            Q5K(buf) => Box::new(buf.dequantize(offset)),
+            Q6K(buf) => Box::new(buf.dequantize(offset)),
+            TQ2_0(buf) => Box::new(buf.dequantize(offset)),
+        };
+        for (o, v) in out.iter_mut().zip(values) {
+            *o = v;
+        }
+    }
+
     pub fn extend(&mut self, iter: impl Iterator<Item = f32>) {
         match self {
             CpuTensorBuf::F32(Cow::Owned(buf)) => buf.extend(iter),
@@ -254,6 +354,25 @@ impl<'a> CpuTensorBuf<'a> {
             "only f32/f16 can be copied to"
         );
 
+        // the quantized blocks are dequantized right into the f16 destination, like the
+        // rows of a quantized token embedding looked up into the f16 activations
+        if let CpuTensorBuf::F16(Cow::Owned(dst)) = self {
+            if src_offset % 32 == 0 && len % 32 == 0 {
+                let dst = &mut dst[dst_offset..dst_offset + len];
+                match src {
+                    CpuTensorBuf::Q8_0(buf) => {
+                        buf.dequantize_f16(src_offset, dst);
+                        return Ok(());
+                    }
+                    CpuTensorBuf::Q4_0(buf) => {
+                        buf.dequantize_f16(src_offset, dst);
+                        return Ok(());
+                    }
+                    _ => {}
+                }
+            }
+        }
+
         match src {
             CpuTensorBuf::F32(buf) => {
                 self.copy_from_iter(buf.iter().skip(src_offset).cloned(), dst_offset, len)
@@ -261,6 +380,16 @@ impl<'a> CpuTensorBuf<'a> {
             CpuTensorBuf::F16(buf) => {
                 self.copy_from_iter(dequantize_f16_buf(buf, src_offset), dst_offset, len)
             }
+            CpuTensorBuf::I8(buf) => self.copy_from_iter(
+                buf.iter().skip(src_offset).map(|v| *v as f32),
+                dst_offset,
+                len,
+            ),
+            CpuTensorBuf::I32(buf) => self.copy_from_iter(
+                buf.iter().skip(src_offset).map(|v| *v as f32),
+                dst_offset,
+                len,
+            ),
             CpuTensorBuf::Q2K(buf) => {
                 self.copy_from_iter(buf.dequantize(src_offset), dst_offset, len)
             }
@@ -297,6 +426,9 @@ impl<'a> CpuTensorBuf<'a> {
             CpuTensorBuf::Q6K(buf) => {
                 self.copy_from_iter(buf.dequantize(src_offset), dst_offset, len)
             }
+            CpuTensorBuf::TQ2_0(buf) => {
+                self.copy_from_iter(buf.dequantize(src_offset), dst_offset, len)
+            }
         };
 
         Ok(())
@@ -349,11 +481,59 @@ impl<'a> CpuTensorBuf<'a> {
         }
     }
 
+    pub fn as_f16_ref(&self) -> &[f16] {
+        match self {
+            CpuTensorBuf::F16(buf) => buf,
+            _ => panic!("not f16, but got {:?}", self.dtype()),
+        }
+    }
+
+    pub fn as_f16_mut(&mut self) -> &mut [f16] {
+        match self {
+            CpuTensorBuf::F16(Cow::Owned(buf)) => buf,
+            _ => panic!(
+                "not owned f16, but got {:?}, owned: {}",
+                self.dtype(),
+                self.is_owned()
+            ),
+        }
+    }
+
+    /// the values of a f32 or f16 buffer in f32, borrowed if they're already in f32.
+    pub fn to_f32_cow(&self) -> Cow<'_, [f32]> {
+        match self {
+            CpuTensorBuf::F32(buf) => Cow::Borrowed(buf),
+            buf => Cow::Owned(buf.iter_f32().collect()),
+        }
+    }
+
+    pub fn as_i32_ref(&self) -> &[i32] {
+        match self {
+            CpuTensorBuf::I32(buf) => buf,
+            _ => panic!("not i32, but got {:?}", self.dtype()),
+        }
+    }
+
+    pub fn as_i8_ref(&self) -> &[i8] {
+        match self {
+            CpuTensorBuf::I8(buf) => buf,
+            _ => panic!("not i8, but got {:?}", self.dtype()),
+        }
+    }
+
     /// the quantized tensor can not be iterated directly. to iterate the quantized tensor,
-    /// use `dequantize` to convert it to f32/f16 tensor first.
+    /// use `dequantize` to convert it to f32/f16 tensor first. the f16 values are converted
+    /// into f32.
     pub fn iter_f32(&self) -> impl Iterator<Item = f32> + '_ {
-        // TODO: convert f16 to f32 here, to make debug easier.
-        self.as_f32_ref().iter().copied()
+        let (buf_f32, buf_f16) = match self {
+            CpuTensorBuf::F16(buf) => (None, Some(buf)),
+            _ => (Some(self.as_f32_ref()), None),
+        };
+        let iter_f32 = buf_f32.into_iter().flat_map(|buf| buf.iter().copied());
+        let iter_f16 = buf_f16
+            .into_iter()
+            .flat_map(|buf| buf.iter().map(|x| x.to_f32()));
+        iter_f32.chain(iter_f16)
     }
 
     pub fn iter_f32_mut(&mut self) -> impl Iterator<Item = &mut f32> {
@@ -366,6 +546,8 @@ impl Clone for CpuTensorBuf<'_> {
         match self {
             CpuTensorBuf::F32(buf) => Self::F32(buf.clone()),
             CpuTensorBuf::F16(buf) => Self::F16(buf.clone()),
+            CpuTensorBuf::I8(buf) => Self::I8(buf.clone()),
+            CpuTensorBuf::I32(buf) => Self::I32(buf.clone()),
             CpuTensorBuf::Q2K(buf) => Self::Q2K(buf.clone()),
             CpuTensorBuf::Q3K(buf) => Self::Q3K(buf.clone()),
             CpuTensorBuf::Q8_0(buf) => Self::Q8_0(buf.clone()),
@@ -376,9 +558,9 @@ impl Clone for CpuTensorBuf<'_> {
             CpuTensorBuf::Q4_0(buf) => Self::Q4_0(buf.clone()),
             CpuTensorBuf::Q4_1(buf) => Self::Q4_1(buf.clone()),
             CpuTensorBuf::Q4K(buf) => Self::Q4K(buf.clone()),
-            CpuTensorBuf::Q5_1(buf) => Self::Q5_1(buf.clone()),
             CpuTensorBuf::Q5K(buf) => Self::Q5K(buf.clone()),
             CpuTensorBuf::Q6K(buf) => Self::Q6K(buf.clone()),
+            CpuTensorBuf::TQ2_0(buf) => Self::TQ2_0(buf.clone()),
         }
     }
 }
@@ -394,3 +576,59 @@ impl<'a> From<&'a [f32]> for CpuTensorBuf<'a> {
         Self::F32(buf.into())
     }
 }
+
+// the blocks are repr(C) and read from the GGUF file as they are, so their memory is the
+// same as the bytes in the file
+fn slice_as_bytes<T>(s: &[T]) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(s.as_ptr() as *const u8, core::mem::size_of_val(s)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::cpu::buf::util::tests::array_rmse;
+    use crate::backends::cpu::buf::util::tests::dot_product;
+    use crate::backends::cpu::buf::util::tests::generate_data;
+
+    const TEST_SIZE: usize = 512;
+
+    #[test]
+    fn test_k_quants_roundtrip() -> Result<()> {
+        let data = generate_data(0.0, TEST_SIZE);
+        let rhs_data = generate_data(1.0, TEST_SIZE);
+        let buf_f32 = CpuTensorBuf::from(data.clone());
+        let rhs_f32 = CpuTensorBuf::from(rhs_data.clone());
+        let dot_ref = dot_product(&data, &rhs_data);
+
+        for typ in [
+            GGMLType::Q2K,
+            GGMLType::Q3K,
+            GGMLType::Q4K,
+            GGMLType::Q5K,
+            GGMLType::Q6K,
+        ] {
+            let buf = buf_f32.quantize(typ)?;
+            assert_eq!(buf.dtype(), typ);
+            assert!(buf.is_quantized());
+            assert_eq!(buf.len(), TEST_SIZE);
+            let bytes = buf.as_bytes();
+            assert_eq!(CpuTensorBuf::from_raw_bytes(bytes, typ)?.as_bytes(), bytes);
+
+            let rhs = rhs_f32.quantize(buf.vec_dot_rhs_dtype())?;
+            let dot = buf.vec_dot(0, &rhs, 0, TEST_SIZE);
+            let diff = (dot - dot_ref).abs() / TEST_SIZE as f32;
+            assert!(diff < 0.05, "{:?} vec_dot diff: {}", typ, diff);
+
+            let dequantized = buf.dequantize(GGMLType::F32)?;
+            let rmse = array_rmse(dequantized.as_f32_ref(), &data);
+            assert!(rmse < 0.02, "{:?} dequantize rmse: {}", typ, rmse);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_raw_bytes_unsupported() {
+        let buf = [0u8; 16];
+        assert!(CpuTensorBuf::from_raw_bytes(&buf, GGMLType::I16).is_err());
+    }
+}
@@ -1,16 +1,18 @@
-use std::borrow::Cow;
-use std::slice;
+use alloc::borrow::Cow;
+use core::slice;
 
 use half::f16;
 
+use crate::kernels::prelude::*;
+
 pub fn f16_buf_from_bytes<'a>(buf: &[u8]) -> Cow<'a, [f16]> {
     let len = buf.len();
     assert_eq!(
-        len % std::mem::size_of::<f32>(),
+        len % core::mem::size_of::<f32>(),
         0,
         "Length of slice must be multiple of f32 size"
     );
-    let new_len = len / std::mem::size_of::<f16>();
+    let new_len = len / core::mem::size_of::<f16>();
     let ptr = buf.as_ptr() as *const f16;
     let f16_buf = unsafe { slice::from_raw_parts(ptr, new_len) };
     f16_buf.into()
@@ -27,6 +29,14 @@ pub fn alloc_f16_buf(len: usize) -> Vec<f16> {
     buf
 }
 
+/// like alloc_f16_buf, but returns None instead of aborting when the allocation fails.
+pub fn try_alloc_f16_buf(len: usize) -> Option<Vec<f16>> {
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(len).ok()?;
+    unsafe { buf.set_len(len) };
+    Some(buf)
+}
+
 pub fn dequantize_f16_buf(buf: &[f16], start: usize) -> impl Iterator<Item = f32> + '_ {
     buf.iter().skip(start).map(|x| x.to_f32())
 }
@@ -174,7 +184,7 @@ pub fn vec_convert_f16_f32(dst: &mut [f16], src: &[f32]) {
 
 #[cfg(target_arch = "aarch64")]
 pub fn vec_convert_f16_f32_neon(dst: &mut [f16], src: &[f32]) {
-    use std::arch::aarch64;
+    use core::arch::aarch64;
 
     use crate::backends::cpu::arch::aarch64 as myaarch64;
 
@@ -183,10 +193,15 @@ pub fn vec_convert_f16_f32_neon(dst: &mut [f16], src: &[f32]) {
         .for_each(|(chunk_dst, chunk_src)| unsafe {
             let dst_ptr = chunk_dst.as_mut_ptr();
             let src_ptr = chunk_src.as_ptr();
-            let src = std::arch::aarch64::vld1q_f32(src_ptr);
+            let src = core::arch::aarch64::vld1q_f32(src_ptr);
             let dst = myaarch64::vcvt_f32_f16(src);
             aarch64::vst1_u16(dst_ptr as *mut u16, dst as aarch64::uint16x4_t);
-        })
+        });
+    let tail = src.len().min(dst.len()) / 4 * 4;
+    dst[tail..]
+        .iter_mut()
+        .zip(src[tail..].iter())
+        .for_each(|(d, s)| *d = f16::from_f32(*s));
 }
 
 #[cfg(target_arch = "aarch64")]
@@ -1,16 +1,14 @@
-use std::borrow::Cow;
-use std::slice;
-
-use half::f16;
+use alloc::borrow::Cow;
+use core::slice;
 
 pub fn f32_buf_from_bytes<'a>(buf: &[u8]) -> Cow<'a, [f32]> {
     let len = buf.len();
     assert_eq!(
-        len % std::mem::size_of::<f32>(),
+        len % core::mem::size_of::<f32>(),
         0,
         "Length of slice must be multiple of f32 size"
     );
-    let new_len = len / std::mem::size_of::<f32>();
+    let new_len = len / core::mem::size_of::<f32>();
     let ptr = buf.as_ptr() as *const f32;
     let f32_buf = unsafe { slice::from_raw_parts(ptr, new_len) };
     f32_buf.into()
@@ -26,12 +24,25 @@ pub fn vec_dot_f32_f32(a: &[f32], a_offset: usize, b: &[f32], b_offset: usize, l
     sum
 }
 
-pub fn exp_f32_cached(x: f32, cache: &[f16]) -> f32 {
-    let cache_ptr = cache.as_ptr();
-    let x16 = f16::from_f32(x);
-    let x16n = x16.to_bits();
-
-    unsafe { (*cache_ptr.add(x16n as usize)).to_f32() }
+/// the dot product in a fixed order without fma: 8 partial sums over the strided lanes, added
+/// up pairwise, then the tail. it gives the same bits on all the platforms and simd widths, the
+/// compiler may vectorize it but never reorders the float additions.
+pub fn vec_dot_f32_f32_strict(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len());
+    let mut lanes = [0.0f32; 8];
+    let (a_chunks, a_tail) = a.as_chunks::<8>();
+    let (b_chunks, b_tail) = b.as_chunks::<8>();
+    for (ac, bc) in a_chunks.iter().zip(b_chunks) {
+        for i in 0..8 {
+            lanes[i] += ac[i] * bc[i];
+        }
+    }
+    let mut sum = ((lanes[0] + lanes[1]) + (lanes[2] + lanes[3]))
+        + ((lanes[4] + lanes[5]) + (lanes[6] + lanes[7]));
+    for (x, y) in a_tail.iter().zip(b_tail) {
+        sum += x * y;
+    }
+    sum
 }
 
 /// vec_dot_f32_f32_strided is called in batch_matmul_vec, which is used in the computation of the
@@ -95,7 +106,7 @@ fn vec_dot_f32_f32_strided_simd(
     k: usize,
     b: &[f32],
 ) -> f32 {
-    use std::arch::aarch64;
+    use core::arch::aarch64;
 
     unsafe {
         let a_ptr = a.as_ptr().add(a_base);
@@ -139,7 +150,7 @@ fn vec_dot_f32_f32_strided_simd(
     k: usize,
     b: &[f32],
 ) -> f32 {
-    use std::arch::x86_64::*;
+    use core::arch::x86_64::*;
 
     unsafe {
         let a_ptr = a.as_ptr().add(a_base);
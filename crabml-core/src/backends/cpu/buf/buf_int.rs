@@ -0,0 +1,23 @@
+use alloc::borrow::Cow;
+use core::slice;
+
+/// the integer buffers hold the token ids, the positions and the masks, they're not
+/// quantized and never take part in the matmuls.
+pub fn i32_buf_from_bytes<'a>(buf: &[u8]) -> Cow<'a, [i32]> {
+    let len = buf.len();
+    assert_eq!(
+        len % core::mem::size_of::<i32>(),
+        0,
+        "Length of slice must be multiple of i32 size"
+    );
+    let new_len = len / core::mem::size_of::<i32>();
+    let ptr = buf.as_ptr() as *const i32;
+    let i32_buf = unsafe { slice::from_raw_parts(ptr, new_len) };
+    i32_buf.into()
+}
+
+pub fn i8_buf_from_bytes<'a>(buf: &[u8]) -> Cow<'a, [i8]> {
+    let ptr = buf.as_ptr() as *const i8;
+    let i8_buf = unsafe { slice::from_raw_parts(ptr, buf.len()) };
+    i8_buf.into()
+}
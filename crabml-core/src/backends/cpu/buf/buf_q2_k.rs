@@ -1,4 +1,4 @@
-use std::borrow::Cow;
+use alloc::borrow::Cow;
 
 use half::f16;
 
@@ -6,6 +6,7 @@ use self::impl_fallback::quantize_f32_q2_k;
 use self::impl_fallback::vec_dot_q2_k_q8_k;
 use super::QuantBufQ8K;
 use crate::backends::cpu::buf::util::*;
+use crate::kernels::prelude::*;
 
 /// A q2_k super block of 2-bit quantization
 ///
@@ -87,13 +88,13 @@ pub struct QuantBufQ2K<'a> {
 
 impl<'a> QuantBufQ2K<'a> {
     pub fn from_bytes(data: &'a [u8]) -> Self {
-        let blk_size = std::mem::size_of::<BlockQ2K>();
+        let blk_size = core::mem::size_of::<BlockQ2K>();
         assert!(
             data.len() % blk_size == 0,
             "data length must be a multiple of BlockQ2K size"
         );
         let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ2K, data.len() / blk_size)
+            core::slice::from_raw_parts(data.as_ptr() as *const BlockQ2K, data.len() / blk_size)
         };
         Self {
             blocks: blocks.into(),
@@ -192,7 +193,7 @@ mod impl_fallback {
                 }
                 let dm = Into::<f32>::into(bs[i].dmin) * (block_scale >> 4) as f32;
                 for ii in 0..16 {
-                    let _l = nearest_i32((data[16 * j + ii] + dm) / d);
+                    let _l = nearest_i32((data_chunk[16 * j + ii] + dm) / d);
                     let _l = 0.max(3.min(_l));
                     l[16 * j + ii] = _l as u8;
                 }
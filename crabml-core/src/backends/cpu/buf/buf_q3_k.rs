@@ -1,5 +1,5 @@
-use std::borrow::Cow;
-use std::ptr;
+use alloc::borrow::Cow;
+use core::ptr;
 
 use half::f16;
 
@@ -8,6 +8,7 @@ use self::impl_fallback::vec_dot_q3_k_q8_k;
 use crate::backends::cpu::buf::buf_q8_k::BlockQ8K;
 use crate::backends::cpu::buf::buf_q8_k::QuantBufQ8K;
 use crate::backends::cpu::buf::util::*;
+use crate::kernels::prelude::*;
 
 /// A q3_k super block of 3-bit quantization
 ///
@@ -53,7 +54,7 @@ impl BlockQ3K {
         aux[0] = (aux[0] & KMASK_2) | (((tmp) & KMASK_1) << 4);
         aux[1] = (aux[1] & KMASK_2) | (((tmp >> 2) & KMASK_1) << 4);
         let scales: &[i8] =
-            unsafe { std::slice::from_raw_parts(&aux as *const [u32] as *const i8, 16) };
+            unsafe { core::slice::from_raw_parts(&aux as *const [u32] as *const i8, 16) };
 
         let mut qs_i: usize = 0; // self.qs index
         let mut buf_i = 0; // buf index
@@ -102,13 +103,13 @@ pub struct QuantBufQ3K<'a> {
 
 impl<'a> QuantBufQ3K<'a> {
     pub fn from_bytes(data: &'a [u8]) -> Self {
-        let blk_size = std::mem::size_of::<BlockQ3K>();
+        let blk_size = core::mem::size_of::<BlockQ3K>();
         assert!(
             data.len() % blk_size == 0,
             "data length must be a multiple of BlockQ3K size"
         );
         let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ3K, data.len() / blk_size)
+            core::slice::from_raw_parts(data.as_ptr() as *const BlockQ3K, data.len() / blk_size)
         };
         Self {
             blocks: blocks.into(),
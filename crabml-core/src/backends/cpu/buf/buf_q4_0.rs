@@ -1,14 +1,16 @@
-use std::borrow::Cow;
+use alloc::borrow::Cow;
 
 use half::f16;
 
+#[cfg(target_arch = "x86_64")]
+use super::buf_q8_0::BlockQ8_0;
 use super::QuantBufQ8_0;
 
 #[repr(C, packed)]
 #[derive(Debug, Clone)]
 pub struct BlockQ4_0 {
-    d: f16,       // delta
-    qs: [u8; 16], // quants
+    pub(crate) d: f16,       // delta
+    pub(crate) qs: [u8; 16], // quants
 }
 
 impl BlockQ4_0 {
@@ -22,6 +24,17 @@ impl BlockQ4_0 {
             buf[i + 16] = (x1 as f32) * d;
         }
     }
+
+    pub fn dequantize_f16(&self, buf: &mut [f16]) {
+        let d = self.d.to_f32();
+        for i in 0..16 {
+            let x0 = (self.qs[i] & 0x0F) as i16 - 8;
+            let x1 = (self.qs[i] >> 4) as i16 - 8;
+
+            buf[i] = f16::from_f32((x0 as f32) * d);
+            buf[i + 16] = f16::from_f32((x1 as f32) * d);
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -31,14 +44,14 @@ pub struct QuantBufQ4_0<'a> {
 
 impl<'a> QuantBufQ4_0<'_> {
     pub fn from_bytes(data: &'a [u8]) -> Self {
-        let blk_size = std::mem::size_of::<BlockQ4_0>();
+        let blk_size = core::mem::size_of::<BlockQ4_0>();
         assert_eq!(
             data.len() % blk_size,
             0,
             "data length must be a multiple of QuantBlockQ4_0 size"
         );
         let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ4_0, data.len() / blk_size)
+            core::slice::from_raw_parts(data.as_ptr() as *const BlockQ4_0, data.len() / blk_size)
         };
         Self {
             blocks: blocks.into(),
@@ -72,6 +85,16 @@ impl<'a> QuantBufQ4_0<'_> {
         })
     }
 
+    /// dequantize the blocks from start into f16 block by block, without going through a
+    /// buffer of f32.
+    pub fn dequantize_f16(&self, start: usize, dst: &mut [f16]) {
+        assert_eq!(start % 32, 0);
+        assert_eq!(dst.len() % 32, 0);
+
+        let blocks = &self.blocks()[start / 32..(start + dst.len()) / 32];
+        dequantize_q4_0_f16(blocks, dst)
+    }
+
     pub fn vec_dot(&self, a_offset: usize, b: &QuantBufQ8_0, b_offset: usize, len: usize) -> f32 {
         let abs = &self.blocks[a_offset / 32..(a_offset + len) / 32];
         let bbs = &b.blocks[b_offset / 32..(b_offset + len) / 32];
@@ -80,11 +103,226 @@ impl<'a> QuantBufQ4_0<'_> {
     }
 }
 
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+mod impl_aarch64_neon {
+    use core::arch::aarch64;
+
+    use half::f16;
+
+    use super::BlockQ4_0;
+    use crate::backends::cpu::buf::buf_q8_0::BlockQ8_0;
+
+    pub fn vec_dot_q4_0_q8_0(abs: &[BlockQ4_0], bbs: &[BlockQ8_0]) -> f32 {
+        assert!(abs.len() == bbs.len());
+
+        unsafe {
+            let mut sumv0 = aarch64::vdupq_n_f32(0.0);
+            let mut sumv1 = aarch64::vdupq_n_f32(0.0);
+
+            let mut i = 0;
+            while i + 1 < bbs.len() {
+                let ab0 = abs.get_unchecked(i);
+                let ab1 = abs.get_unchecked(i + 1);
+                let bb0 = bbs.get_unchecked(i);
+                let bb1 = bbs.get_unchecked(i + 1);
+
+                sumv0 = aarch64::vmlaq_n_f32(
+                    sumv0,
+                    aarch64::vcvtq_f32_s32(vec_dot_block(ab0, bb0)),
+                    f16::to_f32(ab0.d) * f16::to_f32(bb0.d),
+                );
+                sumv1 = aarch64::vmlaq_n_f32(
+                    sumv1,
+                    aarch64::vcvtq_f32_s32(vec_dot_block(ab1, bb1)),
+                    f16::to_f32(ab1.d) * f16::to_f32(bb1.d),
+                );
+                i += 2;
+            }
+
+            if i < bbs.len() {
+                let ab0 = abs.get_unchecked(i);
+                let bb0 = bbs.get_unchecked(i);
+                sumv0 = aarch64::vmlaq_n_f32(
+                    sumv0,
+                    aarch64::vcvtq_f32_s32(vec_dot_block(ab0, bb0)),
+                    f16::to_f32(ab0.d) * f16::to_f32(bb0.d),
+                );
+            }
+
+            aarch64::vaddvq_f32(sumv0) + aarch64::vaddvq_f32(sumv1)
+        }
+    }
+
+    // the integer dot product of a block, the low nibbles are the first 16 quants and the
+    // high nibbles are the last 16 quants, both are offset by 8.
+    #[inline(always)]
+    unsafe fn vec_dot_block(ab: &BlockQ4_0, bb: &BlockQ8_0) -> aarch64::int32x4_t {
+        let zerov = aarch64::vdupq_n_s32(0);
+        let m4b = aarch64::vdupq_n_u8(0x0F);
+        let s8b = aarch64::vdupq_n_s8(0x8);
+
+        let av = aarch64::vld1q_u8(ab.qs.as_ptr());
+        let avl = aarch64::vsubq_s8(
+            aarch64::vreinterpretq_s8_u8(aarch64::vandq_u8(av, m4b)),
+            s8b,
+        );
+        let avh = aarch64::vsubq_s8(
+            aarch64::vreinterpretq_s8_u8(aarch64::vshrq_n_u8(av, 4)),
+            s8b,
+        );
+
+        let bvl = aarch64::vld1q_s8(bb.qs.as_ptr());
+        let bvh = aarch64::vld1q_s8(bb.qs.as_ptr().add(16));
+
+        aarch64::vaddq_s32(
+            aarch64::vdotq_s32(zerov, avl, bvl),
+            aarch64::vdotq_s32(zerov, avh, bvh),
+        )
+    }
+}
+
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+use impl_aarch64_neon::*;
+
+#[cfg(target_arch = "x86_64")]
+mod impl_x86_64_avx2 {
+    use core::arch::x86_64::*;
+
+    use half::f16;
+
+    use super::BlockQ4_0;
+    use crate::backends::cpu::buf::buf_q8_0::BlockQ8_0;
+    use crate::backends::cpu::buf::simd_x86::bytes_from_nibbles_32;
+    use crate::backends::cpu::buf::simd_x86::hsum_float_8;
+    use crate::backends::cpu::buf::simd_x86::mul_sum_i8_pairs_float;
+    use crate::backends::cpu::buf::simd_x86::store_f16_8;
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn vec_dot_q4_0_q8_0(abs: &[BlockQ4_0], bbs: &[BlockQ8_0]) -> f32 {
+        debug_assert_eq!(abs.len(), bbs.len());
+
+        let mut acc = _mm256_setzero_ps();
+        let off = _mm256_set1_epi8(8);
+
+        for (a, b) in abs.iter().zip(bbs) {
+            let d = _mm256_set1_ps(a.d.to_f32() * b.d.to_f32());
+
+            // shift the nibbles from [0, 15] to [-8, 7]
+            let qa = _mm256_sub_epi8(bytes_from_nibbles_32(a.qs.as_ptr()), off);
+            let qb = _mm256_loadu_si256(b.qs.as_ptr() as *const __m256i);
+
+            let q = mul_sum_i8_pairs_float(qa, qb);
+            acc = _mm256_fmadd_ps(d, q, acc);
+        }
+
+        hsum_float_8(acc)
+    }
+
+    #[target_feature(enable = "avx2,f16c")]
+    pub unsafe fn dequantize_q4_0_f16(bs: &[BlockQ4_0], dst: &mut [f16]) {
+        let off = _mm256_set1_epi8(8);
+        for (b, out) in bs.iter().zip(dst.chunks_exact_mut(32)) {
+            let d = _mm256_set1_ps(b.d.to_f32());
+            let q = _mm256_sub_epi8(bytes_from_nibbles_32(b.qs.as_ptr()), off);
+            for (i, half) in [_mm256_castsi256_si128(q), _mm256_extracti128_si256::<1>(q)]
+                .into_iter()
+                .enumerate()
+            {
+                for (j, q) in [half, _mm_srli_si128::<8>(half)].into_iter().enumerate() {
+                    let v = _mm256_mul_ps(_mm256_cvtepi32_ps(_mm256_cvtepi8_epi32(q)), d);
+                    store_f16_8(out.as_mut_ptr().add(i * 16 + j * 8), v);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod impl_x86_64_avx512_vnni {
+    use core::arch::x86_64::*;
+
+    use super::BlockQ4_0;
+    use crate::backends::cpu::buf::buf_q8_0::BlockQ8_0;
+    use crate::backends::cpu::buf::simd_x86::bytes_from_nibbles_32;
+    use crate::backends::cpu::buf::simd_x86::join_256x2;
+    use crate::backends::cpu::buf::simd_x86::scales_2x8;
+
+    /// the nibbles are unsigned already, so they feed `vpdpbusd` directly:
+    /// sum((n - 8) * y) = sum(n * y) - 8 * sum(y).
+    #[target_feature(enable = "avx512f,avx512bw,avx512vnni")]
+    pub unsafe fn vec_dot_q4_0_q8_0(abs: &[BlockQ4_0], bbs: &[BlockQ8_0]) -> f32 {
+        debug_assert_eq!(abs.len(), bbs.len());
+
+        let mut acc = _mm512_setzero_ps();
+        let zero = _mm512_setzero_si512();
+        let ones = _mm512_set1_epi8(1);
+
+        for [(abs0, bbs0), (abs1, bbs1)] in abs.iter().zip(bbs).array_chunks::<2>() {
+            let d = scales_2x8(
+                abs0.d.to_f32() * bbs0.d.to_f32(),
+                abs1.d.to_f32() * bbs1.d.to_f32(),
+            );
+
+            let qa = join_256x2(
+                bytes_from_nibbles_32(abs0.qs.as_ptr()),
+                bytes_from_nibbles_32(abs1.qs.as_ptr()),
+            );
+            let qb = join_256x2(
+                _mm256_loadu_si256(bbs0.qs.as_ptr() as *const __m256i),
+                _mm256_loadu_si256(bbs1.qs.as_ptr() as *const __m256i),
+            );
+
+            let dot = _mm512_dpbusd_epi32(zero, qa, qb);
+            let sum_b = _mm512_dpbusd_epi32(zero, ones, qb);
+            let q = _mm512_cvtepi32_ps(_mm512_sub_epi32(dot, _mm512_slli_epi32::<3>(sum_b)));
+            acc = _mm512_fmadd_ps(d, q, acc);
+        }
+
+        let mut sum = _mm512_reduce_add_ps(acc);
+        if abs.len() % 2 == 1 {
+            let n = abs.len();
+            sum += super::impl_x86_64_avx2::vec_dot_q4_0_q8_0(&abs[n - 1..], &bbs[n - 1..]);
+        }
+        sum
+    }
+}
+
+/// picks the kernel for the running CPU, see `simd_x86`.
+#[cfg(target_arch = "x86_64")]
+fn vec_dot_q4_0_q8_0(abs: &[BlockQ4_0], bbs: &[BlockQ8_0]) -> f32 {
+    use super::simd_x86::x86_simd;
+    use super::simd_x86::X86Simd;
+
+    match x86_simd() {
+        X86Simd::Avx512Vnni => unsafe { impl_x86_64_avx512_vnni::vec_dot_q4_0_q8_0(abs, bbs) },
+        X86Simd::Avx2 => unsafe { impl_x86_64_avx2::vec_dot_q4_0_q8_0(abs, bbs) },
+        X86Simd::Scalar => impl_fallback::vec_dot_q4_0_q8_0(abs, bbs),
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn dequantize_q4_0_f16(bs: &[BlockQ4_0], dst: &mut [f16]) {
+    if super::simd_x86::has_f16c() {
+        return unsafe { impl_x86_64_avx2::dequantize_q4_0_f16(bs, dst) };
+    }
+    for (b, out) in bs.iter().zip(dst.chunks_exact_mut(32)) {
+        b.dequantize_f16(out);
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn dequantize_q4_0_f16(bs: &[BlockQ4_0], dst: &mut [f16]) {
+    for (b, out) in bs.iter().zip(dst.chunks_exact_mut(32)) {
+        b.dequantize_f16(out);
+    }
+}
+
 mod impl_fallback {
     use half::f16;
 
     use super::BlockQ4_0;
     use crate::backends::cpu::buf::buf_q8_0::BlockQ8_0;
+    use crate::kernels::prelude::*;
     pub fn quantize_f32_q4_0(data: &[f32]) -> Vec<BlockQ4_0> {
         let mut bs = Vec::with_capacity(data.len() / 32);
 
@@ -121,6 +359,7 @@ mod impl_fallback {
         bs
     }
 
+    #[cfg(not(all(target_arch = "aarch64", target_feature = "neon")))]
     pub fn vec_dot_q4_0_q8_0(abs: &[BlockQ4_0], bbs: &[BlockQ8_0]) -> f32 {
         let mut sumf: f32 = 0f32;
         for i in 0..bbs.len() {
@@ -136,7 +375,12 @@ mod impl_fallback {
         sumf
     }
 }
-use impl_fallback::*;
+use impl_fallback::quantize_f32_q4_0;
+#[cfg(not(any(
+    all(target_arch = "aarch64", target_feature = "neon"),
+    target_arch = "x86_64"
+)))]
+use impl_fallback::vec_dot_q4_0_q8_0;
 
 #[cfg(test)]
 mod tests {
@@ -145,8 +389,8 @@ mod tests {
     #[test]
     fn test_q4_0_block() {
         assert_eq!(
-            std::mem::size_of::<BlockQ4_0>(),
-            std::mem::size_of::<f16>() + 16,
+            core::mem::size_of::<BlockQ4_0>(),
+            core::mem::size_of::<f16>() + 16,
             "wrong q4_0 block size/padding"
         );
 
@@ -182,4 +426,111 @@ mod tests {
             -24.0, -24.0, -24.0, -24.0
         ]);
     }
+
+    #[test]
+    fn test_vec_dot_q4_0_q8_0() {
+        // odd and even numbers of blocks take different paths in the simd kernels
+        for n_blocks in [1, 2, 3, 8] {
+            let len = n_blocks * 32;
+            let a = (0..len)
+                .map(|i| ((i * 7 + 3) % 19) as f32 - 9.0)
+                .collect::<Vec<_>>();
+            let b = (0..len)
+                .map(|i| ((i * 5 + 1) % 23) as f32 * 0.25 - 2.5)
+                .collect::<Vec<_>>();
+            let qa = QuantBufQ4_0::quantize(&a);
+            let qb = QuantBufQ8_0::quantize(&b);
+
+            // the naive dot product on the dequantized values
+            let expected = qa
+                .dequantize(0)
+                .zip(qb.dequantize(0))
+                .map(|(x, y)| x * y)
+                .sum::<f32>();
+            let got = qa.vec_dot(0, &qb, 0, len);
+            assert!(
+                (got - expected).abs() <= expected.abs() * 1e-4 + 1e-3,
+                "n_blocks: {}, got: {}, expected: {}",
+                n_blocks,
+                got,
+                expected
+            );
+
+            // with offsets
+            if n_blocks > 1 {
+                let expected = qa
+                    .dequantize(32)
+                    .zip(qb.dequantize(32))
+                    .map(|(x, y)| x * y)
+                    .sum::<f32>();
+                let got = qa.vec_dot(32, &qb, 32, len - 32);
+                assert!((got - expected).abs() <= expected.abs() * 1e-4 + 1e-3);
+            }
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_vec_dot_q4_0_q8_0_x86_kernels() {
+        use crate::backends::cpu::buf::simd_x86::X86Simd;
+
+        for n_blocks in [1, 2, 3, 8, 17] {
+            let len = n_blocks * 32;
+            let a = (0..len)
+                .map(|i| ((i * 13 + 5) % 31) as f32 * 0.3 - 4.5)
+                .collect::<Vec<_>>();
+            let b = (0..len)
+                .map(|i| ((i * 11 + 7) % 29) as f32 * 0.2 - 2.8)
+                .collect::<Vec<_>>();
+            let qa = QuantBufQ4_0::quantize(&a);
+            let qb = QuantBufQ8_0::quantize(&b);
+
+            let expected = impl_fallback::vec_dot_q4_0_q8_0(&qa.blocks, &qb.blocks);
+            for simd in X86Simd::available() {
+                let got = unsafe {
+                    match simd {
+                        X86Simd::Scalar => impl_fallback::vec_dot_q4_0_q8_0(&qa.blocks, &qb.blocks),
+                        X86Simd::Avx2 => {
+                            impl_x86_64_avx2::vec_dot_q4_0_q8_0(&qa.blocks, &qb.blocks)
+                        }
+                        X86Simd::Avx512Vnni => {
+                            impl_x86_64_avx512_vnni::vec_dot_q4_0_q8_0(&qa.blocks, &qb.blocks)
+                        }
+                    }
+                };
+                // the integer dot products are exact, only the f32 accumulation order differs
+                assert!(
+                    (got - expected).abs() <= expected.abs() * 1e-5 + 1e-4,
+                    "{:?} n_blocks: {}, got: {}, expected: {}",
+                    simd,
+                    n_blocks,
+                    got,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_dequantize_q4_0_f16() {
+        let len = 5 * 32;
+        let data = (0..len)
+            .map(|i| ((i * 11 + 7) % 29) as f32 * 0.37 - 5.0)
+            .collect::<Vec<_>>();
+        let qb = QuantBufQ4_0::quantize(&data);
+
+        // the simd kernel rounds to the nearest like f16::from_f32 on the f32 values
+        let expected = qb.dequantize(0).map(f16::from_f32).collect::<Vec<_>>();
+        let mut got = vec![f16::ZERO; len];
+        qb.dequantize_f16(0, &mut got);
+        assert_eq!(got, expected);
+
+        let mut got = vec![f16::ZERO; len - 64];
+        qb.dequantize_f16(32, &mut got);
+        assert_eq!(got, expected[32..len - 32]);
+
+        let mut got = vec![f16::ZERO; 32];
+        qb.blocks[1].dequantize_f16(&mut got);
+        assert_eq!(got, expected[32..64]);
+    }
 }
@@ -1,4 +1,4 @@
-use std::borrow::Cow;
+use alloc::borrow::Cow;
 
 use half::f16;
 
@@ -33,14 +33,14 @@ pub struct QuantBufQ4_1<'a> {
 
 impl<'a> QuantBufQ4_1<'a> {
     pub fn from_bytes(data: &'a [u8]) -> Self {
-        let blk_size = std::mem::size_of::<BlockQ4_1>();
+        let blk_size = core::mem::size_of::<BlockQ4_1>();
         assert_eq!(
             data.len() % blk_size,
             0,
             "data length must be a multiple of QuantBlockQ8_0 size"
         );
         let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ4_1, data.len() / blk_size)
+            core::slice::from_raw_parts(data.as_ptr() as *const BlockQ4_1, data.len() / blk_size)
         };
         Self {
             blocks: blocks.into(),
@@ -88,6 +88,7 @@ mod impl_fallback {
 
     use super::BlockQ4_1;
     use crate::backends::cpu::buf::buf_q8_1::BlockQ8_1;
+    use crate::kernels::prelude::*;
     pub fn quantize_f32_q4_1(data: &[f32]) -> Vec<BlockQ4_1> {
         let mut bs = Vec::with_capacity(data.len() / 32);
         for chunk in data.chunks(32) {
@@ -145,8 +146,8 @@ mod tests {
     #[test]
     fn test_q4_1_block() {
         assert_eq!(
-            std::mem::size_of::<BlockQ4_1>(),
-            2 * std::mem::size_of::<f16>() + 16,
+            core::mem::size_of::<BlockQ4_1>(),
+            2 * core::mem::size_of::<f16>() + 16,
             "wrong q4_1 block size/padding"
         );
 
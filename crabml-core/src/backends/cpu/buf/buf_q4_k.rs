@@ -1,4 +1,4 @@
-use std::borrow::Cow;
+use alloc::borrow::Cow;
 
 use half::f16;
 
@@ -27,14 +27,10 @@ impl BlockQ4K {
         for (qs_chunk, buf_chunk) in self.qs.chunks(32).zip(buf.chunks_mut(64)) {
             get_scale_min_k4(is, &self.scales, &mut sc, &mut m);
             let d1 = d * sc as f32;
-            println!("{d}, {sc}, {d1}");
             let m1 = min * m as f32;
             get_scale_min_k4(is + 1, &self.scales, &mut sc, &mut m);
             let d2 = d * sc as f32;
             let m2 = min * m as f32;
-            // println!("{:?}", self.scales);
-            // println!("{d1}, {m1}, {d2}, {m2}");
-            // println!("{:?}", qs_chunk);
             for l in 0..32 {
                 buf_chunk[l] = d1 * (qs_chunk[l] & 0xF) as f32 - m1;
                 buf_chunk[l + 32] = d2 * (qs_chunk[l] >> 4) as f32 - m2;
@@ -51,14 +47,14 @@ pub struct QuantBufQ4K<'a> {
 
 impl<'a> QuantBufQ4K<'_> {
     pub fn from_bytes(data: &'a [u8]) -> Self {
-        let blk_size = std::mem::size_of::<BlockQ4K>();
+        let blk_size = core::mem::size_of::<BlockQ4K>();
         assert_eq!(
             data.len() % blk_size,
             0,
             "data length must be a multiple of QuantBlockQ4_K size"
         );
         let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ4K, data.len() / blk_size)
+            core::slice::from_raw_parts(data.as_ptr() as *const BlockQ4K, data.len() / blk_size)
         };
         Self {
             blocks: blocks.into(),
@@ -110,6 +106,7 @@ mod impl_fallback {
     use crate::backends::cpu::buf::util::make_qkx1_quants;
     use crate::backends::cpu::buf::util::nearest_i32;
     use crate::backends::cpu::buf::util::QK_K;
+    use crate::kernels::prelude::*;
 
     pub fn quantize_f32_q4_k(data: &[f32]) -> Vec<BlockQ4K> {
         assert!(data.len() % QK_K == 0);
@@ -1,4 +1,4 @@
-use std::borrow::Cow;
+use alloc::borrow::Cow;
 
 use byteorder::ByteOrder;
 use byteorder::LittleEndian;
@@ -39,14 +39,14 @@ pub struct QuantBufQ5_0<'a> {
 
 impl<'a> QuantBufQ5_0<'_> {
     pub fn from_bytes(data: &'a [u8]) -> Self {
-        let blk_size = std::mem::size_of::<BlockQ5_0>();
+        let blk_size = core::mem::size_of::<BlockQ5_0>();
         assert_eq!(
             data.len() % blk_size,
             0,
             "data length must be a multiple of QuantBlockQ5_0 size"
         );
         let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ5_0, data.len() / blk_size)
+            core::slice::from_raw_parts(data.as_ptr() as *const BlockQ5_0, data.len() / blk_size)
         };
         Self {
             blocks: blocks.into(),
@@ -89,7 +89,7 @@ impl<'a> QuantBufQ5_0<'_> {
 }
 
 mod impl_fallback {
-    use std::cmp;
+    use core::cmp;
 
     use byteorder::ByteOrder;
     use byteorder::LittleEndian;
@@ -97,6 +97,7 @@ mod impl_fallback {
 
     use super::BlockQ5_0;
     use crate::backends::cpu::buf::buf_q8_0::BlockQ8_0;
+    use crate::kernels::prelude::*;
     pub fn quantize_f32_q5_0(data: &[f32]) -> Vec<BlockQ5_0> {
         let mut bs = Vec::with_capacity(data.len() / 32);
         for chunk in data.chunks(32) {
@@ -178,8 +179,8 @@ mod tests {
     #[test]
     fn test_q5_0_block() {
         assert_eq!(
-            std::mem::size_of::<BlockQ5_0>(),
-            std::mem::size_of::<f16>() + 4 + 16,
+            core::mem::size_of::<BlockQ5_0>(),
+            core::mem::size_of::<f16>() + 4 + 16,
             "wrong q5_0 block size/padding"
         );
 
@@ -1,4 +1,4 @@
-use std::borrow::Cow;
+use alloc::borrow::Cow;
 
 use super::util::get_scale_min_k4;
 use super::util::QK_K;
@@ -45,7 +45,6 @@ impl BlockQ5K {
                     * ((qs_chunk[l] >> 4) as f32 + if self.qh[l] & u2 != 0 { 16.0 } else { 0.0 })
                     - m2;
             }
-            println!("{:?}", buf_chunk);
             is += 2;
             u1 <<= 2;
             u2 <<= 2;
@@ -60,14 +59,14 @@ pub struct QuantBufQ5K<'a> {
 
 impl<'a> QuantBufQ5K<'_> {
     pub fn from_bytes(data: &'a [u8]) -> Self {
-        let blk_size = std::mem::size_of::<BlockQ5K>();
+        let blk_size = core::mem::size_of::<BlockQ5K>();
         assert_eq!(
             data.len() % blk_size,
             0,
             "data length must be a multiple of QuantBlockQ5_K size"
         );
         let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ5K, data.len() / blk_size)
+            core::slice::from_raw_parts(data.as_ptr() as *const BlockQ5K, data.len() / blk_size)
         };
         Self {
             blocks: blocks.into(),
@@ -118,6 +117,7 @@ mod impl_fallback {
     use crate::backends::cpu::buf::util::make_qkx1_quants;
     use crate::backends::cpu::buf::util::nearest_i32;
     use crate::backends::cpu::buf::util::QK_K;
+    use crate::kernels::prelude::*;
 
     pub fn quantize_f32_q5_k(data: &[f32]) -> Vec<BlockQ5K> {
         assert!(data.len() % QK_K == 0);
@@ -242,20 +242,17 @@ mod impl_fallback {
 
             for (aux8_chunk, q5_chunk) in aux8.chunks_mut(64).zip(q5.chunks(32)) {
                 for l in 0..32 {
-                    println!("qhl: {:?}, m: {:?}", qh[l], m);
                     aux8_chunk[l] = (q5_chunk[l] & 0xF) as i8;
                     aux8_chunk[l] += if qh[l] & m != 0 { 16 } else { 0 };
                 }
                 m <<= 1;
 
                 for l in 0..32 {
-                    println!("qhl: {:?}, m: {:?}", qh[l], m);
                     aux8_chunk[l + 32] = (q5_chunk[l] >> 4) as i8;
                     aux8_chunk[l + 32] += if qh[l] & m != 0 { 16 } else { 0 };
                 }
                 m <<= 1;
             }
-            println!("aux8: {:?}", aux8);
 
             for (i, scale_chunk) in abs.scales.chunks(4).enumerate() {
                 // because chunk_size is 4, so unwrap is safe.
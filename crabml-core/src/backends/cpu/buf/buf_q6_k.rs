@@ -1,4 +1,4 @@
-use std::borrow::Cow;
+use alloc::borrow::Cow;
 
 #[repr(C)]
 #[derive(Debug, Clone)]
@@ -46,14 +46,14 @@ pub struct QuantBufQ6K<'a> {
 
 impl<'a> QuantBufQ6K<'_> {
     pub fn from_bytes(data: &'a [u8]) -> Self {
-        let blk_size = std::mem::size_of::<BlockQ6K>();
+        let blk_size = core::mem::size_of::<BlockQ6K>();
         assert_eq!(
             data.len() % blk_size,
             0,
             "data length must be a multiple of QuantBlockQ6_K size"
         );
         let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ6K, data.len() / blk_size)
+            core::slice::from_raw_parts(data.as_ptr() as *const BlockQ6K, data.len() / blk_size)
         };
         Self {
             blocks: blocks.into(),
@@ -102,6 +102,7 @@ mod impl_fallback {
     use crate::backends::cpu::buf::buf_q8_k::BlockQ8K;
     use crate::backends::cpu::buf::util::make_qx_quants;
     use crate::backends::cpu::buf::util::nearest_i32;
+    use crate::kernels::prelude::*;
 
     pub fn quantize_f32_q6_k(data: &[f32]) -> Vec<BlockQ6K> {
         let mut bs = Vec::with_capacity(data.len() / 256);
@@ -246,8 +247,8 @@ mod tests {
     #[test]
     fn test_q6_k_block() {
         assert_eq!(
-            std::mem::size_of::<BlockQ6K>(),
-            std::mem::size_of::<f16>() + 128 + 64 + 16,
+            core::mem::size_of::<BlockQ6K>(),
+            core::mem::size_of::<f16>() + 128 + 64 + 16,
             "wrong q6_k block size/padding"
         );
 
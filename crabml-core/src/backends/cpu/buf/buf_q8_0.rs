@@ -1,4 +1,4 @@
-use std::borrow::Cow;
+use alloc::borrow::Cow;
 
 use half::f16;
 
@@ -12,12 +12,24 @@ pub struct BlockQ8_0 {
 impl BlockQ8_0 {
     pub const BLOCK_ELEMS: usize = 32;
 
+    pub const ZERO: Self = Self {
+        d: f16::ZERO,
+        qs: [0; 32],
+    };
+
     pub fn dequantize(&self, buf: &mut [f32]) {
         let d = self.d.to_f32();
         for (i, v) in buf.iter_mut().enumerate().take(32) {
             *v = self.qs[i] as f32 * d;
         }
     }
+
+    pub fn dequantize_f16(&self, buf: &mut [f16]) {
+        let d = self.d.to_f32();
+        for (i, v) in buf.iter_mut().enumerate().take(32) {
+            *v = f16::from_f32(self.qs[i] as f32 * d);
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -27,14 +39,14 @@ pub struct QuantBufQ8_0<'a> {
 
 impl<'a> QuantBufQ8_0<'a> {
     pub fn from_bytes(data: &'a [u8]) -> Self {
-        let blk_size = std::mem::size_of::<BlockQ8_0>();
+        let blk_size = core::mem::size_of::<BlockQ8_0>();
         assert_eq!(
             data.len() % blk_size,
             0,
             "data length must be a multiple of QuantBlockQ8_0 size"
         );
         let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ8_0, data.len() / blk_size)
+            core::slice::from_raw_parts(data.as_ptr() as *const BlockQ8_0, data.len() / blk_size)
         };
         Self {
             blocks: blocks.into(),
@@ -69,6 +81,17 @@ impl<'a> QuantBufQ8_0<'a> {
         })
     }
 
+    /// dequantize the blocks from start into f16 block by block, without going through a
+    /// buffer of f32, like the rows of a quantized token embedding looked up into the f16
+    /// activations.
+    pub fn dequantize_f16(&self, start: usize, dst: &mut [f16]) {
+        assert_eq!(start % 32, 0);
+        assert_eq!(dst.len() % 32, 0);
+
+        let blocks = &self.blocks()[start / 32..(start + dst.len()) / 32];
+        dequantize_q8_0_f16(blocks, dst)
+    }
+
     pub fn vec_dot(&self, a_offset: usize, b: &Self, b_offset: usize, len: usize) -> f32 {
         let abs = &self.blocks[a_offset / 32..(a_offset + len) / 32];
         let bbs = &b.blocks()[b_offset / 32..(b_offset + len) / 32];
@@ -79,7 +102,7 @@ impl<'a> QuantBufQ8_0<'a> {
 
 #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
 mod impl_aarch64_neon {
-    use std::arch::aarch64;
+    use core::arch::aarch64;
 
     use half::f16;
 
@@ -225,16 +248,20 @@ mod impl_aarch64_neon {
 #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
 use impl_aarch64_neon::*;
 
-#[cfg(all(target_arch = "x86_64", target_feature = "avx2"))]
+#[cfg(target_arch = "x86_64")]
 mod impl_x86_64_avx2 {
-    use std::arch::x86_64::*;
+    use core::arch::x86_64::*;
 
     use half::f16;
 
     use super::BlockQ8_0;
+    use crate::backends::cpu::buf::simd_x86::hsum_float_8;
+    use crate::backends::cpu::buf::simd_x86::mul_sum_i8_pairs_float;
+    use crate::backends::cpu::buf::simd_x86::store_f16_8;
 
     /// Inspired a lot by ggml https://github.com/ggerganov/ggml/blob/master/src/ggml-quants.c
 
+    #[cfg(target_feature = "avx2")]
     pub fn quantize_f32_q8_0(data: &[f32]) -> Vec<BlockQ8_0> {
         debug_assert_eq!(data.len() % 32, 0);
 
@@ -291,101 +318,145 @@ mod impl_x86_64_avx2 {
         bs
     }
 
-    pub fn vec_dot_q8_0_q8_0(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> f32 {
-        debug_assert_eq!(abs.len(), bbs.len());
+    #[target_feature(enable = "avx2,f16c")]
+    pub unsafe fn dequantize_q8_0_f16(bs: &[BlockQ8_0], dst: &mut [f16]) {
+        for (b, out) in bs.iter().zip(dst.chunks_exact_mut(32)) {
+            let d = _mm256_set1_ps(b.d.to_f32());
+            for i in 0..4 {
+                let q = _mm_loadl_epi64(b.qs.as_ptr().add(i * 8) as *const __m128i);
+                let v = _mm256_mul_ps(_mm256_cvtepi32_ps(_mm256_cvtepi8_epi32(q)), d);
+                store_f16_8(out.as_mut_ptr().add(i * 8), v);
+            }
+        }
+    }
 
-        unsafe {
-            let mut acc0 = _mm256_setzero_ps();
-            let mut acc1 = _mm256_setzero_ps();
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn vec_dot_q8_0_q8_0(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> f32 {
+        debug_assert_eq!(abs.len(), bbs.len());
 
-            for [(abs0, bbs0), (abs1, bbs1)] in abs.iter().zip(bbs).array_chunks::<2>() {
-                let d0 = _mm256_set1_ps(abs0.d.to_f32() * bbs0.d.to_f32());
-                let d1 = _mm256_set1_ps(abs1.d.to_f32() * bbs1.d.to_f32());
+        let mut acc0 = _mm256_setzero_ps();
+        let mut acc1 = _mm256_setzero_ps();
 
-                let qa0 = _mm256_loadu_si256(abs0.qs.as_ptr() as *const __m256i);
-                let qb0 = _mm256_loadu_si256(bbs0.qs.as_ptr() as *const __m256i);
+        for [(abs0, bbs0), (abs1, bbs1)] in abs.iter().zip(bbs).array_chunks::<2>() {
+            let d0 = _mm256_set1_ps(abs0.d.to_f32() * bbs0.d.to_f32());
+            let d1 = _mm256_set1_ps(abs1.d.to_f32() * bbs1.d.to_f32());
 
-                let qa1 = _mm256_loadu_si256(abs1.qs.as_ptr() as *const __m256i);
-                let qb1 = _mm256_loadu_si256(bbs1.qs.as_ptr() as *const __m256i);
+            let qa0 = _mm256_loadu_si256(abs0.qs.as_ptr() as *const __m256i);
+            let qb0 = _mm256_loadu_si256(bbs0.qs.as_ptr() as *const __m256i);
 
-                let q0 = mul_sum_i8_pairs_float(qa0, qb0);
-                let q1 = mul_sum_i8_pairs_float(qa1, qb1);
+            let qa1 = _mm256_loadu_si256(abs1.qs.as_ptr() as *const __m256i);
+            let qb1 = _mm256_loadu_si256(bbs1.qs.as_ptr() as *const __m256i);
 
-                acc0 = _mm256_fmadd_ps(d0, q0, acc0);
-                acc1 = _mm256_fmadd_ps(d1, q1, acc1);
-            }
+            let q0 = mul_sum_i8_pairs_float(qa0, qb0);
+            let q1 = mul_sum_i8_pairs_float(qa1, qb1);
 
-            if abs.len() % 2 == 1 {
-                let a = abs.last().unwrap_unchecked();
-                let b = abs.last().unwrap_unchecked();
+            acc0 = _mm256_fmadd_ps(d0, q0, acc0);
+            acc1 = _mm256_fmadd_ps(d1, q1, acc1);
+        }
 
-                let d = _mm256_set1_ps(a.d.to_f32() * b.d.to_f32());
+        if abs.len() % 2 == 1 {
+            let a = abs.last().unwrap_unchecked();
+            let b = bbs.last().unwrap_unchecked();
 
-                let qa = _mm256_loadu_si256(a.qs.as_ptr() as *const __m256i);
-                let qb = _mm256_loadu_si256(b.qs.as_ptr() as *const __m256i);
+            let d = _mm256_set1_ps(a.d.to_f32() * b.d.to_f32());
 
-                let q = mul_sum_i8_pairs_float(qa, qb);
+            let qa = _mm256_loadu_si256(a.qs.as_ptr() as *const __m256i);
+            let qb = _mm256_loadu_si256(b.qs.as_ptr() as *const __m256i);
 
-                acc0 = _mm256_fmadd_ps(d, q, acc0);
-            }
+            let q = mul_sum_i8_pairs_float(qa, qb);
 
-            hsum_float_8(_mm256_add_ps(acc0, acc1))
+            acc0 = _mm256_fmadd_ps(d, q, acc0);
         }
+
+        hsum_float_8(_mm256_add_ps(acc0, acc1))
     }
+}
+#[cfg(all(target_arch = "x86_64", target_feature = "avx2"))]
+use impl_x86_64_avx2::quantize_f32_q8_0;
+
+#[cfg(target_arch = "x86_64")]
+mod impl_x86_64_avx512_vnni {
+    use core::arch::x86_64::*;
+
+    use super::BlockQ8_0;
+    use crate::backends::cpu::buf::simd_x86::dot_i8_512;
+    use crate::backends::cpu::buf::simd_x86::join_256x2;
+    use crate::backends::cpu::buf::simd_x86::scales_2x8;
+
+    /// two blocks per 512 bit register, the odd block left over goes through the avx2 kernel.
+    #[target_feature(enable = "avx512f,avx512bw,avx512vnni")]
+    pub unsafe fn vec_dot_q8_0_q8_0(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> f32 {
+        debug_assert_eq!(abs.len(), bbs.len());
+
+        let mut acc = _mm512_setzero_ps();
+
+        for [(abs0, bbs0), (abs1, bbs1)] in abs.iter().zip(bbs).array_chunks::<2>() {
+            let d = scales_2x8(
+                abs0.d.to_f32() * bbs0.d.to_f32(),
+                abs1.d.to_f32() * bbs1.d.to_f32(),
+            );
+
+            let qa = join_256x2(
+                _mm256_loadu_si256(abs0.qs.as_ptr() as *const __m256i),
+                _mm256_loadu_si256(abs1.qs.as_ptr() as *const __m256i),
+            );
+            let qb = join_256x2(
+                _mm256_loadu_si256(bbs0.qs.as_ptr() as *const __m256i),
+                _mm256_loadu_si256(bbs1.qs.as_ptr() as *const __m256i),
+            );
+
+            let q = _mm512_cvtepi32_ps(dot_i8_512(qa, qb));
+            acc = _mm512_fmadd_ps(d, q, acc);
+        }
 
-    /// TODO: Adding AVX-VNNI support so that we can use `_mm256_dpbssd_epi32`
-    #[inline]
-    unsafe fn mul_sum_i8_pairs_float(x: __m256i, y: __m256i) -> __m256 {
-        // Get absolute values of x vectors
-        let ax = _mm256_sign_epi8(x, x);
-        // Sign the values of the y vectors
-        let sy = _mm256_sign_epi8(y, x);
-        mul_sum_us8_pairs_float(ax, sy)
+        let mut sum = _mm512_reduce_add_ps(acc);
+        if abs.len() % 2 == 1 {
+            let n = abs.len();
+            sum += super::impl_x86_64_avx2::vec_dot_q8_0_q8_0(&abs[n - 1..], &bbs[n - 1..]);
+        }
+        sum
     }
+}
 
-    #[inline]
-    unsafe fn mul_sum_us8_pairs_float(ax: __m256i, sy: __m256i) -> __m256 {
-        let axl = _mm256_castsi256_si128(ax);
-        let axh = _mm256_extractf128_si256(ax, 1);
-        let syl = _mm256_castsi256_si128(sy);
-        let syh = _mm256_extractf128_si256(sy, 1);
-        // Perform multiplication and create 16-bit values
-        let dotl = _mm_maddubs_epi16(axl, syl);
-        let doth = _mm_maddubs_epi16(axh, syh);
-        sum_i16_pairs_float(doth, dotl)
+/// picks the kernel for the running CPU, see `simd_x86`.
+#[cfg(target_arch = "x86_64")]
+fn vec_dot_q8_0_q8_0(abs: &[BlockQ8_0], bbs: &[BlockQ8_0]) -> f32 {
+    use super::simd_x86::x86_simd;
+    use super::simd_x86::X86Simd;
+
+    match x86_simd() {
+        X86Simd::Avx512Vnni => unsafe { impl_x86_64_avx512_vnni::vec_dot_q8_0_q8_0(abs, bbs) },
+        X86Simd::Avx2 => unsafe { impl_x86_64_avx2::vec_dot_q8_0_q8_0(abs, bbs) },
+        X86Simd::Scalar => impl_fallback::vec_dot_q8_0_q8_0(abs, bbs),
     }
+}
 
-    #[inline]
-    unsafe fn sum_i16_pairs_float(xh: __m128i, xl: __m128i) -> __m256 {
-        let ones = _mm_set1_epi16(1);
-        let summed_pairsl = _mm_madd_epi16(ones, xl);
-        let summed_pairsh = _mm_madd_epi16(ones, xh);
-        let summed_pairs = _mm256_set_m128i(summed_pairsh, summed_pairsl);
-        _mm256_cvtepi32_ps(summed_pairs)
+#[cfg(target_arch = "x86_64")]
+fn dequantize_q8_0_f16(bs: &[BlockQ8_0], dst: &mut [f16]) {
+    if super::simd_x86::has_f16c() {
+        return unsafe { impl_x86_64_avx2::dequantize_q8_0_f16(bs, dst) };
     }
+    for (b, out) in bs.iter().zip(dst.chunks_exact_mut(32)) {
+        b.dequantize_f16(out);
+    }
+}
 
-    /// horizontally add 8 floats
-    #[inline]
-    unsafe fn hsum_float_8(x: __m256) -> f32 {
-        let res = _mm256_extractf128_ps(x, 1);
-        let res = _mm_add_ps(res, _mm256_castps256_ps128(x));
-        let res = _mm_add_ps(res, _mm_movehl_ps(res, res));
-        let res = _mm_add_ss(res, _mm_movehdup_ps(res));
-        _mm_cvtss_f32(res)
+#[cfg(not(target_arch = "x86_64"))]
+fn dequantize_q8_0_f16(bs: &[BlockQ8_0], dst: &mut [f16]) {
+    for (b, out) in bs.iter().zip(dst.chunks_exact_mut(32)) {
+        b.dequantize_f16(out);
     }
 }
-#[cfg(all(target_arch = "x86_64", target_feature = "avx2"))]
-use impl_x86_64_avx2::*;
 
-#[cfg(not(any(
-    all(target_arch = "aarch64", target_feature = "neon"),
-    all(target_arch = "x86_64", target_feature = "avx2")
-)))]
+#[cfg(not(all(target_arch = "aarch64", target_feature = "neon")))]
 mod impl_fallback {
+    #[cfg(not(all(target_arch = "x86_64", target_feature = "avx2")))]
     use half::f16;
 
     use super::BlockQ8_0;
+    use crate::kernels::prelude::*;
 
+    #[cfg(not(all(target_arch = "x86_64", target_feature = "avx2")))]
     pub fn quantize_f32_q8_0(data: &[f32]) -> Vec<BlockQ8_0> {
         let mut bs = Vec::with_capacity(data.len() / 32);
 
@@ -437,7 +508,12 @@ mod impl_fallback {
     all(target_arch = "aarch64", target_feature = "neon"),
     all(target_arch = "x86_64", target_feature = "avx2")
 )))]
-use impl_fallback::*;
+use impl_fallback::quantize_f32_q8_0;
+#[cfg(not(any(
+    all(target_arch = "aarch64", target_feature = "neon"),
+    target_arch = "x86_64"
+)))]
+use impl_fallback::vec_dot_q8_0_q8_0;
 
 #[cfg(test)]
 mod tests {
@@ -541,4 +617,108 @@ mod tests {
             assert_eq!(result, expect, "test: {}", name);
         }
     }
+
+    #[test]
+    fn test_vec_dot_q8_0_q8_0_against_naive() {
+        // odd and even numbers of blocks take different paths in the simd kernels
+        for n_blocks in [1, 2, 3, 8] {
+            let len = n_blocks * 32;
+            let a = (0..len)
+                .map(|i| ((i * 7 + 3) % 19) as f32 * 0.5 - 4.5)
+                .collect::<Vec<_>>();
+            let b = (0..len)
+                .map(|i| ((i * 5 + 1) % 23) as f32 * 0.25 - 2.5)
+                .collect::<Vec<_>>();
+            let qa = QuantBufQ8_0::quantize(&a);
+            let qb = QuantBufQ8_0::quantize(&b);
+
+            let expected = qa
+                .blocks
+                .iter()
+                .zip(qb.blocks.iter())
+                .map(|(ab, bb)| {
+                    let sumi = ab
+                        .qs
+                        .iter()
+                        .zip(bb.qs.iter())
+                        .map(|(x, y)| *x as i32 * *y as i32)
+                        .sum::<i32>();
+                    sumi as f32 * ab.d.to_f32() * bb.d.to_f32()
+                })
+                .sum::<f32>();
+            let got = qa.vec_dot(0, &qb, 0, len);
+            assert!(
+                (got - expected).abs() <= expected.abs() * 1e-4 + 1e-3,
+                "n_blocks: {}, got: {}, expected: {}",
+                n_blocks,
+                got,
+                expected
+            );
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_vec_dot_q8_0_q8_0_x86_kernels() {
+        use crate::backends::cpu::buf::simd_x86::X86Simd;
+
+        for n_blocks in [1, 2, 3, 8, 17] {
+            let len = n_blocks * 32;
+            let a = (0..len)
+                .map(|i| ((i * 13 + 5) % 31) as f32 * 0.3 - 4.5)
+                .collect::<Vec<_>>();
+            let b = (0..len)
+                .map(|i| ((i * 11 + 7) % 29) as f32 * 0.2 - 2.8)
+                .collect::<Vec<_>>();
+            let qa = QuantBufQ8_0::quantize(&a);
+            let qb = QuantBufQ8_0::quantize(&b);
+
+            let expected = impl_fallback::vec_dot_q8_0_q8_0(&qa.blocks, &qb.blocks);
+            for simd in X86Simd::available() {
+                let got = unsafe {
+                    match simd {
+                        X86Simd::Scalar => impl_fallback::vec_dot_q8_0_q8_0(&qa.blocks, &qb.blocks),
+                        X86Simd::Avx2 => {
+                            impl_x86_64_avx2::vec_dot_q8_0_q8_0(&qa.blocks, &qb.blocks)
+                        }
+                        X86Simd::Avx512Vnni => {
+                            impl_x86_64_avx512_vnni::vec_dot_q8_0_q8_0(&qa.blocks, &qb.blocks)
+                        }
+                    }
+                };
+                // the integer dot products are exact, only the f32 accumulation order differs
+                assert!(
+                    (got - expected).abs() <= expected.abs() * 1e-5 + 1e-4,
+                    "{:?} n_blocks: {}, got: {}, expected: {}",
+                    simd,
+                    n_blocks,
+                    got,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_dequantize_q8_0_f16() {
+        let len = 5 * 32;
+        let data = (0..len)
+            .map(|i| ((i * 11 + 7) % 29) as f32 * 0.37 - 5.0)
+            .collect::<Vec<_>>();
+        let qb = QuantBufQ8_0::quantize(&data);
+
+        // the simd kernel rounds to the nearest like f16::from_f32 on the f32 values
+        let expected = qb.dequantize(0).map(f16::from_f32).collect::<Vec<_>>();
+        let mut got = vec![f16::ZERO; len];
+        qb.dequantize_f16(0, &mut got);
+        assert_eq!(got, expected);
+
+        let mut got = vec![f16::ZERO; len - 64];
+        qb.dequantize_f16(32, &mut got);
+        assert_eq!(got, expected[32..len - 32]);
+
+        let mut got = vec![f16::ZERO; 32];
+        qb.blocks[1].dequantize_f16(&mut got);
+        assert_eq!(got, expected[32..64]);
+    }
 }
@@ -1,4 +1,4 @@
-use std::borrow::Cow;
+use alloc::borrow::Cow;
 
 #[derive(Debug, Clone)]
 pub struct QuantBufQ8_1<'a> {
@@ -7,14 +7,14 @@ pub struct QuantBufQ8_1<'a> {
 
 impl<'a> QuantBufQ8_1<'_> {
     pub fn from_bytes(data: &'a [u8]) -> Self {
-        let blk_size = std::mem::size_of::<BlockQ8_1>();
+        let blk_size = core::mem::size_of::<BlockQ8_1>();
         assert_eq!(
             data.len() % blk_size,
             0,
             "data length must be a multiple of QuantBlockQ8_1 size"
         );
         let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ8_1, data.len() / blk_size)
+            core::slice::from_raw_parts(data.as_ptr() as *const BlockQ8_1, data.len() / blk_size)
         };
         Self {
             blocks: blocks.into(),
@@ -74,7 +74,7 @@ impl BlockQ8_1 {
 
 #[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
 mod impl_aarch64_neon {
-    use std::arch::aarch64;
+    use core::arch::aarch64;
 
     use super::BlockQ8_1;
 
@@ -231,7 +231,7 @@ use impl_aarch64_neon::*;
 
 #[cfg(all(target_arch = "x86_64", target_feature = "avx2"))]
 mod impl_x86_64_avx2 {
-    use std::arch::x86_64::*;
+    use core::arch::x86_64::*;
 
     use super::BlockQ8_1;
     pub fn quantize_f32_q8_1(data: &[f32]) -> Vec<BlockQ8_1> {
@@ -380,6 +380,7 @@ use impl_x86_64_avx2::*;
 )))]
 mod impl_fallback {
     use super::BlockQ8_1;
+    use crate::kernels::prelude::*;
 
     pub fn quantize_f32_q8_1(data: &[f32]) -> Vec<BlockQ8_1> {
         let mut bs = Vec::with_capacity(data.len() / 32);
@@ -444,8 +445,8 @@ mod tests {
     #[test]
     fn test_q8_1_block() {
         assert_eq!(
-            std::mem::size_of::<BlockQ8_1>(),
-            2 * std::mem::size_of::<f32>() + 32,
+            core::mem::size_of::<BlockQ8_1>(),
+            2 * core::mem::size_of::<f32>() + 32,
             "wrong q8_1 block size/padding"
         );
 
@@ -1,4 +1,4 @@
-use std::borrow::Cow;
+use alloc::borrow::Cow;
 
 #[repr(C)]
 #[derive(Debug, Clone)]
@@ -24,14 +24,14 @@ pub struct QuantBufQ8K<'a> {
 
 impl<'a> QuantBufQ8K<'_> {
     pub fn from_bytes(data: &'a [u8]) -> Self {
-        let blk_size = std::mem::size_of::<BlockQ8K>();
+        let blk_size = core::mem::size_of::<BlockQ8K>();
         assert_eq!(
             data.len() % blk_size,
             0,
             "data length must be a multiple of QuantBlockQ8_K size"
         );
         let blocks = unsafe {
-            std::slice::from_raw_parts(data.as_ptr() as *const BlockQ8K, data.len() / blk_size)
+            core::slice::from_raw_parts(data.as_ptr() as *const BlockQ8K, data.len() / blk_size)
         };
         Self {
             blocks: blocks.into(),
@@ -75,6 +75,7 @@ impl<'a> QuantBufQ8K<'_> {
 
 mod impl_fallback {
     use super::BlockQ8K;
+    use crate::kernels::prelude::*;
     pub fn quantize_f32_q8_k(data: &[f32]) -> Vec<BlockQ8K> {
         let mut bs = Vec::with_capacity(data.len() / 32);
 
@@ -150,8 +151,8 @@ mod tests {
     #[test]
     fn test_q8_k_block() {
         assert_eq!(
-            std::mem::size_of::<BlockQ8K>(),
-            std::mem::size_of::<f32>() + 256 + 16 * 2,
+            core::mem::size_of::<BlockQ8K>(),
+            core::mem::size_of::<f32>() + 256 + 16 * 2,
             "wrong q8_k block size/padding"
         );
 
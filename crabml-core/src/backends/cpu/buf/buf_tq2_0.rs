@@ -0,0 +1,296 @@
+use alloc::borrow::Cow;
+
+use half::f16;
+
+use self::impl_bitplane::vec_dot_tq2_0_q8_k;
+use self::impl_fallback::quantize_f32_tq2_0;
+use super::util::QK_K;
+use super::QuantBufQ8K;
+
+/// Ternary quantization for BitNet b1.58 style weights, where every weight is one of
+/// {-1, 0, 1} times a per-block scale. The layout is compatible with ggml's TQ2_0:
+/// each weight is stored as `q + 1` in 2 bits, 4 weights packed in a byte, and the
+/// byte at `qs[j + m]` holds the weights `j * 4 + m + n * 32` for n in 0..4.
+///
+/// This is experimental.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct BlockTQ2_0 {
+    pub qs: [u8; QK_K / 4], // 2 bits per element
+    pub d: f16,
+}
+
+impl BlockTQ2_0 {
+    pub fn dequantize(&self, buf: &mut [f32]) {
+        let d = self.d.to_f32();
+        for (qs_chunk, buf_chunk) in self.qs.chunks(32).zip(buf.chunks_mut(128)) {
+            for l in 0..4 {
+                for m in 0..32 {
+                    let q = (qs_chunk[m] >> (l * 2)) & 3;
+                    buf_chunk[l * 32 + m] = (q as i8 - 1) as f32 * d;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QuantBufTQ2_0<'a> {
+    pub blocks: Cow<'a, [BlockTQ2_0]>,
+}
+
+impl<'a> QuantBufTQ2_0<'_> {
+    pub fn from_bytes(data: &'a [u8]) -> Self {
+        let blk_size = core::mem::size_of::<BlockTQ2_0>();
+        assert_eq!(
+            data.len() % blk_size,
+            0,
+            "data length must be a multiple of QuantBlockTQ2_0 size"
+        );
+        let blocks = unsafe {
+            core::slice::from_raw_parts(data.as_ptr() as *const BlockTQ2_0, data.len() / blk_size)
+        };
+        Self {
+            blocks: blocks.into(),
+        }
+    }
+
+    pub fn quantize(data: &[f32]) -> Self {
+        let bs = quantize_f32_tq2_0(data);
+        Self { blocks: bs.into() }
+    }
+
+    fn blocks(&self) -> &[BlockTQ2_0] {
+        &self.blocks
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len() * QK_K
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn dequantize(&'a self, start: usize) -> impl Iterator<Item = f32> + 'a {
+        assert!(start % QK_K == 0);
+
+        let block_start = start / QK_K;
+        self.blocks()[block_start..].iter().flat_map(|blk| {
+            let mut buf = [0f32; QK_K];
+            blk.dequantize(&mut buf);
+            buf.into_iter()
+        })
+    }
+
+    pub fn vec_dot(&self, a_offset: usize, b: &QuantBufQ8K, b_offset: usize, len: usize) -> f32 {
+        let abs = &self.blocks[a_offset / QK_K..(a_offset + len) / QK_K];
+        let bbs = &b.blocks[b_offset / QK_K..(b_offset + len) / QK_K];
+
+        vec_dot_tq2_0_q8_k(abs, bbs)
+    }
+}
+
+mod impl_fallback {
+    use half::f16;
+
+    use super::BlockTQ2_0;
+    #[cfg(test)]
+    use crate::backends::cpu::buf::buf_q8_k::BlockQ8K;
+    use crate::backends::cpu::buf::util::QK_K;
+    use crate::kernels::prelude::*;
+
+    pub fn quantize_f32_tq2_0(data: &[f32]) -> Vec<BlockTQ2_0> {
+        assert!(data.len() % QK_K == 0);
+        let mut bs = Vec::with_capacity(data.len() / QK_K);
+
+        for chunk in data.chunks(QK_K) {
+            let amax = chunk.iter().fold(0f32, |acc, x| acc.max(x.abs()));
+            let d = amax;
+            let id = if d != 0.0 { 1.0 / d } else { 0.0 };
+
+            let mut qs = [0u8; QK_K / 4];
+            for (qs_chunk, data_chunk) in qs.chunks_mut(32).zip(chunk.chunks(128)) {
+                for (m, q) in qs_chunk.iter_mut().enumerate() {
+                    for n in 0..4 {
+                        // -1, 0, 1 -> 0, 1, 2
+                        let xi = (data_chunk[m + n * 32] * id).round() as i32 + 1;
+                        *q |= (xi.clamp(0, 2) as u8) << (2 * n);
+                    }
+                }
+            }
+
+            bs.push(BlockTQ2_0 {
+                qs,
+                d: f16::from_f32(d),
+            });
+        }
+
+        bs
+    }
+
+    /// the weights are stored as `q + 1`, so instead of decoding every weight back to
+    /// {-1, 0, 1}, we sum up `(q + 1) * y` on the unsigned crumbs, and subtract `sum(y)`
+    /// at the end, which is already pre-computed in the bsums of Q8_K.
+    ///
+    /// the reference of the bit-plane kernel.
+    #[cfg(test)]
+    pub fn vec_dot_tq2_0_q8_k(abs: &[BlockTQ2_0], bbs: &[BlockQ8K]) -> f32 {
+        let mut sumf = 0.0;
+        for (a, b) in abs.iter().zip(bbs.iter()) {
+            let mut sumi = 0i32;
+            for (qs_chunk, q8_chunk) in a.qs.chunks(32).zip(b.qs.chunks(128)) {
+                for l in 0..4 {
+                    for m in 0..32 {
+                        let q = ((qs_chunk[m] >> (l * 2)) & 3) as i32;
+                        sumi += q * q8_chunk[l * 32 + m] as i32;
+                    }
+                }
+            }
+            let bsum: i32 = b.bsums.iter().map(|&s| s as i32).sum();
+            sumf += (sumi - bsum) as f32 * a.d.to_f32() * b.d;
+        }
+        sumf
+    }
+}
+
+/// the weights and the activations are split into the planes of their bits, 256 bits of a
+/// block in 4 u64, and multiplied by popcounts: with `q = 2 * hi + lo` of a crumb and
+/// `y = sum(2^b * y_b) - 256 * y_7` of the two's complement of an i8, the dot of a block is
+/// `sum(c_b * (2 * popcnt(hi & y_b) + popcnt(lo & y_b))) - sum(y)`, where c_b is 2^b and
+/// -128 on the sign bit.
+mod impl_bitplane {
+    use super::BlockTQ2_0;
+    use crate::backends::cpu::buf::buf_q8_k::BlockQ8K;
+    use crate::backends::cpu::buf::util::QK_K;
+
+    type Plane = [u64; QK_K / 64];
+
+    // the bit of each of the 8 bytes in v, packed into the low byte in the order of the bytes
+    #[inline(always)]
+    fn gather_bits(v: u64, bit: u32) -> u64 {
+        ((v >> bit) & 0x0101_0101_0101_0101).wrapping_mul(0x0102_0408_1020_4080) >> 56
+    }
+
+    #[inline(always)]
+    fn load_u64(bytes: &[u8]) -> u64 {
+        u64::from_le_bytes(bytes[..8].try_into().unwrap())
+    }
+
+    // the bit 64 * w + 8 * i + j of the planes is the weight j of the group of 8 weights at
+    // 64 * w + 8 * i, which are the crumbs of 8 bytes in a row in qs
+    fn weight_planes(a: &BlockTQ2_0) -> (Plane, Plane) {
+        let mut hi = [0u64; QK_K / 64];
+        let mut lo = [0u64; QK_K / 64];
+        for e in (0..QK_K).step_by(8) {
+            let (c, l, m) = (e / 128, (e % 128) / 32, e % 32);
+            let v = load_u64(&a.qs[c * 32 + m..]);
+            let shift = e % 64;
+            hi[e / 64] |= gather_bits(v, 2 * l as u32 + 1) << shift;
+            lo[e / 64] |= gather_bits(v, 2 * l as u32) << shift;
+        }
+        (hi, lo)
+    }
+
+    fn activation_planes(b: &BlockQ8K) -> [Plane; 8] {
+        let mut planes = [[0u64; QK_K / 64]; 8];
+        for e in (0..QK_K).step_by(8) {
+            let bytes: [u8; 8] = core::array::from_fn(|j| b.qs[e + j] as u8);
+            let v = u64::from_le_bytes(bytes);
+            for (bit, plane) in planes.iter_mut().enumerate() {
+                plane[e / 64] |= gather_bits(v, bit as u32) << (e % 64);
+            }
+        }
+        planes
+    }
+
+    pub fn vec_dot_tq2_0_q8_k(abs: &[BlockTQ2_0], bbs: &[BlockQ8K]) -> f32 {
+        let mut sumf = 0.0;
+        for (a, b) in abs.iter().zip(bbs.iter()) {
+            let (hi, lo) = weight_planes(a);
+            let planes = activation_planes(b);
+            let mut sumi = 0i32;
+            for (bit, plane) in planes.iter().enumerate() {
+                let mut n = 0i32;
+                for w in 0..QK_K / 64 {
+                    n += 2 * (hi[w] & plane[w]).count_ones() as i32
+                        + (lo[w] & plane[w]).count_ones() as i32;
+                }
+                sumi += if bit == 7 { -128 * n } else { n << bit };
+            }
+            let bsum: i32 = b.bsums.iter().map(|&s| s as i32).sum();
+            sumf += (sumi - bsum) as f32 * a.d.to_f32() * b.d;
+        }
+        sumf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::cpu::buf::util::tests::dot_product;
+    use crate::backends::cpu::buf::util::tests::generate_data;
+
+    const TEST_SIZE: usize = 512;
+
+    #[test]
+    fn test_tq2_0_block() {
+        let data = (0..QK_K)
+            .map(|i| ((i % 3) as f32 - 1.0) * 0.5)
+            .collect::<Vec<_>>();
+        let bs = QuantBufTQ2_0::quantize(&data);
+        assert_eq!(bs.blocks.len(), 1);
+        assert_eq!(bs.blocks[0].d.to_f32(), 0.5);
+
+        let dequantized = bs.dequantize(0).collect::<Vec<_>>();
+        assert_eq!(dequantized, data);
+    }
+
+    #[test]
+    fn test_vec_dot_tq2_0_q8_k() {
+        let data = generate_data(0.0, TEST_SIZE)
+            .iter()
+            .map(|x| x.signum() * (x.abs() > 0.7) as i32 as f32)
+            .collect::<Vec<_>>();
+        let q8k_data = generate_data(1.0, TEST_SIZE);
+
+        let tq2 = QuantBufTQ2_0::quantize(&data);
+        let q8k = QuantBufQ8K::quantize(&q8k_data);
+
+        let dot_result = tq2.vec_dot(0, &q8k, 0, TEST_SIZE);
+        let dot_ref = dot_product(&data, &q8k_data);
+        let diff = f32::abs(dot_ref - dot_result) / TEST_SIZE as f32;
+        assert!(diff < 0.01, "diff: {}", diff);
+    }
+
+    #[test]
+    fn test_vec_dot_tq2_0_bitplane() {
+        // all the crumbs, 3 included, against all the i8 values
+        let mut a = QuantBufTQ2_0::quantize(&generate_data(0.0, TEST_SIZE))
+            .blocks
+            .to_vec();
+        for (i, blk) in a.iter_mut().enumerate() {
+            for (j, q) in blk.qs.iter_mut().enumerate() {
+                *q = ((i * 64 + j) * 37 % 256) as u8;
+            }
+        }
+        let mut b = QuantBufQ8K::quantize(&generate_data(1.0, TEST_SIZE))
+            .blocks
+            .to_vec();
+        for (i, blk) in b.iter_mut().enumerate() {
+            for (j, q) in blk.qs.iter_mut().enumerate() {
+                *q = ((i * 256 + j) * 113 % 256) as u8 as i8;
+            }
+            blk.qs[0] = i8::MIN;
+            blk.qs[1] = i8::MAX;
+            for (k, bsum) in blk.bsums.iter_mut().enumerate() {
+                *bsum = blk.qs[k * 16..(k + 1) * 16].iter().map(|&q| q as i16).sum();
+            }
+        }
+
+        let expected = impl_fallback::vec_dot_tq2_0_q8_k(&a, &b);
+        let got = impl_bitplane::vec_dot_tq2_0_q8_k(&a, &b);
+        assert_eq!(got, expected);
+        assert_ne!(got, 0.0);
+    }
+}
@@ -3,7 +3,10 @@ pub use api::CpuTensorBuf;
 
 pub mod buf_f16;
 pub mod buf_f32;
+pub mod buf_int;
 
+#[cfg(target_arch = "x86_64")]
+mod simd_x86;
 mod util;
 
 pub mod buf_q2_k;
@@ -18,6 +21,7 @@ pub mod buf_q6_k;
 pub mod buf_q8_0;
 pub mod buf_q8_1;
 pub mod buf_q8_k;
+pub mod buf_tq2_0;
 
 pub use buf_q2_k::QuantBufQ2K;
 pub use buf_q3_k::QuantBufQ3K;
@@ -31,3 +35,4 @@ pub use buf_q6_k::QuantBufQ6K;
 pub use buf_q8_0::QuantBufQ8_0;
 pub use buf_q8_1::QuantBufQ8_1;
 pub use buf_q8_k::QuantBufQ8K;
+pub use buf_tq2_0::QuantBufTQ2_0;
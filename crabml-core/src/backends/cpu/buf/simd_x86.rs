@@ -0,0 +1,169 @@
+//! Runtime selected x86_64 SIMD kernels for the quantized dot products.
+//!
+//! The kernels are compiled with `#[target_feature]` on every x86_64 build, and
+//! the best one supported by the running CPU is picked once at startup, so a
+//! generic release binary still gets the AVX2 / AVX-512 VNNI paths. Without std there's
+//! no runtime detection, the level is fixed by the target features of the build.
+
+use core::arch::x86_64::*;
+#[cfg(feature = "std")]
+use std::sync::LazyLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum X86Simd {
+    Scalar,
+    Avx2,
+    Avx512Vnni,
+}
+
+impl X86Simd {
+    /// all the levels which can run on this CPU, from the slowest to the fastest.
+    #[cfg(test)]
+    pub fn available() -> Vec<X86Simd> {
+        [X86Simd::Scalar, X86Simd::Avx2, X86Simd::Avx512Vnni]
+            .into_iter()
+            .filter(|simd| *simd <= x86_simd())
+            .collect()
+    }
+}
+
+#[cfg(feature = "std")]
+static X86_SIMD: LazyLock<X86Simd> = LazyLock::new(|| {
+    if is_x86_feature_detected!("avx512f")
+        && is_x86_feature_detected!("avx512bw")
+        && is_x86_feature_detected!("avx512vnni")
+    {
+        X86Simd::Avx512Vnni
+    } else if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+        X86Simd::Avx2
+    } else {
+        X86Simd::Scalar
+    }
+});
+
+/// the fastest kernel level supported by the running CPU.
+#[cfg(feature = "std")]
+pub fn x86_simd() -> X86Simd {
+    *X86_SIMD
+}
+
+#[cfg(not(feature = "std"))]
+pub fn x86_simd() -> X86Simd {
+    if cfg!(all(
+        target_feature = "avx512f",
+        target_feature = "avx512bw",
+        target_feature = "avx512vnni"
+    )) {
+        X86Simd::Avx512Vnni
+    } else if cfg!(all(target_feature = "avx2", target_feature = "fma")) {
+        X86Simd::Avx2
+    } else {
+        X86Simd::Scalar
+    }
+}
+
+#[cfg(feature = "std")]
+static HAS_F16C: LazyLock<bool> =
+    LazyLock::new(|| is_x86_feature_detected!("avx2") && is_x86_feature_detected!("f16c"));
+
+/// whether the running CPU converts between f32 and f16 in simd, along with avx2.
+#[cfg(feature = "std")]
+pub fn has_f16c() -> bool {
+    *HAS_F16C
+}
+
+#[cfg(not(feature = "std"))]
+pub fn has_f16c() -> bool {
+    cfg!(all(target_feature = "avx2", target_feature = "f16c"))
+}
+
+/// convert 8 floats into f16 with the rounding to the nearest even, like `f16::from_f32()`,
+/// and store them at dst.
+#[inline]
+#[target_feature(enable = "avx2,f16c")]
+pub unsafe fn store_f16_8(dst: *mut half::f16, x: __m256) {
+    let h = _mm256_cvtps_ph::<_MM_FROUND_TO_NEAREST_INT>(x);
+    _mm_storeu_si128(dst as *mut __m128i, h);
+}
+
+/// multiply int8 pairs and sum them as 8 floats, the signed variant of `vpmaddubsw`.
+///
+/// TODO: Adding AVX-VNNI support so that we can use `_mm256_dpbssd_epi32`
+#[inline]
+#[target_feature(enable = "avx2")]
+pub unsafe fn mul_sum_i8_pairs_float(x: __m256i, y: __m256i) -> __m256 {
+    // Get absolute values of x vectors
+    let ax = _mm256_sign_epi8(x, x);
+    // Sign the values of the y vectors
+    let sy = _mm256_sign_epi8(y, x);
+    mul_sum_us8_pairs_float(ax, sy)
+}
+
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn mul_sum_us8_pairs_float(ax: __m256i, sy: __m256i) -> __m256 {
+    let axl = _mm256_castsi256_si128(ax);
+    let axh = _mm256_extractf128_si256(ax, 1);
+    let syl = _mm256_castsi256_si128(sy);
+    let syh = _mm256_extractf128_si256(sy, 1);
+    // Perform multiplication and create 16-bit values
+    let dotl = _mm_maddubs_epi16(axl, syl);
+    let doth = _mm_maddubs_epi16(axh, syh);
+    sum_i16_pairs_float(doth, dotl)
+}
+
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn sum_i16_pairs_float(xh: __m128i, xl: __m128i) -> __m256 {
+    let ones = _mm_set1_epi16(1);
+    let summed_pairsl = _mm_madd_epi16(ones, xl);
+    let summed_pairsh = _mm_madd_epi16(ones, xh);
+    let summed_pairs = _mm256_set_m128i(summed_pairsh, summed_pairsl);
+    _mm256_cvtepi32_ps(summed_pairs)
+}
+
+/// horizontally add 8 floats
+#[inline]
+#[target_feature(enable = "avx2")]
+pub unsafe fn hsum_float_8(x: __m256) -> f32 {
+    let res = _mm256_extractf128_ps(x, 1);
+    let res = _mm_add_ps(res, _mm256_castps256_ps128(x));
+    let res = _mm_add_ps(res, _mm_movehl_ps(res, res));
+    let res = _mm_add_ss(res, _mm_movehdup_ps(res));
+    _mm_cvtss_f32(res)
+}
+
+/// unpack 32 4-bit values into 32 bytes, the low nibbles first, each in [0, 15].
+#[inline]
+#[target_feature(enable = "avx2")]
+pub unsafe fn bytes_from_nibbles_32(qs: *const u8) -> __m256i {
+    let tmp = _mm_loadu_si128(qs as *const __m128i);
+    let bytes = _mm256_set_m128i(_mm_srli_epi16::<4>(tmp), tmp);
+    _mm256_and_si256(_mm256_set1_epi8(0x0F), bytes)
+}
+
+/// join the quants of two blocks into a single 512 bit register.
+#[inline]
+#[target_feature(enable = "avx512f")]
+pub unsafe fn join_256x2(lo: __m256i, hi: __m256i) -> __m512i {
+    _mm512_inserti64x4::<1>(_mm512_castsi256_si512(lo), hi)
+}
+
+/// the per block scales of two joined blocks, `d0` on the low 8 lanes and `d1` on the high.
+#[inline]
+#[target_feature(enable = "avx512f")]
+pub unsafe fn scales_2x8(d0: f32, d1: f32) -> __m512 {
+    _mm512_mask_blend_ps(0xFF00, _mm512_set1_ps(d0), _mm512_set1_ps(d1))
+}
+
+/// `sum(x * y)` of signed bytes on each group of 4 lanes, using `vpdpbusd` which
+/// takes an unsigned lhs: |x| is multiplied by y with the sign of x moved onto it.
+#[inline]
+#[target_feature(enable = "avx512f,avx512bw,avx512vnni")]
+pub unsafe fn dot_i8_512(x: __m512i, y: __m512i) -> __m512i {
+    let zero = _mm512_setzero_si512();
+    let negative = _mm512_movepi8_mask(x);
+    let ax = _mm512_abs_epi8(x);
+    let sy = _mm512_mask_sub_epi8(y, negative, zero, y);
+    _mm512_dpbusd_epi32(zero, ax, sy)
+}
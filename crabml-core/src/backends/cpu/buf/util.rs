@@ -2,6 +2,9 @@
 //!
 //! Including shared constants and functions
 
+#[cfg(not(feature = "std"))]
+use crate::kernels::prelude::*;
+
 /// Super-block size for Quants-K.
 ///
 /// `QK_K` elements in a super block
@@ -11,7 +14,7 @@ pub fn nearest_i32(fval: f32) -> i32 {
     assert!(fval <= 4194303.0f32);
     let mut val = (fval + 12582912.0f32) as i32;
     let mut i = 0;
-    std::mem::swap(&mut i, &mut val);
+    core::mem::swap(&mut i, &mut val);
     (i & 0x007fffff) - 0x00400000
 }
 
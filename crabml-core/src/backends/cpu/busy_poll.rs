@@ -0,0 +1,109 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(not(target_os = "wasi"))]
+use std::time::Instant;
+
+/// keeps the rayon workers spinning for a while after a parallel op, so the next op finds
+/// them awake instead of waking them up from the futex. the wake up costs tens of
+/// microseconds on each worker, which dominates the latency of a token on the small models.
+///
+/// the spinning burns the cores between the ops, it only pays off when the cores are
+/// dedicated to the model.
+#[derive(Debug, Clone)]
+pub(crate) struct BusyPoll {
+    #[cfg_attr(target_os = "wasi", allow(dead_code))]
+    spin: Duration,
+    // bumped on every park() and wake(), a worker spins until it changes
+    epoch: Arc<AtomicU64>,
+}
+
+impl BusyPoll {
+    pub fn new(spin: Duration) -> Self {
+        Self {
+            spin,
+            epoch: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// releases the spinning workers, before submitting a parallel op.
+    pub fn wake(&self) {
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// lets every worker spin until the next wake() or the spin budget runs out, after a
+    /// parallel op. there are no workers on wasi, the ops run on the calling thread.
+    #[cfg(target_os = "wasi")]
+    pub fn park(&self) {
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// lets every worker spin until the next wake() or the spin budget runs out, after a
+    /// parallel op. the workers are the ones of the pool it's called in, call it inside the
+    /// `install()` of the op when the op runs on a pool of `ThreadPools`.
+    #[cfg(not(target_os = "wasi"))]
+    pub fn park(&self) {
+        let epoch = self.epoch.fetch_add(1, Ordering::AcqRel) + 1;
+        let state = self.epoch.clone();
+        let spin = self.spin;
+        rayon::spawn_broadcast(move |_| {
+            let deadline = Instant::now() + spin;
+            let mut n: u32 = 0;
+            while state.load(Ordering::Acquire) == epoch {
+                std::hint::spin_loop();
+                n = n.wrapping_add(1);
+                // reading the clock is much slower than the atomic
+                if n % 1024 == 0 && Instant::now() >= deadline {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rayon::prelude::*;
+
+    use super::*;
+    use crate::backends::cpu::ThreadPools;
+    use crate::tensor::TaskTag;
+
+    #[test]
+    fn test_busy_poll_wake() {
+        let poll = BusyPoll::new(Duration::from_secs(5));
+        poll.park();
+        std::thread::sleep(Duration::from_millis(10));
+
+        // the spinning workers leave on wake, and take the next op at once
+        let start_at = Instant::now();
+        poll.wake();
+        let sum = (0..1024u64).into_par_iter().sum::<u64>();
+        assert_eq!(sum, 1023 * 1024 / 2);
+        assert!(start_at.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_busy_poll_thread_pools() -> crate::error::Result<()> {
+        let pools = ThreadPools::new().with_threads(TaskTag::Background, 2)?;
+        let poll = BusyPoll::new(Duration::from_secs(5));
+        pools.install(TaskTag::Background, || poll.park());
+        std::thread::sleep(Duration::from_millis(10));
+
+        // the workers of the background pool spin, the global pool is left free
+        let start_at = Instant::now();
+        let sum = (0..1024u64).into_par_iter().sum::<u64>();
+        assert_eq!(sum, 1023 * 1024 / 2);
+        assert!(start_at.elapsed() < Duration::from_secs(1));
+
+        // and the spinning workers of the pool take the next op on wake
+        poll.wake();
+        let sum = pools.install(TaskTag::Background, || {
+            (0..1024u64).into_par_iter().sum::<u64>()
+        });
+        assert_eq!(sum, 1023 * 1024 / 2);
+        assert!(start_at.elapsed() < Duration::from_secs(1));
+        Ok(())
+    }
+}
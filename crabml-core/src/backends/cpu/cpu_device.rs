@@ -1,26 +1,106 @@
+use std::cell::Cell;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
+use std::time::Duration;
 
-use half::f16;
-
+use super::busy_poll::BusyPoll;
+use super::lut;
+use super::parallel::ThreadPools;
 use super::CpuTensor;
+use crate::gguf::GGMLType;
+use crate::tensor::OpProfiler;
+use crate::tensor::ProfileReport;
+use crate::tensor::TaskTag;
 use crate::tensor::TensorMetrics;
 
-#[derive(Debug, Clone, Default)]
+/// how the batch_matmuls of the attention keep their sums from overflowing on the long
+/// contexts.
+///
+/// with the f16 kv cache, the scores q·k are dotted in f16 on aarch64, and written out
+/// in f16 with the f16 activations, a score past 65504 becomes inf and the softmax of its
+/// row NaN. the attn @ v sums the probabilities times the value rows over the positions
+/// into an f16 accumulator, at 32k positions the probabilities are around 2^-15, which is
+/// below half an ulp of the sum once it's past 1/16, so the sum stops growing and the
+/// output silently shrinks. the f32 and q8_0 caches sum up in f32 and are not affected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AttentionAccumulation {
+    /// the sums as they are, the fastest.
+    #[default]
+    Plain,
+    /// the sums in f16 and the outputs written in f16 saturate at ±f16::MAX instead of
+    /// going inf, the rows of the softmax stay finite, the values in the range and the f32
+    /// sums are untouched.
+    Saturate,
+    /// saturate, and rescale the sums: q is scaled by the epilogue before the f16 dots
+    /// instead of the scores after them, which keeps the partial sums sqrt(head_dim) times
+    /// smaller, and the attn @ v over the f16 value cache is summed up in f32.
+    Rescale,
+}
+
+#[derive(Debug, Clone)]
 pub struct CpuTensorDeviceOptions {
     /// when enabled, whenever tensor called with `with_name`, the name and the
     /// tensor will be recorded in the device. only used in test.
     pub debug_named_tensors: bool,
+
+    /// when set, the worker threads spin up to this long after a parallel op instead of
+    /// parking, to cut the wake up jitter on the latency of each token. it burns the cores
+    /// between the ops, only use it on the dedicated cores.
+    pub busy_poll: Option<Duration>,
+
+    /// the strict mode for the golden tests and the verification across the machines: the
+    /// matmuls dequantize the weights and sum up in a fixed order without fma, instead of
+    /// the simd kernels which differ by the platform and the simd width, so the logits are
+    /// the same bits everywhere. it's slower, and the activations are not quantized on the
+    /// way. the transcendental functions like the rope's sin and cos still come from the
+    /// libm of the platform.
+    pub deterministic: bool,
+
+    /// the dtype of the hidden states through the forward pass, F32 by default. with F16,
+    /// the norms, rope, activations, softmax and the elementwise ops read and write f16,
+    /// they still compute in f32 on each row, which halves the memory traffic of the
+    /// activations and skips the conversions on the f16 weights and kv cache.
+    pub activation_dtype: GGMLType,
+
+    /// how the sums of the batch_matmuls in the attention are kept from overflowing on the
+    /// long contexts, `Plain` by default.
+    pub attention_accumulation: AttentionAccumulation,
+
+    /// the pools the parallel ops run on by the task tag of the forward pass, the global
+    /// rayon pool by default.
+    pub thread_pools: ThreadPools,
+
+    /// run all the ops on the pool of this tag whatever the runner tags the passes with, like
+    /// `TaskTag::Background` for the device of an embedding job.
+    pub task_tag: Option<TaskTag>,
+}
+
+impl Default for CpuTensorDeviceOptions {
+    fn default() -> Self {
+        Self {
+            debug_named_tensors: false,
+            busy_poll: None,
+            deterministic: false,
+            activation_dtype: GGMLType::F32,
+            attention_accumulation: AttentionAccumulation::Plain,
+            thread_pools: ThreadPools::default(),
+            task_tag: None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct CpuTensorDevice<'a> {
     pub(crate) opts: CpuTensorDeviceOptions,
     pub(crate) metrics: TensorMetrics,
+    pub(crate) profiler: OpProfiler,
+    pub(crate) busy_poll: Option<BusyPoll>,
     pub(crate) debug_tensors: RefCell<HashMap<String, Vec<f32>>>,
     pub(crate) wbuf: RefCell<Option<Vec<f32>>>,
-    pub(crate) exp_cache: Rc<Vec<f16>>,
+    // the kv cache rows dequantized for the deterministic batch matmul, reused across the ops
+    pub(crate) strict_buf: RefCell<Vec<f32>>,
+    task_tag: Cell<TaskTag>,
     _phantom: std::marker::PhantomData<&'a ()>,
 }
 
@@ -28,24 +108,37 @@ pub type CpuTensorDeviceRef<'a> = Rc<CpuTensorDevice<'a>>;
 
 impl<'a> CpuTensorDevice<'a> {
     pub fn new() -> CpuTensorDeviceRef<'a> {
+        lut::init_tables();
         let device = Self {
             opts: CpuTensorDeviceOptions::default(),
+            busy_poll: None,
             debug_tensors: RefCell::new(HashMap::new()),
             metrics: TensorMetrics::default(),
+            profiler: OpProfiler::default(),
             wbuf: RefCell::new(Some(vec![0.0; 32000])),
-            exp_cache: Rc::new(Self::init_exp_cache()),
+            strict_buf: RefCell::new(vec![]),
+            task_tag: Cell::new(TaskTag::default()),
             _phantom: std::marker::PhantomData,
         };
         Rc::new(device)
     }
 
     pub fn with_options(opts: CpuTensorDeviceOptions) -> CpuTensorDeviceRef<'a> {
+        assert!(
+            matches!(opts.activation_dtype, GGMLType::F32 | GGMLType::F16),
+            "the activations are only supported in f32 or f16, got {:?}",
+            opts.activation_dtype
+        );
+        lut::init_tables();
         let device = Self {
+            busy_poll: opts.busy_poll.map(BusyPoll::new),
             opts,
             debug_tensors: RefCell::new(HashMap::new()),
             metrics: TensorMetrics::default(),
+            profiler: OpProfiler::default(),
             wbuf: RefCell::new(Some(vec![0.0; 32000])),
-            exp_cache: Rc::new(Self::init_exp_cache()),
+            strict_buf: RefCell::new(vec![]),
+            task_tag: Cell::new(TaskTag::default()),
             _phantom: std::marker::PhantomData,
         };
         Rc::new(device)
@@ -56,32 +149,74 @@ impl<'a> CpuTensorDevice<'a> {
             opts: self.opts.clone(),
             debug_tensors: self.debug_tensors.clone(),
             wbuf: self.wbuf.clone(),
-            exp_cache: self.exp_cache.clone(),
+            strict_buf: RefCell::new(vec![]),
+            busy_poll: self.busy_poll.clone(),
+            task_tag: self.task_tag.clone(),
             metrics,
+            profiler: self.profiler.clone(),
             _phantom: std::marker::PhantomData,
         };
         Rc::new(device)
     }
 
+    /// opts in the per-op profiling, which is reported by `profile_report()`.
+    pub fn with_profiler(self: Rc<Self>, profiler: OpProfiler) -> CpuTensorDeviceRef<'a> {
+        let device = Self {
+            opts: self.opts.clone(),
+            debug_tensors: self.debug_tensors.clone(),
+            wbuf: self.wbuf.clone(),
+            strict_buf: RefCell::new(vec![]),
+            busy_poll: self.busy_poll.clone(),
+            task_tag: self.task_tag.clone(),
+            metrics: self.metrics.clone(),
+            profiler,
+            _phantom: std::marker::PhantomData,
+        };
+        Rc::new(device)
+    }
+
+    /// tag the ops from now on, unless the options pin a tag.
+    pub fn set_task_tag(&self, tag: TaskTag) {
+        self.task_tag.set(tag);
+    }
+
+    pub fn task_tag(&self) -> TaskTag {
+        self.opts.task_tag.unwrap_or(self.task_tag.get())
+    }
+
+    // run the parallel loops of an op on the pool of the current tag
+    pub(crate) fn install<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+        self.opts.thread_pools.install(self.task_tag(), op)
+    }
+
+    pub fn is_deterministic(&self) -> bool {
+        self.opts.deterministic
+    }
+
+    pub fn activation_dtype(&self) -> GGMLType {
+        self.opts.activation_dtype
+    }
+
+    pub fn attention_accumulation(&self) -> AttentionAccumulation {
+        self.opts.attention_accumulation
+    }
+
     pub fn metrics(&self) -> &TensorMetrics {
         &self.metrics
     }
 
-    pub fn dump_debug_tensor(&self, name: &str) -> Option<Vec<f32>> {
-        self.debug_tensors.borrow().get(name).cloned()
+    pub fn profiler(&self) -> &OpProfiler {
+        &self.profiler
     }
 
-    pub fn exp_cache(&self) -> Rc<Vec<f16>> {
-        self.exp_cache.clone()
+    /// the wall time, calls, FLOPs and bytes of every op since the last reset, empty if the
+    /// profiler is not enabled.
+    pub fn profile_report(&self) -> ProfileReport {
+        self.profiler.report()
     }
 
-    fn init_exp_cache() -> Vec<f16> {
-        (0..65536)
-            .map(|x| {
-                let exp32 = f16::from_bits(x as u16).to_f32().exp();
-                f16::from_f32(exp32)
-            })
-            .collect()
+    pub fn dump_debug_tensor(&self, name: &str) -> Option<Vec<f32>> {
+        self.debug_tensors.borrow().get(name).cloned()
     }
 
     pub(crate) fn add_debug_tensor(&self, tensor: &CpuTensor<'a>) {
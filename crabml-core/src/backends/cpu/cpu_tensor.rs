@@ -1,14 +1,19 @@
 use std::borrow::Cow;
 
-use crate::backends::cpu::buf::buf_f16::alloc_f16_buf;
+use crate::backends::cpu::buf::buf_f16::try_alloc_f16_buf;
+use crate::backends::cpu::buf::buf_q8_0::BlockQ8_0;
 use crate::backends::cpu::buf::CpuTensorBuf;
+use crate::backends::cpu::buf::QuantBufQ8_0;
 use crate::backends::cpu::primitives;
 use crate::backends::cpu::CpuTensorDeviceRef;
 use crate::error::Error;
 use crate::error::ErrorKind;
 use crate::error::Result;
 use crate::gguf::GGMLType;
+use crate::tensor::profile::OpProfileGuard;
+use crate::tensor::MatmulEpilogue;
 use crate::tensor::RopeMode;
+use crate::tensor::TaskTag;
 use crate::tensor::Tensor;
 use crate::tensor::TensorStrider;
 
@@ -27,7 +32,7 @@ pub struct CpuTensor<'a> {
 // copying the owned buffer. Feel free to clone() the tensor.
 impl<'a> CpuTensor<'a> {
     pub fn new(buf: Vec<f32>, shape: &[usize], device: CpuTensorDeviceRef<'a>) -> Result<Self> {
-        if buf.len() != shape.iter().product() {
+        if buf.len() != shape.iter().product::<usize>() {
             return Err(Error {
                 kind: ErrorKind::TensorError,
                 message: format!("invalid shape {:?} for data of length {}", shape, buf.len()),
@@ -62,6 +67,12 @@ impl<'a> CpuTensor<'a> {
 
     pub fn dequantize(self, dtype: GGMLType) -> Result<Self> {
         let _t = self.device.metrics.dequantize_walltime.track();
+        let n = self.strider.len();
+        let _p = self.profile(
+            "dequantize",
+            0,
+            dtype_bytes(self.typ(), n) + dtype_bytes(dtype, n),
+        );
         let strider = self.strider.clone();
         let device = self.device.clone();
         let name = self.name.clone();
@@ -97,11 +108,11 @@ impl<'a> CpuTensor<'a> {
     /// to_vec is only used for test.
     #[allow(dead_code)]
     fn to_vec(&self) -> Vec<f32> {
-        assert!(self.dtype() == GGMLType::F32);
+        assert!(is_float(self.dtype()));
         if self.is_contiguous() {
             return self.buf.iter_f32().collect();
         }
-        let buf = self.buf().as_f32_ref();
+        let buf = self.buf().to_f32_cow();
         self.strider.iter().map(|pos| buf[pos]).collect()
     }
 
@@ -117,6 +128,51 @@ impl<'a> CpuTensor<'a> {
         &self.buf
     }
 
+    // the estimated bytes of the elements viewed by the tensor
+    fn bytes(&self) -> usize {
+        dtype_bytes(self.dtype(), self.strider.len())
+    }
+
+    fn profile(&self, op: &'static str, flops: usize, bytes: usize) -> OpProfileGuard {
+        self.device.profiler.track(op, flops, bytes)
+    }
+
+    // an inplace op costing `flops` on each element, which reads and writes the tensor once
+    fn profile_elementwise(
+        &self,
+        op: &'static str,
+        flops: usize,
+        rhs_bytes: usize,
+    ) -> OpProfileGuard {
+        self.profile(op, flops * self.strider.len(), 2 * self.bytes() + rhs_bytes)
+    }
+
+    // (m, k) @ (b, k) -> (b, m), the accumulator is read besides written
+    fn profile_matmul_vec(&self, x: &CpuTensor<'a>, acc: bool) -> OpProfileGuard {
+        let (m, k) = (self.shape()[0], self.shape()[1]);
+        let b = x.strider.len() / k;
+        let out_bytes = b * m * 4 * if acc { 2 } else { 1 };
+        let op = if acc { "matmul_vec_acc" } else { "matmul_vec" };
+        self.profile(op, 2 * b * m * k, self.bytes() + x.bytes() + out_bytes)
+    }
+
+    // (b, m, k) @ (b, k, n) -> (b, m, n)
+    fn profile_batch_matmul(&self, rhs: &CpuTensor<'a>, acc: bool) -> OpProfileGuard {
+        let (b, m, k) = (self.shape()[0], self.shape()[1], self.shape()[2]);
+        let n = rhs.shape()[2];
+        let out_bytes = b * m * n * 4 * if acc { 2 } else { 1 };
+        let op = if acc {
+            "batch_matmul_acc"
+        } else {
+            "batch_matmul"
+        };
+        self.profile(
+            op,
+            2 * b * m * k * n,
+            self.bytes() + rhs.bytes() + out_bytes,
+        )
+    }
+
     pub(crate) fn buf_mut(&mut self) -> &mut CpuTensorBuf<'a> {
         &mut self.buf
     }
@@ -126,23 +182,76 @@ impl<'a> Tensor for CpuTensor<'a> {
     type Device = CpuTensorDeviceRef<'a>;
 
     fn alloc(shape: &[usize], dtype: GGMLType, device: Self::Device) -> Result<Self> {
-        if dtype != GGMLType::F32 && dtype != GGMLType::F16 {
-            return Err((ErrorKind::TensorError, "only f32/f16 is supported").into());
+        if !matches!(
+            dtype,
+            GGMLType::F32 | GGMLType::F16 | GGMLType::Q8_0 | GGMLType::I8 | GGMLType::I32
+        ) {
+            return Err((
+                ErrorKind::TensorError,
+                "only f32/f16/q8_0/i8/i32 is supported",
+            )
+                .into());
+        }
+
+        let buf_size: usize = shape.iter().product();
+        if dtype == GGMLType::Q8_0 && buf_size % BlockQ8_0::BLOCK_ELEMS != 0 {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "q8_0 tensor size must be a multiple of 32, got {}",
+                    buf_size
+                ),
+            )
+                .into());
         }
 
-        let buf_size = shape.iter().product();
         let _t = device.metrics.alloc_walltime.track();
+        // fail with an OutOfMemory error instead of aborting the process, the caller may
+        // fall back to a smaller batch or a quantized kv cache
+        let oom = || {
+            let requested = buf_size.saturating_mul(dtype_bytes(dtype, 32)) / 32;
+            Error::out_of_memory(
+                requested,
+                None,
+                format!("failed to allocate a {:?} tensor of {:?}", dtype, shape),
+            )
+        };
         let buf = match dtype {
             GGMLType::F32 => {
-                let vec = Cow::Owned(vec![0.0; buf_size]);
-                CpuTensorBuf::F32(vec)
+                let mut vec = Vec::new();
+                vec.try_reserve_exact(buf_size).map_err(|_| oom())?;
+                vec.resize(buf_size, 0.0);
+                CpuTensorBuf::F32(Cow::Owned(vec))
             }
             GGMLType::F16 => {
                 // it's slow to initialize a vec![f16::ZERO; buf_size], nearly 80~200ms on preparing kv cache
-                let vec_f16 = alloc_f16_buf(buf_size);
+                let vec_f16 = try_alloc_f16_buf(buf_size).ok_or_else(oom)?;
                 let vec = Cow::Owned(vec_f16);
                 CpuTensorBuf::F16(vec)
             }
+            GGMLType::Q8_0 => {
+                // the q8_0 tensor is only used as the kv cache, the blocks are filled on
+                // concatenate
+                let n_blocks = buf_size / BlockQ8_0::BLOCK_ELEMS;
+                let mut blocks = Vec::new();
+                blocks.try_reserve_exact(n_blocks).map_err(|_| oom())?;
+                blocks.resize(n_blocks, BlockQ8_0::ZERO);
+                CpuTensorBuf::Q8_0(QuantBufQ8_0 {
+                    blocks: Cow::Owned(blocks),
+                })
+            }
+            GGMLType::I8 => {
+                let mut vec = Vec::new();
+                vec.try_reserve_exact(buf_size).map_err(|_| oom())?;
+                vec.resize(buf_size, 0);
+                CpuTensorBuf::I8(Cow::Owned(vec))
+            }
+            GGMLType::I32 => {
+                let mut vec = Vec::new();
+                vec.try_reserve_exact(buf_size).map_err(|_| oom())?;
+                vec.resize(buf_size, 0);
+                CpuTensorBuf::I32(Cow::Owned(vec))
+            }
             _ => unreachable!(),
         };
 
@@ -196,6 +305,14 @@ impl<'a> Tensor for CpuTensor<'a> {
         self.buf.dtype()
     }
 
+    fn activation_dtype(device: &Self::Device) -> GGMLType {
+        device.activation_dtype()
+    }
+
+    fn set_task_tag(device: &Self::Device, tag: TaskTag) {
+        device.set_task_tag(tag);
+    }
+
     fn reshape(self, shape: &[usize]) -> Result<Self> {
         let strider = self.strider.reshape(shape.to_vec())?;
         Ok(Self {
@@ -241,15 +358,19 @@ impl<'a> Tensor for CpuTensor<'a> {
 
     fn concatenate(&mut self, rhs: &Self, axis: usize) -> Result<()> {
         let _t = self.device.metrics.concatenate_walltime.track();
+        let _p = self.profile("concatenate", 0, 2 * rhs.bytes());
         // (2, 1) + (2, 1) at axis 0 -> (4, 1)
         // (2, 1) + (2, 3) at axis 1 -> (2, 4)
         if !self.is_owned() {
             return Err((ErrorKind::TensorError, "tensor not owned on concatenate").into());
         }
-        if self.dtype() != GGMLType::F32 && self.dtype() != GGMLType::F16 {
+        if self.dtype() != GGMLType::F32
+            && self.dtype() != GGMLType::F16
+            && self.dtype() != GGMLType::Q8_0
+        {
             return Err((
                 ErrorKind::TensorError,
-                "only f32/f16 is supported on concatenate",
+                "only f32/f16/q8_0 is supported on concatenate",
             )
                 .into());
         }
@@ -292,6 +413,7 @@ impl<'a> Tensor for CpuTensor<'a> {
         if self.is_contiguous() {
             return Ok(self);
         }
+        let _p = self.profile("contiguous", 0, 2 * self.bytes());
         assert!(self.dtype() == GGMLType::F32 || self.dtype() == GGMLType::F16);
 
         let mut out = CpuTensor::alloc(self.shape(), self.dtype(), self.device())?;
@@ -319,6 +441,8 @@ impl<'a> Tensor for CpuTensor<'a> {
         }
 
         let cols = *self.shape().last().unwrap();
+        let row_bytes = dtype_bytes(src.dtype(), cols) + dtype_bytes(self.dtype(), cols);
+        let _p = self.profile("copy_rows", 0, src_rows.len() * row_bytes);
         for (dst_row, src_row) in src_rows.iter().enumerate() {
             let src_offset = src_row * cols;
             let dst_offset = dst_row * cols;
@@ -327,14 +451,74 @@ impl<'a> Tensor for CpuTensor<'a> {
         Ok(())
     }
 
+    fn new_i32(data: &[i32], shape: &[usize], device: Self::Device) -> Result<Self> {
+        if data.len() != shape.iter().product::<usize>() {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "invalid shape {:?} for data of length {}",
+                    shape,
+                    data.len()
+                ),
+            )
+                .into());
+        }
+        Ok(Self {
+            buf: CpuTensorBuf::I32(Cow::Owned(data.to_vec())),
+            strider: TensorStrider::new(shape.to_vec()),
+            device,
+            name: None,
+        })
+    }
+
+    fn new_f32(data: &[f32], shape: &[usize], device: Self::Device) -> Result<Self> {
+        Self::new(data.to_vec(), shape, device)
+    }
+
+    fn get_rows(&mut self, src: &CpuTensor<'a>, ids: &CpuTensor<'a>) -> Result<()> {
+        if ids.dtype() != GGMLType::I32 || ids.strider.dims() != 1 {
+            return Err((
+                ErrorKind::TensorError,
+                "get_rows: ids is not a 1d i32 tensor",
+            )
+                .into());
+        }
+        let n_rows = src.shape()[0];
+        let rows = ids
+            .buf
+            .as_i32_ref()
+            .iter()
+            .map(|id| match usize::try_from(*id) {
+                Ok(row) if row < n_rows => Ok(row),
+                _ => Err((
+                    ErrorKind::TensorError,
+                    format!("get_rows: row {} is out of {} rows", id, n_rows),
+                )
+                    .into()),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.copy_rows_from(src, &rows)
+    }
+
     fn dup(&self) -> Result<Self> {
         let _t = self.device.metrics.dup_walltime.track();
+        let _p = self.profile("dup", 0, 2 * self.bytes());
+        // the f16 activations are kept in f16
+        if let CpuTensorBuf::F16(buf) = &self.buf {
+            return Ok(Self {
+                buf: CpuTensorBuf::F16(Cow::Owned(buf.to_vec())),
+                strider: TensorStrider::new(self.shape().to_vec()),
+                device: self.device.clone(),
+                name: None,
+            });
+        }
         let buf = self.buf.iter_f32().collect::<Vec<_>>();
         Self::new(buf, self.shape(), self.device.clone())
     }
 
     fn export(&self, dst: &mut [f32]) -> Result<()> {
         let _t = self.device.metrics.export_walltime.track();
+        let _p = self.profile("export", 0, self.bytes() + self.strider.len() * 4);
         assert!(self.is_contiguous());
 
         dst.iter_mut()
@@ -345,20 +529,93 @@ impl<'a> Tensor for CpuTensor<'a> {
         Ok(())
     }
 
+    fn export_i32(&self, dst: &mut [i32]) -> Result<()> {
+        if self.dtype() != GGMLType::I32 {
+            return Err((ErrorKind::TensorError, "export_i32: not an i32 tensor").into());
+        }
+        assert!(self.is_contiguous());
+        dst.iter_mut()
+            .zip(self.buf.as_i32_ref())
+            .for_each(|(dst, src)| *dst = *src);
+        Ok(())
+    }
+
     // (b, m, k) @ (b, k, n) -> (b, m, n)
     fn batch_matmul(&self, b: &CpuTensor<'a>) -> Result<Self> {
         let bufa = self.buf();
         let bufb = b.buf();
         let _t = self.device.metrics.batch_matmul_walltime.track();
+        let _p = self.profile_batch_matmul(b, false);
         let mut c = CpuTensor::alloc(
             &[self.shape()[0], self.shape()[1], b.shape()[2]],
-            GGMLType::F32,
+            self.device.activation_dtype(),
             self.device(),
         )?;
         let bufc = c.buf_mut();
         let strider1 = self.strider();
         let strider2 = b.strider();
-        primitives::batch_matmul(&self.device(), bufa, bufb, bufc, strider1, strider2);
+        primitives::batch_matmul(
+            &self.device(),
+            bufa,
+            bufb,
+            bufc,
+            strider1,
+            strider2,
+            false,
+            MatmulEpilogue::default(),
+        )?;
+        Ok(c)
+    }
+
+    fn batch_matmul_acc(&self, b: &CpuTensor<'a>, mut acc: Self) -> Result<Self> {
+        let c_shape = [self.shape()[0], self.shape()[1], b.shape()[2]];
+        if !is_float(acc.dtype()) || !acc.is_contiguous() || acc.shape() != c_shape {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "batch_matmul_acc: expected a contiguous f32/f16 accumulator of {:?}, got {:?} {:?}",
+                    c_shape,
+                    acc.dtype(),
+                    acc.shape()
+                ),
+            )
+                .into());
+        }
+        let _t = self.device.metrics.batch_matmul_walltime.track();
+        let _p = self.profile_batch_matmul(b, true);
+        let strider1 = self.strider();
+        let strider2 = b.strider();
+        primitives::batch_matmul(
+            &self.device(),
+            self.buf(),
+            b.buf(),
+            acc.buf_mut(),
+            strider1,
+            strider2,
+            true,
+            MatmulEpilogue::default(),
+        )?;
+        Ok(acc)
+    }
+
+    fn batch_matmul_epilogue(&self, b: &CpuTensor<'a>, epilogue: MatmulEpilogue) -> Result<Self> {
+        let _t = self.device.metrics.batch_matmul_walltime.track();
+        let _p = self.profile_batch_matmul(b, false);
+        let mut c = CpuTensor::alloc(
+            &[self.shape()[0], self.shape()[1], b.shape()[2]],
+            self.device.activation_dtype(),
+            self.device(),
+        )?;
+        primitives::batch_matmul(
+            &self.device(),
+            self.buf(),
+            b.buf(),
+            c.buf_mut(),
+            self.strider(),
+            b.strider(),
+            false,
+            epilogue,
+        )?;
         Ok(c)
     }
 
@@ -368,19 +625,58 @@ impl<'a> Tensor for CpuTensor<'a> {
     fn matmul_vec(&self, x: &CpuTensor<'a>) -> Result<Self> {
         let bufa = self.buf();
         let bufb = x.buf();
-        let mut c = CpuTensor::alloc(&[self.shape()[0]], GGMLType::F32, x.device())?;
+        let c_shape = match x.shape().len() {
+            1 => vec![self.shape()[0]],
+            _ => vec![x.shape()[0], self.shape()[0]],
+        };
+        let mut c = CpuTensor::alloc(&c_shape, self.device.activation_dtype(), x.device())?;
         let bufc = c.buf_mut();
         let strider1 = self.strider();
         let strider2 = x.strider();
         let _t = self.device.metrics.matmul_walltime.track();
-        primitives::matmul_vec(&self.device, bufa, bufb, bufc, strider1, strider2);
+        let _p = self.profile_matmul_vec(x, false);
+        primitives::matmul_vec(&self.device, bufa, bufb, bufc, strider1, strider2, false)?;
         Ok(c)
     }
 
+    fn matmul_vec_acc(&self, x: &CpuTensor<'a>, mut acc: Self) -> Result<Self> {
+        let c_len = match x.shape().len() {
+            1 => self.shape()[0],
+            _ => x.shape()[0] * self.shape()[0],
+        };
+        if !is_float(acc.dtype()) || !acc.is_contiguous() || acc.strider().len() != c_len {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "matmul_vec_acc: expected a contiguous f32/f16 accumulator of {} elements, got {:?} {:?}",
+                    c_len,
+                    acc.dtype(),
+                    acc.shape()
+                ),
+            )
+                .into());
+        }
+        let strider1 = self.strider();
+        let strider2 = x.strider();
+        let _t = self.device.metrics.matmul_walltime.track();
+        let _p = self.profile_matmul_vec(x, true);
+        primitives::matmul_vec(
+            &self.device,
+            self.buf(),
+            x.buf(),
+            acc.buf_mut(),
+            strider1,
+            strider2,
+            true,
+        )?;
+        Ok(acc)
+    }
+
     fn mul_inplace(mut self, rhs: &CpuTensor<'a>) -> Result<Self> {
         let strider1 = self.strider().clone();
         let strider2 = rhs.strider();
         let _t = self.device.metrics.mul_walltime.track();
+        let _p = self.profile_elementwise("mul", 1, rhs.bytes());
         primitives::mul_inplace(self.buf_mut(), rhs.buf(), &strider1, strider2)?;
         Ok(self)
     }
@@ -389,11 +685,13 @@ impl<'a> Tensor for CpuTensor<'a> {
         let strider1 = self.strider().clone();
         let strider2 = b.strider();
         let _t = self.device.metrics.add_walltime.track();
+        let _p = self.profile_elementwise("add", 1, b.bytes());
         primitives::add_inplace(self.buf_mut(), b.buf(), &strider1, strider2)?;
         Ok(self)
     }
 
     fn div_scalar_inplace(mut self, b: f32) -> Result<Self> {
+        let _p = self.profile_elementwise("div_scalar", 1, 0);
         let rhs = CpuTensor::new(vec![b], &[1], self.device())?;
         let strider1 = self.strider().clone();
         let strider2 = rhs.strider();
@@ -402,6 +700,7 @@ impl<'a> Tensor for CpuTensor<'a> {
     }
 
     fn scale_inplace(mut self, rhs: f32) -> Result<Self> {
+        let _p = self.profile_elementwise("scale", 1, 0);
         let rhs = CpuTensor::new(vec![rhs], &[1], self.device())?;
         let strider1 = self.strider().clone();
         let strider2 = rhs.strider();
@@ -411,46 +710,121 @@ impl<'a> Tensor for CpuTensor<'a> {
 
     fn silu_inplace(mut self) -> Result<Self> {
         let _t = self.device.metrics.activate_walltime.track();
+        // x / (1 + exp(-x))
+        let _p = self.profile_elementwise("silu", 4, 0);
         primitives::silu_inplace(self.device(), self.buf_mut())?;
         Ok(self)
     }
 
     fn gelu_inplace(mut self) -> Result<Self> {
         let _t = self.device.metrics.activate_walltime.track();
+        // 0.5 * x * (1 + tanh(sqrt(2 / pi) * (x + 0.044715 * x^3)))
+        let _p = self.profile_elementwise("gelu", 8, 0);
         primitives::gelu_inplace(self.device(), self.buf_mut())?;
         Ok(self)
     }
 
     fn softmax_inplace(mut self, axis: usize) -> Result<Self> {
         let _t = self.device.metrics.softmax_walltime.track();
+        // max, sub, exp, sum and div
+        let _p = self.profile_elementwise("softmax", 5, 0);
         let strider1 = self.strider().clone();
         primitives::softmax_inplace(self.device(), self.buf_mut(), strider1, axis)?;
         Ok(self)
     }
 
-    fn rope_inplace(mut self, mode: RopeMode, pos: usize, rope_dims: usize) -> Result<Self> {
+    fn causal_mask_inplace(mut self, sliding_window: Option<usize>) -> Result<Self> {
+        let _t = self.device.metrics.softmax_walltime.track();
+        let _p = self.profile_elementwise("causal_mask", 0, 0);
+        let strider1 = self.strider().clone();
+        primitives::causal_mask_inplace(self.buf_mut(), &strider1, sliding_window)?;
+        Ok(self)
+    }
+
+    fn block_mask_inplace(mut self, mask: &[bool]) -> Result<Self> {
+        let _t = self.device.metrics.softmax_walltime.track();
+        let _p = self.profile_elementwise("block_mask", 0, 0);
+        let strider1 = self.strider().clone();
+        primitives::block_mask_inplace(self.buf_mut(), &strider1, mask)?;
+        Ok(self)
+    }
+
+    fn rope_inplace(
+        mut self,
+        mode: RopeMode,
+        pos: usize,
+        rope_dims: usize,
+        freq_base: f32,
+    ) -> Result<Self> {
         let _t = self.device.metrics.rope_walltime.track();
+        // 4 mul and 2 add on each rotated pair, besides the sin and cos
+        let head_dim = *self.shape().last().unwrap();
+        let rotated = self.strider.len() / head_dim * rope_dims.min(head_dim);
+        let _p = self.profile("rope", 3 * rotated, 2 * self.bytes());
         let strider1 = self.strider().clone();
         let buf1 = self.buf_mut();
-        primitives::rope_inplace(buf1, &strider1, mode, pos, rope_dims)?;
+        primitives::rope_inplace(buf1, &strider1, mode, pos, rope_dims, freq_base)?;
+        Ok(self)
+    }
+
+    fn rope_positions_inplace(
+        mut self,
+        mode: RopeMode,
+        positions: &[usize],
+        rope_dims: usize,
+        freq_base: f32,
+    ) -> Result<Self> {
+        let _t = self.device.metrics.rope_walltime.track();
+        let head_dim = *self.shape().last().unwrap();
+        let rotated = self.strider.len() / head_dim * rope_dims.min(head_dim);
+        let _p = self.profile("rope", 3 * rotated, 2 * self.bytes());
+        let strider1 = self.strider().clone();
+        let buf1 = self.buf_mut();
+        primitives::rope_positions_inplace(buf1, &strider1, mode, positions, rope_dims, freq_base)?;
         Ok(self)
     }
 
     fn rms_norm_inplace(mut self, eps: f32) -> Result<Self> {
         let _t = self.device.metrics.rms_norm_walltime.track();
+        // square, sum and scale
+        let _p = self.profile_elementwise("rms_norm", 3, 0);
         let strider1 = self.strider().clone();
         let buf1 = self.buf_mut();
         primitives::rms_norm_inplace(buf1, &strider1, eps)?;
         Ok(self)
     }
+
+    fn layer_norm_inplace(mut self, eps: f32) -> Result<Self> {
+        let _t = self.device.metrics.rms_norm_walltime.track();
+        // mean, variance, sub and scale
+        let _p = self.profile_elementwise("layer_norm", 5, 0);
+        let strider1 = self.strider().clone();
+        let buf1 = self.buf_mut();
+        primitives::layer_norm_inplace(buf1, &strider1, eps)?;
+        Ok(self)
+    }
+}
+
+fn is_float(dtype: GGMLType) -> bool {
+    dtype == GGMLType::F32 || dtype == GGMLType::F16
+}
+
+// the bytes of n elements in the dtype, by the block layouts of ggml
+fn dtype_bytes(dtype: GGMLType, n: usize) -> usize {
+    n * dtype.type_size() / dtype.block_size()
 }
 
 #[cfg(test)]
 mod tests {
     use approx::assert_relative_eq;
+    use half::f16;
 
     use super::*;
+    use crate::backends::cpu::buf::buf_f32::vec_dot_f32_f32_strict;
+    use crate::backends::cpu::AttentionAccumulation;
     use crate::backends::cpu::CpuTensorDevice;
+    use crate::backends::cpu::CpuTensorDeviceOptions;
+    use crate::tensor::OpProfiler;
 
     #[test]
     fn test_tensor_view() -> Result<()> {
@@ -480,6 +854,59 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_get_rows() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let t1 = CpuTensor::new(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[3, 2], device.clone())?;
+        let ids = CpuTensor::new_i32(&[2, 0, 2], &[3], device.clone())?;
+        let mut t2 = CpuTensor::alloc(&[3, 2], GGMLType::F32, device.clone())?;
+        t2.get_rows(&t1, &ids)?;
+        assert_eq!(t2.to_vec(), vec![5.0, 6.0, 1.0, 2.0, 5.0, 6.0]);
+
+        let mut exported = vec![0; 3];
+        ids.export_i32(&mut exported)?;
+        assert_eq!(exported, vec![2, 0, 2]);
+
+        let ids = CpuTensor::new_i32(&[3], &[1], device.clone())?;
+        assert!(t2.get_rows(&t1, &ids).is_err());
+
+        // the masks in i8 are converted by value
+        let mask = CpuTensor::alloc(&[4], GGMLType::I8, device.clone())?;
+        assert_eq!(mask.buf().as_i8_ref(), &[0; 4]);
+        assert_eq!(
+            mask.buf().clone().dequantize(GGMLType::F32)?.as_f32_ref(),
+            &[0.0; 4]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_rows_quantized_f16() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let (rows, cols) = (4, 64);
+        let w = (0..rows * cols)
+            .map(|i| ((i * 7 + 3) % 41) as f32 * 0.13 - 2.6)
+            .collect::<Vec<_>>();
+        let ids = CpuTensor::new_i32(&[3, 0, 3], &[3], device.clone())?;
+        for dtype in [GGMLType::Q8_0, GGMLType::Q4_0] {
+            let wq = CpuTensorBuf::F32(w.clone().into()).quantize(dtype)?;
+            let wq = CpuTensor::from_bytes(wq.as_bytes(), dtype, &[rows, cols], device.clone())?;
+
+            // the rows dequantized into f16 directly are the f32 rows rounded to f16
+            let mut expected = CpuTensor::alloc(&[3, cols], GGMLType::F32, device.clone())?;
+            expected.get_rows(&wq, &ids)?;
+            let mut got = CpuTensor::alloc(&[3, cols], GGMLType::F16, device.clone())?;
+            got.get_rows(&wq, &ids)?;
+            let expected = expected
+                .to_vec()
+                .into_iter()
+                .map(|v| f16::from_f32(v).to_f32())
+                .collect::<Vec<_>>();
+            assert_eq!(got.to_vec(), expected, "{:?}", dtype);
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_rms_norm() -> Result<()> {
         pub fn simple_rmsnorm(x: &mut [f32]) {
@@ -511,7 +938,7 @@ mod tests {
         let v1 = (0..32).map(|v| v as f32).collect::<Vec<_>>();
         let t1 = CpuTensor::new(v1, &[2, 16], device.clone())?;
 
-        let r1 = t1.rope_inplace(RopeMode::Llama, 1, 2)?;
+        let r1 = t1.rope_inplace(RopeMode::Llama, 1, 2, 10000.0)?;
         let out = r1.to_vec();
         assert_relative_eq!(
             &out[..],
@@ -547,6 +974,193 @@ mod tests {
         let out = w.matmul_vec(&b)?;
         assert_eq!(out.to_vec(), &[5.0, 11.0, 17.0, 23.0]);
 
+        // the residual is accumulated into the output
+        let acc = CpuTensor::new(vec![1.0, 1.0, 2.0, 2.0], &[4], device.clone())?;
+        let out = w.matmul_vec_acc(&b, acc)?;
+        assert_eq!(out.to_vec(), &[6.0, 12.0, 19.0, 25.0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_matmul_deterministic() -> Result<()> {
+        let device = CpuTensorDevice::with_options(CpuTensorDeviceOptions {
+            deterministic: true,
+            ..Default::default()
+        });
+        let (m, k) = (8, 96);
+        let w = (0..m * k)
+            .map(|i| (i as f32 * 0.37).sin())
+            .collect::<Vec<_>>();
+        let x = (0..2 * k)
+            .map(|i| (i as f32 * 0.11).cos())
+            .collect::<Vec<_>>();
+        let wq = CpuTensorBuf::F32(w.into()).quantize(GGMLType::Q8_0)?;
+        let wq = CpuTensor::from_bytes(wq.as_bytes(), GGMLType::Q8_0, &[m, k], device.clone())?;
+        let x = CpuTensor::new(x, &[2, k], device.clone())?;
+
+        // the quantized weights are dequantized and dotted in the fixed order, the same bits
+        // as on the dequantized weights
+        let out = wq.matmul_vec(&x)?.to_vec();
+        let wf = wq.clone().dequantize(GGMLType::F32)?;
+        assert_eq!(out, wf.matmul_vec(&x)?.to_vec());
+        for (i, v) in out.iter().enumerate() {
+            let (bi, mi) = (i / m, i % m);
+            let row = &wf.buf().as_f32_ref()[mi * k..(mi + 1) * k];
+            let xs = &x.buf().as_f32_ref()[bi * k..(bi + 1) * k];
+            assert_eq!(*v, vec_dot_f32_f32_strict(row, xs));
+        }
+
+        // close to the simd kernels on the quantized activations
+        let x2 = CpuTensor::new(x.to_vec(), &[2, k], CpuTensorDevice::new())?;
+        let wq2 = CpuTensor::from_bytes(wq.buf().as_bytes(), GGMLType::Q8_0, &[m, k], x2.device())?;
+        assert_relative_eq!(&out[..], &wq2.matmul_vec(&x2)?.to_vec()[..], epsilon = 0.05);
+        Ok(())
+    }
+
+    #[test]
+    fn test_f16_activations() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let device_f16 = CpuTensorDevice::with_options(CpuTensorDeviceOptions {
+            activation_dtype: GGMLType::F16,
+            ..Default::default()
+        });
+        let (rows, cols) = (3, 64);
+        let data = (0..rows * cols)
+            .map(|i| (i as f32 * 0.37).sin() * 2.0)
+            .collect::<Vec<_>>();
+        let rhs = (0..cols)
+            .map(|i| (i as f32 * 0.11).cos())
+            .collect::<Vec<_>>();
+        let t32 = CpuTensor::new(data.clone(), &[rows, cols], device.clone())?;
+        let t16 =
+            CpuTensor::new(data, &[rows, cols], device_f16.clone())?.dequantize(GGMLType::F16)?;
+        let rhs32 = CpuTensor::new(rhs, &[cols], device.clone())?;
+        let rhs16 = rhs32.clone().dequantize(GGMLType::F16)?;
+
+        type Op<'a> = Box<dyn Fn(CpuTensor<'a>, &CpuTensor<'a>) -> Result<CpuTensor<'a>>>;
+        let ops: Vec<(&str, Op)> = vec![
+            ("rms_norm", Box::new(|t, _| t.rms_norm_inplace(1e-5))),
+            ("layer_norm", Box::new(|t, _| t.layer_norm_inplace(1e-5))),
+            ("silu", Box::new(|t, _| t.silu_inplace())),
+            ("gelu", Box::new(|t, _| t.gelu_inplace())),
+            ("softmax", Box::new(|t, _| t.softmax_inplace(1))),
+            ("add", Box::new(|t, rhs| t.add_inplace(rhs))),
+            ("mul", Box::new(|t, rhs| t.mul_inplace(rhs))),
+            ("scale", Box::new(|t, _| t.scale_inplace(0.5))),
+            ("div_scalar", Box::new(|t, _| t.div_scalar_inplace(8.0))),
+            (
+                "rope",
+                Box::new(move |t, _| {
+                    t.reshape(&[rows, 2, cols / 2])?.rope_inplace(
+                        RopeMode::Llama,
+                        3,
+                        cols / 2,
+                        10000.0,
+                    )
+                }),
+            ),
+        ];
+        for (name, op) in ops.iter() {
+            let out32 = op(t32.clone(), &rhs32)?;
+            // the rhs in f32 like the norm weights, or in f16 like the other activations
+            for rhs in [&rhs32, &rhs16] {
+                let out16 = op(t16.dup()?, rhs)?;
+                assert_eq!(out16.dtype(), GGMLType::F16, "{}", name);
+                assert_relative_eq!(
+                    &out16.to_vec()[..],
+                    &out32.to_vec()[..],
+                    epsilon = 1e-2,
+                    max_relative = 5e-3
+                );
+            }
+        }
+
+        // the matmuls write the activations in f16, and accumulate into them
+        let w = CpuTensor::new(
+            (0..8 * cols).map(|i| (i as f32 * 0.7).cos()).collect(),
+            &[8, cols],
+            device.clone(),
+        )?;
+        let w16 = CpuTensor::from_bytes(w.buf().as_bytes(), GGMLType::F32, &[8, cols], device_f16)?;
+        let out32 = w.matmul_vec(&t32)?;
+        let out16 = w16.matmul_vec(&t16)?;
+        assert_eq!(out16.dtype(), GGMLType::F16);
+        assert_relative_eq!(
+            &out16.to_vec()[..],
+            &out32.to_vec()[..],
+            epsilon = 5e-2,
+            max_relative = 5e-3
+        );
+        let acc16 = w16.matmul_vec_acc(&t16, out16.dup()?)?;
+        let doubled = out32.to_vec().iter().map(|v| v * 2.0).collect::<Vec<_>>();
+        assert_relative_eq!(
+            &acc16.to_vec()[..],
+            &doubled[..],
+            epsilon = 1e-1,
+            max_relative = 5e-3
+        );
+
+        // the attention scores in f16 are masked and attend to the f16 values
+        let q16 = t16.dup()?.reshape(&[1, rows, cols])?;
+        let k16 = t16
+            .dup()?
+            .reshape(&[1, rows, cols])?
+            .transpose(&[0, 2, 1])?;
+        let attn16 = q16.batch_matmul(&k16)?.div_scalar_inplace(8.0)?;
+        assert_eq!(attn16.dtype(), GGMLType::F16);
+        let attn16 = attn16.causal_mask_inplace(None)?.softmax_inplace(2)?;
+        let q32 = t32.clone().reshape(&[1, rows, cols])?;
+        let k32 = t32
+            .clone()
+            .reshape(&[1, rows, cols])?
+            .transpose(&[0, 2, 1])?;
+        let attn32 = q32
+            .batch_matmul(&k32)?
+            .div_scalar_inplace(8.0)?
+            .causal_mask_inplace(None)?
+            .softmax_inplace(2)?;
+        assert_relative_eq!(
+            &attn16.to_vec()[..],
+            &attn32.to_vec()[..],
+            epsilon = 1e-2,
+            max_relative = 5e-3
+        );
+        let v16 = t16.dup()?.reshape(&[1, rows, cols])?;
+        let v32 = t32.clone().reshape(&[1, rows, cols])?;
+        assert_relative_eq!(
+            &attn16.batch_matmul(&v16)?.to_vec()[..],
+            &attn32.batch_matmul(&v32)?.to_vec()[..],
+            epsilon = 2e-2,
+            max_relative = 5e-3
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_profile_report() -> Result<()> {
+        let device = CpuTensorDevice::new().with_profiler(OpProfiler::new());
+        let w = CpuTensor::new(vec![1.0; 8], &[4, 2], device.clone())?;
+        let x = CpuTensor::new(vec![1.0; 6], &[3, 2], device.clone())?;
+        let out = w.matmul_vec(&x)?;
+        let out = out.softmax_inplace(1)?.silu_inplace()?;
+        assert_eq!(out.to_vec().len(), 12);
+
+        let report = device.profile_report();
+        assert_eq!(report.ops.len(), 3);
+        let matmul = report.op("matmul_vec").unwrap();
+        // 2 * b * m * k, and the bytes of w, x and the output
+        assert_eq!((matmul.calls, matmul.flops), (1, 2 * 3 * 4 * 2));
+        assert_eq!(matmul.bytes, (8 + 6 + 12) * 4);
+        assert_eq!(report.op("softmax").unwrap().flops, 5 * 12);
+        assert_eq!(report.op("silu").unwrap().bytes, 2 * 12 * 4);
+
+        device.profiler().reset();
+        assert!(device.profile_report().ops.is_empty());
+        // disabled by default
+        let device = CpuTensorDevice::new();
+        CpuTensor::new(vec![1.0; 4], &[4], device.clone())?.silu_inplace()?;
+        assert!(device.profile_report().ops.is_empty());
         Ok(())
     }
 
@@ -566,6 +1180,24 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_softmax_negative_rows() -> Result<()> {
+        // the rows are shifted by their own max, a row of all the values far below 0, like
+        // the saturated scores, gets the same probabilities as the row shifted up to 0
+        // instead of the exps all underflowing into 0 / 0
+        let device = CpuTensorDevice::new();
+        let t1 = CpuTensor::new(vec![-200.0, -201.0, -202.0], &[1, 3], device.clone())?;
+        let t2 = CpuTensor::new(vec![0.0, -1.0, -2.0], &[1, 3], device.clone())?;
+        let (t1, t2) = (t1.softmax_inplace(1)?, t2.softmax_inplace(1)?);
+        assert!(t1.to_vec().iter().all(|v| v.is_finite()));
+        assert_relative_eq!(&t1.to_vec()[..], &t2.to_vec()[..], epsilon = 1e-6);
+
+        // the masked positions are still 0
+        let t3 = CpuTensor::new(vec![f32::NEG_INFINITY, -5.0, -5.0], &[1, 3], device)?;
+        assert_relative_eq!(&t3.softmax_inplace(1)?.to_vec()[..], &[0.0, 0.5, 0.5][..]);
+        Ok(())
+    }
+
     #[test]
     fn test_silu() -> Result<()> {
         let device = CpuTensorDevice::new();
@@ -613,9 +1245,304 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_alloc_out_of_memory() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let err = CpuTensor::alloc(&[1 << 40, 1 << 14], GGMLType::F32, device.clone())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind, ErrorKind::OutOfMemory);
+        let oom = err.as_out_of_memory().unwrap();
+        assert_eq!(oom.requested, 1 << 56);
+        assert_eq!(oom.budget, None);
+        Ok(())
+    }
+
     #[test]
     fn test_resize() -> Result<()> {
         // todo:
         Ok(())
     }
+
+    #[test]
+    fn test_q8_0_kv_cache() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let (n_heads, seq_len, head_dim) = (2, 4, 64);
+
+        // append 3 positions into the f32 and q8_0 caches in the layout of
+        // (n_heads, seq, head_dim)
+        let kv = (0..n_heads * 3 * head_dim)
+            .map(|i| (i as f32 * 0.37).sin())
+            .collect::<Vec<_>>();
+        let kv =
+            CpuTensor::new(kv, &[3, n_heads, head_dim], device.clone())?.transpose(&[1, 0, 2])?;
+        let mut caches = vec![];
+        for dtype in [GGMLType::F32, GGMLType::Q8_0] {
+            let mut cache = CpuTensor::alloc(&[n_heads, seq_len, head_dim], dtype, device.clone())?
+                .resize(1, 0)?;
+            cache.concatenate(&kv, 1)?;
+            assert_eq!(cache.shape(), &[n_heads, 3, head_dim]);
+            caches.push(cache);
+        }
+
+        // q @ k_cache.T => (n_heads, 1, seq)
+        let q = (0..n_heads * head_dim)
+            .map(|i| (i as f32 * 0.11).cos())
+            .collect::<Vec<_>>();
+        let q = CpuTensor::new(q, &[n_heads, 1, head_dim], device.clone())?;
+        let attns = caches
+            .iter()
+            .map(|cache| {
+                let k_cache = cache.clone().transpose(&[0, 2, 1])?;
+                q.batch_matmul(&k_cache)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        assert_relative_eq!(
+            &attns[0].to_vec()[..],
+            &attns[1].to_vec()[..],
+            epsilon = 5e-2
+        );
+
+        // attn @ v_cache => (n_heads, 1, head_dim)
+        let outs = caches
+            .iter()
+            .map(|cache| attns[0].batch_matmul(cache))
+            .collect::<Result<Vec<_>>>()?;
+        assert_relative_eq!(
+            &outs[0].to_vec()[..],
+            &outs[1].to_vec()[..],
+            epsilon = 5e-2,
+            max_relative = 2e-2
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_matmul_deterministic() -> Result<()> {
+        let device = CpuTensorDevice::with_options(CpuTensorDeviceOptions {
+            deterministic: true,
+            ..Default::default()
+        });
+        let (n_heads, seq_len, head_dim) = (2, 8, 32);
+        let kv = (0..n_heads * 3 * head_dim)
+            .map(|i| (i as f32 * 0.37).sin())
+            .collect::<Vec<_>>();
+        let kv = CpuTensor::new(kv, &[n_heads, 3, head_dim], device.clone())?;
+        let q = (0..n_heads * head_dim)
+            .map(|i| (i as f32 * 0.11).cos())
+            .collect::<Vec<_>>();
+        let q = CpuTensor::new(q, &[n_heads, 1, head_dim], device.clone())?;
+
+        for dtype in [GGMLType::F16, GGMLType::Q8_0] {
+            let mut cache = CpuTensor::alloc(&[n_heads, seq_len, head_dim], dtype, device.clone())?
+                .resize(1, 0)?;
+            cache.concatenate(&kv, 1)?;
+            let values = cache.buf().clone().dequantize(GGMLType::F32)?;
+            let values = values.as_f32_ref().to_vec();
+            let cache_f32 = CpuTensor::new(values, &[n_heads, seq_len, head_dim], device.clone())?
+                .resize(1, 3)?;
+
+            // the same bits as the naive loop on the dequantized cache
+            let k_cache = cache.clone().transpose(&[0, 2, 1])?;
+            let attn = q.batch_matmul(&k_cache)?;
+            let k_cache_f32 = cache_f32.clone().transpose(&[0, 2, 1])?;
+            assert_eq!(attn.to_vec(), q.batch_matmul(&k_cache_f32)?.to_vec());
+            let out = attn.batch_matmul(&cache)?;
+            assert_eq!(out.to_vec(), attn.batch_matmul(&cache_f32)?.to_vec());
+
+            // only the live positions up to the last head are dequantized
+            let scratch = device.strict_buf.borrow().len();
+            assert_eq!(scratch, seq_len * head_dim + 3 * head_dim);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_matmul_acc() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let (n_heads, seq_len, head_dim) = (2, 3, 32);
+        let kv = (0..n_heads * seq_len * head_dim)
+            .map(|i| (i as f32 * 0.37).sin())
+            .collect::<Vec<_>>();
+        let kv = CpuTensor::new(kv, &[n_heads, seq_len, head_dim], device.clone())?;
+        let q = (0..n_heads * head_dim)
+            .map(|i| (i as f32 * 0.11).cos())
+            .collect::<Vec<_>>();
+        let q = CpuTensor::new(q, &[n_heads, 1, head_dim], device.clone())?;
+
+        for dtype in [GGMLType::F32, GGMLType::F16, GGMLType::Q8_0] {
+            let mut cache = CpuTensor::alloc(&[n_heads, seq_len, head_dim], dtype, device.clone())?
+                .resize(1, 0)?;
+            cache.concatenate(&kv, 1)?;
+
+            // contiguous on the K dimension: q @ k_cache.T => (n_heads, 1, seq)
+            let k_cache = cache.clone().transpose(&[0, 2, 1])?;
+            let attn = q.batch_matmul(&k_cache)?;
+            let doubled = q.batch_matmul_acc(&k_cache, attn.dup()?)?;
+            let expected = attn.to_vec().iter().map(|v| v * 2.0).collect::<Vec<_>>();
+            assert_relative_eq!(&doubled.to_vec()[..], &expected[..], epsilon = 1e-4);
+
+            // contiguous on the N dimension: attn @ v_cache => (n_heads, 1, head_dim)
+            let out = attn.batch_matmul(&cache)?;
+            let doubled = attn.batch_matmul_acc(&cache, out.dup()?)?;
+            let expected = out.to_vec().iter().map(|v| v * 2.0).collect::<Vec<_>>();
+            assert_relative_eq!(&doubled.to_vec()[..], &expected[..], epsilon = 1e-4);
+
+            assert!(q.batch_matmul_acc(&k_cache, out).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_matmul_epilogue() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let (n_heads, seq_len, head_dim) = (2, 3, 32);
+        let kv = (0..n_heads * seq_len * head_dim)
+            .map(|i| (i as f32 * 0.37).sin() * 4.0)
+            .collect::<Vec<_>>();
+        let kv = CpuTensor::new(kv, &[n_heads, seq_len, head_dim], device.clone())?;
+        let q = (0..n_heads * head_dim)
+            .map(|i| (i as f32 * 0.11).cos())
+            .collect::<Vec<_>>();
+        let q = CpuTensor::new(q, &[n_heads, 1, head_dim], device.clone())?;
+
+        let epilogue = MatmulEpilogue::new(0.5, Some(3.0));
+        for dtype in [GGMLType::F32, GGMLType::F16, GGMLType::Q8_0] {
+            let mut cache = CpuTensor::alloc(&[n_heads, seq_len, head_dim], dtype, device.clone())?
+                .resize(1, 0)?;
+            cache.concatenate(&kv, 1)?;
+
+            // contiguous on the K dimension like the key cache, and on the N dimension like
+            // the value cache
+            let k_cache = cache.clone().transpose(&[0, 2, 1])?;
+            let attn = q.batch_matmul(&k_cache)?;
+            let scores = q.batch_matmul_epilogue(&k_cache, epilogue)?;
+            let expected = attn
+                .to_vec()
+                .iter()
+                .map(|v| epilogue.apply(*v))
+                .collect::<Vec<_>>();
+            assert_relative_eq!(&scores.to_vec()[..], &expected[..], epsilon = 1e-4);
+            assert!(scores.to_vec().iter().all(|v| v.abs() < 3.0));
+
+            let out = attn.batch_matmul(&cache)?;
+            let capped = attn.batch_matmul_epilogue(&cache, epilogue)?;
+            let expected = out
+                .to_vec()
+                .iter()
+                .map(|v| epilogue.apply(*v))
+                .collect::<Vec<_>>();
+            assert_relative_eq!(&capped.to_vec()[..], &expected[..], epsilon = 1e-4);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_attention_accumulation_long_context() -> Result<()> {
+        let device_with = |attention_accumulation, activation_dtype| {
+            CpuTensorDevice::with_options(CpuTensorDeviceOptions {
+                attention_accumulation,
+                activation_dtype,
+                ..Default::default()
+            })
+        };
+        let f16_cache = |data: Vec<f32>, shape: &[usize], device| -> Result<CpuTensor> {
+            let data = CpuTensor::new(data, shape, device)?;
+            let mut cache = CpuTensor::alloc(shape, GGMLType::F16, data.device())?.resize(1, 0)?;
+            cache.concatenate(&data, 1)?;
+            Ok(cache)
+        };
+        let (seq_len, head_dim) = (65536, 32);
+
+        // attn @ v with the uniform attention over 64k positions, each probability is 2^-16,
+        // the f16 sum stops growing at 1/16
+        let values = (0..seq_len * head_dim)
+            .map(|i| 1.0 + (i % head_dim) as f32 / head_dim as f32)
+            .collect::<Vec<_>>();
+        let expected = (0..head_dim)
+            .map(|i| 1.0 + i as f32 / head_dim as f32)
+            .collect::<Vec<_>>();
+        for mode in [
+            AttentionAccumulation::Plain,
+            AttentionAccumulation::Saturate,
+            AttentionAccumulation::Rescale,
+        ] {
+            let device = device_with(mode, GGMLType::F32);
+            let v_cache = f16_cache(values.clone(), &[1, seq_len, head_dim], device.clone())?;
+            let attn = CpuTensor::new(
+                vec![1.0 / seq_len as f32; seq_len],
+                &[1, 1, seq_len],
+                device.clone(),
+            )?;
+            let out = attn.batch_matmul(&v_cache)?.to_vec();
+            if mode == AttentionAccumulation::Rescale {
+                assert_relative_eq!(&out[..], &expected[..], max_relative = 1e-3);
+            } else {
+                assert!(out.iter().zip(&expected).all(|(o, e)| *o < e * 0.5));
+            }
+        }
+
+        // the scores q·k / sqrt(head_dim) = ±2^17 overflow the f16 activations, the softmax
+        // of an inf row is NaN
+        let keys = (0..seq_len * head_dim)
+            .map(|i| {
+                if (i / head_dim) % 2 == 0 {
+                    128.0
+                } else {
+                    -128.0
+                }
+            })
+            .collect::<Vec<_>>();
+        let epilogue = MatmulEpilogue::new(1.0 / (head_dim as f32).sqrt(), None);
+        for mode in [
+            AttentionAccumulation::Plain,
+            AttentionAccumulation::Saturate,
+            AttentionAccumulation::Rescale,
+        ] {
+            let device = device_with(mode, GGMLType::F16);
+            let k_cache = f16_cache(keys.clone(), &[1, seq_len, head_dim], device.clone())?
+                .transpose(&[0, 2, 1])?;
+            let q = CpuTensor::new(vec![128.0; head_dim], &[1, 1, head_dim], device.clone())?
+                .dequantize(GGMLType::F16)?;
+            let scores = q.batch_matmul_epilogue(&k_cache, epilogue)?;
+            let probs = scores.clone().softmax_inplace(2)?.to_vec();
+            let scores = scores.to_vec();
+            if mode == AttentionAccumulation::Plain {
+                assert!(scores.iter().all(|s| s.is_infinite()));
+                assert!(probs.iter().all(|p| p.is_nan()));
+                continue;
+            }
+            let max = f16::MAX.to_f32();
+            assert!(
+                scores
+                    .iter()
+                    .enumerate()
+                    .all(|(i, s)| *s == if i % 2 == 0 { max } else { -max })
+            );
+            assert!(probs.iter().all(|p| p.is_finite()));
+            assert_relative_eq!(probs.iter().sum::<f32>(), 1.0, max_relative = 1e-2);
+        }
+
+        // the f32 scores are out of the reach of the f16 overflow, they are not clamped
+        for mode in [
+            AttentionAccumulation::Saturate,
+            AttentionAccumulation::Rescale,
+        ] {
+            let device = device_with(mode, GGMLType::F32);
+            let k_cache = CpuTensor::new(
+                keys[..8 * head_dim].to_vec(),
+                &[1, 8, head_dim],
+                device.clone(),
+            )?
+            .transpose(&[0, 2, 1])?;
+            let q = CpuTensor::new(vec![128.0; head_dim], &[1, 1, head_dim], device.clone())?;
+            let scores = q.batch_matmul_epilogue(&k_cache, epilogue)?.to_vec();
+            let expected = 128.0 * 128.0 * (head_dim as f32).sqrt();
+            for (i, s) in scores.iter().enumerate() {
+                let e = if i % 2 == 0 { expected } else { -expected };
+                assert_relative_eq!(*s, e, max_relative = 1e-5);
+            }
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,205 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+/// the importance matrix of llama.cpp: the squared activations of the input columns of
+/// every weight, summed over the calibration tokens. the columns with the larger
+/// activations take more of the error of a quantized weight into the output.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportanceMatrix {
+    entries: BTreeMap<String, ImatrixEntry>,
+    /// the chunks of the calibration text.
+    pub last_call: i32,
+    /// the calibration text the matrix is computed on.
+    pub dataset: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct ImatrixEntry {
+    // the sums of the squared activations of each column, over ncall calls
+    values: Vec<f32>,
+    ncall: i32,
+}
+
+impl ImportanceMatrix {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// read the `.dat` file written by the llama-imatrix tool of llama.cpp.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let bytes = std::fs::read(path.as_ref()).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to read {}", path.as_ref().display()),
+            cause: Some(Box::new(err)),
+        })?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// the layout is n_entries, then the name, ncall, nval and the values of each entry,
+    /// followed by the optional last_call and the dataset name. the integers are i32.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut r = Reader { bytes, offset: 0 };
+        let mut imatrix = Self::new();
+        let n_entries = r.read_len()?;
+        for _ in 0..n_entries {
+            let name = r.read_string()?;
+            let ncall = r.read_i32()?;
+            let nval = r.read_len()?;
+            let values = (0..nval)
+                .map(|_| r.read_f32())
+                .collect::<Result<Vec<_>>>()?;
+            imatrix.entries.insert(name, ImatrixEntry { values, ncall });
+        }
+        if r.offset < bytes.len() {
+            imatrix.last_call = r.read_i32()?;
+            imatrix.dataset = r.read_string()?;
+        }
+        Ok(imatrix)
+    }
+
+    /// write the `.dat` file read by the quantize tool of llama.cpp.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        std::fs::write(path.as_ref(), self.to_bytes()).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to write {}", path.as_ref().display()),
+            cause: Some(Box::new(err)),
+        })
+    }
+
+    /// the layout of `from_bytes()`, with the last_call and the dataset name.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        let write_string = |buf: &mut Vec<u8>, s: &str| {
+            buf.extend_from_slice(&(s.len() as i32).to_le_bytes());
+            buf.extend_from_slice(s.as_bytes());
+        };
+        buf.extend_from_slice(&(self.entries.len() as i32).to_le_bytes());
+        for (name, entry) in self.entries.iter() {
+            write_string(&mut buf, name);
+            buf.extend_from_slice(&entry.ncall.to_le_bytes());
+            buf.extend_from_slice(&(entry.values.len() as i32).to_le_bytes());
+            for v in entry.values.iter() {
+                buf.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        buf.extend_from_slice(&self.last_call.to_le_bytes());
+        write_string(&mut buf, &self.dataset);
+        buf
+    }
+
+    /// set the sums of the squared activations of the columns of a weight over ncall calls.
+    pub fn insert(&mut self, name: &str, ncall: i32, values: Vec<f32>) {
+        self.entries
+            .insert(name.to_string(), ImatrixEntry { values, ncall });
+    }
+
+    /// the mean squared activation of each input column of the weight, None if the weight
+    /// is not covered.
+    pub fn get(&self, name: &str) -> Option<Vec<f32>> {
+        let entry = self.entries.get(name)?;
+        let ncall = entry.ncall.max(1) as f32;
+        Some(entry.values.iter().map(|v| v / ncall).collect())
+    }
+
+    /// the names of the covered weights, in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(|name| name.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self.offset.saturating_add(n);
+        if end > self.bytes.len() {
+            return Err((
+                ErrorKind::FormatError,
+                format!("imatrix: unexpected end of file at {}", self.offset),
+            )
+                .into());
+        }
+        let buf = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(buf)
+    }
+
+    fn read_i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    fn read_f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    fn read_len(&mut self) -> Result<usize> {
+        let n = self.read_i32()?;
+        if n < 0 {
+            return Err((
+                ErrorKind::FormatError,
+                format!("imatrix: negative length {} at {}", n, self.offset - 4),
+            )
+                .into());
+        }
+        Ok(n as usize)
+    }
+
+    fn read_string(&mut self) -> Result<String> {
+        let len = self.read_len()?;
+        Ok(String::from_utf8_lossy(self.read_bytes(len)?).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_imatrix() -> Result<()> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&1i32.to_le_bytes());
+        let name = "blk.0.ffn_down.weight";
+        bytes.extend_from_slice(&(name.len() as i32).to_le_bytes());
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.extend_from_slice(&2i32.to_le_bytes());
+        bytes.extend_from_slice(&3i32.to_le_bytes());
+        for v in [2.0f32, 4.0, 0.0] {
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+
+        let imatrix = ImportanceMatrix::from_bytes(&bytes)?;
+        assert_eq!(imatrix.len(), 1);
+        assert_eq!(imatrix.get(name), Some(vec![1.0, 2.0, 0.0]));
+        assert_eq!(imatrix.get("output.weight"), None);
+        assert_eq!(imatrix.dataset, "");
+
+        bytes.extend_from_slice(&10i32.to_le_bytes());
+        bytes.extend_from_slice(&4i32.to_le_bytes());
+        bytes.extend_from_slice(b"wiki");
+        let imatrix = ImportanceMatrix::from_bytes(&bytes)?;
+        assert_eq!(imatrix.last_call, 10);
+        assert_eq!(imatrix.dataset, "wiki");
+
+        assert_eq!(imatrix.to_bytes(), bytes);
+        assert_eq!(ImportanceMatrix::from_bytes(&imatrix.to_bytes())?, imatrix);
+
+        let err = ImportanceMatrix::from_bytes(&bytes[..20]).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::FormatError);
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+//! the lookup tables of the transcendental functions on all the 65536 f16 values, like
+//! ggml's. every table is built once per process on its first use and shared by all the
+//! devices, runners and threads, `init_tables()` builds them up front.
+
+use std::sync::OnceLock;
+
+use half::f16;
+
+const GELU_COEF_A: f32 = 0.044715;
+const SQRT_2_OVER_PI: f64 = 0.797_884_560_802_865_4;
+
+/// f(x) on every f16 x, indexed by the bits of x.
+pub struct F16Table {
+    values: Box<[f16]>,
+}
+
+impl F16Table {
+    fn new(f: impl Fn(f32) -> f32) -> Self {
+        let values = (0..=u16::MAX)
+            .map(|bits| f16::from_f32(f(f16::from_bits(bits).to_f32())))
+            .collect();
+        Self { values }
+    }
+
+    #[inline]
+    pub fn get(&self, x: f16) -> f16 {
+        // the table covers every u16
+        unsafe { *self.values.get_unchecked(x.to_bits() as usize) }
+    }
+
+    /// looks up x rounded to f16.
+    #[inline]
+    pub fn get_f32(&self, x: f32) -> f32 {
+        self.get(f16::from_f32(x)).to_f32()
+    }
+}
+
+pub fn exp_table() -> &'static F16Table {
+    static TABLE: OnceLock<F16Table> = OnceLock::new();
+    TABLE.get_or_init(|| F16Table::new(f32::exp))
+}
+
+/// the natural log, NaN on the negatives.
+pub fn log_table() -> &'static F16Table {
+    static TABLE: OnceLock<F16Table> = OnceLock::new();
+    TABLE.get_or_init(|| F16Table::new(f32::ln))
+}
+
+pub fn silu_table() -> &'static F16Table {
+    static TABLE: OnceLock<F16Table> = OnceLock::new();
+    TABLE.get_or_init(|| F16Table::new(|x| x / (1.0 + (-x).exp())))
+}
+
+pub fn gelu_table() -> &'static F16Table {
+    static TABLE: OnceLock<F16Table> = OnceLock::new();
+    TABLE.get_or_init(|| F16Table::new(gelu))
+}
+
+/// build all the tables, so the first forward pass does not pay for them. it's called on
+/// creating a device.
+pub fn init_tables() {
+    exp_table();
+    log_table();
+    silu_table();
+    gelu_table();
+}
+
+/// the tanh approximation of gelu.
+#[inline]
+pub fn gelu(x: f32) -> f32 {
+    0.5 * x * (1.0 + ((SQRT_2_OVER_PI as f32) * x * (1.0 + GELU_COEF_A * x * x)).tanh())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tables() {
+        for x in [-8.0f32, -1.5, -0.25, 0.0, 0.5, 3.0] {
+            let x16 = f16::from_f32(x);
+            let expected = [
+                (exp_table(), x.exp()),
+                (silu_table(), x / (1.0 + (-x).exp())),
+                (gelu_table(), gelu(x)),
+            ];
+            for (table, y) in expected {
+                assert_eq!(table.get(x16), f16::from_f32(y), "{}", x);
+            }
+        }
+        assert!((log_table().get_f32(2.0) - 2.0f32.ln()).abs() < 1e-3);
+        assert!(log_table().get_f32(-1.0).is_nan());
+
+        // every thread sees the same tables
+        let addr = exp_table() as *const F16Table as usize;
+        let other = std::thread::spawn(|| exp_table() as *const F16Table as usize)
+            .join()
+            .unwrap();
+        assert_eq!(addr, other);
+    }
+}
@@ -1,11 +1,34 @@
 mod arch;
 pub mod buf;
+#[cfg(feature = "std")]
+mod busy_poll;
+#[cfg(feature = "std")]
 mod cpu_device;
+#[cfg(feature = "std")]
 mod cpu_tensor;
+#[cfg(feature = "std")]
+pub mod imatrix;
+#[cfg(feature = "std")]
+pub mod lut;
+#[cfg(feature = "std")]
+pub mod parallel;
+#[cfg(feature = "std")]
 mod primitives;
+#[cfg(feature = "std")]
+pub mod quantize;
 
 pub use buf::CpuTensorBuf;
+#[cfg(feature = "std")]
+pub use cpu_device::AttentionAccumulation;
+#[cfg(feature = "std")]
 pub use cpu_device::CpuTensorDevice;
+#[cfg(feature = "std")]
 pub use cpu_device::CpuTensorDeviceOptions;
+#[cfg(feature = "std")]
 pub use cpu_device::CpuTensorDeviceRef;
+#[cfg(feature = "std")]
 pub use cpu_tensor::CpuTensor;
+#[cfg(feature = "std")]
+pub use imatrix::ImportanceMatrix;
+#[cfg(feature = "std")]
+pub use parallel::ThreadPools;
@@ -0,0 +1,190 @@
+//! the parallel loops of the cpu kernels, on the rayon pool. on wasi there are no threads in
+//! the sandboxes, the loops run one by one on the calling thread.
+
+#[cfg(not(target_os = "wasi"))]
+use std::collections::HashMap;
+#[cfg(not(target_os = "wasi"))]
+use std::sync::Arc;
+
+#[cfg(not(target_os = "wasi"))]
+use rayon::prelude::*;
+
+#[cfg(not(target_os = "wasi"))]
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::tensor::TaskTag;
+
+/// the rayon pools the parallel ops of a device run on by their task tag, the tags without a
+/// pool run on the global pool. a decode pool on its own cores keeps the tokens of a chat
+/// coming while a bulk prefill or an embedding job saturates the other pool in the same
+/// process. the pools can be shared by the devices on several threads.
+#[derive(Debug, Clone, Default)]
+pub struct ThreadPools {
+    #[cfg(not(target_os = "wasi"))]
+    pools: HashMap<TaskTag, Arc<rayon::ThreadPool>>,
+}
+
+impl ThreadPools {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// run the ops of the tag on a new pool of n_threads threads.
+    #[cfg(not(target_os = "wasi"))]
+    pub fn with_threads(self, tag: TaskTag, n_threads: usize) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(n_threads)
+            .thread_name(move |i| format!("crabml-{:?}-{}", tag, i).to_lowercase())
+            .build()
+            .map_err(|err| Error {
+                kind: ErrorKind::Unexpected,
+                message: format!("failed to build the {:?} thread pool", tag),
+                cause: Some(Box::new(err)),
+            })?;
+        Ok(self.with_pool(tag, Arc::new(pool)))
+    }
+
+    /// there are no threads on wasi.
+    #[cfg(target_os = "wasi")]
+    pub fn with_threads(self, _tag: TaskTag, _n_threads: usize) -> Result<Self> {
+        Err((
+            ErrorKind::NotImplemented,
+            "there are no thread pools on wasi",
+        )
+            .into())
+    }
+
+    /// run the ops of the tag on the pool, like one shared with the other devices.
+    #[cfg(not(target_os = "wasi"))]
+    pub fn with_pool(mut self, tag: TaskTag, pool: Arc<rayon::ThreadPool>) -> Self {
+        self.pools.insert(tag, pool);
+        self
+    }
+
+    /// the threads of the pool the ops of the tag run on.
+    pub fn n_threads(&self, tag: TaskTag) -> usize {
+        #[cfg(not(target_os = "wasi"))]
+        return match self.pools.get(&tag) {
+            Some(pool) => pool.current_num_threads(),
+            None => rayon::current_num_threads(),
+        };
+        #[cfg(target_os = "wasi")]
+        {
+            let _ = tag;
+            1
+        }
+    }
+
+    /// run op on the pool of the tag, the parallel loops inside it use the pool.
+    pub fn install<R: Send>(&self, tag: TaskTag, op: impl FnOnce() -> R + Send) -> R {
+        #[cfg(not(target_os = "wasi"))]
+        if let Some(pool) = self.pools.get(&tag) {
+            return pool.install(op);
+        }
+        #[cfg(target_os = "wasi")]
+        let _ = tag;
+        op()
+    }
+}
+
+/// calls f on every item with its index.
+pub fn for_each_mut<T: Send>(items: &mut [T], f: impl Fn(usize, &mut T) + Send + Sync) {
+    #[cfg(not(target_os = "wasi"))]
+    items
+        .par_iter_mut()
+        .enumerate()
+        .for_each(|(i, item)| f(i, item));
+    #[cfg(target_os = "wasi")]
+    items
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, item)| f(i, item));
+}
+
+/// like `for_each_mut()`, with a scratch state made by init and reused across the items of
+/// a worker.
+pub fn for_each_mut_init<T: Send, S>(
+    items: &mut [T],
+    init: impl Fn() -> S + Send + Sync,
+    f: impl Fn(&mut S, usize, &mut T) + Send + Sync,
+) {
+    #[cfg(not(target_os = "wasi"))]
+    items
+        .par_iter_mut()
+        .enumerate()
+        .for_each_init(init, |state, (i, item)| f(state, i, item));
+    #[cfg(target_os = "wasi")]
+    {
+        let mut state = init();
+        items
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, item)| f(&mut state, i, item));
+    }
+}
+
+/// calls f on every chunk of chunk_size items with its index, the remainder is skipped.
+pub fn for_each_chunk_mut<T: Send>(
+    items: &mut [T],
+    chunk_size: usize,
+    f: impl Fn(usize, &mut [T]) + Send + Sync,
+) {
+    #[cfg(not(target_os = "wasi"))]
+    items
+        .par_chunks_exact_mut(chunk_size)
+        .enumerate()
+        .for_each(|(i, chunk)| f(i, chunk));
+    #[cfg(target_os = "wasi")]
+    items
+        .chunks_exact_mut(chunk_size)
+        .enumerate()
+        .for_each(|(i, chunk)| f(i, chunk));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parallel_loops() {
+        let mut items = vec![0usize; 10];
+        for_each_mut(&mut items, |i, item| *item = i * 2);
+        assert_eq!(items, (0..10).map(|i| i * 2).collect::<Vec<_>>());
+
+        for_each_mut_init(
+            &mut items,
+            || vec![1usize],
+            |state, i, item| *item += state[0] + i,
+        );
+        assert_eq!(items, (0..10).map(|i| i * 3 + 1).collect::<Vec<_>>());
+
+        for_each_chunk_mut(&mut items, 3, |i, chunk| chunk.fill(i));
+        assert_eq!(items, vec![0, 0, 0, 1, 1, 1, 2, 2, 2, 28]);
+    }
+
+    #[test]
+    fn test_thread_pools() -> Result<()> {
+        let pools = ThreadPools::new().with_threads(TaskTag::Background, 2)?;
+        assert_eq!(pools.n_threads(TaskTag::Background), 2);
+        assert_eq!(
+            pools.n_threads(TaskTag::Decode),
+            rayon::current_num_threads()
+        );
+
+        // the loops inside run on the threads of the pool
+        let names = pools.install(TaskTag::Background, || {
+            let mut names = vec![String::new(); 64];
+            for_each_mut(&mut names, |_, name| {
+                *name = std::thread::current().name().unwrap_or("").to_string();
+            });
+            names
+        });
+        assert!(names.iter().all(|n| n.starts_with("crabml-background-")));
+        let name = pools.install(TaskTag::Decode, || {
+            std::thread::current().name().map(|n| n.to_string())
+        });
+        assert!(!name.unwrap_or_default().starts_with("crabml-"));
+        Ok(())
+    }
+}
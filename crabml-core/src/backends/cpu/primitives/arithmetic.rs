@@ -1,5 +1,8 @@
+use half::f16;
+
 use crate::backends::cpu::buf::CpuTensorBuf;
 use crate::error::Result;
+use crate::gguf::GGMLType;
 use crate::tensor::TensorStrider;
 
 pub fn add_inplace<'a>(
@@ -13,6 +16,10 @@ pub fn add_inplace<'a>(
     assert!(strider1.is_contiguous());
     assert!(strider2.is_contiguous());
 
+    if buf1.dtype() == GGMLType::F16 {
+        return binary_inplace_f16(buf1, buf2, |ia, ib| ia + ib);
+    }
+
     if buf2.len() == 1 {
         let ib = buf2.iter_f32().next().unwrap();
         buf1.iter_f32_mut().for_each(|ia| {
@@ -22,7 +29,7 @@ pub fn add_inplace<'a>(
     }
 
     let buf1 = buf1.as_f32_mut();
-    let buf2 = buf2.as_f32_ref();
+    let buf2 = buf2.to_f32_cow();
     buf1.chunks_exact_mut(4)
         .zip(buf2.chunks_exact(4).cycle())
         .for_each(|(ia, ib)| {
@@ -55,6 +62,10 @@ pub fn mul_inplace<'a>(
     assert!(strider1.is_contiguous());
     assert!(strider2.is_contiguous());
 
+    if buf1.dtype() == GGMLType::F16 {
+        return binary_inplace_f16(buf1, buf2, |ia, ib| ia * ib);
+    }
+
     if buf2.len() == 1 {
         let ib = buf2.iter_f32().next().unwrap();
         buf1.iter_f32_mut().for_each(|ia| {
@@ -64,7 +75,7 @@ pub fn mul_inplace<'a>(
     }
 
     let buf1 = buf1.as_f32_mut();
-    let buf2 = buf2.as_f32_ref();
+    let buf2 = buf2.to_f32_cow();
     buf1.chunks_exact_mut(4)
         .zip(buf2.chunks_exact(4).cycle())
         .for_each(|(ia, ib)| {
@@ -102,6 +113,13 @@ where
     assert!(strider1.is_contiguous());
     assert!(strider2.is_contiguous());
 
+    if buf1.dtype() == GGMLType::F16 {
+        return binary_inplace_f16(buf1, buf2, |mut ia, ib| {
+            f(&mut ia, ib);
+            ia
+        });
+    }
+
     if buf2.len() == 1 {
         let ib = buf2.iter_f32().next().unwrap();
         buf1.iter_f32_mut().for_each(|ia| {
@@ -112,10 +130,28 @@ where
 
     // it seems that using cycle is slower
     buf1.iter_f32_mut()
-        .zip(buf2.as_f32_ref().iter().cycle())
+        .zip(buf2.to_f32_cow().iter().cycle())
         .for_each(|(ia, ib)| {
             f(ia, *ib);
         });
 
     Ok(())
 }
+
+// the f16 lhs is computed in f32 and rounded back, the rhs is broadcasted like the f32 ops,
+// it's in f32 on the norm weights and the biases, or in f16 on the other activations.
+fn binary_inplace_f16<F>(buf1: &mut CpuTensorBuf, buf2: &CpuTensorBuf, f: F) -> Result<()>
+where F: Fn(f32, f32) -> f32 {
+    let buf1 = buf1.as_f16_mut();
+    match buf2 {
+        CpuTensorBuf::F16(buf2) => buf1
+            .iter_mut()
+            .zip(buf2.iter().cycle())
+            .for_each(|(ia, ib)| *ia = f16::from_f32(f(ia.to_f32(), ib.to_f32()))),
+        buf2 => buf1
+            .iter_mut()
+            .zip(buf2.as_f32_ref().iter().cycle())
+            .for_each(|(ia, ib)| *ia = f16::from_f32(f(ia.to_f32(), *ib))),
+    }
+    Ok(())
+}
@@ -1,46 +1,187 @@
+use std::borrow::Cow;
+
 use half::f16;
 
 use crate::backends::cpu::buf::buf_f16::quantize_f32_f16;
+use crate::backends::cpu::buf::buf_f16::vec_convert_f16_f32;
 use crate::backends::cpu::buf::buf_f16::vec_dot_f16_f16;
 use crate::backends::cpu::buf::buf_f16::vec_fma_f16_f16;
+use crate::backends::cpu::buf::buf_q8_0::BlockQ8_0;
 use crate::backends::cpu::buf::CpuTensorBuf;
+use crate::backends::cpu::buf::QuantBufQ8_0;
+use crate::backends::cpu::AttentionAccumulation;
 use crate::backends::cpu::CpuTensorDeviceRef;
+use crate::error::Result;
 use crate::gguf::GGMLType;
+use crate::tensor::MatmulEpilogue;
 use crate::tensor::TensorStrider;
 
 /// A (b, m, n) @ B (b, k, n) -> C (b, m, n)
 ///
 /// A is expected to be contiguous, B is allowed to be strided, but B should
 /// be contiguous on the K dimension or N dimension.
+///
+/// with acc, the result is added into C instead of overwriting it. A and C can be in f32
+/// or f16, the f16 C is summed up in f32 and rounded once. every dot product goes through
+/// the epilogue before it's added or written into C.
+///
+/// the attention accumulation of the device saturates the results at ±f16::MAX where they
+/// are summed up or written in f16, the f32 sums and outputs are left as they are. on
+/// rescale, it sums up the f16 kv cache with the scale on A and in f32, see
+/// `AttentionAccumulation` for why.
+#[allow(clippy::too_many_arguments)]
 pub fn batch_matmul<'a>(
-    _device: &CpuTensorDeviceRef<'a>,
+    device: &CpuTensorDeviceRef<'a>,
     bufa: &CpuTensorBuf<'a>,
     bufb: &CpuTensorBuf<'a>,
     bufc: &mut CpuTensorBuf<'a>,
     strider1: &TensorStrider,
     strider2: &TensorStrider,
-) {
+    acc: bool,
+    epilogue: MatmulEpilogue,
+) -> Result<()> {
     assert!(strider1.dims() == 3);
     assert!(strider2.dims() == 3);
     assert!(strider1.is_contiguous());
     assert!(strider2.strides()[1] == 1 || strider2.strides()[2] == 1);
     assert!(bufa.dtype() == GGMLType::F32 || bufa.dtype() == GGMLType::F16);
-    assert!(bufb.dtype() == GGMLType::F32 || bufb.dtype() == GGMLType::F16);
+    assert!(
+        bufb.dtype() == GGMLType::F32
+            || bufb.dtype() == GGMLType::F16
+            || bufb.dtype() == GGMLType::Q8_0
+    );
+
+    let accumulation = device.attention_accumulation();
+    let saturate = accumulation != AttentionAccumulation::Plain;
+
+    if let CpuTensorBuf::F16(_) = bufc {
+        let mut bufc_f32 = CpuTensorBuf::from(match acc {
+            true => bufc.iter_f32().collect::<Vec<_>>(),
+            false => vec![0.0; bufc.len()],
+        });
+        batch_matmul(
+            device,
+            bufa,
+            bufb,
+            &mut bufc_f32,
+            strider1,
+            strider2,
+            acc,
+            epilogue,
+        )?;
+        // the sums past f16::MAX would be rounded into inf on the write
+        if saturate {
+            let max = f16::MAX.to_f32();
+            bufc_f32
+                .as_f32_mut()
+                .iter_mut()
+                .for_each(|c| *c = c.clamp(-max, max));
+        }
+        vec_convert_f16_f32(bufc.as_f16_mut(), bufc_f32.as_f32_ref());
+        return Ok(());
+    }
+
+    // the naive loop sums up in order, the f16 and q8_0 kv caches are dequantized for it
+    if device.is_deterministic() && bufb.dtype() != GGMLType::F32 {
+        let mut scratch = device.strict_buf.borrow_mut();
+        dequantize_strided(bufb, strider2, &mut scratch);
+        batch_matmul_naive_f32(
+            &bufa.to_f32_cow(),
+            &scratch,
+            bufc.as_f32_mut(),
+            strider1,
+            strider2,
+            acc,
+            epilogue,
+        );
+        return Ok(());
+    }
 
     match bufb {
         CpuTensorBuf::F32(bufb) => batch_matmul_naive_f32(
-            bufa.as_f32_ref(),
+            &bufa.to_f32_cow(),
             bufb,
             bufc.as_f32_mut(),
             strider1,
             strider2,
+            acc,
+            epilogue,
         ),
         CpuTensorBuf::F16(bufb) => {
-            let bufa = quantize_f32_f16(bufa.as_f32_ref());
-            batch_matmul_simd_f16(&bufa, bufb, bufc.as_f32_mut(), strider1, strider2)
+            let rescale = accumulation == AttentionAccumulation::Rescale;
+            let (bufa, epilogue) = match bufa {
+                // the scale goes on a copy of A, so the f16 partial sums are scaled down
+                // already, it's the same on the soft cap which comes after the scale
+                bufa if rescale && epilogue.scale != 1.0 => {
+                    let scaled = bufa
+                        .iter_f32()
+                        .map(|a| a * epilogue.scale)
+                        .collect::<Vec<_>>();
+                    let epilogue = MatmulEpilogue {
+                        scale: 1.0,
+                        ..epilogue
+                    };
+                    (quantize_f32_f16(&scaled), epilogue)
+                }
+                CpuTensorBuf::F16(bufa) => (Cow::Borrowed(&bufa[..]), epilogue),
+                bufa => (quantize_f32_f16(bufa.as_f32_ref()), epilogue),
+            };
+            batch_matmul_simd_f16(
+                &bufa,
+                bufb,
+                bufc.as_f32_mut(),
+                strider1,
+                strider2,
+                acc,
+                epilogue,
+                saturate,
+                rescale,
+            )
         }
+        CpuTensorBuf::Q8_0(bufb) => batch_matmul_q8_0(
+            &bufa.to_f32_cow(),
+            bufb,
+            bufc.as_f32_mut(),
+            strider1,
+            strider2,
+            acc,
+            epilogue,
+        ),
         _ => unreachable!(),
     }
+    Ok(())
+}
+
+// one past the last element reached from the start of the shape
+fn strided_extent(shape: &[usize], strides: &[usize]) -> usize {
+    if shape.contains(&0) {
+        return 0;
+    }
+    shape
+        .iter()
+        .zip(strides)
+        .map(|(dim, stride)| (dim - 1) * stride)
+        .sum::<usize>()
+        + 1
+}
+
+// dequantize the elements of buf the strider reaches into the scratch at the same offsets.
+// the kv cache of a head is allocated for the whole seq_len, only the positions up to the
+// live length of each head are dequantized, the rest of the scratch is left as it is
+fn dequantize_strided(buf: &CpuTensorBuf, strider: &TensorStrider, scratch: &mut Vec<f32>) {
+    let (shape, strides) = (strider.shape(), strider.strides());
+    scratch.resize(strided_extent(shape, strides), 0.0);
+    let inner = strided_extent(&shape[1..], &strides[1..]);
+    let block_size = buf.dtype().block_size();
+    let mut done = 0;
+    for bi in 0..shape[0] {
+        let end = bi * strides[0] + inner;
+        let start = (bi * strides[0]).max(done) / block_size * block_size;
+        if start < end {
+            buf.dequantize_row(start, &mut scratch[start..end]);
+        }
+        done = done.max(end);
+    }
 }
 
 // TODO: use vec_dot and vec_fma to optimize this function
@@ -50,6 +191,8 @@ fn batch_matmul_naive_f32(
     bufc: &mut [f32], // b x m x n
     stride1: &TensorStrider,
     stride2: &TensorStrider,
+    acc: bool,
+    epilogue: MatmulEpilogue,
 ) {
     let (a_batch, b_batch) = (stride1.shape()[0], stride2.shape()[0]);
     assert!(a_batch >= b_batch);
@@ -57,26 +200,48 @@ fn batch_matmul_naive_f32(
     for bi in 0..a_batch {
         for mi in 0..m {
             for ni in 0..n {
+                let mut dot = 0.0;
                 for ki in 0..k {
-                    bufc[bi * (m * n) + mi * n + ni] += bufa[bi * stride1.strides()[0]
+                    dot += bufa[bi * stride1.strides()[0]
                         + mi * stride1.strides()[1]
                         + ki * stride1.strides()[2]]
                         * bufb[(bi % b_batch) * stride2.strides()[0]
                             + ki * stride2.strides()[1]
                             + ni * stride2.strides()[2]];
                 }
+                let c = &mut bufc[bi * (m * n) + mi * n + ni];
+                *c = if acc {
+                    *c + epilogue.apply(dot)
+                } else {
+                    epilogue.apply(dot)
+                };
             }
         }
     }
 }
 
+/// with sum_f32, the fma over the rows of B which are contiguous on the N dimension sums
+/// up in f32 instead of f16, it's the attn @ v over the value cache, which sums up the
+/// small probabilities over all the positions.
+///
+/// with saturate, the sums kept in f16 are clamped at ±f16::MAX: the dots of vec_dot_f16_f16
+/// on aarch64 and the fma in f16. the sums in f32 are not clamped.
+#[allow(clippy::too_many_arguments)]
 fn batch_matmul_simd_f16(
     bufa: &[f16],     // b x m x k
     bufb: &[f16],     // b x k x n
     bufc: &mut [f32], // b x m x n
     stride1: &TensorStrider,
     stride2: &TensorStrider,
+    acc: bool,
+    epilogue: MatmulEpilogue,
+    saturate: bool,
+    sum_f32: bool,
 ) {
+    let f16_epilogue = match saturate {
+        true => epilogue.with_clamp(f16::MAX.to_f32()),
+        false => epilogue,
+    };
     let (a_batch, b_batch) = (stride1.shape()[0], stride2.shape()[0]);
     assert!(a_batch >= b_batch);
     let (m, k, n) = (stride1.shape()[1], stride1.shape()[2], stride2.shape()[2]);
@@ -91,13 +256,44 @@ fn batch_matmul_simd_f16(
     // if matrix B is contiguous on the k dimension, then we use vec_dot_f16_f16
     // if matrix B is contiguous on the n dimension, then we use vec_fma_f16_f16
     if stride_bk == 1 {
+        let epilogue = match cfg!(target_arch = "aarch64") {
+            true => f16_epilogue,
+            false => epilogue,
+        };
         bufc.iter_mut().enumerate().for_each(|(i, bufcp)| {
             let ni = i % n;
             let mi = (i - ni) / n % m;
             let bi = (i - ni - mi * n) / (m * n);
             let offset_a = bi * (m * k) + mi * k;
             let offset_b = (bi % b_batch) * stride_bb + ni * stride_bn;
-            *bufcp = vec_dot_f16_f16(bufa, offset_a, &bufb[offset_b..offset_b + k], 0, k);
+            let dot = vec_dot_f16_f16(bufa, offset_a, &bufb[offset_b..offset_b + k], 0, k);
+            let dot = epilogue.apply(dot);
+            *bufcp = if acc { *bufcp + dot } else { dot };
+        });
+    } else if stride_bn == 1 && sum_f32 {
+        // the rows of B are widened once and shared by the rows of A like the q8_0 cache
+        let mut tmpc = vec![0.0f32; a_batch * m * n];
+        let mut row = vec![0.0f32; n];
+        for bi in 0..a_batch {
+            for ki in 0..k {
+                let offset_b = (bi % b_batch) * stride_bb + ki * stride_bk;
+                row.iter_mut()
+                    .zip(bufb[offset_b..offset_b + n].iter())
+                    .for_each(|(r, b)| *r = b.to_f32());
+                for mi in 0..m {
+                    let a = bufa[bi * (m * k) + mi * k + ki].to_f32();
+                    let offset_c = bi * (m * n) + mi * n;
+                    tmpc[offset_c..offset_c + n]
+                        .iter_mut()
+                        .zip(row.iter())
+                        .for_each(|(c, b)| *c += a * b);
+                }
+            }
+        }
+
+        bufc.iter_mut().zip(tmpc.iter()).for_each(|(c, tmp)| {
+            let dot = epilogue.apply(*tmp);
+            *c = if acc { *c + dot } else { dot };
         });
     } else if stride_bn == 1 {
         let mut tmpc = vec![f16::ZERO; a_batch * m * n]; // TODO: avoid allocation
@@ -119,8 +315,93 @@ fn batch_matmul_simd_f16(
         }
 
         bufc.iter_mut().zip(tmpc.iter()).for_each(|(c, tmp)| {
-            *c = tmp.to_f32();
+            let dot = f16_epilogue.apply(tmp.to_f32());
+            *c = if acc { *c + dot } else { dot };
+        });
+    } else {
+        unreachable!()
+    }
+}
+
+/// B is the quantized kv cache here. on the key cache which is contiguous on the K
+/// dimension, A is quantized into q8_0 to vec_dot with the rows of B directly; on the
+/// value cache which is contiguous on the N dimension, the rows of B are dequantized
+/// one by one on the fly, the cache is never dequantized as a whole.
+fn batch_matmul_q8_0(
+    bufa: &[f32],        // b x m x k
+    bufb: &QuantBufQ8_0, // b x k x n
+    bufc: &mut [f32],    // b x m x n
+    stride1: &TensorStrider,
+    stride2: &TensorStrider,
+    acc: bool,
+    epilogue: MatmulEpilogue,
+) {
+    let (a_batch, b_batch) = (stride1.shape()[0], stride2.shape()[0]);
+    assert!(a_batch >= b_batch);
+    let (m, k, n) = (stride1.shape()[1], stride1.shape()[2], stride2.shape()[2]);
+    let (stride_bb, stride_bk, stride_bn) = (
+        stride2.strides()[0],
+        stride2.strides()[1],
+        stride2.strides()[2],
+    );
+
+    if stride_bk == 1 {
+        assert!(k % BlockQ8_0::BLOCK_ELEMS == 0);
+        let bufa = QuantBufQ8_0::quantize(bufa);
+        bufc.iter_mut().enumerate().for_each(|(i, bufcp)| {
+            let ni = i % n;
+            let mi = (i - ni) / n % m;
+            let bi = (i - ni - mi * n) / (m * n);
+            let offset_a = bi * (m * k) + mi * k;
+            let offset_b = (bi % b_batch) * stride_bb + ni * stride_bn;
+            let dot = epilogue.apply(bufb.vec_dot(offset_b, &bufa, offset_a, k));
+            *bufcp = if acc { *bufcp + dot } else { dot };
         });
+    } else if stride_bn == 1 {
+        assert!(n % BlockQ8_0::BLOCK_ELEMS == 0);
+        // the dot products are summed up over k, they go through the epilogue at the end
+        let mut dots = match epilogue.is_identity() {
+            true => None,
+            false => Some(vec![0.0; bufc.len()]),
+        };
+        let out = match dots.as_mut() {
+            Some(dots) => &mut dots[..],
+            None => {
+                if !acc {
+                    bufc.fill(0.0);
+                }
+                &mut bufc[..]
+            }
+        };
+        let mut row = vec![0.0; n];
+        for bi in 0..a_batch {
+            for ki in 0..k {
+                let offset_b = (bi % b_batch) * stride_bb + ki * stride_bk;
+                let blocks = &bufb.blocks
+                    [offset_b / BlockQ8_0::BLOCK_ELEMS..(offset_b + n) / BlockQ8_0::BLOCK_ELEMS];
+                for (blk, row_chunk) in blocks.iter().zip(row.chunks_mut(BlockQ8_0::BLOCK_ELEMS)) {
+                    blk.dequantize(row_chunk);
+                }
+
+                for mi in 0..m {
+                    let a = bufa[bi * (m * k) + mi * k + ki];
+                    let offset_c = bi * (m * n) + mi * n;
+                    out[offset_c..offset_c + n]
+                        .iter_mut()
+                        .zip(row.iter())
+                        .for_each(|(c, b)| *c += a * b);
+                }
+            }
+        }
+        if let Some(dots) = dots {
+            bufc.iter_mut().zip(dots).for_each(|(c, dot)| {
+                *c = if acc {
+                    *c + epilogue.apply(dot)
+                } else {
+                    epilogue.apply(dot)
+                };
+            });
+        }
     } else {
         unreachable!()
     }
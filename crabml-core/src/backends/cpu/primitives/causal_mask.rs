@@ -0,0 +1,191 @@
+use half::f16;
+
+use crate::backends::cpu::buf::CpuTensorBuf;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::gguf::GGMLType;
+use crate::tensor::TensorStrider;
+
+/// mask the attention scores of the future positions with -inf before softmax.
+///
+/// the attention scores are in the shape of (n_heads, n_batch, seq), the n_batch queries
+/// are the last n_batch positions in seq, so the query at row i can only attend to the
+/// positions <= seq - n_batch + i. with a sliding window of w, it can't attend to the
+/// positions <= seq - n_batch + i - w either.
+pub fn causal_mask_inplace(
+    buf: &mut CpuTensorBuf<'_>,
+    strider: &TensorStrider,
+    sliding_window: Option<usize>,
+) -> Result<()> {
+    assert!(strider.dims() == 3);
+    assert!(strider.is_contiguous());
+    assert!(buf.dtype() == GGMLType::F32 || buf.dtype() == GGMLType::F16);
+
+    let (n_heads, n_batch, seq) = (strider.shape()[0], strider.shape()[1], strider.shape()[2]);
+    if n_batch > seq {
+        return Err((
+            ErrorKind::TensorError,
+            format!(
+                "causal_mask: n_batch {} is larger than the seq len {}",
+                n_batch, seq
+            ),
+        )
+            .into());
+    }
+
+    match buf.dtype() {
+        GGMLType::F16 => mask_buf(
+            buf.as_f16_mut(),
+            (n_heads, n_batch, seq),
+            sliding_window,
+            f16::NEG_INFINITY,
+        ),
+        _ => mask_buf(
+            buf.as_f32_mut(),
+            (n_heads, n_batch, seq),
+            sliding_window,
+            f32::NEG_INFINITY,
+        ),
+    }
+    Ok(())
+}
+
+fn mask_buf<T: Copy>(
+    buf: &mut [T],
+    (n_heads, n_batch, seq): (usize, usize, usize),
+    sliding_window: Option<usize>,
+    neg_inf: T,
+) {
+    let n_past = seq - n_batch;
+    for hi in 0..n_heads {
+        for bi in 0..n_batch {
+            let offset = hi * n_batch * seq + bi * seq;
+            let qpos = n_past + bi;
+            buf[offset + qpos + 1..offset + seq].fill(neg_inf);
+            if let Some(window) = sliding_window {
+                let start = (qpos + 1).saturating_sub(window);
+                buf[offset..offset + start].fill(neg_inf);
+            }
+        }
+    }
+}
+
+/// mask the attention scores by a block mask of (n_batch, n_block) over the last n_block
+/// positions in seq, the query at row i can't attend to the position seq - n_block + j if
+/// mask[i * n_block + j] is false. the positions before the block are left as is. it's
+/// applied after the causal mask, like to let the tokens of a tree only attend to their
+/// ancestors in the batch.
+pub fn block_mask_inplace(
+    buf: &mut CpuTensorBuf<'_>,
+    strider: &TensorStrider,
+    mask: &[bool],
+) -> Result<()> {
+    assert!(strider.dims() == 3);
+    assert!(strider.is_contiguous());
+    assert!(buf.dtype() == GGMLType::F32 || buf.dtype() == GGMLType::F16);
+
+    let (n_heads, n_batch, seq) = (strider.shape()[0], strider.shape()[1], strider.shape()[2]);
+    let n_block = mask.len() / n_batch.max(1);
+    if n_block * n_batch != mask.len() || n_block > seq {
+        return Err((
+            ErrorKind::TensorError,
+            format!(
+                "block_mask: the mask of {} is not in (n_batch {}, n_block <= seq {})",
+                mask.len(),
+                n_batch,
+                seq
+            ),
+        )
+            .into());
+    }
+
+    match buf.dtype() {
+        GGMLType::F16 => block_mask_buf(
+            buf.as_f16_mut(),
+            (n_heads, n_batch, seq),
+            mask,
+            f16::NEG_INFINITY,
+        ),
+        _ => block_mask_buf(
+            buf.as_f32_mut(),
+            (n_heads, n_batch, seq),
+            mask,
+            f32::NEG_INFINITY,
+        ),
+    }
+    Ok(())
+}
+
+fn block_mask_buf<T: Copy>(
+    buf: &mut [T],
+    (n_heads, n_batch, seq): (usize, usize, usize),
+    mask: &[bool],
+    neg_inf: T,
+) {
+    let n_block = mask.len() / n_batch;
+    let n_past = seq - n_block;
+    for hi in 0..n_heads {
+        for bi in 0..n_batch {
+            let offset = hi * n_batch * seq + bi * seq + n_past;
+            let row = &mask[bi * n_block..(bi + 1) * n_block];
+            for (score, keep) in buf[offset..offset + n_block].iter_mut().zip(row) {
+                if !keep {
+                    *score = neg_inf;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_causal_mask() -> Result<()> {
+        // 1 head, 2 queries at position 1 and 2, 3 positions in total
+        let mut buf = CpuTensorBuf::from(vec![1.0; 6]);
+        let strider = TensorStrider::new(vec![1, 2, 3]);
+        causal_mask_inplace(&mut buf, &strider, None)?;
+
+        let inf = f32::NEG_INFINITY;
+        assert_eq!(buf.as_f32_ref(), &[1.0, 1.0, inf, 1.0, 1.0, 1.0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_causal_mask_sliding_window() -> Result<()> {
+        // 1 head, 2 queries at position 2 and 3, each attends to the last 2 positions
+        let mut buf = CpuTensorBuf::from(vec![1.0; 8]);
+        let strider = TensorStrider::new(vec![1, 2, 4]);
+        causal_mask_inplace(&mut buf, &strider, Some(2))?;
+
+        let inf = f32::NEG_INFINITY;
+        assert_eq!(buf.as_f32_ref(), &[inf, 1.0, 1.0, inf, inf, inf, 1.0, 1.0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_mask() -> Result<()> {
+        // 1 head, 3 queries of a tree after 1 cached position: 0 is the root, 1 and 2 are
+        // its children, 2 can't see 1
+        let mut buf = CpuTensorBuf::from(vec![1.0; 12]);
+        let strider = TensorStrider::new(vec![1, 3, 4]);
+        causal_mask_inplace(&mut buf, &strider, None)?;
+        let mask = [
+            true, false, false, //
+            true, true, false, //
+            true, false, true,
+        ];
+        block_mask_inplace(&mut buf, &strider, &mask)?;
+
+        let inf = f32::NEG_INFINITY;
+        assert_eq!(buf.as_f32_ref(), &[
+            1.0, 1.0, inf, inf, //
+            1.0, 1.0, 1.0, inf, //
+            1.0, 1.0, inf, 1.0
+        ]);
+        assert!(block_mask_inplace(&mut buf, &strider, &[true; 4]).is_err());
+        Ok(())
+    }
+}
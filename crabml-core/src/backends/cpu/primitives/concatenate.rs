@@ -3,6 +3,8 @@ use std::borrow::Cow;
 use half::f16;
 
 use crate::backends::cpu::buf::buf_f16::vec_convert_f16_f32;
+use crate::backends::cpu::buf::buf_q8_0::BlockQ8_0;
+use crate::backends::cpu::buf::QuantBufQ8_0;
 use crate::backends::cpu::CpuTensorBuf;
 use crate::error::ErrorKind;
 use crate::error::Result;
@@ -63,6 +65,31 @@ pub fn concatenate_inplace<'a>(
                 )?
             }
         }
+        // the f16 activations into the f32 cache
+        (CpuTensorBuf::F32(Cow::Owned(buf1)), CpuTensorBuf::F16(buf2)) => concatenate_inner(
+            buf1,
+            buf2,
+            strider1.shape(),
+            strider2.shape(),
+            strider1.strides(),
+            strider2.strides(),
+            axis,
+            f16::to_f32,
+        )?,
+        (
+            CpuTensorBuf::Q8_0(QuantBufQ8_0 {
+                blocks: Cow::Owned(blocks1),
+            }),
+            buf2 @ (CpuTensorBuf::F32(_) | CpuTensorBuf::F16(_)),
+        ) => concatenate_3d_q8_0_f32(
+            blocks1,
+            &buf2.to_f32_cow(),
+            strider1.shape(),
+            strider2.shape(),
+            strider1.strides(),
+            strider2.strides(),
+            axis,
+        )?,
         (buf1, buf2) => {
             return Err((
                 ErrorKind::TensorError,
@@ -201,6 +228,55 @@ pub fn concatenate_3d_dense_f16_f32(
     Ok(new_shape)
 }
 
+/// quantize the rows of buf2 into the q8_0 blocks of buf1 on appending, this is
+/// used on the quantized kv cache. each row on the last dimension should be dense and
+/// aligned to the q8_0 block size.
+#[allow(clippy::too_many_arguments)]
+pub fn concatenate_3d_q8_0_f32(
+    blocks1: &mut [BlockQ8_0],
+    buf2: &[f32],
+    shape1: &[usize],
+    shape2: &[usize],
+    strides1: &[usize],
+    strides2: &[usize],
+    axis: usize,
+) -> Result<Vec<usize>> {
+    if shape1.len() != 3 || strides1[2] != 1 || strides2[2] != 1 {
+        return Err((
+            ErrorKind::TensorError,
+            "only dense 3d tensor can be concatenated into q8_0",
+        )
+            .into());
+    }
+    if shape2[2] % BlockQ8_0::BLOCK_ELEMS != 0 || axis == 2 {
+        return Err((
+            ErrorKind::TensorError,
+            format!(
+                "the rows concatenated into q8_0 must be aligned to 32, got {:?} at axis {}",
+                shape2, axis
+            ),
+        )
+            .into());
+    }
+
+    let buf1_offset = shape1[axis] * strides1[axis];
+    let row_blocks = shape2[2] / BlockQ8_0::BLOCK_ELEMS;
+
+    for x in 0..shape2[0] {
+        for y in 0..shape2[1] {
+            let buf1_base =
+                (buf1_offset + x * strides1[0] + y * strides1[1]) / BlockQ8_0::BLOCK_ELEMS;
+            let buf2_base = x * strides2[0] + y * strides2[1];
+            let row = QuantBufQ8_0::quantize(&buf2[buf2_base..buf2_base + shape2[2]]);
+            blocks1[buf1_base..buf1_base + row_blocks].clone_from_slice(&row.blocks);
+        }
+    }
+
+    let mut new_shape = shape1.to_vec();
+    new_shape[axis] += shape2[axis];
+    Ok(new_shape)
+}
+
 #[cfg(test)]
 mod test {
     use super::concatenate_2d;
@@ -1,18 +1,19 @@
 use crate::backends::cpu::buf::CpuTensorBuf;
+use crate::backends::cpu::lut::gelu;
+use crate::backends::cpu::lut::gelu_table;
 use crate::backends::cpu::CpuTensorDeviceRef;
 use crate::error::Result;
 
-const COEF_A: f32 = 0.044715;
-const SQRT_2_OVER_PI: f64 = 0.797_884_560_802_865_4;
-
 pub fn gelu_inplace<'a>(_device: CpuTensorDeviceRef<'a>, buf: &mut CpuTensorBuf<'a>) -> Result<()> {
+    if let CpuTensorBuf::F16(_) = buf {
+        let gelu_table = gelu_table();
+        buf.as_f16_mut()
+            .iter_mut()
+            .for_each(|x| *x = gelu_table.get(*x));
+        return Ok(());
+    }
     buf.iter_f32_mut().for_each(|x| {
-        *x = gelu_single(*x);
+        *x = gelu(*x);
     });
     Ok(())
 }
-
-#[inline]
-fn gelu_single(x: f32) -> f32 {
-    0.5 * x * (1.0 + ((SQRT_2_OVER_PI as f32) * x * (1.0 + COEF_A * x * x)).tanh())
-}
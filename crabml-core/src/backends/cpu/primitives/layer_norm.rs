@@ -0,0 +1,75 @@
+use half::f16;
+
+use crate::backends::cpu::buf::CpuTensorBuf;
+use crate::error::Result;
+use crate::gguf::GGMLType;
+use crate::tensor::TensorStrider;
+
+pub fn layer_norm_inplace(
+    buf: &mut CpuTensorBuf<'_>,
+    strider: &TensorStrider,
+    eps: f32,
+) -> Result<()> {
+    assert!(strider.is_contiguous());
+    assert!(strider.shape().len() == 1 || strider.shape().len() == 2);
+    assert!(buf.dtype() == GGMLType::F32 || buf.dtype() == GGMLType::F16);
+
+    let (rows, cols) = if strider.shape().len() == 1 {
+        (1, strider.shape()[0])
+    } else {
+        (strider.shape()[0], strider.shape()[1])
+    };
+
+    if buf.dtype() == GGMLType::F16 {
+        let mut row_f32 = vec![0.0; cols];
+        for row in buf.as_f16_mut().chunks_exact_mut(cols).take(rows) {
+            row_f32
+                .iter_mut()
+                .zip(row.iter())
+                .for_each(|(d, s)| *d = s.to_f32());
+            layer_norm_inplace_vec_f32(&mut row_f32, eps);
+            row.iter_mut()
+                .zip(row_f32.iter())
+                .for_each(|(d, s)| *d = f16::from_f32(*s));
+        }
+        return Ok(());
+    }
+
+    let buf = buf.as_f32_mut();
+    for row in 0..rows {
+        layer_norm_inplace_vec_f32(&mut buf[row * cols..(row + 1) * cols], eps)
+    }
+
+    Ok(())
+}
+
+fn layer_norm_inplace_vec_f32(x: &mut [f32], eps: f32) {
+    let len = x.len() as f32;
+    let mean = x.iter().sum::<f32>() / len;
+    let var = x.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / len;
+    let scale = 1.0 / (var + eps).sqrt();
+    x.iter_mut().for_each(|v| *v = (*v - mean) * scale);
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_layer_norm() -> Result<()> {
+        let mut buf = CpuTensorBuf::from(vec![1.0, 2.0, 3.0, 4.0, 2.0, 2.0, 2.0, 2.0]);
+        let strider = TensorStrider::new(vec![2, 4]);
+        layer_norm_inplace(&mut buf, &strider, 1e-5)?;
+
+        // mean 2.5, var 1.25
+        let s = 1.0 / (1.25f32 + 1e-5).sqrt();
+        assert_relative_eq!(
+            buf.as_f32_ref(),
+            &[-1.5 * s, -0.5 * s, 0.5 * s, 1.5 * s, 0.0, 0.0, 0.0, 0.0][..],
+            epsilon = 1e-5
+        );
+        Ok(())
+    }
+}
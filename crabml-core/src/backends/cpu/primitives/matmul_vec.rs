@@ -1,12 +1,17 @@
-use rayon::prelude::*;
-
+use crate::backends::cpu::buf::buf_f16::vec_convert_f16_f32;
+use crate::backends::cpu::buf::buf_f32::vec_dot_f32_f32_strict;
 use crate::backends::cpu::buf::CpuTensorBuf;
+use crate::backends::cpu::parallel;
 use crate::backends::cpu::CpuTensorDeviceRef;
+use crate::error::Result;
+use crate::gguf::GGMLType;
 use crate::tensor::TensorStrider;
 
 /// only dense GEMV is supported
 /// (m, k) @ k -> (m, )
 /// (m, k) @ (b, k) -> (b, m)
+/// with acc, the result is added into bufc instead of overwriting it. bufc is in f32 or
+/// f16, the dots are always summed up in f32.
 pub fn matmul_vec<'a>(
     device: &CpuTensorDeviceRef<'a>,
     bufa: &CpuTensorBuf<'a>,
@@ -14,34 +19,106 @@ pub fn matmul_vec<'a>(
     bufc: &mut CpuTensorBuf<'a>,
     strider1: &TensorStrider,
     strider2: &TensorStrider,
-) {
+    acc: bool,
+) -> Result<()> {
     assert!(strider1.is_contiguous());
     assert!(strider2.is_contiguous());
     assert!(strider1.shape().last() == strider2.shape().last());
 
     let (m, k) = (strider1.shape()[0], strider1.shape()[1]);
-    gemv_dense_2d_2d(device, bufa, bufb, bufc, m, k);
+    if let CpuTensorBuf::F16(_) = bufc {
+        let mut bufc_f32 = CpuTensorBuf::from(match acc {
+            true => bufc.iter_f32().collect::<Vec<_>>(),
+            false => vec![0.0; bufc.len()],
+        });
+        gemv_dense_2d_2d(device, bufa, bufb, &mut bufc_f32, m, k, acc)?;
+        vec_convert_f16_f32(bufc.as_f16_mut(), bufc_f32.as_f32_ref());
+        return Ok(());
+    }
+    gemv_dense_2d_2d(device, bufa, bufb, bufc, m, k, acc)
 }
 
 #[allow(clippy::too_many_arguments)]
 fn gemv_dense_2d_2d(
-    _device: &CpuTensorDeviceRef,
+    device: &CpuTensorDeviceRef,
     bufa: &CpuTensorBuf,
     bufb: &CpuTensorBuf,
     bufc: &mut CpuTensorBuf,
     m: usize,
     k: usize,
-) {
+    acc: bool,
+) -> Result<()> {
     assert!(bufc.len() % 4 == 0);
 
     let bufc = bufc.as_f32_mut();
-    let bufb = &bufb.quantize(bufa.vec_dot_rhs_dtype()).unwrap();
-    bufc.par_iter_mut().enumerate().for_each(|(cn, cp)| {
-        // a: m x k
-        // b: b x k
-        // c: b x m
-        let mi = cn % m;
-        let bi = (cn - mi) / m;
-        *cp = bufa.vec_dot(mi * k, bufb, bi * k, k);
+    if device.is_deterministic() {
+        return gemv_strict(device, bufa, bufb, bufc, m, k, acc);
+    }
+    let bufb = &bufb.quantize(bufa.vec_dot_rhs_dtype())?;
+    let busy_poll = device.busy_poll.as_ref();
+    if let Some(busy_poll) = busy_poll {
+        busy_poll.wake();
+    }
+    device.install(|| {
+        parallel::for_each_mut(bufc, |cn, cp| {
+            // a: m x k
+            // b: b x k
+            // c: b x m
+            let mi = cn % m;
+            let bi = (cn - mi) / m;
+            let dot = bufa.vec_dot(mi * k, bufb, bi * k, k);
+            if acc {
+                *cp += dot;
+            } else {
+                *cp = dot;
+            }
+        });
+        // the main thread runs the small ops and the sampling until the next gemv. parked
+        // inside install(), so the workers of the pool that ran the op are the ones spinning
+        if let Some(busy_poll) = busy_poll {
+            busy_poll.park();
+        }
+    });
+    Ok(())
+}
+
+// dequantize the rows of a and keep b in f32, to dot them in the fixed order. a row of a is
+// dequantized once and dotted with all the rows of b
+fn gemv_strict(
+    device: &CpuTensorDeviceRef,
+    bufa: &CpuTensorBuf,
+    bufb: &CpuTensorBuf,
+    bufc: &mut [f32],
+    m: usize,
+    k: usize,
+    acc: bool,
+) -> Result<()> {
+    let bufb = bufb.clone().dequantize(GGMLType::F32)?;
+    let bufb = bufb.as_f32_ref();
+    let b = bufc.len() / m;
+    // the dots of the row mi of a are at mi * b.., c is b x m
+    let mut dots = vec![0.0; m * b];
+    let mut rows = dots.chunks_exact_mut(b).collect::<Vec<_>>();
+    device.install(|| {
+        parallel::for_each_mut_init(
+            &mut rows,
+            || vec![0.0; k],
+            |row, mi, out| {
+                bufa.dequantize_row(mi * k, row);
+                for (bi, dot) in out.iter_mut().enumerate() {
+                    *dot = vec_dot_f32_f32_strict(row, &bufb[bi * k..(bi + 1) * k]);
+                }
+            },
+        )
     });
+    for (cn, cp) in bufc.iter_mut().enumerate() {
+        let (bi, mi) = (cn / m, cn % m);
+        let dot = dots[mi * b + bi];
+        if acc {
+            *cp += dot;
+        } else {
+            *cp = dot;
+        }
+    }
+    Ok(())
 }
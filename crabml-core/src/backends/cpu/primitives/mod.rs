@@ -1,8 +1,10 @@
 mod arithmetic;
 mod batch_matmul;
+mod causal_mask;
 mod concatenate;
 mod contiguous;
 mod gelu;
+mod layer_norm;
 mod matmul_vec;
 mod rms_norm;
 mod rope;
@@ -13,11 +15,15 @@ pub use arithmetic::add_inplace;
 pub use arithmetic::div_inplace;
 pub use arithmetic::mul_inplace;
 pub use batch_matmul::batch_matmul;
+pub use causal_mask::block_mask_inplace;
+pub use causal_mask::causal_mask_inplace;
 pub use concatenate::concatenate_inplace;
 pub use contiguous::contiguous;
 pub use gelu::gelu_inplace;
+pub use layer_norm::layer_norm_inplace;
 pub use matmul_vec::matmul_vec;
 pub use rms_norm::rms_norm_inplace;
 pub use rope::rope_inplace;
+pub use rope::rope_positions_inplace;
 pub use silu::silu_inplace;
 pub use softmax::softmax_inplace;
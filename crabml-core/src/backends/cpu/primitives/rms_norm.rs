@@ -1,6 +1,8 @@
 use std::simd::f32x32;
 use std::simd::num::SimdFloat;
 
+use half::f16;
+
 use crate::backends::cpu::buf::CpuTensorBuf;
 use crate::error::Result;
 use crate::gguf::GGMLType;
@@ -13,7 +15,7 @@ pub fn rms_norm_inplace(
 ) -> Result<()> {
     assert!(strider.is_contiguous());
     assert!(strider.shape().len() == 1 || strider.shape().len() == 2);
-    assert!(buf.dtype() == GGMLType::F32);
+    assert!(buf.dtype() == GGMLType::F32 || buf.dtype() == GGMLType::F16);
 
     let (rows, cols) = if strider.shape().len() == 1 {
         (1, strider.shape()[0])
@@ -21,6 +23,15 @@ pub fn rms_norm_inplace(
         (strider.shape()[0], strider.shape()[1])
     };
 
+    if buf.dtype() == GGMLType::F16 {
+        let buf = buf.as_f16_mut();
+        let mut row_f32 = vec![0.0; cols];
+        for row in buf.chunks_exact_mut(cols).take(rows) {
+            rms_norm_inplace_vec_f16(row, &mut row_f32, eps);
+        }
+        return Ok(());
+    }
+
     let buf = buf.as_f32_mut();
     for row in 0..rows {
         rms_norm_inplace_vec_f32(&mut buf[row * cols..(row + 1) * cols], eps)
@@ -29,6 +40,18 @@ pub fn rms_norm_inplace(
     Ok(())
 }
 
+// the row is widened into f32 to sum up the squares, which overflows f16 easily
+fn rms_norm_inplace_vec_f16(x: &mut [f16], row_f32: &mut [f32], eps: f32) {
+    row_f32
+        .iter_mut()
+        .zip(x.iter())
+        .for_each(|(d, s)| *d = s.to_f32());
+    rms_norm_inplace_vec_f32(row_f32, eps);
+    x.iter_mut()
+        .zip(row_f32.iter())
+        .for_each(|(d, s)| *d = f16::from_f32(*s));
+}
+
 fn rms_norm_inplace_vec_f32(x: &mut [f32], eps: f32) {
     let len = x.len();
     assert!(len % 32 == 0);
@@ -1,27 +1,42 @@
 use std::borrow::Cow;
 
+use half::f16;
+
 use crate::backends::cpu::buf::CpuTensorBuf;
 use crate::error::Result;
 use crate::tensor::RopeMode;
 use crate::tensor::TensorStrider;
 
-// only support f32 yet
-// TODO: support f16
 pub fn rope_inplace(
     buf1: &mut CpuTensorBuf<'_>,
     strider1: &TensorStrider,
     mode: RopeMode,
     pos: usize,
     rope_dim: usize,
+    freq_base: f32,
+) -> Result<()> {
+    let n_batch = if strider1.dims() == 2 {
+        1
+    } else {
+        strider1.shape()[0]
+    };
+    let positions = (pos..pos + n_batch).collect::<Vec<_>>();
+    rope_positions_inplace(buf1, strider1, mode, &positions, rope_dim, freq_base)
+}
+
+/// like `rope_inplace()`, but the rows of the batch are rotated at the given positions,
+/// which are not required to be contiguous.
+pub fn rope_positions_inplace(
+    buf1: &mut CpuTensorBuf<'_>,
+    strider1: &TensorStrider,
+    mode: RopeMode,
+    positions: &[usize],
+    rope_dim: usize,
+    freq_base: f32,
 ) -> Result<()> {
     assert!(strider1.is_contiguous());
     assert!(strider1.dims() == 2 || strider1.dims() == 3);
 
-    let buf = match buf1 {
-        CpuTensorBuf::F32(Cow::Owned(buf)) => buf,
-        _ => panic!("only support f32 yet"),
-    };
-
     let (n_batch, bi_stride, head_dim) = if strider1.dims() == 2 {
         (1, strider1.len(), strider1.shape()[1])
     } else {
@@ -31,21 +46,46 @@ pub fn rope_inplace(
             strider1.shape()[2],
         )
     };
+    assert_eq!(positions.len(), n_batch);
+
+    let rope_row = |buf_row: &mut [f32], seq_pos: usize| match mode {
+        RopeMode::Llama => rope_llama(buf_row, seq_pos, head_dim, rope_dim, freq_base),
+        RopeMode::Neox => rope_neox(buf_row, seq_pos, head_dim, rope_dim, freq_base),
+    };
 
-    for bi in 0..n_batch {
-        let seq_pos = pos + bi;
-        let buf_row = &mut buf[bi * bi_stride..(bi + 1) * bi_stride];
-        match mode {
-            RopeMode::Llama => rope_llama(buf_row, seq_pos, head_dim, rope_dim),
-            RopeMode::Neox => rope_neox(buf_row, seq_pos, head_dim, rope_dim),
+    match buf1 {
+        CpuTensorBuf::F32(Cow::Owned(buf)) => {
+            for bi in 0..n_batch {
+                rope_row(
+                    &mut buf[bi * bi_stride..(bi + 1) * bi_stride],
+                    positions[bi],
+                );
+            }
+        }
+        // the f16 rows are rotated in f32, the rotation of the pairs needs the precision
+        CpuTensorBuf::F16(Cow::Owned(buf)) => {
+            let mut row_f32 = vec![0.0; bi_stride];
+            for bi in 0..n_batch {
+                let buf_row = &mut buf[bi * bi_stride..(bi + 1) * bi_stride];
+                row_f32
+                    .iter_mut()
+                    .zip(buf_row.iter())
+                    .for_each(|(d, s)| *d = s.to_f32());
+                rope_row(&mut row_f32, positions[bi]);
+                buf_row
+                    .iter_mut()
+                    .zip(row_f32.iter())
+                    .for_each(|(d, s)| *d = f16::from_f32(*s));
+            }
         }
+        _ => panic!("only support owned f32 or f16, got {:?}", buf1.dtype()),
     }
 
     Ok(())
 }
 
-fn rope_llama(buf: &mut [f32], pos: usize, head_dim: usize, rope_dim: usize) {
-    let theta_scale = 10000_f32.powf(-2.0 / head_dim as f32);
+fn rope_llama(buf: &mut [f32], pos: usize, head_dim: usize, rope_dim: usize, freq_base: f32) {
+    let theta_scale = freq_base.powf(-2.0 / rope_dim as f32);
     buf.chunks_exact_mut(head_dim).for_each(|chunk| {
         let mut theta: f32 = pos as f32;
         for i in 0..rope_dim / 2 {
@@ -62,19 +102,77 @@ fn rope_llama(buf: &mut [f32], pos: usize, head_dim: usize, rope_dim: usize) {
     });
 }
 
-fn rope_neox(buf: &mut [f32], pos: usize, head_dim: usize, rope_dim: usize) {
+// the neox style rotates the pairs of (i, i + rope_dim / 2) in the first rope_dim of each
+// head, the remaining dims are left as is, like the partial rotary embedding in Phi-2.
+fn rope_neox(buf: &mut [f32], pos: usize, head_dim: usize, rope_dim: usize, freq_base: f32) {
+    let half = rope_dim / 2;
     buf.chunks_exact_mut(head_dim).for_each(|chunk| {
-        for i in 0..rope_dim / 2 {
-            let freq_exponents = 2.0 * i as f32 / head_dim as f32;
-            let timescale = 10000_f32.powf(freq_exponents);
+        for i in 0..half {
+            let freq_exponents = 2.0 * i as f32 / rope_dim as f32;
+            let timescale = freq_base.powf(freq_exponents);
             let theta = pos as f32 / timescale;
             let cos_theta = theta.cos();
             let sin_theta = theta.sin();
 
             let qp0 = chunk[i];
-            let qp1 = chunk[i + head_dim / 2];
+            let qp1 = chunk[i + half];
             chunk[i] = qp0 * cos_theta - qp1 * sin_theta;
-            chunk[i + head_dim / 2] = qp0 * sin_theta + qp1 * cos_theta;
+            chunk[i + half] = qp0 * sin_theta + qp1 * cos_theta;
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_rope_neox_partial() -> Result<()> {
+        // 1 head of 8 dims, only the first 4 dims are rotated in the pairs of (0, 2), (1, 3)
+        let mut buf = CpuTensorBuf::from((0..8).map(|v| v as f32).collect::<Vec<_>>());
+        let strider = TensorStrider::new(vec![1, 8]);
+        rope_inplace(&mut buf, &strider, RopeMode::Neox, 1, 4, 100.0)?;
+
+        let (c0, s0) = (1f32.cos(), 1f32.sin());
+        let (c1, s1) = (0.1f32.cos(), 0.1f32.sin());
+        assert_relative_eq!(
+            buf.as_f32_ref(),
+            &[
+                -2.0 * s0,
+                c1 - 3.0 * s1,
+                2.0 * c0,
+                s1 + 3.0 * c1,
+                4.0,
+                5.0,
+                6.0,
+                7.0
+            ][..],
+            epsilon = 1e-5
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rope_positions() -> Result<()> {
+        // 3 rows of 1 head, rotating the rows at 2, 0, 5 is the same as rotating each
+        // row alone at its position
+        let data = (0..12).map(|v| v as f32).collect::<Vec<_>>();
+        let strider = TensorStrider::new(vec![3, 1, 4]);
+        let mut buf = CpuTensorBuf::from(data.clone());
+        rope_positions_inplace(&mut buf, &strider, RopeMode::Llama, &[2, 0, 5], 4, 100.0)?;
+
+        for (row, pos) in [2, 0, 5].into_iter().enumerate() {
+            let mut row_buf = CpuTensorBuf::from(data[row * 4..(row + 1) * 4].to_vec());
+            let row_strider = TensorStrider::new(vec![1, 1, 4]);
+            rope_inplace(&mut row_buf, &row_strider, RopeMode::Llama, pos, 4, 100.0)?;
+            assert_relative_eq!(
+                &buf.as_f32_ref()[row * 4..(row + 1) * 4],
+                row_buf.as_f32_ref(),
+                epsilon = 1e-5
+            );
+        }
+        Ok(())
+    }
+}
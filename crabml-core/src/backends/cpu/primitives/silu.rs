@@ -1,12 +1,21 @@
-use crate::backends::cpu::buf::buf_f32::exp_f32_cached;
 use crate::backends::cpu::buf::CpuTensorBuf;
+use crate::backends::cpu::lut::exp_table;
+use crate::backends::cpu::lut::silu_table;
 use crate::backends::cpu::CpuTensorDeviceRef;
 use crate::error::Result;
 
-pub fn silu_inplace<'a>(device: CpuTensorDeviceRef<'a>, buf: &mut CpuTensorBuf<'a>) -> Result<()> {
-    let exp_cache = &device.exp_cache;
+pub fn silu_inplace<'a>(_device: CpuTensorDeviceRef<'a>, buf: &mut CpuTensorBuf<'a>) -> Result<()> {
+    if let CpuTensorBuf::F16(_) = buf {
+        // the f16 values are looked up without a conversion
+        let silu_table = silu_table();
+        buf.as_f16_mut()
+            .iter_mut()
+            .for_each(|n| *n = silu_table.get(*n));
+        return Ok(());
+    }
+    let exp_table = exp_table();
     buf.iter_f32_mut().for_each(|n| {
-        let nexp = exp_f32_cached(-*n, exp_cache);
+        let nexp = exp_table.get_f32(-*n);
         *n /= 1.0 + nexp
     });
     Ok(())
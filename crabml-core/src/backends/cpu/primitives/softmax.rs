@@ -1,21 +1,22 @@
-use crate::backends::cpu::buf::buf_f32::exp_f32_cached;
+use half::f16;
+
 use crate::backends::cpu::buf::CpuTensorBuf;
+use crate::backends::cpu::lut::exp_table;
 use crate::backends::cpu::CpuTensorDeviceRef;
 use crate::error::ErrorKind;
 use crate::error::Result;
 use crate::gguf::GGMLType;
 use crate::tensor::TensorStrider;
 
-// TODO: support f16
 pub fn softmax_inplace<'a>(
-    device: CpuTensorDeviceRef<'a>,
+    _device: CpuTensorDeviceRef<'a>,
     buf: &mut CpuTensorBuf<'a>,
     strider: TensorStrider,
     axis: usize,
 ) -> Result<()> {
     assert!(strider.dims() == 2 || strider.dims() == 3);
     assert!(strider.is_contiguous());
-    assert!(buf.dtype() == GGMLType::F32);
+    assert!(buf.dtype() == GGMLType::F32 || buf.dtype() == GGMLType::F16);
 
     if axis != strider.dims() - 1 {
         return Err((
@@ -36,23 +37,46 @@ pub fn softmax_inplace<'a>(
     };
     let (stride_0, stride_1, _) = (rows * cols, cols, 1);
 
+    // the f16 rows are widened into f32, the sum of the exps is kept in f32
+    if buf.dtype() == GGMLType::F16 {
+        let mut row_f32 = vec![0.0; cols];
+        for buf_row in buf.as_f16_mut().chunks_exact_mut(cols).take(depths * rows) {
+            row_f32
+                .iter_mut()
+                .zip(buf_row.iter())
+                .for_each(|(d, s)| *d = s.to_f32());
+            softmax_row_f32(&mut row_f32);
+            buf_row
+                .iter_mut()
+                .zip(row_f32.iter())
+                .for_each(|(d, s)| *d = f16::from_f32(*s));
+        }
+        return Ok(());
+    }
+
     let buf = buf.as_f32_mut();
 
     for depth in 0..depths {
         for row in 0..rows {
             let buf_offset = depth * stride_0 + row * stride_1;
-            let buf_row = &mut buf[buf_offset..buf_offset + cols];
-            let max = buf_row.iter().fold(0.0, |m, val| val.max(m));
-            let sum = buf_row.iter_mut().fold(0.0, |mut acc, val| {
-                *val = exp_f32_cached(*val - max, &device.exp_cache);
-                acc += *val;
-                acc
-            });
-            buf_row.iter_mut().for_each(|val| {
-                *val /= sum;
-            });
+            softmax_row_f32(&mut buf[buf_offset..buf_offset + cols]);
         }
     }
 
     Ok(())
 }
+
+fn softmax_row_f32(buf_row: &mut [f32]) {
+    let exp_table = exp_table();
+    // the max of the row itself, a row of the scores all far below 0 like the saturated
+    // ones would underflow into a sum of 0 against 0
+    let max = buf_row.iter().fold(f32::NEG_INFINITY, |m, val| val.max(m));
+    let sum = buf_row.iter_mut().fold(0.0, |mut acc, val| {
+        *val = exp_table.get_f32(*val - max);
+        acc += *val;
+        acc
+    });
+    buf_row.iter_mut().for_each(|val| {
+        *val /= sum;
+    });
+}
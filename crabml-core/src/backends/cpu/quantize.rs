@@ -0,0 +1,422 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use crate::backends::cpu::CpuTensorBuf;
+use crate::backends::cpu::ImportanceMatrix;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::gguf::GGMLType;
+use crate::gguf::GGUFFile;
+use crate::gguf::GGUFMetadataValue;
+use crate::gguf::GGUFWriter;
+use crate::gguf::KEY_GENERAL_FILE_TYPE;
+use crate::gguf::KEY_GENERAL_QUANTIZATION_VERSION;
+
+// the quantization version of the block layouts, the same as ggml
+const QUANTIZATION_VERSION: u32 = 2;
+
+/// how a tensor is requantized and the error it takes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TensorQuantizeReport {
+    pub name: String,
+    pub dimensions: Vec<usize>,
+    pub src_typ: GGMLType,
+    pub dst_typ: GGMLType,
+    pub src_bytes: usize,
+    pub dst_bytes: usize,
+    /// the root mean square error against the source dequantized to f32.
+    pub rmse: f32,
+    pub max_abs_error: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct QuantizeOptions {
+    /// the type of the 2d weights.
+    pub dtype: GGMLType,
+    /// mix the types by the sensitivity of the tensors like llama.cpp, see `with_mixed()`.
+    pub mixed: bool,
+    /// pick the tensors taking more bits in the mix by the importance matrix.
+    pub imatrix: Option<ImportanceMatrix>,
+}
+
+impl QuantizeOptions {
+    pub fn new(dtype: GGMLType) -> Self {
+        Self {
+            dtype,
+            mixed: false,
+            imatrix: None,
+        }
+    }
+
+    /// keep output and token_embd in q6_k, or q8_0 on the legacy types, and quantize
+    /// attn_v one level higher, and ffn_down one level higher on the first and the last
+    /// eighth of the layers and every third layer between them, like the _M mixes of
+    /// llama.cpp. the 4 bit k-quants are bumped to q5_k, q5_0 to q8_0 and so on.
+    pub fn with_mixed(mut self, mixed: bool) -> Self {
+        self.mixed = mixed;
+        self
+    }
+
+    /// with the mix, the same number of ffn_down layers are bumped, but the ones whose error
+    /// weighted by the importance of the columns drops the most instead of the fixed layers.
+    pub fn with_imatrix(mut self, imatrix: ImportanceMatrix) -> Self {
+        self.imatrix = Some(imatrix);
+        self
+    }
+}
+
+/// requantize the weights of a gguf file into dtype and write a new gguf file, the tensors
+/// are streamed one by one, so it only holds a tensor in memory at a time.
+///
+/// the 1d tensors like the norms are kept as is. a tensor whose rows are not made of
+/// whole blocks of dtype falls back to q8_0, like the rows of 288 on the k-quants, or is kept
+/// if it's not a multiple of 32 either.
+pub fn quantize_gguf<W: Write>(
+    gf: &GGUFFile,
+    w: W,
+    dtype: GGMLType,
+    on_tensor: impl FnMut(&TensorQuantizeReport),
+) -> Result<W> {
+    quantize_gguf_with_options(gf, w, &QuantizeOptions::new(dtype), on_tensor)
+}
+
+/// like `quantize_gguf()`, with the types of the tensors mixed by the options.
+pub fn quantize_gguf_with_options<W: Write>(
+    gf: &GGUFFile,
+    w: W,
+    options: &QuantizeOptions,
+    mut on_tensor: impl FnMut(&TensorQuantizeReport),
+) -> Result<W> {
+    let dtype = options.dtype;
+    if !matches!(
+        dtype,
+        GGMLType::F32
+            | GGMLType::F16
+            | GGMLType::Q4_0
+            | GGMLType::Q4_1
+            | GGMLType::Q5_0
+            | GGMLType::Q5_1
+            | GGMLType::Q8_0
+            | GGMLType::Q2K
+            | GGMLType::Q3K
+            | GGMLType::Q4K
+            | GGMLType::Q5K
+            | GGMLType::Q6K
+    ) {
+        return Err((
+            ErrorKind::BadInput,
+            format!("quantizing the weights to {} is not supported", dtype),
+        )
+            .into());
+    }
+
+    let mut w = GGUFWriter::new(w, gf.architecture());
+    for (key, value) in gf.metadata().as_hashmap() {
+        w.add_metadata(key, value.clone());
+    }
+    match file_type(dtype) {
+        Some(v) => w.add_metadata(KEY_GENERAL_FILE_TYPE, GGUFMetadataValue::U32(v)),
+        None => w.remove_metadata(KEY_GENERAL_FILE_TYPE),
+    }
+    if dtype.block_size() > 1 {
+        w.add_metadata(
+            KEY_GENERAL_QUANTIZATION_VERSION,
+            GGUFMetadataValue::U32(QUANTIZATION_VERSION),
+        );
+    }
+
+    let mix = if options.mixed {
+        mixed_typs(gf, options)?
+    } else {
+        BTreeMap::new()
+    };
+    let dst_typs = gf
+        .tensor_infos()
+        .iter()
+        .map(|info| {
+            let typ = mix.get(info.name()).copied().unwrap_or(dtype);
+            target_typ(info.dimensions(), info.typ(), typ)
+        })
+        .collect::<Vec<_>>();
+    for (info, dst_typ) in gf.tensor_infos().iter().zip(dst_typs.iter()) {
+        w.add_tensor_info(info.name(), info.dimensions(), *dst_typ)?;
+    }
+    w.write_header()?;
+
+    for (info, dst_typ) in gf.tensor_infos().iter().zip(dst_typs) {
+        let mut report = TensorQuantizeReport {
+            name: info.name().to_string(),
+            dimensions: info.dimensions().to_vec(),
+            src_typ: info.typ(),
+            dst_typ,
+            src_bytes: info.data().len(),
+            dst_bytes: info.data().len(),
+            rmse: 0.0,
+            max_abs_error: 0.0,
+        };
+        if dst_typ == info.typ() {
+            w.write_tensor_data(info.data())?;
+            on_tensor(&report);
+            continue;
+        }
+
+        let src =
+            CpuTensorBuf::from_raw_bytes(info.data(), info.typ())?.dequantize(GGMLType::F32)?;
+        let dst = src.quantize(dst_typ)?;
+        w.write_tensor_data(dst.as_bytes())?;
+
+        let dst_f32 =
+            CpuTensorBuf::from_raw_bytes(dst.as_bytes(), dst_typ)?.dequantize(GGMLType::F32)?;
+        let (mut sum_sq, mut max_abs) = (0.0f64, 0.0f32);
+        for (a, b) in src.as_f32_ref().iter().zip(dst_f32.as_f32_ref()) {
+            let d = (a - b).abs();
+            sum_sq += (d as f64) * (d as f64);
+            max_abs = max_abs.max(d);
+        }
+        report.dst_bytes = dst.as_bytes().len();
+        report.rmse = (sum_sq / src.as_f32_ref().len().max(1) as f64).sqrt() as f32;
+        report.max_abs_error = max_abs;
+        on_tensor(&report);
+    }
+    w.finish()
+}
+
+// the types of the tensors differing from the dtype in the mix
+fn mixed_typs(gf: &GGUFFile, options: &QuantizeOptions) -> Result<BTreeMap<String, GGMLType>> {
+    let dtype = options.dtype;
+    let layer_of = |name: &str| -> Option<(usize, String)> {
+        let rest = name.strip_prefix("blk.")?;
+        let (layer, rest) = rest.split_once('.')?;
+        Some((layer.parse().ok()?, rest.to_string()))
+    };
+    let n_layers = gf
+        .tensor_infos()
+        .iter()
+        .filter_map(|info| layer_of(info.name()))
+        .map(|(l, _)| l + 1)
+        .max()
+        .unwrap_or(0);
+
+    let mut typs = BTreeMap::new();
+    let mut ffn_downs = vec![];
+    for info in gf.tensor_infos() {
+        let name = info.name();
+        match layer_of(name) {
+            None if name == "output.weight" || name == "token_embd.weight" => {
+                typs.insert(name.to_string(), high_typ(dtype));
+            }
+            Some((_, rest)) if rest == "attn_v.weight" => {
+                typs.insert(name.to_string(), more_bits_typ(dtype));
+            }
+            Some((l, rest)) if rest == "ffn_down.weight" => ffn_downs.push((l, info)),
+            _ => {}
+        }
+    }
+
+    // the layers in the pattern of use_more_bits() of llama.cpp, or as many layers taking
+    // the largest drop of the error weighted by the importance matrix
+    let n_more = (0..n_layers)
+        .filter(|l| use_more_bits(*l, n_layers))
+        .count();
+    let more_layers = match &options.imatrix {
+        None => (0..n_layers)
+            .filter(|l| use_more_bits(*l, n_layers))
+            .collect::<Vec<_>>(),
+        Some(imatrix) => {
+            let mut gains = vec![];
+            for (l, info) in ffn_downs.iter() {
+                let typ = target_typ(info.dimensions(), info.typ(), dtype);
+                let more = target_typ(info.dimensions(), info.typ(), more_bits_typ(dtype));
+                let src = CpuTensorBuf::from_raw_bytes(info.data(), info.typ())?
+                    .dequantize(GGMLType::F32)?;
+                let weights = imatrix.get(info.name());
+                let err = weighted_error(&src, typ, weights.as_deref())?;
+                let err_more = weighted_error(&src, more, weights.as_deref())?;
+                gains.push((*l, err - err_more));
+            }
+            gains.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+            gains.into_iter().take(n_more).map(|(l, _)| l).collect()
+        }
+    };
+    for (l, info) in ffn_downs {
+        if more_layers.contains(&l) {
+            typs.insert(info.name().to_string(), more_bits_typ(dtype));
+        }
+    }
+    Ok(typs)
+}
+
+// the sum of the squared errors of the tensor quantized into dtype, the columns are weighted
+// by their importance if there is
+fn weighted_error(src: &CpuTensorBuf, dtype: GGMLType, weights: Option<&[f32]>) -> Result<f64> {
+    let dst = src.quantize(dtype)?;
+    let dst = CpuTensorBuf::from_raw_bytes(dst.as_bytes(), dtype)?.dequantize(GGMLType::F32)?;
+    let mut sum = 0.0f64;
+    for (i, (a, b)) in src.as_f32_ref().iter().zip(dst.as_f32_ref()).enumerate() {
+        let d = (a - b) as f64;
+        let w = match weights {
+            Some(weights) if !weights.is_empty() => weights[i % weights.len()] as f64,
+            _ => 1.0,
+        };
+        sum += w * d * d;
+    }
+    Ok(sum)
+}
+
+fn use_more_bits(layer: usize, n_layers: usize) -> bool {
+    layer < n_layers / 8 || layer >= 7 * n_layers / 8 || (layer - n_layers / 8) % 3 == 2
+}
+
+// one level higher of the same family
+fn more_bits_typ(dtype: GGMLType) -> GGMLType {
+    match dtype {
+        GGMLType::Q2K => GGMLType::Q3K,
+        GGMLType::Q3K => GGMLType::Q4K,
+        GGMLType::Q4K => GGMLType::Q5K,
+        GGMLType::Q5K => GGMLType::Q6K,
+        GGMLType::Q6K => GGMLType::Q8_0,
+        GGMLType::Q4_0 => GGMLType::Q5_0,
+        GGMLType::Q4_1 => GGMLType::Q5_1,
+        GGMLType::Q5_0 | GGMLType::Q5_1 => GGMLType::Q8_0,
+        _ => dtype,
+    }
+}
+
+// the type of output and token_embd, which take the most of the quality
+fn high_typ(dtype: GGMLType) -> GGMLType {
+    match dtype {
+        GGMLType::Q2K | GGMLType::Q3K | GGMLType::Q4K | GGMLType::Q5K => GGMLType::Q6K,
+        GGMLType::Q4_0 | GGMLType::Q4_1 | GGMLType::Q5_0 | GGMLType::Q5_1 => GGMLType::Q8_0,
+        _ => dtype,
+    }
+}
+
+fn target_typ(dimensions: &[usize], src_typ: GGMLType, dtype: GGMLType) -> GGMLType {
+    if dimensions.len() < 2 {
+        return src_typ;
+    }
+    let row = dimensions[0];
+    if row % dtype.block_size() == 0 {
+        dtype
+    } else if row % GGMLType::Q8_0.block_size() == 0 {
+        GGMLType::Q8_0
+    } else {
+        src_typ
+    }
+}
+
+// the general.file_type of the mostly quantized files, in the llama_ftype enum of llama.cpp
+fn file_type(dtype: GGMLType) -> Option<u32> {
+    let v = match dtype {
+        GGMLType::F32 => 0,
+        GGMLType::F16 => 1,
+        GGMLType::Q4_0 => 2,
+        GGMLType::Q4_1 => 3,
+        GGMLType::Q8_0 => 7,
+        GGMLType::Q5_0 => 8,
+        GGMLType::Q5_1 => 9,
+        GGMLType::Q2K => 10,
+        GGMLType::Q3K => 12,
+        GGMLType::Q4K => 15,
+        GGMLType::Q5K => 17,
+        GGMLType::Q6K => 18,
+        _ => return None,
+    };
+    Some(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gguf::GGUFFileLoader;
+    use crate::testing::TempPath;
+
+    #[test]
+    fn test_quantize_gguf() -> Result<()> {
+        let loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf")?;
+        let gf = loader.open()?;
+
+        let path = TempPath::new("quantize-q4_0.gguf");
+        let file = std::fs::File::create(path.path()).unwrap();
+        let mut reports = vec![];
+        quantize_gguf(&gf, file, GGMLType::Q4_0, |r| reports.push(r.clone()))?;
+        assert_eq!(reports.len(), 48);
+
+        let loader2 = GGUFFileLoader::new(path.to_str())?;
+        let gf2 = loader2.open()?;
+        assert_eq!(gf2.metadata().get_u32(KEY_GENERAL_FILE_TYPE), Some(2));
+        for ((info, info2), r) in gf
+            .tensor_infos()
+            .iter()
+            .zip(gf2.tensor_infos())
+            .zip(&reports)
+        {
+            assert_eq!(info.name(), info2.name());
+            assert_eq!(info2.typ(), r.dst_typ);
+            assert_eq!(info2.data().len(), r.dst_bytes);
+            // the norms and the rows of 172 in ffn_down are kept
+            if info.dimensions().len() == 1 || info.dimensions()[0] % 32 != 0 {
+                assert_eq!(info2.typ(), GGMLType::F32);
+                assert_eq!(info.data(), info2.data());
+            } else {
+                assert_eq!(info2.typ(), GGMLType::Q4_0);
+                assert_eq!(r.dst_bytes * 64, r.src_bytes * 9);
+                assert!(r.rmse > 0.0 && r.rmse < 0.1, "{:?}", r);
+                assert!(r.max_abs_error >= r.rmse);
+            }
+        }
+
+        // the rows of 64 are not whole blocks of the k-quants, and fall back to q8_0
+        let mut reports = vec![];
+        quantize_gguf(&gf, vec![], GGMLType::Q4K, |r| reports.push(r.clone()))?;
+        let r = reports
+            .iter()
+            .find(|r| r.name == "blk.0.attn_q.weight")
+            .unwrap();
+        assert_eq!(r.dst_typ, GGMLType::Q8_0);
+        let r = reports
+            .iter()
+            .find(|r| r.name == "blk.0.ffn_down.weight")
+            .unwrap();
+        assert_eq!(r.dst_typ, GGMLType::F32);
+
+        assert!(quantize_gguf(&gf, vec![], GGMLType::Q8K, |_| {}).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_quantize_mixed() -> Result<()> {
+        let loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf")?;
+        let gf = loader.open()?;
+        let options = QuantizeOptions::new(GGMLType::Q4_0).with_mixed(true);
+        let mut reports = vec![];
+        quantize_gguf_with_options(&gf, vec![], &options, |r| reports.push(r.clone()))?;
+        let typ = |name: &str| reports.iter().find(|r| r.name == name).unwrap().dst_typ;
+        assert_eq!(typ("token_embd.weight"), GGMLType::Q8_0);
+        assert_eq!(typ("blk.0.attn_v.weight"), GGMLType::Q5_0);
+        assert_eq!(typ("blk.0.attn_q.weight"), GGMLType::Q4_0);
+
+        // the ffn_down of the layers 2 and 5 of 6 take more bits
+        let loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = loader.open()?;
+        let options = QuantizeOptions::new(GGMLType::Q4_0).with_mixed(true);
+        let typs = mixed_typs(&gf, &options)?;
+        let ffn_down = |typs: &BTreeMap<String, GGMLType>| {
+            (0..6)
+                .filter(|l| typs.get(&format!("blk.{}.ffn_down.weight", l)).is_some())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ffn_down(&typs), vec![2, 5]);
+        assert_eq!(typs.get("blk.3.attn_v.weight"), Some(&GGMLType::Q5_0));
+
+        // the importance matrix covering only the layer 3 picks it first
+        let mut imatrix = ImportanceMatrix::new();
+        imatrix.insert("blk.3.ffn_down.weight", 1, vec![1.0; 768]);
+        for l in [0, 1, 2, 4, 5] {
+            imatrix.insert(&format!("blk.{}.ffn_down.weight", l), 1, vec![0.0; 768]);
+        }
+        let typs = mixed_typs(&gf, &options.clone().with_imatrix(imatrix))?;
+        assert_eq!(ffn_down(&typs), vec![0, 3]);
+        Ok(())
+    }
+}
@@ -1,4 +1,6 @@
 pub mod cpu;
+#[cfg(all(feature = "std", not(target_os = "wasi")))]
 pub mod wgpu;
 
+#[cfg(feature = "std")]
 pub use cpu::CpuTensor;
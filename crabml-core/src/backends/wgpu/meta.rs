@@ -14,7 +14,7 @@ pub struct MatmulMeta {
     pub b: u32,
     pub m: u32,
     pub k: u32,
-    pub _padding: u32,
+    pub acc: u32,
 }
 
 // (M, N, K) x (N, K) = (M, N)
@@ -26,7 +26,7 @@ pub struct BatchMatmulMeta {
     pub k: u32,
     pub n: u32,
     pub strides_b: [u32; 3],
-    pub _padding_1: u32,
+    pub acc: u32,
 }
 
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
@@ -37,7 +37,9 @@ pub struct RopeMeta {
     pub pos: u32,
     pub n_heads: u32,
     pub n_rope_dims: u32,
-    pub _padding: [u32; 7],
+    pub freq_base: f32,
+    pub neox: u32,
+    pub _padding: [u32; 5],
 }
 
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
@@ -61,3 +63,23 @@ pub struct ContiguousMeta {
     pub n_elms: u32,
     pub _padding: [u32; 2],
 }
+
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C, align(16))]
+pub struct SampleMeta {
+    pub n: u32,
+    pub top_k: u32,
+    pub temperature: f32,
+    pub top_p: f32,
+    pub coin: f32,
+    pub _padding: [u32; 7],
+}
+
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C, align(16))]
+pub struct GetRowsMeta {
+    pub n_rows: u32,
+    pub n_dims: u32,
+    pub n_src_rows: u32,
+    pub _padding: u32,
+}
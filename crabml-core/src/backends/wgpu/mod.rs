@@ -1,8 +1,10 @@
 mod meta;
 mod wgpu_device;
+mod wgpu_sampler;
 mod wgpu_tensor;
 
 pub use wgpu_device::WgpuTensorDevice;
 pub use wgpu_device::WgpuTensorDeviceOptions;
 pub use wgpu_device::WgpuTensorDeviceRef;
+pub use wgpu_sampler::WgpuSampler;
 pub use wgpu_tensor::WgpuTensor;
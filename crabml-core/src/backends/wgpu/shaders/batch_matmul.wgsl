@@ -5,6 +5,8 @@ struct Meta {
     K: u32,
     N: u32,
     strides_b: vec3<u32>,
+    // add into the output instead of overwriting it when it's not 0
+    acc: u32,
 };
 
 @group(0) @binding(0)
@@ -25,6 +27,9 @@ fn main(
     @builtin(local_invocation_id) local_id: vec3<u32>,
 ) {
     let gidx = workgroup_id.x * 32u + local_id.x;
+    if gidx >= bufm.B * bufm.M * bufm.N {
+        return;
+    }
     let ni = gidx % bufm.N;
     let mi = ((gidx - ni) / bufm.N) % bufm.M;
     let bi = (gidx - ni - mi * bufm.N) / (bufm.M * bufm.N);
@@ -40,5 +45,10 @@ fn main(
         sum += a * b;
     }
 
-    output[bi * bufm.M * bufm.N + mi * bufm.N + ni] = sum;
+    let oi = bi * bufm.M * bufm.N + mi * bufm.N + ni;
+    if bufm.acc != 0u {
+        output[oi] += sum;
+    } else {
+        output[oi] = sum;
+    }
 }
\ No newline at end of file
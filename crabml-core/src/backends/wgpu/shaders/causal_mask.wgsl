@@ -0,0 +1,31 @@
+struct Meta {
+    B: u32, // n_heads
+    M: u32, // n_batch
+    N: u32, // seq len
+    W: u32, // sliding window, 0 means no sliding window
+}
+
+@group(0) @binding(0)
+var<storage, read_write> input: array<f32>;
+
+@group(0) @binding(1)
+var<storage, read> input_m: Meta;
+
+@compute @workgroup_size(32)
+fn main(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>,
+) {
+    let idx = workgroup_id.x * 32u + local_id.x;
+    if (idx >= input_m.B * input_m.M * input_m.N) {
+        return;
+    }
+
+    // the queries are the last M positions of the seq
+    let ni = idx % input_m.N;
+    let mi = (idx / input_m.N) % input_m.M;
+    let qpos = input_m.N - input_m.M + mi;
+    if (ni > qpos || (input_m.W > 0u && ni + input_m.W <= qpos)) {
+        input[idx] = -3.402823e+38f;
+    }
+}
@@ -15,6 +15,8 @@ fn main(
     }
 
     // x = 0.5 * x * (1.0 + (SQRT_2_OVER_PI * x * (1.0 + COEF_A * x * x)).tanh())
+    // tanh is clamped as it may overflow into nan on some backends, tanh(10) is 1 in f32 already
     let x = input[gidx];
-    input[gidx] = 0.5 * x * tanh(1.0 + (SQRT_2_OVER_PI * x * (1.0 + COEF_A * x * x)));
+    let t = clamp(SQRT_2_OVER_PI * x * (1.0 + COEF_A * x * x), -10.0, 10.0);
+    input[gidx] = 0.5 * x * (1.0 + tanh(t));
 }
\ No newline at end of file
@@ -0,0 +1,39 @@
+struct Meta {
+    nRows: u32, // number of ids
+    nDims: u32, // length of each row
+    nSrcRows: u32,
+    _padding: u32,
+};
+
+@group(0) @binding(0)
+var<storage, read_write> bufDst: array<f32>;
+
+@group(0) @binding(1)
+var<storage, read> bufSrc: array<f32>;
+
+@group(0) @binding(2)
+var<storage, read> bufIds: array<i32>;
+
+@group(0) @binding(3)
+var<storage, read> bufM: Meta;
+
+// each workgroup copies a single row, the rows of the ids out of range are zeroed
+
+@compute @workgroup_size(32)
+fn main(
+    @builtin(workgroup_id) workgroupID: vec3<u32>,
+    @builtin(local_invocation_id) localID: vec3<u32>,
+) {
+    let nDims = bufM.nDims;
+    let row = workgroupID.x;
+    let id = bufIds[row];
+    let valid = id >= 0 && u32(id) < bufM.nSrcRows;
+
+    for (var i = localID.x; i < nDims; i += 32u) {
+        if valid {
+            bufDst[row * nDims + i] = bufSrc[u32(id) * nDims + i];
+        } else {
+            bufDst[row * nDims + i] = 0.0;
+        }
+    }
+}
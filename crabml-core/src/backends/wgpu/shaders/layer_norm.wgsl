@@ -0,0 +1,65 @@
+struct Meta {
+    nBatch: u32, // number of vectors
+    nDims: u32, // length of each vector
+    eps: f32,
+    _padding: f32,
+};
+
+@group(0) @binding(0)
+var<storage, read_write> buf: array<f32>;
+
+@group(0) @binding(1)
+var<storage, read> bufM: Meta;
+
+// workgroup local to reduce the sum and the squared deviation
+var<workgroup> threadSums: array<f32, 32>;
+
+// each workgroup normalize a single vector
+
+@compute @workgroup_size(32)
+fn main(
+    @builtin(workgroup_id) workgroupID: vec3<u32>,
+    @builtin(local_invocation_id) localID: vec3<u32>,
+) {
+    let nDims = bufM.nDims;
+    let eps = bufM.eps;
+    let workgroupSize: u32 = 32u;
+    let offset = nDims * workgroupID.x;
+
+    // calculate the mean
+    var sum = 0.0;
+    for (var i = localID.x; i < nDims; i += workgroupSize) {
+        sum += buf[offset + i];
+    }
+    threadSums[localID.x] = sum;
+    workgroupBarrier();
+    if localID.x == 0u {
+        for (var i = 1u; i < workgroupSize; i += 1u) {
+            threadSums[0] += threadSums[i];
+        }
+    }
+    workgroupBarrier();
+    let mean = threadSums[0] / f32(nDims);
+    workgroupBarrier();
+
+    // calculate the variance
+    var sqSum = 0.0;
+    for (var i = localID.x; i < nDims; i += workgroupSize) {
+        let d = buf[offset + i] - mean;
+        sqSum += d * d;
+    }
+    threadSums[localID.x] = sqSum;
+    workgroupBarrier();
+    if localID.x == 0u {
+        for (var i = 1u; i < workgroupSize; i += 1u) {
+            threadSums[0] += threadSums[i];
+        }
+    }
+    workgroupBarrier();
+
+    // normalize to output
+    let scale = 1.0 / sqrt(threadSums[0] / f32(nDims) + eps);
+    for (var i = localID.x; i < nDims; i += workgroupSize) {
+        buf[offset + i] = (buf[offset + i] - mean) * scale;
+    }
+}
@@ -1,10 +1,12 @@
 struct Meta {
-    nBatch: u32, // number of vectors
+    nBatch: u32, // number of vectors, the vector at row i is at the position of pos + i
     nDims: u32, // length of vector
     pos: u32,
     nHeads: u32,
     nRopeDims: u32,
-    _padding: vec3<u32>,
+    freqBase: f32,
+    neox: u32, // 1 to rotate the pairs of (i, i + nRopeDims / 2) instead of (2i, 2i + 1)
+    _padding: vec2<u32>,
 };
 
 @group(0) @binding(0)
@@ -28,16 +30,22 @@ fn main(
 
     for (var h = 0u; h < bufM.nHeads; h++) {
         for (var i = 0u; i < bufM.nRopeDims / 2u; i++) {
-            let thetaScale = pow(10000.0, -2.0 * f32(i) / f32(nHeadDims));
-            let theta = f32(bufM.pos) * thetaScale;
+            let thetaScale = pow(bufM.freqBase, -2.0 * f32(i) / f32(bufM.nRopeDims));
+            let theta = f32(bufM.pos + gidx) * thetaScale;
 
             let cosTheta = cos(theta);
             let sinTheta = sin(theta);
-            let qpOffset = gidx * bufM.nDims + h * nHeadDims + i * 2u;
-            let qp0 = input[qpOffset];
-            let qp1 = input[qpOffset + 1u];
-            input[qpOffset] = qp0 * cosTheta - qp1 * sinTheta;
-            input[qpOffset+1u] = qp0 * sinTheta + qp1 * cosTheta;
+            let headOffset = gidx * bufM.nDims + h * nHeadDims;
+            var i0 = headOffset + i * 2u;
+            var i1 = i0 + 1u;
+            if bufM.neox == 1u {
+                i0 = headOffset + i;
+                i1 = i0 + bufM.nRopeDims / 2u;
+            }
+            let qp0 = input[i0];
+            let qp1 = input[i1];
+            input[i0] = qp0 * cosTheta - qp1 * sinTheta;
+            input[i1] = qp0 * sinTheta + qp1 * cosTheta;
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,190 @@
+struct Meta {
+    N: u32, // vocab size
+    topK: u32, // 0 means no limit
+    temperature: f32, // <= 0 means greedy
+    topP: f32, // <= 0 or >= 1 means no limit
+    coin: f32, // a random number in [0, 1)
+    _padding: vec3<u32>,
+};
+
+@group(0) @binding(0)
+var<storage, read> logits: array<f32>;
+
+@group(0) @binding(1)
+var<storage, read> bufM: Meta;
+
+@group(0) @binding(2)
+var<storage, read_write> output: array<u32>;
+
+const WG: u32 = 256u;
+
+// the thresholds of top-k and top-p are found by bisecting on the logits, instead of sorting
+// the whole vocab. the tokens which are equal to the threshold are kept.
+const BISECT_STEPS: u32 = 32u;
+
+var<workgroup> sharedF: array<f32, 256>;
+var<workgroup> sharedU: array<u32, 256>;
+
+fn reduceSum(lid: u32, v: f32) -> f32 {
+    sharedF[lid] = v;
+    workgroupBarrier();
+    for (var s = WG / 2u; s > 0u; s = s / 2u) {
+        if lid < s {
+            sharedF[lid] += sharedF[lid + s];
+        }
+        workgroupBarrier();
+    }
+    let r = sharedF[0];
+    workgroupBarrier();
+    return r;
+}
+
+fn reduceMin(lid: u32, v: f32) -> f32 {
+    sharedF[lid] = v;
+    workgroupBarrier();
+    for (var s = WG / 2u; s > 0u; s = s / 2u) {
+        if lid < s {
+            sharedF[lid] = min(sharedF[lid], sharedF[lid + s]);
+        }
+        workgroupBarrier();
+    }
+    let r = sharedF[0];
+    workgroupBarrier();
+    return r;
+}
+
+// the lower index wins on ties
+fn reduceArgmax(lid: u32, v: f32, idx: u32) -> u32 {
+    sharedF[lid] = v;
+    sharedU[lid] = idx;
+    workgroupBarrier();
+    for (var s = WG / 2u; s > 0u; s = s / 2u) {
+        if lid < s {
+            let ov = sharedF[lid + s];
+            let oi = sharedU[lid + s];
+            if ov > sharedF[lid] || (ov == sharedF[lid] && oi < sharedU[lid]) {
+                sharedF[lid] = ov;
+                sharedU[lid] = oi;
+            }
+        }
+        workgroupBarrier();
+    }
+    let r = sharedU[0];
+    workgroupBarrier();
+    return r;
+}
+
+// the sum of the unnormalized probabilities of the logits >= t
+fn massAbove(lid: u32, t: f32, maxLogit: f32, invT: f32) -> f32 {
+    var m = 0.0;
+    for (var i = lid; i < bufM.N; i += WG) {
+        let l = logits[i];
+        if l >= t {
+            m += exp((l - maxLogit) * invT);
+        }
+    }
+    return reduceSum(lid, m);
+}
+
+// the whole vocab is processed in a single workgroup, only the sampled token is written out
+@compute @workgroup_size(256)
+fn main(
+    @builtin(local_invocation_id) localID: vec3<u32>,
+) {
+    let lid = localID.x;
+    let n = bufM.N;
+    let greedy = bufM.temperature <= 0.0;
+    let invT = select(1.0 / bufM.temperature, 1.0, greedy);
+
+    var best = -3.402823e+38f;
+    var bestIdx = 0u;
+    var lo = 3.402823e+38f;
+    for (var i = lid; i < n; i += WG) {
+        let l = logits[i];
+        if l > best {
+            best = l;
+            bestIdx = i;
+        }
+        lo = min(lo, l);
+    }
+    let argmax = reduceArgmax(lid, best, bestIdx);
+    let maxLogit = logits[argmax];
+    let minLogit = reduceMin(lid, lo);
+
+    // top-k: the largest threshold which keeps at least k tokens
+    let k = select(bufM.topK, n, bufM.topK == 0u || bufM.topK >= n);
+    var tLo = minLogit;
+    var tHi = maxLogit;
+    for (var s = 0u; s < BISECT_STEPS; s++) {
+        let mid = (tLo + tHi) * 0.5;
+        var c = 0.0;
+        for (var i = lid; i < n; i += WG) {
+            if logits[i] >= mid {
+                c += 1.0;
+            }
+        }
+        let ok = reduceSum(lid, c) >= f32(k);
+        tLo = select(tLo, mid, ok);
+        tHi = select(mid, tHi, ok);
+    }
+    let tK = tLo;
+
+    // top-p: the largest threshold which keeps at least p of the mass left by top-k
+    let p = select(bufM.topP, 1.0, bufM.topP <= 0.0 || bufM.topP >= 1.0);
+    let massK = massAbove(lid, tK, maxLogit, invT);
+    tHi = maxLogit;
+    for (var s = 0u; s < BISECT_STEPS; s++) {
+        let mid = (tLo + tHi) * 0.5;
+        let ok = massAbove(lid, mid, maxLogit, invT) >= p * massK;
+        tLo = select(tLo, mid, ok);
+        tHi = select(mid, tHi, ok);
+    }
+    let t = tLo;
+
+    // sample in the order of token ids, each thread sums up a contiguous chunk
+    let chunk = (n + WG - 1u) / WG;
+    let start = min(lid * chunk, n);
+    let end = min(start + chunk, n);
+    var m = 0.0;
+    for (var i = start; i < end; i++) {
+        let l = logits[i];
+        if l >= t {
+            m += exp((l - maxLogit) * invT);
+        }
+    }
+    sharedF[lid] = m;
+    workgroupBarrier();
+
+    if lid == 0u {
+        var total = 0.0;
+        for (var j = 0u; j < WG; j++) {
+            total += sharedF[j];
+        }
+        let coinMass = bufM.coin * total;
+
+        var result = argmax;
+        var acc = 0.0;
+        var found = false;
+        for (var j = 0u; j < WG && !found; j++) {
+            if acc + sharedF[j] <= coinMass {
+                acc += sharedF[j];
+                continue;
+            }
+            // in case of rounding errors, the last kept token in the chunk is taken
+            found = true;
+            let s0 = min(j * chunk, n);
+            let e0 = min(s0 + chunk, n);
+            for (var i = s0; i < e0; i++) {
+                let l = logits[i];
+                if l >= t {
+                    acc += exp((l - maxLogit) * invT);
+                    result = i;
+                    if acc > coinMass {
+                        break;
+                    }
+                }
+            }
+        }
+        output[0] = select(result, argmax, greedy);
+    }
+}
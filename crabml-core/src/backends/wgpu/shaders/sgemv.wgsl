@@ -2,7 +2,8 @@ struct Meta {
     B: u32,
     M: u32,
     K: u32,
-    _padding: u32,
+    // add into C instead of overwriting it when it's not 0
+    acc: u32,
 };
 
 @group(0) @binding(0)
@@ -45,5 +46,10 @@ fn main(
         let w = dot(bufA[(mi + 3u) * K / 4u + ki / 4u], bc);
         tmp += vec4<f32>(x, y, z, w);
     }
-    bufC[(bi * M + mi) / 4u] = tmp;
+    let ci = (bi * M + mi) / 4u;
+    if md.acc != 0u {
+        bufC[ci] += tmp;
+    } else {
+        bufC[ci] = tmp;
+    }
 }
\ No newline at end of file
@@ -0,0 +1,66 @@
+struct Meta {
+    B: u32,
+    M: u32,
+    K: u32,
+    // add into C instead of overwriting it when it's not 0
+    acc: u32,
+};
+
+// the q8_0 blocks are repacked on upload, the 32 quants of all the blocks come first, 8 u32
+// per block, followed by the scales of all the blocks in f32.
+@group(0) @binding(0)
+var<storage, read> bufA: array<u32>;
+
+@group(0) @binding(1)
+var<storage, read> bufB: array<vec4<f32>>;
+
+@group(0) @binding(2)
+var<storage, read> md: Meta;
+
+@group(0) @binding(3)
+var<storage, read_write> bufC: array<f32>;
+
+fn unpack_i8x4(w: u32) -> vec4<f32> {
+    return vec4<f32>(
+        f32(i32(w << 24u) >> 24u),
+        f32(i32(w << 16u) >> 24u),
+        f32(i32(w << 8u) >> 24u),
+        f32(i32(w) >> 24u),
+    );
+}
+
+// A: (M, K) in q8_0
+// B: (B, K)
+// C: (B, M)
+// each thread computes one element of C
+
+@compute @workgroup_size(32)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+) {
+    let idx = global_id.x;
+    if idx >= md.B * md.M {
+        return;
+    }
+    let bi = idx / md.M;
+    let mi = idx % md.M;
+
+    let nBlocksRow = md.K / 32u;
+    let scalesOffset = md.M * nBlocksRow * 8u;
+
+    var sum = 0.0f;
+    for (var blk = 0u; blk < nBlocksRow; blk++) {
+        let ai = mi * nBlocksRow + blk;
+        let bOffset = (bi * md.K + blk * 32u) / 4u;
+        var s = 0.0f;
+        for (var w = 0u; w < 8u; w++) {
+            s += dot(unpack_i8x4(bufA[ai * 8u + w]), bufB[bOffset + w]);
+        }
+        sum += bitcast<f32>(bufA[scalesOffset + ai]) * s;
+    }
+    if md.acc != 0u {
+        bufC[idx] += sum;
+    } else {
+        bufC[idx] = sum;
+    }
+}
@@ -19,7 +19,7 @@ fn main(
         return;
     }
 
-    var max = 0.0f;
+    var max = -3.40282347e+38f;
     for (var ni = 0u; ni < input_m.N; ni = ni + 1u) {
         let idx = mi * input_m.N + ni;
         if (input[idx] > max) {
@@ -1,11 +1,15 @@
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::ops::Deref;
 use std::rc::Rc;
 
 use wgpu::util::DeviceExt;
 
+use crate::error::ErrorKind;
+use crate::error::Result;
 use crate::tensor::Tensor;
+
 pub struct WgpuTensorDeviceOptions {
     pub staging_buf_bytes: usize,
 
@@ -44,6 +48,9 @@ pub struct WgpuTensorDevice {
     pub(crate) staging_buf: wgpu::Buffer,
     pub(crate) modules: HashMap<&'static str, wgpu::ShaderModule>,
 
+    /// the released activation buffers by their size in bytes, see `WgpuBuffer`
+    pub(crate) buffer_pool: RefCell<HashMap<u64, Vec<wgpu::Buffer>>>,
+
     /// used for test only
     pub debug_tensors: RefCell<HashMap<String, Vec<f32>>>,
 }
@@ -65,6 +72,7 @@ impl WgpuTensorDevice {
             queue,
             staging_buf,
             modules: HashMap::new(),
+            buffer_pool: RefCell::new(HashMap::new()),
             debug_tensors: RefCell::new(HashMap::new()),
         };
         d.load_modules();
@@ -77,16 +85,29 @@ impl WgpuTensorDevice {
             ("mul_inplace", include_str!("shaders/mul.wgsl")),
             ("div_inplace", include_str!("shaders/div.wgsl")),
             ("rms_norm_inplace", include_str!("shaders/rms_norm.wgsl")),
+            (
+                "layer_norm_inplace",
+                include_str!("shaders/layer_norm.wgsl"),
+            ),
             ("sgemv", include_str!("shaders/sgemv.wgsl")),
+            ("sgemv_q8_0", include_str!("shaders/sgemv_q8_0.wgsl")),
+            ("sgemv_q4_0", include_str!("shaders/sgemv_q4_0.wgsl")),
             ("rope_inplace", include_str!("shaders/rope.wgsl")),
             ("softmax_inplace", include_str!("shaders/softmax.wgsl")),
+            (
+                "causal_mask_inplace",
+                include_str!("shaders/causal_mask.wgsl"),
+            ),
             ("silu_inplace", include_str!("shaders/silu.wgsl")),
+            ("gelu_inplace", include_str!("shaders/gelu.wgsl")),
             ("batch_matmul", include_str!("shaders/batch_matmul.wgsl")),
             (
                 "concatenate_inplace",
                 include_str!("shaders/concatenate.wgsl"),
             ),
             ("contiguous", include_str!("shaders/contiguous.wgsl")),
+            ("sample", include_str!("shaders/sample.wgsl")),
+            ("get_rows", include_str!("shaders/get_rows.wgsl")),
         ];
         let mut modules = HashMap::new();
         for (module_name, module_source) in module_sources {
@@ -101,6 +122,45 @@ impl WgpuTensorDevice {
         self.modules = modules
     }
 
+    /// takes a zeroed storage buffer of the size from the pool, or creates one if the pool
+    /// has none. the buffer returns to the pool when dropped.
+    pub(crate) fn alloc_buffer(device: &WgpuTensorDeviceRef, bytes: u64) -> WgpuBuffer {
+        let pooled = device
+            .buffer_pool
+            .borrow_mut()
+            .get_mut(&bytes)
+            .and_then(|bufs| bufs.pop());
+        let buf = match pooled {
+            Some(buf) => {
+                // the commands on the queue run in order, the ops submitted before still
+                // see the old content
+                let mut encoder = device
+                    .inner
+                    .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+                encoder.clear_buffer(&buf, 0, None);
+                device.queue.submit(Some(encoder.finish()));
+                buf
+            }
+            None => device.inner.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("tensor storage buffer"),
+                size: bytes,
+                usage: wgpu::BufferUsages::STORAGE
+                    | wgpu::BufferUsages::COPY_SRC
+                    | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+        };
+        WgpuBuffer {
+            buf: Some(buf),
+            pool: Some(device.clone()),
+        }
+    }
+
+    /// the count of the buffers released to the pool and not reused yet.
+    pub fn pooled_buffers(&self) -> usize {
+        self.buffer_pool.borrow().values().map(|v| v.len()).sum()
+    }
+
     pub(crate) fn make_storage_buffer(&self, name: &'static str, content: &[u8]) -> wgpu::Buffer {
         self.inner
             .create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -163,6 +223,49 @@ impl WgpuTensorDevice {
         encoder
     }
 
+    /// copy the head of src into dst through the staging buffer, and wait for it.
+    pub(crate) fn read_buffer(&self, src: &wgpu::Buffer, dst: &mut [u8]) -> Result<()> {
+        let buf_size = dst.len();
+        if buf_size > self.opts.staging_buf_bytes {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "buffer size exceeded staging buffer limit: {}, got: {}",
+                    self.opts.staging_buf_bytes, buf_size,
+                ),
+            )
+                .into());
+        }
+
+        // enqueue copy from src to staging buffer
+        let mut encoder = self
+            .inner
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.copy_buffer_to_buffer(src, 0, &self.staging_buf, 0, buf_size as u64);
+        self.queue.submit(Some(encoder.finish()));
+
+        // await from the staging buf
+        let (tx, rx) = std::sync::mpsc::sync_channel(1);
+        let staging_slice = self.staging_buf.slice(..);
+        staging_slice.map_async(wgpu::MapMode::Read, move |v| tx.send(v).unwrap());
+        self.inner.poll(wgpu::Maintain::Wait);
+
+        if let Ok(Ok(())) = rx.recv() {
+            // Gets contents of buffer
+            let data = staging_slice.get_mapped_range();
+            dst.copy_from_slice(&data[0..buf_size]);
+
+            // With the current interface, we have to make sure all mapped views are
+            // dropped before we unmap the buffer.
+            drop(data);
+            self.staging_buf.unmap();
+        } else {
+            panic!("failed to run compute on gpu!")
+        }
+
+        Ok(())
+    }
+
     pub fn record_debug_tensor(&self, name: String, tensor: &impl Tensor) {
         let mut dst = vec![0.0; tensor.strider().len()];
         tensor.export(&mut dst).unwrap();
@@ -173,3 +276,42 @@ impl WgpuTensorDevice {
         self.debug_tensors.borrow().get(name).cloned()
     }
 }
+
+/// a storage buffer on the gpu. the buffers of the weights are owned, and the buffers
+/// allocated for the activations go back to the pool of the device when dropped. so the
+/// intermediate tensors of a forward pass reuse the buffers of the previous one, and stay
+/// resident on the gpu between the ops without any allocation.
+pub struct WgpuBuffer {
+    buf: Option<wgpu::Buffer>,
+    pool: Option<WgpuTensorDeviceRef>,
+}
+
+impl WgpuBuffer {
+    pub(crate) fn owned(buf: wgpu::Buffer) -> Self {
+        Self {
+            buf: Some(buf),
+            pool: None,
+        }
+    }
+}
+
+impl Deref for WgpuBuffer {
+    type Target = wgpu::Buffer;
+
+    fn deref(&self) -> &wgpu::Buffer {
+        self.buf.as_ref().unwrap()
+    }
+}
+
+impl Drop for WgpuBuffer {
+    fn drop(&mut self) {
+        if let (Some(buf), Some(device)) = (self.buf.take(), self.pool.take()) {
+            device
+                .buffer_pool
+                .borrow_mut()
+                .entry(buf.size())
+                .or_default()
+                .push(buf);
+        }
+    }
+}
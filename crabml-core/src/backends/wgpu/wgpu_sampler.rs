@@ -0,0 +1,177 @@
+use rand::Rng;
+use rand::SeedableRng;
+
+use super::meta::SampleMeta;
+use super::WgpuTensor;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::sampler::SamplerRng;
+use crate::tensor::Tensor;
+
+/// sample the next token on the gpu with temperature, top-k and top-p, like the
+/// `TopK -> Temperature -> TopP -> Dist` chain on the host. only the sampled token id is
+/// read back, instead of the whole logits.
+///
+/// the random numbers are drawn from an rng on the host seeded like `SamplerChain`, so a
+/// sequence can be reproduced from the `seed()`.
+pub struct WgpuSampler {
+    temperature: f32,
+    top_k: usize,
+    top_p: f32,
+    seed: u64,
+    rng: SamplerRng,
+}
+
+impl WgpuSampler {
+    /// a temperature <= 0 is greedy, a top_k of 0 or a top_p >= 1 means no limit.
+    pub fn new(temperature: f32, top_k: usize, top_p: f32) -> Self {
+        let seed = rand::random();
+        Self {
+            temperature,
+            top_k,
+            top_p,
+            seed,
+            rng: SamplerRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self.rng = SamplerRng::seed_from_u64(seed);
+        self
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// restart the rng from the seed.
+    pub fn reset(&mut self) {
+        self.rng = SamplerRng::seed_from_u64(self.seed);
+    }
+
+    /// the logits are in the shape of (vocab_size, ) or (1, vocab_size).
+    pub fn sample(&mut self, logits: &WgpuTensor) -> Result<usize> {
+        let n = logits.strider().len();
+        if n == 0
+            || logits
+                .strider()
+                .shape()
+                .iter()
+                .rev()
+                .skip(1)
+                .any(|d| *d != 1)
+        {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "expected the logits of a single token, got the shape of {:?}",
+                    logits.strider().shape()
+                ),
+            )
+                .into());
+        }
+
+        let meta = SampleMeta {
+            n: n as u32,
+            top_k: self.top_k as u32,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            coin: self.rng.gen_range(0.0..1.0),
+            _padding: [0; 7],
+        };
+
+        let device = logits.device();
+        let meta_buf = device.make_storage_buffer("meta", bytemuck::bytes_of(&meta));
+        let output_buf = device.inner.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("sampled_token"),
+            size: 4,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let entries = &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: logits.buf().as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: meta_buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: output_buf.as_entire_binding(),
+            },
+        ];
+        let encoder = device.encode_pipeline_commnad("sample", entries, (1, 1, 1));
+        device.queue.submit(Some(encoder.finish()));
+
+        let mut token = [0u8; 4];
+        device.read_buffer(&output_buf, &mut token)?;
+        Ok(u32::from_le_bytes(token) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::wgpu::WgpuTensorDevice;
+    use crate::backends::wgpu::WgpuTensorDeviceOptions;
+
+    fn sample_n(sampler: &mut WgpuSampler, logits: &[f32], times: usize) -> Result<Vec<usize>> {
+        let device = WgpuTensorDevice::new(WgpuTensorDeviceOptions::new());
+        let logits = WgpuTensor::new(logits, &[logits.len()], device)?;
+        (0..times).map(|_| sampler.sample(&logits)).collect()
+    }
+
+    #[test]
+    fn test_wgpu_sampler_greedy() -> Result<()> {
+        // more logits than the workgroup size, with ties on the max
+        let mut logits = (0..1000)
+            .map(|i| (i % 97) as f32 * 0.01)
+            .collect::<Vec<_>>();
+        logits[600] = 5.0;
+        logits[700] = 5.0;
+
+        let tokens = sample_n(&mut WgpuSampler::new(0.0, 0, 1.0), &logits, 3)?;
+        assert_eq!(tokens, vec![600; 3]);
+
+        // top-k = 1 is greedy whatever the temperature
+        let tokens = sample_n(&mut WgpuSampler::new(1.5, 1, 1.0), &logits, 8)?;
+        assert!(
+            tokens.iter().all(|t| *t == 600 || *t == 700),
+            "{:?}",
+            tokens
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_wgpu_sampler_top_k_top_p() -> Result<()> {
+        let logits = (0..300).map(|i| -(i as f32) * 0.5).collect::<Vec<_>>();
+
+        let tokens = sample_n(&mut WgpuSampler::new(1.0, 3, 1.0), &logits, 50)?;
+        assert!(tokens.iter().all(|t| *t < 3), "{:?}", tokens);
+
+        // probs: 0.39, 0.24, 0.14, 0.09..., the first two tokens cover 0.6
+        let tokens = sample_n(&mut WgpuSampler::new(1.0, 0, 0.6), &logits, 50)?;
+        assert!(tokens.iter().all(|t| *t < 2), "{:?}", tokens);
+        assert!(tokens.contains(&0) && tokens.contains(&1), "{:?}", tokens);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wgpu_sampler_dist() -> Result<()> {
+        // probs: 0.75, 0.25
+        let mut sampler = WgpuSampler::new(1.0, 0, 1.0).with_seed(42);
+        let tokens = sample_n(&mut sampler, &[3f32.ln(), 0.0], 400)?;
+        let n0 = tokens.iter().filter(|t| **t == 0).count();
+        assert!(n0 > 250 && n0 < 350, "{}", n0);
+
+        // reproducible from the seed
+        sampler.reset();
+        let tokens2 = sample_n(&mut sampler, &[3f32.ln(), 0.0], 400)?;
+        assert_eq!(tokens, tokens2);
+        Ok(())
+    }
+}
@@ -1,24 +1,32 @@
 use std::rc::Rc;
 
-use wgpu::util::DeviceExt;
-
 use super::meta::ConcatenateMeta;
+use super::meta::GetRowsMeta;
 use super::meta::MatmulMeta;
 use super::meta::RmsNormMeta;
+use super::wgpu_device::WgpuBuffer;
+use super::WgpuTensorDevice;
 use super::WgpuTensorDeviceRef;
+use crate::backends::cpu::CpuTensor;
+use crate::backends::cpu::CpuTensorBuf;
 use crate::backends::wgpu::meta::BatchMatmulMeta;
 use crate::backends::wgpu::meta::ContiguousMeta;
 use crate::backends::wgpu::meta::RopeMeta;
 use crate::error::ErrorKind;
 use crate::error::Result;
 use crate::gguf::GGMLType;
+use crate::tensor::MatmulEpilogue;
 use crate::tensor::RopeMode;
 use crate::tensor::Tensor;
 use crate::tensor::TensorStrider;
 
+/// the weight buffers are padded to a multiple of it, so the shaders reading the packed
+/// quants by u32 or the values by vec4 never read past the end of a buffer.
+pub const WEIGHT_BUF_ALIGN: usize = 16;
+
 #[derive(Clone)]
 pub struct WgpuTensor {
-    buf: Rc<wgpu::Buffer>,
+    buf: Rc<WgpuBuffer>,
     dtype: GGMLType,
     capacity: usize, // max element count
     strider: TensorStrider,
@@ -28,19 +36,14 @@ pub struct WgpuTensor {
 
 impl WgpuTensor {
     pub fn new(src: &[f32], shape: &[usize], device: WgpuTensorDeviceRef) -> Result<Self> {
-        let buf = device
-            .inner
-            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("tensor weights buffer"),
-                contents: bytemuck::cast_slice(src),
-                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
-            });
         let strider = TensorStrider::new(shape.to_vec());
         if strider.len() != src.len() {
             return Err((ErrorKind::TensorError, "new: buffer size mismatch").into());
         };
+        let bytes: &[u8] = bytemuck::cast_slice(src);
+        let buf = create_weight_buffer(&device, bytes.len(), |dst| dst.copy_from_slice(bytes));
         Ok(Self {
-            buf: Rc::new(buf),
+            buf: Rc::new(WgpuBuffer::owned(buf)),
             capacity: src.len(),
             dtype: GGMLType::F32,
             strider,
@@ -55,16 +58,10 @@ impl WgpuTensor {
         shape: &[usize],
         device: WgpuTensorDeviceRef,
     ) -> Result<Self> {
-        let buf = device
-            .inner
-            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("tensor weights buffer"),
-                contents: buf,
-                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
-            });
+        let buf = create_weight_buffer(&device, buf.len(), |dst| dst.copy_from_slice(buf));
         let strider = TensorStrider::new(shape.to_vec());
         Ok(Self {
-            buf: Rc::new(buf),
+            buf: Rc::new(WgpuBuffer::owned(buf)),
             dtype,
             capacity: strider.len(),
             strider,
@@ -73,6 +70,77 @@ impl WgpuTensor {
         })
     }
 
+    /// uploads a cpu tensor of any dtype and strides. the contiguous f32, and the q8_0 and
+    /// q4_0 tensors with keep_quantized, go through `from_cpu_buf()`. the others are
+    /// dequantized and gathered in the order of their strides straight into the mapped gpu
+    /// buffer in one pass, so a transposed or quantized weight does not take the copies of
+    /// contiguous() and dequantize() on the host while loading.
+    pub fn from_cpu_tensor(
+        tensor: &CpuTensor,
+        keep_quantized: bool,
+        device: WgpuTensorDeviceRef,
+    ) -> Result<Self> {
+        let supported = match tensor.dtype() {
+            GGMLType::F32 => true,
+            GGMLType::Q8_0 | GGMLType::Q4_0 => keep_quantized,
+            _ => false,
+        };
+        if supported && tensor.is_contiguous() {
+            return Self::from_cpu_buf(tensor.buf(), tensor.shape(), device);
+        }
+
+        let strider = tensor.strider();
+        let buf = create_weight_buffer(&device, strider.len() * 4, |dst| {
+            pack_f32(tensor.buf(), strider, bytemuck::cast_slice_mut(dst))
+        });
+        Ok(Self {
+            buf: Rc::new(WgpuBuffer::owned(buf)),
+            dtype: GGMLType::F32,
+            capacity: strider.len(),
+            strider: TensorStrider::new(tensor.shape().to_vec()),
+            device,
+            name: None,
+        })
+    }
+
+    /// uploads a tensor from the cpu. the f32, q8_0 and q4_0 tensors are kept in their dtype,
+    /// the quantized blocks are repacked to a layout which the shaders can read by u32: the
+    /// quants of all the blocks come first, followed by the scales of all the blocks in f32.
+    pub fn from_cpu_buf(
+        buf: &CpuTensorBuf,
+        shape: &[usize],
+        device: WgpuTensorDeviceRef,
+    ) -> Result<Self> {
+        match buf {
+            CpuTensorBuf::F32(buf) => Self::new(buf, shape, device),
+            CpuTensorBuf::Q8_0(buf) => {
+                let mut bytes = Vec::with_capacity(buf.blocks.len() * 36);
+                for blk in buf.blocks.iter() {
+                    bytes.extend(blk.qs.iter().map(|q| *q as u8));
+                }
+                for blk in buf.blocks.iter() {
+                    bytes.extend(blk.d.to_f32().to_le_bytes());
+                }
+                Self::from_buf(&bytes, GGMLType::Q8_0, shape, device)
+            }
+            CpuTensorBuf::Q4_0(buf) => {
+                let mut bytes = Vec::with_capacity(buf.blocks.len() * 20);
+                for blk in buf.blocks.iter() {
+                    bytes.extend(blk.qs.iter());
+                }
+                for blk in buf.blocks.iter() {
+                    bytes.extend(blk.d.to_f32().to_le_bytes());
+                }
+                Self::from_buf(&bytes, GGMLType::Q4_0, shape, device)
+            }
+            _ => Err((
+                ErrorKind::TensorError,
+                format!("unsupported tensor type on gpu {:?}", buf.dtype()),
+            )
+                .into()),
+        }
+    }
+
     pub fn is_contiguous(&self) -> bool {
         self.strider.is_contiguous()
     }
@@ -80,28 +148,140 @@ impl WgpuTensor {
     pub fn shape(&self) -> &[usize] {
         self.strider.shape()
     }
+
+    pub(crate) fn buf(&self) -> &wgpu::Buffer {
+        &self.buf
+    }
+
+    pub(crate) fn device(&self) -> &WgpuTensorDeviceRef {
+        &self.device
+    }
+
+    // (m, k) @ (b, k) => (b, m) into output, with acc the result is added into output
+    fn encode_matmul_vec(&self, rhs: &Self, output: &Self, acc: bool) -> Result<()> {
+        assert!(self.shape().len() == 2);
+        assert!(self.shape().last() == rhs.shape().last());
+        assert!(self.is_contiguous());
+        assert!(rhs.is_contiguous());
+
+        // a 1d rhs is a batch of 1
+        let b = rhs.strider.len() / self.shape()[1];
+        let (module, n_workgroups) = match self.dtype {
+            GGMLType::F32 => ("sgemv", b * self.shape()[0] / 32),
+            GGMLType::Q8_0 => ("sgemv_q8_0", b * self.shape()[0] / 32 + 1),
+            GGMLType::Q4_0 => ("sgemv_q4_0", b * self.shape()[0] / 32 + 1),
+            _ => {
+                return Err((
+                    ErrorKind::TensorError,
+                    format!("matmul_vec: unsupported dtype {:?} on wgpu", self.dtype),
+                )
+                    .into());
+            }
+        };
+
+        let meta = MatmulMeta {
+            b: b as u32,
+            m: self.strider.shape()[0] as u32,
+            k: self.strider.shape()[1] as u32,
+            acc: acc as u32,
+        };
+
+        let meta_buf = self
+            .device
+            .make_storage_buffer("meta", bytemuck::bytes_of(&meta));
+        let entries = &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: self.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: rhs.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: meta_buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: output.buf.as_entire_binding(),
+            },
+        ];
+        let encoder =
+            self.device
+                .encode_pipeline_commnad(module, entries, (n_workgroups as u32, 1, 1));
+        self.device.queue.submit(Some(encoder.finish()));
+        Ok(())
+    }
+
+    // (b, m, k) @ (b, k, n) => (b, m, n) into output, with acc the result is added into output
+    fn encode_batch_matmul(&self, y: &Self, output: &Self, acc: bool) -> Result<()> {
+        assert!(self.shape().len() == 3);
+        assert!(y.shape().len() == 3);
+        assert!(self.shape()[0] == y.shape()[0]);
+        assert!(self.shape()[2] == y.shape()[1]);
+        assert!(self.is_contiguous());
+
+        let meta = BatchMatmulMeta {
+            b: y.shape()[0] as u32,
+            m: self.shape()[1] as u32,
+            k: self.shape()[2] as u32,
+            n: y.shape()[2] as u32,
+            strides_b: [
+                y.strider.strides()[0] as u32,
+                y.strider.strides()[1] as u32,
+                y.strider.strides()[2] as u32,
+            ],
+            acc: acc as u32,
+        };
+        let meta_bytes = bytemuck::bytes_of(&meta);
+
+        let meta_buf = self.device.make_storage_buffer("meta", meta_bytes);
+        let entries = &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: self.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: y.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: meta_buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: output.buf.as_entire_binding(),
+            },
+        ];
+        let encoder = self.device.encode_pipeline_commnad(
+            "batch_matmul",
+            entries,
+            (meta.b * meta.m * meta.n / 32 + 1, 1, 1),
+        );
+        self.device.queue.submit(Some(encoder.finish()));
+        Ok(())
+    }
 }
 
 impl Tensor for WgpuTensor {
     type Device = WgpuTensorDeviceRef;
 
     fn alloc(shape: &[usize], dtype: GGMLType, device: Self::Device) -> Result<Self> {
-        assert!(dtype == GGMLType::F32, "wgpu tensor only support F32 yet");
+        assert!(
+            dtype == GGMLType::F32 || dtype == GGMLType::I32,
+            "wgpu tensor only support F32 and I32 yet"
+        );
         let n_elms = shape.iter().product::<usize>();
 
+        // both f32 and i32 take 4 bytes
         let buf_bytes = n_elms * std::mem::size_of::<f32>();
-        let buf = device.inner.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("tensor storage buffer"),
-            size: buf_bytes as u64,
-            usage: wgpu::BufferUsages::STORAGE
-                | wgpu::BufferUsages::COPY_SRC
-                | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+        let buf = WgpuTensorDevice::alloc_buffer(&device, buf_bytes as u64);
         let strider = TensorStrider::new(shape.to_vec());
         Ok(Self {
             buf: Rc::new(buf),
-            dtype: GGMLType::F32,
+            dtype,
             capacity: n_elms,
             strider,
             device,
@@ -293,46 +473,83 @@ impl Tensor for WgpuTensor {
     }
 
     fn export(&self, dst: &mut [f32]) -> Result<()> {
-        let buf_size = std::mem::size_of_val(dst);
-        if buf_size > self.device.opts.staging_buf_bytes {
+        self.device
+            .read_buffer(&self.buf, bytemuck::cast_slice_mut(dst))
+    }
+
+    fn new_i32(data: &[i32], shape: &[usize], device: Self::Device) -> Result<Self> {
+        if data.len() != shape.iter().product::<usize>() {
+            return Err((ErrorKind::TensorError, "new_i32: buffer size mismatch").into());
+        }
+        Self::from_buf(bytemuck::cast_slice(data), GGMLType::I32, shape, device)
+    }
+
+    fn new_f32(data: &[f32], shape: &[usize], device: Self::Device) -> Result<Self> {
+        Self::new(data, shape, device)
+    }
+
+    fn export_i32(&self, dst: &mut [i32]) -> Result<()> {
+        if self.dtype != GGMLType::I32 {
+            return Err((ErrorKind::TensorError, "export_i32: not an i32 tensor").into());
+        }
+        self.device
+            .read_buffer(&self.buf, bytemuck::cast_slice_mut(dst))
+    }
+
+    fn get_rows(&mut self, src: &Self, ids: &Self) -> Result<()> {
+        if !self.is_contiguous() || self.dtype != GGMLType::F32 {
             return Err((
                 ErrorKind::TensorError,
-                format!(
-                    "buffer size exceeded staging buffer limit: {}, got: {}",
-                    self.device.opts.staging_buf_bytes, buf_size,
-                ),
+                "get_rows: dst is not a contiguous f32",
+            )
+                .into());
+        }
+        if src.dtype != GGMLType::F32 || src.strider.dims() != 2 {
+            return Err((
+                ErrorKind::TensorError,
+                "get_rows: src is not a 2d f32 tensor",
+            )
+                .into());
+        }
+        if ids.dtype != GGMLType::I32 || ids.strider.dims() != 1 {
+            return Err((
+                ErrorKind::TensorError,
+                "get_rows: ids is not a 1d i32 tensor",
             )
                 .into());
         }
 
-        // enqueue copy from self.buf to staging buffer
-        let mut encoder = self
+        let meta = GetRowsMeta {
+            n_rows: ids.shape()[0] as u32,
+            n_dims: src.shape()[1] as u32,
+            n_src_rows: src.shape()[0] as u32,
+            _padding: 0,
+        };
+        let meta_buf = self
             .device
-            .inner
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-        encoder.copy_buffer_to_buffer(&self.buf, 0, &self.device.staging_buf, 0, buf_size as u64);
+            .make_storage_buffer("meta", bytemuck::bytes_of(&meta));
+        let entries = &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: self.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: src.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: ids.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: meta_buf.as_entire_binding(),
+            },
+        ];
+        let encoder = self
+            .device
+            .encode_pipeline_commnad("get_rows", entries, (meta.n_rows, 1, 1));
         self.device.queue.submit(Some(encoder.finish()));
-
-        // await from the staging buf
-        let (tx, rx) = std::sync::mpsc::sync_channel(1);
-        let staging_slice = self.device.staging_buf.slice(..);
-        staging_slice.map_async(wgpu::MapMode::Read, move |v| tx.send(v).unwrap());
-        self.device.inner.poll(wgpu::Maintain::Wait);
-
-        if let Ok(Ok(())) = rx.recv() {
-            // Gets contents of buffer
-            let data = staging_slice.get_mapped_range();
-            // Since contents are got in bytes, this converts these bytes back to u32
-            dst.copy_from_slice(&bytemuck::cast_slice(&data)[0..dst.len()]);
-
-            // With the current interface, we have to make sure all mapped views are
-            // dropped before we unmap the buffer.
-            drop(data);
-            self.device.staging_buf.unmap();
-        } else {
-            panic!("failed to run compute on gpu!")
-        }
-
         Ok(())
     }
 
@@ -343,15 +560,22 @@ impl Tensor for WgpuTensor {
             .device
             .inner
             .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-        encoder.copy_buffer_to_buffer(&self.buf, 0, &new_tensor.buf, 0, self.buf.size());
+        // the weight buffers may be padded past the values
+        let size = self.buf.size().min(new_tensor.buf.size());
+        encoder.copy_buffer_to_buffer(&self.buf, 0, &new_tensor.buf, 0, size);
         self.device.queue.submit(Some(encoder.finish()));
         Ok(new_tensor)
     }
 
-    fn rope_inplace(self, mode: RopeMode, pos: usize, rope_dims: usize) -> Result<Self> {
+    fn rope_inplace(
+        self,
+        mode: RopeMode,
+        pos: usize,
+        rope_dims: usize,
+        freq_base: f32,
+    ) -> Result<Self> {
         assert!(self.shape().len() == 3 || self.shape().len() == 2);
         assert!(self.is_contiguous());
-        assert!(mode == RopeMode::Llama, "TODO: only support Llama mode yet");
 
         let (rows, n_head, m) = if self.strider.dims() == 3 {
             (
@@ -368,7 +592,9 @@ impl Tensor for WgpuTensor {
             pos: pos as u32,
             n_heads: n_head as u32,
             n_rope_dims: rope_dims as u32,
-            _padding: [0; 7],
+            freq_base,
+            neox: (mode == RopeMode::Neox) as u32,
+            _padding: [0; 5],
         };
 
         let meta_buf = self
@@ -427,6 +653,41 @@ impl Tensor for WgpuTensor {
         Ok(self)
     }
 
+    fn layer_norm_inplace(self, eps: f32) -> Result<Self> {
+        assert!(self.strider.dims() == 2 || self.strider.dims() == 1);
+        let (n_batch, n_dims) = if self.strider.dims() == 2 {
+            (self.shape()[0], self.shape()[1])
+        } else {
+            (1, self.shape()[0])
+        };
+        let meta = &RmsNormMeta {
+            n_batch: n_batch as u32,
+            n_dims: n_dims as u32,
+            eps,
+            _padding: 0,
+        };
+        let meta_buf = self
+            .device
+            .make_storage_buffer("meta", bytemuck::bytes_of(meta));
+        let entries = &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: self.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: meta_buf.as_entire_binding(),
+            },
+        ];
+        let encoder = self.device.encode_pipeline_commnad(
+            "layer_norm_inplace",
+            entries,
+            (meta.n_batch, 1, 1),
+        );
+        self.device.queue.submit(Some(encoder.finish()));
+        Ok(self)
+    }
+
     fn softmax_inplace(self, axis: usize) -> Result<Self> {
         assert!(axis == self.strider.dims() - 1);
         assert!(self.is_contiguous());
@@ -460,6 +721,46 @@ impl Tensor for WgpuTensor {
         Ok(self)
     }
 
+    fn causal_mask_inplace(self, sliding_window: Option<usize>) -> Result<Self> {
+        assert!(self.shape().len() == 3);
+        assert!(self.is_contiguous());
+
+        let (b, m, n) = (
+            self.shape()[0] as u32,
+            self.shape()[1] as u32,
+            self.shape()[2] as u32,
+        );
+        if m > n {
+            return Err((
+                ErrorKind::TensorError,
+                "causal_mask: n_batch is larger than the seq len",
+            )
+                .into());
+        }
+
+        let w = sliding_window.unwrap_or(0) as u32;
+        let meta_buf = self
+            .device
+            .make_storage_buffer("meta", bytemuck::cast_slice(&[b, m, n, w]));
+        let entries = &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: self.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: meta_buf.as_entire_binding(),
+            },
+        ];
+        let encoder = self.device.encode_pipeline_commnad(
+            "causal_mask_inplace",
+            entries,
+            (b * m * n / 32 + 1, 1, 1),
+        );
+        self.device.queue.submit(Some(encoder.finish()));
+        Ok(self)
+    }
+
     fn silu_inplace(self) -> Result<Self> {
         assert!(self.is_contiguous());
 
@@ -620,109 +921,78 @@ impl Tensor for WgpuTensor {
 
     // (m, k) @ (b, k) => (b, m)
     fn matmul_vec(&self, rhs: &Self) -> Result<Self> {
-        assert!(self.shape().len() == 2);
-        assert!(self.shape().last() == rhs.shape().last());
-        assert!(self.is_contiguous());
-        assert!(rhs.is_contiguous());
-
-        let output = Self::alloc(
-            &[rhs.strider.shape()[0], self.strider.shape()[0]],
-            GGMLType::F32,
-            self.device.clone(),
-        )?;
-        let meta = MatmulMeta {
-            b: rhs.strider.shape()[0] as u32,
-            m: self.strider.shape()[0] as u32,
-            k: self.strider.shape()[1] as u32,
-            _padding: 0,
+        let output_shape = match rhs.shape().len() {
+            1 => vec![self.shape()[0]],
+            _ => vec![rhs.shape()[0], self.shape()[0]],
         };
-
-        let meta_buf = self
-            .device
-            .make_storage_buffer("meta", bytemuck::bytes_of(&meta));
-        let entries = &[
-            wgpu::BindGroupEntry {
-                binding: 0,
-                resource: self.buf.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 1,
-                resource: rhs.buf.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 2,
-                resource: meta_buf.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 3,
-                resource: output.buf.as_entire_binding(),
-            },
-        ];
-        let encoder =
-            self.device
-                .encode_pipeline_commnad("sgemv", entries, (meta.b * meta.m / 32, 1, 1));
-        self.device.queue.submit(Some(encoder.finish()));
-
+        let output = Self::alloc(&output_shape, GGMLType::F32, self.device.clone())?;
+        self.encode_matmul_vec(rhs, &output, false)?;
         Ok(output)
     }
 
+    fn matmul_vec_acc(&self, rhs: &Self, acc: Self) -> Result<Self> {
+        let c_len = rhs.strider.len() / self.shape()[1] * self.shape()[0];
+        if acc.dtype != GGMLType::F32 || !acc.is_contiguous() || acc.strider.len() != c_len {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "matmul_vec_acc: expected a contiguous f32 accumulator of {} elements, got {:?} {:?}",
+                    c_len,
+                    acc.dtype,
+                    acc.shape()
+                ),
+            )
+                .into());
+        }
+        self.encode_matmul_vec(rhs, &acc, true)?;
+        Ok(acc)
+    }
+
     /// (b, m, k) @ (b, k, n) => (b, m, n)
     /// the A matrix is dense and the B matrix is allowed to be strided
     fn batch_matmul(&self, y: &Self) -> Result<Self> {
-        assert!(self.shape().len() == 3);
-        assert!(y.shape().len() == 3);
-        assert!(self.shape()[0] == y.shape()[0]);
-        assert!(self.shape()[2] == y.shape()[1]);
-        assert!(self.is_contiguous());
-
         // (b, m, k) @ (b, k, n) => (b, m, n)
         let output = Self::alloc(
             &[y.shape()[0], self.shape()[1], y.shape()[2]],
             GGMLType::F32,
             self.device.clone(),
         )?;
+        self.encode_batch_matmul(y, &output, false)?;
+        Ok(output)
+    }
 
-        let meta = BatchMatmulMeta {
-            b: y.shape()[0] as u32,
-            m: self.shape()[1] as u32,
-            k: self.shape()[2] as u32,
-            n: y.shape()[2] as u32,
-            strides_b: [
-                y.strider.strides()[0] as u32,
-                y.strider.strides()[1] as u32,
-                y.strider.strides()[2] as u32,
-            ],
-            ..Default::default()
-        };
-        let meta_bytes = bytemuck::bytes_of(&meta);
-
-        let meta_buf = self.device.make_storage_buffer("meta", meta_bytes);
-        let entries = &[
-            wgpu::BindGroupEntry {
-                binding: 0,
-                resource: self.buf.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 1,
-                resource: y.buf.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 2,
-                resource: meta_buf.as_entire_binding(),
-            },
-            wgpu::BindGroupEntry {
-                binding: 3,
-                resource: output.buf.as_entire_binding(),
-            },
-        ];
-        let encoder = self.device.encode_pipeline_commnad(
-            "batch_matmul",
-            entries,
-            (meta.b * meta.m * meta.n / 32 + 1, 1, 1),
-        );
-        self.device.queue.submit(Some(encoder.finish()));
+    fn batch_matmul_acc(&self, y: &Self, acc: Self) -> Result<Self> {
+        let c_shape = [y.shape()[0], self.shape()[1], y.shape()[2]];
+        if acc.dtype != GGMLType::F32 || !acc.is_contiguous() || acc.shape() != c_shape {
+            return Err((
+                ErrorKind::TensorError,
+                format!(
+                    "batch_matmul_acc: expected a contiguous f32 accumulator of {:?}, got {:?} {:?}",
+                    c_shape,
+                    acc.dtype,
+                    acc.shape()
+                ),
+            )
+                .into());
+        }
+        self.encode_batch_matmul(y, &acc, true)?;
+        Ok(acc)
+    }
 
-        Ok(output)
+    // the shader has no epilogue yet, the scale goes on a copy of A instead, which is
+    // smaller than the output on the attention scores
+    fn batch_matmul_epilogue(&self, y: &Self, epilogue: MatmulEpilogue) -> Result<Self> {
+        if epilogue.soft_cap.is_some() || epilogue.clamp.is_some() {
+            return Err((
+                ErrorKind::NotImplemented,
+                "batch_matmul_epilogue: the soft cap and the clamp are not supported on wgpu yet",
+            )
+                .into());
+        }
+        if epilogue.scale == 1.0 {
+            return self.batch_matmul(y);
+        }
+        self.dup()?.scale_inplace(epilogue.scale)?.batch_matmul(y)
     }
 
     fn contiguous(self) -> Result<Self> {
@@ -772,6 +1042,57 @@ impl Tensor for WgpuTensor {
     }
 }
 
+// create a storage buffer of len bytes padded to WEIGHT_BUF_ALIGN, fill writes the content
+// into the mapped buffer, the padding is zeroed.
+fn create_weight_buffer(
+    device: &WgpuTensorDeviceRef,
+    len: usize,
+    fill: impl FnOnce(&mut [u8]),
+) -> wgpu::Buffer {
+    let size = len.next_multiple_of(WEIGHT_BUF_ALIGN).max(WEIGHT_BUF_ALIGN);
+    let buf = device.inner.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("tensor weights buffer"),
+        size: size as u64,
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: true,
+    });
+    {
+        let mut mapped = buf.slice(..).get_mapped_range_mut();
+        fill(&mut mapped[..len]);
+        mapped[len..].fill(0);
+    }
+    buf.unmap();
+    buf
+}
+
+// gather the values of a cpu buffer in the order of the strider into out as f32. the blocks
+// are dequantized into a small cache keyed by their offset, so walking a transposed weight
+// dequantizes each block about once instead of once per value.
+fn pack_f32(buf: &CpuTensorBuf, strider: &TensorStrider, out: &mut [f32]) {
+    const SLOTS: usize = 1 << 10;
+    // the unquantized values are cached in the spans of 64 as well
+    let span = match buf.dtype().block_size() {
+        1 => 64,
+        n => n,
+    };
+    let mut cache = vec![0.0f32; SLOTS * span];
+    let mut cached = vec![usize::MAX; SLOTS];
+    for (o, offset) in out.iter_mut().zip(strider.iter()) {
+        let start = offset / span * span;
+        // the fibonacci hash spreads the blocks of the rows with a power of 2 stride over
+        // the slots, by the top 10 bits
+        let slot = ((start / span) as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 54;
+        let slot = slot as usize;
+        let values = &mut cache[slot * span..(slot + 1) * span];
+        if cached[slot] != start {
+            let len = span.min(buf.len() - start);
+            buf.dequantize_row(start, &mut values[..len]);
+            cached[slot] = start;
+        }
+        *o = values[offset - start];
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::LazyLock;
@@ -779,6 +1100,12 @@ mod tests {
     use approx::assert_relative_eq;
 
     use super::WgpuTensor;
+    use super::WEIGHT_BUF_ALIGN;
+    use crate::backends::cpu::buf::QuantBufQ4_0;
+    use crate::backends::cpu::buf::QuantBufQ8_0;
+    use crate::backends::cpu::CpuTensor;
+    use crate::backends::cpu::CpuTensorBuf;
+    use crate::backends::cpu::CpuTensorDevice;
     use crate::backends::wgpu::WgpuTensorDevice;
     use crate::backends::wgpu::WgpuTensorDeviceOptions;
     use crate::backends::wgpu::WgpuTensorDeviceRef;
@@ -804,6 +1131,28 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_wgpu_get_rows() -> Result<()> {
+        let src = (0..6 * 40).map(|v| v as f32).collect::<Vec<_>>();
+        let t1 = WgpuTensor::new(&src, &[6, 40], DEVICE.clone())?;
+        let ids = WgpuTensor::new_i32(&[5, 1, 5, 7], &[4], DEVICE.clone())?;
+        let mut t2 = WgpuTensor::alloc(&[4, 40], GGMLType::F32, DEVICE.clone())?;
+        t2.get_rows(&t1, &ids)?;
+
+        let mut dst = vec![0.0; 4 * 40];
+        t2.export(&mut dst)?;
+        assert_eq!(&dst[0..40], &src[200..240]);
+        assert_eq!(&dst[40..80], &src[40..80]);
+        assert_eq!(&dst[80..120], &src[200..240]);
+        // the ids out of range are zeroed
+        assert_eq!(&dst[120..160], &[0.0; 40]);
+
+        let mut exported = vec![0; 4];
+        ids.export_i32(&mut exported)?;
+        assert_eq!(exported, vec![5, 1, 5, 7]);
+        Ok(())
+    }
+
     #[test]
     fn test_wgpu_tensor_add() -> Result<()> {
         let t1 = WgpuTensor::new(&[2.0; 64], &[16, 4], DEVICE.clone())?;
@@ -921,6 +1270,35 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_wgpu_tensor_layer_norm() -> Result<()> {
+        let v1 = (0..80).map(|i| (i % 13) as f32).collect::<Vec<_>>();
+        let t1 = WgpuTensor::new(&v1, &[2, 40], DEVICE.clone())?;
+        let t1 = t1.layer_norm_inplace(1e-5)?;
+        let mut dst1 = vec![0.0; 80];
+        t1.export(&mut dst1)?;
+
+        let t2 = CpuTensor::new(v1, &[2, 40], CpuTensorDevice::new())?;
+        let t2 = t2.layer_norm_inplace(1e-5)?;
+        let mut dst2 = vec![0.0; 80];
+        t2.export(&mut dst2)?;
+        assert_relative_eq!(&dst1[..], &dst2[..], epsilon = 1e-5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wgpu_causal_mask() -> Result<()> {
+        let t1 = WgpuTensor::new(&[1.0; 16], &[2, 2, 4], DEVICE.clone())?;
+        let t1 = t1.causal_mask_inplace(Some(2))?;
+        let mut dst1 = vec![0.0; 16];
+        t1.export(&mut dst1)?;
+
+        let m = -3.402_823e38_f32;
+        assert_eq!(dst1[..8], [m, 1.0, 1.0, m, m, m, 1.0, 1.0]);
+        assert_eq!(dst1[8..], dst1[..8]);
+        Ok(())
+    }
+
     #[test]
     fn test_wgpu_matmul() -> Result<()> {
         let v1 = (0..256).map(|i| i as f32).collect::<Vec<_>>();
@@ -938,6 +1316,25 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_wgpu_matmul_acc() -> Result<()> {
+        let v1 = (0..256).map(|i| i as f32).collect::<Vec<_>>();
+        let v2 = (0..32).map(|i| i as f32).collect::<Vec<_>>();
+
+        let t1 = WgpuTensor::new(&v1, &[32, 8], DEVICE.clone())?;
+        let t2 = WgpuTensor::new(&[2.0; 8], &[8], DEVICE.clone())?;
+        let acc = WgpuTensor::new(&v2, &[32], DEVICE.clone())?;
+        let t3 = t1.matmul_vec_acc(&t2, acc)?;
+        let mut dst1 = vec![0.0; 32];
+        t3.export(&mut dst1)?;
+        assert_eq!(dst1[0..4], vec![56.0, 185.0, 314.0, 443.0]);
+        assert_eq!(dst1[31], 4024.0 + 31.0);
+
+        let acc = WgpuTensor::new(&v2[..16], &[16], DEVICE.clone())?;
+        assert!(t1.matmul_vec_acc(&t2, acc).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_wgpu_batch_matmul() -> Result<()> {
         let v1 = (0..6).map(|i| i as f32).collect::<Vec<_>>();
@@ -956,6 +1353,13 @@ mod tests {
         assert_eq!(t1.strider().strides(), &[6, 2, 1]);
         assert_eq!(dst1, vec![2.0, 10.0, 18.0]);
 
+        let acc = WgpuTensor::new(&[1.0, 2.0, 3.0], &[1, 3, 1], DEVICE.clone())?;
+        let t4 = t1.batch_matmul_acc(&t2, acc)?;
+        t4.export(&mut dst1)?;
+        assert_eq!(dst1, vec![3.0, 12.0, 21.0]);
+
+        let acc = WgpuTensor::new(&[1.0, 2.0, 3.0], &[3], DEVICE.clone())?;
+        assert!(t1.batch_matmul_acc(&t2, acc).is_err());
         Ok(())
     }
 
@@ -963,7 +1367,7 @@ mod tests {
     fn test_wgpu_rope() -> Result<()> {
         let v1 = (0..32).map(|i| i as f32).collect::<Vec<_>>();
         let t1 = WgpuTensor::new(&v1, &[2, 16], DEVICE.clone())?;
-        let t1 = t1.rope_inplace(RopeMode::Llama, 1, 2)?;
+        let t1 = t1.rope_inplace(RopeMode::Llama, 1, 2, 10000.0)?;
 
         let mut dst1 = vec![0.0; 32];
         t1.export(&mut dst1)?;
@@ -1085,6 +1489,170 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_wgpu_gelu() -> Result<()> {
+        let v1 = vec![-30.0, -2.0, -0.5, 0.0, 0.5, 1.0, 2.0, 30.0];
+        let t1 = WgpuTensor::new(&v1, &[8], DEVICE.clone())?;
+        let t1 = t1.gelu_inplace()?;
+        let mut dst1 = vec![0.0; 8];
+        t1.export(&mut dst1)?;
+
+        let t2 = CpuTensor::new(v1, &[8], CpuTensorDevice::new())?;
+        let t2 = t2.gelu_inplace()?;
+        let mut dst2 = vec![0.0; 8];
+        t2.export(&mut dst2)?;
+        assert_relative_eq!(&dst1[..], &dst2[..], epsilon = 1e-5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wgpu_softmax_negative() -> Result<()> {
+        let v1 = vec![-1.0, -2.0, -3.0, -1000.0, -1001.0, -1002.0];
+        let t1 = WgpuTensor::new(&v1, &[2, 3], DEVICE.clone())?;
+        let t1 = t1.softmax_inplace(1)?;
+        let mut dst1 = vec![0.0; 6];
+        t1.export(&mut dst1)?;
+
+        let expected = [0.66524094, 0.24472848, 0.09003057];
+        assert_relative_eq!(&dst1[..3], &expected[..], epsilon = 1e-5);
+        assert_relative_eq!(&dst1[3..], &expected[..], epsilon = 1e-5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wgpu_rope_neox() -> Result<()> {
+        // 2 rows, 2 heads of 8 dims, only the first 4 dims of each head are rotated
+        let v1 = (0..32).map(|i| i as f32 * 0.1).collect::<Vec<_>>();
+        let t1 = WgpuTensor::new(&v1, &[2, 2, 8], DEVICE.clone())?;
+        let t1 = t1.rope_inplace(RopeMode::Neox, 3, 4, 10000.0)?;
+        let mut dst1 = vec![0.0; 32];
+        t1.export(&mut dst1)?;
+
+        let t2 = CpuTensor::new(v1, &[2, 2, 8], CpuTensorDevice::new())?;
+        let t2 = t2.rope_inplace(RopeMode::Neox, 3, 4, 10000.0)?;
+        let mut dst2 = vec![0.0; 32];
+        t2.export(&mut dst2)?;
+        assert_relative_eq!(&dst1[..], &dst2[..], epsilon = 1e-5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wgpu_matmul_quantized() -> Result<()> {
+        let (m, k, b) = (40, 96, 2);
+        let w = (0..m * k)
+            .map(|i| ((i * 7 + 3) % 19) as f32 * 0.1 - 0.9)
+            .collect::<Vec<_>>();
+        let x = (0..b * k)
+            .map(|i| ((i * 5 + 1) % 23) as f32 * 0.05 - 0.5)
+            .collect::<Vec<_>>();
+
+        let bufs = [
+            CpuTensorBuf::Q8_0(QuantBufQ8_0::quantize(&w)),
+            CpuTensorBuf::Q4_0(QuantBufQ4_0::quantize(&w)),
+        ];
+        for buf in bufs {
+            let dequantized = match &buf {
+                CpuTensorBuf::Q8_0(buf) => buf.dequantize(0).collect::<Vec<_>>(),
+                CpuTensorBuf::Q4_0(buf) => buf.dequantize(0).collect::<Vec<_>>(),
+                _ => unreachable!(),
+            };
+            let expected = (0..b)
+                .flat_map(|bi| {
+                    let dequantized = &dequantized;
+                    let x = &x;
+                    (0..m).map(move |mi| {
+                        (0..k)
+                            .map(|ki| dequantized[mi * k + ki] * x[bi * k + ki])
+                            .sum::<f32>()
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let t1 = WgpuTensor::from_cpu_buf(&buf, &[m, k], DEVICE.clone())?;
+            assert_eq!(t1.dtype(), buf.dtype());
+            let t2 = WgpuTensor::new(&x, &[b, k], DEVICE.clone())?;
+            let t3 = t1.matmul_vec(&t2)?;
+            let mut dst = vec![0.0; b * m];
+            t3.export(&mut dst)?;
+            assert_relative_eq!(&dst[..], &expected[..], epsilon = 1e-4);
+
+            // accumulating into the output doubles it
+            let t4 = t1.matmul_vec_acc(&t2, t3)?;
+            t4.export(&mut dst)?;
+            let doubled = expected.iter().map(|v| v * 2.0).collect::<Vec<_>>();
+            assert_relative_eq!(&dst[..], &doubled[..], epsilon = 1e-4);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_wgpu_from_cpu_tensor() -> Result<()> {
+        // within the staging buffer of 4096 bytes, a row takes a block of q4_k
+        let (m, k) = (4, 256);
+        let w = (0..m * k)
+            .map(|i| ((i * 7 + 3) % 19) as f32 * 0.1 - 0.9)
+            .collect::<Vec<_>>();
+        let device_cpu = CpuTensorDevice::new();
+
+        for dtype in [GGMLType::F32, GGMLType::Q8_0, GGMLType::Q4_0, GGMLType::Q4K] {
+            let buf = CpuTensorBuf::from(w.clone()).quantize(dtype)?;
+            let dequantized = buf.clone().dequantize(GGMLType::F32)?.as_f32_ref().to_vec();
+
+            // kept quantized when it's contiguous
+            let t = CpuTensor::from_bytes(buf.as_bytes(), dtype, &[m, k], device_cpu.clone())?;
+            let t1 = WgpuTensor::from_cpu_tensor(&t, true, DEVICE.clone())?;
+            let expected = match dtype {
+                GGMLType::Q4K => GGMLType::F32,
+                _ => dtype,
+            };
+            assert_eq!(t1.dtype(), expected);
+            let t1 = WgpuTensor::from_cpu_tensor(&t, false, DEVICE.clone())?;
+            assert_eq!(t1.dtype(), GGMLType::F32);
+            let mut dst = vec![0.0; m * k];
+            t1.export(&mut dst)?;
+            assert_relative_eq!(&dst[..], &dequantized[..], epsilon = 1e-6);
+
+            // a transposed one is dequantized and packed in the order of its strides
+            let t = t.transpose(&[1, 0])?;
+            let t2 = WgpuTensor::from_cpu_tensor(&t, true, DEVICE.clone())?;
+            assert_eq!(t2.dtype(), GGMLType::F32);
+            assert_eq!(t2.shape(), &[k, m]);
+            assert_eq!(t2.buf().size() as usize % WEIGHT_BUF_ALIGN, 0);
+            t2.export(&mut dst)?;
+            let transposed = (0..k)
+                .flat_map(|ki| (0..m).map(move |mi| (mi, ki)))
+                .map(|(mi, ki)| dequantized[mi * k + ki])
+                .collect::<Vec<_>>();
+            assert_relative_eq!(&dst[..], &transposed[..], epsilon = 1e-6);
+        }
+
+        // the odd sizes are padded
+        let t = WgpuTensor::new(&[1.0, 2.0, 3.0], &[3], DEVICE.clone())?;
+        assert_eq!(t.buf().size() as usize, WEIGHT_BUF_ALIGN);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wgpu_buffer_pool() -> Result<()> {
+        let device = WgpuTensorDevice::new(WgpuTensorDeviceOptions::new());
+        let t1 = WgpuTensor::new(&[1.0; 24], &[4, 6], device.clone())?;
+        let t2 = t1.dup()?;
+        drop(t2);
+        assert_eq!(device.pooled_buffers(), 1);
+
+        // the buffer is reused, and cleared before the reuse
+        let t3 = WgpuTensor::alloc(&[6, 4], GGMLType::F32, device.clone())?;
+        assert_eq!(device.pooled_buffers(), 0);
+        let mut dst = vec![1.0; 24];
+        t3.export(&mut dst)?;
+        assert_eq!(dst, vec![0.0; 24]);
+
+        // the weights are not pooled
+        drop(t1);
+        assert_eq!(device.pooled_buffers(), 0);
+        Ok(())
+    }
+
     #[test]
     fn test_dup() -> Result<()> {
         let v1 = vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
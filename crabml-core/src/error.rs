@@ -1,3 +1,5 @@
+use crate::kernels::prelude::*;
+
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 pub enum ErrorKind {
     /// Unexpected error
@@ -23,17 +25,21 @@ pub enum ErrorKind {
 
     /// unimplemented yet
     NotImplemented,
+
+    /// raised when an allocation fails or exceeds the memory budget, the cause is an
+    /// `OutOfMemory` with the amount requested
+    OutOfMemory,
 }
 
 #[derive(Debug)]
 pub struct Error {
     pub kind: ErrorKind,
     pub message: String,
-    pub cause: Option<Box<dyn std::error::Error>>,
+    pub cause: Option<Box<dyn core::error::Error>>,
 }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(f, "{:?}: {}", self.kind, self.message)?;
         if let Some(cause) = self.cause.as_ref() {
             write!(f, "\ncaused by: {}", cause)?;
@@ -52,6 +58,48 @@ impl<S: Into<String>> From<(ErrorKind, S)> for Error {
     }
 }
 
-impl std::error::Error for Error {}
+impl core::error::Error for Error {}
+
+impl Error {
+    pub fn out_of_memory(
+        requested: usize,
+        budget: Option<usize>,
+        message: impl Into<String>,
+    ) -> Self {
+        Self {
+            kind: ErrorKind::OutOfMemory,
+            message: message.into(),
+            cause: Some(Box::new(OutOfMemory { requested, budget })),
+        }
+    }
+
+    /// the amount requested, if it's an OutOfMemory error.
+    pub fn as_out_of_memory(&self) -> Option<&OutOfMemory> {
+        self.cause.as_ref()?.downcast_ref()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct OutOfMemory {
+    /// in bytes
+    pub requested: usize,
+    /// the configured budget in bytes, None if the allocator failed
+    pub budget: Option<usize>,
+}
+
+impl core::fmt::Display for OutOfMemory {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.budget {
+            Some(budget) => write!(
+                f,
+                "requested {} bytes, exceeding the budget of {} bytes",
+                self.requested, budget
+            ),
+            None => write!(f, "failed to allocate {} bytes", self.requested),
+        }
+    }
+}
+
+impl core::error::Error for OutOfMemory {}
 
-pub type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = core::result::Result<T, Error>;
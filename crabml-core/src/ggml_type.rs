@@ -0,0 +1,102 @@
+use core::fmt::Display;
+
+use int_enum::IntEnum;
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntEnum)]
+pub enum GGMLType {
+    F32 = 0,
+    F16 = 1,
+    Q4_0 = 2,
+    Q4_1 = 3,
+    // GGML_TYPE_Q4_2 = 4, support has been removed
+    // GGML_TYPE_Q4_3 (5) support has been removed
+    Q5_0 = 6,
+    Q5_1 = 7,
+    Q8_0 = 8,
+    Q8_1 = 9,
+    // k-quantizations
+    Q2K = 10,
+    Q3K = 11,
+    Q4K = 12,
+    Q5K = 13,
+    Q6K = 14,
+    Q8K = 15,
+    I8 = 16,
+    I16 = 17,
+    I32 = 18,
+    COUNT = 19,
+    // ternary quantizations (BitNet b1.58), experimental
+    TQ2_0 = 35,
+}
+
+impl Display for GGMLType {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match *self {
+            GGMLType::F32 => write!(f, "F32"),
+            GGMLType::F16 => write!(f, "F16"),
+            GGMLType::Q4_0 => write!(f, "Q4_0"),
+            GGMLType::Q4_1 => write!(f, "Q4_1"),
+            GGMLType::Q5_0 => write!(f, "Q5_0"),
+            GGMLType::Q5_1 => write!(f, "Q5_1"),
+            GGMLType::Q8_0 => write!(f, "Q8_0"),
+            GGMLType::Q8_1 => write!(f, "Q8_1"),
+            GGMLType::Q2K => write!(f, "Q2_K"),
+            GGMLType::Q3K => write!(f, "Q3_K"),
+            GGMLType::Q4K => write!(f, "Q4_K"),
+            GGMLType::Q5K => write!(f, "Q5_K"),
+            GGMLType::Q6K => write!(f, "Q6_K"),
+            GGMLType::Q8K => write!(f, "Q8_K"),
+            GGMLType::I8 => write!(f, "I8"),
+            GGMLType::I16 => write!(f, "I16"),
+            GGMLType::I32 => write!(f, "I32"),
+            GGMLType::COUNT => write!(f, "COUNT"),
+            GGMLType::TQ2_0 => write!(f, "TQ2_0"),
+        }
+    }
+}
+
+impl GGMLType {
+    /// the number of elements in a block, 1 for the unquantized types.
+    pub fn block_size(&self) -> usize {
+        match self {
+            GGMLType::Q4_0
+            | GGMLType::Q4_1
+            | GGMLType::Q5_0
+            | GGMLType::Q5_1
+            | GGMLType::Q8_0
+            | GGMLType::Q8_1 => 32,
+            GGMLType::Q2K
+            | GGMLType::Q3K
+            | GGMLType::Q4K
+            | GGMLType::Q5K
+            | GGMLType::Q6K
+            | GGMLType::Q8K
+            | GGMLType::TQ2_0 => 256,
+            _ => 1,
+        }
+    }
+
+    /// the bytes of a block.
+    pub fn type_size(&self) -> usize {
+        match self {
+            GGMLType::F32 | GGMLType::I32 => 4,
+            GGMLType::F16 | GGMLType::I16 => 2,
+            GGMLType::I8 => 1,
+            GGMLType::Q4_0 => 18,
+            GGMLType::Q4_1 => 20,
+            GGMLType::Q5_0 => 22,
+            GGMLType::Q5_1 => 24,
+            GGMLType::Q8_0 => 34,
+            GGMLType::Q8_1 => 36,
+            GGMLType::Q2K => 84,
+            GGMLType::Q3K => 110,
+            GGMLType::Q4K => 144,
+            GGMLType::Q5K => 176,
+            GGMLType::Q6K => 210,
+            GGMLType::Q8K => 292,
+            GGMLType::TQ2_0 => 66,
+            GGMLType::COUNT => 0,
+        }
+    }
+}
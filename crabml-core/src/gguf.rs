@@ -1,14 +1,28 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fmt;
 use std::fmt::Display;
 use std::fs::File;
+use std::io::Read;
+use std::io::Write;
 use std::mem;
+use std::ops::Deref;
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::thread::JoinHandle;
 
 use int_enum::IntEnum;
 use memmap2::Mmap;
+use memmap2::MmapMut;
+#[cfg(not(target_os = "wasi"))]
+use rayon::prelude::*;
 
 use crate::error::Error;
 use crate::error::ErrorKind;
 use crate::error::Result;
+pub use crate::ggml_type::GGMLType;
 
 const GGUF_MAGIC: u32 = 0x46554747;
 const GGUF_DEFAULT_ALIGNMENT: u64 = 32;
@@ -79,57 +93,6 @@ impl Display for GGUFVersion {
     }
 }
 
-#[repr(u32)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, IntEnum)]
-pub enum GGMLType {
-    F32 = 0,
-    F16 = 1,
-    Q4_0 = 2,
-    Q4_1 = 3,
-    // GGML_TYPE_Q4_2 = 4, support has been removed
-    // GGML_TYPE_Q4_3 (5) support has been removed
-    Q5_0 = 6,
-    Q5_1 = 7,
-    Q8_0 = 8,
-    Q8_1 = 9,
-    // k-quantizations
-    Q2K = 10,
-    Q3K = 11,
-    Q4K = 12,
-    Q5K = 13,
-    Q6K = 14,
-    Q8K = 15,
-    I8 = 16,
-    I16 = 17,
-    I32 = 18,
-    COUNT = 19,
-}
-
-impl Display for GGMLType {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match *self {
-            GGMLType::F32 => write!(f, "F32"),
-            GGMLType::F16 => write!(f, "F16"),
-            GGMLType::Q4_0 => write!(f, "Q4_0"),
-            GGMLType::Q4_1 => write!(f, "Q4_1"),
-            GGMLType::Q5_0 => write!(f, "Q5_0"),
-            GGMLType::Q5_1 => write!(f, "Q5_1"),
-            GGMLType::Q8_0 => write!(f, "Q8_0"),
-            GGMLType::Q8_1 => write!(f, "Q8_1"),
-            GGMLType::Q2K => write!(f, "Q2_K"),
-            GGMLType::Q3K => write!(f, "Q3_K"),
-            GGMLType::Q4K => write!(f, "Q4_K"),
-            GGMLType::Q5K => write!(f, "Q5_K"),
-            GGMLType::Q6K => write!(f, "Q6_K"),
-            GGMLType::Q8K => write!(f, "Q8_K"),
-            GGMLType::I8 => write!(f, "I8"),
-            GGMLType::I16 => write!(f, "I16"),
-            GGMLType::I32 => write!(f, "I32"),
-            GGMLType::COUNT => write!(f, "COUNT"),
-        }
-    }
-}
-
 impl TryFrom<u32> for GGMLType {
     type Error = Error;
 
@@ -225,19 +188,21 @@ impl<'a> GGUFMetadataValue<'a> {
     }
 }
 
+// the arrays are borrowed from the file, unless they're not aligned for their type, the
+// metadata has no padding, so a f32 array may start anywhere after the strings.
 #[derive(Debug, Clone, PartialEq)]
 pub enum GGUFMetadataArray<'a> {
-    U8Array(&'a [u8]),
-    I8Array(&'a [i8]),
-    U16Array(&'a [u16]),
-    I16Array(&'a [i16]),
-    U32Array(&'a [u32]),
-    I32Array(&'a [i32]),
-    U64Array(&'a [u64]),
-    I64Array(&'a [i64]),
-    F32Array(&'a [f32]),
-    F64Array(&'a [f64]),
-    BoolArray(&'a [u8]),
+    U8Array(Cow<'a, [u8]>),
+    I8Array(Cow<'a, [i8]>),
+    U16Array(Cow<'a, [u16]>),
+    I16Array(Cow<'a, [i16]>),
+    U32Array(Cow<'a, [u32]>),
+    I32Array(Cow<'a, [i32]>),
+    U64Array(Cow<'a, [u64]>),
+    I64Array(Cow<'a, [i64]>),
+    F32Array(Cow<'a, [f32]>),
+    F64Array(Cow<'a, [f64]>),
+    BoolArray(Cow<'a, [u8]>),
     StringArray(Vec<&'a str>),
     NestedArray(Vec<GGUFMetadataArray<'a>>),
 }
@@ -291,15 +256,19 @@ where 'a: 'b
 
 macro_rules! define_gguf_metadata_value_read_fn {
     ($read_array_func:ident, $read_item_func:ident, $typ:ty) => {
-        fn $read_array_func(&mut self, n: usize) -> Result<&'a [$typ]> {
+        fn $read_array_func(&mut self, n: usize) -> Result<Cow<'a, [$typ]>> {
             let typ_size = mem::size_of::<$typ>();
             let data = self.buf.read(n * typ_size)?;
-            let transmuted_data = unsafe {
-                assert!(data.len() % typ_size == 0);
-                let ptr = data.as_ptr();
-                mem::transmute(std::slice::from_raw_parts(ptr, data.len() / typ_size))
-            };
-            Ok(transmuted_data)
+            assert!(data.len() % typ_size == 0);
+            let ptr = data.as_ptr() as *const $typ;
+            if ptr as usize % mem::align_of::<$typ>() == 0 {
+                let arr: &'a [$typ] = unsafe { std::slice::from_raw_parts(ptr, n) };
+                return Ok(Cow::Borrowed(arr));
+            }
+            let arr = (0..n)
+                .map(|i| unsafe { ptr.add(i).read_unaligned() })
+                .collect::<Vec<_>>();
+            Ok(Cow::Owned(arr))
         }
 
         fn $read_item_func(&mut self) -> Result<$typ> {
@@ -443,7 +412,7 @@ macro_rules! define_gguf_metadata_get_primitive_fn {
                 _ => return None,
             };
             match arr {
-                GGUFMetadataArray::$array_enum_kind(arr) => Some(arr),
+                GGUFMetadataArray::$array_enum_kind(arr) => Some(arr.as_ref()),
                 _ => None,
             }
         }
@@ -696,6 +665,9 @@ pub struct GGUFFile<'a> {
     // Tensor infos, which can be used to locate the tensor data.
     tensor_infos: Vec<GGUFTensorInfo<'a>>,
 
+    // The raw bytes of the header and the tensor infos, used to fingerprint the file.
+    header_bytes: &'a [u8],
+
     // Tensor data.
     //
     // This is arbitrary binary data corresponding to the weights of the model. This data should be close
@@ -707,10 +679,21 @@ pub struct GGUFFile<'a> {
     // The offset of each tensor's data must be a multiple of `ALIGNMENT`, and the space between tensors
     // should be padded to `ALIGNMENT` bytes.
     _tensor_data: &'a [u8],
+
+    // the file map when the tensors are paged in from the file on their access, None when
+    // the file is read into a buffer or locked in the memory.
+    file_map: Option<Arc<Mmap>>,
+
+    // whether the tensors are views of a read-only map of the file
+    shared: bool,
+
+    // the fingerprint cached on the loader, shared by all the opens of the file.
+    fingerprint: Option<&'a OnceLock<u64>>,
 }
 
 impl<'a> GGUFFile<'a> {
     fn decode(buf: &mut GGUFBufReader<'a>) -> Result<Self> {
+        let file_bytes = buf.cursor();
         let header = GGUFHeader::decode(buf)?;
 
         // load on disk tensor infos
@@ -722,8 +705,9 @@ impl<'a> GGUFFile<'a> {
 
         // find the tensor_data position
         let position = buf.read_bytes();
+        let header_bytes = &file_bytes[..position];
         let alignment = header.alignment() as usize;
-        let next_position = position - (position % alignment) + alignment;
+        let next_position = position.div_ceil(alignment) * alignment;
         let _ = buf.read(next_position - position)?;
         let tensor_data = buf.cursor();
 
@@ -733,7 +717,11 @@ impl<'a> GGUFFile<'a> {
         Ok(Self {
             header,
             tensor_infos,
+            header_bytes,
             _tensor_data: tensor_data,
+            file_map: None,
+            shared: false,
+            fingerprint: None,
         })
     }
 
@@ -742,13 +730,23 @@ impl<'a> GGUFFile<'a> {
         tensor_data: &'a [u8],
     ) -> Result<Vec<GGUFTensorInfo<'a>>> {
         let mut result = Vec::with_capacity(tensor_infos.len());
-        for (i, tensor_info) in tensor_infos.iter().enumerate() {
-            let next_offset = if i + 1 >= tensor_infos.len() {
-                tensor_data.len()
-            } else {
-                tensor_infos[i + 1].offset as usize
-            };
-            let data = &tensor_data[tensor_info.offset as usize..next_offset];
+        for tensor_info in tensor_infos.iter() {
+            // the padding between the tensors is not included
+            let start = tensor_info.offset as usize;
+            let end = start + tensor_info.data_bytes();
+            if end > tensor_data.len() {
+                return Err(Error {
+                    kind: ErrorKind::FormatError,
+                    message: format!(
+                        "the data of tensor {} is out of the file, {} > {}",
+                        tensor_info.name,
+                        end,
+                        tensor_data.len()
+                    ),
+                    cause: None,
+                });
+            }
+            let data = &tensor_data[start..end];
 
             let item = GGUFTensorInfo::new(
                 tensor_info.name.clone(),
@@ -765,6 +763,21 @@ impl<'a> GGUFFile<'a> {
         self.header.architecture()
     }
 
+    /// whether the tensors are the views of a read-only map of the file, whose pages live in
+    /// the page cache once and are shared by all the processes mapping the same file, like
+    /// the workers serving the same model. false if the file is read into a buffer.
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+
+    /// whether the bytes are in the shared map of the file, like the data of a tensor loaded
+    /// in place, but not a copy of it.
+    pub fn is_shared_data(&self, data: &[u8]) -> bool {
+        let range = self._tensor_data.as_ptr_range();
+        let (start, end) = (data.as_ptr(), data.as_ptr().wrapping_add(data.len()));
+        self.shared && range.start <= start && end <= range.end
+    }
+
     pub fn quantization_version(&self) -> Option<u32> {
         self.header.quantization_version()
     }
@@ -781,51 +794,778 @@ impl<'a> GGUFFile<'a> {
         &self.tensor_infos
     }
 
+    /// the fingerprint to tell whether two files contain the same model, an FNV-1a hash of
+    /// the metadata, the tensor infos and all the tensor data. hashing reads every weight of
+    /// the file once, the tensors are hashed in parallel and the result is cached on the
+    /// loader, so the later opens of the same file get it for free.
+    pub fn fingerprint(&self) -> u64 {
+        match self.fingerprint {
+            Some(cached) => *cached.get_or_init(|| self.hash()),
+            None => self.hash(),
+        }
+    }
+
+    fn hash(&self) -> u64 {
+        // FNV-1a, which is stable across the platforms and the rust versions
+        fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+            for b in bytes {
+                hash ^= *b as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+            hash
+        }
+        const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+
+        #[cfg(not(target_os = "wasi"))]
+        let tensor_hashes = self
+            .tensor_infos
+            .par_iter()
+            .map(|tensor_info| fnv1a(OFFSET_BASIS, tensor_info.data()))
+            .collect::<Vec<_>>();
+        #[cfg(target_os = "wasi")]
+        let tensor_hashes = self
+            .tensor_infos
+            .iter()
+            .map(|tensor_info| fnv1a(OFFSET_BASIS, tensor_info.data()))
+            .collect::<Vec<_>>();
+
+        let mut hash = fnv1a(OFFSET_BASIS, self.header_bytes);
+        for (tensor_info, tensor_hash) in self.tensor_infos.iter().zip(tensor_hashes) {
+            hash = fnv1a(hash, &(tensor_info.data().len() as u64).to_le_bytes());
+            hash = fnv1a(hash, &tensor_hash.to_le_bytes());
+        }
+        hash
+    }
+
     pub fn get_tensor_info(&self, name: &str) -> Option<GGUFTensorInfo> {
         self.tensor_infos
             .iter()
             .find(|ti| ti.name() == name)
             .cloned()
     }
+
+    /// keep only a window of the first n_layers layers resident, see `GGUFLayerStreamer`.
+    /// the tensors of a layer are the ones named like `blk.{layer}.*`. it requires the file
+    /// to be mapped without mlock.
+    pub fn layer_streamer(&self, n_layers: usize, window: usize) -> Result<GGUFLayerStreamer> {
+        let mmap = match &self.file_map {
+            Some(mmap) => mmap.clone(),
+            None => {
+                return Err((
+                    ErrorKind::BadInput,
+                    "streaming the layers requires the file to be mapped without mlock",
+                )
+                    .into());
+            }
+        };
+        if window == 0 {
+            return Err((
+                ErrorKind::BadInput,
+                "the window of the layers should be at least 1",
+            )
+                .into());
+        }
+
+        let base = mmap.as_ptr() as usize;
+        let mut layers = vec![vec![]; n_layers];
+        for info in self.tensor_infos.iter() {
+            let layer = info
+                .name()
+                .strip_prefix("blk.")
+                .and_then(|s| s.split_once('.'))
+                .and_then(|(l, _)| l.parse::<usize>().ok());
+            if let Some(layer) = layer.filter(|l| *l < n_layers) {
+                let start = info.data().as_ptr() as usize - base;
+                layers[layer].push(start..start + info.data().len());
+            }
+        }
+        Ok(GGUFLayerStreamer {
+            mmap,
+            layers,
+            window: window.min(n_layers),
+            resident: Mutex::new(vec![true; n_layers]),
+        })
+    }
+
+    /// page in the tensors of the first `n_layers` layers from the file in a background
+    /// thread, then the tensors out of the layers but the token embedding, whose rows are
+    /// read one by one on the lookup. it overlaps the disk reads with the work before the
+    /// first forward pass like the tokenization. nothing is read when the file is not
+    /// mapped, as the whole file is in the memory already.
+    pub fn warmup(&self, n_layers: usize) -> GGUFWarmup {
+        let mmap = match &self.file_map {
+            Some(mmap) => mmap.clone(),
+            None => return GGUFWarmup { handle: None },
+        };
+
+        let base = mmap.as_ptr() as usize;
+        let mut layers = vec![vec![]; n_layers];
+        let mut others = vec![];
+        for info in self.tensor_infos.iter() {
+            let start = info.data().as_ptr() as usize - base;
+            let range = start..start + info.data().len();
+            let layer = info
+                .name()
+                .strip_prefix("blk.")
+                .and_then(|s| s.split_once('.'))
+                .and_then(|(l, _)| l.parse::<usize>().ok());
+            match layer {
+                Some(l) if l < n_layers => layers[l].push(range),
+                Some(_) => {}
+                None if info.name() == "token_embd.weight" => {}
+                None => others.push(range),
+            }
+        }
+        // in the order of the forward pass, the first layer is ready first
+        let ranges = layers
+            .into_iter()
+            .flatten()
+            .chain(others)
+            .collect::<Vec<_>>();
+        let handle = std::thread::spawn(move || {
+            let mut touched = 0;
+            for range in ranges {
+                #[cfg(unix)]
+                let _ = mmap.advise_range(memmap2::Advice::WillNeed, range.start, range.len());
+                let mut sum = 0u8;
+                for offset in (range.start..range.end).step_by(PAGE_BYTES) {
+                    // SAFETY: the offset is in the bounds of the map
+                    sum = sum.wrapping_add(unsafe { std::ptr::read_volatile(&mmap[offset]) });
+                }
+                std::hint::black_box(sum);
+                touched += range.len();
+            }
+            touched
+        });
+        GGUFWarmup {
+            handle: Some(handle),
+        }
+    }
+}
+
+/// the background thread of `GGUFFile::warmup()`, dropping it leaves the thread running to
+/// the end, it holds the map of the file.
+pub struct GGUFWarmup {
+    handle: Option<JoinHandle<usize>>,
+}
+
+impl GGUFWarmup {
+    pub fn is_finished(&self) -> bool {
+        self.handle.as_ref().map_or(true, |h| h.is_finished())
+    }
+
+    /// wait for the warmup to finish, returns the bytes paged in.
+    pub fn wait(self) -> usize {
+        self.handle
+            .map(|h| h.join().unwrap_or_default())
+            .unwrap_or(0)
+    }
+}
+
+/// keeps only a sliding window of the layers' weights resident in the memory, so a model
+/// larger than the ram runs slowly instead of swapping everything else out. on entering a
+/// layer, the pages of the next `window - 1` layers are prefetched from the file in the
+/// background while the layer computes, and the layers out of the window are dropped from
+/// the memory, to be read from the file again on the next token. the window wraps around,
+/// the last layer prefetches the first ones for the next token.
+///
+/// the advices are best effort, the weights are always read correctly whatever the kernel
+/// keeps.
+pub struct GGUFLayerStreamer {
+    mmap: Arc<Mmap>,
+    // the ranges in the file of the tensors of every layer
+    layers: Vec<Vec<Range<usize>>>,
+    window: usize,
+    resident: Mutex<Vec<bool>>,
+}
+
+impl GGUFLayerStreamer {
+    pub fn enter_layer(&self, layer: usize) {
+        let n_layers = self.layers.len();
+        let mut resident = self.resident.lock().unwrap();
+        for (l, ranges) in self.layers.iter().enumerate() {
+            let in_window = (l + n_layers - layer) % n_layers < self.window;
+            if in_window == resident[l] {
+                continue;
+            }
+            #[cfg(unix)]
+            {
+                let advice = if in_window {
+                    memmap2::Advice::WillNeed
+                } else {
+                    memmap2::Advice::DontNeed
+                };
+                for range in ranges.iter() {
+                    let _ = self.mmap.advise_range(advice, range.start, range.len());
+                }
+            }
+            resident[l] = in_window;
+        }
+    }
+
+    /// the layers in the window after the last `enter_layer()`, all of them before it.
+    pub fn resident_layers(&self) -> Vec<usize> {
+        let resident = self.resident.lock().unwrap();
+        (0..resident.len()).filter(|l| resident[*l]).collect()
+    }
+
+    /// the bytes of the tensors of a layer.
+    pub fn layer_bytes(&self, layer: usize) -> usize {
+        self.layers[layer].iter().map(|r| r.len()).sum()
+    }
+}
+
+/// how `GGUFFileLoader` brings the file into the memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GGUFLoadMode {
+    /// map the file, the pages of a tensor are read from the disk on its first access, so
+    /// the loading takes no time and the untouched tensors never take the memory. the pages
+    /// are shared with the other processes mapping the same file.
+    #[cfg_attr(not(target_os = "wasi"), default)]
+    Mmap,
+    /// read the whole file into an anonymous buffer up front, which does not hold the file
+    /// open and does not page fault on the inference. every process has its own copy. on
+    /// wasi there is no mmap, the file is read from a preopened directory into the heap.
+    #[cfg_attr(target_os = "wasi", default)]
+    Buffered,
+}
+
+/// called with the bytes loaded and the total bytes of the file.
+pub type GGUFLoadProgress = Arc<dyn Fn(usize, usize) + Send + Sync>;
+
+/// the options of `GGUFFileLoader::new_with_options()`.
+#[derive(Clone, Default)]
+pub struct GGUFLoadOptions {
+    mode: GGUFLoadMode,
+    mlock: bool,
+    progress: Option<GGUFLoadProgress>,
+}
+
+impl fmt::Debug for GGUFLoadOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GGUFLoadOptions")
+            .field("mode", &self.mode)
+            .field("mlock", &self.mlock)
+            .field("progress", &self.progress.is_some())
+            .finish()
+    }
+}
+
+impl GGUFLoadOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_mode(mut self, mode: GGUFLoadMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// pin the file in the memory, so the weights are never swapped out. all the pages are
+    /// read on loading, in the mmap mode too. it fails when the file is larger than the
+    /// memlock limit of the process (`ulimit -l`).
+    pub fn with_mlock(mut self, mlock: bool) -> Self {
+        self.mlock = mlock;
+        self
+    }
+
+    /// report the bytes read on loading. in the mmap mode without mlock nothing is read up
+    /// front, and the progress completes at once.
+    pub fn with_progress(
+        mut self,
+        progress: impl Fn(usize, usize) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    fn report(&self, loaded: usize, total: usize) {
+        if let Some(progress) = &self.progress {
+            progress(loaded, total);
+        }
+    }
+}
+
+// the bytes read at a time on loading, between the progress reports
+const LOAD_CHUNK_BYTES: usize = 16 * 1024 * 1024;
+
+const PAGE_BYTES: usize = 4096;
+
+#[derive(Clone, Copy)]
+#[repr(C, align(4096))]
+struct HeapPage([u8; PAGE_BYTES]);
+
+/// a page aligned buffer on the heap like the anonymous map, for the targets without mmap.
+struct HeapBuf {
+    pages: Vec<HeapPage>,
+    len: usize,
+}
+
+impl HeapBuf {
+    fn new(len: usize) -> Self {
+        Self {
+            pages: vec![HeapPage([0; PAGE_BYTES]); len.div_ceil(PAGE_BYTES)],
+            len,
+        }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // the pages are contiguous bytes without padding
+        unsafe { std::slice::from_raw_parts_mut(self.pages.as_mut_ptr() as *mut u8, self.len) }
+    }
+}
+
+impl Deref for HeapBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.pages.as_ptr() as *const u8, self.len) }
+    }
+}
+
+// the bytes of the file in the memory
+enum GGUFFileBuf {
+    Mapped(Arc<Mmap>),
+    Heap(HeapBuf),
+}
+
+impl Deref for GGUFFileBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            GGUFFileBuf::Mapped(mmap) => mmap,
+            GGUFFileBuf::Heap(buf) => buf,
+        }
+    }
 }
 
 pub struct GGUFFileLoader {
-    mmap: memmap2::Mmap,
+    buf: GGUFFileBuf,
+    // whether the tensors are paged in from the file, to be dropped and read again
+    file_mapped: bool,
+    // whether the file is mapped, with or without mlock
+    shared: bool,
+    // the fingerprint of the file, hashed once by the first open which asks for it
+    fingerprint: OnceLock<u64>,
 }
 
 impl GGUFFileLoader {
     pub fn new(path: &str) -> Result<Self> {
-        let file = File::open(path).map_err(|err| Error {
-            kind: ErrorKind::IOError,
-            message: format!("failed to open the file: {}", path),
-            cause: Some(Box::new(err)),
-        })?;
+        Self::new_with_options(path, &GGUFLoadOptions::default())
+    }
 
-        let mmap = unsafe {
-            Mmap::map(&file).map_err(|err| Error {
+    pub fn new_with_options(path: &str, options: &GGUFLoadOptions) -> Result<Self> {
+        let io_error = |message: String| {
+            move |err: std::io::Error| Error {
                 kind: ErrorKind::IOError,
-                message: format!("failed to mmap file: {}", path),
+                message,
                 cause: Some(Box::new(err)),
-            })?
+            }
         };
-        mmap.advise(memmap2::Advice::WillNeed)
-            .map_err(|err| Error {
-                kind: ErrorKind::IOError,
-                message: format!("failed to advise the mmap: {}", path),
-                cause: Some(Box::new(err)),
-            })?;
-        Ok(Self { mmap })
+        let mut file =
+            File::open(path).map_err(io_error(format!("failed to open the file: {}", path)))?;
+
+        let buf = match options.mode {
+            GGUFLoadMode::Mmap => {
+                let mmap = unsafe { Mmap::map(&file) }
+                    .map_err(io_error(format!("failed to mmap file: {}", path)))?;
+                if options.mlock {
+                    // touch the pages chunk by chunk to report the progress, locking the
+                    // pages already read is quick
+                    let mut sum = 0u8;
+                    for start in (0..mmap.len()).step_by(LOAD_CHUNK_BYTES) {
+                        let end = (start + LOAD_CHUNK_BYTES).min(mmap.len());
+                        #[cfg(unix)]
+                        mmap.advise_range(memmap2::Advice::WillNeed, start, end - start)
+                            .map_err(io_error(format!("failed to advise the mmap: {}", path)))?;
+                        for i in (start..end).step_by(4096) {
+                            sum = sum.wrapping_add(mmap[i]);
+                        }
+                        options.report(end, mmap.len());
+                    }
+                    std::hint::black_box(sum);
+                } else {
+                    options.report(mmap.len(), mmap.len());
+                }
+                GGUFFileBuf::Mapped(Arc::new(mmap))
+            }
+            GGUFLoadMode::Buffered => {
+                let len = file
+                    .metadata()
+                    .map_err(io_error(format!("failed to stat the file: {}", path)))?
+                    .len() as usize;
+                if len == 0 {
+                    return Err((ErrorKind::FormatError, format!("empty file: {}", path)).into());
+                }
+                let mut read_chunks = |buf: &mut [u8]| -> Result<()> {
+                    for start in (0..len).step_by(LOAD_CHUNK_BYTES) {
+                        let end = (start + LOAD_CHUNK_BYTES).min(len);
+                        file.read_exact(&mut buf[start..end])
+                            .map_err(io_error(format!("failed to read the file: {}", path)))?;
+                        options.report(end, len);
+                    }
+                    Ok(())
+                };
+                if cfg!(target_os = "wasi") {
+                    let mut buf = HeapBuf::new(len);
+                    read_chunks(buf.as_mut_slice())?;
+                    GGUFFileBuf::Heap(buf)
+                } else {
+                    // an anonymous map is page aligned like the file map, the tensors can be
+                    // viewed as the f32 or the blocks in place
+                    let mut buf = MmapMut::map_anon(len)
+                        .map_err(io_error(format!("failed to allocate {} bytes", len)))?;
+                    read_chunks(&mut buf)?;
+                    let mmap = buf
+                        .make_read_only()
+                        .map_err(io_error(format!("failed to protect the buffer: {}", path)))?;
+                    GGUFFileBuf::Mapped(Arc::new(mmap))
+                }
+            }
+        };
+        if options.mlock {
+            Self::lock(&buf, path)?;
+        }
+        Ok(Self {
+            buf,
+            file_mapped: options.mode == GGUFLoadMode::Mmap && !options.mlock,
+            shared: options.mode == GGUFLoadMode::Mmap,
+            fingerprint: OnceLock::new(),
+        })
+    }
+
+    #[cfg(unix)]
+    fn lock(buf: &GGUFFileBuf, path: &str) -> Result<()> {
+        let GGUFFileBuf::Mapped(mmap) = buf else {
+            return Err((ErrorKind::NotImplemented, "mlock on a heap buffer").into());
+        };
+        mmap.lock().map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!(
+                "failed to mlock {} bytes of {}, try raising the memlock limit with ulimit -l",
+                mmap.len(),
+                path
+            ),
+            cause: Some(Box::new(err)),
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn lock(_buf: &GGUFFileBuf, _path: &str) -> Result<()> {
+        Err((
+            ErrorKind::NotImplemented,
+            "mlock is not supported on this target",
+        )
+            .into())
     }
 
     pub fn open(&self) -> Result<GGUFFile<'_>> {
-        let buf = &mut GGUFBufReader::new(&self.mmap[..]);
-        GGUFFile::decode(buf)
+        let buf = &mut GGUFBufReader::new(&self.buf[..]);
+        let mut gf = GGUFFile::decode(buf)?;
+        if let GGUFFileBuf::Mapped(mmap) = &self.buf {
+            if self.file_mapped {
+                gf.file_map = Some(mmap.clone());
+            }
+        }
+        gf.shared = self.shared;
+        gf.fingerprint = Some(&self.fingerprint);
+        Ok(gf)
+    }
+
+    /// the bytes of the file.
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+}
+
+/// serializes the metadata and the tensors into a GGUF v3 file. the tensor infos are added
+/// first, so the header with the offsets is written before the data, and the tensors can be
+/// streamed one by one in the same order without holding them all in memory:
+///
+/// ```ignore
+/// let mut w = GGUFWriter::new(file, "llama");
+/// w.add_metadata("llama.block_count", GGUFMetadataValue::U32(5));
+/// w.add_tensor_info("token_embd.weight", &[64, 512], GGMLType::Q8_0)?;
+/// w.write_header()?;
+/// w.write_tensor_data(&data)?;
+/// w.finish()?;
+/// ```
+pub struct GGUFWriter<'a, W: Write> {
+    w: W,
+    metadata: Vec<(String, GGUFMetadataValue<'a>)>,
+    tensor_infos: Vec<GGUFOnDiskTensorInfo>,
+    alignment: usize,
+    // the bytes written to w
+    position: usize,
+    // the number of tensors whose data is written, None before the header is written
+    n_tensors_written: Option<usize>,
+}
+
+impl<'a, W: Write> GGUFWriter<'a, W> {
+    pub fn new(w: W, architecture: &'a str) -> Self {
+        Self {
+            w,
+            metadata: vec![(
+                KEY_GENERAL_ARCHITECTURE.to_string(),
+                GGUFMetadataValue::String(architecture),
+            )],
+            tensor_infos: vec![],
+            alignment: GGUF_DEFAULT_ALIGNMENT as usize,
+            position: 0,
+            n_tensors_written: None,
+        }
+    }
+
+    /// set a metadata value, the existing value of the key is replaced.
+    pub fn add_metadata(&mut self, key: &str, value: GGUFMetadataValue<'a>) {
+        if key == KEY_GENERAL_ALIGNMENT {
+            if let Some(alignment) = value.as_alignment() {
+                self.alignment = alignment;
+            }
+        }
+        match self.metadata.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value,
+            None => self.metadata.push((key.to_string(), value)),
+        }
+    }
+
+    pub fn remove_metadata(&mut self, key: &str) {
+        self.metadata.retain(|(k, _)| k != key);
+    }
+
+    /// the dimensions are in the ggml order, the first one is the contiguous row.
+    pub fn add_tensor_info(
+        &mut self,
+        name: &str,
+        dimensions: &[usize],
+        typ: GGMLType,
+    ) -> Result<()> {
+        if self.n_tensors_written.is_some() {
+            return Err((
+                ErrorKind::Unexpected,
+                "can not add a tensor after the header is written",
+            )
+                .into());
+        }
+        if dimensions.first().unwrap_or(&0) % typ.block_size() != 0 {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "the row of tensor {} in {:?} is not a multiple of the block size {}",
+                    name,
+                    dimensions,
+                    typ.block_size()
+                ),
+            )
+                .into());
+        }
+        let offset = match self.tensor_infos.last() {
+            None => 0,
+            Some(last) => {
+                let end = last.offset as usize + last.data_bytes();
+                end.div_ceil(self.alignment) * self.alignment
+            }
+        };
+        self.tensor_infos.push(GGUFOnDiskTensorInfo {
+            name: name.to_string(),
+            dimensions: dimensions.to_vec(),
+            typ,
+            offset: offset as u64,
+        });
+        Ok(())
+    }
+
+    /// write the metadata and the tensor infos, the data of the tensors follows.
+    pub fn write_header(&mut self) -> Result<()> {
+        if self.n_tensors_written.is_some() {
+            return Err((ErrorKind::Unexpected, "the header is already written").into());
+        }
+        let mut buf = vec![];
+        buf.extend_from_slice(&GGUF_MAGIC.to_le_bytes());
+        buf.extend_from_slice(&(GGUFVersion::V3 as u32).to_le_bytes());
+        buf.extend_from_slice(&(self.tensor_infos.len() as u64).to_le_bytes());
+        buf.extend_from_slice(&(self.metadata.len() as u64).to_le_bytes());
+        for (key, value) in self.metadata.iter() {
+            encode_string(&mut buf, key);
+            buf.extend_from_slice(&(value.typ() as u32).to_le_bytes());
+            encode_value(&mut buf, value);
+        }
+        for info in self.tensor_infos.iter() {
+            encode_string(&mut buf, &info.name);
+            buf.extend_from_slice(&(info.dimensions.len() as u32).to_le_bytes());
+            for dim in info.dimensions.iter() {
+                buf.extend_from_slice(&(*dim as u64).to_le_bytes());
+            }
+            buf.extend_from_slice(&(info.typ as u32).to_le_bytes());
+            buf.extend_from_slice(&info.offset.to_le_bytes());
+        }
+        self.write(&buf)?;
+        self.pad()?;
+        self.n_tensors_written = Some(0);
+        Ok(())
+    }
+
+    /// write the data of the next tensor, in the order of `add_tensor_info()`.
+    pub fn write_tensor_data(&mut self, data: &[u8]) -> Result<()> {
+        let n = match self.n_tensors_written {
+            None => {
+                return Err((ErrorKind::Unexpected, "the header is not written yet").into());
+            }
+            Some(n) => n,
+        };
+        let info = match self.tensor_infos.get(n) {
+            None => {
+                return Err((
+                    ErrorKind::Unexpected,
+                    format!("only {} tensors are added", self.tensor_infos.len()),
+                )
+                    .into());
+            }
+            Some(info) => info,
+        };
+        if data.len() != info.data_bytes() {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "expected {} bytes of tensor {}, got {}",
+                    info.data_bytes(),
+                    info.name,
+                    data.len()
+                ),
+            )
+                .into());
+        }
+        self.write(data)?;
+        self.pad()?;
+        self.n_tensors_written = Some(n + 1);
+        Ok(())
+    }
+
+    /// check all the tensors are written and flush, return the inner writer.
+    pub fn finish(mut self) -> Result<W> {
+        if self.n_tensors_written != Some(self.tensor_infos.len()) {
+            return Err((
+                ErrorKind::Unexpected,
+                format!(
+                    "{} of {} tensors are written",
+                    self.n_tensors_written.unwrap_or(0),
+                    self.tensor_infos.len()
+                ),
+            )
+                .into());
+        }
+        self.w.flush().map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: "failed to flush the gguf file".to_string(),
+            cause: Some(Box::new(err)),
+        })?;
+        Ok(self.w)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.w.write_all(buf).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: "failed to write the gguf file".to_string(),
+            cause: Some(Box::new(err)),
+        })?;
+        self.position += buf.len();
+        Ok(())
+    }
+
+    fn pad(&mut self) -> Result<()> {
+        let padding = self.position.div_ceil(self.alignment) * self.alignment - self.position;
+        self.write(&vec![0; padding])
+    }
+}
+
+impl GGUFOnDiskTensorInfo {
+    fn data_bytes(&self) -> usize {
+        let n_elems = self.dimensions.iter().product::<usize>();
+        n_elems / self.typ.block_size() * self.typ.type_size()
+    }
+}
+
+impl<'a> GGUFMetadataValue<'a> {
+    fn as_alignment(&self) -> Option<usize> {
+        match self {
+            GGUFMetadataValue::U32(v) if *v > 0 => Some(*v as usize),
+            GGUFMetadataValue::U64(v) if *v > 0 => Some(*v as usize),
+            _ => None,
+        }
+    }
+}
+
+fn encode_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u64).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn encode_value(buf: &mut Vec<u8>, value: &GGUFMetadataValue) {
+    match value {
+        GGUFMetadataValue::U8(v) => buf.push(*v),
+        GGUFMetadataValue::I8(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        GGUFMetadataValue::U16(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        GGUFMetadataValue::I16(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        GGUFMetadataValue::U32(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        GGUFMetadataValue::I32(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        GGUFMetadataValue::U64(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        GGUFMetadataValue::I64(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        GGUFMetadataValue::F32(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        GGUFMetadataValue::F64(v) => buf.extend_from_slice(&v.to_le_bytes()),
+        GGUFMetadataValue::Bool(v) => buf.push(*v),
+        GGUFMetadataValue::String(v) => encode_string(buf, v),
+        GGUFMetadataValue::Array(arr) => encode_array(buf, arr),
+    }
+}
+
+fn encode_array(buf: &mut Vec<u8>, arr: &GGUFMetadataArray) {
+    macro_rules! encode_items {
+        ($typ:ident, $items:expr) => {{
+            buf.extend_from_slice(&(GGUFMetadataValueType::$typ as u32).to_le_bytes());
+            buf.extend_from_slice(&($items.len() as u64).to_le_bytes());
+            for v in $items.iter() {
+                buf.extend_from_slice(&v.to_le_bytes());
+            }
+        }};
+    }
+    match arr {
+        GGUFMetadataArray::U8Array(items) => encode_items!(U8, items),
+        GGUFMetadataArray::I8Array(items) => encode_items!(I8, items),
+        GGUFMetadataArray::U16Array(items) => encode_items!(U16, items),
+        GGUFMetadataArray::I16Array(items) => encode_items!(I16, items),
+        GGUFMetadataArray::U32Array(items) => encode_items!(U32, items),
+        GGUFMetadataArray::I32Array(items) => encode_items!(I32, items),
+        GGUFMetadataArray::U64Array(items) => encode_items!(U64, items),
+        GGUFMetadataArray::I64Array(items) => encode_items!(I64, items),
+        GGUFMetadataArray::F32Array(items) => encode_items!(F32, items),
+        GGUFMetadataArray::F64Array(items) => encode_items!(F64, items),
+        GGUFMetadataArray::BoolArray(items) => encode_items!(Bool, items),
+        GGUFMetadataArray::StringArray(items) => {
+            buf.extend_from_slice(&(GGUFMetadataValueType::String as u32).to_le_bytes());
+            buf.extend_from_slice(&(items.len() as u64).to_le_bytes());
+            for v in items.iter() {
+                encode_string(buf, v);
+            }
+        }
+        GGUFMetadataArray::NestedArray(items) => {
+            buf.extend_from_slice(&(GGUFMetadataValueType::Array as u32).to_le_bytes());
+            buf.extend_from_slice(&(items.len() as u64).to_le_bytes());
+            for v in items.iter() {
+                encode_array(buf, v);
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::TempPath;
 
     #[test]
     fn test_load_tensors() -> Result<()> {
@@ -835,6 +1575,11 @@ mod tests {
         assert_eq!(gf.tensor_infos.len(), 48);
         assert_eq!(gf.tensor_infos[0].name(), "token_embd.weight");
         assert_eq!(gf.tensor_infos[0].data().len(), 131072);
+
+        let loader2 = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf2 = loader2.open()?;
+        assert_eq!(gf.fingerprint(), loader.open()?.fingerprint());
+        assert_ne!(gf.fingerprint(), gf2.fingerprint());
         assert_eq!(gf.tensor_infos[0].data().len() % 32, 0);
         assert_eq!(gf.tensor_infos[0].typ().to_string(), "F32");
         assert_eq!(gf.tensor_infos[0].dimensions(), vec![64, 512]);
@@ -904,6 +1649,193 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_fingerprint() -> Result<()> {
+        let path = "../testdata/tinyllamas-stories-260k-f32.gguf";
+        let loader = GGUFFileLoader::new(path)?;
+        let gf = loader.open()?;
+        let fingerprint = gf.fingerprint();
+        assert_eq!(loader.fingerprint.get(), Some(&fingerprint));
+        assert_eq!(loader.open()?.fingerprint(), fingerprint);
+
+        // flip a bit in the middle of a tensor, and in its last weight
+        let data = gf.tensor_infos[0].data();
+        let start = data.as_ptr() as usize - loader.buf[..].as_ptr() as usize;
+        for offset in [start + data.len() / 2, start + data.len() - 1] {
+            let mut bytes = std::fs::read(path).unwrap();
+            bytes[offset] ^= 0x01;
+            let patched = TempPath::new("fingerprint.gguf");
+            std::fs::write(patched.path(), &bytes).unwrap();
+            let loader2 = GGUFFileLoader::new(patched.to_str())?;
+            assert_ne!(loader2.open()?.fingerprint(), fingerprint);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_modes() -> Result<()> {
+        let path = "../testdata/tinyllamas-stories-260k-f32.gguf";
+        let mmap_loader = GGUFFileLoader::new(path)?;
+        let reports = Arc::new(std::sync::Mutex::new(vec![]));
+        let reports2 = reports.clone();
+        let options = GGUFLoadOptions::new()
+            .with_mode(GGUFLoadMode::Buffered)
+            .with_progress(move |loaded, total| reports2.lock().unwrap().push((loaded, total)));
+        let buffered_loader = GGUFFileLoader::new_with_options(path, &options)?;
+        assert_eq!(buffered_loader.len(), mmap_loader.len());
+
+        // the small file is read in one chunk
+        let reports = reports.lock().unwrap().clone();
+        let total = mmap_loader.len();
+        assert_eq!(reports, vec![(total, total)]);
+
+        let (gf, gf2) = (mmap_loader.open()?, buffered_loader.open()?);
+        assert_eq!(gf.fingerprint(), gf2.fingerprint());
+        assert!(gf.is_shared());
+        assert!(!gf2.is_shared());
+        for (a, b) in gf.tensor_infos().iter().zip(gf2.tensor_infos()) {
+            assert_eq!(a.data(), b.data());
+            assert_eq!(b.data().as_ptr() as usize % 32, 0);
+        }
+
+        assert!(GGUFFileLoader::new_with_options("/not/exists.gguf", &options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_heap_buf() {
+        let mut buf = HeapBuf::new(5000);
+        assert_eq!(buf.len(), 5000);
+        assert_eq!(buf.as_ptr() as usize % PAGE_BYTES, 0);
+        buf.as_mut_slice()[4999] = 7;
+        assert_eq!(buf[4999], 7);
+        assert!(HeapBuf::new(0).is_empty());
+    }
+
+    #[test]
+    fn test_layer_streamer() -> Result<()> {
+        let path = "../testdata/tinyllamas-stories-260k-f32.gguf";
+        let loader = GGUFFileLoader::new(path)?;
+        let gf = loader.open()?;
+        let n_layers = gf.metadata().get_u32("llama.block_count").unwrap() as usize;
+        assert_eq!(n_layers, 5);
+
+        let streamer = gf.layer_streamer(n_layers, 2)?;
+        assert!(streamer.layer_bytes(0) > 0);
+        assert_eq!(streamer.resident_layers(), vec![0, 1, 2, 3, 4]);
+        streamer.enter_layer(0);
+        assert_eq!(streamer.resident_layers(), vec![0, 1]);
+        streamer.enter_layer(3);
+        assert_eq!(streamer.resident_layers(), vec![3, 4]);
+        // the last layer prefetches the first one for the next token
+        streamer.enter_layer(4);
+        assert_eq!(streamer.resident_layers(), vec![0, 4]);
+
+        // the weights are still read correctly after being dropped
+        let loader2 = GGUFFileLoader::new(path)?;
+        let gf2 = loader2.open()?;
+        for (a, b) in gf.tensor_infos().iter().zip(gf2.tensor_infos()) {
+            assert_eq!(a.data(), b.data());
+        }
+
+        // a buffer can not be dropped and read again
+        let options = GGUFLoadOptions::new().with_mode(GGUFLoadMode::Buffered);
+        let buffered_loader = GGUFFileLoader::new_with_options(path, &options)?;
+        assert!(buffered_loader.open()?.layer_streamer(n_layers, 2).is_err());
+        assert!(gf.layer_streamer(n_layers, 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_warmup() -> Result<()> {
+        let path = "../testdata/tinyllamas-stories-260k-f32.gguf";
+        let loader = GGUFFileLoader::new(path)?;
+        let gf = loader.open()?;
+        let layer_bytes = gf
+            .tensor_infos()
+            .iter()
+            .filter(|ti| ti.name().starts_with("blk.0.") || ti.name().starts_with("blk.1."))
+            .map(|ti| ti.data().len())
+            .sum::<usize>();
+        let other_bytes = gf
+            .tensor_infos()
+            .iter()
+            .filter(|ti| !ti.name().starts_with("blk.") && ti.name() != "token_embd.weight")
+            .map(|ti| ti.data().len())
+            .sum::<usize>();
+        assert_eq!(gf.warmup(2).wait(), layer_bytes + other_bytes);
+
+        // the file in a buffer needs no warmup
+        let options = GGUFLoadOptions::new().with_mode(GGUFLoadMode::Buffered);
+        let buffered_loader = GGUFFileLoader::new_with_options(path, &options)?;
+        let warmup = buffered_loader.open()?.warmup(2);
+        assert!(warmup.is_finished());
+        assert_eq!(warmup.wait(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_roundtrip() -> Result<()> {
+        let loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf")?;
+        let gf = loader.open()?;
+
+        let mut w = GGUFWriter::new(vec![], gf.architecture());
+        for (key, value) in gf.metadata().as_hashmap() {
+            w.add_metadata(key, value.clone());
+        }
+        w.add_metadata(KEY_GENERAL_ALIGNMENT, GGUFMetadataValue::U32(64));
+        for info in gf.tensor_infos() {
+            w.add_tensor_info(info.name(), info.dimensions(), info.typ())?;
+        }
+        assert!(w.write_tensor_data(&[]).is_err());
+        w.write_header()?;
+        for info in gf.tensor_infos() {
+            w.write_tensor_data(info.data())?;
+        }
+        let buf = w.finish()?;
+
+        let gf2 = GGUFFile::decode(&mut GGUFBufReader::new(&buf))?;
+        assert_eq!(gf2.version().to_string(), "3");
+        assert_eq!(gf2.header.alignment(), 64);
+        assert_eq!(
+            gf2.metadata().as_hashmap().len(),
+            gf.metadata().as_hashmap().len() + 1
+        );
+        for (key, value) in gf.metadata().as_hashmap() {
+            assert_eq!(gf2.metadata().as_hashmap().get(key), Some(value), "{}", key);
+        }
+        assert_eq!(gf2.tensor_infos().len(), gf.tensor_infos().len());
+        for (a, b) in gf.tensor_infos().iter().zip(gf2.tensor_infos()) {
+            assert_eq!(a.name(), b.name());
+            assert_eq!(a.dimensions(), b.dimensions());
+            assert_eq!(a.typ(), b.typ());
+            assert_eq!(a.data(), b.data());
+            assert_eq!(b.data().as_ptr() as usize % 64, buf.as_ptr() as usize % 64);
+        }
+
+        // the f32 array lands off its alignment after the strings
+        let mut w = GGUFWriter::new(vec![], "llama");
+        let scores = [0.5f32, -1.0, 2.0];
+        w.add_metadata(
+            "x",
+            GGUFMetadataValue::Array(GGUFMetadataArray::F32Array(Cow::Borrowed(&scores))),
+        );
+        w.write_header()?;
+        let buf = w.finish()?;
+        let gf2 = GGUFFile::decode(&mut GGUFBufReader::new(&buf))?;
+        assert_eq!(gf2.metadata().get_f32_array("x"), Some(&scores[..]));
+
+        // the rows should be made of whole blocks
+        let mut w = GGUFWriter::new(vec![], "llama");
+        assert!(w.add_tensor_info("x", &[48, 2], GGMLType::Q8_0).is_err());
+        w.add_tensor_info("x", &[64, 2], GGMLType::Q8_0)?;
+        w.write_header()?;
+        assert!(w.write_tensor_data(&[0; 64]).is_err());
+        w.write_tensor_data(&[0; 136])?;
+        w.finish()?;
+        Ok(())
+    }
+
     #[test]
     fn test_load_metadata() -> Result<()> {
         let loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf")?;
@@ -0,0 +1,59 @@
+//! the no_std subset of crabml: the quantization blocks, their vec_dot kernels and the
+//! strider, which only need `alloc`. build with `default-features = false` to reuse the
+//! kernels on the embedded or WASI targets, without the devices, the threads and the gguf
+//! loader.
+
+pub use crate::backends::cpu::buf::buf_f16::quantize_f32_f16;
+pub use crate::backends::cpu::buf::buf_f16::vec_dot_f16_f16;
+pub use crate::backends::cpu::buf::buf_f32::vec_dot_f32_f32;
+pub use crate::backends::cpu::buf::buf_q8_0::BlockQ8_0;
+pub use crate::backends::cpu::buf::CpuTensorBuf;
+pub use crate::backends::cpu::buf::QuantBufQ2K;
+pub use crate::backends::cpu::buf::QuantBufQ3K;
+pub use crate::backends::cpu::buf::QuantBufQ4K;
+pub use crate::backends::cpu::buf::QuantBufQ4_0;
+pub use crate::backends::cpu::buf::QuantBufQ4_1;
+pub use crate::backends::cpu::buf::QuantBufQ5K;
+pub use crate::backends::cpu::buf::QuantBufQ5_0;
+pub use crate::backends::cpu::buf::QuantBufQ5_1;
+pub use crate::backends::cpu::buf::QuantBufQ6K;
+pub use crate::backends::cpu::buf::QuantBufQ8K;
+pub use crate::backends::cpu::buf::QuantBufQ8_0;
+pub use crate::backends::cpu::buf::QuantBufQ8_1;
+pub use crate::backends::cpu::buf::QuantBufTQ2_0;
+pub use crate::error::Error;
+pub use crate::error::ErrorKind;
+pub use crate::error::Result;
+pub use crate::ggml_type::GGMLType;
+pub use crate::tensor::TensorStrider;
+
+/// the alloc items used by the kernels, they're only in the prelude with std.
+pub(crate) mod prelude {
+    pub use alloc::boxed::Box;
+    pub use alloc::format;
+    pub use alloc::string::String;
+    pub use alloc::vec;
+    pub use alloc::vec::Vec;
+
+    #[cfg(not(feature = "std"))]
+    pub use num_traits::float::FloatCore;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kernels() -> Result<()> {
+        let data = (0..64).map(|i| (i as f32 * 0.1).sin()).collect::<Vec<_>>();
+        let q = QuantBufQ8_0::quantize(&data);
+        let expected = data.iter().map(|x| x * x).sum::<f32>();
+        assert!((q.vec_dot(0, &q, 0, 64) - expected).abs() < expected * 0.02);
+        assert_eq!(vec_dot_f32_f32(&data, 0, &data, 0, 64), expected);
+
+        let strider = TensorStrider::new(vec![2, 32]).transpose(&[1, 0])?;
+        assert_eq!(strider.shape(), &[32, 2]);
+        assert_eq!(GGMLType::Q8_0.block_size(), 32);
+        Ok(())
+    }
+}
@@ -1,14 +1,30 @@
+#![cfg_attr(not(feature = "std"), no_std)]
 #![feature(portable_simd)]
 #![feature(slice_as_chunks)]
 #![cfg_attr(target_arch = "aarch64", feature(stdarch_neon_dotprod))]
+#![cfg_attr(
+    target_arch = "x86_64",
+    feature(stdarch_x86_avx512, avx512_target_feature)
+)]
 #![feature(thread_local)]
 #![feature(lazy_cell)]
 #![feature(iter_array_chunks)]
 #![feature(lint_reasons)]
+#![feature(error_in_core)]
+
+extern crate alloc;
 
 #[allow(unreachable_patterns)]
 pub mod backends;
 pub mod error;
+mod ggml_type;
+#[cfg(feature = "std")]
 pub mod gguf;
+pub mod kernels;
+#[cfg(feature = "std")]
+pub mod sampler;
 pub mod tensor;
+#[cfg(all(test, feature = "std"))]
+mod testing;
+#[cfg(feature = "std")]
 pub mod tokenizer;
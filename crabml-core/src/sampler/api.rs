@@ -0,0 +1,253 @@
+use std::cmp::Ordering;
+
+use crate::error::Result;
+
+pub type SamplerRng = rand::rngs::StdRng;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candidate {
+    pub token: usize,
+    pub logit: f32,
+    pub prob: f32,
+}
+
+/// the tokens which are still possible to be sampled. the samplers in a chain adjust the
+/// logits or drop the candidates one by one, until one of them selects the token.
+///
+/// the order never depends on where the candidates come from: the lower token id wins on
+/// ties, and the NaN logits rank below everything like -inf and take no probability.
+#[derive(Debug, Clone, Default)]
+pub struct Candidates {
+    items: Vec<Candidate>,
+    sorted: bool,
+    // whether the items are still in the ascending order of the token ids, some may be dropped
+    token_order: bool,
+    selected: Option<usize>,
+    tie_epsilon: f32,
+}
+
+impl Candidates {
+    pub fn from_logits(logits: &[f32]) -> Self {
+        let mut candidates = Self::default();
+        candidates.reset(logits);
+        candidates
+    }
+
+    /// refill the candidates with the logits of the next step, the allocated buffer is reused,
+    /// so is the tie epsilon.
+    pub fn reset(&mut self, logits: &[f32]) {
+        self.items.clear();
+        self.items
+            .extend(logits.iter().enumerate().map(|(token, logit)| Candidate {
+                token,
+                logit: *logit,
+                prob: 0.0,
+            }));
+        self.sorted = false;
+        self.token_order = true;
+        self.selected = None;
+    }
+
+    pub fn items(&self) -> &[Candidate] {
+        &self.items
+    }
+
+    /// adjusting the logits may break the order, the sampler should call `mark_unsorted()`
+    /// after that.
+    pub fn items_mut(&mut self) -> &mut [Candidate] {
+        // the items may be reordered too
+        self.token_order = false;
+        &mut self.items
+    }
+
+    pub fn mark_unsorted(&mut self) {
+        self.sorted = false;
+    }
+
+    /// the candidate of the token, found by a binary search until the candidates are sorted
+    /// by the logits, like for the few tokens of a penalty window.
+    pub fn get_mut(&mut self, token: usize) -> Option<&mut Candidate> {
+        let i = if self.token_order {
+            self.items.binary_search_by_key(&token, |c| c.token).ok()?
+        } else {
+            self.items.iter().position(|c| c.token == token)?
+        };
+        Some(&mut self.items[i])
+    }
+
+    /// the logits within epsilon of the highest one are taken as a tie by `argmax()`, see
+    /// `argmax_with_epsilon()`.
+    pub fn set_tie_epsilon(&mut self, epsilon: f32) {
+        self.tie_epsilon = epsilon;
+    }
+
+    /// the candidate with the highest logit, the lower token id wins on ties.
+    pub fn argmax(&self) -> Option<usize> {
+        let logits = self.items.iter().map(|c| (c.token, c.logit));
+        argmax_by(logits, self.tie_epsilon)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// sort the candidates by the logits in descending order, the lower token id wins on ties,
+    /// and the NaNs come last.
+    pub fn sort(&mut self) {
+        if self.sorted {
+            return;
+        }
+        self.items.sort_by(|a, b| {
+            (a.logit.is_nan().cmp(&b.logit.is_nan()))
+                .then(b.logit.partial_cmp(&a.logit).unwrap_or(Ordering::Equal))
+                .then(a.token.cmp(&b.token))
+        });
+        self.sorted = true;
+        self.token_order = false;
+    }
+
+    /// keep the first n candidates, the candidates are sorted before truncating.
+    pub fn truncate(&mut self, n: usize) {
+        self.sort();
+        self.items.truncate(n.max(1));
+    }
+
+    /// sort the candidates and calculate the probabilities of the remaining candidates. the
+    /// probabilities always sum to 1: the +inf logits share all of it, and if none of the
+    /// logits is above -inf, the first candidate takes it.
+    pub fn softmax(&mut self) {
+        self.sort();
+        let max = match self.items.first() {
+            Some(c) => rank_key(c.logit),
+            None => return,
+        };
+        if max == f32::NEG_INFINITY {
+            for (i, c) in self.items.iter_mut().enumerate() {
+                c.prob = if i == 0 { 1.0 } else { 0.0 };
+            }
+            return;
+        }
+        let mut sum = 0.0;
+        for c in self.items.iter_mut() {
+            c.prob = if max == f32::INFINITY {
+                if c.logit == max { 1.0 } else { 0.0 }
+            } else {
+                (rank_key(c.logit) - max).exp()
+            };
+            sum += c.prob;
+        }
+        for c in self.items.iter_mut() {
+            c.prob /= sum;
+        }
+    }
+
+    /// keep only the candidates matching the predicate, the order is kept.
+    pub fn retain(&mut self, f: impl FnMut(&Candidate) -> bool) {
+        self.items.retain(f);
+    }
+
+    pub fn select(&mut self, token: usize) {
+        self.selected = Some(token);
+    }
+
+    pub fn selected(&self) -> Option<usize> {
+        self.selected
+    }
+}
+
+// NaN takes no probability, like -inf
+fn rank_key(logit: f32) -> f32 {
+    if logit.is_nan() {
+        f32::NEG_INFINITY
+    } else {
+        logit
+    }
+}
+
+/// the token with the highest logit, the lower token id wins on ties like `sort()`. NaNs
+/// are never taken.
+pub fn argmax(logits: &[f32]) -> Option<usize> {
+    argmax_with_epsilon(logits, 0.0)
+}
+
+/// the lowest token id whose logit is within epsilon of the highest logit. the simd kernels
+/// sum up the matmuls in different orders, so two tokens tied in exact math may differ in the
+/// last bits of their logits by the platform, a small epsilon like 1e-5 makes the greedy
+/// choice stable across them. NaNs are never taken.
+pub fn argmax_with_epsilon(logits: &[f32], epsilon: f32) -> Option<usize> {
+    argmax_by(logits.iter().copied().enumerate(), epsilon)
+}
+
+fn argmax_by(logits: impl Iterator<Item = (usize, f32)> + Clone, epsilon: f32) -> Option<usize> {
+    let max = logits
+        .clone()
+        .filter(|(_, l)| !l.is_nan())
+        .map(|(_, l)| l)
+        .reduce(f32::max)?;
+    logits
+        .filter(|(_, l)| *l >= max - epsilon)
+        .map(|(token, _)| token)
+        .min()
+}
+
+/// `log(sum(exp(logits)))`, computed in two passes without allocating. NaNs are skipped.
+pub fn log_sum_exp(logits: &[f32]) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if !max.is_finite() {
+        return max;
+    }
+    let sum: f32 = logits
+        .iter()
+        .filter(|l| !l.is_nan())
+        .map(|l| (l - max).exp())
+        .sum();
+    max + sum.ln()
+}
+
+/// a setting of the samplers which can be changed between the steps of a generation, see
+/// `SamplerChain::set_param()`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SamplerParam {
+    Temperature(f32),
+    TopK(usize),
+    TopP(f32),
+}
+
+pub trait Sampler {
+    /// adjust the logits, drop some candidates, or select the token from the candidates.
+    fn apply(&mut self, candidates: &mut Candidates, rng: &mut SamplerRng) -> Result<()>;
+
+    /// whether `apply()` leaves the candidates untouched with the current settings, a chain
+    /// of such samplers is greedy and takes the argmax of the raw logits directly.
+    fn is_noop(&self) -> bool {
+        false
+    }
+
+    /// called on every token in the context, including the prompt tokens and the sampled ones.
+    fn accept(&mut self, _token: usize) {}
+
+    /// forget the state about the previous tokens, like on starting a new conversation.
+    fn reset(&mut self) {}
+
+    /// take the new setting if it's one of this sampler, returns whether it's taken. the
+    /// state about the previous tokens is kept.
+    fn set_param(&mut self, _param: SamplerParam) -> bool {
+        false
+    }
+}
+
+/// adjusts or masks the candidates before the samplers run, like a grammar. unlike the
+/// samplers, it only sees the sampled tokens, the prompt is not constrained.
+pub trait LogitsProcessor {
+    fn process(&mut self, candidates: &mut Candidates) -> Result<()>;
+
+    /// called on every sampled token.
+    fn accept(&mut self, token: usize) -> Result<()>;
+
+    /// restart from the beginning, like on starting a new conversation.
+    fn reset(&mut self) {}
+}
@@ -0,0 +1,398 @@
+use std::collections::BTreeMap;
+
+use rand::SeedableRng;
+
+use super::argmax_with_epsilon;
+use super::log_sum_exp;
+use super::Candidates;
+use super::LogitsProcessor;
+use super::Sampler;
+use super::SamplerParam;
+use super::SamplerRng;
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+/// run the samplers in order on the logits, like:
+///
+/// ```ignore
+/// let mut sampler = SamplerChain::new()
+///     .with_seed(42)
+///     .with(Penalties::new(64, 1.1, 0.0, 0.0))
+///     .with(TopK::new(40))
+///     .with(Temperature::new(0.8))
+///     .with(TopP::new(0.9))
+///     .with(Dist);
+/// ```
+///
+/// if none of the samplers selects a token, the candidate with the highest logit is taken,
+/// so an empty chain is greedy.
+///
+/// the logits processors (like a `Grammar`) run before the samplers, to mask the candidates
+/// which are not allowed.
+///
+/// a chain without processors whose samplers are all no-ops (like the default penalties) is greedy, and takes the
+/// argmax of the raw logits without building, sorting or softmaxing the candidates.
+///
+/// every chain owns its rng, so the sequences sampled by different chains never perturb
+/// each other, a sequence can be reproduced from the `seed()` of its chain alone.
+///
+/// the greedy choice takes the lowest token id on ties, `with_tie_epsilon()` widens the ties
+/// to the logits within epsilon of the highest one, so the output does not flip with the
+/// rounding of the simd kernels.
+///
+/// the logit biases and the settings of the samplers can be changed between the steps of a
+/// generation by `set_logit_bias()` and `set_param()`, the state about the previous tokens
+/// and the rng are kept.
+pub struct SamplerChain {
+    processors: Vec<Box<dyn LogitsProcessor>>,
+    samplers: Vec<Box<dyn Sampler>>,
+    seed: u64,
+    rng: SamplerRng,
+    candidates: Candidates,
+    logprobs: bool,
+    last_logprob: Option<f32>,
+    tie_epsilon: f32,
+    logit_bias: BTreeMap<usize, f32>,
+}
+
+impl SamplerChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self.rng = SamplerRng::seed_from_u64(seed);
+        self
+    }
+
+    pub fn with_processor(mut self, processor: impl LogitsProcessor + 'static) -> Self {
+        self.processors.push(Box::new(processor));
+        self
+    }
+
+    pub fn with(mut self, sampler: impl Sampler + 'static) -> Self {
+        self.samplers.push(Box::new(sampler));
+        self
+    }
+
+    /// keep the log probability of the sampled tokens, it costs an extra pass over the logits
+    /// on every token, so it's off by default.
+    pub fn with_logprobs(mut self, logprobs: bool) -> Self {
+        self.logprobs = logprobs;
+        self
+    }
+
+    /// the logits within epsilon of the highest one tie on the greedy choice, 0 by default.
+    pub fn with_tie_epsilon(mut self, epsilon: f32) -> Self {
+        self.set_tie_epsilon(epsilon);
+        self
+    }
+
+    pub fn set_tie_epsilon(&mut self, epsilon: f32) {
+        self.tie_epsilon = epsilon;
+        self.candidates.set_tie_epsilon(epsilon);
+    }
+
+    /// add the bias to the logit of the token before the processors and the samplers run,
+    /// -inf bans the token.
+    pub fn with_logit_bias(mut self, token: usize, bias: f32) -> Self {
+        self.set_logit_bias(token, bias);
+        self
+    }
+
+    /// a bias of 0.0 removes the bias of the token.
+    pub fn set_logit_bias(&mut self, token: usize, bias: f32) {
+        if bias == 0.0 {
+            self.logit_bias.remove(&token);
+        } else {
+            self.logit_bias.insert(token, bias);
+        }
+    }
+
+    pub fn clear_logit_biases(&mut self) {
+        self.logit_bias.clear();
+    }
+
+    /// change a setting of the samplers in the chain, like the temperature, from the next
+    /// sample on. it fails if no sampler in the chain takes the setting.
+    pub fn set_param(&mut self, param: SamplerParam) -> Result<()> {
+        let mut taken = false;
+        for sampler in self.samplers.iter_mut() {
+            taken |= sampler.set_param(param);
+        }
+        if !taken {
+            return Err((
+                ErrorKind::BadInput,
+                format!("no sampler in the chain takes {:?}", param),
+            )
+                .into());
+        }
+        Ok(())
+    }
+
+    pub fn tie_epsilon(&self) -> f32 {
+        self.tie_epsilon
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// the log probability of the last sampled token in the distribution of the raw logits,
+    /// only kept when `with_logprobs(true)`.
+    pub fn last_logprob(&self) -> Option<f32> {
+        self.last_logprob
+    }
+
+    pub fn is_greedy(&self) -> bool {
+        self.processors.is_empty()
+            && self.logit_bias.is_empty()
+            && self.samplers.iter().all(|s| s.is_noop())
+    }
+
+    pub fn sample(&mut self, logits: &[f32]) -> Result<usize> {
+        let token = if self.is_greedy() {
+            match argmax_with_epsilon(logits, self.tie_epsilon) {
+                Some(token) => token,
+                None => {
+                    return Err((ErrorKind::Unexpected, "failed to sample from logits").into());
+                }
+            }
+        } else {
+            self.sample_candidates(logits)?
+        };
+
+        if self.logprobs {
+            self.last_logprob = Some(logits[token] - log_sum_exp(logits));
+        }
+        for processor in self.processors.iter_mut() {
+            processor.accept(token)?;
+        }
+        self.accept(token);
+        Ok(token)
+    }
+
+    fn sample_candidates(&mut self, logits: &[f32]) -> Result<usize> {
+        self.candidates.reset(logits);
+        for (token, bias) in self.logit_bias.iter() {
+            if let Some(c) = self.candidates.get_mut(*token) {
+                c.logit += bias;
+            }
+        }
+        for processor in self.processors.iter_mut() {
+            processor.process(&mut self.candidates)?;
+        }
+        for sampler in self.samplers.iter_mut() {
+            if self.candidates.selected().is_some() {
+                break;
+            }
+            sampler.apply(&mut self.candidates, &mut self.rng)?;
+        }
+
+        let token = match self.candidates.selected() {
+            Some(token) => token,
+            None => match self.candidates.argmax() {
+                Some(token) => token,
+                None => {
+                    return Err((ErrorKind::Unexpected, "failed to sample from logits").into());
+                }
+            },
+        };
+        Ok(token)
+    }
+
+    /// the sampled tokens are accepted by `sample()`, only the prompt tokens need to be
+    /// accepted explicitly.
+    pub fn accept(&mut self, token: usize) {
+        for sampler in self.samplers.iter_mut() {
+            sampler.accept(token);
+        }
+    }
+
+    /// reset the state of the processors and the samplers, and restart the rng from the seed.
+    pub fn reset(&mut self) {
+        for processor in self.processors.iter_mut() {
+            processor.reset();
+        }
+        for sampler in self.samplers.iter_mut() {
+            sampler.reset();
+        }
+        self.rng = SamplerRng::seed_from_u64(self.seed);
+        self.last_logprob = None;
+    }
+}
+
+impl Default for SamplerChain {
+    fn default() -> Self {
+        let seed = rand::random();
+        Self {
+            processors: vec![],
+            samplers: vec![],
+            seed,
+            rng: SamplerRng::seed_from_u64(seed),
+            candidates: Candidates::default(),
+            logprobs: false,
+            last_logprob: None,
+            tie_epsilon: 0.0,
+            logit_bias: BTreeMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::Dist;
+    use crate::sampler::Penalties;
+    use crate::sampler::Temperature;
+    use crate::sampler::TopK;
+
+    #[test]
+    fn test_empty_chain_is_greedy() -> Result<()> {
+        let mut sampler = SamplerChain::new();
+        assert_eq!(sampler.sample(&[0.1, 0.5, 0.5, 0.2])?, 1);
+        assert!(sampler.sample(&[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_greedy_fast_path() -> Result<()> {
+        let logits = [0.3, f32::NAN, 2.0, 2.0, -1.0];
+
+        // the default penalties do nothing, so the chain takes the argmax of the raw logits
+        let mut fast = SamplerChain::new().with(Penalties::new(64, 1.0, 0.0, 0.0));
+        assert!(fast.is_greedy());
+        assert_eq!(fast.sample(&logits)?, 2);
+        assert_eq!(fast.last_logprob(), None);
+
+        // falls back to the candidates and agrees with the fast path
+        let mut slow = SamplerChain::new().with(Penalties::new(64, 1.0, 0.0, 0.1));
+        assert!(!slow.is_greedy());
+        assert_eq!(slow.sample(&logits[2..])?, 0);
+
+        let logits = [1.0, 3.0, 2.0];
+        let mut sampler = SamplerChain::new().with_logprobs(true);
+        assert_eq!(sampler.sample(&logits)?, 1);
+        let expected = (3.0f32.exp() / logits.iter().map(|l| l.exp()).sum::<f32>()).ln();
+        assert!((sampler.last_logprob().unwrap() - expected).abs() < 1e-5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tie_epsilon() -> Result<()> {
+        // the logits of two kernels tied in exact math, but rounded differently
+        let a = [0.1, 2.5, 2.5 + 3e-7, -1.0];
+        let b = [0.1, 2.5 + 3e-7, 2.5, -1.0];
+        let mut exact = SamplerChain::new();
+        assert_ne!(exact.sample(&a)?, exact.sample(&b)?);
+
+        let mut fast = SamplerChain::new().with_tie_epsilon(1e-5);
+        assert_eq!(fast.sample(&a)?, 1);
+        assert_eq!(fast.sample(&b)?, 1);
+
+        // the candidates of a non-greedy chain break the ties the same way
+        let mut slow = SamplerChain::new()
+            .with_tie_epsilon(1e-5)
+            .with(Penalties::new(64, 1.0, 0.0, 0.1));
+        assert!(!slow.is_greedy());
+        assert_eq!(slow.sample(&a)?, 1);
+        slow.reset();
+        assert_eq!(slow.sample(&b)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chain_with_seed() -> Result<()> {
+        let logits = (0..32).map(|i| (i % 7) as f32 * 0.3).collect::<Vec<_>>();
+        let new_chain = || {
+            SamplerChain::new()
+                .with_seed(42)
+                .with(TopK::new(10))
+                .with(Temperature::new(1.5))
+                .with(Dist)
+        };
+
+        let mut s1 = new_chain();
+        let mut s2 = new_chain();
+        let tokens1 = (0..16)
+            .map(|_| s1.sample(&logits))
+            .collect::<Result<Vec<_>>>()?;
+        let tokens2 = (0..16)
+            .map(|_| s2.sample(&logits))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(tokens1, tokens2);
+
+        s1.reset();
+        let tokens3 = (0..16)
+            .map(|_| s1.sample(&logits))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(tokens1, tokens3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chain_rng_isolation() -> Result<()> {
+        let logits = (0..32).map(|i| (i % 5) as f32 * 0.2).collect::<Vec<_>>();
+        let new_chain = |seed| SamplerChain::new().with_seed(seed).with(Dist);
+
+        let mut alone = new_chain(7);
+        let expected = (0..16)
+            .map(|_| alone.sample(&logits))
+            .collect::<Result<Vec<_>>>()?;
+
+        // sampling another sequence in between does not change the tokens of seed 7
+        let mut s1 = new_chain(7);
+        let mut s2 = new_chain(8);
+        let mut got = vec![];
+        for _ in 0..16 {
+            s2.sample(&logits)?;
+            got.push(s1.sample(&logits)?);
+            s2.sample(&logits)?;
+        }
+        assert_eq!(got, expected);
+        assert_eq!(s1.seed(), 7);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chain_accepts_sampled_tokens() -> Result<()> {
+        // the repetition penalty pushes the greedy sampling to the next best token
+        let mut sampler = SamplerChain::new().with(Penalties::new(8, 2.0, 0.0, 0.0));
+        let logits = [1.0, 3.0, 2.0];
+        assert_eq!(sampler.sample(&logits)?, 1);
+        assert_eq!(sampler.sample(&logits)?, 2);
+        assert_eq!(sampler.sample(&logits)?, 1);
+        sampler.reset();
+        assert_eq!(sampler.sample(&logits)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_change_between_steps() -> Result<()> {
+        let logits = [1.0, 3.0, 2.0];
+        let mut sampler = SamplerChain::new().with_logit_bias(1, f32::NEG_INFINITY);
+        assert!(!sampler.is_greedy());
+        assert_eq!(sampler.sample(&logits)?, 2);
+        sampler.set_logit_bias(0, 5.0);
+        assert_eq!(sampler.sample(&logits)?, 0);
+        sampler.clear_logit_biases();
+        assert!(sampler.is_greedy());
+        assert_eq!(sampler.sample(&logits)?, 1);
+
+        // the temperature of 0 takes the top one, the top k of 1 keeps only it
+        let mut sampler = SamplerChain::new()
+            .with_seed(1)
+            .with(TopK::new(0))
+            .with(Temperature::new(100.0))
+            .with(Dist);
+        let logits = (0..32).map(|i| i as f32 * 0.01).collect::<Vec<_>>();
+        sampler.set_param(SamplerParam::Temperature(0.0))?;
+        assert_eq!(sampler.sample(&logits)?, 31);
+        sampler.set_param(SamplerParam::Temperature(100.0))?;
+        sampler.set_param(SamplerParam::TopK(1))?;
+        assert_eq!(sampler.sample(&logits)?, 31);
+        assert!(sampler.set_param(SamplerParam::TopP(0.9)).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,886 @@
+//! Constrained decoding with GBNF grammars, the format used by llama.cpp:
+//!
+//! ```text
+//! root   ::= answer "."
+//! answer ::= "yes" | "no" | [0-9]+
+//! ```
+//!
+//! Literals, char classes (`[a-z]`, `[^"\\]`), `.`, rule references, groups and the
+//! `*`, `+`, `?`, `{m}`, `{m,}`, `{m,n}` repetitions are supported, `#` starts a comment.
+//! A newline ends a rule unless it's inside a group.
+//!
+//! The matcher keeps every possible parse as a stack of the positions in the rules, like
+//! llama.cpp. Left recursive rules are rejected on parsing.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use super::Candidates;
+use super::LogitsProcessor;
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::tokenizer::BpeTokenizer;
+use crate::tokenizer::VocabTrie;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Element {
+    /// one char in any of the inclusive ranges, or in none of them if negated.
+    Chars {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Rule(usize),
+}
+
+impl Element {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Element::Chars { ranges, negated } => {
+                ranges.iter().any(|(lo, hi)| *lo <= c && c <= *hi) != *negated
+            }
+            Element::Rule(_) => false,
+        }
+    }
+}
+
+type Alternative = Vec<Element>;
+
+/// the parsed rules of a grammar, the groups and repetitions are turned into generated rules.
+#[derive(Debug, Clone)]
+pub struct GrammarRules {
+    rules: Vec<Vec<Alternative>>,
+    names: Vec<String>,
+    root: usize,
+}
+
+impl GrammarRules {
+    pub fn parse(src: &str) -> Result<Self> {
+        GrammarParser::new(src).parse()
+    }
+
+    pub fn rule_names(&self) -> &[String] {
+        &self.names
+    }
+
+    fn element(&self, pos: &Pos) -> &Element {
+        &self.rules[pos.rule][pos.alt][pos.elem]
+    }
+
+    fn alt_len(&self, rule: usize, alt: usize) -> usize {
+        self.rules[rule][alt].len()
+    }
+}
+
+struct GrammarParser {
+    chars: Vec<char>,
+    pos: usize,
+    rule_ids: HashMap<String, usize>,
+    rules: Vec<Option<Vec<Alternative>>>,
+    names: Vec<String>,
+}
+
+impl GrammarParser {
+    fn new(src: &str) -> Self {
+        Self {
+            chars: src.chars().collect(),
+            pos: 0,
+            rule_ids: HashMap::new(),
+            rules: vec![],
+            names: vec![],
+        }
+    }
+
+    fn parse(mut self) -> Result<GrammarRules> {
+        loop {
+            self.skip_space(true);
+            if self.peek().is_none() {
+                break;
+            }
+            let name = self.parse_name()?;
+            self.skip_space(false);
+            self.expect("::=")?;
+            self.skip_space(true);
+            let alts = self.parse_alternatives(&name, false)?;
+            let id = self.rule_id(&name);
+            if self.rules[id].is_some() {
+                return Err(self.error(format!("rule {} is defined twice", name)));
+            }
+            self.rules[id] = Some(alts);
+            match self.peek() {
+                None | Some('\n') | Some('\r') => {}
+                Some(c) => return Err(self.error(format!("unexpected char {:?}", c))),
+            }
+        }
+
+        let root = match self.rule_ids.get("root") {
+            Some(id) => *id,
+            None => return Err(self.error("the grammar has no root rule".to_string())),
+        };
+        let mut rules = Vec::with_capacity(self.rules.len());
+        for (id, rule) in self.rules.into_iter().enumerate() {
+            match rule {
+                Some(rule) => rules.push(rule),
+                None => {
+                    return Err((
+                        ErrorKind::BadInput,
+                        format!(
+                            "grammar: rule {} is referenced but not defined",
+                            self.names[id]
+                        ),
+                    )
+                        .into());
+                }
+            }
+        }
+        let rules = GrammarRules {
+            rules,
+            names: self.names,
+            root,
+        };
+        check_left_recursion(&rules)?;
+        Ok(rules)
+    }
+
+    fn parse_alternatives(&mut self, name: &str, nested: bool) -> Result<Vec<Alternative>> {
+        let mut alts = vec![self.parse_sequence(name, nested)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            self.skip_space(true);
+            alts.push(self.parse_sequence(name, nested)?);
+        }
+        Ok(alts)
+    }
+
+    fn parse_sequence(&mut self, name: &str, nested: bool) -> Result<Alternative> {
+        let mut seq: Alternative = vec![];
+        // where the last symbol starts, the repetitions apply to it
+        let mut last_start = 0;
+        loop {
+            self.skip_space(nested);
+            let c = match self.peek() {
+                Some(c) => c,
+                None => break,
+            };
+            match c {
+                '"' => {
+                    self.pos += 1;
+                    last_start = seq.len();
+                    loop {
+                        match self.peek() {
+                            None => return Err(self.error("unterminated literal".to_string())),
+                            Some('"') => {
+                                self.pos += 1;
+                                break;
+                            }
+                            Some(_) => {
+                                let c = self.parse_char()?;
+                                seq.push(Element::Chars {
+                                    ranges: vec![(c, c)],
+                                    negated: false,
+                                });
+                            }
+                        }
+                    }
+                }
+                '[' => {
+                    self.pos += 1;
+                    last_start = seq.len();
+                    let negated = self.peek() == Some('^');
+                    if negated {
+                        self.pos += 1;
+                    }
+                    let mut ranges = vec![];
+                    loop {
+                        match self.peek() {
+                            None => return Err(self.error("unterminated char class".to_string())),
+                            Some(']') => {
+                                self.pos += 1;
+                                break;
+                            }
+                            Some(_) => {
+                                let lo = self.parse_char()?;
+                                let hi = if self.peek() == Some('-')
+                                    && self.peek_at(1).is_some_and(|c| c != ']')
+                                {
+                                    self.pos += 1;
+                                    self.parse_char()?
+                                } else {
+                                    lo
+                                };
+                                ranges.push((lo, hi));
+                            }
+                        }
+                    }
+                    seq.push(Element::Chars { ranges, negated });
+                }
+                '.' => {
+                    self.pos += 1;
+                    last_start = seq.len();
+                    seq.push(Element::Chars {
+                        ranges: vec![],
+                        negated: true,
+                    });
+                }
+                '(' => {
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let alts = self.parse_alternatives(name, true)?;
+                    self.skip_space(true);
+                    self.expect(")")?;
+                    last_start = seq.len();
+                    let id = self.generate_rule(name, alts);
+                    seq.push(Element::Rule(id));
+                }
+                '*' | '+' | '?' | '{' => {
+                    if last_start >= seq.len() {
+                        return Err(self.error(format!("nothing to repeat before {:?}", c)));
+                    }
+                    self.pos += 1;
+                    let (min, max) = match c {
+                        '*' => (0, None),
+                        '+' => (1, None),
+                        '?' => (0, Some(1)),
+                        _ => self.parse_braces()?,
+                    };
+                    let symbol = seq.split_off(last_start);
+                    let id = self.generate_repetition(name, symbol, min, max);
+                    seq.push(Element::Rule(id));
+                }
+                c if is_name_char(c) => {
+                    let rule = self.parse_name()?;
+                    last_start = seq.len();
+                    let id = self.rule_id(&rule);
+                    seq.push(Element::Rule(id));
+                }
+                _ => break,
+            }
+        }
+        Ok(seq)
+    }
+
+    // {m}, {m,} or {m,n}, the opening brace is consumed
+    fn parse_braces(&mut self) -> Result<(usize, Option<usize>)> {
+        self.skip_space(true);
+        let min = self.parse_int()?;
+        self.skip_space(true);
+        let max = if self.peek() == Some(',') {
+            self.pos += 1;
+            self.skip_space(true);
+            if self.peek() == Some('}') {
+                None
+            } else {
+                Some(self.parse_int()?)
+            }
+        } else {
+            Some(min)
+        };
+        self.skip_space(true);
+        self.expect("}")?;
+        if max.is_some_and(|max| max < min) {
+            return Err(self.error(format!("bad repetition {{{},{:?}}}", min, max)));
+        }
+        Ok((min, max))
+    }
+
+    fn parse_int(&mut self) -> Result<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let s: String = self.chars[start..self.pos].iter().collect();
+        s.parse()
+            .map_err(|_| self.error("expected an integer".to_string()))
+    }
+
+    // symbol{min,max} => symbol symbol ... (symbol (symbol ...)?)? or symbol* for no max
+    fn generate_repetition(
+        &mut self,
+        name: &str,
+        symbol: Alternative,
+        min: usize,
+        max: Option<usize>,
+    ) -> usize {
+        let mut seq: Alternative = vec![];
+        for _ in 0..min {
+            seq.extend(symbol.iter().cloned());
+        }
+        match max {
+            None => {
+                // star ::= symbol star |
+                let star = self.rule_id(&format!("{}-{}", name, self.rules.len()));
+                let mut alt = symbol.clone();
+                alt.push(Element::Rule(star));
+                self.rules[star] = Some(vec![alt, vec![]]);
+                seq.push(Element::Rule(star));
+            }
+            Some(max) => {
+                // the optional tail is nested: opt_n ::= symbol opt_n-1 |
+                let mut tail: Option<usize> = None;
+                for _ in min..max {
+                    let mut alt = symbol.clone();
+                    if let Some(tail) = tail {
+                        alt.push(Element::Rule(tail));
+                    }
+                    tail = Some(self.generate_rule(name, vec![alt, vec![]]));
+                }
+                if let Some(tail) = tail {
+                    seq.push(Element::Rule(tail));
+                }
+            }
+        }
+        self.generate_rule(name, vec![seq])
+    }
+
+    fn generate_rule(&mut self, name: &str, alts: Vec<Alternative>) -> usize {
+        let id = self.rule_id(&format!("{}-{}", name, self.rules.len()));
+        self.rules[id] = Some(alts);
+        id
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        if let Some(id) = self.rule_ids.get(name) {
+            return *id;
+        }
+        let id = self.rules.len();
+        self.rules.push(None);
+        self.names.push(name.to_string());
+        self.rule_ids.insert(name.to_string(), id);
+        id
+    }
+
+    fn parse_name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_name_char) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(self.error("expected a rule name".to_string()));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    fn parse_char(&mut self) -> Result<char> {
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Err(self.error("unexpected end".to_string())),
+        };
+        self.pos += 1;
+        if c != '\\' {
+            return Ok(c);
+        }
+        let e = match self.peek() {
+            Some(e) => e,
+            None => return Err(self.error("unexpected end after \\".to_string())),
+        };
+        self.pos += 1;
+        let n_hex = match e {
+            'n' => return Ok('\n'),
+            't' => return Ok('\t'),
+            'r' => return Ok('\r'),
+            'x' => 2,
+            'u' => 4,
+            'U' => 8,
+            '\\' | '"' | '[' | ']' | '-' | '^' => return Ok(e),
+            _ => return Err(self.error(format!("unknown escape \\{}", e))),
+        };
+        if self.pos + n_hex > self.chars.len() {
+            return Err(self.error("unexpected end in the escape".to_string()));
+        }
+        let hex: String = self.chars[self.pos..self.pos + n_hex].iter().collect();
+        self.pos += n_hex;
+        u32::from_str_radix(&hex, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| self.error(format!("bad escape \\{}{}", e, hex)))
+    }
+
+    // skip the spaces and the comments, the newlines are only skipped if newline_ok
+    fn skip_space(&mut self, newline_ok: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' => self.pos += 1,
+                '\n' | '\r' if newline_ok => self.pos += 1,
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n' && c != '\r') {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn expect(&mut self, s: &str) -> Result<()> {
+        for expected in s.chars() {
+            if self.peek() != Some(expected) {
+                return Err(self.error(format!("expected {:?}", s)));
+            }
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn error(&self, message: String) -> Error {
+        let line = self.chars[..self.pos.min(self.chars.len())]
+            .iter()
+            .filter(|c| **c == '\n')
+            .count()
+            + 1;
+        Error {
+            kind: ErrorKind::BadInput,
+            message: format!("grammar: {} on line {}", message, line),
+            cause: None,
+        }
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+// a rule which can be expanded into itself without consuming any char would make the
+// matcher loop forever
+fn check_left_recursion(rules: &GrammarRules) -> Result<()> {
+    let n = rules.rules.len();
+    let mut nullable = vec![false; n];
+    loop {
+        let mut changed = false;
+        for (id, alts) in rules.rules.iter().enumerate() {
+            if nullable[id] {
+                continue;
+            }
+            let is_nullable = alts.iter().any(|alt| {
+                alt.iter()
+                    .all(|e| matches!(e, Element::Rule(r) if nullable[*r]))
+            });
+            if is_nullable {
+                nullable[id] = true;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    // the rules reachable at the left edge of each rule
+    let left_edges = rules
+        .rules
+        .iter()
+        .map(|alts| {
+            let mut edges = vec![];
+            for alt in alts {
+                for e in alt {
+                    match e {
+                        Element::Rule(r) => {
+                            edges.push(*r);
+                            if !nullable[*r] {
+                                break;
+                            }
+                        }
+                        Element::Chars { .. } => break,
+                    }
+                }
+            }
+            edges
+        })
+        .collect::<Vec<_>>();
+
+    // 0: unvisited, 1: visiting, 2: done
+    fn visit(id: usize, edges: &[Vec<usize>], state: &mut [u8]) -> Option<usize> {
+        if state[id] == 1 {
+            return Some(id);
+        }
+        if state[id] == 2 {
+            return None;
+        }
+        state[id] = 1;
+        for next in edges[id].iter() {
+            if let Some(r) = visit(*next, edges, state) {
+                return Some(r);
+            }
+        }
+        state[id] = 2;
+        None
+    }
+    let mut state = vec![0u8; n];
+    for id in 0..n {
+        if let Some(r) = visit(id, &left_edges, &mut state) {
+            return Err((
+                ErrorKind::BadInput,
+                format!("grammar: rule {} is left recursive", rules.names[r]),
+            )
+                .into());
+        }
+    }
+    Ok(())
+}
+
+/// the position of the next element to match in an alternative of a rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Pos {
+    rule: usize,
+    alt: usize,
+    elem: usize,
+}
+
+/// the positions to return to, the top is the next element to match. an empty stack means
+/// the root rule is completed.
+type Stack = Vec<Pos>;
+
+/// tracks all the possible parses of the text accepted so far.
+#[derive(Debug, Clone)]
+pub struct GrammarMatcher {
+    rules: GrammarRules,
+    stacks: Vec<Stack>,
+}
+
+impl GrammarMatcher {
+    pub fn new(rules: GrammarRules) -> Self {
+        let stacks = expand_rule(&rules, &[], rules.root);
+        Self { rules, stacks }
+    }
+
+    pub fn reset(&mut self) {
+        self.stacks = expand_rule(&self.rules, &[], self.rules.root);
+    }
+
+    /// the text accepted so far is a complete match of the root rule.
+    pub fn is_accepting(&self) -> bool {
+        self.stacks.iter().any(|s| s.is_empty())
+    }
+
+    /// no more text can be accepted.
+    pub fn is_finished(&self) -> bool {
+        self.stacks.iter().all(|s| s.is_empty())
+    }
+
+    /// whether the text can be accepted from the current state, without changing the state.
+    pub fn can_accept(&self, text: &str) -> bool {
+        self.advance(text).is_some()
+    }
+
+    /// accept the text, returns false and keeps the state if the text violates the grammar.
+    pub fn accept(&mut self, text: &str) -> bool {
+        match self.advance(text) {
+            Some(stacks) => {
+                self.stacks = stacks;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// mark the tokens of the trie which can be accepted from the current state. the tokens
+    /// sharing a prefix are matched together, so the prefix is only advanced once, and the
+    /// tokens ending in the middle of a utf8 char are not allowed.
+    pub fn allowed_tokens(&self, trie: &VocabTrie, allowed: &mut [bool]) {
+        allowed.fill(false);
+        // the stacks after the complete chars, and the bytes of the incomplete char
+        let root = (self.stacks.clone(), vec![]);
+        trie.walk(
+            root,
+            |(stacks, pending): &(Vec<Stack>, Vec<u8>), b, tokens| {
+                let mut pending = pending.clone();
+                pending.push(b);
+                match std::str::from_utf8(&pending) {
+                    Ok(s) => {
+                        let c = s.chars().next().unwrap();
+                        let stacks = accept_char(&self.rules, stacks, c);
+                        if stacks.is_empty() {
+                            return None;
+                        }
+                        for t in tokens {
+                            if let Some(a) = allowed.get_mut(*t as usize) {
+                                *a = true;
+                            }
+                        }
+                        Some((stacks, vec![]))
+                    }
+                    Err(err) if err.error_len().is_none() => Some((stacks.clone(), pending)),
+                    Err(_) => None,
+                }
+            },
+        );
+    }
+
+    fn advance(&self, text: &str) -> Option<Vec<Stack>> {
+        let mut chars = text.chars();
+        let first = chars.next()?;
+        let mut stacks = accept_char(&self.rules, &self.stacks, first);
+        for c in chars {
+            if stacks.is_empty() {
+                return None;
+            }
+            stacks = accept_char(&self.rules, &stacks, c);
+        }
+        if stacks.is_empty() {
+            None
+        } else {
+            Some(stacks)
+        }
+    }
+}
+
+// expand the rule on top of the stack into the stacks whose tops are all chars
+fn expand_rule(rules: &GrammarRules, stack: &[Pos], rule: usize) -> Vec<Stack> {
+    let mut out = vec![];
+    let mut seen = HashSet::new();
+    for alt in 0..rules.rules[rule].len() {
+        let mut new_stack = stack.to_vec();
+        if rules.alt_len(rule, alt) > 0 {
+            new_stack.push(Pos { rule, alt, elem: 0 });
+        }
+        expand_stack(rules, new_stack, &mut out, &mut seen);
+    }
+    out
+}
+
+fn expand_stack(
+    rules: &GrammarRules,
+    mut stack: Stack,
+    out: &mut Vec<Stack>,
+    seen: &mut HashSet<Stack>,
+) {
+    let top = match stack.last() {
+        None => {
+            if seen.insert(stack.clone()) {
+                out.push(stack);
+            }
+            return;
+        }
+        Some(top) => *top,
+    };
+    match rules.element(&top) {
+        Element::Chars { .. } => {
+            if seen.insert(stack.clone()) {
+                out.push(stack);
+            }
+        }
+        Element::Rule(rule) => {
+            let rule = *rule;
+            stack.pop();
+            if top.elem + 1 < rules.alt_len(top.rule, top.alt) {
+                stack.push(Pos {
+                    elem: top.elem + 1,
+                    ..top
+                });
+            }
+            for alt in 0..rules.rules[rule].len() {
+                let mut new_stack = stack.clone();
+                if rules.alt_len(rule, alt) > 0 {
+                    new_stack.push(Pos { rule, alt, elem: 0 });
+                }
+                expand_stack(rules, new_stack, out, seen);
+            }
+        }
+    }
+}
+
+fn accept_char(rules: &GrammarRules, stacks: &[Stack], c: char) -> Vec<Stack> {
+    let mut out = vec![];
+    let mut seen = HashSet::new();
+    for stack in stacks {
+        let top = match stack.last() {
+            Some(top) => *top,
+            None => continue,
+        };
+        if !rules.element(&top).matches(c) {
+            continue;
+        }
+        let mut new_stack = stack[..stack.len() - 1].to_vec();
+        if top.elem + 1 < rules.alt_len(top.rule, top.alt) {
+            new_stack.push(Pos {
+                elem: top.elem + 1,
+                ..top
+            });
+        }
+        expand_stack(rules, new_stack, &mut out, &mut seen);
+    }
+    out
+}
+
+/// masks the tokens which would violate the grammar, the eos token is only allowed once the
+/// root rule is completed. a JSON schema can be turned into a grammar with
+/// `json_schema_to_gbnf()`.
+///
+/// the tokens which are not a complete utf8 char on their own (like the byte fallback
+/// tokens of a multi-byte char) are always masked.
+pub struct Grammar {
+    matcher: GrammarMatcher,
+    trie: Arc<VocabTrie>,
+    allowed: Vec<bool>,
+    eos_token: usize,
+}
+
+impl Grammar {
+    pub fn new(gbnf: &str, tokenizer: &BpeTokenizer) -> Result<Self> {
+        let rules = GrammarRules::parse(gbnf)?;
+        Ok(Self {
+            matcher: GrammarMatcher::new(rules),
+            trie: tokenizer.trie().clone(),
+            allowed: vec![false; tokenizer.vocab().len()],
+            eos_token: tokenizer.eos_token(),
+        })
+    }
+
+    pub fn from_json_schema(schema: &str, tokenizer: &BpeTokenizer) -> Result<Self> {
+        Self::new(&super::json_schema_to_gbnf(schema)?, tokenizer)
+    }
+
+    pub fn matcher(&self) -> &GrammarMatcher {
+        &self.matcher
+    }
+}
+
+impl LogitsProcessor for Grammar {
+    fn process(&mut self, candidates: &mut Candidates) -> Result<()> {
+        self.matcher.allowed_tokens(&self.trie, &mut self.allowed);
+        let accepting = self.matcher.is_accepting();
+        let (allowed, eos_token) = (&self.allowed, self.eos_token);
+        candidates.retain(|c| {
+            if c.token == eos_token {
+                return accepting;
+            }
+            allowed.get(c.token).copied().unwrap_or(false)
+        });
+        Ok(())
+    }
+
+    fn accept(&mut self, token: usize) -> Result<()> {
+        if token == self.eos_token {
+            return Ok(());
+        }
+        let accepted = match std::str::from_utf8(self.trie.token_bytes(token)) {
+            Ok(piece) => self.matcher.accept(piece),
+            Err(_) => false,
+        };
+        if !accepted {
+            return Err((
+                ErrorKind::BadInput,
+                format!("token {} violates the grammar", token),
+            )
+                .into());
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.matcher.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(src: &str) -> GrammarMatcher {
+        GrammarMatcher::new(GrammarRules::parse(src).unwrap())
+    }
+
+    #[test]
+    fn test_grammar_matcher() {
+        let src = r#"
+# a comment
+root   ::= answer "."
+answer ::= "yes" | "no" | [1-9] [0-9]{0,2}
+"#;
+        let mut m = matcher(src);
+        assert!(m.can_accept("yes."));
+        assert!(m.can_accept("n"));
+        assert!(!m.can_accept("maybe"));
+        assert!(m.can_accept("123."));
+        assert!(!m.can_accept("1234"));
+        assert!(!m.can_accept("0"));
+
+        assert!(m.accept("12"));
+        assert!(!m.is_accepting());
+        assert!(!m.accept("x"));
+        assert!(m.accept("."));
+        assert!(m.is_accepting());
+        assert!(m.is_finished());
+
+        m.reset();
+        assert!(m.can_accept("no."));
+    }
+
+    #[test]
+    fn test_grammar_repetitions_and_classes() {
+        let src = r#"
+root ::= ( item "," )* item?
+item ::= [^,\n"] | "\"" ( [a-z] | "\\" . )+ "\""
+"#;
+        let mut m = matcher(src);
+        assert!(m.is_accepting());
+        assert!(m.accept("a,b,\"x\\\"y\""));
+        assert!(m.is_accepting());
+        assert!(!m.can_accept("\n"));
+        assert!(m.accept(","));
+        assert!(m.is_accepting());
+
+        let m = matcher("root ::= \"\\u00e9\" [\\x41-\\x43]+");
+        assert!(m.can_accept("éABC"));
+        assert!(!m.can_accept("éD"));
+    }
+
+    #[test]
+    fn test_grammar_allowed_tokens() -> Result<()> {
+        let gl = crate::gguf::GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let tokens = gf
+            .metadata()
+            .get_string_array("tokenizer.ggml.tokens")
+            .unwrap()
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let scores = gf
+            .metadata()
+            .get_f32_array("tokenizer.ggml.scores")
+            .unwrap()
+            .to_vec();
+        let tk = BpeTokenizer::new(tokens, scores, 1, 2);
+
+        // the walk over the trie agrees with matching the tokens one by one
+        let mut m = matcher(r#"root ::= " " ( "é" | [a-z]+ ) [ ,.]"#);
+        let mut allowed = vec![false; tk.vocab().len()];
+        for text in ["", " ", "ab"] {
+            assert!(text.is_empty() || m.accept(text));
+            m.allowed_tokens(tk.trie(), &mut allowed);
+            for (token, allowed) in allowed.iter().enumerate() {
+                let expected = std::str::from_utf8(tk.trie().token_bytes(token))
+                    .map_or(false, |piece| m.can_accept(piece));
+                assert_eq!(
+                    *allowed,
+                    expected,
+                    "{:?} after {:?}",
+                    tk.vocab()[token],
+                    text
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_grammar_errors() {
+        assert!(GrammarRules::parse("answer ::= \"yes\"").is_err());
+        assert!(GrammarRules::parse("root ::= missing").is_err());
+        assert!(GrammarRules::parse("root ::= \"a").is_err());
+        assert!(GrammarRules::parse("root ::= root \"a\" | \"b\"").is_err());
+        assert!(GrammarRules::parse("root ::= x? root \"a\" | \"b\"\nx ::= \"x\"").is_err());
+        // right recursion is fine
+        assert!(GrammarRules::parse("root ::= \"a\" root | \"b\"").is_ok());
+        assert!(GrammarRules::parse("root ::= *").is_err());
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use serde_json::Value;
+
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+const PRIMITIVE_RULES: &[(&str, &str)] = &[
+    ("ws", r#"[ \t\n]{0,4}"#),
+    (
+        "string",
+        r#""\"" ( [^"\\\x00-\x1f] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F]{4} ) )* "\"""#,
+    ),
+    (
+        "number",
+        r#""-"? ( "0" | [1-9] [0-9]* ) ( "." [0-9]+ )? ( [eE] [-+]? [0-9]+ )?"#,
+    ),
+    ("integer", r#""-"? ( "0" | [1-9] [0-9]* )"#),
+    ("boolean", r#""true" | "false""#),
+    ("null", r#""null""#),
+    (
+        "value",
+        r#"object | array | string | number | boolean | null"#,
+    ),
+    (
+        "object",
+        r#""{" ws ( string ws ":" ws value ws ( "," ws string ws ":" ws value ws )* )? "}""#,
+    ),
+    ("array", r#""[" ws ( value ws ( "," ws value ws )* )? "]""#),
+];
+
+/// convert a JSON schema into a GBNF grammar, which accepts the JSON documents valid in the
+/// schema. the supported keywords are `type`, `properties`, `required`, `items`, `enum`,
+/// `const`, `anyOf`, `oneOf` and the local `$ref`s in `definitions` or `$defs`, the others
+/// are ignored.
+///
+/// the properties are generated in the order of the schema, the required ones first. when
+/// none of them is required, an optional property is only allowed after the previous one.
+pub fn json_schema_to_gbnf(schema: &str) -> Result<String> {
+    let schema: Value = serde_json::from_str(schema).map_err(|err| Error {
+        kind: ErrorKind::BadInput,
+        message: "failed to parse the json schema".to_string(),
+        cause: Some(Box::new(err)),
+    })?;
+    let mut converter = SchemaConverter {
+        root: &schema,
+        rules: vec![],
+        names: HashMap::new(),
+        refs: HashMap::new(),
+    };
+    let root = converter.visit(&schema, "root")?;
+    if root != "root" {
+        converter.add_rule("root", root);
+    }
+
+    let mut out = String::new();
+    for (name, body) in converter.rules.iter() {
+        writeln!(out, "{} ::= {}", name, body).unwrap();
+    }
+    for (name, body) in PRIMITIVE_RULES {
+        writeln!(out, "{} ::= {}", name, body).unwrap();
+    }
+    Ok(out)
+}
+
+struct SchemaConverter<'a> {
+    root: &'a Value,
+    rules: Vec<(String, String)>,
+    // the number of the rules generated with the name
+    names: HashMap<String, usize>,
+    // the rules generated for the $refs
+    refs: HashMap<String, String>,
+}
+
+impl<'a> SchemaConverter<'a> {
+    // returns the expression matching the schema, which is a rule name or a literal
+    fn visit(&mut self, schema: &'a Value, name: &str) -> Result<String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok("value".to_string()),
+            Value::Object(schema) => schema,
+            _ => return Err(unsupported(format!("schema {}", schema))),
+        };
+
+        if let Some(r) = schema.get("$ref").and_then(|r| r.as_str()) {
+            return self.visit_ref(r);
+        }
+        if let Some(c) = schema.get("const") {
+            return Ok(literal(c));
+        }
+        if let Some(values) = schema.get("enum").and_then(|e| e.as_array()) {
+            let alts = values.iter().map(literal).collect::<Vec<_>>();
+            return Ok(self.add_rule(name, alts.join(" | ")));
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(key).and_then(|s| s.as_array()) {
+                let alts = schemas
+                    .iter()
+                    .enumerate()
+                    .map(|(i, s)| self.visit(s, &format!("{}-{}", name, i)))
+                    .collect::<Result<Vec<_>>>()?;
+                return Ok(self.add_rule(name, alts.join(" | ")));
+            }
+        }
+
+        match schema.get("type") {
+            None => {
+                if schema.contains_key("properties") {
+                    self.visit_object(schema, name)
+                } else {
+                    Ok("value".to_string())
+                }
+            }
+            Some(Value::String(t)) => self.visit_type(schema, t, name),
+            Some(Value::Array(types)) => {
+                let alts = types
+                    .iter()
+                    .enumerate()
+                    .map(|(i, t)| match t.as_str() {
+                        Some(t) => self.visit_type(schema, t, &format!("{}-{}", name, i)),
+                        None => Err(unsupported(format!("type {}", t))),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(self.add_rule(name, alts.join(" | ")))
+            }
+            Some(t) => Err(unsupported(format!("type {}", t))),
+        }
+    }
+
+    fn visit_type(
+        &mut self,
+        schema: &'a serde_json::Map<String, Value>,
+        t: &str,
+        name: &str,
+    ) -> Result<String> {
+        match t {
+            "object" => self.visit_object(schema, name),
+            "array" => match schema.get("items") {
+                Some(items) => {
+                    let item = self.visit(items, &format!("{}-item", name))?;
+                    let body = format!(r#""[" ws ( {} ws ( "," ws {} ws )* )? "]""#, item, item);
+                    Ok(self.add_rule(name, body))
+                }
+                None => Ok("array".to_string()),
+            },
+            "string" | "number" | "integer" | "boolean" | "null" => Ok(t.to_string()),
+            _ => Err(unsupported(format!("type {}", t))),
+        }
+    }
+
+    fn visit_object(
+        &mut self,
+        schema: &'a serde_json::Map<String, Value>,
+        name: &str,
+    ) -> Result<String> {
+        let properties = match schema.get("properties").and_then(|p| p.as_object()) {
+            Some(properties) => properties,
+            None => return Ok("object".to_string()),
+        };
+        let required = schema
+            .get("required")
+            .and_then(|r| r.as_array())
+            .map(|r| r.iter().filter_map(|v| v.as_str()).collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        let mut props = vec![];
+        for (key, prop_schema) in properties.iter() {
+            let value = self.visit(prop_schema, &format!("{}-{}", name, sanitize(key)))?;
+            let kv = format!(
+                r#"{} ws ":" ws {} ws"#,
+                literal(&Value::String(key.clone())),
+                value
+            );
+            props.push((required.contains(&key.as_str()), kv));
+        }
+        // the required ones first
+        props.sort_by_key(|(required, _)| !required);
+
+        let mut body = String::from(r#""{" ws "#);
+        let n_required = props.iter().filter(|(r, _)| *r).count();
+        if n_required > 0 {
+            for (i, (_, kv)) in props.iter().enumerate() {
+                match (i == 0, i < n_required) {
+                    (true, _) => write!(body, "{} ", kv).unwrap(),
+                    (false, true) => write!(body, r#""," ws {} "#, kv).unwrap(),
+                    (false, false) => write!(body, r#"( "," ws {} )? "#, kv).unwrap(),
+                }
+            }
+        } else if !props.is_empty() {
+            // ( a ( "," ws b ( "," ws c )? )? )?
+            let mut tail = String::new();
+            for (_, kv) in props.iter().skip(1).rev() {
+                tail = format!(r#"( "," ws {} {})? "#, kv, tail);
+            }
+            write!(body, "( {} {})? ", props[0].1, tail).unwrap();
+        }
+        body.push_str(r#""}""#);
+        Ok(self.add_rule(name, body))
+    }
+
+    fn visit_ref(&mut self, r: &str) -> Result<String> {
+        if let Some(rule) = self.refs.get(r) {
+            return Ok(rule.clone());
+        }
+        let path = match r.strip_prefix("#/") {
+            Some(path) => path,
+            None => return Err(unsupported(format!("$ref {}", r))),
+        };
+        let mut target = self.root;
+        for part in path.split('/') {
+            target = match target.get(part) {
+                Some(t) => t,
+                None => return Err(unsupported(format!("$ref {}", r))),
+            };
+        }
+
+        // reserve the rule before visiting for the recursive schemas
+        let name = self.unique_name(&sanitize(path.rsplit('/').next().unwrap_or("ref")));
+        self.refs.insert(r.to_string(), name.clone());
+        let idx = self.rules.len();
+        self.rules.push((name.clone(), String::new()));
+        let body = self.visit(target, &name)?;
+        if self.rules[idx].1.is_empty() {
+            self.rules[idx].1 = body;
+        }
+        Ok(name)
+    }
+
+    fn add_rule(&mut self, name: &str, body: String) -> String {
+        let name = self.unique_name(name);
+        self.rules.push((name.clone(), body));
+        name
+    }
+
+    fn unique_name(&mut self, name: &str) -> String {
+        let n = self.names.entry(name.to_string()).or_insert(0);
+        *n += 1;
+        if *n == 1 && !PRIMITIVE_RULES.iter().any(|(p, _)| *p == name) {
+            name.to_string()
+        } else {
+            format!("{}-{}", name, n)
+        }
+    }
+}
+
+// the json text of the value as a GBNF literal
+fn literal(v: &Value) -> String {
+    let json = v.to_string();
+    let mut out = String::with_capacity(json.len() + 2);
+    out.push('"');
+    for c in json.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+fn unsupported(what: String) -> Error {
+    Error {
+        kind: ErrorKind::BadInput,
+        message: format!("json schema: unsupported {}", what),
+        cause: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::GrammarMatcher;
+    use crate::sampler::GrammarRules;
+
+    fn matcher(schema: &str) -> GrammarMatcher {
+        let gbnf = json_schema_to_gbnf(schema).unwrap();
+        GrammarMatcher::new(GrammarRules::parse(&gbnf).unwrap())
+    }
+
+    fn accepts(m: &GrammarMatcher, text: &str) -> bool {
+        let mut m = m.clone();
+        m.accept(text) && m.is_accepting()
+    }
+
+    #[test]
+    fn test_json_schema_object() {
+        let m = matcher(
+            r#"{
+                "type": "object",
+                "properties": {
+                    "name": {"type": "string"},
+                    "age": {"type": "integer"},
+                    "tags": {"type": "array", "items": {"enum": ["a", "b"]}}
+                },
+                "required": ["name", "tags"]
+            }"#,
+        );
+        assert!(accepts(&m, r#"{"name": "bob", "tags": ["a","b"]}"#));
+        assert!(accepts(&m, r#"{"name":"b\"o","tags":[],"age":-12}"#));
+        assert!(!accepts(&m, r#"{"name": "bob"}"#));
+        assert!(!accepts(&m, r#"{"name": "bob", "tags": ["c"]}"#));
+        assert!(!accepts(&m, r#"{"name": 1, "tags": []}"#));
+        assert!(!accepts(&m, r#"{"name": "bob", "tags": [], "age": 1.5}"#));
+    }
+
+    #[test]
+    fn test_json_schema_refs_and_any_of() {
+        let m = matcher(
+            r##"{
+                "$defs": {
+                    "node": {
+                        "type": "object",
+                        "properties": {
+                            "value": {"anyOf": [{"type": "number"}, {"type": "null"}]},
+                            "next": {"$ref": "#/$defs/node"}
+                        }
+                    }
+                },
+                "$ref": "#/$defs/node"
+            }"##,
+        );
+        assert!(accepts(&m, r#"{}"#));
+        assert!(accepts(&m, r#"{"value": 1.5e3}"#));
+        assert!(accepts(&m, r#"{"value": null, "next": {"value": 2}}"#));
+        assert!(!accepts(&m, r#"{"next": {}}"#));
+        assert!(!accepts(&m, r#"{"value": "x"}"#));
+
+        // no type accepts any json
+        let m = matcher("{}");
+        assert!(accepts(&m, r#"[1, {"a": [true, "x"]}, null]"#));
+        assert!(!accepts(&m, r#"[1,]"#));
+
+        assert!(json_schema_to_gbnf("{\"type\": \"date\"}").is_err());
+        assert!(json_schema_to_gbnf("{").is_err());
+    }
+}
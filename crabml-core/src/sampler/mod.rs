@@ -0,0 +1,27 @@
+mod api;
+mod chain;
+mod grammar;
+mod json_schema;
+mod samplers;
+
+pub use api::argmax;
+pub use api::argmax_with_epsilon;
+pub use api::log_sum_exp;
+pub use api::Candidate;
+pub use api::Candidates;
+pub use api::LogitsProcessor;
+pub use api::Sampler;
+pub use api::SamplerParam;
+pub use api::SamplerRng;
+pub use chain::SamplerChain;
+pub use grammar::Grammar;
+pub use grammar::GrammarMatcher;
+pub use grammar::GrammarRules;
+pub use json_schema::json_schema_to_gbnf;
+pub use samplers::Dist;
+pub use samplers::Greedy;
+pub use samplers::MirostatV2;
+pub use samplers::Penalties;
+pub use samplers::Temperature;
+pub use samplers::TopK;
+pub use samplers::TopP;
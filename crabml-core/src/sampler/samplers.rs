@@ -0,0 +1,522 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+use rand::Rng;
+
+use super::Candidates;
+use super::Sampler;
+use super::SamplerParam;
+use super::SamplerRng;
+use crate::error::Result;
+
+/// select the candidate with the highest logit, the lower token id wins on ties.
+pub struct Greedy;
+
+impl Sampler for Greedy {
+    fn apply(&mut self, candidates: &mut Candidates, _rng: &mut SamplerRng) -> Result<()> {
+        if let Some(token) = candidates.argmax() {
+            candidates.select(token);
+        }
+        Ok(())
+    }
+}
+
+/// sample from the distribution of the remaining candidates.
+pub struct Dist;
+
+impl Sampler for Dist {
+    fn apply(&mut self, candidates: &mut Candidates, rng: &mut SamplerRng) -> Result<()> {
+        candidates.softmax();
+        let coin: f32 = rng.gen_range(0.0..1.0);
+        if let Some(token) = sample_probs(candidates, coin) {
+            candidates.select(token);
+        }
+        Ok(())
+    }
+}
+
+/// scale the logits by 1 / temperature. the lower the temperature, the more deterministic
+/// the sampling. a temperature <= 0 only keeps the candidate with the highest logit.
+pub struct Temperature {
+    temperature: f32,
+}
+
+impl Temperature {
+    pub fn new(temperature: f32) -> Self {
+        Self { temperature }
+    }
+}
+
+impl Sampler for Temperature {
+    fn apply(&mut self, candidates: &mut Candidates, _rng: &mut SamplerRng) -> Result<()> {
+        if self.temperature <= 0.0 {
+            candidates.truncate(1);
+            return Ok(());
+        }
+        for c in candidates.items_mut() {
+            c.logit /= self.temperature;
+        }
+        Ok(())
+    }
+
+    fn set_param(&mut self, param: SamplerParam) -> bool {
+        match param {
+            SamplerParam::Temperature(v) => {
+                self.temperature = v;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// keep the k candidates with the highest logits, k = 0 keeps all of them.
+pub struct TopK {
+    k: usize,
+}
+
+impl TopK {
+    pub fn new(k: usize) -> Self {
+        Self { k }
+    }
+}
+
+impl Sampler for TopK {
+    fn apply(&mut self, candidates: &mut Candidates, _rng: &mut SamplerRng) -> Result<()> {
+        if self.k > 0 && self.k < candidates.len() {
+            candidates.truncate(self.k);
+        }
+        Ok(())
+    }
+
+    fn set_param(&mut self, param: SamplerParam) -> bool {
+        match param {
+            SamplerParam::TopK(v) => {
+                self.k = v;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// top-p sampling (or "nucleus sampling") keeps the smallest set of candidates whose
+/// cumulative probability exceeds p. This way we never sample tokens that have very
+/// low probabilities and are less likely to go "off the rails".
+pub struct TopP {
+    p: f32,
+}
+
+impl TopP {
+    pub fn new(p: f32) -> Self {
+        Self { p }
+    }
+}
+
+impl Sampler for TopP {
+    fn apply(&mut self, candidates: &mut Candidates, _rng: &mut SamplerRng) -> Result<()> {
+        if self.p <= 0.0 || self.p >= 1.0 {
+            return Ok(());
+        }
+
+        candidates.softmax();
+        let mut cumulative_prob = 0.0;
+        let mut n = candidates.len();
+        for (i, c) in candidates.items().iter().enumerate() {
+            cumulative_prob += c.prob;
+            if cumulative_prob >= self.p {
+                n = i + 1;
+                break;
+            }
+        }
+        candidates.truncate(n);
+        Ok(())
+    }
+
+    fn set_param(&mut self, param: SamplerParam) -> bool {
+        match param {
+            SamplerParam::TopP(v) => {
+                self.p = v;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// the last n tokens of a sequence with the count of every token in them, updated on every
+/// token instead of recounted on every step.
+#[derive(Debug, Clone, Default)]
+struct PenaltyWindow {
+    last_n: usize,
+    history: VecDeque<usize>,
+    counts: HashMap<usize, usize>,
+}
+
+impl PenaltyWindow {
+    fn new(last_n: usize) -> Self {
+        Self {
+            last_n,
+            // the whole context of usize::MAX grows on demand
+            history: VecDeque::with_capacity(last_n.min(1024)),
+            counts: HashMap::new(),
+        }
+    }
+
+    fn push(&mut self, token: usize) {
+        if self.last_n == 0 {
+            return;
+        }
+        if self.history.len() == self.last_n {
+            let evicted = self.history.pop_front().unwrap();
+            if let Some(count) = self.counts.get_mut(&evicted) {
+                *count -= 1;
+                if *count == 0 {
+                    self.counts.remove(&evicted);
+                }
+            }
+        }
+        self.history.push_back(token);
+        *self.counts.entry(token).or_insert(0) += 1;
+    }
+
+    fn clear(&mut self) {
+        self.history.clear();
+        self.counts.clear();
+    }
+}
+
+/// penalize the tokens which appeared in the last n tokens of the context, like the
+/// `repeat_last_n` of llama.cpp: 0 disables the penalties, and `Penalties::WHOLE_CONTEXT`
+/// takes all the tokens of the context.
+///
+/// - repeat: the positive logits are divided by it, and the negative ones are multiplied by it.
+/// - frequency: subtracted from the logit once per occurrence.
+/// - presence: subtracted from the logit once if the token occurred at all.
+///
+/// the history belongs to one sequence, clone it to fork the sequence like a beam, and
+/// keep one per sequence in a batch.
+#[derive(Debug, Clone)]
+pub struct Penalties {
+    repeat: f32,
+    frequency: f32,
+    presence: f32,
+    window: PenaltyWindow,
+}
+
+impl Penalties {
+    pub const WHOLE_CONTEXT: usize = usize::MAX;
+
+    pub fn new(last_n: usize, repeat: f32, frequency: f32, presence: f32) -> Self {
+        Self {
+            repeat,
+            frequency,
+            presence,
+            window: PenaltyWindow::new(last_n),
+        }
+    }
+
+    /// penalize the raw logits of the sequence in place, only the tokens in the window are
+    /// touched.
+    pub fn apply_logits(&self, logits: &mut [f32]) {
+        if self.is_noop() {
+            return;
+        }
+        for (token, count) in self.window.counts.iter() {
+            if let Some(logit) = logits.get_mut(*token) {
+                *logit = self.penalize(*logit, *count);
+            }
+        }
+    }
+
+    fn penalize(&self, logit: f32, count: usize) -> f32 {
+        let logit = if logit > 0.0 {
+            logit / self.repeat
+        } else {
+            logit * self.repeat
+        };
+        logit - (count as f32 * self.frequency + self.presence)
+    }
+}
+
+impl Sampler for Penalties {
+    fn apply(&mut self, candidates: &mut Candidates, _rng: &mut SamplerRng) -> Result<()> {
+        if self.window.counts.is_empty() {
+            return Ok(());
+        }
+
+        for (token, count) in self.window.counts.iter() {
+            let logit = match candidates.get_mut(*token) {
+                Some(c) => &mut c.logit,
+                None => continue,
+            };
+            *logit = self.penalize(*logit, *count);
+        }
+        candidates.mark_unsorted();
+        Ok(())
+    }
+
+    fn is_noop(&self) -> bool {
+        self.window.last_n == 0
+            || (self.repeat == 1.0 && self.frequency == 0.0 && self.presence == 0.0)
+    }
+
+    fn accept(&mut self, token: usize) {
+        self.window.push(token);
+    }
+
+    fn reset(&mut self) {
+        self.window.clear();
+    }
+}
+
+/// mirostat v2 keeps the surprise (-log2(p)) of the sampled tokens around the target tau,
+/// by dropping the candidates more surprising than mu, and learning mu with the rate eta.
+///
+/// see: https://arxiv.org/abs/2007.14966
+pub struct MirostatV2 {
+    tau: f32,
+    eta: f32,
+    mu: f32,
+}
+
+impl MirostatV2 {
+    pub fn new(tau: f32, eta: f32) -> Self {
+        Self {
+            tau,
+            eta,
+            mu: 2.0 * tau,
+        }
+    }
+}
+
+impl Sampler for MirostatV2 {
+    fn apply(&mut self, candidates: &mut Candidates, rng: &mut SamplerRng) -> Result<()> {
+        candidates.softmax();
+        let n = candidates
+            .items()
+            .iter()
+            .position(|c| -c.prob.log2() > self.mu)
+            .unwrap_or(candidates.len());
+        candidates.truncate(n);
+        candidates.softmax();
+
+        let coin: f32 = rng.gen_range(0.0..1.0);
+        if let Some(token) = sample_probs(candidates, coin) {
+            let prob = candidates
+                .items()
+                .iter()
+                .find(|c| c.token == token)
+                .map(|c| c.prob)
+                .unwrap_or(1.0);
+            self.mu -= self.eta * (-prob.log2() - self.tau);
+            candidates.select(token);
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.mu = 2.0 * self.tau;
+    }
+}
+
+fn sample_probs(candidates: &Candidates, coin: f32) -> Option<usize> {
+    // coin is a random number in [0, 1), the probabilities must sum to 1. the candidates of
+    // no probability are never taken, even on a coin of 0
+    let mut cdf = 0.0;
+    let mut last = None;
+    for c in candidates.items() {
+        if c.prob.is_nan() || c.prob <= 0.0 {
+            continue;
+        }
+        cdf += c.prob;
+        last = Some(c.token);
+        if cdf > coin {
+            return last;
+        }
+    }
+    // in case of rounding errors
+    last.or_else(|| candidates.items().first().map(|c| c.token))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn apply(sampler: &mut impl Sampler, logits: &[f32]) -> Result<Candidates> {
+        let mut candidates = Candidates::from_logits(logits);
+        let mut rng = SamplerRng::seed_from_u64(0);
+        sampler.apply(&mut candidates, &mut rng)?;
+        Ok(candidates)
+    }
+
+    fn tokens(candidates: &Candidates) -> Vec<usize> {
+        candidates.items().iter().map(|c| c.token).collect()
+    }
+
+    #[test]
+    fn test_top_k() -> Result<()> {
+        let candidates = apply(&mut TopK::new(2), &[0.1, 0.4, 0.3, 0.4])?;
+        assert_eq!(tokens(&candidates), vec![1, 3]);
+
+        let candidates = apply(&mut TopK::new(0), &[0.1, 0.4, 0.3])?;
+        assert_eq!(candidates.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_top_p() -> Result<()> {
+        // probs: 0.5, 0.25, 0.125, 0.125
+        let logits = [2f32.ln(), 1f32.ln(), 0.5f32.ln(), 0.5f32.ln()];
+        let candidates = apply(&mut TopP::new(0.7), &logits)?;
+        assert_eq!(tokens(&candidates), vec![0, 1]);
+
+        let candidates = apply(&mut TopP::new(0.3), &logits)?;
+        assert_eq!(tokens(&candidates), vec![0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_temperature() -> Result<()> {
+        let candidates = apply(&mut Temperature::new(0.5), &[1.0, -2.0])?;
+        assert_eq!(candidates.items()[0].logit, 2.0);
+        assert_eq!(candidates.items()[1].logit, -4.0);
+
+        let candidates = apply(&mut Temperature::new(0.0), &[1.0, 3.0, 2.0])?;
+        assert_eq!(tokens(&candidates), vec![1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_penalties() -> Result<()> {
+        let mut sampler = Penalties::new(2, 2.0, 0.5, 0.25);
+        sampler.accept(0);
+        sampler.accept(1);
+        sampler.accept(1);
+
+        // the first token 0 is out of the window
+        let candidates = apply(&mut sampler, &[1.0, 2.0, -1.0])?;
+        let logits = candidates
+            .items()
+            .iter()
+            .map(|c| c.logit)
+            .collect::<Vec<_>>();
+        assert_eq!(logits, vec![1.0, 2.0 / 2.0 - 2.0 * 0.5 - 0.25, -1.0]);
+
+        // the same on the raw logits, and on the candidates out of the token order
+        let mut raw = vec![1.0, 2.0, -1.0];
+        sampler.apply_logits(&mut raw);
+        assert_eq!(raw, logits);
+        let mut candidates = Candidates::from_logits(&[1.0, 2.0, -1.0]);
+        candidates.sort();
+        sampler.apply(&mut candidates, &mut SamplerRng::seed_from_u64(0))?;
+        candidates.sort();
+        assert_eq!(candidates.items()[0].token, 0);
+
+        // a forked history goes on its own
+        let mut fork = sampler.clone();
+        fork.accept(2);
+        fork.accept(2);
+        let mut raw = vec![1.0, 2.0, -1.0];
+        fork.apply_logits(&mut raw);
+        assert_eq!(raw, vec![1.0, 2.0, -1.0 * 2.0 - 2.0 * 0.5 - 0.25]);
+
+        // the whole context is never evicted
+        let mut sampler = Penalties::new(Penalties::WHOLE_CONTEXT, 1.0, 1.0, 0.0);
+        (0..3000).for_each(|i| sampler.accept(i % 2));
+        let mut raw = vec![0.0, 0.0];
+        sampler.apply_logits(&mut raw);
+        assert_eq!(raw, vec![-1500.0, -1500.0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mirostat_v2() -> Result<()> {
+        let logits = (0..100).map(|i| -(i as f32) * 0.1).collect::<Vec<_>>();
+        let mut sampler = MirostatV2::new(3.0, 0.1);
+        let mut rng = SamplerRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let mut candidates = Candidates::from_logits(&logits);
+            sampler.apply(&mut candidates, &mut rng)?;
+            let token = candidates.selected().unwrap();
+            // the candidates more surprising than mu are dropped
+            assert!(candidates.items().iter().any(|c| c.token == token));
+            assert!(candidates.len() < logits.len());
+        }
+        assert!(sampler.mu > 0.0 && sampler.mu < 2.0 * 3.0 + 1.0);
+
+        sampler.reset();
+        assert_eq!(sampler.mu, 6.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_greedy_ties() -> Result<()> {
+        let candidates = apply(&mut Greedy, &[0.5, f32::NAN, 2.0, 2.0, -1.0])?;
+        assert_eq!(candidates.selected(), Some(2));
+
+        // the order of the candidates does not matter
+        let mut candidates = Candidates::from_logits(&[1.0, 3.0, 3.0]);
+        candidates.items_mut().reverse();
+        Greedy.apply(&mut candidates, &mut SamplerRng::seed_from_u64(0))?;
+        assert_eq!(candidates.selected(), Some(1));
+
+        // the logits within epsilon of the highest one tie
+        let mut candidates = Candidates::from_logits(&[1.0, 3.0, 3.0 + 1e-6]);
+        candidates.set_tie_epsilon(1e-5);
+        Greedy.apply(&mut candidates, &mut SamplerRng::seed_from_u64(0))?;
+        assert_eq!(candidates.selected(), Some(1));
+
+        let candidates = apply(&mut Greedy, &[f32::NAN, f32::NAN])?;
+        assert_eq!(candidates.selected(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_softmax_edge_cases() -> Result<()> {
+        let probs = |logits: &[f32]| {
+            let mut candidates = Candidates::from_logits(logits);
+            candidates.softmax();
+            let mut probs = vec![0.0; logits.len()];
+            for c in candidates.items() {
+                probs[c.token] = c.prob;
+            }
+            probs
+        };
+        // NaN sorts below -inf and takes nothing
+        let mut candidates = Candidates::from_logits(&[f32::NAN, f32::NEG_INFINITY, 1.0]);
+        candidates.sort();
+        assert_eq!(tokens(&candidates), vec![2, 1, 0]);
+        assert_eq!(probs(&[f32::NAN, 0.0, 0.0]), vec![0.0, 0.5, 0.5]);
+        assert_eq!(probs(&[1.0, f32::INFINITY, f32::INFINITY]), vec![
+            0.0, 0.5, 0.5
+        ]);
+        assert_eq!(
+            probs(&[f32::NAN, f32::NEG_INFINITY, f32::NEG_INFINITY]),
+            vec![0.0, 1.0, 0.0]
+        );
+
+        // the tokens of no probability are never sampled, even on the coin of 0
+        let mut candidates = Candidates::from_logits(&[f32::NEG_INFINITY, f32::NAN, 0.0]);
+        candidates.softmax();
+        assert_eq!(sample_probs(&candidates, 0.0), Some(2));
+        assert_eq!(sample_probs(&candidates, 0.999_999_9), Some(2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dist() -> Result<()> {
+        let mut rng = SamplerRng::seed_from_u64(0);
+        let mut counts = [0; 2];
+        for _ in 0..1000 {
+            // probs: 0.75, 0.25
+            let mut candidates = Candidates::from_logits(&[3f32.ln(), 0.0]);
+            Dist.apply(&mut candidates, &mut rng)?;
+            counts[candidates.selected().unwrap()] += 1;
+        }
+        assert!(counts[0] > 650 && counts[0] < 850, "{:?}", counts);
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
 use super::strider::TensorStrider;
+use crate::error::ErrorKind;
 use crate::error::Result;
 use crate::gguf::GGMLType;
 
@@ -8,11 +9,78 @@ pub enum RopeMode {
     Neox,
 }
 
+/// the epilogue applied on every dot product of a batch_matmul before it's written out,
+/// like scaling the attention scores by 1/sqrt(head_dim) and soft-capping them into
+/// (-cap, cap) by cap * tanh(x / cap) like Gemma 2, without another pass over the scores.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MatmulEpilogue {
+    pub scale: f32,
+    pub soft_cap: Option<f32>,
+    /// saturates the results into [-clamp, clamp] after the soft cap, an inf from an
+    /// overflowed accumulator ends up on the bound, NaN stays NaN.
+    pub clamp: Option<f32>,
+}
+
+impl Default for MatmulEpilogue {
+    fn default() -> Self {
+        Self {
+            scale: 1.0,
+            soft_cap: None,
+            clamp: None,
+        }
+    }
+}
+
+impl MatmulEpilogue {
+    pub fn new(scale: f32, soft_cap: Option<f32>) -> Self {
+        Self {
+            scale,
+            soft_cap,
+            clamp: None,
+        }
+    }
+
+    pub fn with_clamp(mut self, clamp: f32) -> Self {
+        self.clamp = Some(clamp);
+        self
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.scale == 1.0 && self.soft_cap.is_none() && self.clamp.is_none()
+    }
+
+    #[inline]
+    pub fn apply(&self, x: f32) -> f32 {
+        let x = x * self.scale;
+        let x = match self.soft_cap {
+            Some(cap) => cap * (x / cap).tanh(),
+            None => x,
+        };
+        match self.clamp {
+            Some(clamp) => x.clamp(-clamp, clamp),
+            None => x,
+        }
+    }
+}
+
+/// what the ops of a forward pass are for, so a device can run the interactive decode and
+/// the bulk work like a long prefill on separate threads.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub enum TaskTag {
+    /// the tokens generated one by one, waited on by a user.
+    #[default]
+    Decode,
+    /// the prompt of a request, forwarded in batches.
+    Prefill,
+    /// the jobs nobody waits on interactively, like embedding a corpus.
+    Background,
+}
+
 pub trait Tensor: Sized + Clone {
     type Device: Clone;
 
-    /// alloc an owned tensor, only used on storing activations and kv caches.
-    /// only F32 and F16 are supported.
+    /// alloc an owned tensor, only used on storing activations and kv caches, the
+    /// integer dtypes hold the token ids, positions and masks.
     fn alloc(shape: &[usize], dtype: GGMLType, device: Self::Device) -> Result<Self>;
 
     /// resize the tensor to a smaller size, the underlying storage is not changed,
@@ -22,6 +90,16 @@ pub trait Tensor: Sized + Clone {
 
     fn dtype(&self) -> GGMLType;
 
+    /// the dtype the hidden states are allocated in on the device, f32 unless the device
+    /// runs the forward pass in a lower precision.
+    fn activation_dtype(_device: &Self::Device) -> GGMLType {
+        GGMLType::F32
+    }
+
+    /// tag the ops submitted to the device from now on, the runner tags every forward pass
+    /// by its batch size. the devices without the separate pools ignore it.
+    fn set_task_tag(_device: &Self::Device, _tag: TaskTag) {}
+
     fn with_strider(self, strider: TensorStrider) -> Result<Self>;
 
     fn with_name(self, name: String) -> Self;
@@ -42,15 +120,75 @@ pub trait Tensor: Sized + Clone {
 
     fn export(&self, buf: &mut [f32]) -> Result<()>;
 
+    /// create an I32 tensor like the token ids or the positions of a batch.
+    fn new_i32(data: &[i32], shape: &[usize], device: Self::Device) -> Result<Self>;
+
+    /// create an F32 tensor from the host, like a bias passed by the caller.
+    fn new_f32(data: &[f32], shape: &[usize], device: Self::Device) -> Result<Self>;
+
+    fn export_i32(&self, buf: &mut [i32]) -> Result<()>;
+
+    /// gather the rows of a 2d src tensor by the ids in a 1d I32 tensor, like looking up
+    /// the token embeddings of a batch.
+    fn get_rows(&mut self, src: &Self, ids: &Self) -> Result<()>;
+
     /// duplicate the tensor and the underlying storage
     fn dup(&self) -> Result<Self>;
 
-    fn rope_inplace(self, mode: RopeMode, pos: usize, rope_dims: usize) -> Result<Self>;
+    /// rotate the first rope_dims of each head, the theta of the i-th pair is
+    /// pos * freq_base ^ (-2i / rope_dims).
+    fn rope_inplace(
+        self,
+        mode: RopeMode,
+        pos: usize,
+        rope_dims: usize,
+        freq_base: f32,
+    ) -> Result<Self>;
+
+    /// like `rope_inplace()`, but the i-th row of the batch is rotated at positions[i], the
+    /// positions can go backwards or jump, like the grouped positions of self-extend. the
+    /// devices without the kernel only take the contiguous positions.
+    fn rope_positions_inplace(
+        self,
+        mode: RopeMode,
+        positions: &[usize],
+        rope_dims: usize,
+        freq_base: f32,
+    ) -> Result<Self> {
+        let pos = positions.first().copied().unwrap_or(0);
+        if positions.iter().enumerate().any(|(i, p)| *p != pos + i) {
+            return Err((
+                ErrorKind::NotImplemented,
+                "the device only rotates the contiguous positions",
+            )
+                .into());
+        }
+        self.rope_inplace(mode, pos, rope_dims, freq_base)
+    }
 
     fn rms_norm_inplace(self, eps: f32) -> Result<Self>;
 
+    /// normalize the last dim to zero mean and unit variance, without the affine weights.
+    fn layer_norm_inplace(self, eps: f32) -> Result<Self>;
+
     fn softmax_inplace(self, axis: usize) -> Result<Self>;
 
+    /// mask the attention scores of the future positions before softmax, the attention
+    /// scores are in the shape of (n_heads, n_batch, seq). with a sliding window, the
+    /// positions which are sliding_window or more tokens behind the query are masked too.
+    fn causal_mask_inplace(self, sliding_window: Option<usize>) -> Result<Self>;
+
+    /// mask the attention scores by a block mask of (n_batch, n_block) over the last n_block
+    /// positions, false masks the position from the query. it's applied after the causal
+    /// mask, like the tree attention mask of the tokens of a tree in a verification batch.
+    fn block_mask_inplace(self, _mask: &[bool]) -> Result<Self> {
+        Err((
+            ErrorKind::NotImplemented,
+            "the device does not support the block mask",
+        )
+            .into())
+    }
+
     fn silu_inplace(self) -> Result<Self>;
 
     fn gelu_inplace(self) -> Result<Self>;
@@ -65,5 +203,16 @@ pub trait Tensor: Sized + Clone {
 
     fn matmul_vec(&self, y: &Self) -> Result<Self>;
 
+    /// like matmul_vec, but the result is added into acc instead of a new tensor, which
+    /// fuses the residual connection into the matmul. acc is in the shape of the output.
+    fn matmul_vec_acc(&self, y: &Self, acc: Self) -> Result<Self>;
+
     fn batch_matmul(&self, y: &Self) -> Result<Self>;
+
+    /// like batch_matmul, but the result is added into acc instead of a new tensor.
+    fn batch_matmul_acc(&self, y: &Self, acc: Self) -> Result<Self>;
+
+    /// like batch_matmul, but every dot product goes through the epilogue on its way out,
+    /// like the attention scores.
+    fn batch_matmul_epilogue(&self, y: &Self, epilogue: MatmulEpilogue) -> Result<Self>;
 }
@@ -1,6 +1,9 @@
 use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
 
+use super::trace::TraceRecorder;
+use super::trace::TraceSpan;
+
 /// stores the metrics on the tensor's privimives
 #[derive(Debug, Default, Clone)]
 pub struct TensorMetrics {
@@ -27,9 +30,61 @@ pub struct TensorMetrics {
     pub contiguous_walltime: TimeMetric,
     pub batch_matmul_rowwise_walltime: TimeMetric,
     pub batch_matmul_colwise_walltime: TimeMetric,
+    pub trace: TraceRecorder,
 }
 
 impl TensorMetrics {
+    /// besides summing up the walltime, records every tracked op as a span on the trace.
+    pub fn with_trace(mut self, trace: TraceRecorder) -> Self {
+        for (category, name, metric) in self.named_metrics_mut() {
+            metric.category = category;
+            metric.name = name;
+            metric.trace = trace.clone();
+        }
+        self.trace = trace;
+        self
+    }
+
+    fn named_metrics_mut(&mut self) -> Vec<(&'static str, &'static str, &mut TimeMetric)> {
+        vec![
+            ("op", "rms_norm", &mut self.rms_norm_walltime),
+            ("op", "add", &mut self.add_walltime),
+            ("runner", "total", &mut self.total_walltime),
+            ("op", "mul", &mut self.mul_walltime),
+            ("op", "rope", &mut self.rope_walltime),
+            ("op", "softmax", &mut self.softmax_walltime),
+            ("op", "activate", &mut self.activate_walltime),
+            ("op", "matmul", &mut self.matmul_walltime),
+            ("op", "dequantize", &mut self.dequantize_walltime),
+            ("op", "matmul_quantize", &mut self.matmul_quantize_walltime),
+            (
+                "op",
+                "batch_matmul_quantize",
+                &mut self.batch_matmul_quantize_walltime,
+            ),
+            ("op", "export", &mut self.export_walltime),
+            ("op", "batch_matmul", &mut self.batch_matmul_walltime),
+            ("op", "alloc", &mut self.alloc_walltime),
+            ("runner", "sample", &mut self.sample_walltime),
+            ("runner", "forward", &mut self.forward_walltime),
+            ("runner", "save_kv_cache", &mut self.save_kvcache_walltime),
+            ("op", "copy", &mut self.copy_from_walltime),
+            ("op", "concatenate", &mut self.concatenate_walltime),
+            ("op", "dup", &mut self.dup_walltime),
+            ("op", "contiguous", &mut self.contiguous_walltime),
+            (
+                "op",
+                "batch_matmul_rowwise",
+                &mut self.batch_matmul_rowwise_walltime,
+            ),
+            (
+                "op",
+                "batch_matmul_colwise",
+                &mut self.batch_matmul_colwise_walltime,
+            ),
+        ]
+    }
+
     pub fn reset(&self) {
         self.rms_norm_walltime.reset();
         self.add_walltime.reset();
@@ -145,17 +200,22 @@ impl TensorMetrics {
 #[derive(Clone, Debug, Default)]
 pub struct TimeMetric {
     pub inner: Arc<AtomicU64>,
+    category: &'static str,
+    name: &'static str,
+    trace: TraceRecorder,
 }
 
 pub struct TimeMetricGuard {
     m: TimeMetric,
     start_at: std::time::Instant,
+    _span: TraceSpan,
 }
 
 impl TimeMetric {
     pub fn new() -> Self {
         Self {
             inner: Arc::new(AtomicU64::new(0)),
+            ..Default::default()
         }
     }
 
@@ -171,6 +231,7 @@ impl TimeMetric {
         TimeMetricGuard {
             m: self.clone(),
             start_at: std::time::Instant::now(),
+            _span: self.trace.span(self.category, self.name),
         }
     }
 }
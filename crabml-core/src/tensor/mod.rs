@@ -1,8 +1,35 @@
+#[cfg(feature = "std")]
 mod api;
+#[cfg(feature = "std")]
 pub mod metrics;
+#[cfg(feature = "std")]
+mod moe;
+#[cfg(feature = "std")]
+pub mod profile;
 mod strider;
+#[cfg(feature = "std")]
+pub mod trace;
 
+#[cfg(feature = "std")]
+pub use api::MatmulEpilogue;
+#[cfg(feature = "std")]
 pub use api::RopeMode;
+#[cfg(feature = "std")]
+pub use api::TaskTag;
+#[cfg(feature = "std")]
 pub use api::Tensor;
+#[cfg(feature = "std")]
 pub use metrics::TensorMetrics;
+#[cfg(feature = "std")]
+pub use moe::ExpertRoutes;
+#[cfg(feature = "std")]
+pub use moe::MoeGating;
+#[cfg(feature = "std")]
+pub use moe::MoeRouter;
+#[cfg(feature = "std")]
+pub use profile::OpProfiler;
+#[cfg(feature = "std")]
+pub use profile::ProfileReport;
 pub use strider::TensorStrider;
+#[cfg(feature = "std")]
+pub use trace::TraceRecorder;
@@ -0,0 +1,200 @@
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+/// how the gate weights of the selected experts are derived from the router logits.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MoeGating {
+    /// take the softmax over all the experts, then pick the top-k. the weights are the
+    /// probabilities of the picked ones, which do not sum to 1 unless `normalize` is set,
+    /// like `norm_topk_prob` on Qwen-MoE.
+    SoftmaxTopK { normalize: bool },
+    /// pick the top-k logits, then take the softmax over them, like Mixtral. it's the same
+    /// as `SoftmaxTopK { normalize: true }`, but skips the exp of the unpicked experts.
+    TopKSoftmax,
+}
+
+/// picks the experts of each token from the router logits of (n_tokens, n_experts).
+#[derive(Debug, Clone)]
+pub struct MoeRouter {
+    n_experts: usize,
+    top_k: usize,
+    gating: MoeGating,
+}
+
+/// the picked experts of each token, in the order of the logits desc.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpertRoutes {
+    pub top_k: usize,
+    /// (n_tokens, top_k)
+    pub experts: Vec<usize>,
+    /// (n_tokens, top_k)
+    pub weights: Vec<f32>,
+}
+
+impl MoeRouter {
+    pub fn new(n_experts: usize, top_k: usize, gating: MoeGating) -> Self {
+        Self {
+            n_experts,
+            top_k,
+            gating,
+        }
+    }
+
+    pub fn n_experts(&self) -> usize {
+        self.n_experts
+    }
+
+    pub fn top_k(&self) -> usize {
+        self.top_k
+    }
+
+    /// the lower expert id wins on a tie, and the NaN logits are never picked before the
+    /// others.
+    pub fn route(&self, logits: &[f32]) -> Result<ExpertRoutes> {
+        if self.top_k == 0 || self.top_k > self.n_experts {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "top_k should be in 1..={}, but got {}",
+                    self.n_experts, self.top_k
+                ),
+            )
+                .into());
+        }
+        if logits.len() % self.n_experts != 0 {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "the router logits of {} are not a multiple of {} experts",
+                    logits.len(),
+                    self.n_experts
+                ),
+            )
+                .into());
+        }
+
+        let n_tokens = logits.len() / self.n_experts;
+        let mut routes = ExpertRoutes {
+            top_k: self.top_k,
+            experts: Vec::with_capacity(n_tokens * self.top_k),
+            weights: Vec::with_capacity(n_tokens * self.top_k),
+        };
+        let mut order = Vec::with_capacity(self.n_experts);
+        for row in logits.chunks_exact(self.n_experts) {
+            order.clear();
+            order.extend(0..self.n_experts);
+            // a stable sort keeps the lower id first on a tie
+            order.sort_by(|a, b| key(row[*b]).total_cmp(&key(row[*a])));
+            let picked = &order[..self.top_k];
+
+            let weights = match self.gating {
+                MoeGating::SoftmaxTopK { normalize } => {
+                    let max = key(row[order[0]]);
+                    let sum = row.iter().map(|x| (key(*x) - max).exp()).sum::<f32>();
+                    let mut weights = picked
+                        .iter()
+                        .map(|e| (key(row[*e]) - max).exp() / sum)
+                        .collect::<Vec<_>>();
+                    if normalize {
+                        let total = weights.iter().sum::<f32>();
+                        weights.iter_mut().for_each(|w| *w /= total);
+                    }
+                    weights
+                }
+                MoeGating::TopKSoftmax => {
+                    let max = key(row[picked[0]]);
+                    let exps = picked
+                        .iter()
+                        .map(|e| (key(row[*e]) - max).exp())
+                        .collect::<Vec<_>>();
+                    let sum = exps.iter().sum::<f32>();
+                    exps.iter().map(|x| x / sum).collect()
+                }
+            };
+            routes.experts.extend_from_slice(picked);
+            routes.weights.extend(weights);
+        }
+        Ok(routes)
+    }
+}
+
+impl ExpertRoutes {
+    pub fn n_tokens(&self) -> usize {
+        self.experts.len() / self.top_k
+    }
+
+    /// the picked experts and their weights of the token.
+    pub fn token(&self, i: usize) -> (&[usize], &[f32]) {
+        let range = i * self.top_k..(i + 1) * self.top_k;
+        (&self.experts[range.clone()], &self.weights[range])
+    }
+
+    /// the (token, weight) pairs routed to each expert, to run an expert once on all of its
+    /// tokens in the batch.
+    pub fn group_by_expert(&self, n_experts: usize) -> Vec<Vec<(usize, f32)>> {
+        let mut groups = vec![vec![]; n_experts];
+        for (i, (expert, weight)) in self.experts.iter().zip(&self.weights).enumerate() {
+            groups[*expert].push((i / self.top_k, *weight));
+        }
+        groups
+    }
+}
+
+// the NaN logits are ranked as -inf
+fn key(x: f32) -> f32 {
+    if x.is_nan() { f32::NEG_INFINITY } else { x }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_moe_router() -> Result<()> {
+        let logits = [1.0, 2.0, 3.0, 4.0, 0.5, f32::NAN, 0.5, -1.0];
+
+        // softmax([1, 2, 3, 4]) = [0.0320586, 0.0871443, 0.2368828, 0.6439143]
+        let router = MoeRouter::new(4, 2, MoeGating::SoftmaxTopK { normalize: false });
+        let routes = router.route(&logits)?;
+        assert_eq!(routes.n_tokens(), 2);
+        let (experts, weights) = routes.token(0);
+        assert_eq!(experts, &[3, 2]);
+        assert_relative_eq!(weights[0], 0.6439143, epsilon = 1e-6);
+        assert_relative_eq!(weights[1], 0.2368828, epsilon = 1e-6);
+        // the tie goes to the lower id, the NaN is never picked
+        assert_eq!(routes.token(1).0, &[0, 2]);
+
+        // softmax([4, 3]) = [0.7310586, 0.2689414]
+        for gating in [
+            MoeGating::SoftmaxTopK { normalize: true },
+            MoeGating::TopKSoftmax,
+        ] {
+            let routes = MoeRouter::new(4, 2, gating).route(&logits)?;
+            let (experts, weights) = routes.token(0);
+            assert_eq!(experts, &[3, 2]);
+            assert_relative_eq!(weights[0], 0.7310586, epsilon = 1e-6);
+            assert_relative_eq!(weights[1], 0.2689414, epsilon = 1e-6);
+            assert_eq!(routes.token(1).1, &[0.5, 0.5]);
+        }
+
+        let groups = routes.group_by_expert(4);
+        assert_eq!(groups[0], vec![(1, routes.weights[2])]);
+        assert!(groups[1].is_empty());
+        assert_eq!(groups[2].len(), 2);
+        assert_eq!(groups[3], vec![(0, routes.weights[0])]);
+
+        assert!(
+            MoeRouter::new(4, 5, MoeGating::TopKSoftmax)
+                .route(&logits)
+                .is_err()
+        );
+        assert!(
+            MoeRouter::new(3, 2, MoeGating::TopKSoftmax)
+                .route(&logits)
+                .is_err()
+        );
+        Ok(())
+    }
+}
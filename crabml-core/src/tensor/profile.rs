@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use super::trace::TraceRecorder;
+use super::trace::TraceSpan;
+
+type OpProfiles = Arc<Mutex<HashMap<&'static str, OpProfile>>>;
+
+/// records the wall time, call count, the estimated FLOPs and the bytes moved of every
+/// primitive, to find out which op is the bottleneck.
+///
+/// the profiler is disabled by default, like the TraceRecorder, and tracking an op on a
+/// disabled profiler costs nothing but a branch.
+#[derive(Debug, Clone, Default)]
+pub struct OpProfiler {
+    inner: Option<OpProfiles>,
+    trace: TraceRecorder,
+}
+
+/// the accumulated numbers of an op.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OpProfile {
+    pub name: &'static str,
+    pub calls: u64,
+    pub walltime: Duration,
+    /// a rough estimate, like 2 * m * k for a gemv, the exp and div are counted as 1.
+    pub flops: u64,
+    /// the bytes of the inputs read and the outputs written, in the dtype of the tensors.
+    pub bytes: u64,
+}
+
+pub struct OpProfileGuard {
+    inner: Option<(OpProfiles, Instant)>,
+    name: &'static str,
+    flops: u64,
+    bytes: u64,
+    _span: TraceSpan,
+}
+
+impl OpProfiler {
+    pub fn new() -> Self {
+        Self {
+            inner: Some(Arc::new(Mutex::new(HashMap::new()))),
+            trace: TraceRecorder::default(),
+        }
+    }
+
+    /// besides the report, records every tracked op as a span with its flops and bytes on
+    /// the trace.
+    pub fn with_trace(mut self, trace: TraceRecorder) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.is_some()
+    }
+
+    pub fn trace(&self) -> &TraceRecorder {
+        &self.trace
+    }
+
+    /// starts tracking an op, which is accounted when the guard is dropped.
+    pub fn track(&self, name: &'static str, flops: usize, bytes: usize) -> OpProfileGuard {
+        let inner = self
+            .inner
+            .as_ref()
+            .map(|inner| (inner.clone(), Instant::now()));
+        let span = self
+            .trace
+            .span("profile", name)
+            .with_arg("flops", flops as u64)
+            .with_arg("bytes", bytes as u64);
+        OpProfileGuard {
+            inner,
+            name,
+            flops: flops as u64,
+            bytes: bytes as u64,
+            _span: span,
+        }
+    }
+
+    pub fn report(&self) -> ProfileReport {
+        let mut ops = match &self.inner {
+            None => vec![],
+            Some(inner) => inner.lock().unwrap().values().cloned().collect::<Vec<_>>(),
+        };
+        ops.sort_by(|a, b| b.walltime.cmp(&a.walltime).then(a.name.cmp(b.name)));
+        ProfileReport { ops }
+    }
+
+    pub fn reset(&self) {
+        if let Some(inner) = &self.inner {
+            inner.lock().unwrap().clear();
+        }
+    }
+}
+
+impl Drop for OpProfileGuard {
+    fn drop(&mut self) {
+        if let Some((inner, start_at)) = self.inner.take() {
+            let elapsed = start_at.elapsed();
+            let mut ops = inner.lock().unwrap();
+            let op = ops.entry(self.name).or_insert_with(|| OpProfile {
+                name: self.name,
+                ..Default::default()
+            });
+            op.calls += 1;
+            op.walltime += elapsed;
+            op.flops += self.flops;
+            op.bytes += self.bytes;
+        }
+    }
+}
+
+impl OpProfile {
+    pub fn gflops_per_sec(&self) -> f64 {
+        per_sec(self.flops, self.walltime) / 1e9
+    }
+
+    pub fn gbytes_per_sec(&self) -> f64 {
+        per_sec(self.bytes, self.walltime) / 1e9
+    }
+}
+
+/// the profiled ops, sorted by the wall time desc.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProfileReport {
+    pub ops: Vec<OpProfile>,
+}
+
+impl ProfileReport {
+    pub fn op(&self, name: &str) -> Option<&OpProfile> {
+        self.ops.iter().find(|op| op.name == name)
+    }
+
+    pub fn total_walltime(&self) -> Duration {
+        self.ops.iter().map(|op| op.walltime).sum()
+    }
+}
+
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.total_walltime().as_secs_f64();
+        writeln!(
+            f,
+            "{:<16} {:>8} {:>12} {:>10} {:>7} {:>10} {:>10}",
+            "op", "calls", "total(ms)", "avg(us)", "%", "GFLOP/s", "GB/s"
+        )?;
+        for op in &self.ops {
+            let secs = op.walltime.as_secs_f64();
+            writeln!(
+                f,
+                "{:<16} {:>8} {:>12.3} {:>10.2} {:>7.2} {:>10.2} {:>10.2}",
+                op.name,
+                op.calls,
+                secs * 1e3,
+                secs * 1e6 / op.calls as f64,
+                if total > 0.0 {
+                    secs / total * 100.0
+                } else {
+                    0.0
+                },
+                op.gflops_per_sec(),
+                op.gbytes_per_sec(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn per_sec(n: u64, walltime: Duration) -> f64 {
+    let secs = walltime.as_secs_f64();
+    if secs > 0.0 { n as f64 / secs } else { 0.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_op_profiler() {
+        let trace = TraceRecorder::new();
+        let profiler = OpProfiler::new().with_trace(trace.clone());
+        for _ in 0..3 {
+            let _t = profiler.track("matmul_vec", 200, 100);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        drop(profiler.track("rope", 10, 8));
+
+        let report = profiler.report();
+        assert_eq!(report.ops.len(), 2);
+        assert_eq!(report.ops[0].name, "matmul_vec");
+        let matmul = report.op("matmul_vec").unwrap();
+        assert_eq!((matmul.calls, matmul.flops, matmul.bytes), (3, 600, 300));
+        assert!(matmul.walltime >= Duration::from_millis(3));
+        assert!(matmul.gflops_per_sec() > 0.0);
+        assert_eq!(report.op("rope").unwrap().calls, 1);
+        assert!(
+            report
+                .to_string()
+                .lines()
+                .nth(1)
+                .unwrap()
+                .starts_with("matmul_vec")
+        );
+
+        let events = trace.events();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0].category, "profile");
+        assert_eq!(events[3].args, vec![("flops", 10), ("bytes", 8)]);
+
+        profiler.reset();
+        assert!(profiler.report().ops.is_empty());
+
+        let disabled = OpProfiler::default();
+        drop(disabled.track("rope", 10, 8));
+        assert!(disabled.report().ops.is_empty());
+    }
+}
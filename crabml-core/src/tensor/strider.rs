@@ -1,5 +1,6 @@
 use crate::error::ErrorKind;
 use crate::error::Result;
+use crate::kernels::prelude::*;
 
 #[derive(Clone, Debug, Default)]
 pub struct TensorStrider {
@@ -0,0 +1,238 @@
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+/// records the spans of the ops, layers and sampling with the thread they run on, and
+/// exports them in the chrome trace json format, which can be opened in perfetto
+/// (https://ui.perfetto.dev) or chrome://tracing.
+///
+/// the recorder is disabled by default, and a span on a disabled recorder costs nothing
+/// but a branch.
+#[derive(Debug, Clone, Default)]
+pub struct TraceRecorder {
+    inner: Option<Arc<TraceRecorderInner>>,
+}
+
+#[derive(Debug)]
+struct TraceRecorderInner {
+    start_at: Instant,
+    events: Mutex<Vec<TraceEvent>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceEvent {
+    pub name: Cow<'static, str>,
+    pub category: &'static str,
+    pub thread_id: u64,
+    pub thread_name: Option<String>,
+    /// microseconds since the recorder is created
+    pub start_us: f64,
+    pub duration_us: f64,
+    pub args: Vec<(&'static str, u64)>,
+}
+
+impl TraceRecorder {
+    pub fn new() -> Self {
+        Self {
+            inner: Some(Arc::new(TraceRecorderInner {
+                start_at: Instant::now(),
+                events: Mutex::new(vec![]),
+            })),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.inner.is_some()
+    }
+
+    /// starts a span which is recorded when dropped.
+    pub fn span(&self, category: &'static str, name: impl Into<Cow<'static, str>>) -> TraceSpan {
+        let inner = match &self.inner {
+            None => return TraceSpan { inner: None },
+            Some(inner) => inner.clone(),
+        };
+        let event = TraceEvent {
+            name: name.into(),
+            category,
+            thread_id: current_thread_id(),
+            thread_name: std::thread::current().name().map(|s| s.to_string()),
+            start_us: inner.start_at.elapsed().as_secs_f64() * 1e6,
+            duration_us: 0.0,
+            args: vec![],
+        };
+        TraceSpan {
+            inner: Some((inner, event)),
+        }
+    }
+
+    pub fn events(&self) -> Vec<TraceEvent> {
+        match &self.inner {
+            None => vec![],
+            Some(inner) => inner.events.lock().unwrap().clone(),
+        }
+    }
+
+    pub fn clear(&self) {
+        if let Some(inner) = &self.inner {
+            inner.events.lock().unwrap().clear();
+        }
+    }
+
+    /// the recorded spans in the chrome trace json format, as complete ("X") events
+    /// with the thread names as metadata ("M") events.
+    pub fn to_chrome_trace(&self) -> String {
+        let events = self.events();
+        let mut out = String::from("{\"traceEvents\":[");
+
+        let mut thread_names = events
+            .iter()
+            .filter_map(|e| e.thread_name.as_ref().map(|n| (e.thread_id, n)))
+            .collect::<Vec<_>>();
+        thread_names.sort();
+        thread_names.dedup_by_key(|(tid, _)| *tid);
+
+        let mut first = true;
+        for (tid, name) in thread_names {
+            if !first {
+                out.push(',');
+            }
+            first = false;
+            write!(
+                out,
+                "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":{},\"args\":{{\"name\":\"{}\"}}}}",
+                tid,
+                escape_json(name)
+            )
+            .unwrap();
+        }
+
+        for e in events.iter() {
+            if !first {
+                out.push(',');
+            }
+            first = false;
+            write!(
+                out,
+                "{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"X\",\"pid\":1,\"tid\":{},\"ts\":{:.3},\"dur\":{:.3}",
+                escape_json(&e.name),
+                escape_json(e.category),
+                e.thread_id,
+                e.start_us,
+                e.duration_us
+            )
+            .unwrap();
+            if !e.args.is_empty() {
+                out.push_str(",\"args\":{");
+                for (i, (k, v)) in e.args.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    write!(out, "\"{}\":{}", escape_json(k), v).unwrap();
+                }
+                out.push('}');
+            }
+            out.push('}');
+        }
+
+        out.push_str("],\"displayTimeUnit\":\"ms\"}");
+        out
+    }
+
+    pub fn write_chrome_trace(&self, path: &str) -> Result<()> {
+        std::fs::write(path, self.to_chrome_trace()).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to write the trace to {}", path),
+            cause: Some(Box::new(err)),
+        })
+    }
+}
+
+pub struct TraceSpan {
+    inner: Option<(Arc<TraceRecorderInner>, TraceEvent)>,
+}
+
+impl TraceSpan {
+    pub fn with_arg(mut self, key: &'static str, value: u64) -> Self {
+        if let Some((_, event)) = &mut self.inner {
+            event.args.push((key, value));
+        }
+        self
+    }
+}
+
+impl Drop for TraceSpan {
+    fn drop(&mut self) {
+        if let Some((inner, mut event)) = self.inner.take() {
+            event.duration_us = inner.start_at.elapsed().as_secs_f64() * 1e6 - event.start_us;
+            inner.events.lock().unwrap().push(event);
+        }
+    }
+}
+
+// a small and stable id for each thread, std::thread::ThreadId can not be turned into a number
+// on stable yet
+fn current_thread_id() -> u64 {
+    static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
+    thread_local! {
+        static THREAD_ID: u64 = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+    }
+    THREAD_ID.with(|id| *id)
+}
+
+fn escape_json(s: &str) -> Cow<'_, str> {
+    if !s.chars().any(|c| c == '"' || c == '\\' || c.is_control()) {
+        return Cow::Borrowed(s);
+    }
+    let mut out = String::with_capacity(s.len() + 2);
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chrome_trace() {
+        let trace = TraceRecorder::new();
+        {
+            let _layer = trace.span("layer", "layer").with_arg("layer", 3);
+            let _op = trace.span("op", "mat\"mul");
+        }
+        let events = trace.events();
+        assert_eq!(events.len(), 2);
+        // the inner span is closed first
+        assert_eq!(events[0].name, "mat\"mul");
+        assert_eq!(events[1].args, vec![("layer", 3)]);
+        assert!(events[1].start_us <= events[0].start_us);
+        assert!(events[1].duration_us >= events[0].duration_us);
+
+        let json = trace.to_chrome_trace();
+        assert!(json.starts_with("{\"traceEvents\":["));
+        assert!(json.contains("\"name\":\"mat\\\"mul\",\"cat\":\"op\",\"ph\":\"X\""));
+        assert!(json.contains("\"args\":{\"layer\":3}"));
+
+        let disabled = TraceRecorder::default();
+        drop(disabled.span("op", "matmul"));
+        assert!(disabled.events().is_empty());
+        assert_eq!(
+            disabled.to_chrome_trace(),
+            "{\"traceEvents\":[],\"displayTimeUnit\":\"ms\"}"
+        );
+    }
+}
@@ -0,0 +1,33 @@
+//! the helpers of the tests which write the files to load them.
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+/// a path in the temp dir unique to the process and the call, so the tests running at the
+/// same time never share a file. the file is removed on drop, also when the test fails.
+pub(crate) struct TempPath(PathBuf);
+
+impl TempPath {
+    pub fn new(name: &str) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let file = format!("crabml-test-{}-{}-{}", std::process::id(), n, name);
+        Self(std::env::temp_dir().join(file))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn to_str(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
@@ -1,5 +1,10 @@
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::OnceLock;
 
+use super::gpt2::ByteLevelBpe;
+use super::gpt2::PreTokenizer;
+use super::trie::VocabTrie;
 use crate::error::Result;
 
 type Token = String;
@@ -14,6 +19,10 @@ pub struct BpeTokenizer {
     // the state on decoding
     byte_pieces: [u8; 256],
     token_buf_len: usize,
+    // set on the gpt2 style tokenizers, which are ranked by the merges instead of the scores
+    byte_level: Option<ByteLevelBpe>,
+    // built on the first prefix query
+    trie: OnceLock<Arc<VocabTrie>>,
 }
 
 impl BpeTokenizer {
@@ -41,45 +50,112 @@ impl BpeTokenizer {
             byte_pieces,
             bos_token,
             eos_token,
+            byte_level: None,
+            trie: OnceLock::new(),
         }
     }
 
+    /// a gpt2 style byte level BPE tokenizer, like on GPT-2, Qwen, StarCoder and Llama 3.
+    /// the tokens are merged in the order of the merges like "Ġ t", the control and user
+    /// defined tokens in `token_types` are matched verbatim in the text.
+    pub fn new_byte_level(
+        tokens: Vec<String>,
+        merges: &[&str],
+        token_types: Option<&[i32]>,
+        pre: PreTokenizer,
+        bos_token: TokenID,
+        eos_token: TokenID,
+    ) -> Result<Self> {
+        let byte_level = ByteLevelBpe::new(&tokens, merges, token_types, pre)?;
+        let mut tk = Self::new(tokens, vec![], bos_token, eos_token);
+        tk.byte_level = Some(byte_level);
+        Ok(tk)
+    }
+
+    /// whether it's a gpt2 style tokenizer, which has no scores.
+    pub fn is_byte_level(&self) -> bool {
+        self.byte_level.is_some()
+    }
+
     pub fn vocab(&self) -> &[String] {
         &self.tokens
     }
 
+    pub fn token_scores(&self) -> &[f32] {
+        &self.token_scores
+    }
+
+    pub fn bos_token(&self) -> TokenID {
+        self.bos_token
+    }
+
     pub fn eos_token(&self) -> TokenID {
         self.eos_token
     }
 
+    pub fn token_id(&self, token: &str) -> Option<TokenID> {
+        self.token_ids.get(token).copied()
+    }
+
     pub fn token(&self, token_id: TokenID) -> Token {
         self.tokens[token_id].clone()
     }
 
+    /// the byte trie over the vocabulary for the prefix queries, it's built on the first
+    /// call.
+    pub fn trie(&self) -> &Arc<VocabTrie> {
+        self.trie.get_or_init(|| Arc::new(VocabTrie::new(self)))
+    }
+
     pub fn decode(&self, prev_token: usize, token: usize) -> Result<Token> {
-        let mut piece: &[u8] = self.tokens[token].as_bytes();
-        // following BOS (1) token, sentencepiece decoder strips any leading whitespace (see PR #89)
-        if prev_token == 1 && piece[0] == b' ' {
-            piece = &piece[1..];
+        let bytes = self.decode_bytes(prev_token, token)?;
+        Ok(String::from_utf8_lossy(&bytes).to_string())
+    }
+
+    /// decode the token into the raw bytes. a byte fallback token only carries one byte of a
+    /// multi-byte utf8 char, so the bytes of consecutive tokens should be concatenated before
+    /// converting into a string.
+    pub fn decode_bytes(&self, prev_token: usize, token: usize) -> Result<Vec<u8>> {
+        if let Some(bpe) = &self.byte_level {
+            return Ok(bpe.decode_bytes(&self.tokens[token], bpe.is_special(token)));
         }
+
+        let piece: &[u8] = self.tokens[token].as_bytes();
         // careful, some tokens designate raw bytes, and look like e.g. '<0x01>'
         // parse this and convert and return the actual byte
         if piece.starts_with(b"<0x") && piece[piece.len() - 1] == b'>' {
             let s = String::from_utf8_lossy(&piece[1..piece.len() - 1]);
             let s = s.trim_start_matches("0x");
             if let Ok(byte) = u8::from_str_radix(s, 16) {
-                piece = &self.byte_pieces[(byte as usize)..(byte as usize) + 1]
+                return Ok(self.byte_pieces[(byte as usize)..(byte as usize) + 1].to_vec());
             }
         }
 
-        let mut s = String::from_utf8(piece.to_vec()).unwrap();
-        s = s.replace('▁', " ");
-        Ok(s)
+        let s = String::from_utf8_lossy(piece).replace('▁', " ");
+        // following BOS (1) token, sentencepiece decoder strips any leading whitespace (see PR #89)
+        if prev_token == self.bos_token {
+            if let Some(s) = s.strip_prefix(' ') {
+                return Ok(s.as_bytes().to_vec());
+            }
+        }
+        Ok(s.into_bytes())
     }
 
     // encode the string text (input) into an upper-bound preallocated tokens[] array
     // bos != 0 means prepend the BOS token (=1), eos != 0 means append the EOS token (=2)
     pub fn encode(&self, text: &str, bos: bool, eos: bool) -> Result<Vec<TokenID>> {
+        if let Some(bpe) = &self.byte_level {
+            let mut tokens = vec![];
+            if bos {
+                tokens.push(self.bos_token);
+            }
+            tokens.extend(bpe.encode(text, &self.token_ids)?);
+            if eos {
+                tokens.push(self.eos_token);
+            }
+            return Ok(tokens);
+        }
+
         // create a temporary buffer that will store merge candidates of always two consecutive tokens
         // *2 for concat, +1 for null terminator +2 for UTF8 (in case max_token_length is 1)
         let mut token_buf = String::with_capacity(self.token_buf_len * 2 + 1 + 2);
@@ -216,4 +292,85 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_gguf_tokenizer_decode() -> Result<()> {
+        let gf_loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gf_loader.open()?;
+        let tokens = gf
+            .metadata()
+            .get_string_array("tokenizer.ggml.tokens")
+            .unwrap()
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let tk = BpeTokenizer::new(tokens, vec![], 1, 2);
+        assert_eq!(tk.token(68), "<0x41>");
+        assert_eq!(tk.token(229), "<0xE2>");
+
+        // unchanged: the '▁' turns into a space, and the ascii byte tokens into their char
+        assert_eq!(tk.decode(260, 10842)?, " Captain");
+        assert_eq!(tk.decode(260, 68)?, "A");
+        // changed: the space after the BOS is stripped, it was kept before as the check
+        // looked for a ' ' before replacing the '▁'
+        assert_eq!(tk.decode(1, 10842)?, "Captain");
+        assert_eq!(tk.decode(1, 29871)?, "");
+        // changed: a byte token of a multi-byte char decodes lossily instead of panicking,
+        // decode_bytes() keeps the raw byte to join with the next tokens
+        assert_eq!(tk.decode(260, 229)?, "\u{FFFD}");
+        assert_eq!(tk.decode_bytes(260, 229)?, vec![0xE2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_byte_level_tokenizer() -> Result<()> {
+        // the 256 byte tokens, then the merges, then the special tokens
+        let mut tokens = crate::tokenizer::gpt2::byte_chars()
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>();
+        let merges = [
+            "Ġ w", "o r", "Ġw or", "l d", "Ġwor ld", "H e", "l l", "He ll", "Hell o",
+        ];
+        for merge in merges {
+            tokens.push(merge.replace(' ', ""));
+        }
+        tokens.push("<|endoftext|>".to_string());
+        tokens.push("<|im_start|>".to_string());
+        let mut token_types = vec![1; tokens.len()];
+        token_types[265] = 3;
+        token_types[266] = 3;
+        let tk = BpeTokenizer::new_byte_level(
+            tokens,
+            &merges,
+            Some(&token_types),
+            PreTokenizer::Gpt2,
+            265,
+            265,
+        )?;
+
+        let tests: Vec<(&str, Vec<usize>)> = vec![
+            ("Hello world", vec![264, 260]),
+            ("Hello  world", vec![264, 32, 260]),
+            ("<|im_start|>Hello<|endoftext|>", vec![266, 264, 265]),
+            ("world", vec![119, 257, 259]),
+            ("é\n", vec![195, 169, 10]),
+        ];
+        for (text, expected) in tests {
+            let got = tk.encode(text, false, false)?;
+            assert_eq!(got, expected, "failed to encode {:?}", text);
+            let decoded = got
+                .iter()
+                .map(|t| tk.decode_bytes(265, *t))
+                .collect::<Result<Vec<_>>>()?
+                .concat();
+            assert_eq!(decoded, text.as_bytes());
+        }
+        assert_eq!(tk.encode("Hello", true, true)?, vec![265, 264, 265]);
+        assert_eq!(tk.decode(265, 260)?, " world");
+
+        let report = tk.self_test(crate::tokenizer::TOKENIZER_SELF_TEST_CORPUS, Some(&merges))?;
+        assert!(report.is_ok(), "{}", report);
+        Ok(())
+    }
 }
@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use fancy_regex::Regex;
+
+use crate::error::Error;
+use crate::error::ErrorKind;
+use crate::error::Result;
+
+const GPT2_PATTERN: &str =
+    r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+
+const LLAMA3_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+const QWEN2_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+/// the token types in `tokenizer.ggml.token_type`, the control and user defined tokens are
+/// matched verbatim in the text before the pre-tokenization.
+const TOKEN_TYPE_CONTROL: i32 = 3;
+const TOKEN_TYPE_USER_DEFINED: i32 = 4;
+
+/// how the text is split into words before the merges, by the `tokenizer.ggml.pre` metadata.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum PreTokenizer {
+    #[default]
+    Gpt2,
+    /// like gpt2, but the digits are grouped by 3 and the newlines are kept apart.
+    Llama3,
+    /// like llama3, but every digit is a word.
+    Qwen2,
+}
+
+impl PreTokenizer {
+    /// the unknown names fall back to gpt2, like llama.cpp does.
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "llama3" | "llama-bpe" | "smaug-bpe" => Self::Llama3,
+            "qwen2" => Self::Qwen2,
+            _ => Self::Gpt2,
+        }
+    }
+
+    fn pattern(&self) -> &'static str {
+        match self {
+            Self::Gpt2 => GPT2_PATTERN,
+            Self::Llama3 => LLAMA3_PATTERN,
+            Self::Qwen2 => QWEN2_PATTERN,
+        }
+    }
+}
+
+/// the byte level BPE of gpt2: every byte of the text is mapped to a printable char, so the
+/// vocab needs no byte fallback, and the words are merged by the rank of the merges.
+pub(crate) struct ByteLevelBpe {
+    regex: Regex,
+    merge_ranks: HashMap<(String, String), usize>,
+    byte_chars: [char; 256],
+    char_bytes: HashMap<char, u8>,
+    // sorted by the length desc, so the longest one matches first
+    special_tokens: Vec<(String, usize)>,
+    special_ids: HashSet<usize>,
+}
+
+impl ByteLevelBpe {
+    pub fn new(
+        tokens: &[String],
+        merges: &[&str],
+        token_types: Option<&[i32]>,
+        pre: PreTokenizer,
+    ) -> Result<Self> {
+        let regex = Regex::new(pre.pattern()).map_err(|err| Error {
+            kind: ErrorKind::Unexpected,
+            message: "failed to compile the pre-tokenizer regex".to_string(),
+            cause: Some(Box::new(err)),
+        })?;
+
+        let mut merge_ranks = HashMap::with_capacity(merges.len());
+        for (rank, merge) in merges.iter().enumerate() {
+            let (a, b) = merge.split_once(' ').ok_or_else(|| Error {
+                kind: ErrorKind::ModelError,
+                message: format!("invalid merge {:?}", merge),
+                cause: None,
+            })?;
+            merge_ranks.insert((a.to_string(), b.to_string()), rank);
+        }
+
+        let byte_chars = byte_chars();
+        let char_bytes = byte_chars
+            .iter()
+            .enumerate()
+            .map(|(b, c)| (*c, b as u8))
+            .collect();
+
+        let token_types = token_types.unwrap_or_default();
+        if token_types.len() > tokens.len() {
+            return Err(Error {
+                kind: ErrorKind::ModelError,
+                message: format!(
+                    "{} token types, but only {} tokens",
+                    token_types.len(),
+                    tokens.len()
+                ),
+                cause: None,
+            });
+        }
+        let mut special_tokens = token_types
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| **t == TOKEN_TYPE_CONTROL || **t == TOKEN_TYPE_USER_DEFINED)
+            .filter(|(id, _)| !tokens[*id].is_empty())
+            .map(|(id, _)| (tokens[id].clone(), id))
+            .collect::<Vec<_>>();
+        special_tokens.sort_by_key(|(t, _)| std::cmp::Reverse(t.len()));
+        let special_ids = special_tokens.iter().map(|(_, id)| *id).collect();
+
+        Ok(Self {
+            regex,
+            merge_ranks,
+            byte_chars,
+            char_bytes,
+            special_tokens,
+            special_ids,
+        })
+    }
+
+    pub fn is_special(&self, token: usize) -> bool {
+        self.special_ids.contains(&token)
+    }
+
+    pub fn encode(&self, text: &str, token_ids: &HashMap<String, usize>) -> Result<Vec<usize>> {
+        let mut tokens = vec![];
+        let mut rest = text;
+        while !rest.is_empty() {
+            let (text, special) = self.split_special(rest);
+            for word in self.pre_split(text)? {
+                self.encode_word(word, token_ids, &mut tokens)?;
+            }
+            match special {
+                Some((len, id)) => {
+                    tokens.push(id);
+                    rest = &rest[text.len() + len..];
+                }
+                None => break,
+            }
+        }
+        Ok(tokens)
+    }
+
+    /// the bytes of a token, the special tokens are decoded as their text.
+    pub fn decode_bytes(&self, piece: &str, special: bool) -> Vec<u8> {
+        if special {
+            return piece.as_bytes().to_vec();
+        }
+        let mut bytes = Vec::with_capacity(piece.len());
+        for c in piece.chars() {
+            match self.char_bytes.get(&c) {
+                Some(b) => bytes.push(*b),
+                None => bytes.extend(c.to_string().as_bytes()),
+            }
+        }
+        bytes
+    }
+
+    // the text before the first special token, and the length and id of the special token
+    fn split_special<'a>(&self, text: &'a str) -> (&'a str, Option<(usize, usize)>) {
+        let first = self
+            .special_tokens
+            .iter()
+            .filter_map(|(t, id)| text.find(t.as_str()).map(|pos| (pos, t.len(), *id)))
+            // the earliest one, and the longest one on the same position
+            .min_by_key(|(pos, len, _)| (*pos, std::cmp::Reverse(*len)));
+        match first {
+            Some((pos, len, id)) => (&text[..pos], Some((len, id))),
+            None => (text, None),
+        }
+    }
+
+    pub(crate) fn pre_split<'a>(&self, text: &'a str) -> Result<Vec<&'a str>> {
+        self.regex
+            .find_iter(text)
+            .map(|m| {
+                m.map(|m| m.as_str()).map_err(|err| Error {
+                    kind: ErrorKind::BadInput,
+                    message: "failed to pre-tokenize the text".to_string(),
+                    cause: Some(Box::new(err)),
+                })
+            })
+            .collect()
+    }
+
+    fn encode_word(
+        &self,
+        word: &str,
+        token_ids: &HashMap<String, usize>,
+        tokens: &mut Vec<usize>,
+    ) -> Result<()> {
+        let mut parts = word
+            .bytes()
+            .map(|b| self.byte_chars[b as usize].to_string())
+            .collect::<Vec<_>>();
+
+        // merge the pair with the lowest rank each iteration
+        let mut key = (String::new(), String::new());
+        loop {
+            let mut best: Option<(usize, usize)> = None;
+            for i in 0..parts.len().saturating_sub(1) {
+                key.0.clone_from(&parts[i]);
+                key.1.clone_from(&parts[i + 1]);
+                if let Some(rank) = self.merge_ranks.get(&key) {
+                    if best.map_or(true, |(r, _)| *rank < r) {
+                        best = Some((*rank, i));
+                    }
+                }
+            }
+            match best {
+                Some((_, i)) => {
+                    let next = parts.remove(i + 1);
+                    parts[i].push_str(&next);
+                }
+                None => break,
+            }
+        }
+
+        for part in parts {
+            match token_ids.get(&part) {
+                Some(id) => tokens.push(*id),
+                None => {
+                    return Err(Error {
+                        kind: ErrorKind::BadInput,
+                        message: format!("token {:?} is not in the vocab", part),
+                        cause: None,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// the printable chars of the bytes in gpt2: the printable latin-1 chars stand for
+/// themselves, the others are shifted to 256 and above, like the space is 'Ġ'.
+pub(crate) fn byte_chars() -> [char; 256] {
+    let mut chars = ['\0'; 256];
+    let mut n = 0;
+    for (b, c) in chars.iter_mut().enumerate() {
+        let printable = (b'!'..=b'~').contains(&(b as u8))
+            || (0xa1..=0xac).contains(&b)
+            || (0xae..=0xff).contains(&b);
+        *c = if printable {
+            char::from_u32(b as u32).unwrap()
+        } else {
+            n += 1;
+            char::from_u32(255 + n).unwrap()
+        };
+    }
+    chars
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pre_split() -> Result<()> {
+        let tests = [
+            (PreTokenizer::Gpt2, "Hello  world's 12345\n\n", vec![
+                "Hello", " ", " world", "'s", " 12345", "\n\n",
+            ]),
+            (PreTokenizer::Llama3, "Hello  WORLD'S 12345\n\n", vec![
+                "Hello", " ", " WORLD", "'S", " ", "123", "45", "\n\n",
+            ]),
+            (PreTokenizer::Qwen2, "x=123;", vec![
+                "x", "=", "1", "2", "3", ";",
+            ]),
+        ];
+        for (pre, text, expected) in tests {
+            let bpe = ByteLevelBpe::new(&[], &[], None, pre)?;
+            assert_eq!(bpe.pre_split(text)?, expected, "{:?}", pre);
+        }
+
+        let chars = byte_chars();
+        assert_eq!(chars[b' ' as usize], 'Ġ');
+        assert_eq!(chars[b'\n' as usize], 'Ċ');
+        assert_eq!(chars[b'a' as usize], 'a');
+        Ok(())
+    }
+
+    // the head of the gpt2 vocab: the 256 byte tokens ordered by their chars, and the first 7
+    // merges, on which the pre-tokenizers of llama3 and qwen2 are checked as well.
+    fn gpt2_vocab_head() -> (Vec<String>, Vec<&'static str>) {
+        let mut chars = byte_chars();
+        chars.sort();
+        let mut tokens = chars.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        let merges = vec!["Ġ t", "Ġ a", "h e", "i n", "r e", "o n", "Ġt he"];
+        for merge in merges.iter() {
+            tokens.push(merge.replace(' ', ""));
+        }
+        (tokens, merges)
+    }
+
+    #[test]
+    fn test_encode_gpt2_vocab() -> Result<()> {
+        let (tokens, merges) = gpt2_vocab_head();
+        // the ids of the gpt2 vocab, as the merges after the first 7 do not apply
+        assert_eq!(tokens[220], "Ġ");
+        assert_eq!(tokens[198], "Ċ");
+        assert_eq!(tokens[262], "Ġthe");
+        let token_ids = tokens
+            .iter()
+            .enumerate()
+            .map(|(i, t)| (t.clone(), i))
+            .collect::<HashMap<_, _>>();
+
+        let tests = [
+            (PreTokenizer::Gpt2, "he's in the  123\n\n", vec![
+                258, 6, 82, 220, 259, 262, 220, 220, 16, 17, 18, 198, 198,
+            ]),
+            // the contractions are case insensitive, and the digits are grouped by 3
+            (PreTokenizer::Llama3, "HE'D  1234\n", vec![
+                39, 36, 6, 35, 220, 220, 16, 17, 18, 19, 198,
+            ]),
+            // every digit is a word, and the "\r\n" is kept together
+            (PreTokenizer::Qwen2, "in 2024\r\n", vec![
+                259, 220, 17, 15, 17, 19, 201, 198,
+            ]),
+        ];
+        for (pre, text, expected) in tests {
+            let bpe = ByteLevelBpe::new(&tokens, &merges, None, pre)?;
+            assert_eq!(bpe.encode(text, &token_ids)?, expected, "{:?}", pre);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_token_types_out_of_vocab() {
+        let tokens = vec!["a".to_string(), "b".to_string()];
+        let result = ByteLevelBpe::new(&tokens, &[], Some(&[1, 1, 3]), PreTokenizer::Gpt2);
+        assert!(result.is_err());
+        assert!(ByteLevelBpe::new(&tokens, &[], Some(&[1, 3]), PreTokenizer::Gpt2).is_ok());
+    }
+}
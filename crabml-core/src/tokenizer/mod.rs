@@ -1,3 +1,10 @@
 mod bpe;
+mod gpt2;
+mod self_test;
+mod trie;
 
 pub use bpe::BpeTokenizer;
+pub use gpt2::PreTokenizer;
+pub use self_test::TokenizerSelfTestReport;
+pub use self_test::TOKENIZER_SELF_TEST_CORPUS;
+pub use trie::VocabTrie;
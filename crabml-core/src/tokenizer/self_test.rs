@@ -0,0 +1,200 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use super::BpeTokenizer;
+use crate::error::Result;
+
+/// the strings which are likely to break a tokenizer: whitespace runs, byte fallback on the
+/// chars which are not in the vocab, multi-byte utf8 chars, and emoji sequences.
+pub const TOKENIZER_SELF_TEST_CORPUS: &[&str] = &[
+    "hello, world",
+    "Captain America: ",
+    "  leading and trailing spaces  ",
+    "a  b   c    d",
+    "line1\nline2\n\nline3",
+    "tab\tseparated\tvalues",
+    "1234567890 3.14159 -42",
+    "!@#$%^&*()_+-=[]{}|;':\",./<>?",
+    "café naïve façade",
+    "Привет, мир",
+    "你好，世界",
+    "日本語のテキスト",
+    "한국어 텍스트",
+    "🦀🚀🔥",
+    "👩‍💻 👍🏽",
+    "\u{7f}\u{1}",
+];
+
+#[derive(Debug, Default)]
+pub struct TokenizerSelfTestReport {
+    pub checks: usize,
+    pub failures: Vec<String>,
+}
+
+impl TokenizerSelfTestReport {
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    fn check(&mut self, ok: bool, failure: impl FnOnce() -> String) {
+        self.checks += 1;
+        if !ok {
+            self.failures.push(failure());
+        }
+    }
+}
+
+impl fmt::Display for TokenizerSelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for failure in &self.failures {
+            writeln!(f, "FAIL: {}", failure)?;
+        }
+        write!(
+            f,
+            "tokenizer self test: {} checks, {} passed, {} failed",
+            self.checks,
+            self.checks - self.failures.len(),
+            self.failures.len()
+        )
+    }
+}
+
+impl BpeTokenizer {
+    /// verify the vocab is consistent with the metadata it's loaded from, and every string in
+    /// the corpus survives an encode -> decode round trip. the merges are only available in
+    /// the gguf files with a `tokenizer.ggml.merges` metadata, every merge is expected to
+    /// produce a token in the vocab.
+    pub fn self_test(
+        &self,
+        corpus: &[&str],
+        merges: Option<&[&str]>,
+    ) -> Result<TokenizerSelfTestReport> {
+        let mut report = TokenizerSelfTestReport::default();
+        self.check_vocab(&mut report);
+        if let Some(merges) = merges {
+            self.check_merges(merges, &mut report);
+        }
+        for text in corpus {
+            self.check_round_trip(text, &mut report)?;
+        }
+        Ok(report)
+    }
+
+    fn check_vocab(&self, report: &mut TokenizerSelfTestReport) {
+        let vocab = self.vocab();
+        // the byte level tokenizers are ranked by the merges, and have no scores
+        let n_scores = self.token_scores().len();
+        if !self.is_byte_level() {
+            report.check(n_scores == vocab.len(), || {
+                format!(
+                    "the vocab has {} tokens, but {} scores",
+                    vocab.len(),
+                    n_scores
+                )
+            });
+        }
+
+        for (name, token) in [("bos", self.bos_token()), ("eos", self.eos_token())] {
+            report.check(token < vocab.len(), || {
+                format!("{} token {} is out of the vocab", name, token)
+            });
+        }
+
+        let mut seen = HashSet::new();
+        let duplicated = vocab
+            .iter()
+            .filter(|t| !seen.insert(t.as_str()))
+            .take(5)
+            .collect::<Vec<_>>();
+        report.check(duplicated.is_empty(), || {
+            format!("duplicated tokens in the vocab: {:?}", duplicated)
+        });
+
+        // the encoder falls back to the byte tokens at the id of byte + 3, which follows the
+        // <unk>, <s>, </s> tokens.
+        if vocab.iter().any(|t| t.starts_with("<0x")) {
+            for byte in 0..=255usize {
+                let expected = format!("<0x{:02X}>", byte);
+                let got = vocab.get(byte + 3);
+                report.check(got == Some(&expected), || {
+                    format!(
+                        "byte fallback token {} is expected at id {}, but got {:?}",
+                        expected,
+                        byte + 3,
+                        got
+                    )
+                });
+            }
+        }
+    }
+
+    fn check_merges(&self, merges: &[&str], report: &mut TokenizerSelfTestReport) {
+        for merge in merges {
+            let parts = merge.split(' ').collect::<Vec<_>>();
+            let ok = match parts.as_slice() {
+                [a, b] => [*a, *b, &format!("{}{}", a, b)]
+                    .iter()
+                    .all(|t| self.token_id(t).is_some()),
+                _ => false,
+            };
+            report.check(ok, || {
+                format!("merge {:?} does not match the tokens in the vocab", merge)
+            });
+        }
+    }
+
+    fn check_round_trip(&self, text: &str, report: &mut TokenizerSelfTestReport) -> Result<()> {
+        let tokens = self.encode(text, false, false)?;
+        let mut decoded = vec![];
+        let mut prev_token = self.bos_token();
+        for token in tokens.iter() {
+            decoded.extend(self.decode_bytes(prev_token, *token)?);
+            prev_token = *token;
+        }
+
+        report.check(decoded == text.as_bytes(), || {
+            format!(
+                "round trip of {:?} got {:?}, tokens: {:?}",
+                text,
+                String::from_utf8_lossy(&decoded),
+                tokens.iter().map(|t| self.token(*t)).collect::<Vec<_>>()
+            )
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gguf::GGUFFileLoader;
+
+    #[test]
+    fn test_tokenizer_self_test() -> Result<()> {
+        let gf_loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gf_loader.open()?;
+
+        let tokens = gf
+            .metadata()
+            .get_string_array("tokenizer.ggml.tokens")
+            .unwrap()
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let token_scores = gf
+            .metadata()
+            .get_f32_array("tokenizer.ggml.scores")
+            .unwrap()
+            .to_vec();
+        let tk = BpeTokenizer::new(tokens.clone(), token_scores.clone(), 1, 2);
+
+        let report = tk.self_test(TOKENIZER_SELF_TEST_CORPUS, Some(&["▁ t", "i k"]))?;
+        assert!(report.is_ok(), "{}", report);
+
+        // a vocab with the scores drifted away
+        let tk = BpeTokenizer::new(tokens, token_scores[1..].to_vec(), 1, 2);
+        let report = tk.self_test(&[], Some(&["▁ zzz"]))?;
+        assert_eq!(report.failures.len(), 2, "{}", report);
+        Ok(())
+    }
+}
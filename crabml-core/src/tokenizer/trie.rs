@@ -0,0 +1,222 @@
+use super::BpeTokenizer;
+
+#[derive(Debug, Default, Clone)]
+struct TrieNode {
+    // sorted by the byte
+    children: Vec<(u8, u32)>,
+    // the tokens whose bytes end here, several tokens may decode into the same bytes, like
+    // "▁" and "<0x20>"
+    tokens: Vec<u32>,
+}
+
+/// a byte trie over the decoded bytes of the tokens, for the prefix queries of the
+/// constrained decoding. the bos and eos tokens and the tokens decoding into nothing are
+/// left out. the tokens are decoded after the eos, so the leading space is kept.
+///
+/// it's built on the first call of `BpeTokenizer::trie()`.
+#[derive(Debug, Clone)]
+pub struct VocabTrie {
+    nodes: Vec<TrieNode>,
+    token_bytes: Vec<Vec<u8>>,
+}
+
+impl VocabTrie {
+    pub fn new(tokenizer: &BpeTokenizer) -> Self {
+        let (bos_token, eos_token) = (tokenizer.bos_token(), tokenizer.eos_token());
+        let token_bytes = (0..tokenizer.vocab().len())
+            .map(|token| {
+                if token == bos_token || token == eos_token {
+                    return vec![];
+                }
+                tokenizer.decode_bytes(eos_token, token).unwrap_or_default()
+            })
+            .collect::<Vec<_>>();
+
+        let mut trie = Self {
+            nodes: vec![TrieNode::default()],
+            token_bytes: vec![],
+        };
+        for (token, bytes) in token_bytes.iter().enumerate() {
+            if !bytes.is_empty() {
+                trie.insert(bytes, token);
+            }
+        }
+        trie.token_bytes = token_bytes;
+        trie
+    }
+
+    fn insert(&mut self, bytes: &[u8], token: usize) {
+        let mut node = 0;
+        for &b in bytes {
+            node = match self.child(node, b) {
+                Some(child) => child,
+                None => {
+                    let child = self.nodes.len() as u32;
+                    self.nodes.push(TrieNode::default());
+                    let children = &mut self.nodes[node].children;
+                    let at = children.partition_point(|(c, _)| *c < b);
+                    children.insert(at, (b, child));
+                    child as usize
+                }
+            };
+        }
+        self.nodes[node].tokens.push(token as u32);
+    }
+
+    fn child(&self, node: usize, b: u8) -> Option<usize> {
+        let children = &self.nodes[node].children;
+        children
+            .binary_search_by_key(&b, |(c, _)| *c)
+            .ok()
+            .map(|i| children[i].1 as usize)
+    }
+
+    fn find(&self, bytes: &[u8]) -> Option<usize> {
+        bytes.iter().try_fold(0, |node, &b| self.child(node, b))
+    }
+
+    /// the decoded bytes of the token, empty if it's left out of the trie.
+    pub fn token_bytes(&self, token: usize) -> &[u8] {
+        self.token_bytes.get(token).map_or(&[], |b| b.as_slice())
+    }
+
+    /// all the tokens whose bytes start with the prefix, in the byte order of their bytes.
+    /// an empty prefix takes all the tokens in the trie.
+    pub fn tokens_with_prefix(&self, prefix: &[u8]) -> Vec<usize> {
+        let mut out = vec![];
+        let Some(node) = self.find(prefix) else {
+            return out;
+        };
+        let mut stack = vec![node];
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            out.extend(node.tokens.iter().map(|t| *t as usize));
+            stack.extend(node.children.iter().rev().map(|(_, c)| *c as usize));
+        }
+        out
+    }
+
+    /// all the tokens whose bytes are a prefix of the text, from the shortest to the
+    /// longest, like the candidates to start the text with.
+    pub fn tokens_prefixing(&self, text: &[u8]) -> Vec<usize> {
+        let mut out = vec![];
+        let mut node = 0;
+        for &b in text {
+            match self.child(node, b) {
+                Some(child) => node = child,
+                None => break,
+            }
+            out.extend(self.nodes[node].tokens.iter().map(|t| *t as usize));
+        }
+        out
+    }
+
+    /// walk the trie depth first from the root, visit is called on every byte with the
+    /// state of the parent node and the tokens ending on the byte, it returns the state of
+    /// the child node or None to skip the subtree. the tokens sharing a prefix are visited
+    /// together, so the prefix is only walked once.
+    pub fn walk<S>(&self, root: S, mut visit: impl FnMut(&S, u8, &[u32]) -> Option<S>) {
+        self.walk_node(0, &root, &mut visit);
+    }
+
+    // the depth is bounded by the longest token
+    fn walk_node<S>(
+        &self,
+        node: usize,
+        state: &S,
+        visit: &mut impl FnMut(&S, u8, &[u32]) -> Option<S>,
+    ) {
+        for &(b, child) in self.nodes[node].children.iter() {
+            let child = child as usize;
+            if let Some(child_state) = visit(state, b, &self.nodes[child].tokens) {
+                self.walk_node(child, &child_state, visit);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Result;
+    use crate::gguf::GGUFFileLoader;
+
+    #[test]
+    fn test_vocab_trie() {
+        let tokens = [
+            "<unk>", "<s>", "</s>", "▁", "a", "ab", "abc", "b", "<0x20>", "▁a",
+        ];
+        let tk = BpeTokenizer::new(
+            tokens.iter().map(|s| s.to_string()).collect(),
+            vec![0.0; tokens.len()],
+            1,
+            2,
+        );
+        let trie = VocabTrie::new(&tk);
+        assert_eq!(trie.token_bytes(9), b" a");
+        assert_eq!(trie.token_bytes(1), b"");
+
+        assert_eq!(trie.tokens_with_prefix(b"a"), vec![4, 5, 6]);
+        assert_eq!(trie.tokens_with_prefix(b"ab"), vec![5, 6]);
+        assert_eq!(trie.tokens_with_prefix(b"x"), Vec::<usize>::new());
+        // "▁" and "<0x20>" decode into the same space
+        assert_eq!(trie.tokens_with_prefix(b" "), vec![3, 8, 9]);
+        assert_eq!(trie.tokens_with_prefix(b"").len(), tokens.len() - 2);
+
+        assert_eq!(trie.tokens_prefixing(b"abd"), vec![4, 5]);
+        assert_eq!(trie.tokens_prefixing(b" ab"), vec![3, 8, 9]);
+        assert_eq!(trie.tokens_prefixing(b"c"), Vec::<usize>::new());
+
+        // only the subtree of "a" is walked
+        let mut visited = vec![];
+        trie.walk(String::new(), |prefix, b, tokens| {
+            let prefix = format!("{}{}", prefix, b as char);
+            visited.extend(tokens.iter().map(|t| *t as usize));
+            prefix.starts_with('a').then_some(prefix)
+        });
+        assert_eq!(visited, vec![3, 8, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn test_vocab_trie_gguf() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let tokens = gf
+            .metadata()
+            .get_string_array("tokenizer.ggml.tokens")
+            .unwrap()
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let scores = gf
+            .metadata()
+            .get_f32_array("tokenizer.ggml.scores")
+            .unwrap()
+            .to_vec();
+        let tk = BpeTokenizer::new(tokens, scores, 1, 2);
+
+        // the same as a scan over the vocabulary
+        let trie = tk.trie();
+        let scan = |f: &dyn Fn(&[u8]) -> bool| {
+            let mut tokens = (0..tk.vocab().len())
+                .filter(|t| *t != 1 && *t != 2)
+                .filter(|t| f(&tk.decode_bytes(2, *t).unwrap()))
+                .collect::<Vec<_>>();
+            tokens.sort();
+            tokens
+        };
+        let sorted = |mut v: Vec<usize>| {
+            v.sort();
+            v
+        };
+        let got = sorted(trie.tokens_with_prefix(b" Capt"));
+        assert!(got.contains(&10842));
+        assert_eq!(got, scan(&|b| b.starts_with(b" Capt")));
+
+        let text = b" Captain America";
+        let got = trie.tokens_prefixing(text);
+        assert!(got.contains(&10842));
+        assert_eq!(sorted(got), scan(&|b| !b.is_empty() && text.starts_with(b)));
+        Ok(())
+    }
+}
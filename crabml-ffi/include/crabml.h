@@ -0,0 +1,224 @@
+// the C API of crabml.
+//
+// - a CrabmlModel holds the weights and the tokenizer, created by crabml_model_load() and
+//   released by crabml_model_free().
+// - a CrabmlContext holds the kv cache of a generation, created from a model by
+//   crabml_context_new() and released by crabml_context_free(). a context keeps its model
+//   alive, so the model can be freed before its contexts.
+//
+// the handles are not thread safe: a model and all the contexts created from it share the
+// weights, they must be used from one thread at a time. different models can be used on
+// different threads. the message of the last error is kept per thread, see
+// crabml_last_error().
+
+
+#ifndef CRABML_H
+#define CRABML_H
+
+/* generated by cbindgen from crabml-ffi, do not edit by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// the seed of `CrabmlSamplerParams` to sample with a random seed.
+#define CRABML_SEED_RANDOM UINT64_MAX
+
+// the result of a call, `CRABML_STATUS_OK` on success. the error codes besides the last
+// three are mapped from the `ErrorKind` of crabml.
+typedef enum CrabmlStatus {
+  CRABML_STATUS_OK = 0,
+  CRABML_STATUS_UNEXPECTED = 1,
+  CRABML_STATUS_IO_ERROR = 2,
+  CRABML_STATUS_TENSOR_NOT_FOUND = 3,
+  CRABML_STATUS_MODEL_ERROR = 4,
+  CRABML_STATUS_BAD_INPUT = 5,
+  CRABML_STATUS_FORMAT_ERROR = 6,
+  CRABML_STATUS_TENSOR_ERROR = 7,
+  CRABML_STATUS_NOT_IMPLEMENTED = 8,
+  CRABML_STATUS_OUT_OF_MEMORY = 9,
+  // the output buffer is too small, the required length is still written out.
+  CRABML_STATUS_BUFFER_TOO_SMALL = 100,
+  // a required pointer argument is NULL.
+  CRABML_STATUS_NULL_POINTER = 101,
+  // crabml panicked, the handles involved should not be used any more.
+  CRABML_STATUS_PANIC = 102,
+} CrabmlStatus;
+
+// an opaque handle of a generation context, which holds the kv cache.
+typedef struct CrabmlContext CrabmlContext;
+
+// an opaque handle of a loaded model.
+typedef struct CrabmlModel CrabmlModel;
+
+// how the tokens are sampled, start from `crabml_sampler_params_default()`.
+typedef struct CrabmlSamplerParams {
+  // 0 or below picks the most likely token, and the other params besides the penalties
+  // are ignored.
+  float temperature;
+  // sample from the k most likely tokens, 0 means no limit.
+  uint32_t top_k;
+  // sample from the most likely tokens whose probabilities add up to top_p, 1.0 means
+  // no limit.
+  float top_p;
+  // penalize the tokens repeated in the last repeat_last_n tokens, 1.0 means disabled.
+  float repeat_penalty;
+  uint32_t repeat_last_n;
+  // `CRABML_SEED_RANDOM` picks a random seed.
+  uint64_t seed;
+} CrabmlSamplerParams;
+
+// called on every generated token with the token id, its text, and the user_data passed
+// to `crabml_generate()`. the text is NUL terminated and only valid during the call.
+// returning false stops the generation.
+typedef bool (*CrabmlTokenCallback)(uint32_t token,
+                                    const char *piece,
+                                    size_t piece_len,
+                                    void *user_data);
+
+// the options of `crabml_model_load()`, start from `crabml_model_params_default()`.
+typedef struct CrabmlModelParams {
+  // map the file and read the tensors on their first access, or read the whole file into
+  // the memory up front.
+  bool use_mmap;
+  // pin the model in the memory so it's never swapped out.
+  bool use_mlock;
+  // run only the first n transformer layers, 0 runs all of them.
+  uint32_t n_layers;
+} CrabmlModelParams;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+struct CrabmlSamplerParams crabml_sampler_params_default(void);
+
+// create a context to generate with the model, holding up to n_ctx tokens of the prompt
+// and the generation, 0 takes the context length of the model. the handle is written into
+// `*out`, which should be released by `crabml_context_free()`. the context keeps the model
+// alive, but shares it, so it must not be used at the same time as the model or its other
+// contexts.
+//
+// # Safety
+//
+// model is a valid handle, out is a valid pointer.
+enum CrabmlStatus crabml_context_new(const struct CrabmlModel *model,
+                                     uint32_t n_ctx,
+                                     struct CrabmlContext **out);
+
+// release the context handle, NULL is ignored.
+//
+// # Safety
+//
+// context is NULL or a handle from `crabml_context_new()`, and it's not used afterwards.
+void crabml_context_free(struct CrabmlContext *context);
+
+// generate up to max_tokens tokens after the prompt, calling the callback on every token.
+// the kv cache is reset first, so every call starts a new conversation. the generation
+// stops on the eos token, when the context is full, or when the callback returns false,
+// these all return `CRABML_STATUS_OK`. sampler can be NULL for the defaults, and the
+// number of generated tokens is written into `*n_generated` if it's not NULL.
+//
+// # Safety
+//
+// context is a valid handle, prompt is a NUL terminated string, sampler and n_generated
+// are NULL or valid, the callback is NULL or safe to call with user_data.
+enum CrabmlStatus crabml_generate(struct CrabmlContext *context,
+                                  const char *prompt,
+                                  uint32_t max_tokens,
+                                  const struct CrabmlSamplerParams *sampler,
+                                  CrabmlTokenCallback callback,
+                                  void *user_data,
+                                  size_t *n_generated);
+
+// the message of the last error on the calling thread, or NULL if the last call succeeded.
+// the string is owned by crabml and only valid until the next call on this thread.
+const char *crabml_last_error(void);
+
+struct CrabmlModelParams crabml_model_params_default(void);
+
+// load a gguf model from the path, params can be NULL for the defaults. on success the
+// handle is written into `*out`, which should be released by `crabml_model_free()`.
+//
+// # Safety
+//
+// path is a NUL terminated string, params is NULL or valid, out is a valid pointer.
+enum CrabmlStatus crabml_model_load(const char *path,
+                                    const struct CrabmlModelParams *params,
+                                    struct CrabmlModel **out);
+
+// release the model handle, NULL is ignored. the weights are kept until the contexts
+// created from the model are also released.
+//
+// # Safety
+//
+// model is NULL or a handle from `crabml_model_load()`, and it's not used afterwards.
+void crabml_model_free(struct CrabmlModel *model);
+
+// the number of tokens in the vocabulary, 0 if model is NULL.
+//
+// # Safety
+//
+// model is NULL or a valid handle.
+uint32_t crabml_model_vocab_size(const struct CrabmlModel *model);
+
+// the context length the model is trained on, 0 if model is NULL.
+//
+// # Safety
+//
+// model is NULL or a valid handle.
+uint32_t crabml_model_n_ctx(const struct CrabmlModel *model);
+
+// the beginning of sequence token, 0 if model is NULL.
+//
+// # Safety
+//
+// model is NULL or a valid handle.
+uint32_t crabml_token_bos(const struct CrabmlModel *model);
+
+// the end of sequence token, 0 if model is NULL.
+//
+// # Safety
+//
+// model is NULL or a valid handle.
+uint32_t crabml_token_eos(const struct CrabmlModel *model);
+
+// encode the text into the tokens, with the bos token in front if add_bos. the number of
+// tokens is written into `*n_tokens`, if it's over capacity, nothing is written into tokens
+// and `CRABML_STATUS_BUFFER_TOO_SMALL` is returned, so the call can be retried with a
+// larger buffer.
+//
+// # Safety
+//
+// text is a NUL terminated string, tokens has room for capacity tokens, n_tokens is valid.
+enum CrabmlStatus crabml_tokenize(const struct CrabmlModel *model,
+                                  const char *text,
+                                  bool add_bos,
+                                  uint32_t *tokens,
+                                  size_t capacity,
+                                  size_t *n_tokens);
+
+// decode the token into its bytes, the previous token decides whether the leading space is
+// stripped after the bos. the bytes are not always a valid utf8 string: a byte fallback
+// token carries only one byte of a multi-byte char, concatenate the bytes of the tokens
+// before converting them. the length is written into `*len`, and the bytes with a NUL
+// after them into buf, which needs `*len + 1` bytes, or `CRABML_STATUS_BUFFER_TOO_SMALL` is
+// returned.
+//
+// # Safety
+//
+// buf has room for capacity bytes, len is valid.
+enum CrabmlStatus crabml_token_to_piece(const struct CrabmlModel *model,
+                                        uint32_t prev_token,
+                                        uint32_t token,
+                                        char *buf,
+                                        size_t capacity,
+                                        size_t *len);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* CRABML_H */
@@ -0,0 +1,312 @@
+use std::ffi::c_char;
+use std::ffi::c_void;
+use std::ffi::CString;
+use std::rc::Rc;
+
+use crabml::backends::cpu::CpuTensor;
+use crabml::gguf::GGMLType;
+use crabml::sampler::Dist;
+use crabml::sampler::Penalties;
+use crabml::sampler::SamplerChain;
+use crabml::sampler::Temperature;
+use crabml::sampler::TopK;
+use crabml::sampler::TopP;
+use crabml::tensor::TensorMetrics;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::GenerationOptions;
+
+use crate::error::ffi_call;
+use crate::error::CrabmlStatus;
+use crate::error::FfiError;
+use crate::model::c_str;
+use crate::model::handle;
+use crate::model::CrabmlModel;
+use crate::model::ModelInner;
+
+/// the seed of `CrabmlSamplerParams` to sample with a random seed.
+pub const CRABML_SEED_RANDOM: u64 = u64::MAX;
+
+/// how the tokens are sampled, start from `crabml_sampler_params_default()`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct CrabmlSamplerParams {
+    /// 0 or below picks the most likely token, and the other params besides the penalties
+    /// are ignored.
+    pub temperature: f32,
+    /// sample from the k most likely tokens, 0 means no limit.
+    pub top_k: u32,
+    /// sample from the most likely tokens whose probabilities add up to top_p, 1.0 means
+    /// no limit.
+    pub top_p: f32,
+    /// penalize the tokens repeated in the last repeat_last_n tokens, 1.0 means disabled.
+    pub repeat_penalty: f32,
+    pub repeat_last_n: u32,
+    /// `CRABML_SEED_RANDOM` picks a random seed.
+    pub seed: u64,
+}
+
+impl Default for CrabmlSamplerParams {
+    fn default() -> Self {
+        Self {
+            temperature: 1.0,
+            top_k: 0,
+            top_p: 0.9,
+            repeat_penalty: 1.0,
+            repeat_last_n: 64,
+            seed: CRABML_SEED_RANDOM,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn crabml_sampler_params_default() -> CrabmlSamplerParams {
+    CrabmlSamplerParams::default()
+}
+
+impl CrabmlSamplerParams {
+    fn build(&self) -> SamplerChain {
+        let mut sampler = SamplerChain::new();
+        if self.seed != CRABML_SEED_RANDOM {
+            sampler = sampler.with_seed(self.seed);
+        }
+        sampler = sampler.with(Penalties::new(
+            self.repeat_last_n as usize,
+            self.repeat_penalty,
+            0.0,
+            0.0,
+        ));
+        if self.temperature <= 0.0 {
+            return sampler;
+        }
+        sampler
+            .with(TopK::new(self.top_k as usize))
+            .with(Temperature::new(self.temperature))
+            .with(TopP::new(self.top_p))
+            .with(Dist)
+    }
+}
+
+/// called on every generated token with the token id, its text, and the user_data passed
+/// to `crabml_generate()`. the text is NUL terminated and only valid during the call.
+/// returning false stops the generation.
+pub type CrabmlTokenCallback = Option<
+    unsafe extern "C" fn(
+        token: u32,
+        piece: *const c_char,
+        piece_len: usize,
+        user_data: *mut c_void,
+    ) -> bool,
+>;
+
+/// an opaque handle of a generation context, which holds the kv cache.
+pub struct CrabmlContext {
+    // dropped before the model it borrows the tensors from
+    runner: Llama2Runner<CpuTensor<'static>>,
+    _model: Rc<ModelInner>,
+}
+
+/// create a context to generate with the model, holding up to n_ctx tokens of the prompt
+/// and the generation, 0 takes the context length of the model. the handle is written into
+/// `*out`, which should be released by `crabml_context_free()`. the context keeps the model
+/// alive, but shares it, so it must not be used at the same time as the model or its other
+/// contexts.
+///
+/// # Safety
+///
+/// model is a valid handle, out is a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn crabml_context_new(
+    model: *const CrabmlModel,
+    n_ctx: u32,
+    out: *mut *mut CrabmlContext,
+) -> CrabmlStatus {
+    ffi_call(|| {
+        let model = handle(model, "model")?;
+        if out.is_null() {
+            return Err(FfiError::null_pointer("out"));
+        }
+        let lm = &*model.inner.model;
+        let n_ctx = match n_ctx {
+            0 => lm.conf.seq_len,
+            n => n as usize,
+        };
+        let runner = Llama2Runner::new(lm, TensorMetrics::default(), n_ctx, GGMLType::F16)?;
+        let context = CrabmlContext {
+            runner,
+            _model: model.inner.clone(),
+        };
+        *out = Box::into_raw(Box::new(context));
+        Ok(())
+    })
+}
+
+/// release the context handle, NULL is ignored.
+///
+/// # Safety
+///
+/// context is NULL or a handle from `crabml_context_new()`, and it's not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn crabml_context_free(context: *mut CrabmlContext) {
+    if !context.is_null() {
+        drop(Box::from_raw(context));
+    }
+}
+
+/// generate up to max_tokens tokens after the prompt, calling the callback on every token.
+/// the kv cache is reset first, so every call starts a new conversation. the generation
+/// stops on the eos token, when the context is full, or when the callback returns false,
+/// these all return `CRABML_STATUS_OK`. sampler can be NULL for the defaults, and the
+/// number of generated tokens is written into `*n_generated` if it's not NULL.
+///
+/// # Safety
+///
+/// context is a valid handle, prompt is a NUL terminated string, sampler and n_generated
+/// are NULL or valid, the callback is NULL or safe to call with user_data.
+#[no_mangle]
+pub unsafe extern "C" fn crabml_generate(
+    context: *mut CrabmlContext,
+    prompt: *const c_char,
+    max_tokens: u32,
+    sampler: *const CrabmlSamplerParams,
+    callback: CrabmlTokenCallback,
+    user_data: *mut c_void,
+    n_generated: *mut usize,
+) -> CrabmlStatus {
+    ffi_call(|| {
+        let context = context
+            .as_mut()
+            .ok_or_else(|| FfiError::null_pointer("context"))?;
+        let prompt = c_str(prompt, "prompt")?;
+        let mut sampler = sampler.as_ref().copied().unwrap_or_default().build();
+        if let Some(n) = n_generated.as_mut() {
+            *n = 0;
+        }
+
+        context.runner.reset_kv_cache()?;
+        let options = GenerationOptions::new(max_tokens as usize);
+        let stream = context.runner.stream(prompt, &mut sampler, options)?;
+        for token in stream {
+            let token = token?;
+            if let Some(n) = n_generated.as_mut() {
+                *n += 1;
+            }
+            if let Some(callback) = callback {
+                let piece = CString::new(token.piece.replace('\0', " ")).unwrap();
+                let len = piece.as_bytes().len();
+                if !callback(token.token as u32, piece.as_ptr(), len, user_data) {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+    use std::ptr;
+
+    use super::*;
+    use crate::*;
+
+    unsafe extern "C" fn collect_text(
+        _token: u32,
+        piece: *const c_char,
+        _piece_len: usize,
+        user_data: *mut c_void,
+    ) -> bool {
+        let text = &mut *(user_data as *mut String);
+        text.push_str(CStr::from_ptr(piece).to_str().unwrap());
+        true
+    }
+
+    #[test]
+    fn test_generate() {
+        unsafe {
+            let path = CString::new("../testdata/tinyllamas-stories-15m-q8_0.gguf").unwrap();
+            let mut model = ptr::null_mut();
+            let status = crabml_model_load(path.as_ptr(), ptr::null(), &mut model);
+            assert_eq!(status, CrabmlStatus::Ok);
+            assert!(crabml_last_error().is_null());
+            assert_eq!(crabml_model_vocab_size(model), 32000);
+
+            // the required length is reported on a small buffer
+            let text = CString::new("Lily is a cute cat, ").unwrap();
+            let mut tokens = [0u32; 4];
+            let mut n_tokens = 0;
+            let status = crabml_tokenize(
+                model,
+                text.as_ptr(),
+                true,
+                tokens.as_mut_ptr(),
+                4,
+                &mut n_tokens,
+            );
+            assert_eq!(status, CrabmlStatus::BufferTooSmall);
+            assert!(!crabml_last_error().is_null());
+            let mut tokens = vec![0u32; n_tokens];
+            let status = crabml_tokenize(
+                model,
+                text.as_ptr(),
+                true,
+                tokens.as_mut_ptr(),
+                n_tokens,
+                &mut n_tokens,
+            );
+            assert_eq!(status, CrabmlStatus::Ok);
+            assert_eq!(tokens[0], crabml_token_bos(model));
+
+            let mut buf = [0 as c_char; 16];
+            let mut len = 0;
+            let status =
+                crabml_token_to_piece(model, tokens[0], tokens[1], buf.as_mut_ptr(), 16, &mut len);
+            assert_eq!(status, CrabmlStatus::Ok);
+            assert_eq!(CStr::from_ptr(buf.as_ptr()).to_str().unwrap(), "L");
+
+            // the context keeps the model alive
+            let mut context = ptr::null_mut();
+            assert_eq!(
+                crabml_context_new(model, 200, &mut context),
+                CrabmlStatus::Ok
+            );
+            crabml_model_free(model);
+
+            let mut params = crabml_sampler_params_default();
+            params.temperature = 0.0;
+            let mut out = String::new();
+            let mut n_generated = 0;
+            let status = crabml_generate(
+                context,
+                text.as_ptr(),
+                11,
+                &params,
+                Some(collect_text),
+                &mut out as *mut String as *mut c_void,
+                &mut n_generated,
+            );
+            assert_eq!(status, CrabmlStatus::Ok);
+            assert_eq!(out, "3 years old. She likes to play with her");
+            assert_eq!(n_generated, 11);
+            crabml_context_free(context);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        unsafe {
+            let path = CString::new("../testdata/not-found.gguf").unwrap();
+            let mut model = ptr::null_mut();
+            let status = crabml_model_load(path.as_ptr(), ptr::null(), &mut model);
+            assert_eq!(status, CrabmlStatus::IoError);
+            assert!(model.is_null());
+            assert!(!crabml_last_error().is_null());
+
+            let status = crabml_model_load(ptr::null(), ptr::null(), &mut model);
+            assert_eq!(status, CrabmlStatus::NullPointer);
+            let message = CStr::from_ptr(crabml_last_error()).to_str().unwrap();
+            assert_eq!(message, "path is NULL");
+            crabml_model_free(ptr::null_mut());
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::cell::RefCell;
+use std::ffi::c_char;
+use std::ffi::CString;
+use std::panic::catch_unwind;
+use std::panic::AssertUnwindSafe;
+use std::ptr;
+
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+
+/// the result of a call, `CRABML_STATUS_OK` on success. the error codes besides the last
+/// three are mapped from the `ErrorKind` of crabml.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CrabmlStatus {
+    Ok = 0,
+    Unexpected = 1,
+    IoError = 2,
+    TensorNotFound = 3,
+    ModelError = 4,
+    BadInput = 5,
+    FormatError = 6,
+    TensorError = 7,
+    NotImplemented = 8,
+    OutOfMemory = 9,
+    /// the output buffer is too small, the required length is still written out.
+    BufferTooSmall = 100,
+    /// a required pointer argument is NULL.
+    NullPointer = 101,
+    /// crabml panicked, the handles involved should not be used any more.
+    Panic = 102,
+}
+
+impl From<ErrorKind> for CrabmlStatus {
+    fn from(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::Unexpected => CrabmlStatus::Unexpected,
+            ErrorKind::IOError => CrabmlStatus::IoError,
+            ErrorKind::TensorNotFound => CrabmlStatus::TensorNotFound,
+            ErrorKind::ModelError => CrabmlStatus::ModelError,
+            ErrorKind::BadInput => CrabmlStatus::BadInput,
+            ErrorKind::FormatError => CrabmlStatus::FormatError,
+            ErrorKind::TensorError => CrabmlStatus::TensorError,
+            ErrorKind::NotImplemented => CrabmlStatus::NotImplemented,
+            ErrorKind::OutOfMemory => CrabmlStatus::OutOfMemory,
+        }
+    }
+}
+
+// an error raised by the C API itself, which has no ErrorKind in crabml
+pub(crate) struct FfiError {
+    status: CrabmlStatus,
+    message: String,
+}
+
+impl From<Error> for FfiError {
+    fn from(err: Error) -> Self {
+        Self {
+            status: err.kind.into(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl FfiError {
+    pub(crate) fn null_pointer(name: &str) -> Self {
+        Self {
+            status: CrabmlStatus::NullPointer,
+            message: format!("{} is NULL", name),
+        }
+    }
+
+    pub(crate) fn buffer_too_small(required: usize, capacity: usize) -> Self {
+        Self {
+            status: CrabmlStatus::BufferTooSmall,
+            message: format!(
+                "the buffer of {} is too small, {} required",
+                capacity, required
+            ),
+        }
+    }
+}
+
+pub(crate) type FfiResult<T> = std::result::Result<T, FfiError>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: Option<String>) {
+    // the interior NULs can not be passed in a C string
+    let message = message.map(|m| CString::new(m.replace('\0', " ")).unwrap());
+    LAST_ERROR.with(|e| *e.borrow_mut() = message);
+}
+
+/// run the body of an exported function, keep the message of its error for
+/// `crabml_last_error()`, and stop a panic at the boundary.
+pub(crate) fn ffi_call(f: impl FnOnce() -> FfiResult<()>) -> CrabmlStatus {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => {
+            set_last_error(None);
+            CrabmlStatus::Ok
+        }
+        Ok(Err(err)) => {
+            set_last_error(Some(err.message));
+            err.status
+        }
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            set_last_error(Some(format!("panicked: {}", message)));
+            CrabmlStatus::Panic
+        }
+    }
+}
+
+/// the message of the last error on the calling thread, or NULL if the last call succeeded.
+/// the string is owned by crabml and only valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn crabml_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |m| m.as_ptr()))
+}
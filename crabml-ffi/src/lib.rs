@@ -0,0 +1,26 @@
+//! the C API of crabml, to embed it in the applications in other languages like python or
+//! swift. the header is generated by cbindgen into `include/crabml.h`.
+//!
+//! all the objects are behind the opaque handles:
+//!
+//! - a `CrabmlModel` holds the weights and the tokenizer, it's created by
+//!   `crabml_model_load()` and released by `crabml_model_free()`.
+//! - a `CrabmlContext` holds the kv cache of a generation, it's created from a model by
+//!   `crabml_context_new()` and released by `crabml_context_free()`. a context keeps its
+//!   model alive, so the model can be freed before its contexts.
+//!
+//! the handles are not thread safe: a model and all the contexts created from it share the
+//! weights, they must be used from one thread at a time. different models can be used on
+//! different threads.
+//!
+//! every fallible function returns a `CrabmlStatus`, and the message of the last error on
+//! the calling thread is kept for `crabml_last_error()`. a panic inside crabml never unwinds
+//! into the caller, it's reported as `CRABML_STATUS_PANIC`.
+
+mod context;
+mod error;
+mod model;
+
+pub use context::*;
+pub use error::*;
+pub use model::*;
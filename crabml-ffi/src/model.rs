@@ -0,0 +1,299 @@
+use std::ffi::c_char;
+use std::ffi::CStr;
+use std::mem::ManuallyDrop;
+use std::ptr;
+use std::rc::Rc;
+
+use crabml::backends::cpu::CpuTensorDevice;
+use crabml::error::ErrorKind;
+use crabml::gguf::GGUFFile;
+use crabml::gguf::GGUFFileLoader;
+use crabml::gguf::GGUFLoadMode;
+use crabml::tokenizer::BpeTokenizer;
+use crabml_llama2::CpuLlama2Model;
+use crabml_llama2::ModelLoadOptions;
+
+use crate::error::ffi_call;
+use crate::error::CrabmlStatus;
+use crate::error::FfiError;
+use crate::error::FfiResult;
+
+/// the options of `crabml_model_load()`, start from `crabml_model_params_default()`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct CrabmlModelParams {
+    /// map the file and read the tensors on their first access, or read the whole file into
+    /// the memory up front.
+    pub use_mmap: bool,
+    /// pin the model in the memory so it's never swapped out.
+    pub use_mlock: bool,
+    /// run only the first n transformer layers, 0 runs all of them.
+    pub n_layers: u32,
+}
+
+impl Default for CrabmlModelParams {
+    fn default() -> Self {
+        Self {
+            use_mmap: true,
+            use_mlock: false,
+            n_layers: 0,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn crabml_model_params_default() -> CrabmlModelParams {
+    CrabmlModelParams::default()
+}
+
+// the model borrows the tensors from the gguf file, which borrows the mapped file from the
+// loader. they're leaked to live as long as the model, and dropped in the reverse order.
+pub(crate) struct ModelInner {
+    pub(crate) model: ManuallyDrop<CpuLlama2Model<'static>>,
+    gf: *mut GGUFFile<'static>,
+    loader: *mut GGUFFileLoader,
+}
+
+impl Drop for ModelInner {
+    fn drop(&mut self) {
+        unsafe {
+            ManuallyDrop::drop(&mut self.model);
+            drop(Box::from_raw(self.gf));
+            drop(Box::from_raw(self.loader));
+        }
+    }
+}
+
+/// an opaque handle of a loaded model.
+pub struct CrabmlModel {
+    pub(crate) inner: Rc<ModelInner>,
+}
+
+impl CrabmlModel {
+    fn load(path: &str, params: &CrabmlModelParams) -> FfiResult<Self> {
+        let mut options = ModelLoadOptions::new().with_mlock(params.use_mlock);
+        if !params.use_mmap {
+            options = options.with_load_mode(GGUFLoadMode::Buffered);
+        }
+        if params.n_layers > 0 {
+            options = options.with_n_layers(params.n_layers as usize);
+        }
+
+        unsafe {
+            let loader = Box::into_raw(Box::new(options.open(path)?));
+            let gf: *mut GGUFFile<'static> = match (*loader).open() {
+                Ok(gf) => Box::into_raw(Box::new(gf)),
+                Err(err) => {
+                    drop(Box::from_raw(loader));
+                    return Err(err.into());
+                }
+            };
+            let model =
+                match CpuLlama2Model::load_with_options(&*gf, CpuTensorDevice::new(), options) {
+                    Ok(model) => model,
+                    Err(err) => {
+                        drop(Box::from_raw(gf));
+                        drop(Box::from_raw(loader));
+                        return Err(err.into());
+                    }
+                };
+            let inner = ModelInner {
+                model: ManuallyDrop::new(model),
+                gf,
+                loader,
+            };
+            Ok(Self {
+                inner: Rc::new(inner),
+            })
+        }
+    }
+
+    pub(crate) fn tokenizer(&self) -> &BpeTokenizer {
+        &self.inner.model.tokenizer
+    }
+}
+
+pub(crate) unsafe fn c_str<'a>(s: *const c_char, name: &str) -> FfiResult<&'a str> {
+    if s.is_null() {
+        return Err(FfiError::null_pointer(name));
+    }
+    CStr::from_ptr(s).to_str().map_err(|_| {
+        crabml::error::Error::from((ErrorKind::BadInput, format!("{} is not valid utf8", name)))
+            .into()
+    })
+}
+
+pub(crate) unsafe fn handle<'a, T>(p: *const T, name: &str) -> FfiResult<&'a T> {
+    p.as_ref().ok_or_else(|| FfiError::null_pointer(name))
+}
+
+/// load a gguf model from the path, params can be NULL for the defaults. on success the
+/// handle is written into `*out`, which should be released by `crabml_model_free()`.
+///
+/// # Safety
+///
+/// path is a NUL terminated string, params is NULL or valid, out is a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn crabml_model_load(
+    path: *const c_char,
+    params: *const CrabmlModelParams,
+    out: *mut *mut CrabmlModel,
+) -> CrabmlStatus {
+    ffi_call(|| {
+        if out.is_null() {
+            return Err(FfiError::null_pointer("out"));
+        }
+        let path = c_str(path, "path")?;
+        let params = params.as_ref().copied().unwrap_or_default();
+        let model = CrabmlModel::load(path, &params)?;
+        *out = Box::into_raw(Box::new(model));
+        Ok(())
+    })
+}
+
+/// release the model handle, NULL is ignored. the weights are kept until the contexts
+/// created from the model are also released.
+///
+/// # Safety
+///
+/// model is NULL or a handle from `crabml_model_load()`, and it's not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn crabml_model_free(model: *mut CrabmlModel) {
+    if !model.is_null() {
+        drop(Box::from_raw(model));
+    }
+}
+
+/// the number of tokens in the vocabulary, 0 if model is NULL.
+///
+/// # Safety
+///
+/// model is NULL or a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn crabml_model_vocab_size(model: *const CrabmlModel) -> u32 {
+    model
+        .as_ref()
+        .map_or(0, |m| m.inner.model.conf.vocab_size as u32)
+}
+
+/// the context length the model is trained on, 0 if model is NULL.
+///
+/// # Safety
+///
+/// model is NULL or a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn crabml_model_n_ctx(model: *const CrabmlModel) -> u32 {
+    model
+        .as_ref()
+        .map_or(0, |m| m.inner.model.conf.seq_len as u32)
+}
+
+/// the beginning of sequence token, 0 if model is NULL.
+///
+/// # Safety
+///
+/// model is NULL or a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn crabml_token_bos(model: *const CrabmlModel) -> u32 {
+    model
+        .as_ref()
+        .map_or(0, |m| m.tokenizer().bos_token() as u32)
+}
+
+/// the end of sequence token, 0 if model is NULL.
+///
+/// # Safety
+///
+/// model is NULL or a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn crabml_token_eos(model: *const CrabmlModel) -> u32 {
+    model
+        .as_ref()
+        .map_or(0, |m| m.tokenizer().eos_token() as u32)
+}
+
+/// encode the text into the tokens, with the bos token in front if add_bos. the number of
+/// tokens is written into `*n_tokens`, if it's over capacity, nothing is written into tokens
+/// and `CRABML_STATUS_BUFFER_TOO_SMALL` is returned, so the call can be retried with a
+/// larger buffer.
+///
+/// # Safety
+///
+/// text is a NUL terminated string, tokens has room for capacity tokens, n_tokens is valid.
+#[no_mangle]
+pub unsafe extern "C" fn crabml_tokenize(
+    model: *const CrabmlModel,
+    text: *const c_char,
+    add_bos: bool,
+    tokens: *mut u32,
+    capacity: usize,
+    n_tokens: *mut usize,
+) -> CrabmlStatus {
+    ffi_call(|| {
+        let model = handle(model, "model")?;
+        let text = c_str(text, "text")?;
+        if n_tokens.is_null() {
+            return Err(FfiError::null_pointer("n_tokens"));
+        }
+        let encoded = model.tokenizer().encode(text, add_bos, false)?;
+        *n_tokens = encoded.len();
+        if encoded.len() > capacity {
+            return Err(FfiError::buffer_too_small(encoded.len(), capacity));
+        }
+        if !encoded.is_empty() {
+            if tokens.is_null() {
+                return Err(FfiError::null_pointer("tokens"));
+            }
+            for (i, token) in encoded.iter().enumerate() {
+                *tokens.add(i) = *token as u32;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// decode the token into its bytes, the previous token decides whether the leading space is
+/// stripped after the bos. the bytes are not always a valid utf8 string: a byte fallback
+/// token carries only one byte of a multi-byte char, concatenate the bytes of the tokens
+/// before converting them. the length is written into `*len`, and the bytes with a NUL
+/// after them into buf, which needs `*len + 1` bytes, or `CRABML_STATUS_BUFFER_TOO_SMALL` is
+/// returned.
+///
+/// # Safety
+///
+/// buf has room for capacity bytes, len is valid.
+#[no_mangle]
+pub unsafe extern "C" fn crabml_token_to_piece(
+    model: *const CrabmlModel,
+    prev_token: u32,
+    token: u32,
+    buf: *mut c_char,
+    capacity: usize,
+    len: *mut usize,
+) -> CrabmlStatus {
+    ffi_call(|| {
+        let model = handle(model, "model")?;
+        if len.is_null() {
+            return Err(FfiError::null_pointer("len"));
+        }
+        let tokenizer = model.tokenizer();
+        if token as usize >= tokenizer.vocab().len() {
+            return Err(crabml::error::Error::from((
+                ErrorKind::BadInput,
+                format!("token {} is out of the vocabulary", token),
+            ))
+            .into());
+        }
+        let bytes = tokenizer.decode_bytes(prev_token as usize, token as usize)?;
+        *len = bytes.len();
+        if bytes.len() + 1 > capacity {
+            return Err(FfiError::buffer_too_small(bytes.len() + 1, capacity));
+        }
+        if buf.is_null() {
+            return Err(FfiError::null_pointer("buf"));
+        }
+        ptr::copy_nonoverlapping(bytes.as_ptr(), buf as *mut u8, bytes.len());
+        *buf.add(bytes.len()) = 0;
+        Ok(())
+    })
+}
@@ -0,0 +1,800 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use std::time::Instant;
+
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::sampler::SamplerChain;
+use crabml::tensor::Tensor;
+
+use crate::llama2::Llama2Runner;
+use crate::stop::find_stop;
+use crate::stop::HeldTokens;
+use crate::stream::FinishReason;
+use crate::stream::GeneratedToken;
+use crate::stream::GenerationOptions;
+use crate::stream::StepMetrics;
+
+/// the id of a request in a `BatchScheduler`, in the order of `add_request()`.
+pub type RequestId = usize;
+
+/// the kv cache of a sequence, it's swapped into the runner when the tokens of the sequence
+/// are attended.
+pub(crate) struct KvSlot<T: Tensor> {
+    pub(crate) key_cache: Vec<Option<T>>, // (layer, seq_len, kv_dim)
+    pub(crate) value_cache: Vec<Option<T>>, // (layer, seq_len, kv_dim)
+}
+
+impl<T: Tensor> Default for KvSlot<T> {
+    fn default() -> Self {
+        Self {
+            key_cache: vec![],
+            value_cache: vec![],
+        }
+    }
+}
+
+impl<T: Tensor> KvSlot<T> {
+    // forget all the positions, the capacity is kept
+    fn reset(&mut self) -> Result<()> {
+        self.truncate(0)
+    }
+
+    // keep only the first len positions
+    pub(crate) fn truncate(&mut self, len: usize) -> Result<()> {
+        for cache in self.key_cache.iter_mut().chain(self.value_cache.iter_mut()) {
+            let t = cache.take().unwrap().resize(1, len)?;
+            cache.replace(t);
+        }
+        Ok(())
+    }
+
+    // replace the positions with the ones of src, like forking a sequence. the q8_0 caches
+    // can not be copied yet.
+    pub(crate) fn copy_from(&mut self, src: &KvSlot<T>) -> Result<()> {
+        self.reset()?;
+        let dst = self.key_cache.iter_mut().chain(self.value_cache.iter_mut());
+        let src = src.key_cache.iter().chain(src.value_cache.iter());
+        for (dst, src) in dst.zip(src) {
+            let src = src.as_ref().unwrap();
+            if src.strider().shape()[1] > 0 {
+                dst.as_mut().unwrap().concatenate(src, 1)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// a sequence in a batch, the rows of start..start + len are at the positions from pos on
+pub(crate) struct BatchSegment<T: Tensor> {
+    pub(crate) start: usize,
+    pub(crate) len: usize,
+    pub(crate) pos: usize,
+    pub(crate) slot: KvSlot<T>,
+}
+
+/// a token of a request yielded on a `BatchScheduler::step()`. a step yields several tokens
+/// of a request at once when the tokens held back as the start of a stop string are released.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchOutput {
+    pub id: RequestId,
+    /// None if the request finishes without a new token, like on the eos token.
+    pub token: Option<GeneratedToken>,
+    /// the seed of the sampler of the request, to reproduce it on its own.
+    pub seed: u64,
+    /// set on the last output of the request, its kv cache slot is released by then.
+    pub finish_reason: Option<FinishReason>,
+}
+
+struct WaitingRequest {
+    id: RequestId,
+    prompt_tokens: Vec<usize>,
+    sampler: SamplerChain,
+    options: GenerationOptions,
+}
+
+struct ActiveRequest<T: Tensor> {
+    id: RequestId,
+    sampler: SamplerChain,
+    options: GenerationOptions,
+    slot: KvSlot<T>,
+    // the tokens to feed on the next step: the prompt at first, then the last sampled one
+    pending: Vec<usize>,
+    pos: usize,
+    prev_token: usize,
+    n_generated: usize,
+    text: String,
+    held: HeldTokens,
+    started_at: Instant,
+    finished: bool,
+    // the step it's last forwarded on, the decoding requests left out of a full step go
+    // first on the next one
+    last_step: usize,
+}
+
+impl<T: Tensor> ActiveRequest<T> {
+    // done with the prompt, a token is sampled on every step it's forwarded
+    fn is_decoding(&self) -> bool {
+        self.n_generated > 0
+    }
+
+    // the tokens held back go out before the last output, which carries the reason
+    fn finish(&mut self, reason: FinishReason, outputs: &mut Vec<BatchOutput>) {
+        let tokens = self.held.flush();
+        self.finish_with(tokens, reason, outputs);
+    }
+
+    fn finish_with(
+        &mut self,
+        mut tokens: Vec<GeneratedToken>,
+        reason: FinishReason,
+        outputs: &mut Vec<BatchOutput>,
+    ) {
+        self.finished = true;
+        let last = tokens.pop();
+        self.emit(tokens, outputs);
+        outputs.push(BatchOutput {
+            finish_reason: Some(reason),
+            ..self.output(last)
+        });
+    }
+
+    fn emit(&self, tokens: Vec<GeneratedToken>, outputs: &mut Vec<BatchOutput>) {
+        outputs.extend(tokens.into_iter().map(|t| self.output(Some(t))));
+    }
+
+    fn output(&self, token: Option<GeneratedToken>) -> BatchOutput {
+        BatchOutput {
+            id: self.id,
+            token,
+            seed: self.sampler.seed(),
+            finish_reason: None,
+        }
+    }
+}
+
+/// continuous batching: serves several independent requests on one runner. each running
+/// request holds a kv cache slot, and a `step()` forwards the pending tokens of all of them
+/// in one pass, so the matmuls are batched over the requests instead of running the requests
+/// one after another. the prompt of a new request is forwarded along with the tokens of the
+/// others on the next step.
+///
+/// the requests beyond the slots wait in a queue until a running one finishes. the kv cache
+/// of the runner itself is not touched.
+///
+/// a request gets the same logits whoever it's batched with: the kernels compute every row
+/// of a matmul and a norm on its own, in a summation order which does not depend on the
+/// batch size or the threads, each sequence attends over its own kv cache slot, and a
+/// released slot is reset before the next request. the requests are forwarded in the order
+/// they were added, and a step never fails the running requests for a new prompt which does
+/// not fit: the prompts are split over the steps by the tokens left in a pass, see
+/// `with_max_step_tokens()` and `with_target_step_latency()`.
+pub struct BatchScheduler<'a, T: Tensor> {
+    runner: &'a mut Llama2Runner<T>,
+    free_slots: Vec<KvSlot<T>>,
+    active: Vec<ActiveRequest<T>>,
+    waiting: VecDeque<WaitingRequest>,
+    next_id: RequestId,
+    max_step_tokens: Option<usize>,
+    target_step_latency: Option<Duration>,
+    // the moving average of the time to forward a token, measured on the steps
+    token_latency: Option<Duration>,
+    n_steps: usize,
+}
+
+impl<'a, T: Tensor> BatchScheduler<'a, T> {
+    /// allocate the kv caches of n_slots sequences with the capacity of the runner's seq_len,
+    /// they count into the memory budget of the runner.
+    pub fn new(runner: &'a mut Llama2Runner<T>, n_slots: usize) -> Result<Self> {
+        if n_slots == 0 {
+            return Err((ErrorKind::BadInput, "n_slots should be at least 1").into());
+        }
+        let free_slots = runner.alloc_kv_slots(n_slots)?;
+        Ok(Self {
+            runner,
+            free_slots,
+            active: vec![],
+            waiting: VecDeque::new(),
+            next_id: 0,
+            max_step_tokens: None,
+            target_step_latency: None,
+            token_latency: None,
+            n_steps: 0,
+        })
+    }
+
+    /// forward at most this many tokens on a step, besides the max batch of the runner in
+    /// its memory budget. the decoding requests take their token first, the ones beyond the
+    /// budget go first on the next step. the prompts are forwarded in chunks of the tokens
+    /// left and sample their first token after the last chunk, which gives the same tokens
+    /// as the whole prompt at once.
+    pub fn with_max_step_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_step_tokens = Some(max_tokens.max(1));
+        self
+    }
+
+    /// size the steps to take about this long, by the time per token measured on the
+    /// previous steps. a long prompt is forwarded in the chunks which fit in the latency, so
+    /// the decoding requests batched with it keep a steady pace. the first step is bounded
+    /// by the max step tokens only, and a step forwards at least 1 token.
+    pub fn with_target_step_latency(mut self, latency: Duration) -> Self {
+        self.target_step_latency = Some(latency);
+        self
+    }
+
+    /// the max tokens to forward on the next step, by the max step tokens, the max batch of
+    /// the runner and the target step latency. None if unbounded.
+    pub fn step_tokens(&self) -> Option<usize> {
+        let by_latency = match (self.target_step_latency, self.token_latency) {
+            (Some(target), Some(per_token)) if !per_token.is_zero() => {
+                let n = target.as_secs_f64() / per_token.as_secs_f64();
+                Some((n as usize).max(1))
+            }
+            _ => None,
+        };
+        [self.max_step_tokens, self.runner.max_batch(), by_latency]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// queue a request, it starts on the next step if there's a free slot. the prompt is
+    /// truncated to fit into the kv cache by the runner's truncation options. the stop lists,
+    /// max_tokens and the cancellation in the options work as in `Llama2Runner::stream()`.
+    pub fn add_request(
+        &mut self,
+        prompt: &str,
+        mut sampler: SamplerChain,
+        options: GenerationOptions,
+    ) -> Result<RequestId> {
+        let prompt_tokens = self.runner.prompt_tokens(prompt)?;
+        for token in prompt_tokens.iter() {
+            sampler.accept(*token);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.waiting.push_back(WaitingRequest {
+            id,
+            prompt_tokens,
+            sampler,
+            options,
+        });
+        Ok(id)
+    }
+
+    /// the number of the requests holding a slot.
+    pub fn n_running(&self) -> usize {
+        self.active.len()
+    }
+
+    /// the number of the requests waiting for a slot.
+    pub fn n_waiting(&self) -> usize {
+        self.waiting.len()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.active.is_empty() && self.waiting.is_empty()
+    }
+
+    /// start the waiting requests on the free slots, forward the pending tokens of the
+    /// running requests in one pass within the max tokens of a step, and sample a token for
+    /// each one done with its prompt. returns the new tokens and the finished requests. on a
+    /// forward error, all the running requests are dropped.
+    pub fn step(&mut self) -> Result<Vec<BatchOutput>> {
+        while !self.waiting.is_empty() && !self.free_slots.is_empty() {
+            let req = self.waiting.pop_front().unwrap();
+            let prev_token = *req.prompt_tokens.last().unwrap();
+            self.active.push(ActiveRequest {
+                id: req.id,
+                sampler: req.sampler,
+                options: req.options,
+                slot: self.free_slots.pop().unwrap(),
+                pending: req.prompt_tokens,
+                pos: 0,
+                prev_token,
+                n_generated: 0,
+                text: String::new(),
+                held: HeldTokens::default(),
+                started_at: Instant::now(),
+                finished: false,
+                last_step: 0,
+            });
+        }
+
+        // finish the requests which can not go on before the forward pass
+        let seq_len = self.runner.seq_len();
+        let mut outputs = vec![];
+        for req in self.active.iter_mut() {
+            if req
+                .options
+                .cancel
+                .as_ref()
+                .is_some_and(|c| c.is_cancelled())
+            {
+                req.finish(FinishReason::Cancelled, &mut outputs);
+            } else if req.n_generated >= req.options.max_tokens
+                || req.pos + req.pending.len() > seq_len
+            {
+                req.finish(FinishReason::Length, &mut outputs);
+            }
+        }
+        self.release_finished()?;
+        if self.active.is_empty() {
+            return Ok(outputs);
+        }
+
+        self.n_steps += 1;
+        let n_forward = self.plan_step();
+        let mut seqs = self
+            .active
+            .iter_mut()
+            .zip(n_forward.iter())
+            .filter(|(_, n)| **n > 0)
+            .map(|(req, n)| (&req.pending[..*n], req.pos, &mut req.slot))
+            .collect::<Vec<_>>();
+        let n_tokens = n_forward.iter().sum::<usize>();
+        let forward_started_at = Instant::now();
+        let mut logits = match self.runner.forward_segments(&mut seqs) {
+            Ok(logits) => logits,
+            Err(err) => {
+                self.active.iter_mut().for_each(|req| req.finished = true);
+                self.release_finished()?;
+                return Err(err);
+            }
+        };
+
+        let per_token = forward_started_at.elapsed() / n_tokens.max(1) as u32;
+        self.token_latency = Some(match self.token_latency {
+            Some(avg) => (avg * 3 + per_token) / 4,
+            None => per_token,
+        });
+
+        let vocab_size = self.runner.conf().vocab_size;
+        let eos_token = self.runner.tokenizer().eos_token();
+        let mut rows = logits.chunks_exact_mut(vocab_size);
+        for (req, n) in self.active.iter_mut().zip(n_forward) {
+            if n == 0 {
+                continue;
+            }
+            let logits = rows.next().unwrap();
+            req.last_step = self.n_steps;
+            req.pos += n;
+            req.pending.drain(..n);
+            // the rest of the prompt goes on the next steps
+            if !req.pending.is_empty() {
+                continue;
+            }
+            let token = match req.sampler.sample(logits) {
+                Ok(token) => token,
+                Err(_) => {
+                    req.finish(FinishReason::Error, &mut outputs);
+                    continue;
+                }
+            };
+            if token == eos_token || req.options.stop_tokens.contains(&token) {
+                req.finish(FinishReason::StopToken, &mut outputs);
+                continue;
+            }
+            let piece = match self.runner.tokenizer().decode(req.prev_token, token) {
+                Ok(piece) => piece,
+                Err(_) => {
+                    req.finish(FinishReason::Error, &mut outputs);
+                    continue;
+                }
+            };
+            req.pending = vec![token];
+            req.prev_token = token;
+            req.n_generated += 1;
+
+            let now = Instant::now();
+            let offset = req.text.len();
+            req.text.push_str(&piece);
+            req.held.push(offset, GeneratedToken {
+                token,
+                piece,
+                logprob: req.sampler.last_logprob(),
+                metrics: req
+                    .options
+                    .metrics_temperature
+                    .map(|t| StepMetrics::from_logits(logits, token, t)),
+                seed: Some(req.sampler.seed()),
+                elapsed: now - req.started_at,
+            });
+            req.started_at = now;
+
+            // the stop string is cut off and held back the same way as in GenerationStream
+            if let Some((stop_at, reason)) = find_stop(&req.options, &req.text, offset) {
+                req.text.truncate(stop_at);
+                let tokens = req.held.cut(stop_at);
+                req.finish_with(tokens, reason, &mut outputs);
+            } else if req.n_generated >= req.options.max_tokens || req.pos >= seq_len {
+                req.finish(FinishReason::Length, &mut outputs);
+            } else {
+                let tokens = req.held.release(&req.text, &req.options.stop_strings);
+                req.emit(tokens, &mut outputs);
+            }
+        }
+        self.release_finished()?;
+        Ok(outputs)
+    }
+
+    // the tokens of each running request to forward on this step, at most the step tokens
+    // in total. the decoding requests go first, the ones waited the longest first, and the
+    // ones beyond the budget wait for the next step. then the prompts take the tokens left
+    // in the order of the requests.
+    fn plan_step(&self) -> Vec<usize> {
+        let mut budget = self.step_tokens().unwrap_or(usize::MAX);
+        let mut n_forward = vec![0; self.active.len()];
+        let mut decoding = (0..self.active.len())
+            .filter(|i| self.active[*i].is_decoding())
+            .collect::<Vec<_>>();
+        decoding.sort_by_key(|i| self.active[*i].last_step);
+        for i in decoding.into_iter().take(budget) {
+            n_forward[i] = 1;
+            budget -= 1;
+        }
+        for (req, n) in self.active.iter().zip(n_forward.iter_mut()) {
+            if !req.is_decoding() {
+                *n = req.pending.len().min(budget);
+                budget -= *n;
+            }
+        }
+        n_forward
+    }
+
+    // reset the slots of the finished requests and put them back to the free list
+    fn release_finished(&mut self) -> Result<()> {
+        let mut i = 0;
+        while i < self.active.len() {
+            if !self.active[i].finished {
+                i += 1;
+                continue;
+            }
+            let mut req = self.active.remove(i);
+            req.slot.reset()?;
+            self.free_slots.push(req.slot);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::backends::cpu::CpuTensorDeviceOptions;
+    use crabml::gguf::GGMLType;
+    use crabml::gguf::GGUFFileLoader;
+    use crabml::tensor::TensorMetrics;
+
+    use super::*;
+    use crate::CpuLlama2Model;
+    use crate::HiddenBias;
+
+    #[test]
+    fn test_batch_scheduler() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+
+        let prompts = [
+            "Lily is a cute cat, ",
+            "Once upon a time",
+            "Tom has a red ball",
+        ];
+        let mut expected = vec![];
+        for prompt in prompts {
+            runner.reset_kv_cache()?;
+            let mut sampler = SamplerChain::new();
+            let mut stream = runner.stream(prompt, &mut sampler, GenerationOptions::new(10))?;
+            stream.by_ref().collect::<Result<Vec<_>>>()?;
+            expected.push(stream.text().to_string());
+        }
+        runner.reset_kv_cache()?;
+
+        // the third request waits for the slot of a finished one
+        let mut scheduler = BatchScheduler::new(&mut runner, 2)?;
+        let mut ids = vec![];
+        for (i, prompt) in prompts.iter().enumerate() {
+            let options = GenerationOptions::new(10 - i * 3);
+            let sampler = SamplerChain::new().with_seed(i as u64);
+            ids.push(scheduler.add_request(prompt, sampler, options)?);
+        }
+        let mut texts: HashMap<RequestId, String> = HashMap::new();
+        let mut finished = vec![];
+        let mut steps = 0;
+        while !scheduler.is_idle() {
+            assert!(scheduler.n_running() <= 2);
+            for output in scheduler.step()? {
+                let i = ids.iter().position(|id| *id == output.id).unwrap();
+                assert_eq!(output.seed, i as u64);
+                if let Some(token) = output.token {
+                    assert_eq!(token.seed, Some(output.seed));
+                    texts.entry(output.id).or_default().push_str(&token.piece);
+                }
+                if let Some(reason) = output.finish_reason {
+                    assert_eq!(reason, FinishReason::Length);
+                    finished.push(output.id);
+                }
+            }
+            steps += 1;
+        }
+        assert_eq!(finished, vec![ids[1], ids[0], ids[2]]);
+        assert!(steps < 10 + 4, "{}", steps);
+        for (i, id) in ids.iter().enumerate() {
+            // the greedy tokens are the same as running the requests one by one
+            let n_tokens = 10 - i * 3;
+            let text = &texts[id];
+            assert!(
+                expected[i].starts_with(text.as_str()),
+                "{} vs {}",
+                text,
+                expected[i]
+            );
+            if n_tokens == 10 {
+                assert_eq!(text, &expected[i]);
+            }
+        }
+        drop(scheduler);
+        assert_eq!(runner.kv_cache_len(), 0);
+
+        assert!(BatchScheduler::new(&mut runner, 0).is_err());
+
+        // the start of a stop string is held back, and released along with the next token
+        // once it's ruled out
+        for (stop, text, reason) in [
+            (". She", "3 years old", FinishReason::StopString),
+            ("old. He", "3 years old. She", FinishReason::Length),
+        ] {
+            let mut scheduler = BatchScheduler::new(&mut runner, 1)?;
+            let options = GenerationOptions::new(5).with_stop_string(stop);
+            scheduler.add_request(prompts[0], SamplerChain::new(), options)?;
+            let (mut pieces, mut outputs) = (String::new(), vec![]);
+            while !scheduler.is_idle() {
+                outputs.extend(scheduler.step()?);
+            }
+            for output in &outputs[..outputs.len() - 1] {
+                assert_eq!(output.finish_reason, None);
+                pieces.push_str(&output.token.as_ref().unwrap().piece);
+            }
+            let last = outputs.last().unwrap();
+            pieces.extend(last.token.as_ref().map(|t| t.piece.as_str()));
+            assert_eq!(last.finish_reason, Some(reason));
+            assert_eq!(pieces, text);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_invariance() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let (a, b, c) = (
+            vec![1, 365, 2354, 338, 263, 274, 1082],
+            vec![1, 9038, 2501, 263, 931],
+            vec![1, 4335],
+        );
+        let devices = [
+            CpuTensorDevice::new(),
+            CpuTensorDevice::with_options(CpuTensorDeviceOptions {
+                activation_dtype: GGMLType::F16,
+                ..Default::default()
+            }),
+        ];
+        for device in devices {
+            let lm = CpuLlama2Model::load(&gf, device)?;
+            let vocab_size = lm.conf.vocab_size;
+            for kv_cache_dtype in [GGMLType::F32, GGMLType::F16] {
+                let mut runner =
+                    Llama2Runner::new(&lm, TensorMetrics::default(), 32, kv_cache_dtype)?;
+                let mut slots = runner.alloc_kv_slots(3)?;
+                let [sa, sb, sc] = slots.as_mut_slice() else {
+                    unreachable!()
+                };
+
+                // the prompt of a alone, and between the prompts of b and c
+                let alone = runner.forward_segments(&mut [(&a[..], 0, &mut *sa)])?;
+                sa.reset()?;
+                let batched = runner.forward_segments(&mut [
+                    (&b[..], 0, &mut *sb),
+                    (&a[..], 0, &mut *sa),
+                    (&c[..], 0, &mut *sc),
+                ])?;
+                assert!(alone[..] == batched[vocab_size..2 * vocab_size]);
+
+                // a decoding step of a alone, and after a prompt and another decoding step
+                let token = [13];
+                let alone = runner.forward_segments(&mut [(&token[..], a.len(), &mut *sa)])?;
+                sa.truncate(a.len())?;
+                sc.reset()?;
+                let batched = runner.forward_segments(&mut [
+                    (&c[..], 0, &mut *sc),
+                    (&[29879][..], b.len(), &mut *sb),
+                    (&token[..], a.len(), &mut *sa),
+                ])?;
+                assert!(alone[..] == batched[2 * vocab_size..]);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_hooks() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let (vocab_size, embed_dim) = (lm.conf.vocab_size, lm.conf.embedding_dim);
+        let (a, b) = (vec![1, 365, 2354, 338, 263], vec![1, 9038, 2501]);
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 32, GGMLType::F32)?;
+        let plain = runner.forward_batch(&a, 0)?.to_vec();
+
+        // a soft prompt on the positions 1..4, which are the positions of a in its sequence
+        runner.add_hook(HiddenBias::new().with_vectors(0, 1, vec![vec![-3.0; embed_dim]; 3]));
+        runner.reset_kv_cache()?;
+        let expected = runner.forward_batch(&a, 0)?.to_vec();
+        assert_ne!(plain, expected);
+
+        let mut slots = runner.alloc_kv_slots(2)?;
+        let [sa, sb] = slots.as_mut_slice() else {
+            unreachable!()
+        };
+        let batched =
+            runner.forward_segments(&mut [(&b[..], 0, &mut *sb), (&a[..], 0, &mut *sa)])?;
+        for (x, y) in batched[vocab_size..].iter().zip(&expected) {
+            assert!((x - y).abs() < 1e-3, "{} vs {}", x, y);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_scheduler_isolation() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+
+        // the tokens and the metrics on the full logits of every step, the time is left out
+        type Outputs = HashMap<RequestId, Vec<(usize, String, Option<StepMetrics>)>>;
+        let collect = |outputs: &mut Outputs, step: Vec<BatchOutput>| {
+            for output in step {
+                let token = output.token.map(|t| (t.token, t.piece, t.metrics));
+                outputs.entry(output.id).or_default().extend(token);
+            }
+        };
+        let options = |n: usize| GenerationOptions::new(n).with_step_metrics(1.0);
+        let (prompt_a, prompt_c) = ("Lily is a cute cat, ", "Once upon a time, there was a");
+
+        // each one alone
+        let mut alone = vec![];
+        for prompt in [prompt_a, prompt_c] {
+            let mut scheduler = BatchScheduler::new(&mut runner, 3)?;
+            let id = scheduler.add_request(prompt, SamplerChain::new(), options(12))?;
+            let mut outputs = Outputs::new();
+            while !scheduler.is_idle() {
+                collect(&mut outputs, scheduler.step()?);
+            }
+            alone.push(outputs.remove(&id).unwrap());
+        }
+
+        // a after b which leaves early, c joins in the middle, and the prompts are split over
+        // the steps by the 4 tokens of a step
+        let mut scheduler = BatchScheduler::new(&mut runner, 3)?.with_max_step_tokens(4);
+        let mut outputs = Outputs::new();
+        scheduler.add_request("Tom has a red ball", SamplerChain::new(), options(3))?;
+        let id_a = scheduler.add_request(prompt_a, SamplerChain::new(), options(12))?;
+        let mut id_c = None;
+        let mut steps = 0;
+        while !scheduler.is_idle() {
+            if steps == 6 {
+                id_c = Some(scheduler.add_request(prompt_c, SamplerChain::new(), options(12))?);
+            }
+            collect(&mut outputs, scheduler.step()?);
+            steps += 1;
+        }
+        assert_eq!(outputs[&id_a].len(), 12);
+        assert!(outputs[&id_a] == alone[0]);
+        assert!(outputs[&id_c.unwrap()] == alone[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_scheduler_step_latency() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let prompt = "Once upon a time, there was a";
+
+        let mut scheduler = BatchScheduler::new(&mut runner, 2)?;
+        let id = scheduler.add_request(prompt, SamplerChain::new(), GenerationOptions::new(4))?;
+        let mut expected = String::new();
+        while !scheduler.is_idle() {
+            for output in scheduler.step()? {
+                assert_eq!(output.id, id);
+                expected.extend(output.token.map(|t| t.piece));
+            }
+        }
+        drop(scheduler);
+
+        // the first step is unbounded, then no step fits in a nanosecond, so the prompt
+        // joining later goes 1 token a step
+        let mut scheduler =
+            BatchScheduler::new(&mut runner, 2)?.with_target_step_latency(Duration::from_nanos(1));
+        assert_eq!(scheduler.step_tokens(), None);
+        scheduler.add_request("Lily", SamplerChain::new(), GenerationOptions::new(1))?;
+        scheduler.step()?;
+        assert_eq!(scheduler.step_tokens(), Some(1));
+        let id = scheduler.add_request(prompt, SamplerChain::new(), GenerationOptions::new(4))?;
+        let mut text = String::new();
+        let mut steps = 0;
+        while !scheduler.is_idle() {
+            for output in scheduler.step()? {
+                if output.id == id {
+                    text.extend(output.token.map(|t| t.piece));
+                }
+            }
+            steps += 1;
+        }
+        assert_eq!(text, expected);
+        assert!(steps >= 10, "{}", steps);
+
+        // a target of an hour does not bound the steps anymore than the max step tokens
+        let scheduler = BatchScheduler::new(&mut runner, 2)?
+            .with_max_step_tokens(8)
+            .with_target_step_latency(Duration::from_secs(3600));
+        assert_eq!(scheduler.step_tokens(), Some(8));
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_scheduler_step_budget() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let prompts = ["Lily is a cute cat, ", "Once upon a time", "Tom"];
+
+        let mut alone = vec![];
+        let mut n_tokens = 0;
+        for prompt in prompts {
+            n_tokens += runner.prompt_tokens(prompt)?.len() + 5 - 1;
+            let mut scheduler = BatchScheduler::new(&mut runner, 3)?;
+            scheduler.add_request(prompt, SamplerChain::new(), GenerationOptions::new(5))?;
+            let mut text = String::new();
+            while !scheduler.is_idle() {
+                for output in scheduler.step()? {
+                    text.extend(output.token.map(|t| t.piece));
+                }
+            }
+            alone.push(text);
+        }
+
+        // a step of 1 token holds back the decoding requests as well as the prompts, the
+        // ones left out go on the next steps, so every step forwards exactly 1 token
+        let mut scheduler = BatchScheduler::new(&mut runner, 3)?.with_max_step_tokens(1);
+        let mut ids = vec![];
+        for prompt in prompts {
+            ids.push(scheduler.add_request(
+                prompt,
+                SamplerChain::new(),
+                GenerationOptions::new(5),
+            )?);
+        }
+        let mut texts: HashMap<RequestId, String> = HashMap::new();
+        let mut steps = 0;
+        while !scheduler.is_idle() {
+            for output in scheduler.step()? {
+                let text = texts.entry(output.id).or_default();
+                text.extend(output.token.map(|t| t.piece));
+            }
+            steps += 1;
+        }
+        assert_eq!(steps, n_tokens);
+        for (id, expected) in ids.iter().zip(alone.iter()) {
+            assert_eq!(&texts[id], expected);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,513 @@
+use std::mem;
+
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::sampler::log_sum_exp;
+use crabml::sampler::Penalties;
+use crabml::sampler::Sampler;
+use crabml::sampler::SamplerRng;
+use crabml::tensor::Tensor;
+use rand::Rng;
+use rand::SeedableRng;
+
+use crate::batch::KvSlot;
+use crate::llama2::Llama2Runner;
+use crate::stream::FinishReason;
+
+#[derive(Debug, Clone)]
+pub struct BeamSearchOptions {
+    /// the beams kept in every group on each step.
+    pub n_beams: usize,
+    /// the beams are split into the groups searched one after another on each step, see
+    /// `with_groups()`.
+    pub n_groups: usize,
+    /// subtracted from the score of a token once for every beam of the earlier groups taking
+    /// the same token on the step.
+    pub diversity_penalty: f32,
+    /// sample the beams without replacement by the gumbel top-k trick instead of keeping the
+    /// most likely ones, the logits are divided by the temperature. None searches greedily.
+    pub stochastic: Option<f32>,
+    pub seed: u64,
+    /// the hypotheses are ranked by their logprob divided by their length to this power, 0
+    /// ranks by the logprob alone, larger ones favor the longer hypotheses.
+    pub length_penalty: f32,
+    /// the max number of tokens of a hypothesis, the search also stops when the kv cache is
+    /// full.
+    pub max_tokens: usize,
+    /// penalize the logits of every beam by its own history, from the prompt on.
+    pub penalties: Option<Penalties>,
+}
+
+impl BeamSearchOptions {
+    pub fn new(n_beams: usize, max_tokens: usize) -> Self {
+        Self {
+            n_beams,
+            n_groups: 1,
+            diversity_penalty: 0.0,
+            stochastic: None,
+            seed: 0,
+            length_penalty: 1.0,
+            max_tokens,
+            penalties: None,
+        }
+    }
+
+    /// diverse beam search: n_groups groups of n_beams beams, a group is penalized for
+    /// taking the tokens the earlier groups take on the same step, so the groups drift apart
+    /// into varied candidates, like for reranking.
+    pub fn with_groups(mut self, n_groups: usize, diversity_penalty: f32) -> Self {
+        self.n_groups = n_groups;
+        self.diversity_penalty = diversity_penalty;
+        self
+    }
+
+    /// stochastic beam search: sample the hypotheses without replacement from the softmax
+    /// of the logits divided by the temperature, reproducible by the seed.
+    pub fn with_stochastic(mut self, temperature: f32, seed: u64) -> Self {
+        self.stochastic = Some(temperature);
+        self.seed = seed;
+        self
+    }
+
+    /// the penalties are applied before the softmax, the logprobs of the hypotheses are
+    /// taken from the penalized logits.
+    pub fn with_penalties(mut self, penalties: Penalties) -> Self {
+        self.penalties = Some(penalties);
+        self
+    }
+
+    pub fn with_length_penalty(mut self, length_penalty: f32) -> Self {
+        self.length_penalty = length_penalty;
+        self
+    }
+}
+
+/// a finished beam.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BeamHypothesis {
+    /// the generated tokens, without the eos token.
+    pub tokens: Vec<usize>,
+    pub text: String,
+    /// the sum of the log probabilities of the tokens and the eos token if it ends on it, in
+    /// the softmax of the logits divided by the temperature.
+    pub logprob: f32,
+    /// the logprob normalized by the length penalty, the hypotheses are ranked by it.
+    pub score: f32,
+    pub group: usize,
+    /// `FinishReason::StopToken` on the eos token, `FinishReason::Length` otherwise.
+    pub finish_reason: FinishReason,
+}
+
+struct Beam<T: Tensor> {
+    slot: KvSlot<T>,
+    tokens: Vec<usize>,
+    logprob: f32,
+    // the key the beams are ranked by: the logprob, or its gumbel perturbation on the
+    // stochastic search
+    key: f32,
+    // the log softmax of the logits after the last token
+    next_logprobs: Vec<f32>,
+    // the history of the beam, forked with it
+    penalties: Option<Penalties>,
+}
+
+// a token taken by a group on a step
+struct Expansion {
+    group: usize,
+    parent: usize,
+    token: usize,
+    logprob: f32,
+    key: f32,
+}
+
+/// beam search over a runner: each group keeps its n_beams best hypotheses on every step,
+/// all the beams are forwarded in one batched pass on their own kv cache slots, like in
+/// `BatchScheduler`. a beam taking the eos token is finished into a hypothesis, a group
+/// stops once it has n_beams hypotheses.
+///
+/// the kv cache of the runner itself is not touched, the slots are forked by copying, so
+/// the q8_0 kv caches are not supported yet.
+pub struct BeamSearch<'a, T: Tensor> {
+    runner: &'a mut Llama2Runner<T>,
+    options: BeamSearchOptions,
+    rng: SamplerRng,
+}
+
+impl<'a, T: Tensor> BeamSearch<'a, T> {
+    pub fn new(runner: &'a mut Llama2Runner<T>, options: BeamSearchOptions) -> Result<Self> {
+        if options.n_beams == 0 || options.n_groups == 0 {
+            return Err((
+                ErrorKind::BadInput,
+                "n_beams and n_groups should be at least 1",
+            )
+                .into());
+        }
+        if options.stochastic.is_some_and(|t| t <= 0.0) {
+            return Err((
+                ErrorKind::BadInput,
+                "the temperature of the stochastic beams should be positive",
+            )
+                .into());
+        }
+        let rng = SamplerRng::seed_from_u64(options.seed);
+        Ok(Self {
+            runner,
+            options,
+            rng,
+        })
+    }
+
+    /// search the continuations of the prompt, returns up to n_beams hypotheses of every
+    /// group, ranked by their score.
+    pub fn search(&mut self, prompt: &str) -> Result<Vec<BeamHypothesis>> {
+        let (n_beams, n_groups) = (self.options.n_beams, self.options.n_groups);
+        let prompt_tokens = self.runner.prompt_tokens(prompt)?;
+        let seq_len = self.runner.seq_len();
+        let eos_token = self.runner.tokenizer().eos_token();
+        let prev_token = *prompt_tokens.last().unwrap();
+
+        let mut free_slots = self.runner.alloc_kv_slots(n_beams * n_groups)?;
+        let mut slot = free_slots.pop().unwrap();
+        let mut penalties = self.options.penalties.clone();
+        if let Some(penalties) = penalties.as_mut() {
+            penalties.reset();
+            prompt_tokens.iter().for_each(|t| penalties.accept(*t));
+        }
+        let mut logits =
+            self.runner
+                .forward_segments(&mut [(prompt_tokens.as_slice(), 0, &mut slot)])?;
+        if let Some(penalties) = &penalties {
+            penalties.apply_logits(&mut logits);
+        }
+        let root = Beam {
+            slot,
+            tokens: vec![],
+            logprob: 0.0,
+            key: 0.0,
+            next_logprobs: self.log_softmax(&logits),
+            penalties,
+        };
+        // every group starts from the prompt
+        let mut beams = vec![root];
+        let mut groups = vec![vec![0]; n_groups];
+        let mut hypotheses: Vec<Vec<BeamHypothesis>> = vec![vec![]; n_groups];
+
+        for step in 0..self.options.max_tokens {
+            if groups.iter().all(|g| g.is_empty()) {
+                break;
+            }
+            let expansions = self.expand(&beams, &groups, eos_token)?;
+
+            // the eos tokens finish the beams, the other expansions are the next beams
+            let mut next = vec![];
+            for exp in expansions {
+                if hypotheses[exp.group].len() >= n_beams {
+                    continue;
+                }
+                if exp.token == eos_token {
+                    let tokens = beams[exp.parent].tokens.clone();
+                    let hyp = self.hypothesis(prev_token, tokens, exp.logprob, exp.group, true)?;
+                    hypotheses[exp.group].push(hyp);
+                } else {
+                    next.push(exp);
+                }
+            }
+            let pos = prompt_tokens.len() + step;
+            // the new tokens can not be forwarded once the kv cache is full
+            if step + 1 == self.options.max_tokens || pos >= seq_len {
+                for exp in next {
+                    let mut tokens = beams[exp.parent].tokens.clone();
+                    tokens.push(exp.token);
+                    let hyp = self.hypothesis(prev_token, tokens, exp.logprob, exp.group, false)?;
+                    hypotheses[exp.group].push(hyp);
+                }
+                break;
+            }
+
+            beams = Self::fork(&mut beams, &next, &mut free_slots)?;
+            groups = vec![vec![]; n_groups];
+            for (i, exp) in next.iter().enumerate() {
+                if hypotheses[exp.group].len() < n_beams {
+                    groups[exp.group].push(i);
+                }
+            }
+
+            let inputs = next.iter().map(|exp| [exp.token]).collect::<Vec<_>>();
+            let mut seqs = beams
+                .iter_mut()
+                .zip(inputs.iter())
+                .map(|(beam, input)| (input.as_slice(), pos, &mut beam.slot))
+                .collect::<Vec<_>>();
+            let mut logits = self.runner.forward_segments(&mut seqs)?;
+            let vocab_size = self.runner.conf().vocab_size;
+            for (beam, logits) in beams.iter_mut().zip(logits.chunks_exact_mut(vocab_size)) {
+                if let Some(penalties) = &beam.penalties {
+                    penalties.apply_logits(logits);
+                }
+                beam.next_logprobs = self.log_softmax(logits);
+            }
+        }
+
+        let mut hypotheses = hypotheses.into_iter().flatten().collect::<Vec<_>>();
+        hypotheses.sort_by(|a, b| b.score.total_cmp(&a.score));
+        Ok(hypotheses)
+    }
+
+    // the candidates of every group in the order of their keys, up to 2 * n_beams of each
+    // group, so there are still n_beams beams to go on with if some of them take the eos.
+    fn expand(
+        &mut self,
+        beams: &[Beam<T>],
+        groups: &[Vec<usize>],
+        eos_token: usize,
+    ) -> Result<Vec<Expansion>> {
+        let n_beams = self.options.n_beams;
+        let vocab_size = self.runner.conf().vocab_size;
+        // how many beams of the earlier groups take every token on this step
+        let mut taken = vec![0usize; vocab_size];
+        let mut expansions = vec![];
+        for (g, group) in groups.iter().enumerate() {
+            let mut candidates = vec![];
+            for &parent in group {
+                let beam = &beams[parent];
+                let keys = match self.options.stochastic {
+                    Some(_) => self.gumbel_keys(beam),
+                    None => beam
+                        .next_logprobs
+                        .iter()
+                        .map(|lp| beam.logprob + lp)
+                        .collect(),
+                };
+                for (token, key) in keys.into_iter().enumerate() {
+                    if key == f32::NEG_INFINITY || key.is_nan() {
+                        continue;
+                    }
+                    let score = key - self.options.diversity_penalty * taken[token] as f32;
+                    candidates.push((score, key, parent, token));
+                }
+            }
+            let n_keep = (2 * n_beams).min(candidates.len());
+            if n_keep < candidates.len() {
+                candidates.select_nth_unstable_by(n_keep, |a, b| b.0.total_cmp(&a.0));
+                candidates.truncate(n_keep);
+            }
+            candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+            // like the eos, the candidates after n_beams continuing ones are dropped
+            let mut n_continuing = 0;
+            for (rank, (_, key, parent, token)) in candidates.into_iter().enumerate() {
+                if n_continuing >= n_beams || (token == eos_token && rank >= n_beams) {
+                    continue;
+                }
+                if token != eos_token {
+                    n_continuing += 1;
+                }
+                taken[token] += 1;
+                expansions.push(Expansion {
+                    group: g,
+                    parent,
+                    token,
+                    logprob: beams[parent].logprob + beams[parent].next_logprobs[token],
+                    key,
+                });
+            }
+        }
+        Ok(expansions)
+    }
+
+    // the perturbed logprobs of the children conditioned on their max being the key of the
+    // parent, so the top keys on a step are a sample without replacement of the sequences
+    // (Kool et al., 2019).
+    fn gumbel_keys(&mut self, beam: &Beam<T>) -> Vec<f32> {
+        let perturbed = beam
+            .next_logprobs
+            .iter()
+            .map(|lp| {
+                let u: f32 = self.rng.gen::<f32>().max(f32::MIN_POSITIVE);
+                beam.logprob + lp - (-u.ln()).ln()
+            })
+            .collect::<Vec<_>>();
+        let max = perturbed.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        perturbed
+            .into_iter()
+            .map(|g| {
+                if g == f32::NEG_INFINITY {
+                    return g;
+                }
+                // -ln(exp(-key) - exp(-max) + exp(-g)) in a stable form
+                let v = beam.key - g + log1mexp(g - max);
+                beam.key - v.max(0.0) - (-v.abs()).exp().ln_1p()
+            })
+            .collect()
+    }
+
+    // the next beams on their parents' slots, a parent with several children is copied into
+    // the free slots, the slots of the parents without children are freed
+    fn fork(
+        beams: &mut [Beam<T>],
+        next: &[Expansion],
+        free_slots: &mut Vec<KvSlot<T>>,
+    ) -> Result<Vec<Beam<T>>> {
+        let mut n_children = vec![0; beams.len()];
+        next.iter().for_each(|exp| n_children[exp.parent] += 1);
+        for (beam, n) in beams.iter_mut().zip(n_children.iter()) {
+            if *n == 0 {
+                free_slots.push(mem::take(&mut beam.slot));
+            }
+        }
+
+        let mut forked = Vec::with_capacity(next.len());
+        for exp in next {
+            let parent = &mut beams[exp.parent];
+            n_children[exp.parent] -= 1;
+            let slot = if n_children[exp.parent] == 0 {
+                mem::take(&mut parent.slot)
+            } else {
+                let mut slot = free_slots.pop().unwrap();
+                slot.copy_from(&parent.slot)?;
+                slot
+            };
+            let mut tokens = parent.tokens.clone();
+            tokens.push(exp.token);
+            let mut penalties = parent.penalties.clone();
+            if let Some(penalties) = penalties.as_mut() {
+                penalties.accept(exp.token);
+            }
+            forked.push(Beam {
+                slot,
+                tokens,
+                logprob: exp.logprob,
+                key: exp.key,
+                next_logprobs: vec![],
+                penalties,
+            });
+        }
+        Ok(forked)
+    }
+
+    fn hypothesis(
+        &self,
+        mut prev_token: usize,
+        tokens: Vec<usize>,
+        logprob: f32,
+        group: usize,
+        eos: bool,
+    ) -> Result<BeamHypothesis> {
+        let tokenizer = self.runner.tokenizer();
+        let mut text = String::new();
+        for &token in tokens.iter() {
+            text.push_str(&tokenizer.decode(prev_token, token)?);
+            prev_token = token;
+        }
+        // the eos token is scored as a step too
+        let length = (tokens.len() + eos as usize).max(1) as f32;
+        Ok(BeamHypothesis {
+            tokens,
+            text,
+            logprob,
+            score: logprob / length.powf(self.options.length_penalty),
+            group,
+            finish_reason: if eos {
+                FinishReason::StopToken
+            } else {
+                FinishReason::Length
+            },
+        })
+    }
+
+    fn log_softmax(&self, logits: &[f32]) -> Vec<f32> {
+        let inv_temp = 1.0 / self.options.stochastic.unwrap_or(1.0);
+        let scaled = logits.iter().map(|l| l * inv_temp).collect::<Vec<_>>();
+        let lse = log_sum_exp(&scaled);
+        scaled.into_iter().map(|l| l - lse).collect()
+    }
+}
+
+// ln(1 - exp(x)) for x <= 0
+fn log1mexp(x: f32) -> f32 {
+    if x > -std::f32::consts::LN_2 {
+        (-x.exp_m1()).ln()
+    } else {
+        (-x.exp()).ln_1p()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::gguf::GGMLType;
+    use crabml::gguf::GGUFFileLoader;
+    use crabml::sampler::SamplerChain;
+    use crabml::tensor::TensorMetrics;
+
+    use super::*;
+    use crate::stream::GenerationOptions;
+    use crate::CpuLlama2Model;
+
+    #[test]
+    fn test_beam_search() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let prompt = "Lily is a cute cat, ";
+
+        let mut sampler = SamplerChain::new();
+        let mut stream = runner.stream(prompt, &mut sampler, GenerationOptions::new(8))?;
+        stream.by_ref().collect::<Result<Vec<_>>>()?;
+        let greedy = stream.text().to_string();
+        runner.reset_kv_cache()?;
+
+        // a single beam is the greedy decoding
+        let mut search = BeamSearch::new(&mut runner, BeamSearchOptions::new(1, 8))?;
+        let hyps = search.search(prompt)?;
+        assert_eq!(hyps.len(), 1);
+        assert_eq!(hyps[0].text, greedy);
+        assert_eq!(hyps[0].finish_reason, FinishReason::Length);
+
+        let mut search = BeamSearch::new(&mut runner, BeamSearchOptions::new(3, 8))?;
+        let hyps = search.search(prompt)?;
+        assert_eq!(hyps.len(), 3);
+        assert!(hyps.windows(2).all(|w| w[0].score >= w[1].score));
+        assert!(hyps.iter().all(|h| h.tokens.len() == 8 && h.logprob < 0.0));
+        assert_ne!(hyps[0].tokens, hyps[1].tokens);
+
+        // the second group avoids the first tokens of the first one
+        let options = BeamSearchOptions::new(2, 4).with_groups(2, 100.0);
+        let hyps = BeamSearch::new(&mut runner, options)?.search(prompt)?;
+        assert_eq!(hyps.len(), 4);
+        let first_tokens = |g: usize| {
+            hyps.iter()
+                .filter(|h| h.group == g)
+                .map(|h| h.tokens[0])
+                .collect::<Vec<_>>()
+        };
+        assert!(first_tokens(1).iter().all(|t| !first_tokens(0).contains(t)));
+
+        // the stochastic beams are reproducible by the seed
+        let options = BeamSearchOptions::new(3, 6).with_stochastic(1.0, 42);
+        let hyps1 = BeamSearch::new(&mut runner, options.clone())?.search(prompt)?;
+        let hyps2 = BeamSearch::new(&mut runner, options)?.search(prompt)?;
+        assert_eq!(hyps1, hyps2);
+        assert_eq!(hyps1.len(), 3);
+        assert_eq!(runner.kv_cache_len(), 0);
+
+        // every beam is penalized by its own history, none repeats a token
+        let penalties = Penalties::new(Penalties::WHOLE_CONTEXT, 1.0, 0.0, 1000.0);
+        let options = BeamSearchOptions::new(3, 8).with_penalties(penalties);
+        let hyps = BeamSearch::new(&mut runner, options)?.search(prompt)?;
+        let prompt_tokens = runner.prompt_tokens(prompt)?;
+        for hyp in hyps.iter() {
+            let mut seen = prompt_tokens.clone();
+            for token in hyp.tokens.iter() {
+                assert!(!seen.contains(token), "{:?}", hyp.text);
+                seen.push(*token);
+            }
+        }
+
+        assert!(BeamSearch::new(&mut runner, BeamSearchOptions::new(0, 8)).is_err());
+        let options = BeamSearchOptions::new(2, 8).with_stochastic(0.0, 0);
+        assert!(BeamSearch::new(&mut runner, options).is_err());
+        Ok(())
+    }
+}
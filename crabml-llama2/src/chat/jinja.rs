@@ -0,0 +1,2313 @@
+//! A subset of Jinja2 to render the chat templates stored in the GGUF files, the same way as
+//! `apply_chat_template()` of transformers: the blocks are trimmed (`trim_blocks` and
+//! `lstrip_blocks`), and `raise_exception()`, `strftime_now()` and `tojson` are available.
+//!
+//! The `{{ }}` outputs, `{% if %}`, `{% for %}` with the `loop` variable and the `else`
+//! branch, `{% set %}` with the `namespace()` attributes, `{% macro %}`, the `-` whitespace
+//! control and the comments are supported, so are the common operators, filters, tests and
+//! the python string and dict methods. There's no autoescaping or template inheritance.
+//!
+//! The templates come from the model files and are not trusted: the nesting, the loop
+//! iterations and the size of the output are capped, a template beyond them fails with an
+//! error instead of overflowing the stack or running out of the memory.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Undefined,
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    List(Vec<Value>),
+    Map(Vec<(String, Value)>),
+    Namespace(Rc<RefCell<Vec<(String, Value)>>>),
+    Macro(Rc<Macro>),
+}
+
+impl Value {
+    pub fn from_json(v: &serde_json::Value) -> Self {
+        match v {
+            serde_json::Value::Null => Value::None,
+            serde_json::Value::Bool(b) => Value::Bool(*b),
+            serde_json::Value::Number(n) => match n.as_i64() {
+                Some(i) => Value::Int(i),
+                None => Value::Float(n.as_f64().unwrap_or(f64::NAN)),
+            },
+            serde_json::Value::String(s) => Value::Str(s.clone()),
+            serde_json::Value::Array(vs) => Value::List(vs.iter().map(Value::from_json).collect()),
+            serde_json::Value::Object(m) => Value::Map(
+                m.iter()
+                    .map(|(k, v)| (k.clone(), Value::from_json(v)))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn is_true(&self) -> bool {
+        match self {
+            Value::Undefined | Value::None => false,
+            Value::Bool(b) => *b,
+            Value::Int(i) => *i != 0,
+            Value::Float(f) => *f != 0.0,
+            Value::Str(s) => !s.is_empty(),
+            Value::List(vs) => !vs.is_empty(),
+            Value::Map(m) => !m.is_empty(),
+            Value::Namespace(_) | Value::Macro(_) => true,
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Undefined => "undefined",
+            Value::None => "none",
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::Str(_) => "string",
+            Value::List(_) => "list",
+            Value::Map(_) => "dict",
+            Value::Namespace(_) => "namespace",
+            Value::Macro(_) => "macro",
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Bool(b) => Some(*b as i64 as f64),
+            Value::Int(i) => Some(*i as f64),
+            Value::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+
+    fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Bool(b) => Some(*b as i64),
+            Value::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    fn get_attr(&self, name: &str) -> Value {
+        match self {
+            Value::Map(m) => lookup(m, name),
+            Value::Namespace(ns) => lookup(&ns.borrow(), name),
+            _ => Value::Undefined,
+        }
+    }
+
+    fn get_item(&self, key: &Value) -> Value {
+        match (self, key) {
+            (Value::Map(_) | Value::Namespace(_), Value::Str(k)) => self.get_attr(k),
+            (Value::List(vs), _) => match key.as_i64().and_then(|i| py_index(i, vs.len())) {
+                Some(i) => vs[i].clone(),
+                None => Value::Undefined,
+            },
+            (Value::Str(s), _) => {
+                let chars = s.chars().collect::<Vec<_>>();
+                match key.as_i64().and_then(|i| py_index(i, chars.len())) {
+                    Some(i) => Value::Str(chars[i].to_string()),
+                    None => Value::Undefined,
+                }
+            }
+            _ => Value::Undefined,
+        }
+    }
+
+    // the items to iterate in a for loop, the keys of a dict
+    fn iter_items(&self) -> Result<Vec<Value>> {
+        match self {
+            Value::Undefined | Value::None => Ok(vec![]),
+            Value::List(vs) => Ok(vs.clone()),
+            Value::Map(m) => Ok(m.iter().map(|(k, _)| Value::Str(k.clone())).collect()),
+            Value::Str(s) => Ok(s.chars().map(|c| Value::Str(c.to_string())).collect()),
+            v => Err(template_error(format!("{} is not iterable", v.type_name()))),
+        }
+    }
+
+    fn len(&self) -> Option<usize> {
+        match self {
+            Value::Str(s) => Some(s.chars().count()),
+            Value::List(vs) => Some(vs.len()),
+            Value::Map(m) => Some(m.len()),
+            Value::Namespace(ns) => Some(ns.borrow().len()),
+            _ => None,
+        }
+    }
+
+    // the text of {{ value }}, the containers are printed like python
+    fn to_output(&self) -> String {
+        match self {
+            Value::Undefined => String::new(),
+            Value::Str(s) => s.clone(),
+            v => v.repr(),
+        }
+    }
+
+    fn repr(&self) -> String {
+        match self {
+            Value::Undefined => String::new(),
+            Value::None => "None".to_string(),
+            Value::Bool(true) => "True".to_string(),
+            Value::Bool(false) => "False".to_string(),
+            Value::Int(i) => i.to_string(),
+            Value::Float(f) => py_float(*f),
+            Value::Str(s) => py_str_repr(s),
+            Value::List(vs) => {
+                let items = vs.iter().map(|v| v.repr()).collect::<Vec<_>>();
+                format!("[{}]", items.join(", "))
+            }
+            Value::Map(m) => {
+                let items = m
+                    .iter()
+                    .map(|(k, v)| format!("{}: {}", py_str_repr(k), v.repr()))
+                    .collect::<Vec<_>>();
+                format!("{{{}}}", items.join(", "))
+            }
+            Value::Namespace(_) => "<Namespace>".to_string(),
+            Value::Macro(m) => format!("<Macro '{}'>", m.name),
+        }
+    }
+
+    fn to_json(&self, indent: Option<usize>, depth: usize, out: &mut String) {
+        let newline = |out: &mut String, depth: usize| {
+            if let Some(indent) = indent {
+                out.push('\n');
+                out.push_str(&" ".repeat(indent * depth));
+            }
+        };
+        let item_sep = if indent.is_some() { "," } else { ", " };
+        match self {
+            Value::Undefined | Value::None => out.push_str("null"),
+            Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            Value::Int(i) => out.push_str(&i.to_string()),
+            Value::Float(f) => out.push_str(&py_float(*f)),
+            Value::Str(s) => out.push_str(&serde_json::Value::String(s.clone()).to_string()),
+            Value::List(vs) if vs.is_empty() => out.push_str("[]"),
+            Value::List(vs) => {
+                out.push('[');
+                for (i, v) in vs.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(item_sep);
+                    }
+                    newline(out, depth + 1);
+                    v.to_json(indent, depth + 1, out);
+                }
+                newline(out, depth);
+                out.push(']');
+            }
+            Value::Map(m) if m.is_empty() => out.push_str("{}"),
+            Value::Map(m) => {
+                out.push('{');
+                for (i, (k, v)) in m.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(item_sep);
+                    }
+                    newline(out, depth + 1);
+                    out.push_str(&serde_json::Value::String(k.clone()).to_string());
+                    out.push_str(": ");
+                    v.to_json(indent, depth + 1, out);
+                }
+                newline(out, depth);
+                out.push('}');
+            }
+            Value::Namespace(ns) => Value::Map(ns.borrow().clone()).to_json(indent, depth, out),
+            Value::Macro(_) => out.push_str("null"),
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Undefined, Value::Undefined) | (Value::None, Value::None) => true,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::List(a), Value::List(b)) => a == b,
+            (Value::Map(a), Value::Map(b)) => a == b,
+            (Value::Namespace(a), Value::Namespace(b)) => Rc::ptr_eq(a, b),
+            (Value::Macro(a), Value::Macro(b)) => Rc::ptr_eq(a, b),
+            (a, b) => match (a.as_f64(), b.as_f64()) {
+                (Some(a), Some(b)) => a == b,
+                _ => false,
+            },
+        }
+    }
+}
+
+fn lookup(m: &[(String, Value)], name: &str) -> Value {
+    m.iter()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.clone())
+        .unwrap_or(Value::Undefined)
+}
+
+fn insert(m: &mut Vec<(String, Value)>, name: &str, value: Value) {
+    match m.iter_mut().find(|(k, _)| k == name) {
+        Some((_, v)) => *v = value,
+        None => m.push((name.to_string(), value)),
+    }
+}
+
+fn py_index(i: i64, len: usize) -> Option<usize> {
+    let i = if i < 0 { i + len as i64 } else { i };
+    (i >= 0 && (i as usize) < len).then_some(i as usize)
+}
+
+fn py_float(f: f64) -> String {
+    if f.is_finite() && f.fract() == 0.0 && f.abs() < 1e16 {
+        format!("{:.1}", f)
+    } else {
+        f.to_string()
+    }
+}
+
+fn py_str_repr(s: &str) -> String {
+    let quote = if s.contains('\'') && !s.contains('"') {
+        '"'
+    } else {
+        '\''
+    };
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push(quote);
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c == quote => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push(quote);
+    out
+}
+
+// the nesting of the expressions and the blocks on parsing, and of the macro calls on
+// rendering
+const MAX_DEPTH: usize = 64;
+// the tokens of a tag, which bounds the depth of a chain of the binary operators
+const MAX_TAG_TOKENS: usize = 1024;
+// the loop iterations of a render in total, and the items of a range()
+const MAX_ITERATIONS: usize = 1 << 20;
+// the bytes of the rendered text, and of any string made on the way
+const MAX_OUTPUT: usize = 16 << 20;
+
+fn limit_error(message: impl Into<String>) -> Error {
+    Error {
+        kind: ErrorKind::BadInput,
+        message: format!("template limit exceeded: {}", message.into()),
+        cause: None,
+    }
+}
+
+fn template_error(message: impl Into<String>) -> Error {
+    Error {
+        kind: ErrorKind::BadInput,
+        message: message.into(),
+        cause: None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Name(String),
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Op(&'static str),
+}
+
+const OPS: [&str; 25] = [
+    "**", "//", "==", "!=", "<=", ">=", "+", "-", "*", "/", "%", "<", ">", "(", ")", "[", "]", "{",
+    "}", ",", ".", ":", "|", "~", "=",
+];
+
+fn tokenize(src: &str) -> Result<Vec<Tok>> {
+    let chars = src.chars().collect::<Vec<_>>();
+    let mut toks = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        if toks.len() >= MAX_TAG_TOKENS {
+            return Err(limit_error(format!(
+                "more than {} tokens in a tag",
+                MAX_TAG_TOKENS
+            )));
+        }
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            toks.push(Tok::Name(chars[start..i].iter().collect()));
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            if i + 1 < chars.len() && chars[i] == '.' && chars[i + 1].is_ascii_digit() {
+                i += 1;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+                let s = chars[start..i].iter().collect::<String>();
+                toks.push(Tok::Float(s.parse().unwrap()));
+            } else {
+                let s = chars[start..i].iter().collect::<String>();
+                let n = s
+                    .parse()
+                    .map_err(|_| template_error(format!("invalid integer {}", s)))?;
+                toks.push(Tok::Int(n));
+            }
+        } else if c == '\'' || c == '"' {
+            let mut s = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(template_error("unterminated string in the template")),
+                    Some(&q) if q == c => break,
+                    Some('\\') => {
+                        i += 1;
+                        match chars.get(i) {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some('r') => s.push('\r'),
+                            Some(&e @ ('\\' | '\'' | '"')) => s.push(e),
+                            Some(&e) => {
+                                s.push('\\');
+                                s.push(e);
+                            }
+                            None => return Err(template_error("unterminated string")),
+                        }
+                    }
+                    Some(&ch) => s.push(ch),
+                }
+                i += 1;
+            }
+            i += 1;
+            toks.push(Tok::Str(s));
+        } else {
+            let op = OPS
+                .iter()
+                .find(|op| {
+                    op.chars()
+                        .enumerate()
+                        .all(|(j, oc)| chars.get(i + j) == Some(&oc))
+                })
+                .ok_or_else(|| {
+                    template_error(format!("unexpected char {:?} in the template", c))
+                })?;
+            i += op.len();
+            toks.push(Tok::Op(op));
+        }
+    }
+    Ok(toks)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    FloorDiv,
+    Mod,
+    Pow,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    In,
+    NotIn,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Literal(Value),
+    Name(String),
+    List(Vec<Expr>),
+    Dict(Vec<(Expr, Expr)>),
+    Attr(Box<Expr>, String),
+    Index(Box<Expr>, Box<Expr>),
+    Slice(Box<Expr>, [Option<Box<Expr>>; 3]),
+    Call(Box<Expr>, Args),
+    Filter(Box<Expr>, String, Args),
+    Test(Box<Expr>, String, Args, bool),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Cond(Box<Expr>, Box<Expr>, Option<Box<Expr>>),
+}
+
+#[derive(Debug, Clone, Default)]
+struct Args {
+    positional: Vec<Expr>,
+    keywords: Vec<(String, Expr)>,
+}
+
+struct ExprParser<'t> {
+    toks: &'t [Tok],
+    pos: usize,
+    depth: usize,
+}
+
+impl<'t> ExprParser<'t> {
+    fn new(toks: &'t [Tok]) -> Self {
+        Self {
+            toks,
+            pos: 0,
+            depth: 0,
+        }
+    }
+
+    // parse one level deeper, like the expression in the parentheses
+    fn nested(&mut self, parse: impl FnOnce(&mut Self) -> Result<Expr>) -> Result<Expr> {
+        if self.depth >= MAX_DEPTH {
+            return Err(limit_error(format!(
+                "the expressions are nested deeper than {}",
+                MAX_DEPTH
+            )));
+        }
+        self.depth += 1;
+        let r = parse(self);
+        self.depth -= 1;
+        r
+    }
+
+    fn peek(&self) -> Option<&Tok> {
+        self.toks.get(self.pos)
+    }
+
+    fn peek_op(&self, op: &str) -> bool {
+        matches!(self.peek(), Some(Tok::Op(o)) if *o == op)
+    }
+
+    fn peek_name(&self, name: &str) -> bool {
+        matches!(self.peek(), Some(Tok::Name(n)) if n == name)
+    }
+
+    fn eat_op(&mut self, op: &str) -> bool {
+        if self.peek_op(op) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn eat_name(&mut self, name: &str) -> bool {
+        if self.peek_name(name) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<()> {
+        if !self.eat_op(op) {
+            return Err(template_error(format!(
+                "expected '{}' but got {:?}",
+                op,
+                self.peek()
+            )));
+        }
+        Ok(())
+    }
+
+    fn expect_name(&mut self) -> Result<String> {
+        match self.peek() {
+            Some(Tok::Name(n)) => {
+                let n = n.clone();
+                self.pos += 1;
+                Ok(n)
+            }
+            t => Err(template_error(format!("expected a name but got {:?}", t))),
+        }
+    }
+
+    fn is_end(&self) -> bool {
+        self.pos >= self.toks.len()
+    }
+
+    fn expect_end(&self) -> Result<()> {
+        if !self.is_end() {
+            return Err(template_error(format!(
+                "unexpected {:?} in the template",
+                self.peek()
+            )));
+        }
+        Ok(())
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr> {
+        self.nested(Self::parse_cond)
+    }
+
+    fn parse_cond(&mut self) -> Result<Expr> {
+        let expr = self.parse_or()?;
+        if self.eat_name("if") {
+            let cond = self.parse_or()?;
+            let otherwise = if self.eat_name("else") {
+                Some(Box::new(self.parse_expr()?))
+            } else {
+                None
+            };
+            return Ok(Expr::Cond(Box::new(cond), Box::new(expr), otherwise));
+        }
+        Ok(expr)
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_and()?;
+        while self.eat_name("or") {
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.parse_and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_not()?;
+        while self.eat_name("and") {
+            lhs = Expr::And(Box::new(lhs), Box::new(self.parse_not()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr> {
+        if self.eat_name("not") {
+            return Ok(Expr::Not(Box::new(self.nested(Self::parse_not)?)));
+        }
+        self.parse_compare()
+    }
+
+    fn parse_compare(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_math1()?;
+        loop {
+            let op = match self.peek() {
+                Some(Tok::Op("==")) => BinOp::Eq,
+                Some(Tok::Op("!=")) => BinOp::Ne,
+                Some(Tok::Op("<")) => BinOp::Lt,
+                Some(Tok::Op(">")) => BinOp::Gt,
+                Some(Tok::Op("<=")) => BinOp::Le,
+                Some(Tok::Op(">=")) => BinOp::Ge,
+                Some(Tok::Name(n)) if n == "in" => BinOp::In,
+                Some(Tok::Name(n))
+                    if n == "not"
+                        && matches!(self.toks.get(self.pos + 1), Some(Tok::Name(n)) if n == "in") =>
+                {
+                    self.pos += 1;
+                    BinOp::NotIn
+                }
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.parse_math1()?));
+        }
+    }
+
+    fn parse_math1(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_concat()?;
+        loop {
+            let op = if self.eat_op("+") {
+                BinOp::Add
+            } else if self.eat_op("-") {
+                BinOp::Sub
+            } else {
+                return Ok(lhs);
+            };
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.parse_concat()?));
+        }
+    }
+
+    fn parse_concat(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_math2()?;
+        while self.eat_op("~") {
+            lhs = Expr::Binary(BinOp::Concat, Box::new(lhs), Box::new(self.parse_math2()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_math2(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_pow()?;
+        loop {
+            let op = if self.eat_op("*") {
+                BinOp::Mul
+            } else if self.eat_op("/") {
+                BinOp::Div
+            } else if self.eat_op("//") {
+                BinOp::FloorDiv
+            } else if self.eat_op("%") {
+                BinOp::Mod
+            } else {
+                return Ok(lhs);
+            };
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.parse_pow()?));
+        }
+    }
+
+    fn parse_pow(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_unary()?;
+        while self.eat_op("**") {
+            lhs = Expr::Binary(BinOp::Pow, Box::new(lhs), Box::new(self.parse_unary()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        let expr = if self.eat_op("-") {
+            Expr::Neg(Box::new(self.nested(Self::parse_unary)?))
+        } else if self.eat_op("+") {
+            self.nested(Self::parse_unary)?
+        } else {
+            let primary = self.parse_primary()?;
+            self.parse_postfix(primary)?
+        };
+        self.parse_filters(expr)
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr> {
+        let tok = self
+            .peek()
+            .cloned()
+            .ok_or_else(|| template_error("unexpected end of the expression"))?;
+        self.pos += 1;
+        let expr = match tok {
+            Tok::Name(n) => match n.as_str() {
+                "true" | "True" => Expr::Literal(Value::Bool(true)),
+                "false" | "False" => Expr::Literal(Value::Bool(false)),
+                "none" | "None" => Expr::Literal(Value::None),
+                _ => Expr::Name(n),
+            },
+            Tok::Str(mut s) => {
+                // the adjacent strings are concatenated like python
+                while let Some(Tok::Str(next)) = self.peek() {
+                    s.push_str(next);
+                    self.pos += 1;
+                }
+                Expr::Literal(Value::Str(s))
+            }
+            Tok::Int(i) => Expr::Literal(Value::Int(i)),
+            Tok::Float(f) => Expr::Literal(Value::Float(f)),
+            Tok::Op("(") => {
+                let expr = self.parse_expr()?;
+                if self.peek_op(",") {
+                    let mut items = vec![expr];
+                    while self.eat_op(",") && !self.peek_op(")") {
+                        items.push(self.parse_expr()?);
+                    }
+                    self.expect_op(")")?;
+                    Expr::List(items)
+                } else {
+                    self.expect_op(")")?;
+                    expr
+                }
+            }
+            Tok::Op("[") => {
+                let mut items = vec![];
+                while !self.peek_op("]") {
+                    items.push(self.parse_expr()?);
+                    if !self.eat_op(",") {
+                        break;
+                    }
+                }
+                self.expect_op("]")?;
+                Expr::List(items)
+            }
+            Tok::Op("{") => {
+                let mut items = vec![];
+                while !self.peek_op("}") {
+                    let key = self.parse_expr()?;
+                    self.expect_op(":")?;
+                    items.push((key, self.parse_expr()?));
+                    if !self.eat_op(",") {
+                        break;
+                    }
+                }
+                self.expect_op("}")?;
+                Expr::Dict(items)
+            }
+            t => {
+                return Err(template_error(format!(
+                    "unexpected {:?} in the expression",
+                    t
+                )));
+            }
+        };
+        Ok(expr)
+    }
+
+    fn parse_postfix(&mut self, mut expr: Expr) -> Result<Expr> {
+        loop {
+            if self.eat_op(".") {
+                let name = match self.peek().cloned() {
+                    Some(Tok::Name(n)) => n,
+                    Some(Tok::Int(i)) => i.to_string(),
+                    t => return Err(template_error(format!("unexpected {:?} after '.'", t))),
+                };
+                self.pos += 1;
+                expr = Expr::Attr(Box::new(expr), name);
+            } else if self.eat_op("[") {
+                expr = self.parse_subscript(expr)?;
+            } else if self.peek_op("(") {
+                expr = Expr::Call(Box::new(expr), self.parse_args()?);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn parse_subscript(&mut self, expr: Expr) -> Result<Expr> {
+        let mut parts: [Option<Box<Expr>>; 3] = [None, None, None];
+        let mut n_colons = 0;
+        loop {
+            if self.eat_op("]") {
+                break;
+            }
+            if self.eat_op(":") {
+                n_colons += 1;
+                if n_colons > 2 {
+                    return Err(template_error("invalid slice in the template"));
+                }
+                continue;
+            }
+            parts[n_colons] = Some(Box::new(self.parse_expr()?));
+        }
+        if n_colons == 0 {
+            let index = parts[0]
+                .take()
+                .ok_or_else(|| template_error("empty subscript in the template"))?;
+            return Ok(Expr::Index(Box::new(expr), index));
+        }
+        Ok(Expr::Slice(Box::new(expr), parts))
+    }
+
+    fn parse_args(&mut self) -> Result<Args> {
+        self.expect_op("(")?;
+        let mut args = Args::default();
+        while !self.peek_op(")") {
+            let is_keyword = matches!(self.peek(), Some(Tok::Name(_)))
+                && matches!(self.toks.get(self.pos + 1), Some(Tok::Op("=")));
+            if is_keyword {
+                let name = self.expect_name()?;
+                self.pos += 1;
+                args.keywords.push((name, self.parse_expr()?));
+            } else {
+                args.positional.push(self.parse_expr()?);
+            }
+            if !self.eat_op(",") {
+                break;
+            }
+        }
+        self.expect_op(")")?;
+        Ok(args)
+    }
+
+    fn parse_filters(&mut self, mut expr: Expr) -> Result<Expr> {
+        loop {
+            if self.eat_op("|") {
+                let name = self.expect_name()?;
+                let args = if self.peek_op("(") {
+                    self.parse_args()?
+                } else {
+                    Args::default()
+                };
+                expr = Expr::Filter(Box::new(expr), name, args);
+            } else if self.eat_name("is") {
+                let negated = self.eat_name("not");
+                let name = self.expect_name()?;
+                let args = match self.peek() {
+                    Some(Tok::Op("(")) => self.parse_args()?,
+                    // a single argument without the parens, like `is equalto 'user'`
+                    Some(Tok::Str(_) | Tok::Int(_) | Tok::Float(_)) => Args {
+                        positional: vec![self.parse_primary()?],
+                        keywords: vec![],
+                    },
+                    _ => Args::default(),
+                };
+                expr = Expr::Test(Box::new(expr), name, args, negated);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Macro {
+    name: String,
+    params: Vec<(String, Option<Expr>)>,
+    body: Vec<Node>,
+}
+
+#[derive(Debug, Clone)]
+enum SetTarget {
+    Names(Vec<String>),
+    Attr(String, String),
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Text(String),
+    Output(Expr),
+    If(Vec<(Expr, Vec<Node>)>, Vec<Node>),
+    For {
+        targets: Vec<String>,
+        iter: Expr,
+        cond: Option<Expr>,
+        body: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    Set(SetTarget, Expr),
+    Macro(Rc<Macro>),
+}
+
+#[derive(Debug)]
+enum Chunk {
+    Text(String),
+    Expr(String),
+    Stmt(String),
+}
+
+// split the template into the texts and the tags, with the whitespace control applied
+fn split_chunks(src: &str) -> Result<Vec<Chunk>> {
+    let mut chunks = vec![];
+    let mut text = String::new();
+    let mut rest = src;
+    let mut trim_next = false; // the last tag ends with -%}
+    let mut after_block = false; // the last tag is a block or a comment, for trim_blocks
+    loop {
+        let start = ["{{", "{%", "{#"]
+            .iter()
+            .filter_map(|open| rest.find(open))
+            .min();
+        let (before, tag) = match start {
+            Some(start) => (&rest[..start], Some(&rest[start..])),
+            None => (rest, None),
+        };
+        let mut before = before;
+        if trim_next {
+            before = before.trim_start();
+        } else if after_block {
+            before = before.strip_prefix('\n').unwrap_or(before);
+        }
+        text.push_str(before);
+
+        let tag = match tag {
+            Some(tag) => tag,
+            None => break,
+        };
+        let kind = &tag[..2];
+        let inner = &tag[2..];
+        let (trim_before, keep_lstrip) = (inner.starts_with('-'), inner.starts_with('+'));
+        if trim_before {
+            text.truncate(text.trim_end().len());
+        } else if kind != "{{" && !keep_lstrip {
+            // lstrip_blocks: the spaces before a block at the start of a line are removed
+            let line_start = text.rfind('\n').map(|i| i + 1).unwrap_or(0);
+            if text[line_start..].chars().all(|c| c == ' ' || c == '\t') {
+                text.truncate(line_start);
+            }
+        }
+        let inner = if trim_before || keep_lstrip {
+            &inner[1..]
+        } else {
+            inner
+        };
+
+        let close = match kind {
+            "{{" => "}}",
+            "{%" => "%}",
+            _ => "#}",
+        };
+        let end = if kind == "{#" {
+            inner.find(close)
+        } else {
+            find_tag_end(inner, close)
+        }
+        .ok_or_else(|| template_error(format!("unclosed {} in the template", kind)))?;
+        let mut code = &inner[..end];
+        trim_next = code.ends_with('-');
+        if trim_next || code.ends_with('+') {
+            code = &code[..code.len() - 1];
+        }
+        after_block = kind != "{{";
+        rest = &inner[end + close.len()..];
+
+        if !text.is_empty() {
+            chunks.push(Chunk::Text(std::mem::take(&mut text)));
+        }
+        match kind {
+            "{{" => chunks.push(Chunk::Expr(code.to_string())),
+            "{%" => chunks.push(Chunk::Stmt(code.to_string())),
+            _ => {}
+        }
+    }
+    if !text.is_empty() {
+        chunks.push(Chunk::Text(text));
+    }
+    Ok(chunks)
+}
+
+// the end of a tag, skipping the closing marks in the string literals
+fn find_tag_end(s: &str, close: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if s[i..].starts_with(close) => {
+                // `-%}` leaves the `-` in the code
+                return Some(i);
+            }
+            None => {}
+        }
+    }
+    None
+}
+
+// the keyword and the rest tokens of the tag which ends a block
+type EndTag = (String, Vec<Tok>);
+
+struct TemplateParser {
+    chunks: Vec<Chunk>,
+    pos: usize,
+    depth: usize,
+}
+
+impl TemplateParser {
+    // parse the nodes until one of the end tags, return the tag and its tokens
+    fn parse_nodes(&mut self, ends: &[&str]) -> Result<(Vec<Node>, Option<EndTag>)> {
+        if self.depth >= MAX_DEPTH {
+            return Err(limit_error(format!(
+                "the blocks are nested deeper than {}",
+                MAX_DEPTH
+            )));
+        }
+        self.depth += 1;
+        let r = self.parse_block(ends);
+        self.depth -= 1;
+        r
+    }
+
+    fn parse_block(&mut self, ends: &[&str]) -> Result<(Vec<Node>, Option<EndTag>)> {
+        let mut nodes = vec![];
+        while self.pos < self.chunks.len() {
+            let chunk = &self.chunks[self.pos];
+            self.pos += 1;
+            match chunk {
+                Chunk::Text(t) => nodes.push(Node::Text(t.clone())),
+                Chunk::Expr(code) => {
+                    let toks = tokenize(code)?;
+                    let mut p = ExprParser::new(&toks);
+                    let expr = p.parse_expr()?;
+                    p.expect_end()?;
+                    nodes.push(Node::Output(expr));
+                }
+                Chunk::Stmt(code) => {
+                    let toks = tokenize(code)?;
+                    let keyword = match toks.first() {
+                        Some(Tok::Name(n)) => n.clone(),
+                        _ => return Err(template_error(format!("invalid tag {{%{}%}}", code))),
+                    };
+                    if ends.contains(&keyword.as_str()) {
+                        return Ok((nodes, Some((keyword, toks[1..].to_vec()))));
+                    }
+                    nodes.extend(self.parse_stmt(&keyword, &toks[1..])?);
+                }
+            }
+        }
+        if let Some(end) = ends.last() {
+            return Err(template_error(format!("missing {{% {} %}}", end)));
+        }
+        Ok((nodes, None))
+    }
+
+    fn parse_stmt(&mut self, keyword: &str, toks: &[Tok]) -> Result<Vec<Node>> {
+        let mut p = ExprParser::new(toks);
+        let node = match keyword {
+            "if" => {
+                let mut branches = vec![];
+                let mut cond = p.parse_expr()?;
+                p.expect_end()?;
+                loop {
+                    let (body, end) = self.parse_nodes(&["elif", "else", "endif"])?;
+                    branches.push((cond, body));
+                    let (end, toks) = end.unwrap();
+                    match end.as_str() {
+                        "elif" => {
+                            let mut p = ExprParser::new(&toks);
+                            cond = p.parse_expr()?;
+                            p.expect_end()?;
+                        }
+                        "else" => {
+                            let (otherwise, _) = self.parse_nodes(&["endif"])?;
+                            break Node::If(branches, otherwise);
+                        }
+                        _ => break Node::If(branches, vec![]),
+                    }
+                }
+            }
+            "for" => {
+                let mut targets = vec![p.expect_name()?];
+                while p.eat_op(",") {
+                    targets.push(p.expect_name()?);
+                }
+                if !p.eat_name("in") {
+                    return Err(template_error("expected 'in' in the for loop"));
+                }
+                // the filter of the items binds tighter than a conditional expression
+                let iter = p.parse_or()?;
+                let cond = if p.eat_name("if") {
+                    Some(p.parse_expr()?)
+                } else {
+                    None
+                };
+                p.eat_name("recursive");
+                p.expect_end()?;
+                let (body, end) = self.parse_nodes(&["else", "endfor"])?;
+                let otherwise = match end.unwrap().0.as_str() {
+                    "else" => self.parse_nodes(&["endfor"])?.0,
+                    _ => vec![],
+                };
+                Node::For {
+                    targets,
+                    iter,
+                    cond,
+                    body,
+                    otherwise,
+                }
+            }
+            "set" => {
+                let name = p.expect_name()?;
+                let target = if p.eat_op(".") {
+                    SetTarget::Attr(name, p.expect_name()?)
+                } else {
+                    let mut names = vec![name];
+                    while p.eat_op(",") {
+                        names.push(p.expect_name()?);
+                    }
+                    SetTarget::Names(names)
+                };
+                p.expect_op("=")?;
+                let value = p.parse_expr()?;
+                p.expect_end()?;
+                Node::Set(target, value)
+            }
+            "macro" => {
+                let name = p.expect_name()?;
+                p.expect_op("(")?;
+                let mut params = vec![];
+                while !p.peek_op(")") {
+                    let param = p.expect_name()?;
+                    let default = if p.eat_op("=") {
+                        Some(p.parse_expr()?)
+                    } else {
+                        None
+                    };
+                    params.push((param, default));
+                    if !p.eat_op(",") {
+                        break;
+                    }
+                }
+                p.expect_op(")")?;
+                p.expect_end()?;
+                let (body, _) = self.parse_nodes(&["endmacro"])?;
+                Node::Macro(Rc::new(Macro { name, params, body }))
+            }
+            // the marks of the assistant tokens for training, the content is rendered as is
+            "generation" => return Ok(self.parse_nodes(&["endgeneration"])?.0),
+            _ => {
+                return Err(template_error(format!(
+                    "unsupported tag {{% {} %}} in the template",
+                    keyword
+                )));
+            }
+        };
+        Ok(vec![node])
+    }
+}
+
+/// a parsed jinja template.
+#[derive(Debug, Clone)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    pub fn parse(src: &str) -> Result<Self> {
+        let mut parser = TemplateParser {
+            chunks: split_chunks(src)?,
+            pos: 0,
+            depth: 0,
+        };
+        let (nodes, _) = parser.parse_nodes(&[])?;
+        Ok(Self { nodes })
+    }
+
+    /// render with the variables in the context.
+    pub fn render(&self, context: Vec<(String, Value)>) -> Result<String> {
+        let mut renderer = Renderer {
+            scopes: vec![context.into_iter().collect()],
+            depth: 0,
+            iterations: 0,
+        };
+        let mut out = String::new();
+        renderer.render_nodes(&self.nodes, &mut out)?;
+        Ok(out)
+    }
+}
+
+type Keywords = Vec<(String, Value)>;
+
+struct Renderer {
+    scopes: Vec<HashMap<String, Value>>,
+    depth: usize,      // the nesting of the macro calls
+    iterations: usize, // the loop iterations so far
+}
+
+impl Renderer {
+    fn lookup(&self, name: &str) -> Value {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|s| s.get(name).cloned())
+            .unwrap_or(Value::Undefined)
+    }
+
+    fn set(&mut self, name: &str, value: Value) {
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .insert(name.to_string(), value);
+    }
+
+    fn render_nodes(&mut self, nodes: &[Node], out: &mut String) -> Result<()> {
+        for node in nodes {
+            if out.len() > MAX_OUTPUT {
+                return Err(limit_error(format!(
+                    "the output is longer than {} bytes",
+                    MAX_OUTPUT
+                )));
+            }
+            match node {
+                Node::Text(t) => out.push_str(t),
+                Node::Output(expr) => out.push_str(&self.eval(expr)?.to_output()),
+                Node::If(branches, otherwise) => {
+                    let mut taken = None;
+                    for (cond, body) in branches {
+                        if self.eval(cond)?.is_true() {
+                            taken = Some(body);
+                            break;
+                        }
+                    }
+                    self.render_nodes(taken.unwrap_or(otherwise), out)?;
+                }
+                Node::For {
+                    targets,
+                    iter,
+                    cond,
+                    body,
+                    otherwise,
+                } => self.render_for(targets, iter, cond.as_ref(), body, otherwise, out)?,
+                Node::Set(SetTarget::Names(names), expr) => {
+                    let value = self.eval(expr)?;
+                    self.assign(names, value)?;
+                }
+                Node::Set(SetTarget::Attr(name, attr), expr) => {
+                    let value = self.eval(expr)?;
+                    match self.lookup(name) {
+                        Value::Namespace(ns) => insert(&mut ns.borrow_mut(), attr, value),
+                        v => {
+                            return Err(template_error(format!(
+                                "can not set the attribute {} on a {}",
+                                attr,
+                                v.type_name()
+                            )));
+                        }
+                    }
+                }
+                Node::Macro(m) => self.set(&m.name, Value::Macro(m.clone())),
+            }
+        }
+        Ok(())
+    }
+
+    fn assign(&mut self, names: &[String], value: Value) -> Result<()> {
+        if names.len() == 1 {
+            self.set(&names[0], value);
+            return Ok(());
+        }
+        let items = value.iter_items()?;
+        if items.len() != names.len() {
+            return Err(template_error(format!(
+                "can not unpack {} values into {} names",
+                items.len(),
+                names.len()
+            )));
+        }
+        for (name, item) in names.iter().zip(items) {
+            self.set(name, item);
+        }
+        Ok(())
+    }
+
+    fn render_for(
+        &mut self,
+        targets: &[String],
+        iter: &Expr,
+        cond: Option<&Expr>,
+        body: &[Node],
+        otherwise: &[Node],
+        out: &mut String,
+    ) -> Result<()> {
+        let mut items = vec![];
+        for item in self.eval(iter)?.iter_items()? {
+            if let Some(cond) = cond {
+                self.scopes.push(HashMap::new());
+                let r = self.assign(targets, item.clone());
+                let keep = r.and_then(|_| self.eval(cond));
+                self.scopes.pop();
+                if !keep?.is_true() {
+                    continue;
+                }
+            }
+            items.push(item);
+        }
+        if items.is_empty() {
+            return self.render_nodes(otherwise, out);
+        }
+
+        let n = items.len();
+        for (i, item) in items.iter().enumerate() {
+            self.iterations += 1;
+            if self.iterations > MAX_ITERATIONS {
+                return Err(limit_error(format!(
+                    "more than {} loop iterations",
+                    MAX_ITERATIONS
+                )));
+            }
+            let loop_var = vec![
+                ("index".to_string(), Value::Int(i as i64 + 1)),
+                ("index0".to_string(), Value::Int(i as i64)),
+                ("revindex".to_string(), Value::Int((n - i) as i64)),
+                ("revindex0".to_string(), Value::Int((n - i - 1) as i64)),
+                ("first".to_string(), Value::Bool(i == 0)),
+                ("last".to_string(), Value::Bool(i == n - 1)),
+                ("length".to_string(), Value::Int(n as i64)),
+                (
+                    "previtem".to_string(),
+                    i.checked_sub(1)
+                        .map(|j| items[j].clone())
+                        .unwrap_or(Value::Undefined),
+                ),
+                (
+                    "nextitem".to_string(),
+                    items.get(i + 1).cloned().unwrap_or(Value::Undefined),
+                ),
+            ];
+            // the variables set in the loop body do not leak out of it
+            self.scopes.push(HashMap::new());
+            self.set("loop", Value::Map(loop_var));
+            let r = self
+                .assign(targets, item.clone())
+                .and_then(|_| self.render_nodes(body, out));
+            self.scopes.pop();
+            r?;
+        }
+        Ok(())
+    }
+
+    fn eval_args(&mut self, args: &Args) -> Result<(Vec<Value>, Keywords)> {
+        let positional = args
+            .positional
+            .iter()
+            .map(|e| self.eval(e))
+            .collect::<Result<Vec<_>>>()?;
+        let keywords = args
+            .keywords
+            .iter()
+            .map(|(k, e)| Ok((k.clone(), self.eval(e)?)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok((positional, keywords))
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Value> {
+        let v = match expr {
+            Expr::Literal(v) => v.clone(),
+            Expr::Name(n) => self.lookup(n),
+            Expr::List(items) => Value::List(
+                items
+                    .iter()
+                    .map(|e| self.eval(e))
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            Expr::Dict(items) => {
+                let mut m = vec![];
+                for (k, v) in items {
+                    let k = match self.eval(k)? {
+                        Value::Str(s) => s,
+                        k => k.repr(),
+                    };
+                    let v = self.eval(v)?;
+                    insert(&mut m, &k, v);
+                }
+                Value::Map(m)
+            }
+            Expr::Attr(obj, name) => self.eval(obj)?.get_attr(name),
+            Expr::Index(obj, index) => {
+                let obj = self.eval(obj)?;
+                let index = self.eval(index)?;
+                obj.get_item(&index)
+            }
+            Expr::Slice(obj, parts) => {
+                let obj = self.eval(obj)?;
+                let mut bounds = [None, None, None];
+                for (bound, part) in bounds.iter_mut().zip(parts.iter()) {
+                    if let Some(part) = part {
+                        *bound = self.eval(part)?.as_i64();
+                    }
+                }
+                slice(&obj, bounds)?
+            }
+            Expr::Call(callee, args) => {
+                let (positional, keywords) = self.eval_args(args)?;
+                match callee.as_ref() {
+                    Expr::Attr(obj, name) => {
+                        let obj = self.eval(obj)?;
+                        match obj.get_attr(name) {
+                            Value::Macro(m) => self.call_macro(&m, positional, keywords)?,
+                            _ => call_method(&obj, name, &positional, &keywords)?,
+                        }
+                    }
+                    Expr::Name(name) => match self.lookup(name) {
+                        Value::Macro(m) => self.call_macro(&m, positional, keywords)?,
+                        _ => call_function(name, &positional, &keywords)?,
+                    },
+                    _ => return Err(template_error("the value is not callable")),
+                }
+            }
+            Expr::Filter(obj, name, args) => {
+                let obj = self.eval(obj)?;
+                let (positional, keywords) = self.eval_args(args)?;
+                apply_filter(name, obj, &positional, &keywords)?
+            }
+            Expr::Test(obj, name, args, negated) => {
+                let obj = self.eval(obj)?;
+                let (positional, _) = self.eval_args(args)?;
+                Value::Bool(apply_test(name, &obj, &positional)? != *negated)
+            }
+            Expr::Not(e) => Value::Bool(!self.eval(e)?.is_true()),
+            Expr::Neg(e) => match self.eval(e)? {
+                Value::Int(i) => Value::Int(-i),
+                Value::Float(f) => Value::Float(-f),
+                v => {
+                    return Err(template_error(format!(
+                        "can not negate a {}",
+                        v.type_name()
+                    )));
+                }
+            },
+            Expr::And(a, b) => {
+                let a = self.eval(a)?;
+                if !a.is_true() { a } else { self.eval(b)? }
+            }
+            Expr::Or(a, b) => {
+                let a = self.eval(a)?;
+                if a.is_true() { a } else { self.eval(b)? }
+            }
+            Expr::Binary(op, a, b) => {
+                let a = self.eval(a)?;
+                let b = self.eval(b)?;
+                binary(*op, a, b)?
+            }
+            Expr::Cond(cond, then, otherwise) => {
+                if self.eval(cond)?.is_true() {
+                    self.eval(then)?
+                } else {
+                    match otherwise {
+                        Some(e) => self.eval(e)?,
+                        None => Value::Undefined,
+                    }
+                }
+            }
+        };
+        Ok(v)
+    }
+
+    fn call_macro(
+        &mut self,
+        m: &Macro,
+        positional: Vec<Value>,
+        keywords: Vec<(String, Value)>,
+    ) -> Result<Value> {
+        let mut scope = HashMap::new();
+        let mut positional = positional.into_iter();
+        for (param, default) in m.params.iter() {
+            let value = match keywords.iter().find(|(k, _)| k == param) {
+                Some((_, v)) => v.clone(),
+                None => match positional.next() {
+                    Some(v) => v,
+                    None => match default {
+                        Some(e) => self.eval(e)?,
+                        None => Value::Undefined,
+                    },
+                },
+            };
+            scope.insert(param.clone(), value);
+        }
+        // a macro may call itself, but not without an end
+        if self.depth >= MAX_DEPTH {
+            return Err(limit_error(format!(
+                "the macro calls are nested deeper than {}",
+                MAX_DEPTH
+            )));
+        }
+        self.depth += 1;
+        self.scopes.push(scope);
+        let mut out = String::new();
+        let r = self.render_nodes(&m.body, &mut out);
+        self.scopes.pop();
+        self.depth -= 1;
+        r?;
+        Ok(Value::Str(out))
+    }
+}
+
+fn slice(obj: &Value, bounds: [Option<i64>; 3]) -> Result<Value> {
+    let items = match obj {
+        Value::List(vs) => vs.clone(),
+        Value::Str(s) => s.chars().map(|c| Value::Str(c.to_string())).collect(),
+        Value::Undefined | Value::None => return Ok(Value::Undefined),
+        v => return Err(template_error(format!("can not slice a {}", v.type_name()))),
+    };
+    let len = items.len() as i64;
+    let step = bounds[2].unwrap_or(1);
+    if step == 0 {
+        return Err(template_error("the slice step can not be zero"));
+    }
+    let clamp = |i: i64, lo: i64, hi: i64| {
+        let i = if i < 0 { i + len } else { i };
+        i.clamp(lo, hi)
+    };
+    let mut picked = vec![];
+    if step > 0 {
+        let start = bounds[0].map(|i| clamp(i, 0, len)).unwrap_or(0);
+        let stop = bounds[1].map(|i| clamp(i, 0, len)).unwrap_or(len);
+        let mut i = start;
+        while i < stop {
+            picked.push(items[i as usize].clone());
+            i += step;
+        }
+    } else {
+        let start = bounds[0].map(|i| clamp(i, -1, len - 1)).unwrap_or(len - 1);
+        let stop = bounds[1].map(|i| clamp(i, -1, len - 1)).unwrap_or(-1);
+        let mut i = start;
+        while i > stop {
+            picked.push(items[i as usize].clone());
+            i += step;
+        }
+    }
+    Ok(match obj {
+        Value::Str(_) => Value::Str(picked.iter().map(|v| v.to_output()).collect()),
+        _ => Value::List(picked),
+    })
+}
+
+fn binary(op: BinOp, a: Value, b: Value) -> Result<Value> {
+    let unsupported = |a: &Value, b: &Value| {
+        template_error(format!(
+            "unsupported operand types for {:?}: {} and {}",
+            op,
+            a.type_name(),
+            b.type_name()
+        ))
+    };
+    let v = match op {
+        BinOp::Eq => Value::Bool(a == b),
+        BinOp::Ne => Value::Bool(a != b),
+        BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge => {
+            let ord = match (&a, &b) {
+                (Value::Str(x), Value::Str(y)) => x.partial_cmp(y),
+                _ => match (a.as_f64(), b.as_f64()) {
+                    (Some(x), Some(y)) => x.partial_cmp(&y),
+                    _ => return Err(unsupported(&a, &b)),
+                },
+            };
+            let ord = ord.ok_or_else(|| unsupported(&a, &b))?;
+            Value::Bool(match op {
+                BinOp::Lt => ord.is_lt(),
+                BinOp::Gt => ord.is_gt(),
+                BinOp::Le => ord.is_le(),
+                _ => ord.is_ge(),
+            })
+        }
+        BinOp::In | BinOp::NotIn => {
+            let found = match (&a, &b) {
+                (Value::Str(x), Value::Str(y)) => y.contains(x.as_str()),
+                (x, Value::List(vs)) => vs.contains(x),
+                (Value::Str(x), Value::Map(_) | Value::Namespace(_)) => {
+                    !matches!(b.get_attr(x), Value::Undefined)
+                }
+                (_, Value::Undefined | Value::None) => false,
+                _ => return Err(unsupported(&a, &b)),
+            };
+            Value::Bool(found == (op == BinOp::In))
+        }
+        BinOp::Concat => Value::Str(a.to_output() + &b.to_output()),
+        BinOp::Add => match (&a, &b) {
+            (Value::Str(x), Value::Str(y)) => Value::Str(x.clone() + y),
+            (Value::List(x), Value::List(y)) => Value::List(x.iter().chain(y).cloned().collect()),
+            _ => arith(op, &a, &b).ok_or_else(|| unsupported(&a, &b))?,
+        },
+        BinOp::Mul => match (&a, &b) {
+            (Value::Str(s), Value::Int(n)) | (Value::Int(n), Value::Str(s)) => {
+                let n = (*n).max(0) as usize;
+                if s.len().saturating_mul(n) > MAX_OUTPUT {
+                    return Err(limit_error(format!(
+                        "a string is longer than {} bytes",
+                        MAX_OUTPUT
+                    )));
+                }
+                Value::Str(s.repeat(n))
+            }
+            _ => arith(op, &a, &b).ok_or_else(|| unsupported(&a, &b))?,
+        },
+        _ => arith(op, &a, &b).ok_or_else(|| unsupported(&a, &b))?,
+    };
+    Ok(v)
+}
+
+fn arith(op: BinOp, a: &Value, b: &Value) -> Option<Value> {
+    if let (Some(x), Some(y)) = (a.as_i64(), b.as_i64()) {
+        let v = match op {
+            BinOp::Add => Value::Int(x.checked_add(y)?),
+            BinOp::Sub => Value::Int(x.checked_sub(y)?),
+            BinOp::Mul => Value::Int(x.checked_mul(y)?),
+            BinOp::Div if y != 0 => Value::Float(x as f64 / y as f64),
+            BinOp::FloorDiv if y != 0 => Value::Int(x.div_euclid(y)),
+            BinOp::Mod if y != 0 => Value::Int(x.rem_euclid(y)),
+            BinOp::Pow if y >= 0 => Value::Int(x.checked_pow(y as u32)?),
+            BinOp::Pow => Value::Float((x as f64).powf(y as f64)),
+            _ => return None,
+        };
+        return Some(v);
+    }
+    let (x, y) = (a.as_f64()?, b.as_f64()?);
+    let v = match op {
+        BinOp::Add => x + y,
+        BinOp::Sub => x - y,
+        BinOp::Mul => x * y,
+        BinOp::Div if y != 0.0 => x / y,
+        BinOp::FloorDiv if y != 0.0 => (x / y).floor(),
+        BinOp::Mod if y != 0.0 => x.rem_euclid(y),
+        BinOp::Pow => x.powf(y),
+        _ => return None,
+    };
+    Some(Value::Float(v))
+}
+
+fn arg<'v>(
+    positional: &'v [Value],
+    keywords: &'v [(String, Value)],
+    i: usize,
+    name: &str,
+) -> Option<&'v Value> {
+    keywords
+        .iter()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v)
+        .or_else(|| positional.get(i))
+}
+
+fn str_arg(v: Option<&Value>) -> Option<String> {
+    match v {
+        Some(Value::Str(s)) => Some(s.clone()),
+        _ => None,
+    }
+}
+
+fn strip(s: &str, chars: Option<String>, left: bool, right: bool) -> String {
+    let is_strip = |c: char| match &chars {
+        Some(chars) => chars.contains(c),
+        None => c.is_whitespace(),
+    };
+    let mut s = s;
+    if left {
+        s = s.trim_start_matches(is_strip);
+    }
+    if right {
+        s = s.trim_end_matches(is_strip);
+    }
+    s.to_string()
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) => c
+            .to_uppercase()
+            .chain(chars.flat_map(|c| c.to_lowercase()))
+            .collect(),
+        None => String::new(),
+    }
+}
+
+fn title(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut prev_alpha = false;
+    for c in s.chars() {
+        if prev_alpha {
+            out.extend(c.to_lowercase());
+        } else {
+            out.extend(c.to_uppercase());
+        }
+        prev_alpha = c.is_alphanumeric();
+    }
+    out
+}
+
+fn call_method(
+    obj: &Value,
+    name: &str,
+    positional: &[Value],
+    keywords: &[(String, Value)],
+) -> Result<Value> {
+    let arg = |i: usize, n: &str| arg(positional, keywords, i, n);
+    let v = match (obj, name) {
+        (Value::Str(s), "strip") => Value::Str(strip(s, str_arg(arg(0, "chars")), true, true)),
+        (Value::Str(s), "lstrip") => Value::Str(strip(s, str_arg(arg(0, "chars")), true, false)),
+        (Value::Str(s), "rstrip") => Value::Str(strip(s, str_arg(arg(0, "chars")), false, true)),
+        (Value::Str(s), "upper") => Value::Str(s.to_uppercase()),
+        (Value::Str(s), "lower") => Value::Str(s.to_lowercase()),
+        (Value::Str(s), "title") => Value::Str(title(s)),
+        (Value::Str(s), "capitalize") => Value::Str(capitalize(s)),
+        (Value::Str(s), "startswith" | "endswith") => {
+            let prefixes = match arg(0, "prefix") {
+                Some(Value::List(vs)) => vs.iter().map(|v| v.to_output()).collect(),
+                Some(v) => vec![v.to_output()],
+                None => return Err(template_error(format!("{}() takes an argument", name))),
+            };
+            let found = prefixes.iter().any(|p| match name {
+                "startswith" => s.starts_with(p.as_str()),
+                _ => s.ends_with(p.as_str()),
+            });
+            Value::Bool(found)
+        }
+        (Value::Str(s), "split") => {
+            let maxsplit = arg(1, "maxsplit").and_then(|v| v.as_i64()).unwrap_or(-1);
+            let parts: Vec<String> = match str_arg(arg(0, "sep")) {
+                Some(sep) if maxsplit >= 0 => s
+                    .splitn(maxsplit as usize + 1, sep.as_str())
+                    .map(String::from)
+                    .collect(),
+                Some(sep) => s.split(sep.as_str()).map(String::from).collect(),
+                None => s.split_whitespace().map(String::from).collect(),
+            };
+            Value::List(parts.into_iter().map(Value::Str).collect())
+        }
+        (Value::Str(s), "splitlines") => {
+            Value::List(s.lines().map(|l| Value::Str(l.to_string())).collect())
+        }
+        (Value::Str(s), "replace") => {
+            let old = str_arg(arg(0, "old")).unwrap_or_default();
+            let new = str_arg(arg(1, "new")).unwrap_or_default();
+            match arg(2, "count").and_then(|v| v.as_i64()) {
+                Some(n) if n >= 0 => Value::Str(s.replacen(old.as_str(), &new, n as usize)),
+                _ => Value::Str(s.replace(old.as_str(), &new)),
+            }
+        }
+        (Value::Str(s), "find") => {
+            let sub = str_arg(arg(0, "sub")).unwrap_or_default();
+            Value::Int(match s.find(sub.as_str()) {
+                Some(i) => s[..i].chars().count() as i64,
+                None => -1,
+            })
+        }
+        (Value::Str(s), "count") => {
+            let sub = str_arg(arg(0, "sub")).unwrap_or_default();
+            Value::Int(s.matches(sub.as_str()).count() as i64)
+        }
+        (Value::Str(s), "join") => {
+            let items = arg(0, "iterable").cloned().unwrap_or(Value::Undefined);
+            let items = items.iter_items()?;
+            Value::Str(
+                items
+                    .iter()
+                    .map(|v| v.to_output())
+                    .collect::<Vec<_>>()
+                    .join(s),
+            )
+        }
+        (Value::Map(_) | Value::Namespace(_), "items") => Value::List(
+            map_entries(obj)
+                .into_iter()
+                .map(|(k, v)| Value::List(vec![Value::Str(k), v]))
+                .collect(),
+        ),
+        (Value::Map(_) | Value::Namespace(_), "keys") => Value::List(
+            map_entries(obj)
+                .into_iter()
+                .map(|(k, _)| Value::Str(k))
+                .collect(),
+        ),
+        (Value::Map(_) | Value::Namespace(_), "values") => {
+            Value::List(map_entries(obj).into_iter().map(|(_, v)| v).collect())
+        }
+        (Value::Map(_) | Value::Namespace(_), "get") => {
+            let key = str_arg(arg(0, "key")).unwrap_or_default();
+            match obj.get_attr(&key) {
+                Value::Undefined => arg(1, "default").cloned().unwrap_or(Value::None),
+                v => v,
+            }
+        }
+        _ => {
+            return Err(template_error(format!(
+                "unknown method {}() of {}",
+                name,
+                obj.type_name()
+            )));
+        }
+    };
+    Ok(v)
+}
+
+fn map_entries(obj: &Value) -> Vec<(String, Value)> {
+    match obj {
+        Value::Map(m) => m.clone(),
+        Value::Namespace(ns) => ns.borrow().clone(),
+        _ => vec![],
+    }
+}
+
+fn call_function(name: &str, positional: &[Value], keywords: &[(String, Value)]) -> Result<Value> {
+    let v = match name {
+        "raise_exception" => {
+            let message = positional
+                .first()
+                .map(|v| v.to_output())
+                .unwrap_or_default();
+            return Err(template_error(format!(
+                "the chat template raised: {}",
+                message
+            )));
+        }
+        "namespace" | "dict" => {
+            let mut m = vec![];
+            if let Some(Value::Map(init)) = positional.first() {
+                m.clone_from(init);
+            }
+            for (k, v) in keywords {
+                insert(&mut m, k, v.clone());
+            }
+            match name {
+                "namespace" => Value::Namespace(Rc::new(RefCell::new(m))),
+                _ => Value::Map(m),
+            }
+        }
+        "range" => {
+            let ints = positional
+                .iter()
+                .map(|v| v.as_i64())
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| template_error("range() takes integers"))?;
+            let (start, stop, step) = match ints.as_slice() {
+                [stop] => (0, *stop, 1),
+                [start, stop] => (*start, *stop, 1),
+                [start, stop, step] if *step != 0 => (*start, *stop, *step),
+                _ => return Err(template_error("invalid arguments of range()")),
+            };
+            let mut items = vec![];
+            let mut i = Some(start);
+            while let Some(v) = i.filter(|v| (step > 0 && *v < stop) || (step < 0 && *v > stop)) {
+                if items.len() >= MAX_ITERATIONS {
+                    return Err(limit_error(format!(
+                        "range() of more than {} items",
+                        MAX_ITERATIONS
+                    )));
+                }
+                items.push(Value::Int(v));
+                // the range ends where the next one would overflow
+                i = v.checked_add(step);
+            }
+            Value::List(items)
+        }
+        "strftime_now" => {
+            let format = str_arg(positional.first()).unwrap_or_default();
+            let secs = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(0);
+            Value::Str(strftime(&format, secs))
+        }
+        _ => return Err(template_error(format!("unknown function {}()", name))),
+    };
+    Ok(v)
+}
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+const WEEKDAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+
+// format the utc time of the unix timestamp like python's strftime
+fn strftime(format: &str, secs: i64) -> String {
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+    let (hour, minute, second) = (rem / 3600, rem % 3600 / 60, rem % 60);
+    // the civil date of the days since 1970-01-01, by the algorithm of Howard Hinnant
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    let weekday = (days + 4).rem_euclid(7) as usize;
+
+    let mut out = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => out.push_str(&year.to_string()),
+            Some('y') => out.push_str(&format!("{:02}", year % 100)),
+            Some('m') => out.push_str(&format!("{:02}", month)),
+            Some('d') => out.push_str(&format!("{:02}", day)),
+            Some('e') => out.push_str(&format!("{:2}", day)),
+            Some('H') => out.push_str(&format!("{:02}", hour)),
+            Some('I') => out.push_str(&format!("{:02}", (hour + 11) % 12 + 1)),
+            Some('M') => out.push_str(&format!("{:02}", minute)),
+            Some('S') => out.push_str(&format!("{:02}", second)),
+            Some('p') => out.push_str(if hour < 12 { "AM" } else { "PM" }),
+            Some('B') => out.push_str(MONTHS[month as usize - 1]),
+            Some('b') => out.push_str(&MONTHS[month as usize - 1][..3]),
+            Some('A') => out.push_str(WEEKDAYS[weekday]),
+            Some('a') => out.push_str(&WEEKDAYS[weekday][..3]),
+            Some('%') => out.push('%'),
+            Some(other) => {
+                out.push('%');
+                out.push(other);
+            }
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+fn apply_filter(
+    name: &str,
+    obj: Value,
+    positional: &[Value],
+    keywords: &[(String, Value)],
+) -> Result<Value> {
+    let arg = |i: usize, n: &str| arg(positional, keywords, i, n);
+    let v = match name {
+        "trim" | "strip" => Value::Str(strip(
+            &obj.to_output(),
+            str_arg(arg(0, "chars")),
+            true,
+            true,
+        )),
+        "upper" => Value::Str(obj.to_output().to_uppercase()),
+        "lower" => Value::Str(obj.to_output().to_lowercase()),
+        "title" => Value::Str(title(&obj.to_output())),
+        "capitalize" => Value::Str(capitalize(&obj.to_output())),
+        "string" => Value::Str(obj.to_output()),
+        "safe" => obj,
+        "escape" | "e" => Value::Str(
+            obj.to_output()
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&#34;")
+                .replace('\'', "&#39;"),
+        ),
+        "length" | "count" => Value::Int(obj.len().unwrap_or(0) as i64),
+        "first" => obj
+            .iter_items()?
+            .into_iter()
+            .next()
+            .unwrap_or(Value::Undefined),
+        "last" => obj.iter_items()?.pop().unwrap_or(Value::Undefined),
+        "list" => Value::List(obj.iter_items()?),
+        "reverse" => match obj {
+            Value::Str(s) => Value::Str(s.chars().rev().collect()),
+            v => Value::List(v.iter_items()?.into_iter().rev().collect()),
+        },
+        "items" => call_method(&obj, "items", &[], &[])?,
+        "join" => {
+            let sep = str_arg(arg(0, "d")).unwrap_or_default();
+            let attribute = str_arg(arg(1, "attribute"));
+            let items = obj
+                .iter_items()?
+                .into_iter()
+                .map(|v| match &attribute {
+                    Some(a) => v.get_attr(a).to_output(),
+                    None => v.to_output(),
+                })
+                .collect::<Vec<_>>();
+            Value::Str(items.join(&sep))
+        }
+        "default" | "d" => {
+            let boolean = arg(1, "boolean").is_some_and(|v| v.is_true());
+            let missing = matches!(obj, Value::Undefined) || (boolean && !obj.is_true());
+            if missing {
+                arg(0, "default_value")
+                    .cloned()
+                    .unwrap_or(Value::Str(String::new()))
+            } else {
+                obj
+            }
+        }
+        "tojson" => {
+            let indent = arg(0, "indent")
+                .and_then(|v| v.as_i64())
+                .map(|i| i as usize);
+            let mut out = String::new();
+            obj.to_json(indent, 0, &mut out);
+            Value::Str(out)
+        }
+        "int" => match &obj {
+            Value::Str(s) => Value::Int(s.trim().parse().unwrap_or(0)),
+            Value::Float(f) => Value::Int(*f as i64),
+            v => Value::Int(v.as_i64().unwrap_or(0)),
+        },
+        "float" => match &obj {
+            Value::Str(s) => Value::Float(s.trim().parse().unwrap_or(0.0)),
+            v => Value::Float(v.as_f64().unwrap_or(0.0)),
+        },
+        "abs" => match obj {
+            Value::Int(i) => Value::Int(i.abs()),
+            Value::Float(f) => Value::Float(f.abs()),
+            v => return Err(template_error(format!("abs of a {}", v.type_name()))),
+        },
+        "replace" => call_method(
+            &Value::Str(obj.to_output()),
+            "replace",
+            positional,
+            keywords,
+        )?,
+        "indent" => {
+            let width = arg(0, "width").and_then(|v| v.as_i64()).unwrap_or(4) as usize;
+            let first = arg(1, "first").is_some_and(|v| v.is_true());
+            let pad = " ".repeat(width);
+            let text = obj.to_output();
+            let mut out = String::new();
+            for (i, line) in text.split('\n').enumerate() {
+                if i > 0 {
+                    out.push('\n');
+                }
+                if (i > 0 || first) && !line.is_empty() {
+                    out.push_str(&pad);
+                }
+                out.push_str(line);
+            }
+            Value::Str(out)
+        }
+        "map" => {
+            let items = obj.iter_items()?;
+            let mapped = match str_arg(arg(usize::MAX, "attribute")) {
+                Some(attribute) => items.iter().map(|v| v.get_attr(&attribute)).collect(),
+                None => {
+                    let filter = str_arg(positional.first())
+                        .ok_or_else(|| template_error("map() takes a filter or an attribute"))?;
+                    items
+                        .into_iter()
+                        .map(|v| apply_filter(&filter, v, &positional[1..], &[]))
+                        .collect::<Result<Vec<_>>>()?
+                }
+            };
+            Value::List(mapped)
+        }
+        "select" | "reject" | "selectattr" | "rejectattr" => {
+            let by_attr = name.ends_with("attr");
+            let (attribute, rest) = if by_attr {
+                (
+                    str_arg(positional.first()),
+                    positional.get(1..).unwrap_or(&[]),
+                )
+            } else {
+                (None, positional)
+            };
+            let test = str_arg(rest.first());
+            let test_args = rest.get(1..).unwrap_or(&[]);
+            let keep = name.starts_with("select");
+            let mut picked = vec![];
+            for item in obj.iter_items()? {
+                let v = match &attribute {
+                    Some(a) => item.get_attr(a),
+                    None => item.clone(),
+                };
+                let passed = match &test {
+                    Some(test) => apply_test(test, &v, test_args)?,
+                    None => v.is_true(),
+                };
+                if passed == keep {
+                    picked.push(item);
+                }
+            }
+            Value::List(picked)
+        }
+        _ => return Err(template_error(format!("unknown filter {}", name))),
+    };
+    Ok(v)
+}
+
+fn apply_test(name: &str, v: &Value, args: &[Value]) -> Result<bool> {
+    let other = || args.first().cloned().unwrap_or(Value::Undefined);
+    let r = match name {
+        "defined" => !matches!(v, Value::Undefined),
+        "undefined" => matches!(v, Value::Undefined),
+        "none" => matches!(v, Value::None),
+        "boolean" => matches!(v, Value::Bool(_)),
+        "true" => matches!(v, Value::Bool(true)),
+        "false" => matches!(v, Value::Bool(false)),
+        "string" => matches!(v, Value::Str(_)),
+        "number" => matches!(v, Value::Int(_) | Value::Float(_)),
+        "integer" => matches!(v, Value::Int(_)),
+        "float" => matches!(v, Value::Float(_)),
+        "mapping" => matches!(v, Value::Map(_) | Value::Namespace(_)),
+        "sequence" | "iterable" => matches!(v, Value::List(_) | Value::Str(_) | Value::Map(_)),
+        "callable" => matches!(v, Value::Macro(_)),
+        "odd" => v.as_i64().is_some_and(|i| i % 2 != 0),
+        "even" => v.as_i64().is_some_and(|i| i % 2 == 0),
+        "divisibleby" => match (v.as_i64(), other().as_i64()) {
+            (Some(a), Some(b)) if b != 0 => a % b == 0,
+            _ => false,
+        },
+        "eq" | "equalto" | "==" | "sameas" => *v == other(),
+        "ne" | "!=" => *v != other(),
+        "lt" | "gt" | "le" | "ge" => {
+            let op = match name {
+                "lt" => BinOp::Lt,
+                "gt" => BinOp::Gt,
+                "le" => BinOp::Le,
+                _ => BinOp::Ge,
+            };
+            binary(op, v.clone(), other())?.is_true()
+        }
+        "in" => binary(BinOp::In, v.clone(), other())?.is_true(),
+        "lower" => matches!(v, Value::Str(s) if s.to_lowercase() == *s),
+        "upper" => matches!(v, Value::Str(s) if s.to_uppercase() == *s),
+        _ => return Err(template_error(format!("unknown test {}", name))),
+    };
+    Ok(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(src: &str, context: serde_json::Value) -> Result<String> {
+        let context = match Value::from_json(&context) {
+            Value::Map(m) => m,
+            _ => vec![],
+        };
+        Template::parse(src)?.render(context)
+    }
+
+    #[test]
+    fn test_render_expressions() -> Result<()> {
+        let ctx = serde_json::json!({"a": 3, "s": "  Hi  ", "xs": [1, 2, 3], "m": {"k": "v"}});
+        let cases = [
+            ("{{ a + 2 * 3 }}", "9"),
+            (
+                "{{ 7 // 2 }} {{ 7 % 4 }} {{ 2 ** 3 }} {{ 1 / 2 }}",
+                "3 3 8 0.5",
+            ),
+            ("{{ s | trim }}|{{ s.strip().upper() }}", "Hi|HI"),
+            ("{{ 'a' ~ a ~ none }}", "a3None"),
+            (
+                "{{ xs[-1] }}{{ xs[1:] }}{{ xs[::-1] | join(',') }}",
+                "3[2, 3]3,2,1",
+            ),
+            ("{{ m.k }}{{ m['k'] }}{{ m.missing }}", "vv"),
+            ("{{ 'yes' if a > 2 else 'no' }}", "yes"),
+            ("{{ a is defined and b is not defined }}", "True"),
+            (
+                "{{ 2 in xs }} {{ 'k' in m }} {{ 'x' not in 'abc' }}",
+                "True True True",
+            ),
+            (
+                "{{ xs | length }} {{ xs | first }} {{ xs | last }}",
+                "3 1 3",
+            ),
+            (
+                "{{ m | tojson }} {{ {'a': [1, 'b']} | tojson }}",
+                r#"{"k": "v"} {"a": [1, "b"]}"#,
+            ),
+            (
+                "{{ b | default('d') }}{{ 'abc'.startswith(('x', 'a')) }}",
+                "dTrue",
+            ),
+            (
+                "{{ [1, 2] + [3] }} {{ 'ab' * 2 }} {{ -a }}",
+                "[1, 2, 3] abab -3",
+            ),
+            ("{{ 'a,b'.split(',') }}", "['a', 'b']"),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(render(src, ctx.clone())?, expected, "{}", src);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_statements() -> Result<()> {
+        let ctx = serde_json::json!({"xs": ["a", "b", "c"]});
+        let cases = [
+            // trim_blocks and lstrip_blocks are enabled like transformers
+            (
+                "{% for x in xs %}\n  {{ loop.index }}{{ x }}\n{% endfor %}",
+                "  1a\n  2b\n  3c\n",
+            ),
+            (
+                "{%- for x in xs -%} {{ x }}{% if not loop.last %},{% endif %} {%- endfor %}",
+                "a,b,c",
+            ),
+            (
+                "{% for x in xs if x != 'b' %}{{ loop.length }}{{ x }}{% endfor %}",
+                "2a2c",
+            ),
+            ("{% for x in [] %}x{% else %}empty{% endfor %}", "empty"),
+            (
+                "{% set y = 1 %}{% for x in xs %}{% set y = 2 %}{% endfor %}{{ y }}",
+                "1",
+            ),
+            (
+                "{% set ns = namespace(n=0) %}{% for x in xs %}{% set ns.n = ns.n + 1 %}{% endfor %}{{ ns.n }}",
+                "3",
+            ),
+            ("{% if 0 %}a{% elif xs %}b{% else %}c{% endif %}", "b"),
+            (
+                "{# comment #}{% for k, v in {'a': 1}.items() %}{{ k }}={{ v }}{% endfor %}",
+                "a=1",
+            ),
+            (
+                "{% macro m(x, y='!') %}<{{ x }}{{ y }}>{% endmacro %}{{ m('a') }}{{ m('b', y='?') }}",
+                "<a!><b?>",
+            ),
+            (
+                "{{ xs | selectattr('x', 'defined') | list | length }}{{ xs | select('equalto', 'b') | list }}",
+                "0['b']",
+            ),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(render(src, ctx.clone())?, expected, "{}", src);
+        }
+
+        let err = render("{{ raise_exception('bad roles') }}", ctx.clone()).unwrap_err();
+        assert!(err.message.contains("bad roles"));
+        assert!(Template::parse("{% if x %}").is_err());
+        assert!(Template::parse("{{ x").is_err());
+        assert!(Template::parse("{% foo %}").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_limits() -> Result<()> {
+        let ctx = serde_json::json!({});
+        let cases = [
+            "{% macro m() %}{{ m() }}{% endmacro %}{{ m() }}",
+            "{% for i in range(100000000) %}{% endfor %}",
+            "{% for i in range(2000) %}{% for j in range(2000) %}{% endfor %}{% endfor %}",
+            "{{ 'x' * 100000000000 }}",
+            "{% for i in range(1000) %}{{ 'x' * 100000 }}{% endfor %}",
+        ];
+        for src in cases {
+            let err = render(src, ctx.clone()).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::BadInput, "{}", src);
+            assert!(err.message.contains("limit"), "{}", src);
+        }
+
+        // the parsing of the deep nesting fails before the stack overflows
+        let deep = format!("{{{{ {}1{} }}}}", "(".repeat(10000), ")".repeat(10000));
+        assert!(Template::parse(&deep).is_err());
+        let deep = format!("{{{{ {}x }}}}", "not ".repeat(10000));
+        assert!(Template::parse(&deep).is_err());
+        let deep = "{% if x %}".repeat(10000);
+        assert!(Template::parse(&deep).is_err());
+
+        // the range stops before the next item overflows
+        assert_eq!(
+            render(
+                "{{ range(9223372036854775805, 9223372036854775807, 3) }}",
+                ctx.clone()
+            )?,
+            "[9223372036854775805]"
+        );
+        assert_eq!(
+            render(
+                "{{ range(-9223372036854775806, -9223372036854775807, -5) | length }}",
+                ctx
+            )?,
+            "1"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_strftime() {
+        // 2024-02-29 13:05:09 utc, a thursday
+        assert_eq!(
+            strftime("%d %b %Y %H:%M:%S %A %B %%", 1709211909),
+            "29 Feb 2024 13:05:09 Thursday February %"
+        );
+        assert_eq!(strftime("%Y-%m-%d", 0), "1970-01-01");
+    }
+}
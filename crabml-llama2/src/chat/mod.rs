@@ -0,0 +1,429 @@
+#[cfg(feature = "chat-template")]
+pub mod jinja;
+
+use crabml::error::Result;
+use crabml::tokenizer::BpeTokenizer;
+
+use crate::model::Llama2Config;
+use crate::model::ModelArchitecture;
+
+/// a message in a conversation, the role is usually one of system, user, assistant or tool.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+    /// the tool calls of an assistant message, passed to the jinja templates as is.
+    pub tool_calls: Vec<serde_json::Value>,
+}
+
+impl ChatMessage {
+    pub fn new(role: &str, content: &str) -> Self {
+        Self {
+            role: role.to_string(),
+            content: content.to_string(),
+            tool_calls: vec![],
+        }
+    }
+
+    pub fn system(content: &str) -> Self {
+        Self::new("system", content)
+    }
+
+    pub fn user(content: &str) -> Self {
+        Self::new("user", content)
+    }
+
+    pub fn assistant(content: &str) -> Self {
+        Self::new("assistant", content)
+    }
+
+    pub fn with_tool_calls(mut self, tool_calls: Vec<serde_json::Value>) -> Self {
+        self.tool_calls = tool_calls;
+        self
+    }
+}
+
+/// the well known chat formats, used when the model has no chat template or the
+/// `chat-template` feature is off. they do not render the tools.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuiltinTemplate {
+    ChatML,
+    Llama2,
+    Llama3,
+    Gemma,
+}
+
+impl BuiltinTemplate {
+    /// guess the format of a jinja chat template by its special tokens.
+    pub fn detect(source: &str) -> Option<Self> {
+        if source.contains("<|im_start|>") {
+            Some(Self::ChatML)
+        } else if source.contains("<|start_header_id|>") {
+            Some(Self::Llama3)
+        } else if source.contains("<start_of_turn>") {
+            Some(Self::Gemma)
+        } else if source.contains("[INST]") {
+            Some(Self::Llama2)
+        } else {
+            None
+        }
+    }
+
+    pub fn for_architecture(architecture: ModelArchitecture) -> Self {
+        match architecture {
+            ModelArchitecture::Llama => Self::Llama2,
+            ModelArchitecture::Gemma => Self::Gemma,
+            ModelArchitecture::Qwen2 | ModelArchitecture::Phi2 => Self::ChatML,
+        }
+    }
+
+    fn render(
+        &self,
+        messages: &[ChatMessage],
+        add_generation_prompt: bool,
+        bos_token: &str,
+        eos_token: &str,
+    ) -> String {
+        let mut out = String::new();
+        match self {
+            Self::ChatML => {
+                for m in messages {
+                    out.push_str(&format!(
+                        "<|im_start|>{}\n{}<|im_end|>\n",
+                        m.role, m.content
+                    ));
+                }
+                if add_generation_prompt {
+                    out.push_str("<|im_start|>assistant\n");
+                }
+            }
+            Self::Llama3 => {
+                out.push_str(bos_token);
+                for m in messages {
+                    out.push_str(&format!(
+                        "<|start_header_id|>{}<|end_header_id|>\n\n{}<|eot_id|>",
+                        m.role,
+                        m.content.trim()
+                    ));
+                }
+                if add_generation_prompt {
+                    out.push_str("<|start_header_id|>assistant<|end_header_id|>\n\n");
+                }
+            }
+            Self::Llama2 => {
+                // the system prompt is folded into the first user message
+                let (system, messages) = split_system(messages);
+                for (i, m) in messages.iter().enumerate() {
+                    let content = match (i, system) {
+                        (0, Some(system)) => {
+                            format!("<<SYS>>\n{}\n<</SYS>>\n\n{}", system, m.content.trim())
+                        }
+                        _ => m.content.trim().to_string(),
+                    };
+                    if m.role == "assistant" {
+                        out.push_str(&format!(" {} {}", content, eos_token));
+                    } else {
+                        out.push_str(&format!("{}[INST] {} [/INST]", bos_token, content));
+                    }
+                }
+            }
+            Self::Gemma => {
+                // gemma has no system role, the system prompt goes before the first message
+                out.push_str(bos_token);
+                let (system, messages) = split_system(messages);
+                for (i, m) in messages.iter().enumerate() {
+                    let role = if m.role == "assistant" {
+                        "model"
+                    } else {
+                        &m.role
+                    };
+                    let content = match (i, system) {
+                        (0, Some(system)) => format!("{}\n\n{}", system, m.content.trim()),
+                        _ => m.content.trim().to_string(),
+                    };
+                    out.push_str(&format!(
+                        "<start_of_turn>{}\n{}<end_of_turn>\n",
+                        role, content
+                    ));
+                }
+                if add_generation_prompt {
+                    out.push_str("<start_of_turn>model\n");
+                }
+            }
+        }
+        out
+    }
+}
+
+fn split_system(messages: &[ChatMessage]) -> (Option<&str>, &[ChatMessage]) {
+    match messages.first() {
+        Some(m) if m.role == "system" => (Some(m.content.trim()), &messages[1..]),
+        _ => (None, messages),
+    }
+}
+
+#[derive(Debug, Clone)]
+enum TemplateKind {
+    Builtin(BuiltinTemplate),
+    #[cfg(feature = "chat-template")]
+    Jinja(jinja::Template),
+}
+
+/// formats the messages of a conversation into a prompt, by the jinja chat template stored
+/// in the GGUF file, or a built-in format.
+#[derive(Debug, Clone)]
+pub struct ChatTemplate {
+    kind: TemplateKind,
+    bos_token: String,
+    eos_token: String,
+}
+
+impl ChatTemplate {
+    pub fn builtin(template: BuiltinTemplate) -> Self {
+        Self {
+            kind: TemplateKind::Builtin(template),
+            bos_token: "<s>".to_string(),
+            eos_token: "</s>".to_string(),
+        }
+    }
+
+    /// parse a jinja chat template like the `tokenizer.chat_template` in the GGUF files.
+    #[cfg(feature = "chat-template")]
+    pub fn jinja(source: &str) -> Result<Self> {
+        Ok(Self {
+            kind: TemplateKind::Jinja(jinja::Template::parse(source)?),
+            bos_token: "<s>".to_string(),
+            eos_token: "</s>".to_string(),
+        })
+    }
+
+    /// the texts of the bos and eos tokens, which are the `bos_token` and `eos_token`
+    /// variables in the jinja templates.
+    pub fn with_special_tokens(mut self, bos_token: &str, eos_token: &str) -> Self {
+        self.bos_token = bos_token.to_string();
+        self.eos_token = eos_token.to_string();
+        self
+    }
+
+    /// the chat template stored in the model, falls back to a built-in format when the model
+    /// has none, it can not be parsed, or the `chat-template` feature is off. the built-in
+    /// format is guessed from the stored template first, then from the architecture.
+    pub fn from_model(conf: &Llama2Config, tokenizer: &BpeTokenizer) -> Self {
+        let source = conf.chat_template.as_deref();
+        let builtin = source
+            .and_then(BuiltinTemplate::detect)
+            .unwrap_or_else(|| BuiltinTemplate::for_architecture(conf.architecture));
+        #[allow(unused_mut)]
+        let mut template = Self::builtin(builtin);
+        #[cfg(feature = "chat-template")]
+        if let Some(Ok(jinja)) = source.map(Self::jinja) {
+            template = jinja;
+        }
+        template.with_special_tokens(
+            &tokenizer.token(tokenizer.bos_token()),
+            &tokenizer.token(tokenizer.eos_token()),
+        )
+    }
+
+    /// true if the template is a built-in format rather than a jinja template.
+    pub fn is_builtin(&self) -> bool {
+        matches!(self.kind, TemplateKind::Builtin(_))
+    }
+
+    /// format the messages into a prompt, with the header of the assistant's reply appended if
+    /// add_generation_prompt. the tools are a json array of the function definitions, they're
+    /// only passed to the jinja templates.
+    ///
+    /// the leading bos token is stripped from the prompt, as the runner prepends it on
+    /// encoding.
+    pub fn render(
+        &self,
+        messages: &[ChatMessage],
+        tools: Option<&serde_json::Value>,
+        add_generation_prompt: bool,
+    ) -> Result<String> {
+        let prompt = match &self.kind {
+            TemplateKind::Builtin(t) => {
+                let _ = tools;
+                t.render(
+                    messages,
+                    add_generation_prompt,
+                    &self.bos_token,
+                    &self.eos_token,
+                )
+            }
+            #[cfg(feature = "chat-template")]
+            TemplateKind::Jinja(t) => {
+                t.render(self.jinja_context(messages, tools, add_generation_prompt))?
+            }
+        };
+        Ok(match prompt.strip_prefix(self.bos_token.as_str()) {
+            Some(rest) if !self.bos_token.is_empty() => rest.to_string(),
+            _ => prompt,
+        })
+    }
+
+    #[cfg(feature = "chat-template")]
+    fn jinja_context(
+        &self,
+        messages: &[ChatMessage],
+        tools: Option<&serde_json::Value>,
+        add_generation_prompt: bool,
+    ) -> Vec<(String, jinja::Value)> {
+        use jinja::Value;
+
+        let messages = messages
+            .iter()
+            .map(|m| {
+                let mut fields = vec![
+                    ("role".to_string(), Value::Str(m.role.clone())),
+                    ("content".to_string(), Value::Str(m.content.clone())),
+                ];
+                if !m.tool_calls.is_empty() {
+                    let calls = m.tool_calls.iter().map(Value::from_json).collect();
+                    fields.push(("tool_calls".to_string(), Value::List(calls)));
+                }
+                Value::Map(fields)
+            })
+            .collect();
+        let mut context = vec![
+            ("messages".to_string(), Value::List(messages)),
+            (
+                "add_generation_prompt".to_string(),
+                Value::Bool(add_generation_prompt),
+            ),
+            ("bos_token".to_string(), Value::Str(self.bos_token.clone())),
+            ("eos_token".to_string(), Value::Str(self.eos_token.clone())),
+        ];
+        if let Some(tools) = tools {
+            context.push(("tools".to_string(), Value::from_json(tools)));
+        }
+        context
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::gguf::GGUFFileLoader;
+
+    use super::*;
+    use crate::model::CpuLlama2Model;
+    use crate::model::Llama2Model;
+
+    fn conversation() -> Vec<ChatMessage> {
+        vec![
+            ChatMessage::system("You are a helpful assistant."),
+            ChatMessage::user("Hi!"),
+            ChatMessage::assistant("Hello, how can I help?"),
+            ChatMessage::user("Tell me a story."),
+        ]
+    }
+
+    #[test]
+    fn test_builtin_templates() -> Result<()> {
+        let messages = conversation();
+        let chatml =
+            ChatTemplate::builtin(BuiltinTemplate::ChatML).render(&messages, None, true)?;
+        assert_eq!(
+            chatml,
+            "<|im_start|>system\nYou are a helpful assistant.<|im_end|>\n<|im_start|>user\nHi!<|im_end|>\n<|im_start|>assistant\nHello, how can I help?<|im_end|>\n<|im_start|>user\nTell me a story.<|im_end|>\n<|im_start|>assistant\n"
+        );
+        let llama2 =
+            ChatTemplate::builtin(BuiltinTemplate::Llama2).render(&messages, None, true)?;
+        assert_eq!(
+            llama2,
+            "[INST] <<SYS>>\nYou are a helpful assistant.\n<</SYS>>\n\nHi! [/INST] Hello, how can I help? </s><s>[INST] Tell me a story. [/INST]"
+        );
+        let gemma = ChatTemplate::builtin(BuiltinTemplate::Gemma)
+            .with_special_tokens("<bos>", "<eos>")
+            .render(&messages[..2], None, true)?;
+        assert_eq!(
+            gemma,
+            "<start_of_turn>user\nYou are a helpful assistant.\n\nHi!<end_of_turn>\n<start_of_turn>model\n"
+        );
+        assert_eq!(
+            BuiltinTemplate::detect("{{ '<|start_header_id|>' + message['role'] }}"),
+            Some(BuiltinTemplate::Llama3)
+        );
+        assert_eq!(BuiltinTemplate::detect("{{ messages }}"), None);
+        Ok(())
+    }
+
+    #[cfg(feature = "chat-template")]
+    #[test]
+    fn test_jinja_templates_match_builtins() -> Result<()> {
+        // the templates in the tokenizer_config.json of the models, as stored in the GGUF files
+        let chatml = "{% for message in messages %}{{'<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>' + '\n'}}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}";
+        let llama3 = "{% set loop_messages = messages %}{% for message in loop_messages %}{% set content = '<|start_header_id|>' + message['role'] + '<|end_header_id|>\n\n'+ message['content'] | trim + '<|eot_id|>' %}{% if loop.index0 == 0 %}{% set content = bos_token + content %}{% endif %}{{ content }}{% endfor %}{% if add_generation_prompt %}{{ '<|start_header_id|>assistant<|end_header_id|>\n\n' }}{% endif %}";
+        let llama2 = "{% if messages[0]['role'] == 'system' %}{% set loop_messages = messages[1:] %}{% set system_message = messages[0]['content'] %}{% else %}{% set loop_messages = messages %}{% set system_message = false %}{% endif %}{% for message in loop_messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if loop.index0 == 0 and system_message != false %}{% set content = '<<SYS>>\\n' + system_message + '\\n<</SYS>>\\n\\n' + message['content'] %}{% else %}{% set content = message['content'] %}{% endif %}{% if message['role'] == 'user' %}{{ bos_token + '[INST] ' + content.strip() + ' [/INST]' }}{% elif message['role'] == 'assistant' %}{{ ' '  + content.strip() + ' ' + eos_token }}{% endif %}{% endfor %}";
+
+        let messages = conversation();
+        for (source, builtin) in [
+            (chatml, BuiltinTemplate::ChatML),
+            (llama3, BuiltinTemplate::Llama3),
+            (llama2, BuiltinTemplate::Llama2),
+        ] {
+            assert_eq!(BuiltinTemplate::detect(source), Some(builtin));
+            let (bos, eos) = match builtin {
+                BuiltinTemplate::Llama3 => ("<|begin_of_text|>", "<|end_of_text|>"),
+                _ => ("<s>", "</s>"),
+            };
+            let jinja = ChatTemplate::jinja(source)?.with_special_tokens(bos, eos);
+            let builtin = ChatTemplate::builtin(builtin).with_special_tokens(bos, eos);
+            assert_eq!(
+                jinja.render(&messages, None, true)?,
+                builtin.render(&messages, None, true)?
+            );
+        }
+
+        // the roles do not alternate
+        let template = ChatTemplate::jinja(llama2)?;
+        let err = template
+            .render(
+                &[ChatMessage::user("a"), ChatMessage::user("b")],
+                None,
+                true,
+            )
+            .unwrap_err();
+        assert!(err.message.contains("roles must alternate"), "{}", err);
+
+        // the tools and the tool calls are passed as json
+        let tools_template = "{% if tools %}{% for tool in tools %}{{ tool | tojson }}\n{% endfor %}{% endif %}{% for m in messages %}{{ m.role }}: {{ m.content }}{% if m.tool_calls is defined %}{{ m.tool_calls[0].function.name }}{% endif %}{{ '\\n' }}{% endfor %}";
+        let tools = serde_json::json!([{"type": "function", "function": {"name": "get_weather"}}]);
+        let call = serde_json::json!({"function": {"name": "get_weather", "arguments": {}}});
+        let messages = [
+            ChatMessage::user("weather?"),
+            ChatMessage::assistant("").with_tool_calls(vec![call]),
+        ];
+        let prompt = ChatTemplate::jinja(tools_template)?.render(&messages, Some(&tools), false)?;
+        assert_eq!(
+            prompt,
+            "{\"type\": \"function\", \"function\": {\"name\": \"get_weather\"}}\nuser: weather?\nassistant: get_weather\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_chat_template_from_model() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut conf = lm.conf.clone();
+        assert_eq!(conf.chat_template, None);
+
+        // no template in the file, falls back to the format of the architecture
+        let template = ChatTemplate::from_model(&conf, &lm.tokenizer);
+        assert!(template.is_builtin());
+        let prompt = template.render(&[ChatMessage::user("Hi")], None, true)?;
+        assert_eq!(prompt, "[INST] Hi [/INST]");
+
+        // a broken template falls back to the format it looks like
+        conf.chat_template = Some("{% for m in messages %}<|im_start|>{{ m.role }".to_string());
+        let template = ChatTemplate::from_model(&conf, &(&lm).tokenizer());
+        assert!(template.is_builtin());
+        let prompt = template.render(&[ChatMessage::user("Hi")], None, false)?;
+        assert_eq!(prompt, "<|im_start|>user\nHi<|im_end|>\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+/// how the hidden states of the tokens are reduced into a single vector.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum Pooling {
+    /// the hidden state of the last token, which has attended to all the others.
+    #[default]
+    Last,
+    /// the average of the hidden states of all the tokens.
+    Mean,
+    /// the hidden state of the first token, like the [CLS] token of the BERT models. on a
+    /// causal model it has attended only to itself.
+    Cls,
+}
+
+impl Pooling {
+    /// the `{arch}.pooling_type` in the gguf metadata, in the llama_pooling_type enum of
+    /// llama.cpp. none and rank have no single vector to pool.
+    pub fn from_gguf(v: u32) -> Option<Self> {
+        match v {
+            1 => Some(Pooling::Mean),
+            2 => Some(Pooling::Cls),
+            3 => Some(Pooling::Last),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EmbeddingOptions {
+    /// None takes the pooling type in the model metadata, or `Pooling::Last` if it's absent.
+    pub pooling: Option<Pooling>,
+    /// scale the final embedding to unit length, so the dot product is the cosine similarity.
+    pub normalize: bool,
+    /// also keep the pooled hidden state after every layer, for probing.
+    pub capture_layers: bool,
+}
+
+impl EmbeddingOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_pooling(mut self, pooling: Pooling) -> Self {
+        self.pooling = Some(pooling);
+        self
+    }
+
+    pub fn with_normalize(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    pub fn with_capture_layers(mut self, capture_layers: bool) -> Self {
+        self.capture_layers = capture_layers;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Embeddings {
+    /// the pooled hidden state after the final norm, (embed_dim, )
+    pub embedding: Vec<f32>,
+    /// the pooled hidden state after each layer, before the final norm. it's empty unless
+    /// `capture_layers` is set, and the states are never normalized.
+    pub layers: Vec<Vec<f32>>,
+}
+
+/// reduce the hidden states of (n_tokens, embed_dim) into (embed_dim, ).
+pub(crate) fn pool(hidden: &[f32], embed_dim: usize, pooling: Pooling) -> Vec<f32> {
+    let n_tokens = hidden.len() / embed_dim;
+    match pooling {
+        Pooling::Last => hidden[(n_tokens - 1) * embed_dim..n_tokens * embed_dim].to_vec(),
+        Pooling::Cls => hidden[..embed_dim].to_vec(),
+        Pooling::Mean => {
+            let mut out = vec![0.0; embed_dim];
+            for row in hidden.chunks_exact(embed_dim) {
+                out.iter_mut().zip(row).for_each(|(o, x)| *o += x);
+            }
+            out.iter_mut().for_each(|o| *o /= n_tokens as f32);
+            out
+        }
+    }
+}
+
+/// scale the vector to unit length, a zero vector is left as it is.
+pub(crate) fn l2_normalize(v: &mut [f32]) {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_and_normalize() {
+        let hidden = [1.0, 2.0, 3.0, 6.0];
+        assert_eq!(pool(&hidden, 2, Pooling::Last), vec![3.0, 6.0]);
+        assert_eq!(pool(&hidden, 2, Pooling::Mean), vec![2.0, 4.0]);
+        assert_eq!(pool(&hidden, 2, Pooling::Cls), vec![1.0, 2.0]);
+        assert_eq!(Pooling::from_gguf(2), Some(Pooling::Cls));
+        assert_eq!(Pooling::from_gguf(0), None);
+
+        let mut v = vec![3.0, 4.0];
+        l2_normalize(&mut v);
+        assert_eq!(v, vec![0.6, 0.8]);
+
+        let mut zero = vec![0.0, 0.0];
+        l2_normalize(&mut zero);
+        assert_eq!(zero, vec![0.0, 0.0]);
+    }
+}
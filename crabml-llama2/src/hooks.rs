@@ -0,0 +1,124 @@
+use std::ops::Range;
+
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+
+/// a hook into the forward pass of a runner, called after every layer with the positions
+/// of the tokens in the pass. on a batched step it's called on every sequence of the batch.
+pub trait LayerHook {
+    /// an additive bias of (n_tokens, embed_dim) on the hidden states after the layer, for
+    /// the tokens at the positions `pos..pos + n_tokens`, None leaves them as is.
+    fn layer_bias(
+        &mut self,
+        layer: usize,
+        pos: usize,
+        n_tokens: usize,
+        embed_dim: usize,
+    ) -> Result<Option<Vec<f32>>>;
+}
+
+#[derive(Debug, Clone)]
+enum BiasRows {
+    /// the same row on every position in the range, like a control vector.
+    Repeat(Range<usize>, Vec<f32>),
+    /// the i-th row on the position start + i, like the soft prompt of prompt tuning.
+    Sequence(usize, Vec<Vec<f32>>),
+}
+
+impl BiasRows {
+    fn row_at(&self, pos: usize) -> Option<&[f32]> {
+        match self {
+            BiasRows::Repeat(range, row) => range.contains(&pos).then_some(row.as_slice()),
+            BiasRows::Sequence(start, rows) => pos
+                .checked_sub(*start)
+                .and_then(|i| rows.get(i))
+                .map(|row| row.as_slice()),
+        }
+    }
+}
+
+/// the biases added to the hidden states after some layers on some positions, a
+/// generalization of the control vectors.
+#[derive(Debug, Clone, Default)]
+pub struct HiddenBias {
+    entries: Vec<(usize, BiasRows)>,
+}
+
+impl HiddenBias {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// add the vector to the hidden state after the layer on every position in the range,
+    /// `0..usize::MAX` steers the whole generation.
+    pub fn with_vector(mut self, layer: usize, positions: Range<usize>, vector: Vec<f32>) -> Self {
+        self.entries
+            .push((layer, BiasRows::Repeat(positions, vector)));
+        self
+    }
+
+    /// add the i-th vector to the hidden state after the layer on the position start + i.
+    pub fn with_vectors(mut self, layer: usize, start: usize, vectors: Vec<Vec<f32>>) -> Self {
+        self.entries
+            .push((layer, BiasRows::Sequence(start, vectors)));
+        self
+    }
+}
+
+impl LayerHook for HiddenBias {
+    fn layer_bias(
+        &mut self,
+        layer: usize,
+        pos: usize,
+        n_tokens: usize,
+        embed_dim: usize,
+    ) -> Result<Option<Vec<f32>>> {
+        let mut bias: Option<Vec<f32>> = None;
+        for (_, rows) in self.entries.iter().filter(|(l, _)| *l == layer) {
+            for i in 0..n_tokens {
+                let Some(row) = rows.row_at(pos + i) else {
+                    continue;
+                };
+                if row.len() != embed_dim {
+                    return Err((
+                        ErrorKind::BadInput,
+                        format!(
+                            "the bias on the layer {} has {} dims, expected {}",
+                            layer,
+                            row.len(),
+                            embed_dim
+                        ),
+                    )
+                        .into());
+                }
+                let bias = bias.get_or_insert_with(|| vec![0.0; n_tokens * embed_dim]);
+                bias[i * embed_dim..(i + 1) * embed_dim]
+                    .iter_mut()
+                    .zip(row)
+                    .for_each(|(b, r)| *b += r);
+            }
+        }
+        Ok(bias)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hidden_bias() -> Result<()> {
+        let mut bias = HiddenBias::new()
+            .with_vector(1, 2..4, vec![1.0, 2.0])
+            .with_vectors(1, 3, vec![vec![10.0, 10.0], vec![20.0, 20.0]]);
+
+        // the positions 1..5 on the layer 1
+        let got = bias.layer_bias(1, 1, 4, 2)?.unwrap();
+        assert_eq!(got, vec![0.0, 0.0, 1.0, 2.0, 11.0, 12.0, 20.0, 20.0]);
+
+        assert!(bias.layer_bias(0, 1, 4, 2)?.is_none());
+        assert!(bias.layer_bias(1, 5, 3, 2)?.is_none());
+        assert!(bias.layer_bias(1, 2, 1, 3).is_err());
+        Ok(())
+    }
+}
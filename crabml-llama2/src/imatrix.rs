@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+
+use crabml::backends::cpu::ImportanceMatrix;
+
+#[derive(Debug, Clone)]
+pub struct ImatrixOptions {
+    /// the tokens of each chunk of the calibration text, the kv cache is reset between the
+    /// chunks. None takes the capacity of the kv cache.
+    pub n_ctx: Option<usize>,
+    /// the max tokens of a forward pass. None takes the whole chunk, or the max batch in the
+    /// memory budget.
+    pub n_batch: Option<usize>,
+    /// start every chunk with the bos token.
+    pub add_bos: bool,
+}
+
+impl Default for ImatrixOptions {
+    fn default() -> Self {
+        Self {
+            n_ctx: None,
+            n_batch: None,
+            add_bos: true,
+        }
+    }
+}
+
+impl ImatrixOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_n_ctx(mut self, n_ctx: usize) -> Self {
+        self.n_ctx = Some(n_ctx);
+        self
+    }
+
+    pub fn with_n_batch(mut self, n_batch: usize) -> Self {
+        self.n_batch = Some(n_batch);
+        self
+    }
+
+    pub fn with_add_bos(mut self, add_bos: bool) -> Self {
+        self.add_bos = add_bos;
+        self
+    }
+}
+
+/// sums up the squared activations of the input columns of every weight over the tokens
+/// of the forward passes.
+#[derive(Debug, Default)]
+pub(crate) struct ImatrixCollector {
+    // (the sums of each column, the tokens summed)
+    stats: BTreeMap<String, (Vec<f64>, usize)>,
+}
+
+impl ImatrixCollector {
+    /// x is the input of the weight in (n_tokens, n_cols).
+    pub fn add(&mut self, name: String, x: &[f32], n_cols: usize) {
+        let (sums, n_tokens) = self
+            .stats
+            .entry(name)
+            .or_insert_with(|| (vec![0.0; n_cols], 0));
+        for row in x.chunks(n_cols) {
+            sums.iter_mut()
+                .zip(row)
+                .for_each(|(s, v)| *s += (*v as f64) * (*v as f64));
+            *n_tokens += 1;
+        }
+    }
+
+    /// like llama.cpp, the values are the mean over the tokens scaled by ncall, which is
+    /// the number of the chunks, so the readers get the mean back by dividing by ncall.
+    pub fn finish(self, n_chunks: usize) -> ImportanceMatrix {
+        let mut imatrix = ImportanceMatrix::new();
+        let ncall = n_chunks as i32;
+        for (name, (sums, n_tokens)) in self.stats {
+            let scale = ncall as f64 / n_tokens.max(1) as f64;
+            let values = sums.iter().map(|s| (s * scale) as f32).collect();
+            imatrix.insert(&name, ncall, values);
+        }
+        imatrix.last_call = ncall;
+        imatrix
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_imatrix_collector() {
+        let mut collector = ImatrixCollector::default();
+        collector.add("blk.0.attn_q.weight".to_string(), &[1.0, 2.0, 3.0, 0.0], 2);
+        collector.add("blk.0.attn_q.weight".to_string(), &[1.0, 2.0], 2);
+        let imatrix = collector.finish(2);
+        assert_eq!(imatrix.last_call, 2);
+        // the means over the 3 tokens
+        assert_eq!(
+            imatrix.get("blk.0.attn_q.weight"),
+            Some(vec![11.0 / 3.0, 8.0 / 3.0])
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use std::fmt;
+
+use crate::session::Session;
+
+/// the checksums of the kv cache at every layer and position, to find where two runs or two
+/// steps of a run diverge, like a bug in the eviction, fork or copy of the cache, which
+/// only shows up as degraded text many tokens later. take it with
+/// `Llama2Runner::kv_snapshot()` or from a saved `Session`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KvCacheSnapshot {
+    pub pos: usize,
+    // (layer, pos) => the checksum of the keys and the values of all the kv heads
+    keys: Vec<Vec<u64>>,
+    values: Vec<Vec<u64>>,
+}
+
+/// which of the cache differ at a layer and position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KvMismatch {
+    pub layer: usize,
+    pub pos: usize,
+    pub key: bool,
+    pub value: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct KvCacheDiff {
+    /// the mismatches at the positions filled in both snapshots, by position then layer.
+    pub mismatches: Vec<KvMismatch>,
+    /// the positions filled in the snapshots, they're compared up to the shorter one.
+    pub pos: (usize, usize),
+}
+
+impl KvCacheSnapshot {
+    pub fn from_session(session: &Session) -> Self {
+        let checksums = |cache: &Vec<Vec<f32>>| {
+            cache
+                .iter()
+                .map(|data| {
+                    (0..session.pos)
+                        .map(|p| position_checksum(data, session.pos, session.head_size, p))
+                        .collect()
+                })
+                .collect()
+        };
+        Self {
+            pos: session.pos,
+            keys: checksums(&session.key_cache),
+            values: checksums(&session.value_cache),
+        }
+    }
+
+    pub fn n_layers(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// the checksums of the keys and the values at a layer and position.
+    pub fn checksum(&self, layer: usize, pos: usize) -> Option<(u64, u64)> {
+        let key = *self.keys.get(layer)?.get(pos)?;
+        Some((key, self.values[layer][pos]))
+    }
+
+    pub fn diff(&self, other: &KvCacheSnapshot) -> KvCacheDiff {
+        let mut mismatches = vec![];
+        let n_layers = self.n_layers().min(other.n_layers());
+        for pos in 0..self.pos.min(other.pos) {
+            for layer in 0..n_layers {
+                let key = self.keys[layer][pos] != other.keys[layer][pos];
+                let value = self.values[layer][pos] != other.values[layer][pos];
+                if key || value {
+                    mismatches.push(KvMismatch {
+                        layer,
+                        pos,
+                        key,
+                        value,
+                    });
+                }
+            }
+        }
+        KvCacheDiff {
+            mismatches,
+            pos: (self.pos, other.pos),
+        }
+    }
+}
+
+impl KvCacheDiff {
+    /// true if the positions filled in both are the same, a snapshot of an earlier step
+    /// of the same run is a prefix of the later one.
+    pub fn is_prefix(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+
+    pub fn is_same(&self) -> bool {
+        self.is_prefix() && self.pos.0 == self.pos.1
+    }
+
+    /// the first position differing at any layer.
+    pub fn first_divergence(&self) -> Option<usize> {
+        self.mismatches.first().map(|m| m.pos)
+    }
+}
+
+impl fmt::Display for KvCacheDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "positions: {} vs {}", self.pos.0, self.pos.1)?;
+        if self.mismatches.is_empty() {
+            return writeln!(f, "no mismatch in the common positions");
+        }
+        writeln!(f, "{} mismatches:", self.mismatches.len())?;
+        for m in self.mismatches.iter() {
+            let which = match (m.key, m.value) {
+                (true, true) => "k v",
+                (true, false) => "k",
+                _ => "v",
+            };
+            writeln!(f, "  pos {:>5} layer {:>3}: {}", m.pos, m.layer, which)?;
+        }
+        Ok(())
+    }
+}
+
+// fnv-1a over the bits of the position in every head of (n_kv_heads, n_pos, head_size)
+fn position_checksum(data: &[f32], n_pos: usize, head_size: usize, pos: usize) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for head in data.chunks(n_pos * head_size) {
+        for v in &head[pos * head_size..(pos + 1) * head_size] {
+            for b in v.to_bits().to_le_bytes() {
+                hash ^= b as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::error::Result;
+    use crabml::gguf::GGMLType;
+    use crabml::gguf::GGUFFileLoader;
+    use crabml::tensor::TensorMetrics;
+
+    use crate::llama2::Llama2Runner;
+    use crate::CpuLlama2Model;
+
+    #[test]
+    fn test_kv_cache_diff() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 32, GGMLType::F32)?;
+
+        // an earlier step is a prefix of the later one
+        runner.forward_batch(&[1, 365, 931], 0)?;
+        let a = runner.kv_snapshot()?;
+        assert_eq!(a.n_layers(), lm.conf.n_layers);
+        runner.forward(263, 3)?;
+        let b = runner.kv_snapshot()?;
+        let diff = a.diff(&b);
+        assert!(diff.is_prefix());
+        assert!(!diff.is_same());
+        assert_eq!(diff.pos, (3, 4));
+
+        // the bos forwarded alone fills the same first position
+        runner.reset_kv_cache()?;
+        for (pos, token) in [1, 365, 931].iter().enumerate() {
+            runner.forward(*token, pos)?;
+        }
+        assert_eq!(runner.kv_snapshot()?.checksum(0, 0), a.checksum(0, 0));
+
+        // a different token diverges at its position on every layer
+        runner.reset_kv_cache()?;
+        runner.forward_batch(&[1, 365, 1200], 0)?;
+        let diff = a.diff(&runner.kv_snapshot()?);
+        assert_eq!(diff.first_divergence(), Some(2));
+        assert!(diff.mismatches.iter().all(|m| m.pos == 2 && m.key));
+        assert_eq!(diff.mismatches.len(), lm.conf.n_layers);
+        assert!(diff.to_string().contains("pos     2 layer   0: k v"));
+        Ok(())
+    }
+}
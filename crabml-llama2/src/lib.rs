@@ -1,8 +1,84 @@
+pub mod batch;
+pub mod beam;
+pub mod chat;
+pub mod embeddings;
+pub mod hooks;
+pub mod imatrix;
+pub mod kv_diff;
 pub mod llama2;
+pub mod lora;
+pub mod medusa;
+pub mod memory;
 pub mod model;
+pub mod perplexity;
 pub mod sampler;
+pub mod session;
+pub mod similarity;
+pub mod speculative;
+pub mod startup;
+pub mod stop;
+pub mod stream;
+#[cfg(test)]
+mod testing;
+pub mod truncation;
 
+pub use batch::BatchOutput;
+pub use batch::BatchScheduler;
+pub use batch::RequestId;
+pub use beam::BeamHypothesis;
+pub use beam::BeamSearch;
+pub use beam::BeamSearchOptions;
+pub use chat::BuiltinTemplate;
+pub use chat::ChatMessage;
+pub use chat::ChatTemplate;
+pub use embeddings::EmbeddingOptions;
+pub use embeddings::Embeddings;
+pub use embeddings::Pooling;
+pub use hooks::HiddenBias;
+pub use hooks::LayerHook;
+pub use imatrix::ImatrixOptions;
+pub use kv_diff::KvCacheDiff;
+pub use kv_diff::KvCacheSnapshot;
+pub use kv_diff::KvMismatch;
+pub use lora::LoraAdapter;
+pub use lora::LoraMode;
+pub use lora::LoraTarget;
+pub use medusa::MedusaDecoder;
+pub use medusa::MedusaHead;
+pub use medusa::MedusaHeads;
+pub use medusa::MedusaOptions;
+pub use memory::MemoryOptions;
 pub use model::CpuLlama2Model;
 pub use model::Llama2Model;
+pub use model::ModelLoadOptions;
+pub use model::TokenizerReload;
+#[cfg(not(target_os = "wasi"))]
 pub use model::WgpuLlama2Model;
+pub use perplexity::Perplexity;
+pub use perplexity::PerplexityOptions;
+#[allow(deprecated)]
 pub use sampler::Llama2Sampler;
+pub use session::Session;
+pub use session::SessionShape;
+pub use session::SharedSession;
+pub use similarity::EmbeddingIndex;
+pub use similarity::Neighbor;
+pub use speculative::SpeculativeDecoder;
+pub use speculative::SpeculativeStats;
+pub use speculative::VocabMapping;
+pub use startup::load_and_prefill;
+pub use startup::FirstToken;
+pub use startup::StartupOptions;
+pub use startup::StartupPrompt;
+pub use stop::BalancedDelimiters;
+pub use stop::StopCriteria;
+pub use stop::StopRegex;
+pub use stop::StopStrings;
+pub use stream::CancellationToken;
+pub use stream::FinishReason;
+pub use stream::GeneratedToken;
+pub use stream::GenerationOptions;
+pub use stream::GenerationStream;
+pub use stream::StepMetrics;
+pub use truncation::TruncationOptions;
+pub use truncation::TruncationPolicy;
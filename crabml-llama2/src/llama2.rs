@@ -1,20 +1,52 @@
+use std::cell::RefCell;
 use std::rc::Rc;
 use std::vec;
 
+use crabml::backends::cpu::CpuTensor;
+use crabml::backends::cpu::ImportanceMatrix;
+#[cfg(not(target_os = "wasi"))]
+use crabml::backends::wgpu::WgpuSampler;
+#[cfg(not(target_os = "wasi"))]
+use crabml::backends::wgpu::WgpuTensor;
 use crabml::error::Error;
 use crabml::error::ErrorKind;
 use crabml::error::Result;
 use crabml::gguf::GGMLType;
+use crabml::sampler::SamplerChain;
+use crabml::tensor::MatmulEpilogue;
 use crabml::tensor::RopeMode;
+use crabml::tensor::TaskTag;
 use crabml::tensor::Tensor;
 use crabml::tensor::TensorMetrics;
 use crabml::tokenizer::BpeTokenizer;
 
+use crate::batch::BatchSegment;
+use crate::batch::KvSlot;
+use crate::embeddings;
+use crate::embeddings::EmbeddingOptions;
+use crate::embeddings::Embeddings;
+use crate::hooks::LayerHook;
+use crate::imatrix::ImatrixCollector;
+use crate::imatrix::ImatrixOptions;
+use crate::kv_diff::KvCacheSnapshot;
+use crate::lora::LoraTarget;
+use crate::memory;
+use crate::memory::MemoryOptions;
 use crate::model::Llama2Config;
 use crate::model::Llama2Model;
 use crate::model::Llama2Weights;
 use crate::model::ModelArchitecture;
-use crate::sampler::Llama2Sampler;
+use crate::perplexity;
+use crate::perplexity::Perplexity;
+use crate::perplexity::PerplexityOptions;
+use crate::session::Session;
+use crate::session::SessionShape;
+use crate::stream::GenerationOptions;
+use crate::stream::GenerationStream;
+use crate::stream::SampledToken;
+use crate::stream::StepMetrics;
+use crate::truncation;
+use crate::truncation::TruncationOptions;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Activation {
@@ -22,6 +54,59 @@ pub enum Activation {
     GeLU,
 }
 
+/// picks the next token from the logits of a forward pass. `SamplerChain` samples on the
+/// host from the exported logits, while `WgpuSampler` samples on the gpu and only reads back
+/// the token id.
+pub trait TokenSampler<T: Tensor> {
+    /// buf is a scratch buffer of vocab_size to export the logits into.
+    fn sample(&mut self, logits: &T, buf: &mut [f32]) -> Result<usize>;
+
+    /// called on the prompt tokens, the sampled tokens are accepted by `sample()`.
+    fn accept(&mut self, token: usize);
+
+    /// the log probability of the last sampled token, if the sampler keeps it.
+    fn last_logprob(&self) -> Option<f32> {
+        None
+    }
+
+    /// the seed the random numbers are drawn from, to reproduce the sequence.
+    fn seed(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl<T: Tensor> TokenSampler<T> for SamplerChain {
+    fn sample(&mut self, logits: &T, buf: &mut [f32]) -> Result<usize> {
+        logits.export(buf)?;
+        SamplerChain::sample(self, buf)
+    }
+
+    fn accept(&mut self, token: usize) {
+        SamplerChain::accept(self, token)
+    }
+
+    fn last_logprob(&self) -> Option<f32> {
+        SamplerChain::last_logprob(self)
+    }
+
+    fn seed(&self) -> Option<u64> {
+        Some(SamplerChain::seed(self))
+    }
+}
+
+#[cfg(not(target_os = "wasi"))]
+impl TokenSampler<WgpuTensor> for WgpuSampler {
+    fn sample(&mut self, logits: &WgpuTensor, _buf: &mut [f32]) -> Result<usize> {
+        WgpuSampler::sample(self, logits)
+    }
+
+    fn accept(&mut self, _token: usize) {}
+
+    fn seed(&self) -> Option<u64> {
+        Some(WgpuSampler::seed(self))
+    }
+}
+
 pub struct Llama2Runner<T: Tensor> {
     conf: Llama2Config,
     weights: Rc<Llama2Weights<T>>,
@@ -30,7 +115,28 @@ pub struct Llama2Runner<T: Tensor> {
     logits: Vec<f32>,            // output logits (vocab_size, )
     key_cache: Vec<Option<T>>,   // (layer, seq_len, kv_dim)
     value_cache: Vec<Option<T>>, // (layer, seq_len, kv_dim)
+    seq_len: usize,              // the capacity of the kv cache
+    kv_cache_dtype: GGMLType,
     metrics: TensorMetrics,
+    fingerprint: u64,
+    captured_layers: Option<Vec<Vec<f32>>>, // (layer, n_batch * embed_dim) when capturing
+    truncation: TruncationOptions,
+    memory: MemoryOptions,
+    max_batch: Option<usize>, // the max tokens of a forward pass within the memory budget
+    segments: Option<Vec<BatchSegment<T>>>, // the sequences of a batch in forward_segments()
+    positions: Option<Vec<usize>>, // the rope positions of the tokens in forward_positions()
+    tree_mask: Option<Vec<bool>>, // the tree attention mask of the tokens in forward_tree()
+    tree: Option<TokenTree<T>>, // the kv of the tree in forward_tree() until keep_tree_nodes()
+    hooks: Vec<Box<dyn LayerHook>>,
+    imatrix: Option<RefCell<ImatrixCollector>>, /* the activations of the weights in importance_matrix() */
+}
+
+// the tokens of a tree forwarded in one pass, the k and v of every node are kept to move the
+// nodes on the accepted path together in the kv cache.
+struct TokenTree<T: Tensor> {
+    start: usize, // the kv cache len before the tree
+    n_nodes: usize,
+    kv: Vec<(T, T)>, // (layer, the k and v of the nodes in (n_nodes, kv_dim))
 }
 
 impl<'a, T: Tensor> Llama2Runner<T> {
@@ -38,76 +144,311 @@ impl<'a, T: Tensor> Llama2Runner<T> {
         model: impl Llama2Model<T = T>,
         metrics: TensorMetrics,
         seq_len: usize,
-        use_f16_kv_cache: bool,
+        kv_cache_dtype: GGMLType,
     ) -> Result<Self> {
-        let kv_cache_dtype = if use_f16_kv_cache {
-            GGMLType::F16
-        } else {
-            GGMLType::F32
-        };
+        Self::new_with_memory(
+            model,
+            metrics,
+            seq_len,
+            kv_cache_dtype,
+            MemoryOptions::default(),
+        )
+    }
 
+    /// like `new()`, but keeps the kv cache and the scratch within the memory budget. when
+    /// the kv cache does not fit, it fails with an `ErrorKind::OutOfMemory` error carrying
+    /// the bytes requested, or falls back to a q8_0 kv cache if the options allow.
+    pub fn new_with_memory(
+        model: impl Llama2Model<T = T>,
+        metrics: TensorMetrics,
+        seq_len: usize,
+        kv_cache_dtype: GGMLType,
+        memory: MemoryOptions,
+    ) -> Result<Self> {
         let conf = &model.conf();
+        if kv_cache_dtype == GGMLType::Q8_0 && conf.head_size() % 32 != 0 {
+            return Err(Error {
+                kind: ErrorKind::BadInput,
+                message: format!(
+                    "q8_0 kv cache requires the head size to be a multiple of 32, got {}",
+                    conf.head_size()
+                ),
+                cause: None,
+            });
+        }
+
         let device = model.device().clone();
         let weights = model.weights();
         let tokenizer = model.tokenizer();
+        let fingerprint = model.fingerprint();
         let logits = vec![0.0; conf.vocab_size];
-        let key_cache = (0..conf.n_layers)
-            .map(|_| {
-                T::alloc(
-                    &[conf.n_kv_heads, seq_len, conf.head_size()],
-                    kv_cache_dtype,
-                    device.clone(),
-                )
-                .map(|t| t.resize(1, 0).unwrap())
-                .map(Some)
-            })
-            .collect::<Result<Vec<_>>>()?;
-        let value_cache = (0..conf.n_layers)
-            .map(|_| {
-                T::alloc(
-                    &[conf.n_kv_heads, seq_len, conf.head_size()],
-                    kv_cache_dtype,
-                    device.clone(),
-                )
-                .map(|t| t.resize(1, 0).unwrap())
-                .map(Some)
-            })
-            .collect::<Result<Vec<_>>>()?;
+        let can_fall_back = memory.allow_q8_0_kv_cache
+            && kv_cache_dtype != GGMLType::Q8_0
+            && conf.head_size() % 32 == 0;
+        let mut kv_cache_dtype = kv_cache_dtype;
+        if let Err(err) = Self::check_kv_cache_budget(conf, seq_len, kv_cache_dtype, &memory) {
+            if !can_fall_back {
+                return Err(err);
+            }
+            Self::check_kv_cache_budget(conf, seq_len, GGMLType::Q8_0, &memory)?;
+            kv_cache_dtype = GGMLType::Q8_0;
+        }
+        let (key_cache, value_cache) =
+            match Self::alloc_kv_caches(conf, seq_len, kv_cache_dtype, &device) {
+                Err(err) if err.kind == ErrorKind::OutOfMemory && can_fall_back => {
+                    kv_cache_dtype = GGMLType::Q8_0;
+                    Self::alloc_kv_caches(conf, seq_len, kv_cache_dtype, &device)?
+                }
+                r => r?,
+            };
+        let max_batch = Self::max_batch_in_budget(conf, seq_len, kv_cache_dtype, &memory);
         Ok(Self {
             conf: conf.clone(),
             logits,
             key_cache,
             value_cache,
+            seq_len,
+            kv_cache_dtype,
             weights,
             tokenizer,
             device,
             metrics,
+            fingerprint,
+            captured_layers: None,
+            truncation: TruncationOptions::default(),
+            memory,
+            max_batch,
+            segments: None,
+            positions: None,
+            tree_mask: None,
+            tree: None,
+            hooks: vec![],
+            imatrix: None,
         })
     }
 
+    #[allow(clippy::type_complexity)]
+    fn alloc_kv_caches(
+        conf: &Llama2Config,
+        seq_len: usize,
+        dtype: GGMLType,
+        device: &T::Device,
+    ) -> Result<(Vec<Option<T>>, Vec<Option<T>>)> {
+        let key_cache = (0..conf.n_layers)
+            .map(|_| Self::alloc_kv_cache(conf, seq_len, dtype, device).map(Some))
+            .collect::<Result<Vec<_>>>()?;
+        let value_cache = (0..conf.n_layers)
+            .map(|_| Self::alloc_kv_cache(conf, seq_len, dtype, device).map(Some))
+            .collect::<Result<Vec<_>>>()?;
+        Ok((key_cache, value_cache))
+    }
+
+    // the kv cache should leave room for the scratch of at least one token
+    fn check_kv_cache_budget(
+        conf: &Llama2Config,
+        seq_len: usize,
+        dtype: GGMLType,
+        memory: &MemoryOptions,
+    ) -> Result<()> {
+        let budget = match memory.budget {
+            None => return Ok(()),
+            Some(budget) => budget,
+        };
+        let requested = memory::kv_cache_bytes(conf, seq_len, dtype)
+            + memory::scratch_bytes_per_token(conf, seq_len);
+        if requested > budget {
+            return Err(Error::out_of_memory(
+                requested,
+                Some(budget),
+                format!(
+                    "the {:?} kv cache of {} positions does not fit into the memory budget",
+                    dtype, seq_len
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn max_batch_in_budget(
+        conf: &Llama2Config,
+        seq_len: usize,
+        dtype: GGMLType,
+        memory: &MemoryOptions,
+    ) -> Option<usize> {
+        let budget = memory.budget?;
+        let rest = budget.saturating_sub(memory::kv_cache_bytes(conf, seq_len, dtype));
+        Some((rest / memory::scratch_bytes_per_token(conf, seq_len)).max(1))
+    }
+
+    // allocate an empty kv cache of (n_kv_heads, 0, head_size) with the capacity of seq_len
+    fn alloc_kv_cache(
+        conf: &Llama2Config,
+        seq_len: usize,
+        dtype: GGMLType,
+        device: &T::Device,
+    ) -> Result<T> {
+        T::alloc(
+            &[conf.n_kv_heads, seq_len, conf.head_size()],
+            dtype,
+            device.clone(),
+        )?
+        .resize(1, 0)
+    }
+
+    /// the max number of positions the kv cache can hold.
+    pub fn seq_len(&self) -> usize {
+        self.seq_len
+    }
+
+    /// the dtype of the kv cache, which may be q8_0 after falling back on the memory budget.
+    pub fn kv_cache_dtype(&self) -> GGMLType {
+        self.kv_cache_dtype
+    }
+
+    /// the max number of tokens forwarded in one pass within the memory budget, None if
+    /// there's no budget.
+    pub fn max_batch(&self) -> Option<usize> {
+        self.max_batch
+    }
+
+    /// the number of positions filled in the kv cache.
+    pub fn kv_cache_len(&self) -> usize {
+        self.key_cache[0].as_ref().unwrap().strider().shape()[1]
+    }
+
+    /// grow or shrink the kv cache to hold seq_len positions, the filled positions are kept,
+    /// so it can't be shrunk below `kv_cache_len()`. call `reset_kv_cache()` before shrinking
+    /// to release the memory after a conversation ends.
+    pub fn resize_kv_cache(&mut self, seq_len: usize) -> Result<()> {
+        let pos = self.kv_cache_len();
+        if seq_len < pos {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "can not shrink the kv cache to {}, {} positions are in use",
+                    seq_len, pos
+                ),
+            )
+                .into());
+        }
+        if pos > 0 && self.kv_cache_dtype == GGMLType::Q8_0 {
+            return Err((
+                ErrorKind::NotImplemented,
+                "resizing a non-empty q8_0 kv cache is not supported yet",
+            )
+                .into());
+        }
+        Self::check_kv_cache_budget(&self.conf, seq_len, self.kv_cache_dtype, &self.memory)?;
+
+        for cache in self.key_cache.iter_mut().chain(self.value_cache.iter_mut()) {
+            let mut new_cache =
+                Self::alloc_kv_cache(&self.conf, seq_len, self.kv_cache_dtype, &self.device)?;
+            if pos > 0 {
+                new_cache.concatenate(cache.as_ref().unwrap(), 1)?;
+            }
+            cache.replace(new_cache);
+        }
+        self.seq_len = seq_len;
+        self.max_batch =
+            Self::max_batch_in_budget(&self.conf, seq_len, self.kv_cache_dtype, &self.memory);
+        Ok(())
+    }
+
+    /// how `prefill()` fits a prompt longer than the kv cache, it fails by default.
+    pub fn set_truncation(&mut self, options: TruncationOptions) {
+        self.truncation = options;
+    }
+
+    /// take the tokenizer and the chat template of the model again after its
+    /// `reload_tokenizer()`, the kv cache is kept. fails on a model of other weights.
+    pub fn reload_tokenizer(&mut self, model: impl Llama2Model<T = T>) -> Result<()> {
+        if model.fingerprint() != self.fingerprint {
+            return Err((
+                ErrorKind::BadInput,
+                "reload_tokenizer: the model is not the one of the runner",
+            )
+                .into());
+        }
+        self.tokenizer = model.tokenizer();
+        self.conf.chat_template = model.conf().chat_template;
+        Ok(())
+    }
+
+    /// add a hook called after every layer of the forward passes, like a `HiddenBias`
+    /// steering the hidden states. on the batches of `BatchScheduler` the hooks are called
+    /// on every request with its own positions.
+    pub fn add_hook(&mut self, hook: impl LayerHook + 'static) {
+        self.hooks.push(Box::new(hook));
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.hooks.clear();
+    }
+
+    /// drop the positions from `len` on in the kv cache, like the draft tokens rejected on a
+    /// speculative decoding step. the next forward pass should start at `len`.
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        let pos = self.kv_cache_len();
+        if len > pos {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "can not truncate the kv cache to {}, only {} positions are filled",
+                    len, pos
+                ),
+            )
+                .into());
+        }
+        for cache in self.key_cache.iter_mut().chain(self.value_cache.iter_mut()) {
+            let t = cache.take().unwrap().resize(1, len)?;
+            cache.replace(t);
+        }
+        Ok(())
+    }
+
+    /// forget all the positions in the kv cache, the capacity is kept.
+    pub fn reset_kv_cache(&mut self) -> Result<()> {
+        for cache in self.key_cache.iter_mut().chain(self.value_cache.iter_mut()) {
+            let t = cache.take().unwrap().resize(1, 0)?;
+            cache.replace(t);
+        }
+        Ok(())
+    }
+
     // prefill the model with the prompt, return the next position and the first generated token
     pub fn prefill(
         &mut self,
         prompt: &str,
-        sampler: &'a mut Llama2Sampler,
+        sampler: &'a mut impl TokenSampler<T>,
     ) -> Result<(usize, usize, usize)> {
-        let prompt_tokens = self.tokenizer.encode(prompt, true, false)?;
-        if prompt_tokens.is_empty() {
-            return Err(Error {
-                kind: ErrorKind::BadInput,
-                message: "something is wrong, expected at least 1 prompt token".to_string(),
-                cause: None,
-            });
-        }
+        let (pos, last_token, logits) = self.prefill_logits(prompt, sampler)?;
+        let token = self.sample(&logits, sampler)?;
+        Ok((pos, last_token, token))
+    }
+
+    // like prefill(), but returns the logits of the last prompt token instead of sampling
+    pub(crate) fn prefill_logits(
+        &mut self,
+        prompt: &str,
+        sampler: &mut impl TokenSampler<T>,
+    ) -> Result<(usize, usize, T)> {
+        let prompt_tokens = self.prompt_tokens(prompt)?;
+        self.prefill_tokens_logits(prompt_tokens, sampler)
+    }
 
-        let mut logits: &mut [f32] = &mut [];
-        for (pos, token) in prompt_tokens.iter().enumerate() {
-            logits = self.forward(*token, pos)?;
+    // like prefill_logits(), on the prompt tokens from `fit_prompt_tokens()`
+    pub(crate) fn prefill_tokens_logits(
+        &mut self,
+        prompt_tokens: Vec<usize>,
+        sampler: &mut impl TokenSampler<T>,
+    ) -> Result<(usize, usize, T)> {
+        for token in prompt_tokens.iter() {
+            sampler.accept(*token);
         }
-        let token = sampler.sample(logits)?;
-        let last_token = *prompt_tokens.last().unwrap();
 
-        Ok((prompt_tokens.len(), last_token, token))
+        let logits = self.forward_logits_in_budget(&prompt_tokens, 0)?;
+        let last_token = *prompt_tokens.last().unwrap();
+        Ok((prompt_tokens.len(), last_token, logits))
     }
 
     pub fn generate(
@@ -116,13 +457,15 @@ impl<'a, T: Tensor> Llama2Runner<T> {
         prev_token: usize,
         token: usize,
         steps: usize,
-        sampler: &'a mut Llama2Sampler,
+        sampler: &'a mut impl TokenSampler<T>,
     ) -> impl Iterator<Item = Result<String>> + '_ {
-        let max_steps = (self.conf.seq_len - pos).min(steps);
-        let first_token = self.tokenizer.decode(prev_token, token);
+        // a pos past the kv cache forwards nothing
+        let max_steps = self.seq_len.saturating_sub(pos).min(steps);
+        // no step yields nothing, not even the token sampled on the prefill
+        let first_token = (steps > 0).then(|| self.tokenizer.decode(prev_token, token));
         let tokens_iter = (pos..pos + max_steps).scan(token, move |current_token, pos| {
-            let logits = self.forward(*current_token, pos).unwrap();
-            let new_token = sampler.sample(logits).unwrap();
+            let logits = self.forward_logits(&[*current_token], pos).unwrap();
+            let new_token = self.sample(&logits, sampler).unwrap();
             if new_token == self.tokenizer.eos_token() {
                 return None;
             }
@@ -130,7 +473,7 @@ impl<'a, T: Tensor> Llama2Runner<T> {
             *current_token = new_token;
             Some(Ok(r))
         });
-        std::iter::once(first_token).chain(tokens_iter)
+        first_token.into_iter().chain(tokens_iter)
     }
 
     // simplify the test cases
@@ -138,46 +481,751 @@ impl<'a, T: Tensor> Llama2Runner<T> {
         &'a mut self,
         prompt: &str,
         steps: usize,
-        sampler: &'a mut Llama2Sampler,
+        sampler: &'a mut impl TokenSampler<T>,
     ) -> Result<impl Iterator<Item = Result<String>> + '_> {
         let (pos, prev_token, token) = self.prefill(prompt, sampler)?;
         Ok(self.generate(pos, prev_token, token, steps, sampler))
     }
 
+    /// prefill the prompt and stream the generated tokens one by one, with the stop lists
+    /// and the cancellation in the options.
+    pub fn stream<S: TokenSampler<T>>(
+        &'a mut self,
+        prompt: &str,
+        sampler: &'a mut S,
+        options: GenerationOptions,
+    ) -> Result<GenerationStream<'a, T, S>> {
+        GenerationStream::new(self, sampler, prompt, options)
+    }
+
+    // the tokens of the prompt with the BOS, truncated to fit into the kv cache
+    pub(crate) fn prompt_tokens(&self, prompt: &str) -> Result<Vec<usize>> {
+        self.fit_prompt_tokens(&encode_prompt(&self.tokenizer, prompt)?)
+    }
+
+    // the tokens of encode_prompt() truncated by the truncation options of the runner
+    pub(crate) fn fit_prompt_tokens(&self, prompt_tokens: &[usize]) -> Result<Vec<usize>> {
+        let prompt_tokens = truncation::truncate_tokens(
+            prompt_tokens,
+            self.seq_len,
+            self.tokenizer.bos_token(),
+            &self.truncation,
+        )?;
+        if prompt_tokens.is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadInput,
+                message: "something is wrong, expected at least 1 prompt token".to_string(),
+                cause: None,
+            });
+        }
+        Ok(prompt_tokens)
+    }
+
+    pub(crate) fn conf(&self) -> &Llama2Config {
+        &self.conf
+    }
+
+    pub(crate) fn tokenizer(&self) -> &BpeTokenizer {
+        &self.tokenizer
+    }
+
+    pub(crate) fn sample(
+        &mut self,
+        logits: &T,
+        sampler: &mut impl TokenSampler<T>,
+    ) -> Result<usize> {
+        let _t = self.metrics.sample_walltime.track();
+        sampler.sample(logits, &mut self.logits)
+    }
+
+    // sample the token and take its log probability from the sampler, or from the raw
+    // logits if the sampler does not keep it and the logprobs are asked for. the step
+    // metrics are also taken from the raw logits if asked for.
+    pub(crate) fn sample_step(
+        &mut self,
+        logits: &T,
+        sampler: &mut impl TokenSampler<T>,
+        options: &GenerationOptions,
+    ) -> Result<SampledToken> {
+        let token = self.sample(logits, sampler)?;
+        let mut logprob = sampler.last_logprob();
+        let need_logprob = logprob.is_none() && options.logprobs;
+        if !need_logprob && options.metrics_temperature.is_none() {
+            return Ok((token, logprob, None));
+        }
+
+        logits.export(&mut self.logits)?;
+        if need_logprob {
+            logprob = Some(perplexity::token_logprob(&self.logits, token));
+        }
+        let metrics = options
+            .metrics_temperature
+            .map(|t| StepMetrics::from_logits(&self.logits, token, t));
+        Ok((token, logprob, metrics))
+    }
+
     pub fn forward(&mut self, token: usize, pos: usize) -> Result<&mut [f32]> {
+        self.forward_batch(&[token], pos)
+    }
+
+    /// forward the tokens at the positions of pos..pos + tokens.len() in one pass, the kv
+    /// cache is filled for all these positions, and the logits of the last token is returned.
+    /// no tokens is a `BadInput` error, as there's no last token to return the logits of.
+    pub fn forward_batch(&mut self, tokens: &[usize], pos: usize) -> Result<&mut [f32]> {
+        let logits = self.forward_logits_in_budget(tokens, pos)?;
+        logits.export(&mut self.logits)?;
+        Ok(&mut self.logits)
+    }
+
+    /// like `forward_batch()`, but returns the logits of every token in (n_tokens, vocab_size),
+    /// to verify the draft tokens of speculative decoding in one pass. no tokens is a
+    /// `BadInput` error like on `forward_batch()`, the kv cache is left as it is.
+    pub fn forward_batch_all(&mut self, tokens: &[usize], pos: usize) -> Result<Vec<f32>> {
+        let logits = {
+            let _t = self.metrics.forward_walltime.track();
+            let x = self.forward_hidden(tokens, pos)?;
+            self.classify(&x)?
+        };
+        let mut out = vec![0.0; tokens.len() * self.conf.vocab_size];
+        logits.export(&mut out)?;
+        Ok(out)
+    }
+
+    /// forward the tokens at the explicit positions, like the grouped positions of self-extend
+    /// or the positions left after evicting some entries of the kv cache. the tokens are
+    /// appended to the kv cache and attend to the entries before them in the cache order, the
+    /// positions only rotate q and k. returns the logits of the last token.
+    pub fn forward_positions(
+        &mut self,
+        tokens: &[usize],
+        positions: &[usize],
+    ) -> Result<&mut [f32]> {
+        if tokens.is_empty() || tokens.len() != positions.len() {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "expected a position for each of the tokens, got {} tokens and {} positions",
+                    tokens.len(),
+                    positions.len()
+                ),
+            )
+                .into());
+        }
+        let kv_len = self.kv_cache_len();
+        if kv_len + tokens.len() > self.seq_len {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "{} tokens do not fit into the kv cache of {} positions with {} filled",
+                    tokens.len(),
+                    self.seq_len,
+                    kv_len
+                ),
+            )
+                .into());
+        }
+
+        self.positions = Some(positions.to_vec());
+        let x = {
+            let _t = self.metrics.forward_walltime.track();
+            self.forward_hidden(tokens, positions[0])
+        };
+        self.positions = None;
+        let x = self.take_rows(&x?, &[tokens.len() - 1])?;
+        let logits = self.classify(&x)?;
+        logits.export(&mut self.logits)?;
+        Ok(&mut self.logits)
+    }
+
+    /// forward a tree of tokens in one pass, like the candidates of the tree speculative
+    /// decoding. parents[i] is the index of the parent of the i-th token in the batch, which
+    /// comes before it, or None if it follows the kv cache directly. each token attends only
+    /// to the kv cache and its ancestors, at the position of its depth in the tree. returns
+    /// the logits of every token in (n_tokens, vocab_size).
+    ///
+    /// all the tokens are appended to the kv cache, call `keep_tree_nodes()` to keep only the
+    /// accepted ones.
+    pub fn forward_tree(
+        &mut self,
+        tokens: &[usize],
+        parents: &[Option<usize>],
+    ) -> Result<Vec<f32>> {
+        let x = self.forward_tree_hidden(tokens, parents)?;
+        let logits = self.classify(&x)?;
+        let mut out = vec![0.0; tokens.len() * self.conf.vocab_size];
+        logits.export(&mut out)?;
+        Ok(out)
+    }
+
+    // like forward_tree(), but returns the hidden states after the final norm
+    pub(crate) fn forward_tree_hidden(
+        &mut self,
+        tokens: &[usize],
+        parents: &[Option<usize>],
+    ) -> Result<T> {
+        let n = tokens.len();
+        if n == 0 || parents.len() != n {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "expected a parent for each of the tokens, got {} tokens and {} parents",
+                    n,
+                    parents.len()
+                ),
+            )
+                .into());
+        }
+        if let Some(i) = (0..n).find(|i| parents[*i].is_some_and(|p| p >= *i)) {
+            return Err((
+                ErrorKind::BadInput,
+                format!("the parent of the token {} should come before it", i),
+            )
+                .into());
+        }
+        let start = self.kv_cache_len();
+        if start + n > self.seq_len {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "{} tokens do not fit into the kv cache of {} positions with {} filled",
+                    n, self.seq_len, start
+                ),
+            )
+                .into());
+        }
+
+        // a node sees itself and the nodes its parent sees
+        let mut mask = vec![false; n * n];
+        let mut positions = vec![start; n];
+        for i in 0..n {
+            if let Some(p) = parents[i] {
+                let (before, row) = mask.split_at_mut(i * n);
+                row[..n].copy_from_slice(&before[p * n..(p + 1) * n]);
+                positions[i] = positions[p] + 1;
+            }
+            mask[i * n + i] = true;
+        }
+
+        self.tree = Some(TokenTree {
+            start,
+            n_nodes: n,
+            kv: Vec::with_capacity(self.conf.n_layers),
+        });
+        self.tree_mask = Some(mask);
+        self.positions = Some(positions);
+        let x = {
+            let _t = self.metrics.forward_walltime.track();
+            self.forward_hidden(tokens, start)
+        };
+        self.positions = None;
+        self.tree_mask = None;
+        if x.is_err() {
+            self.tree = None;
+            self.truncate_kv_cache(start)?;
+        }
+        x
+    }
+
+    /// keep the nodes of the last `forward_tree()` in the kv cache, in the order of nodes,
+    /// like the accepted path of the tree. the other nodes are dropped.
+    pub fn keep_tree_nodes(&mut self, nodes: &[usize]) -> Result<()> {
+        let tree = match self.tree.take() {
+            None => return Err((ErrorKind::BadInput, "forward_tree() is not called").into()),
+            Some(tree) => tree,
+        };
+        if self.kv_cache_len() != tree.start + tree.n_nodes {
+            return Err((
+                ErrorKind::BadInput,
+                "the kv cache is changed after forward_tree()",
+            )
+                .into());
+        }
+        if let Some(node) = nodes.iter().find(|node| **node >= tree.n_nodes) {
+            return Err((
+                ErrorKind::BadInput,
+                format!("the tree has {} nodes, got node {}", tree.n_nodes, node),
+            )
+                .into());
+        }
+
+        self.truncate_kv_cache(tree.start)?;
+        let (n_kv_heads, head_dim) = (self.conf.n_kv_heads, self.conf.head_size());
+        for (l, (k, v)) in tree.kv.iter().enumerate() {
+            let caches = [(&mut self.key_cache[l], k), (&mut self.value_cache[l], v)];
+            for (cache, src) in caches {
+                if let Some(cache) = cache.as_mut() {
+                    let kv_dim = n_kv_heads * head_dim;
+                    let mut rows =
+                        T::alloc(&[nodes.len(), kv_dim], src.dtype(), self.device.clone())?;
+                    rows.copy_rows_from(src, nodes)?;
+                    let rows = rows
+                        .reshape(&[nodes.len(), n_kv_heads, head_dim])?
+                        .transpose(&[1, 0, 2])?;
+                    cache.concatenate(&rows, 1)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// run the tokens from the position 0 and pool their hidden states after the final norm
+    /// into a single vector, for retrieval. the kv cache is reset before the run, so it
+    /// should not be called in the middle of a conversation.
+    pub fn embeddings(
+        &mut self,
+        tokens: &[usize],
+        options: &EmbeddingOptions,
+    ) -> Result<Embeddings> {
+        if tokens.is_empty() || tokens.len() > self.seq_len {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "expected 1 to {} tokens for the embeddings, got {}",
+                    self.seq_len,
+                    tokens.len()
+                ),
+            )
+                .into());
+        }
+
+        self.reset_kv_cache()?;
+        if options.capture_layers {
+            self.captured_layers = Some(Vec::with_capacity(self.conf.n_layers));
+        }
+        let x = {
+            let _t = self.metrics.forward_walltime.track();
+            self.forward_hidden(tokens, 0)
+        };
+        let captured_layers = self.captured_layers.take();
+        let x = x?;
+
+        let embed_dim = self.conf.embedding_dim;
+        let mut hidden = vec![0.0; tokens.len() * embed_dim];
+        x.export(&mut hidden)?;
+        let pooling = options.pooling.or(self.conf.pooling).unwrap_or_default();
+        let mut embedding = embeddings::pool(&hidden, embed_dim, pooling);
+        if options.normalize {
+            embeddings::l2_normalize(&mut embedding);
+        }
+        let layers = captured_layers
+            .unwrap_or_default()
+            .iter()
+            .map(|hidden| embeddings::pool(hidden, embed_dim, pooling))
+            .collect();
+        Ok(Embeddings { embedding, layers })
+    }
+
+    /// like `embeddings()`, but on the tokens of the prompt as in the generation, with the
+    /// bos and the truncation. an empty prompt takes the embedding of the bos alone.
+    pub fn embed_prompt(&mut self, prompt: &str, options: &EmbeddingOptions) -> Result<Embeddings> {
+        let tokens = self.prompt_tokens(prompt)?;
+        self.embeddings(&tokens, options)
+    }
+
+    /// score the tokens of a text by the model: the tokens are split into the chunks of
+    /// `n_ctx`, each one runs from the position 0 in the batches of `n_batch`, and the log
+    /// likelihood of every token is taken from the softmax of the logits before it.
+    /// `on_chunk(done, n_chunks, perplexity)` is called after each chunk with the running
+    /// estimate. the kv cache is reset, so it should not be called in the middle of a
+    /// conversation.
+    pub fn perplexity(
+        &mut self,
+        tokens: &[usize],
+        options: &PerplexityOptions,
+        mut on_chunk: impl FnMut(usize, usize, f64),
+    ) -> Result<Perplexity> {
+        let n_ctx = options.n_ctx.unwrap_or(self.seq_len).min(self.seq_len);
+        // the bos takes a position of each chunk
+        let n_text = n_ctx - options.add_bos as usize;
+        if n_text < 2 {
+            return Err((
+                ErrorKind::BadInput,
+                format!("the chunk of {} tokens is too short to score", n_ctx),
+            )
+                .into());
+        }
+        let n_batch = options
+            .n_batch
+            .or(self.max_batch)
+            .unwrap_or(n_ctx)
+            .clamp(1, n_ctx);
+
+        let vocab_size = self.conf.vocab_size;
+        let n_chunks = tokens.len().div_ceil(n_text);
+        let mut result = Perplexity::default();
+        for (i, text) in tokens.chunks(n_text).enumerate() {
+            let mut chunk = Vec::with_capacity(n_ctx);
+            if options.add_bos {
+                chunk.push(self.tokenizer.bos_token());
+            }
+            chunk.extend_from_slice(text);
+
+            self.reset_kv_cache()?;
+            for pos in (0..chunk.len()).step_by(n_batch) {
+                let batch = &chunk[pos..(pos + n_batch).min(chunk.len())];
+                let logits = self.forward_batch_all(batch, pos)?;
+                // the logits of the token at p predict the token at p + 1
+                for (p, logits) in (pos..).zip(logits.chunks(vocab_size)) {
+                    if let Some(next) = chunk.get(p + 1) {
+                        result.push(perplexity::token_logprob(logits, *next));
+                    }
+                }
+            }
+            on_chunk(i + 1, n_chunks, result.perplexity());
+        }
+        Ok(result)
+    }
+
+    /// run the calibration text through the model and sum up the squared activations of the
+    /// input columns of every weight in the layers and the output, into an importance
+    /// matrix of llama.cpp. the text is split into the chunks of `n_ctx` like
+    /// `perplexity()`, and `on_chunk(done, n_chunks)` is called after each chunk. the kv
+    /// cache is reset, so it should not be called in the middle of a conversation.
+    pub fn importance_matrix(
+        &mut self,
+        tokens: &[usize],
+        options: &ImatrixOptions,
+        mut on_chunk: impl FnMut(usize, usize),
+    ) -> Result<ImportanceMatrix> {
+        let n_ctx = options.n_ctx.unwrap_or(self.seq_len).min(self.seq_len);
+        let n_text = n_ctx.saturating_sub(options.add_bos as usize);
+        if n_text < 1 {
+            return Err((
+                ErrorKind::BadInput,
+                format!("the chunk of {} tokens has no room for the text", n_ctx),
+            )
+                .into());
+        }
+        let n_batch = options
+            .n_batch
+            .or(self.max_batch)
+            .unwrap_or(n_ctx)
+            .clamp(1, n_ctx);
+        // the output takes the token embedding if it's tied
+        let output_name = match self.weights.output_weight {
+            Some(_) => "output.weight",
+            None => "token_embd.weight",
+        };
+
+        let n_chunks = tokens.len().div_ceil(n_text);
+        self.imatrix = Some(RefCell::new(ImatrixCollector::default()));
+        let mut result = Ok(());
+        for (i, text) in tokens.chunks(n_text).enumerate() {
+            let mut chunk = Vec::with_capacity(n_ctx);
+            if options.add_bos {
+                chunk.push(self.tokenizer.bos_token());
+            }
+            chunk.extend_from_slice(text);
+
+            result = self.collect_chunk(&chunk, n_batch, output_name);
+            if result.is_err() {
+                break;
+            }
+            on_chunk(i + 1, n_chunks);
+        }
+        let collector = self.imatrix.take().unwrap().into_inner();
+        result?;
+        Ok(collector.finish(n_chunks))
+    }
+
+    // forward a chunk from the position 0 while the activations are collected
+    fn collect_chunk(&mut self, chunk: &[usize], n_batch: usize, output_name: &str) -> Result<()> {
+        self.reset_kv_cache()?;
+        for pos in (0..chunk.len()).step_by(n_batch) {
+            let batch = &chunk[pos..(pos + n_batch).min(chunk.len())];
+            let x = {
+                let _t = self.metrics.forward_walltime.track();
+                self.forward_hidden(batch, pos)?
+            };
+            // the logits are not needed, only the input of the output weight
+            self.collect_activations(output_name.to_string(), &x)?;
+        }
+        Ok(())
+    }
+
+    /// allocate the kv caches of n sequences besides the runner's own, for `BatchScheduler`.
+    pub(crate) fn alloc_kv_slots(&self, n_slots: usize) -> Result<Vec<KvSlot<T>>> {
+        if let Some(budget) = self.memory.budget {
+            let kv_bytes = memory::kv_cache_bytes(&self.conf, self.seq_len, self.kv_cache_dtype);
+            let per_token = memory::scratch_bytes_per_token(&self.conf, self.seq_len);
+            let requested = kv_bytes * (n_slots + 1) + per_token * n_slots;
+            if requested > budget {
+                return Err(Error::out_of_memory(
+                    requested,
+                    Some(budget),
+                    format!(
+                        "the kv caches of {} sequences do not fit into the memory budget",
+                        n_slots
+                    ),
+                ));
+            }
+        }
+        (0..n_slots)
+            .map(|_| {
+                let (key_cache, value_cache) = Self::alloc_kv_caches(
+                    &self.conf,
+                    self.seq_len,
+                    self.kv_cache_dtype,
+                    &self.device,
+                )?;
+                Ok(KvSlot {
+                    key_cache,
+                    value_cache,
+                })
+            })
+            .collect()
+    }
+
+    /// forward the tokens of several sequences in one pass, each one starts at its own
+    /// position and fills its own kv cache slot. the matmuls are batched over the tokens of
+    /// all the sequences. returns the logits of the last token of every sequence in
+    /// (n_seqs, vocab_size).
+    ///
+    /// on an error the slots may be filled partially, they should be reset.
+    pub(crate) fn forward_segments(
+        &mut self,
+        seqs: &mut [(&[usize], usize, &mut KvSlot<T>)],
+    ) -> Result<Vec<f32>> {
+        let last_rows = seqs
+            .iter()
+            .scan(0, |start, (seq_tokens, _, _)| {
+                *start += seq_tokens.len();
+                Some(*start - 1)
+            })
+            .collect::<Vec<_>>();
+        let x = self.forward_segments_hidden(seqs)?;
+
+        // only the last token of each sequence is needed to get the logits
+        let x = self.take_rows(&x, &last_rows)?;
+        let logits = self.classify(&x)?;
+        let mut out = vec![0.0; last_rows.len() * self.conf.vocab_size];
+        logits.export(&mut out)?;
+        Ok(out)
+    }
+
+    /// like `forward_segments()`, but returns the hidden states of all the tokens after the
+    /// final norm, in (n_tokens, embed_dim).
+    pub(crate) fn forward_segments_hidden(
+        &mut self,
+        seqs: &mut [(&[usize], usize, &mut KvSlot<T>)],
+    ) -> Result<T> {
+        let _t = self.metrics.forward_walltime.track();
+        let mut tokens = vec![];
+        let mut segments = Vec::with_capacity(seqs.len());
+        for (seq_tokens, pos, slot) in seqs.iter_mut() {
+            segments.push(BatchSegment {
+                start: tokens.len(),
+                len: seq_tokens.len(),
+                pos: *pos,
+                slot: std::mem::take(*slot),
+            });
+            tokens.extend_from_slice(seq_tokens);
+        }
+
+        self.segments = Some(segments);
+        let x = self.forward_hidden(&tokens, 0);
+        let segments = self.segments.take().unwrap();
+        for ((_, _, slot), seg) in seqs.iter_mut().zip(segments) {
+            **slot = seg.slot;
+        }
+        x
+    }
+
+    // like forward_batch, but the logits are left on the device
+    pub(crate) fn forward_logits(&mut self, tokens: &[usize], pos: usize) -> Result<T> {
         let _t = self.metrics.forward_walltime.track();
+        let x = self.forward_hidden(tokens, pos)?;
 
-        let x = match self.conf.architecture {
-            ModelArchitecture::Llama => self.forward_llama(&[token], pos)?,
-            ModelArchitecture::Gemma => self.forward_gemma(&[token], pos)?,
+        // only the last token is needed to get the logits
+        let x = if tokens.len() == 1 {
+            x
+        } else {
+            let mut x_last = T::alloc(
+                &[1, self.conf.embedding_dim],
+                T::activation_dtype(&self.device),
+                self.device.clone(),
+            )?;
+            x_last.copy_rows_from(&x, &[tokens.len() - 1])?;
+            x_last
         };
 
-        // classifier into logits
+        self.classify(&x)
+    }
+
+    // like forward_logits(), but splits the tokens into the batches fitting into the memory
+    // budget. the batch is halved on an allocation failure if the options allow.
+    fn forward_logits_in_budget(&mut self, tokens: &[usize], pos: usize) -> Result<T> {
+        let mut n_batch = self.max_batch.unwrap_or(tokens.len()).min(tokens.len());
+        if n_batch < tokens.len() && !self.memory.allow_smaller_batch {
+            let per_token = memory::scratch_bytes_per_token(&self.conf, self.seq_len);
+            let kv_bytes = memory::kv_cache_bytes(&self.conf, self.seq_len, self.kv_cache_dtype);
+            return Err(Error::out_of_memory(
+                kv_bytes + per_token * tokens.len(),
+                self.memory.budget,
+                format!(
+                    "the scratch of {} tokens does not fit into the memory budget, at most {} \
+                     tokens fit in a batch",
+                    tokens.len(),
+                    n_batch
+                ),
+            ));
+        }
+
+        let mut done = 0;
+        loop {
+            let end = (done + n_batch).min(tokens.len());
+            match self.forward_logits(&tokens[done..end], pos + done) {
+                Ok(logits) if end == tokens.len() => return Ok(logits),
+                Ok(_) => done = end,
+                Err(err)
+                    if err.kind == ErrorKind::OutOfMemory
+                        && self.memory.allow_smaller_batch
+                        && end - done > 1 =>
+                {
+                    // drop the positions the failed batch may have filled
+                    self.truncate_kv_cache(pos + done)?;
+                    n_batch = (end - done) / 2;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // the classifier of the hidden states into the logits, (n_batch, vocab_size)
+    pub(crate) fn classify(&self, x: &T) -> Result<T> {
         // TODO: it'd be make sense to reuse the same buffer for the logits
         let output_weight = self
             .weights
             .output_weight
             .as_ref()
             .unwrap_or_else(|| &self.weights.token_embed);
-        let logits = output_weight.matmul_vec(&x)?; // (vocab_size,
-        logits.export(&mut self.logits)?;
-        Ok(&mut self.logits)
+        let logits = output_weight.matmul_vec(x)?; // (vocab_size,
+        let logits = match &self.weights.output_scale {
+            Some(scale) => logits.mul_inplace(scale)?,
+            None => logits,
+        };
+        add_bias(logits, self.weights.output_bias.as_ref())
     }
 
-    fn forward_llama(&mut self, tokens: &[usize], pos: usize) -> Result<T> {
-        let embed_dim = self.conf.embedding_dim;
-        let n_heads = self.conf.n_heads;
-        let n_kv_heads = self.conf.n_kv_heads;
-        let head_dim = self.conf.head_size();
-        let rope_dim = self.conf.rope_dim.unwrap_or(head_dim);
-        let n_batch = tokens.len();
+    // the hidden states of all the tokens after the final norm, (n_batch, embed_dim)
+    pub(crate) fn forward_hidden(&mut self, tokens: &[usize], pos: usize) -> Result<T> {
+        // the primitives do not take the empty tensors
+        if tokens.is_empty() {
+            return Err((ErrorKind::BadInput, "expected at least 1 token to forward").into());
+        }
+        let tag = if tokens.len() > 1 {
+            TaskTag::Prefill
+        } else {
+            TaskTag::Decode
+        };
+        T::set_task_tag(&self.device, tag);
+        match self.conf.architecture {
+            ModelArchitecture::Llama => self.forward_llama(tokens, pos, RopeMode::Llama),
+            ModelArchitecture::Qwen2 => self.forward_llama(tokens, pos, RopeMode::Neox),
+            ModelArchitecture::Gemma => self.forward_gemma(tokens, pos),
+            ModelArchitecture::Phi2 => self.forward_phi2(tokens, pos),
+        }
+    }
 
+    // look up the token embeddings of the batch, the ids go to the device as an i32 tensor
+    fn embed_tokens(&self, tokens: &[usize]) -> Result<T> {
+        let ids = tokens.iter().map(|t| *t as i32).collect::<Vec<_>>();
+        let ids = T::new_i32(&ids, &[ids.len()], self.device.clone())?;
+        let mut x = T::alloc(
+            &[tokens.len(), self.conf.embedding_dim],
+            T::activation_dtype(&self.device),
+            self.device.clone(),
+        )?;
+        x.get_rows(&self.weights.token_embed, &ids)?;
+        Ok(x)
+    }
+
+    // keep a copy of the hidden states after a layer if embeddings() asked for them
+    fn capture_layer(&mut self, x: &T) -> Result<()> {
+        if let Some(layers) = self.captured_layers.as_mut() {
+            let mut hidden = vec![0.0; x.strider().len()];
+            x.export(&mut hidden)?;
+            layers.push(hidden);
+        }
+        Ok(())
+    }
+
+    // sum up the squared activations of x as the input of the weight if importance_matrix()
+    // asked for them
+    fn collect_activations(&self, name: String, x: &T) -> Result<()> {
+        if let Some(imatrix) = self.imatrix.as_ref() {
+            let n_cols = x.strider().shape()[1];
+            let mut buf = vec![0.0; x.strider().len()];
+            x.export(&mut buf)?;
+            imatrix.borrow_mut().add(name, &buf, n_cols);
+        }
+        Ok(())
+    }
+
+    // add the biases of the hooks to the hidden states after the layer l. the sequences of a
+    // batch in forward_segments() are biased one by one, at their own positions.
+    fn apply_hooks(&mut self, mut x: T, l: usize, pos: usize) -> Result<T> {
+        if self.hooks.is_empty() {
+            return Ok(x);
+        }
+        let shape = x.strider().shape().to_vec();
+        let (n_tokens, embed_dim) = (shape[0], shape[1]);
+        // (start, len, pos) of the sequences in the rows of x
+        let seqs = match self.segments.as_ref() {
+            Some(segments) => segments
+                .iter()
+                .map(|seg| (seg.start, seg.len, seg.pos))
+                .collect::<Vec<_>>(),
+            None => vec![(0, n_tokens, pos)],
+        };
+        for hook in self.hooks.iter_mut() {
+            let mut bias: Option<Vec<f32>> = None;
+            for &(start, len, pos) in seqs.iter() {
+                let Some(rows) = hook.layer_bias(l, pos, len, embed_dim)? else {
+                    continue;
+                };
+                if len == n_tokens {
+                    bias = Some(rows);
+                    continue;
+                }
+                if rows.len() != len * embed_dim {
+                    return Err(Error {
+                        kind: ErrorKind::BadInput,
+                        message: format!(
+                            "expected a bias of {} values on layer {}, got {}",
+                            len * embed_dim,
+                            l,
+                            rows.len()
+                        ),
+                        cause: None,
+                    });
+                }
+                bias.get_or_insert_with(|| vec![0.0; n_tokens * embed_dim])
+                    [start * embed_dim..(start + len) * embed_dim]
+                    .copy_from_slice(&rows);
+            }
+            if let Some(bias) = bias {
+                let bias = T::new_f32(&bias, &shape, self.device.clone())?;
+                x = x.add_inplace(&bias)?;
+            }
+        }
+        Ok(x)
+    }
+
+    // Mistral (a `llama` with a sliding window) and Qwen2 share the same graph with LLAMA, and
+    // Qwen2 has the biases on qkv and rotates in the NEOX style.
+    fn forward_llama(&mut self, tokens: &[usize], pos: usize, rope_mode: RopeMode) -> Result<T> {
         // copy the token embedding into x
-        let mut x = T::alloc(&[n_batch, embed_dim], GGMLType::F32, self.device.clone())?;
-        x.copy_rows_from(&self.weights.token_embed, tokens)?;
+        let mut x = self.embed_tokens(tokens)?;
 
         // forward all the layers
         for l in 0..self.conf.n_layers {
+            let _span = self
+                .metrics
+                .trace
+                .span("layer", "layer")
+                .with_arg("layer", l as u64);
+            self.weights.enter_layer(l);
             let x_attn_orig = x.dup()?;
 
             // attention rnsnorm
@@ -193,28 +1241,12 @@ impl<'a, T: Tensor> Llama2Runner<T> {
                 // wq: (embed_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, embed_dim, )
                 // wk: (kv_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, kv_dim, )
                 // wv: (kv_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, kv_dim, )
-                let q = self.weights.wq[l].matmul_vec(&x)?;
-                let k = self.weights.wk[l].matmul_vec(&x)?;
-                let v = self.weights.wv[l].matmul_vec(&x)?;
+                let (q, k, v) = self.forward_qkv(&x, l)?;
                 (q, k, v)
             };
 
-            // ROPE
-            let (q, k) = {
-                let q = q.reshape(&[n_batch, n_heads, head_dim])?;
-                let k = k.reshape(&[n_batch, n_kv_heads, head_dim])?;
-
-                let q = q.rope_inplace(RopeMode::Llama, pos, rope_dim)?;
-                let k = k.rope_inplace(RopeMode::Llama, pos, rope_dim)?;
-                (
-                    q.with_name(format!("q_roped:{}:{}", l, pos)),
-                    k.with_name(format!("k_roped:{}:{}", l, pos)),
-                )
-            };
-
-            x = self.forward_multi_query_attention(
-                q, k, v, l, pos, n_kv_heads, n_heads, embed_dim, head_dim, n_batch,
-            )?;
+            // ROPE and attention
+            x = self.forward_rope_attention(q, k, v, l, pos, rope_mode)?;
             x = x.with_name(format!("attn_out:{}:{}", l, pos));
 
             // residual connection back into x
@@ -222,7 +1254,9 @@ impl<'a, T: Tensor> Llama2Runner<T> {
 
             // ffn
             x = self.forward_ffn(x, l, pos, Activation::SiLU)?;
+            x = self.apply_hooks(x, l, pos)?;
             x = x.with_name(format!("ffn_out:{}:{}", l, pos));
+            self.capture_layer(&x)?;
         }
 
         // final rmsnorm
@@ -244,15 +1278,9 @@ impl<'a, T: Tensor> Llama2Runner<T> {
     //    don't need to do it here.
     fn forward_gemma(&mut self, tokens: &[usize], pos: usize) -> Result<T> {
         let embed_dim = self.conf.embedding_dim;
-        let n_heads = self.conf.n_heads;
-        let n_kv_heads = self.conf.n_kv_heads;
-        let head_dim = self.conf.head_size();
-        let rope_dim = self.conf.rope_dim.unwrap_or(head_dim);
-        let n_batch = tokens.len();
 
         // copy the token embedding into x
-        let mut x = T::alloc(&[n_batch, embed_dim], GGMLType::F32, self.device.clone())?;
-        x.copy_rows_from(&self.weights.token_embed, tokens)?;
+        let mut x = self.embed_tokens(tokens)?;
 
         // GEMMA only: scale the embedding with sqrt(embed_dim)
         x = x.scale_inplace((embed_dim as f32).sqrt())?;
@@ -260,6 +1288,12 @@ impl<'a, T: Tensor> Llama2Runner<T> {
 
         // forward all the layers
         for l in 0..self.conf.n_layers {
+            let _span = self
+                .metrics
+                .trace
+                .span("layer", "layer")
+                .with_arg("layer", l as u64);
+            self.weights.enter_layer(l);
             let x_attn_orig = x.dup()?;
 
             // attention rnsnorm
@@ -272,38 +1306,24 @@ impl<'a, T: Tensor> Llama2Runner<T> {
 
             // matmul qkv for every head
             let (q, k, v) = {
-                // wq: (embed_dim, embed_dim) @ x (embed_dim, ) => (embed_dim, )
-                // wk: (kv_dim, embed_dim) @ x (embed_dim, ) => (kv_dim, )
-                // wv: (kv_dim, embed_dim) @ x (embed_dim, ) => (kv_dim, )
-                let q = self.weights.wq[l].matmul_vec(&x)?;
-                let k = self.weights.wk[l].matmul_vec(&x)?;
-                let v = self.weights.wv[l].matmul_vec(&x)?;
+                // wq: (embed_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, embed_dim, )
+                // wk: (kv_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, kv_dim, )
+                // wv: (kv_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, kv_dim, )
+                let (q, k, v) = self.forward_qkv(&x, l)?;
                 (q, k, v)
             };
 
-            // ROPE
-            let (q, k) = {
-                let q = q.reshape(&[n_heads, head_dim])?;
-                let k = k.reshape(&[n_kv_heads, head_dim])?;
-
-                let q = q.rope_inplace(RopeMode::Neox, pos, rope_dim)?;
-                let k = k.rope_inplace(RopeMode::Neox, pos, rope_dim)?;
-                (
-                    q.with_name(format!("q_roped:{}:{}", l, pos)),
-                    k.with_name(format!("k_roped:{}:{}", l, pos)),
-                )
-            };
-
-            x = self.forward_multi_query_attention(
-                q, k, v, l, pos, n_kv_heads, n_heads, embed_dim, head_dim, n_batch,
-            )?;
+            // ROPE and attention
+            x = self.forward_rope_attention(q, k, v, l, pos, RopeMode::Neox)?;
 
             // residual connection back into x
             x = x.add_inplace(&x_attn_orig)?;
 
             // ffn
             x = self.forward_ffn(x, l, pos, Activation::GeLU)?;
+            x = self.apply_hooks(x, l, pos)?;
             x = x.with_name(format!("ffn_out:{}:{}", l, pos));
+            self.capture_layer(&x)?;
         }
 
         // final rmsnorm
@@ -316,23 +1336,206 @@ impl<'a, T: Tensor> Llama2Runner<T> {
         Ok(x)
     }
 
-    #[allow(clippy::too_many_arguments)]
+    // The differences between PHI-2 and LLAMA are:
+    // 1. it uses layer norm with biases instead of rmsnorm.
+    // 2. the matmuls in attention and ffn have biases, so do the logits.
+    // 3. it rotates only the first rope_dim of each head in the NEOX style.
+    // 4. the attention and the ffn run in parallel on the same normalized input, and their
+    //    outputs are summed up with the residual.
+    // 5. the ffn is a plain MLP with GELU, without the gate.
+    fn forward_phi2(&mut self, tokens: &[usize], pos: usize) -> Result<T> {
+        // copy the token embedding into x
+        let mut x = self.embed_tokens(tokens)?;
+
+        // forward all the layers
+        for l in 0..self.conf.n_layers {
+            let _span = self
+                .metrics
+                .trace
+                .span("layer", "layer")
+                .with_arg("layer", l as u64);
+            self.weights.enter_layer(l);
+            let x_orig = x.dup()?;
+
+            // attention layer norm, the ffn shares it
+            x = {
+                x = x.layer_norm_inplace(self.conf.rms_norm_eps)?;
+                x = x.mul_inplace(&self.weights.rms_att_weight[l])?;
+                x = add_bias(x, self.weights.attn_norm_bias[l].as_ref())?;
+                x.with_name(format!("attn_norm:{}:{}", l, pos))
+            };
+
+            let (q, k, v) = self.forward_qkv(&x, l)?;
+
+            // ROPE and attention
+            let x_attn = self.forward_rope_attention(q, k, v, l, pos, RopeMode::Neox)?;
+
+            // sum up the parallel attention, ffn and the residual, the ffn output is
+            // accumulated by its down projection
+            let residual = x_attn.add_inplace(&x_orig)?;
+            x = self.forward_mlp(x, l, Activation::GeLU, Some(residual))?;
+            x = self.apply_hooks(x, l, pos)?;
+            x = x.with_name(format!("ffn_out:{}:{}", l, pos));
+            self.capture_layer(&x)?;
+        }
+
+        // final layer norm
+        x = {
+            x = x.layer_norm_inplace(self.conf.rms_norm_eps)?;
+            x = x.mul_inplace(&self.weights.rms_final_weight)?;
+            x = add_bias(x, self.weights.final_norm_bias.as_ref())?;
+            x.with_name(format!("final_norm:{}", pos))
+        };
+
+        Ok(x)
+    }
+
+    // wq: (embed_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, embed_dim, )
+    // wk: (kv_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, kv_dim, )
+    // wv: (kv_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, kv_dim, )
+    fn forward_qkv(&self, x: &T, l: usize) -> Result<(T, T, T)> {
+        let q = self.linear(x, l, LoraTarget::AttnQ, None)?;
+        let k = self.linear(x, l, LoraTarget::AttnK, None)?;
+        let v = self.linear(x, l, LoraTarget::AttnV, None)?;
+        let q = add_bias(q, self.weights.bq[l].as_ref())?;
+        let k = add_bias(k, self.weights.bk[l].as_ref())?;
+        let v = add_bias(v, self.weights.bv[l].as_ref())?;
+        Ok((q, k, v))
+    }
+
+    // rotate q and k at the positions from pos on, or at the explicit positions, attend to
+    // the kv cache and project the output. in a batch of several sequences, each one is rotated at its own positions and
+    // attends to its own kv cache slot, while the output projection is still batched.
+    fn forward_rope_attention(
+        &mut self,
+        q: T,
+        k: T,
+        v: T,
+        l: usize,
+        pos: usize,
+        rope_mode: RopeMode,
+    ) -> Result<T> {
+        let x = match self.segments.take() {
+            None => {
+                let n_batch = q.strider().shape()[0];
+                let positions = self.positions.as_deref();
+                let (q, k) = self.forward_rope(q, k, l, pos, positions, rope_mode)?;
+                self.forward_multi_query_attention(q, k, v, l, n_batch)
+            }
+            Some(mut segments) => {
+                let x = self.forward_segments_attention(&q, &k, &v, l, rope_mode, &mut segments);
+                self.segments = Some(segments);
+                x
+            }
+        }?;
+
+        // final matmul to get the output of the attention
+        let x = self.linear(&x, l, LoraTarget::AttnOutput, None)?;
+        add_bias(x, self.weights.bo[l].as_ref())
+    }
+
+    fn forward_rope(
+        &self,
+        q: T,
+        k: T,
+        l: usize,
+        pos: usize,
+        positions: Option<&[usize]>,
+        rope_mode: RopeMode,
+    ) -> Result<(T, T)> {
+        let n_batch = q.strider().shape()[0];
+        let head_dim = self.conf.head_size();
+        let rope = self.conf.rope(l);
+
+        let mut q = q.reshape(&[n_batch, self.conf.n_heads, head_dim])?;
+        let mut k = k.reshape(&[n_batch, self.conf.n_kv_heads, head_dim])?;
+        if rope.dim > 0 {
+            (q, k) = match positions {
+                None => (
+                    q.rope_inplace(rope_mode, pos, rope.dim, rope.freq_base)?,
+                    k.rope_inplace(rope_mode, pos, rope.dim, rope.freq_base)?,
+                ),
+                Some(positions) => (
+                    q.rope_positions_inplace(rope_mode, positions, rope.dim, rope.freq_base)?,
+                    k.rope_positions_inplace(rope_mode, positions, rope.dim, rope.freq_base)?,
+                ),
+            };
+        }
+        Ok((
+            q.with_name(format!("q_roped:{}:{}", l, pos)),
+            k.with_name(format!("k_roped:{}:{}", l, pos)),
+        ))
+    }
+
+    // the rows of q, k and v of each segment are copied out to be rotated and attended with
+    // the kv cache slot of the segment, the outputs are gathered back in the same order.
+    fn forward_segments_attention(
+        &mut self,
+        q: &T,
+        k: &T,
+        v: &T,
+        l: usize,
+        rope_mode: RopeMode,
+        segments: &mut [BatchSegment<T>],
+    ) -> Result<T> {
+        let embed_dim = self.conf.embedding_dim;
+        let n_batch = q.strider().shape()[0];
+        let dtype = T::activation_dtype(&self.device);
+        let mut out =
+            T::alloc(&[1, n_batch, embed_dim], dtype, self.device.clone())?.resize(1, 0)?;
+        for seg in segments.iter_mut() {
+            let rows = (seg.start..seg.start + seg.len).collect::<Vec<_>>();
+            let (q, k, v) = (
+                self.take_rows(q, &rows)?,
+                self.take_rows(k, &rows)?,
+                self.take_rows(v, &rows)?,
+            );
+            let (q, k) = self.forward_rope(q, k, l, seg.pos, None, rope_mode)?;
+
+            std::mem::swap(&mut self.key_cache, &mut seg.slot.key_cache);
+            std::mem::swap(&mut self.value_cache, &mut seg.slot.value_cache);
+            let x = self.forward_multi_query_attention(q, k, v, l, seg.len);
+            std::mem::swap(&mut self.key_cache, &mut seg.slot.key_cache);
+            std::mem::swap(&mut self.value_cache, &mut seg.slot.value_cache);
+
+            out.concatenate(&x?.reshape(&[1, seg.len, embed_dim])?, 1)?;
+        }
+        out.reshape(&[n_batch, embed_dim])
+    }
+
+    // copy the rows of a 2d tensor into a new one
+    pub(crate) fn take_rows(&self, t: &T, rows: &[usize]) -> Result<T> {
+        let cols = t.strider().shape()[1];
+        let dtype = T::activation_dtype(&self.device);
+        let mut out = T::alloc(&[rows.len(), cols], dtype, self.device.clone())?;
+        out.copy_rows_from(t, rows)?;
+        Ok(out)
+    }
+
+    // attend q to the kv cache after saving k and v into it, the output is not projected yet
     fn forward_multi_query_attention(
         &mut self,
         q: T,
         k: T,
         v: T,
         l: usize,
-        _pos: usize,
-        n_kv_heads: usize,
-        n_heads: usize,
-        embed_dim: usize,
-        head_dim: usize,
         n_batch: usize,
     ) -> Result<T> {
+        let embed_dim = self.conf.embedding_dim;
+        let n_heads = self.conf.n_heads;
+        let n_kv_heads = self.conf.n_kv_heads;
+        let head_dim = self.conf.head_size();
+
         // save to kv cache in layout of (n_kv_heads, n_batch, head_dim)
         {
             let _t = self.metrics.save_kvcache_walltime.track();
+            if let (Some(tree), Some(_)) = (self.tree.as_mut(), &self.tree_mask) {
+                let kv_dim = n_kv_heads * head_dim;
+                tree.kv.push((
+                    k.dup()?.reshape(&[n_batch, kv_dim])?,
+                    v.dup()?.reshape(&[n_batch, kv_dim])?,
+                ));
+            }
             let k = k
                 .reshape(&[n_batch, n_kv_heads, head_dim])?
                 .transpose(&[1, 0, 2])?;
@@ -355,18 +1558,34 @@ impl<'a, T: Tensor> Llama2Runner<T> {
             let q = q
                 .reshape(&[n_batch, n_heads, head_dim])?
                 .transpose(&[1, 0, 2])?
-                .contiguous()?
-                .div_scalar_inplace((head_dim as f32).sqrt())?;
+                .contiguous()?;
 
             // get attention scores:
             // - key_cache: [n_kv_head, seq, head_size].transpose(0, 2, 1) => [n_kv_head, head_size, seq]
-            // - attn_scores = batch_matmul(q, key_cache) => [n_head, n_batch, seq]
+            // - attn_scores = batch_matmul(q, key_cache) / sqrt(head_size) => [n_head, n_batch, seq]
             // - attn_scores = softmax(attn_score, axis=2) => [n_head, n_batch, seq]
+            // the scaling and the soft cap are applied in the dot products
             let k_cache = self.key_cache[l].take().unwrap();
             let k_cache_strider_orig = k_cache.strider().clone();
             let k_cache = k_cache.transpose(&[0, 2, 1])?; // (n_kv_heads, head_size, seq)
             // (n_head, 1, head_size) @ (n_kv_heads, head_size, seq)
-            let attn = q.batch_matmul(&k_cache)?; // (n_head, n_batch, seq)
+            let epilogue =
+                MatmulEpilogue::new(1.0 / (head_dim as f32).sqrt(), self.conf.attn_soft_cap);
+            let attn = q.batch_matmul_epilogue(&k_cache, epilogue)?; // (n_head, n_batch, seq)
+            // a single query attends to all the positions in the cache, unless some of them
+            // are out of the sliding window
+            let sliding_window = self.conf.sliding_window;
+            let seq = attn.strider().shape()[2];
+            let attn = if n_batch > 1 || sliding_window.is_some_and(|w| seq > w) {
+                attn.causal_mask_inplace(sliding_window)?
+            } else {
+                attn
+            };
+            // the tokens of a tree only attend to their ancestors in the batch
+            let attn = match &self.tree_mask {
+                Some(mask) => attn.block_mask_inplace(mask)?,
+                None => attn,
+            };
             let attn = attn.softmax_inplace(2)?;
             self.key_cache[l].replace(k_cache.with_strider(k_cache_strider_orig)?);
 
@@ -387,9 +1606,7 @@ impl<'a, T: Tensor> Llama2Runner<T> {
                     .reshape(&[n_batch, embed_dim])?
             };
             self.value_cache[l].replace(v_cache.with_strider(v_cache_strider_orig)?);
-
-            // final matmul to get the output of the attention
-            self.weights.wo[l].matmul_vec(&x_with_attn)?
+            x_with_attn
         };
         Ok(x)
     }
@@ -401,32 +1618,204 @@ impl<'a, T: Tensor> Llama2Runner<T> {
         // ffn rmsnorm
         x = {
             x = x.rms_norm_inplace(1e-5)?;
-            x = x.mul_inplace(&self.weights.rms_ffn_weight[l])?;
+            if let Some(rms_ffn_weight) = &self.weights.rms_ffn_weight[l] {
+                x = x.mul_inplace(rms_ffn_weight)?;
+            }
             x
         };
 
+        // the residual connection is accumulated by the down projection
+        self.forward_mlp(x, l, activation, Some(x_orig_ffn))
+    }
+
+    // with the residual, the output of the down projection is added into it in place
+    fn forward_mlp(
+        &self,
+        x: T,
+        l: usize,
+        activation: Activation,
+        residual: Option<T>,
+    ) -> Result<T> {
         // Now for FFN in PyTorch we have: self.down_proj(F.silu(self.gate_proj(x)) * self.up_proj(x))
         // first calculate self.w1(x) and self.w3(x)
         // w1: (hidden_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, hidden_dim, )
         // w3: (hidden_dim, embed_dim) @ x (n_batch, embed_dim, ) => (n_batch, hidden_dim, )
-        let mut h1 = self.weights.ffn_gate_weight[l].matmul_vec(&x)?;
-        let h2 = self.weights.ffn_up_weight[l].matmul_vec(&x)?;
+        // without the gate (like Phi-2), it's self.down_proj(F.gelu(self.up_proj(x)))
+        let h2 = self.linear(&x, l, LoraTarget::FfnUp, None)?;
+        let h2 = add_bias(h2, self.weights.ffn_up_bias[l].as_ref())?;
+        let (h1, h2) = match &self.weights.ffn_gate_weight[l] {
+            Some(_) => (self.linear(&x, l, LoraTarget::FfnGate, None)?, Some(h2)),
+            None => (h2, None),
+        };
 
         // F.silu; silu(x)=x*σ(x),where σ(x) is the logistic sigmoid
-        h1 = match activation {
+        let mut h1 = match activation {
             Activation::SiLU => h1.silu_inplace()?,
             Activation::GeLU => h1.gelu_inplace()?,
         };
 
         // elementwise multiply with w3(x)
-        h1 = h1.mul_inplace(&h2)?;
+        if let Some(h2) = h2 {
+            h1 = h1.mul_inplace(&h2)?;
+        }
 
         // final matmul to get the output of the ffn
-        x = self.weights.ffn_down_weight[l].matmul_vec(&h1)?; // (n_batch, embed_dim)
+        let x = self.linear(&h1, l, LoraTarget::FfnDown, residual)?; // (n_batch, embed_dim)
+        add_bias(x, self.weights.ffn_down_bias[l].as_ref())
+    }
+
+    // the projection of x by the weight of the target, plus the delta b @ (a @ x) of the
+    // fused lora adapters. with acc, the result is added into it in place.
+    fn linear(&self, x: &T, l: usize, target: LoraTarget, acc: Option<T>) -> Result<T> {
+        let w = &self.weights;
+        let weight = w.weight(l, target).unwrap();
+        if self.imatrix.is_some() {
+            self.collect_activations(format!("blk.{}.{}.weight", l, target.gguf_name()), x)?;
+        }
+        let y = match (acc, w.scale(l, target)) {
+            (Some(acc), None) => weight.matmul_vec_acc(x, acc)?,
+            (None, None) => weight.matmul_vec(x)?,
+            // the scales of the output channels apply on the product of the weight alone,
+            // before the accumulation and the lora delta
+            (acc, Some(scale)) => {
+                let y = weight.matmul_vec(x)?.mul_inplace(scale)?;
+                match acc {
+                    Some(acc) => acc.add_inplace(&y)?,
+                    None => y,
+                }
+            }
+        };
+        match w.lora(l, target) {
+            Some(lora) => {
+                let h = lora.a.matmul_vec(x)?; // (n_batch, rank)
+                lora.b.matmul_vec_acc(&h, y)
+            }
+            None => Ok(y),
+        }
+    }
+}
+
+// the bias is broadcasted to every row of x
+/// the tokens of the prompt with the bos prepended, before the truncation of the runner.
+pub(crate) fn encode_prompt(tokenizer: &BpeTokenizer, prompt: &str) -> Result<Vec<usize>> {
+    // an empty prompt is the bos alone, without the space prefixed on encoding
+    if prompt.is_empty() {
+        return Ok(vec![tokenizer.bos_token()]);
+    }
+    tokenizer.encode(prompt, true, false)
+}
+
+fn add_bias<T: Tensor>(x: T, bias: Option<&T>) -> Result<T> {
+    match bias {
+        Some(bias) => x.add_inplace(bias),
+        None => Ok(x),
+    }
+}
+
+impl<'a> Llama2Runner<CpuTensor<'a>> {
+    /// snapshot the kv cache, the session can be saved to disk and restored later to continue
+    /// the generation from the current position.
+    pub fn session(&self) -> Result<Session> {
+        let dump = |cache: &Option<CpuTensor<'a>>| -> Result<(usize, Vec<f32>)> {
+            let cache = cache.as_ref().unwrap();
+            let buf = cache.buf().clone().dequantize(GGMLType::F32)?;
+            let buf = buf.as_f32_ref();
+            let data = cache.strider().iter().map(|i| buf[i]).collect();
+            Ok((cache.shape()[1], data))
+        };
+
+        let mut pos = 0;
+        let mut key_cache = Vec::with_capacity(self.conf.n_layers);
+        let mut value_cache = Vec::with_capacity(self.conf.n_layers);
+        for l in 0..self.conf.n_layers {
+            let (k_pos, k) = dump(&self.key_cache[l])?;
+            let (_, v) = dump(&self.value_cache[l])?;
+            pos = k_pos;
+            key_cache.push(k);
+            value_cache.push(v);
+        }
+
+        Ok(Session {
+            fingerprint: self.fingerprint,
+            n_layers: self.conf.n_layers,
+            n_kv_heads: self.conf.n_kv_heads,
+            head_size: self.conf.head_size(),
+            pos,
+            key_cache,
+            value_cache,
+        })
+    }
+
+    /// the checksums of the kv cache at every layer and position, diff two of them to find
+    /// where the caches diverge.
+    pub fn kv_snapshot(&self) -> Result<KvCacheSnapshot> {
+        Ok(KvCacheSnapshot::from_session(&self.session()?))
+    }
+
+    /// the shape of the kv cache of this runner, for `Session::load_checked()`.
+    pub fn session_shape(&self) -> SessionShape {
+        SessionShape {
+            n_layers: self.conf.n_layers,
+            n_kv_heads: self.conf.n_kv_heads,
+            head_size: self.conf.head_size(),
+            seq_len: self.seq_len,
+        }
+    }
+
+    /// load a session file, refusing the one of another shape before reading its caches.
+    /// pass it to `restore_session()`, which also checks the fingerprint.
+    pub fn load_session(&self, path: impl AsRef<std::path::Path>) -> Result<Session> {
+        Session::load_checked(path, &self.session_shape())
+    }
+
+    /// restore the kv cache from the session, the session must be created from the same model.
+    /// returns the position to forward the next token.
+    pub fn restore_session(&mut self, session: &Session) -> Result<usize> {
+        if session.fingerprint != self.fingerprint
+            || session.n_layers != self.conf.n_layers
+            || session.n_kv_heads != self.conf.n_kv_heads
+            || session.head_size != self.conf.head_size()
+        {
+            return Err((
+                ErrorKind::BadInput,
+                "the session is created from a different model",
+            )
+                .into());
+        }
+
+        let (n_kv_heads, head_size) = (self.conf.n_kv_heads, self.conf.head_size());
+        if session.pos > self.seq_len {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "the session has {} positions, larger than the seq len {}",
+                    session.pos, self.seq_len
+                ),
+            )
+                .into());
+        }
+
+        let n_elems = n_kv_heads * session.pos * head_size;
+        let caches_data = session.key_cache.iter().chain(session.value_cache.iter());
+        if caches_data.clone().any(|data| data.len() != n_elems) {
+            return Err((ErrorKind::BadInput, "the session kv cache size mismatch").into());
+        }
 
-        // residual connection
-        x = x.add_inplace(&x_orig_ffn)?;
-        Ok(x)
+        let caches = self.key_cache.iter_mut().zip(session.key_cache.iter());
+        let caches = caches.chain(self.value_cache.iter_mut().zip(session.value_cache.iter()));
+        for (cache, data) in caches {
+            let mut t = cache.take().unwrap().resize(1, 0)?;
+            if session.pos > 0 {
+                let src = CpuTensor::new(
+                    data.clone(),
+                    &[n_kv_heads, session.pos, head_size],
+                    self.device.clone(),
+                )?;
+                t.concatenate(&src, 1)?;
+            }
+            cache.replace(t);
+        }
+        Ok(session.pos)
     }
 }
 
@@ -435,12 +1824,21 @@ mod tests {
     use approx::assert_relative_eq;
     use crabml::backends::cpu::CpuTensorDevice;
     use crabml::backends::cpu::CpuTensorDeviceOptions;
+    use crabml::backends::cpu::ThreadPools;
     use crabml::backends::wgpu::WgpuTensorDevice;
     use crabml::backends::wgpu::WgpuTensorDeviceOptions;
     use crabml::gguf::GGUFFileLoader;
+    use crabml::gguf::GGUFLoadMode;
 
     use super::*;
+    use crate::testing::TempPath;
     use crate::CpuLlama2Model;
+    use crate::HiddenBias;
+    use crate::LoraAdapter;
+    use crate::LoraMode;
+    use crate::ModelLoadOptions;
+    use crate::SharedSession;
+    use crate::TruncationPolicy;
     use crate::WgpuLlama2Model;
 
     #[test]
@@ -449,13 +1847,15 @@ mod tests {
             GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
         let gf = gl.open()?;
 
+        // the output does not change with the workers spinning between the ops
         let device = CpuTensorDevice::with_options(CpuTensorDeviceOptions {
-            debug_named_tensors: false,
+            busy_poll: Some(std::time::Duration::from_micros(200)),
+            ..Default::default()
         });
         let lm = CpuLlama2Model::load(&gf, device.clone())?;
 
-        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0, device.exp_cache());
-        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, false)?;
+        let mut sampler = SamplerChain::new();
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
         let output = runner.prefill_and_generate("Lily is a cat", 30, &mut sampler)?;
         let s = output.collect::<Result<Vec<String>>>()?.join("");
 
@@ -476,11 +1876,48 @@ mod tests {
         assert_eq!(lm.conf.rope_dim, Some(48));
         assert_eq!(lm.conf.head_size(), 48);
 
-        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0, device.exp_cache());
-        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, false)?;
+        let mut sampler = SamplerChain::new();
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let output = runner.prefill_and_generate("Lily is a cute cat, ", 10, &mut sampler)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(s, "3 years old. She likes to play with her");
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_task_tags() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+
+        // the prompt and the tokens run on their own pools, the output is the same
+        let pools = ThreadPools::new()
+            .with_threads(TaskTag::Prefill, 3)?
+            .with_threads(TaskTag::Decode, 1)?;
+        let device = CpuTensorDevice::with_options(CpuTensorDeviceOptions {
+            thread_pools: pools.clone(),
+            ..Default::default()
+        });
+        let lm = CpuLlama2Model::load(&gf, device.clone())?;
+        let mut sampler = SamplerChain::new();
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        runner.forward_batch(&runner.prompt_tokens("Lily is a cute cat, ")?, 0)?;
+        assert_eq!(device.task_tag(), TaskTag::Prefill);
+        runner.reset_kv_cache()?;
         let output = runner.prefill_and_generate("Lily is a cute cat, ", 10, &mut sampler)?;
         let s = output.collect::<Result<Vec<String>>>()?.join("");
         assert_eq!(s, "3 years old. She likes to play with her");
+        assert_eq!(device.task_tag(), TaskTag::Decode);
+
+        // a pinned tag is kept on every pass
+        let device = CpuTensorDevice::with_options(CpuTensorDeviceOptions {
+            thread_pools: pools.with_threads(TaskTag::Background, 1)?,
+            task_tag: Some(TaskTag::Background),
+            ..Default::default()
+        });
+        let lm = CpuLlama2Model::load(&gf, device.clone())?;
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        runner.forward_batch(&runner.prompt_tokens("Lily is a cute cat, ")?, 0)?;
+        assert_eq!(device.task_tag(), TaskTag::Background);
         Ok(())
     }
 
@@ -494,11 +1931,782 @@ mod tests {
         assert_eq!(lm.conf.rope_dim, Some(48));
         assert_eq!(lm.conf.head_size(), 48);
 
-        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0, device.exp_cache());
-        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, true)?;
+        let mut sampler = SamplerChain::new();
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F16)?;
+        let output = runner.prefill_and_generate("Lily is a cute cat, ", 10, &mut sampler)?;
+        let s = output.collect::<Result<Vec<String>>>()?.join("");
+        assert_eq!(s, "3 years old. She likes to play with her");
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_first_n_layers() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+
+        let device = CpuTensorDevice::new();
+        let lm = CpuLlama2Model::load_with_options(
+            &gf,
+            device.clone(),
+            ModelLoadOptions::new().with_n_layers(3),
+        )?;
+
+        let mut sampler = SamplerChain::new();
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let output = runner.prefill_and_generate("Lily is a cute cat, ", 10, &mut sampler)?;
+        let tokens = output.collect::<Result<Vec<String>>>()?;
+        // the token sampled on the prefill comes first
+        assert_eq!(tokens.len(), 11);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_layer_ropes() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let mut lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+
+        let generate = |lm: &CpuLlama2Model| -> Result<String> {
+            let mut sampler = SamplerChain::new();
+            let mut runner = Llama2Runner::new(lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+            let output = runner.prefill_and_generate("Lily is a cute cat, ", 10, &mut sampler)?;
+            Ok(output.collect::<Result<Vec<String>>>()?.join(""))
+        };
+
+        // spelling out the global rope on every layer changes nothing
+        lm.conf.layer_ropes = (0..lm.conf.n_layers).map(|l| lm.conf.rope(l)).collect();
+        assert_eq!(generate(&lm)?, "3 years old. She likes to play with her");
+
+        // the layers without the rope or with another theta see the positions differently
+        lm.conf.layer_ropes[0].dim = 0;
+        assert_ne!(generate(&lm)?, "3 years old. She likes to play with her");
+        lm.conf.layer_ropes[0].dim = lm.conf.head_size();
+        lm.conf.layer_ropes[0].freq_base = 10.0;
+        assert_ne!(generate(&lm)?, "3 years old. She likes to play with her");
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_attn_soft_cap() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let mut lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        assert_eq!(lm.conf.attn_soft_cap, None);
+
+        let generate = |lm: &CpuLlama2Model| -> Result<String> {
+            let mut sampler = SamplerChain::new();
+            let mut runner = Llama2Runner::new(lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+            let output = runner.prefill_and_generate("Lily is a cute cat, ", 10, &mut sampler)?;
+            Ok(output.collect::<Result<Vec<String>>>()?.join(""))
+        };
+        assert_eq!(generate(&lm)?, "3 years old. She likes to play with her");
+
+        // a loose cap barely touches the scores, a tight one flattens the attention
+        lm.conf.attn_soft_cap = Some(1000.0);
+        assert_eq!(generate(&lm)?, "3 years old. She likes to play with her");
+        lm.conf.attn_soft_cap = Some(0.1);
+        assert_ne!(generate(&lm)?, "3 years old. She likes to play with her");
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_hidden_bias() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let embed_dim = lm.conf.embedding_dim;
+
+        let generate = |bias: HiddenBias| -> Result<String> {
+            let mut sampler = SamplerChain::new();
+            let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+            runner.add_hook(bias);
+            let output = runner.prefill_and_generate("Lily is a cute cat, ", 10, &mut sampler)?;
+            Ok(output.collect::<Result<Vec<String>>>()?.join(""))
+        };
+
+        // a zero bias, or a bias on the positions never reached, changes nothing
+        let bias = HiddenBias::new().with_vector(2, 0..usize::MAX, vec![0.0; embed_dim]);
+        assert_eq!(generate(bias)?, "3 years old. She likes to play with her");
+        let bias = HiddenBias::new().with_vector(2, 100..200, vec![5.0; embed_dim]);
+        assert_eq!(generate(bias)?, "3 years old. She likes to play with her");
+
+        // steering every position, or the soft prompt on the first positions
+        let bias = HiddenBias::new().with_vector(2, 0..usize::MAX, vec![5.0; embed_dim]);
+        assert_ne!(generate(bias)?, "3 years old. She likes to play with her");
+        let bias = HiddenBias::new().with_vectors(0, 1, vec![vec![-3.0; embed_dim]; 4]);
+        assert_ne!(generate(bias)?, "3 years old. She likes to play with her");
+
+        let bias = HiddenBias::new().with_vector(0, 0..1, vec![1.0; embed_dim + 1]);
+        assert!(generate(bias).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_layer_streaming() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+
+        let device = CpuTensorDevice::new();
+        let lm = CpuLlama2Model::load_with_options(
+            &gf,
+            device.clone(),
+            ModelLoadOptions::new().with_layer_streaming(2),
+        )?;
+
+        let mut sampler = SamplerChain::new();
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F16)?;
         let output = runner.prefill_and_generate("Lily is a cute cat, ", 10, &mut sampler)?;
         let s = output.collect::<Result<Vec<String>>>()?.join("");
         assert_eq!(s, "3 years old. She likes to play with her");
+        let streamer = lm.weights.streamer.as_ref().unwrap();
+        assert_eq!(streamer.resident_layers(), vec![0, 5]);
+
+        // the weights read into a buffer can not be streamed
+        let options = ModelLoadOptions::new()
+            .with_load_mode(GGUFLoadMode::Buffered)
+            .with_layer_streaming(2);
+        let gl = options.open("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        assert!(CpuLlama2Model::load_with_options(&gf, device, options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_embeddings() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+
+        let device = CpuTensorDevice::new();
+        let lm = CpuLlama2Model::load(&gf, device.clone())?;
+        let tokens = lm.tokenizer.encode("Lily is a cute cat", true, false)?;
+
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let options = EmbeddingOptions::new()
+            .with_pooling(crate::Pooling::Mean)
+            .with_normalize(true)
+            .with_capture_layers(true);
+        let e1 = runner.embeddings(&tokens, &options)?;
+        assert_eq!(e1.embedding.len(), lm.conf.embedding_dim);
+        let norm = e1.embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+        assert_relative_eq!(norm, 1.0, epsilon = 1e-5);
+        assert_eq!(e1.layers.len(), lm.conf.n_layers);
+        assert!(e1.layers.iter().all(|l| l.len() == lm.conf.embedding_dim));
+
+        // the kv cache is reset between the runs, and the layers are only captured on demand
+        let e2 = runner.embeddings(&tokens, &EmbeddingOptions::new().with_normalize(true))?;
+        let e3 = runner.embeddings(&tokens, &options)?;
+        assert!(e2.layers.is_empty());
+        assert_eq!(e1.embedding, e3.embedding);
+        assert_ne!(e1.embedding, e2.embedding);
+
+        assert!(runner.embeddings(&[], &options).is_err());
+
+        // the pooling in the metadata is taken by default, and can be overridden
+        let mut lm = lm;
+        lm.conf.pooling = Some(crate::Pooling::Mean);
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let e4 = runner.embeddings(&tokens, &EmbeddingOptions::new().with_normalize(true))?;
+        assert_eq!(e4.embedding, e1.embedding);
+        let options = EmbeddingOptions::new()
+            .with_pooling(crate::Pooling::Last)
+            .with_normalize(true);
+        let e5 = runner.embeddings(&tokens, &options)?;
+        assert_eq!(e5.embedding, e2.embedding);
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_requests() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let bos = lm.tokenizer.bos_token();
+
+        // an empty prompt is the bos alone
+        let mut sampler = SamplerChain::new();
+        let (pos, prev_token, _) = runner.prefill("", &mut sampler)?;
+        assert_eq!((pos, prev_token), (1, bos));
+
+        // no step generates nothing
+        runner.reset_kv_cache()?;
+        let output = runner.prefill_and_generate("", 0, &mut sampler)?;
+        assert_eq!(output.count(), 0);
+
+        // no max_tokens only evaluates the prompt
+        runner.reset_kv_cache()?;
+        let mut stream = runner.stream("Lily", &mut sampler, GenerationOptions::new(0))?;
+        assert_eq!(stream.next().map(|t| t.is_ok()), None);
+        assert_eq!(stream.finish_reason(), Some(crate::FinishReason::Length));
+        let pos = stream.pos();
+        drop(stream);
+        assert_eq!(runner.kv_cache_len(), pos);
+
+        // no tokens to forward
+        runner.reset_kv_cache()?;
+        let err = runner.forward_batch(&[], 0).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadInput);
+        let err = runner.forward_batch_all(&[], 0).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadInput);
+        assert_eq!(runner.kv_cache_len(), 0);
+
+        // the embedding of an empty prompt is the one of the bos
+        let options = EmbeddingOptions::new().with_normalize(true);
+        let e1 = runner.embed_prompt("", &options)?;
+        let e2 = runner.embeddings(&[bos], &options)?;
+        assert_eq!(e1.embedding, e2.embedding);
+        Ok(())
+    }
+
+    #[test]
+    fn test_perplexity() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let story = "Lily is a cute cat, 3 years old. She likes to play with her ball.";
+        let tokens = lm.tokenizer.encode(story, false, false)?;
+
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let mut progress = vec![];
+        let ppl = runner.perplexity(&tokens, &PerplexityOptions::new(), |done, n, ppl| {
+            progress.push((done, n, ppl))
+        })?;
+        assert_eq!(ppl.n_scored(), tokens.len());
+        assert!(ppl.logprobs.iter().all(|l| *l <= 0.0));
+        assert!(ppl.perplexity() > 1.0 && ppl.perplexity() < 20.0);
+        assert_eq!(progress, vec![(1, 1, ppl.perplexity())]);
+
+        // the smaller batches score the same
+        let options = PerplexityOptions::new().with_n_batch(5);
+        let ppl2 = runner.perplexity(&tokens, &options, |_, _, _| {})?;
+        assert_relative_eq!(&ppl.logprobs[..], &ppl2.logprobs[..], epsilon = 1e-3);
+
+        // the chunks without the bos skip their first tokens, with less context to predict
+        // the rest
+        let options = PerplexityOptions::new().with_n_ctx(8).with_add_bos(false);
+        let mut n_chunks = 0;
+        let ppl3 = runner.perplexity(&tokens, &options, |_, n, _| n_chunks = n)?;
+        assert_eq!(n_chunks, tokens.len().div_ceil(8));
+        assert_eq!(ppl3.n_scored(), tokens.len() - n_chunks);
+        assert!(ppl3.perplexity() > ppl.perplexity());
+
+        let options = PerplexityOptions::new().with_n_ctx(1);
+        assert!(runner.perplexity(&tokens, &options, |_, _, _| {}).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_importance_matrix() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let story = "Lily is a cute cat, 3 years old. She likes to play with her ball.";
+        let tokens = lm.tokenizer.encode(story, false, false)?;
+
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let options = ImatrixOptions::new().with_n_ctx(8);
+        let mut progress = vec![];
+        let imatrix =
+            runner.importance_matrix(&tokens, &options, |done, n| progress.push((done, n)))?;
+        let n_chunks = tokens.len().div_ceil(7);
+        assert_eq!(progress.last(), Some(&(n_chunks, n_chunks)));
+        assert_eq!(imatrix.last_call, n_chunks as i32);
+        // 7 weights of the 6 layers, and the output tied to the token embedding
+        assert_eq!(imatrix.len(), 6 * 7 + 1);
+        let ffn_down = imatrix.get("blk.0.ffn_down.weight").unwrap();
+        assert_eq!(ffn_down.len(), lm.conf.hidden_dim);
+        assert!(ffn_down.iter().all(|v| *v >= 0.0) && ffn_down.iter().any(|v| *v > 0.0));
+        let wq = imatrix.get("blk.5.attn_q.weight").unwrap();
+        assert_eq!(wq.len(), lm.conf.embedding_dim);
+
+        // the smaller batches collect the same
+        let options = options.with_n_batch(3);
+        let imatrix2 = runner.importance_matrix(&tokens, &options, |_, _| {})?;
+        assert_relative_eq!(
+            &ffn_down[..],
+            &imatrix2.get("blk.0.ffn_down.weight").unwrap()[..],
+            epsilon = 1e-3,
+            max_relative = 1e-3
+        );
+
+        // the runner stops collecting after the run
+        assert!(runner.imatrix.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_forward_batch() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+
+        let device = CpuTensorDevice::new();
+        let lm = CpuLlama2Model::load(&gf, device.clone())?;
+        let tokens = [1, 365, 2354, 338, 263, 6635];
+
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let mut logits_seq = vec![];
+        for (pos, token) in tokens.iter().enumerate() {
+            logits_seq = runner.forward(*token, pos)?.to_vec();
+        }
+
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let logits_batch = runner.forward_batch(&tokens, 0)?.to_vec();
+        assert_relative_eq!(logits_seq[..], logits_batch[..], epsilon = 1e-3);
+
+        // continue decoding after the batched prefill
+        let logits_seq = runner.forward(263, tokens.len())?.to_vec();
+        assert_eq!(logits_seq.len(), lm.conf.vocab_size);
+        Ok(())
+    }
+
+    #[test]
+    fn test_forward_positions() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+
+        let device = CpuTensorDevice::new();
+        let lm = CpuLlama2Model::load(&gf, device.clone())?;
+        let tokens = [1, 365, 2354, 338, 263, 6635];
+
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let logits_batch = runner.forward_batch(&tokens, 0)?.to_vec();
+
+        // the contiguous positions are the same as forward_batch()
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let logits = runner
+            .forward_positions(&tokens, &[0, 1, 2, 3, 4, 5])?
+            .to_vec();
+        assert_relative_eq!(logits_batch[..], logits[..], epsilon = 1e-3);
+
+        // rope only sees the relative positions, shifting them all keeps the logits
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let logits = runner
+            .forward_positions(&tokens, &[40, 41, 42, 43, 44, 45])?
+            .to_vec();
+        assert_relative_eq!(logits_batch[..], logits[..], epsilon = 1e-2);
+
+        // the positions with a gap in a batch are the same as decoding them one by one
+        let positions = [0, 1, 2, 9, 10, 11];
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let logits_gap = runner.forward_positions(&tokens, &positions)?.to_vec();
+        assert_eq!(runner.kv_cache_len(), tokens.len());
+        assert!(
+            logits_gap
+                .iter()
+                .zip(logits_batch.iter())
+                .any(|(a, b)| (a - b).abs() > 1e-2)
+        );
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let mut logits_seq = vec![];
+        for (token, pos) in tokens.iter().zip(positions) {
+            logits_seq = runner.forward_positions(&[*token], &[pos])?.to_vec();
+        }
+        assert_relative_eq!(logits_gap[..], logits_seq[..], epsilon = 1e-3);
+
+        let err = runner.forward_positions(&tokens, &[0, 1]).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadInput);
+        Ok(())
+    }
+
+    #[test]
+    fn test_forward_tree() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+
+        let device = CpuTensorDevice::new();
+        let lm = CpuLlama2Model::load(&gf, device.clone())?;
+        let vocab_size = lm.conf.vocab_size;
+        let prompt = [1, 365, 2354];
+        let row = |logits: &[f32], i: usize| logits[i * vocab_size..(i + 1) * vocab_size].to_vec();
+
+        // the branches of 338 -> 263 -> 6635 and 338 -> 2354 in one pass
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        runner.forward_batch(&prompt, 0)?;
+        let tokens = [338, 263, 2354, 6635];
+        let parents = [None, Some(0), Some(0), Some(1)];
+        let logits_tree = runner.forward_tree(&tokens, &parents)?;
+        assert_eq!(runner.kv_cache_len(), prompt.len() + tokens.len());
+
+        let mut runner_a = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        runner_a.forward_batch(&prompt, 0)?;
+        let logits_a = runner_a.forward_batch_all(&[338, 263, 6635], prompt.len())?;
+        let mut runner_b = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        runner_b.forward_batch(&prompt, 0)?;
+        let logits_b = runner_b.forward_batch_all(&[338, 2354], prompt.len())?;
+        for (node, expected) in [
+            (0, row(&logits_a, 0)),
+            (1, row(&logits_a, 1)),
+            (3, row(&logits_a, 2)),
+            (2, row(&logits_b, 1)),
+        ] {
+            assert_relative_eq!(row(&logits_tree, node)[..], expected[..], epsilon = 1e-3);
+        }
+
+        // keep the first branch and continue decoding after it
+        runner.keep_tree_nodes(&[0, 1, 3])?;
+        assert_eq!(runner.kv_cache_len(), 6);
+        let logits = runner.forward(263, 6)?.to_vec();
+        let expected = runner_a.forward(263, 6)?.to_vec();
+        assert_relative_eq!(logits[..], expected[..], epsilon = 1e-3);
+
+        assert!(runner.keep_tree_nodes(&[0]).is_err());
+        let err = runner.forward_tree(&[1, 2], &[None, Some(1)]).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadInput);
+        Ok(())
+    }
+
+    #[test]
+    fn test_forward_batch_deterministic() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+
+        let device = CpuTensorDevice::with_options(CpuTensorDeviceOptions {
+            deterministic: true,
+            ..Default::default()
+        });
+        let lm = CpuLlama2Model::load(&gf, device.clone())?;
+        let tokens = [1, 365, 2354, 338];
+
+        // the strict mode gives the same bits on the batched verification of the tokens
+        // decoded one by one, like on the speculative decoding
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F16)?;
+        let mut logits_seq = vec![];
+        for (pos, token) in tokens.iter().enumerate() {
+            logits_seq.push(runner.forward(*token, pos)?.to_vec());
+        }
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F16)?;
+        let logits_batch = runner.forward_batch_all(&tokens, 0)?;
+        for (i, logits) in logits_batch.chunks(lm.conf.vocab_size).enumerate() {
+            assert_eq!(logits_seq[i], logits, "{}", i);
+        }
+
+        // the simd kernels on the quantized activations pick the same token
+        let lm2 = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut runner = Llama2Runner::new(&lm2, TensorMetrics::default(), 200, GGMLType::F16)?;
+        let logits = runner.forward_batch(&tokens, 0)?.to_vec();
+        assert_ne!(logits_seq[3], logits);
+        let argmax = |v: &[f32]| (0..v.len()).max_by(|a, b| v[*a].total_cmp(&v[*b])).unwrap();
+        assert_eq!(argmax(&logits_seq[3]), argmax(&logits));
+        Ok(())
+    }
+
+    // write the lora tensors of (name, shape, data) in the safetensors format of PEFT
+    fn write_safetensors(path: &std::path::Path, tensors: &[(&str, [usize; 2], Vec<f32>)]) {
+        let mut header = vec![];
+        let mut data = vec![];
+        for (name, shape, values) in tensors {
+            let start = data.len();
+            values.iter().for_each(|v| data.extend(v.to_le_bytes()));
+            header.push(format!(
+                r#""{}": {{"dtype": "F32", "shape": [{}, {}], "data_offsets": [{}, {}]}}"#,
+                name,
+                shape[0],
+                shape[1],
+                start,
+                data.len()
+            ));
+        }
+        let header = format!("{{{}}}", header.join(", "));
+        let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+        bytes.extend(header.as_bytes());
+        bytes.extend(data);
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn test_lora_adapter() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let tokens = [1, 365, 2354, 338, 263, 6635];
+        let forward = |lm: &CpuLlama2Model| -> Result<Vec<f32>> {
+            let mut runner = Llama2Runner::new(lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+            Ok(runner.forward_batch(&tokens, 0)?.to_vec())
+        };
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let logits_base = forward(&lm)?;
+
+        // rank 4 on the q and the down projections, dim 288 and hidden_dim 768
+        let values = |n: usize| {
+            (0..n)
+                .map(|i| ((i * 7919) % 23) as f32 / 230.0 - 0.05)
+                .collect()
+        };
+        let path = TempPath::new("lora.safetensors");
+        let prefix = "base_model.model.model.layers";
+        write_safetensors(path.path(), &[
+            (
+                &format!("{}.0.self_attn.q_proj.lora_A.weight", prefix),
+                [4, 288],
+                values(4 * 288),
+            ),
+            (
+                &format!("{}.0.self_attn.q_proj.lora_B.weight", prefix),
+                [288, 4],
+                values(288 * 4),
+            ),
+            (
+                &format!("{}.1.mlp.down_proj.lora_A.weight", prefix),
+                [4, 768],
+                values(4 * 768),
+            ),
+            (
+                &format!("{}.1.mlp.down_proj.lora_B.weight", prefix),
+                [288, 4],
+                values(288 * 4),
+            ),
+        ]);
+        let adapter = LoraAdapter::load(path.path())?
+            .with_alpha(8.0)
+            .with_scale(0.5);
+        assert_eq!(adapter.targets().collect::<Vec<_>>(), vec![
+            (0, LoraTarget::AttnQ),
+            (1, LoraTarget::FfnDown)
+        ]);
+
+        let options = ModelLoadOptions::new().with_lora(adapter.clone());
+        let lm_merged = CpuLlama2Model::load_with_options(&gf, CpuTensorDevice::new(), options)?;
+        assert_eq!(lm_merged.weights.wq[0].dtype(), GGMLType::F32);
+        assert_ne!(lm_merged.fingerprint, lm.fingerprint);
+        let logits_merged = forward(&lm_merged)?;
+        assert!(
+            logits_merged
+                .iter()
+                .zip(logits_base.iter())
+                .any(|(a, b)| (a - b).abs() > 1e-2)
+        );
+
+        // the fused adapters give the same logits as the merged ones, on the cpu and the gpu
+        let options = ModelLoadOptions::new()
+            .with_lora(adapter.clone())
+            .with_lora_mode(LoraMode::Fused);
+        let lm_fused = CpuLlama2Model::load_with_options(&gf, CpuTensorDevice::new(), options)?;
+        assert_eq!(lm_fused.fingerprint, lm_merged.fingerprint);
+        // the attention scores are scaled after the dot products, which shifts the rounding
+        // through the f16 silu table a bit
+        assert_relative_eq!(forward(&lm_fused)?[..], logits_merged[..], epsilon = 2e-3);
+
+        let device_wgpu = WgpuTensorDevice::new(
+            WgpuTensorDeviceOptions::new().with_staging_buf_bytes(lm.conf.vocab_size * 4),
+        );
+        let lm_wgpu = WgpuLlama2Model::from_cpu(&lm_fused, device_wgpu)?;
+        let mut runner = Llama2Runner::new(&lm_wgpu, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let logits_wgpu = runner.forward_batch(&tokens, 0)?.to_vec();
+        assert_relative_eq!(logits_wgpu[..], logits_merged[..], epsilon = 1e-2);
+
+        // a disabled adapter changes nothing
+        let options = ModelLoadOptions::new().with_lora(adapter.with_scale(0.0));
+        let lm_disabled = CpuLlama2Model::load_with_options(&gf, CpuTensorDevice::new(), options)?;
+        assert_eq!(forward(&lm_disabled)?, logits_base);
+
+        // the adapter on a missing layer is refused
+        let adapter =
+            LoraAdapter::new().with_tensor(6, LoraTarget::AttnQ, values(288), values(288), 1)?;
+        let options = ModelLoadOptions::new().with_lora(adapter);
+        assert!(CpuLlama2Model::load_with_options(&gf, CpuTensorDevice::new(), options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resize_kv_cache() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let device = CpuTensorDevice::new();
+        let lm = CpuLlama2Model::load(&gf, device.clone())?;
+        let mut sampler = SamplerChain::new();
+
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F16)?;
+        let expected = runner
+            .prefill_and_generate("Lily is a cat", 10, &mut sampler)?
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+
+        // grow the context after prefilling
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 8, GGMLType::F16)?;
+        let (pos, prev_token, token) = runner.prefill("Lily is a cat", &mut sampler)?;
+        runner.resize_kv_cache(200)?;
+        assert_eq!(runner.seq_len(), 200);
+        assert_eq!(runner.kv_cache_len(), pos);
+        let output = runner
+            .generate(pos, prev_token, token, 10, &mut sampler)
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        assert_eq!(output, expected);
+
+        // a pos past the kv cache yields the sampled token only, instead of underflowing
+        let output = runner
+            .generate(300, prev_token, token, 10, &mut sampler)
+            .collect::<Result<Vec<String>>>()?;
+        assert_eq!(output.len(), 1);
+
+        // shrink it after the conversation ends
+        assert!(runner.resize_kv_cache(4).is_err());
+        runner.reset_kv_cache()?;
+        runner.resize_kv_cache(4)?;
+        assert_eq!(runner.kv_cache_len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefill_truncation() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut sampler = SamplerChain::new();
+        let prompt = "Lily is a cute cat, she likes to play with her ball in the garden";
+
+        // fails before the forward pass by default
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 8, GGMLType::F16)?;
+        let err = runner.prefill(prompt, &mut sampler).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadInput);
+        assert_eq!(runner.kv_cache_len(), 0);
+
+        runner.set_truncation(
+            TruncationOptions::new(TruncationPolicy::Left)
+                .with_keep(2)
+                .with_reserve(3),
+        );
+        let (pos, _, _) = runner.prefill(prompt, &mut sampler)?;
+        assert_eq!(pos, 5);
+        assert_eq!(runner.kv_cache_len(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_budget_fallback() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let conf = lm.conf.clone();
+        let prompt = "Lily is a cute cat, she likes to play";
+        let metrics = TensorMetrics::default();
+
+        let expected = {
+            let mut runner = Llama2Runner::new(&lm, metrics.clone(), 200, GGMLType::F32)?;
+            runner.prefill(prompt, &mut SamplerChain::new())?
+        };
+
+        // the kv cache leaves no room for the scratch
+        let kv_bytes = memory::kv_cache_bytes(&conf, 200, GGMLType::F32);
+        let per_token = memory::scratch_bytes_per_token(&conf, 200);
+        // the head size of 48 can't fall back to a q8_0 kv cache
+        let options = MemoryOptions::new()
+            .with_budget(kv_bytes)
+            .with_q8_0_kv_cache(true);
+        let err = Llama2Runner::new_with_memory(&lm, metrics.clone(), 200, GGMLType::F32, options)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind, ErrorKind::OutOfMemory);
+        let oom = err.as_out_of_memory().unwrap();
+        assert_eq!(oom.requested, kv_bytes + per_token);
+        assert_eq!(oom.budget, Some(kv_bytes));
+
+        // room for 3 tokens in a batch
+        let options = MemoryOptions::new().with_budget(kv_bytes + 3 * per_token);
+        let mut runner = Llama2Runner::new_with_memory(
+            &lm,
+            metrics.clone(),
+            200,
+            GGMLType::F32,
+            options.clone(),
+        )?;
+        assert_eq!(runner.kv_cache_dtype(), GGMLType::F32);
+        assert_eq!(runner.max_batch(), Some(3));
+        let err = runner
+            .prefill(prompt, &mut SamplerChain::new())
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::OutOfMemory);
+        assert_eq!(runner.kv_cache_len(), 0);
+
+        let options = options.with_smaller_batch(true);
+        let mut runner =
+            Llama2Runner::new_with_memory(&lm, metrics.clone(), 200, GGMLType::F32, options)?;
+        let (pos, prev_token, token) = runner.prefill(prompt, &mut SamplerChain::new())?;
+        assert_eq!((pos, prev_token, token), expected);
+        assert_eq!(runner.kv_cache_len(), pos);
+        Ok(())
+    }
+
+    #[test]
+    fn test_session_save_and_restore() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let device = CpuTensorDevice::new();
+        let lm = CpuLlama2Model::load(&gf, device.clone())?;
+
+        let mut sampler = SamplerChain::new();
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F16)?;
+        let (pos, prev_token, token) = runner.prefill("Lily is a cat", &mut sampler)?;
+        let session_path = TempPath::new("session.bin");
+        let path = session_path.path();
+        runner.session()?.save(path)?;
+        let expected = runner
+            .generate(pos, prev_token, token, 10, &mut sampler)
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F16)?;
+        let session = runner.load_session(path)?;
+        assert_eq!(Session::load(path)?, session);
+
+        // the header is checked against the model and the file before the caches are read
+        let small = Llama2Runner::new(&lm, TensorMetrics::default(), 2, GGMLType::F16)?;
+        assert!(small.load_session(path).is_err());
+        let mut shape = runner.session_shape();
+        shape.n_layers += 1;
+        assert!(Session::load_checked(path, &shape).is_err());
+        let mut bytes = std::fs::read(path).unwrap();
+        // a pos of u32::MAX
+        bytes[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(path, &bytes).unwrap();
+        assert!(Session::load(path).is_err());
+        assert_eq!(runner.restore_session(&session)?, pos);
+        let output = runner
+            .generate(pos, prev_token, token, 10, &mut sampler)
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        assert_eq!(output, expected);
+
+        // pass the session through the shared memory, as another process would open it
+        let path = TempPath::new("shared-session.bin");
+        let mut region = SharedSession::create(path.path(), session.bytes())?;
+        assert!(region.load()?.is_none());
+        region.store(&session)?;
+        let other = SharedSession::open(path.path())?;
+        drop(path);
+        let shared = other.load()?.unwrap();
+        assert_eq!(shared, session);
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F16)?;
+        assert_eq!(runner.restore_session(&shared)?, pos);
+        let output = runner
+            .generate(pos, prev_token, token, 10, &mut sampler)
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        assert_eq!(output, expected);
+        let mut larger = session.clone();
+        larger.key_cache[0].push(0.0);
+        assert!(region.store(&larger).is_err());
+
+        // refuse the session from another model
+        let session = Session {
+            fingerprint: session.fingerprint + 1,
+            ..session
+        };
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F16)?;
+        assert!(runner.restore_session(&session).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_q8_0_kvcache_requires_aligned_head_size() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+
+        let device = CpuTensorDevice::new();
+        let lm = CpuLlama2Model::load(&gf, device.clone())?;
+        assert_eq!(lm.conf.head_size(), 48);
+
+        let runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::Q8_0);
+        assert!(runner.is_err());
         Ok(())
     }
 
@@ -512,14 +2720,87 @@ mod tests {
         assert_eq!(lm.conf.rope_dim, Some(4));
         assert_eq!(lm.conf.head_size(), 4);
 
-        let mut sampler = Llama2Sampler::new(lm.conf.vocab_size, 0.0, 0.0, device.exp_cache());
-        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, false)?;
+        let mut sampler = SamplerChain::new();
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
         let output = runner.prefill_and_generate("Lily is a cute cat, ", 10, &mut sampler)?;
         let s = output.collect::<Result<Vec<String>>>()?.join("");
         assert_eq!(s, "3 year old. She likes to play with her friends");
         Ok(())
     }
 
+    #[test]
+    fn test_f16_activations() -> Result<()> {
+        for (path, expected) in [
+            (
+                "../testdata/tinyllamas-stories-15m-q8_0.gguf",
+                "3 years old. She likes to play with her",
+            ),
+            (
+                "../testdata/TinyLLama-v0-5M-F16.gguf",
+                "3 year old. She likes to play with her friends",
+            ),
+        ] {
+            let gl = GGUFFileLoader::new(path)?;
+            let gf = gl.open()?;
+            let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+            let device_f16 = CpuTensorDevice::with_options(CpuTensorDeviceOptions {
+                activation_dtype: GGMLType::F16,
+                ..Default::default()
+            });
+            let lm_f16 = CpuLlama2Model::load(&gf, device_f16)?;
+
+            let tokens = lm.tokenizer.encode("Lily is a cute cat, ", true, false)?;
+            let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F16)?;
+            let mut runner_f16 =
+                Llama2Runner::new(&lm_f16, TensorMetrics::default(), 200, GGMLType::F16)?;
+            let logits = runner.forward_batch_all(&tokens, 0)?;
+            let logits_f16 = runner_f16.forward_batch_all(&tokens, 0)?;
+
+            // the logits of every position are close to the f32 pipeline's, and the last one
+            // picks the same next token. the earlier positions may flip on the near ties.
+            let vocab_size = lm.conf.vocab_size;
+            let argmax = |row: &[f32]| {
+                (0..row.len())
+                    .max_by(|a, b| row[*a].total_cmp(&row[*b]))
+                    .unwrap()
+            };
+            for (row, row_f16) in logits
+                .chunks_exact(vocab_size)
+                .zip(logits_f16.chunks_exact(vocab_size))
+            {
+                let max_abs = row.iter().fold(0.0f32, |m, v| m.max(v.abs()));
+                let mean_diff = row
+                    .iter()
+                    .zip(row_f16)
+                    .map(|(a, b)| (a - b).abs())
+                    .sum::<f32>()
+                    / row.len() as f32;
+                assert!(
+                    mean_diff < 0.02 * max_abs,
+                    "{}: {} {}",
+                    path,
+                    mean_diff,
+                    max_abs
+                );
+            }
+            let last = logits.len() - vocab_size;
+            assert_eq!(
+                argmax(&logits[last..]),
+                argmax(&logits_f16[last..]),
+                "{}",
+                path
+            );
+
+            runner_f16.reset_kv_cache()?;
+            let mut sampler = SamplerChain::new();
+            let output =
+                runner_f16.prefill_and_generate("Lily is a cute cat, ", 10, &mut sampler)?;
+            let s = output.collect::<Result<Vec<String>>>()?.join("");
+            assert_eq!(s, expected, "{}", path);
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_generate_f32_gpu() -> Result<()> {
         let gl: GGUFFileLoader =
@@ -528,6 +2809,7 @@ mod tests {
 
         let device_cpu = CpuTensorDevice::with_options(CpuTensorDeviceOptions {
             debug_named_tensors: true,
+            ..Default::default()
         });
         let model_cpu = CpuLlama2Model::load(&gf, device_cpu.clone())?;
 
@@ -538,10 +2820,11 @@ mod tests {
         );
         let model_wgpu = WgpuLlama2Model::from_cpu(&model_cpu, device_wgpu.clone())?;
 
-        let mut sampler =
-            Llama2Sampler::new(model_cpu.conf.vocab_size, 0.0, 0.0, device_cpu.exp_cache());
-        let mut runner_cpu = Llama2Runner::new(&model_cpu, TensorMetrics::default(), 200, false)?;
-        let mut runner_wgpu = Llama2Runner::new(&model_wgpu, TensorMetrics::default(), 200, false)?;
+        let mut sampler = SamplerChain::new();
+        let mut runner_cpu =
+            Llama2Runner::new(&model_cpu, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let mut runner_wgpu =
+            Llama2Runner::new(&model_wgpu, TensorMetrics::default(), 200, GGMLType::F32)?;
 
         let output_cpu = runner_cpu
             .prefill_and_generate("Lily is a cat", 30, &mut sampler)?
@@ -583,4 +2866,63 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_generate_q8_0_gpu() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+
+        let device_cpu = CpuTensorDevice::new();
+        let model_cpu = CpuLlama2Model::load(&gf, device_cpu.clone())?;
+
+        let device_wgpu = WgpuTensorDevice::new(
+            WgpuTensorDeviceOptions::new().with_staging_buf_bytes(model_cpu.conf.vocab_size * 4),
+        );
+        let model_wgpu = WgpuLlama2Model::from_cpu(&model_cpu, device_wgpu.clone())?;
+        // the matmul weights stay quantized on the gpu
+        assert_eq!(model_wgpu.weights.wq[0].dtype(), GGMLType::Q8_0);
+        assert_eq!(model_wgpu.weights.token_embed.dtype(), GGMLType::F32);
+
+        let mut sampler = SamplerChain::new();
+        let mut runner_wgpu =
+            Llama2Runner::new(&model_wgpu, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let output_wgpu = runner_wgpu
+            .prefill_and_generate("Lily is a cute cat, ", 10, &mut sampler)?
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        assert_eq!(output_wgpu, "3 years old. She likes to play with her");
+
+        // the activations of the previous tokens are released to the pool for reuse
+        assert!(device_wgpu.pooled_buffers() > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_f32_gpu_sample_on_device() -> Result<()> {
+        let gl: GGUFFileLoader =
+            GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+
+        let device_cpu = CpuTensorDevice::new();
+        let model_cpu = CpuLlama2Model::load(&gf, device_cpu)?;
+
+        // only the sampled token id is read back, the staging buffer does not hold the logits
+        let device_wgpu =
+            WgpuTensorDevice::new(WgpuTensorDeviceOptions::new().with_staging_buf_bytes(4));
+        let model_wgpu = WgpuLlama2Model::from_cpu(&model_cpu, device_wgpu)?;
+
+        let mut sampler = WgpuSampler::new(0.0, 0, 1.0);
+        let mut runner =
+            Llama2Runner::new(&model_wgpu, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let output = runner
+            .prefill_and_generate("Lily is a cat", 30, &mut sampler)?
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+
+        assert_eq!(
+            output,
+            " who likes to play with yarn. She has many colors of yarn in her box. She likes to make shapes with yarn and show"
+        );
+        Ok(())
+    }
 }
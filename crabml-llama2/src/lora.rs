@@ -0,0 +1,613 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use crabml::backends::cpu::parallel;
+use crabml::backends::cpu::CpuTensor;
+use crabml::backends::cpu::CpuTensorDevice;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::gguf::GGUFFileLoader;
+use crabml::tensor::Tensor;
+use half::bf16;
+use half::f16;
+
+/// the projections a LoRA adapter can target.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum LoraTarget {
+    AttnQ,
+    AttnK,
+    AttnV,
+    AttnOutput,
+    FfnGate,
+    FfnUp,
+    FfnDown,
+}
+
+impl LoraTarget {
+    /// the name in the gguf tensor names, like `blk.0.attn_q.weight`.
+    pub fn gguf_name(&self) -> &'static str {
+        match self {
+            Self::AttnQ => "attn_q",
+            Self::AttnK => "attn_k",
+            Self::AttnV => "attn_v",
+            Self::AttnOutput => "attn_output",
+            Self::FfnGate => "ffn_gate",
+            Self::FfnUp => "ffn_up",
+            Self::FfnDown => "ffn_down",
+        }
+    }
+
+    pub fn from_gguf_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().find(|t| t.gguf_name() == name).copied()
+    }
+
+    /// the module names of the huggingface models in the PEFT adapters, including the ones
+    /// of Phi-2.
+    pub fn from_peft_name(name: &str) -> Option<Self> {
+        match name {
+            "q_proj" => Some(Self::AttnQ),
+            "k_proj" => Some(Self::AttnK),
+            "v_proj" => Some(Self::AttnV),
+            "o_proj" | "dense" => Some(Self::AttnOutput),
+            "gate_proj" => Some(Self::FfnGate),
+            "up_proj" | "fc1" => Some(Self::FfnUp),
+            "down_proj" | "fc2" => Some(Self::FfnDown),
+            _ => None,
+        }
+    }
+
+    pub(crate) const ALL: [Self; 7] = [
+        Self::AttnQ,
+        Self::AttnK,
+        Self::AttnV,
+        Self::AttnOutput,
+        Self::FfnGate,
+        Self::FfnUp,
+        Self::FfnDown,
+    ];
+}
+
+/// how the adapters are applied on loading the model.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum LoraMode {
+    /// add the deltas into the weights once, the targeted weights are dequantized into f32.
+    #[default]
+    Merge,
+    /// keep the base weights as they are, and add the low rank delta after every matmul of
+    /// the targeted weights.
+    Fused,
+}
+
+/// the low rank matrices of a projection, delta = b @ a.
+#[derive(Clone)]
+struct LoraPair {
+    a: Vec<f32>, // (rank, n_in)
+    b: Vec<f32>, // (n_out, rank)
+    rank: usize,
+}
+
+/// a LoRA adapter loaded on the host, which is applied on loading the model with
+/// `ModelLoadOptions::with_lora()`.
+#[derive(Clone)]
+pub struct LoraAdapter {
+    pairs: BTreeMap<(usize, LoraTarget), LoraPair>,
+    alpha: Option<f32>,
+    scale: f32,
+}
+
+impl fmt::Debug for LoraAdapter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoraAdapter")
+            .field("n_tensors", &self.pairs.len())
+            .field("alpha", &self.alpha)
+            .field("scale", &self.scale)
+            .finish()
+    }
+}
+
+impl Default for LoraAdapter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LoraAdapter {
+    pub fn new() -> Self {
+        Self {
+            pairs: BTreeMap::new(),
+            alpha: None,
+            scale: 1.0,
+        }
+    }
+
+    /// load the adapter from a `.safetensors` file in the PEFT layout, or a gguf file
+    /// converted by llama.cpp.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if path.extension().is_some_and(|ext| ext == "safetensors") {
+            Self::from_safetensors(path)
+        } else {
+            Self::from_gguf(path)
+        }
+    }
+
+    /// the tensors are named like `blk.0.attn_q.weight.lora_a`, the alpha is read from the
+    /// `adapter.lora.alpha` metadata.
+    pub fn from_gguf(path: impl AsRef<Path>) -> Result<Self> {
+        let gl = GGUFFileLoader::new(&path.as_ref().to_string_lossy())?;
+        let gf = gl.open()?;
+        let device = CpuTensorDevice::new();
+
+        let mut adapter = Self::new();
+        adapter.alpha = gf
+            .metadata()
+            .get_f32("adapter.lora.alpha")
+            .filter(|a| *a != 0.0);
+        let mut halves: HashMap<(usize, LoraTarget), (Option<Tensor2D>, Option<Tensor2D>)> =
+            HashMap::new();
+        for info in gf.tensor_infos() {
+            let (base, is_a) = match info.name().rsplit_once(".lora_") {
+                Some((base, "a")) => (base, true),
+                Some((base, "b")) => (base, false),
+                _ => continue,
+            };
+            let key = parse_gguf_name(base).ok_or_else(|| unknown_tensor(info.name()))?;
+            // the dimensions stored in GGUF are in a reverse order of numpy's shape
+            let dims = info.dimensions().iter().rev().copied().collect::<Vec<_>>();
+            let tensor = CpuTensor::from_bytes(info.data(), info.typ(), &dims, device.clone())?
+                .dequantize(GGMLType::F32)?;
+            let mut data = vec![0.0; tensor.len()];
+            tensor.export(&mut data)?;
+            let entry = halves.entry(key).or_default();
+            let half = Some(Tensor2D { data, dims });
+            if is_a {
+                entry.0 = half;
+            } else {
+                entry.1 = half;
+            }
+        }
+        adapter.insert_halves(halves)?;
+        Ok(adapter)
+    }
+
+    /// the tensors are named like `base_model.model.model.layers.0.self_attn.q_proj.lora_A.weight`.
+    /// the alpha is read from the `adapter_config.json` of PEFT in the same directory if it
+    /// exists, or pass it with `with_alpha()`.
+    pub fn from_safetensors(path: impl AsRef<Path>) -> Result<Self> {
+        let bytes = std::fs::read(path.as_ref()).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to read {}", path.as_ref().display()),
+            cause: Some(Box::new(err)),
+        })?;
+
+        let mut halves: HashMap<(usize, LoraTarget), (Option<Tensor2D>, Option<Tensor2D>)> =
+            HashMap::new();
+        for (name, tensor) in read_safetensors(&bytes)? {
+            let (base, is_a) = if let Some(i) = name.find(".lora_A.") {
+                (&name[..i], true)
+            } else if let Some(i) = name.find(".lora_B.") {
+                (&name[..i], false)
+            } else {
+                continue;
+            };
+            let key = parse_peft_name(base).ok_or_else(|| unknown_tensor(&name))?;
+            let entry = halves.entry(key).or_default();
+            if is_a {
+                entry.0 = Some(tensor);
+            } else {
+                entry.1 = Some(tensor);
+            }
+        }
+
+        let mut adapter = Self::new();
+        adapter.alpha = read_peft_alpha(&path.as_ref().with_file_name("adapter_config.json"));
+        adapter.insert_halves(halves)?;
+        Ok(adapter)
+    }
+
+    /// add the delta of b @ a on the projection, a is (rank, n_in) and b is (n_out, rank).
+    pub fn with_tensor(
+        mut self,
+        layer: usize,
+        target: LoraTarget,
+        a: Vec<f32>,
+        b: Vec<f32>,
+        rank: usize,
+    ) -> Result<Self> {
+        if rank == 0 || a.len() % rank != 0 || b.len() % rank != 0 {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "lora {}.{}: the sizes of a {} and b {} do not match the rank {}",
+                    layer,
+                    target.gguf_name(),
+                    a.len(),
+                    b.len(),
+                    rank
+                ),
+            )
+                .into());
+        }
+        self.pairs.insert((layer, target), LoraPair { a, b, rank });
+        Ok(self)
+    }
+
+    /// the delta is scaled by alpha / rank, like the `lora_alpha` of PEFT.
+    pub fn with_alpha(mut self, alpha: f32) -> Self {
+        self.alpha = Some(alpha);
+        self
+    }
+
+    /// the strength of the adapter, 0.0 disables it.
+    pub fn with_scale(mut self, scale: f32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    /// the projections of the layers targeted by the adapter.
+    pub fn targets(&self) -> impl Iterator<Item = (usize, LoraTarget)> + '_ {
+        self.pairs.keys().copied()
+    }
+
+    fn pair_scale(&self, pair: &LoraPair) -> f32 {
+        match self.alpha {
+            Some(alpha) => self.scale * alpha / pair.rank as f32,
+            None => self.scale,
+        }
+    }
+
+    /// changes when the weights or the scale change, mixed into the model fingerprint.
+    pub(crate) fn fingerprint(&self) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let mut update = |bytes: &[u8]| {
+            for b in bytes {
+                hash ^= *b as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        };
+        for ((layer, target), pair) in self.pairs.iter() {
+            update(&(*layer as u64).to_le_bytes());
+            update(target.gguf_name().as_bytes());
+            update(&self.pair_scale(pair).to_le_bytes());
+            for x in pair.a.iter().chain(pair.b.iter()) {
+                update(&x.to_le_bytes());
+            }
+        }
+        hash
+    }
+
+    fn insert_halves(
+        &mut self,
+        halves: HashMap<(usize, LoraTarget), (Option<Tensor2D>, Option<Tensor2D>)>,
+    ) -> Result<()> {
+        for ((layer, target), (a, b)) in halves {
+            let (a, b) = match (a, b) {
+                (Some(a), Some(b)) if a.dims.len() == 2 && b.dims == [b.dims[0], a.dims[0]] => {
+                    (a, b)
+                }
+                (a, b) => {
+                    return Err((
+                        ErrorKind::ModelError,
+                        format!(
+                            "lora {}.{}: mismatched a {:?} and b {:?}",
+                            layer,
+                            target.gguf_name(),
+                            a.map(|t| t.dims),
+                            b.map(|t| t.dims)
+                        ),
+                    )
+                        .into());
+                }
+            };
+            let rank = a.dims[0];
+            self.pairs.insert((layer, target), LoraPair {
+                a: a.data,
+                b: b.data,
+                rank,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// the low rank matrices of the fused adapters on a projection, with the scales folded into
+/// b. the adapters are concatenated along the rank, which is padded with zeros to a multiple
+/// of 32 for the matmul kernels.
+pub struct LoraWeights<T: Tensor> {
+    pub a: T, // (rank, n_in)
+    pub b: T, // (n_out, rank)
+}
+
+/// add the deltas of the adapters into the weight of (n_out, n_in), and return the merged
+/// weight in f32. it returns None if no adapter targets the weight.
+pub(crate) fn merge_weight<'a>(
+    adapters: &[LoraAdapter],
+    layer: usize,
+    target: LoraTarget,
+    weight: &CpuTensor<'a>,
+    channel_scale: Option<&CpuTensor<'a>>,
+) -> Result<Option<CpuTensor<'a>>> {
+    let pairs = matched_pairs(adapters, layer, target, weight.shape())?;
+    if pairs.is_empty() {
+        return Ok(None);
+    }
+
+    let (n_out, n_in) = (weight.shape()[0], weight.shape()[1]);
+    let mut merged = vec![0.0; n_out * n_in];
+    weight
+        .clone()
+        .dequantize(GGMLType::F32)?
+        .export(&mut merged)?;
+    // the scales of the output channels are applied on the weight before the delta
+    if let Some(channel_scale) = channel_scale {
+        let mut scales = vec![0.0; n_out];
+        channel_scale.export(&mut scales)?;
+        parallel::for_each_chunk_mut(&mut merged, n_in, |o, row| {
+            row.iter_mut().for_each(|w| *w *= scales[o]);
+        });
+    }
+    for (pair, scale) in pairs {
+        parallel::for_each_chunk_mut(&mut merged, n_in, |o, row| {
+            for r in 0..pair.rank {
+                let coef = scale * pair.b[o * pair.rank + r];
+                let a_row = &pair.a[r * n_in..(r + 1) * n_in];
+                row.iter_mut().zip(a_row).for_each(|(w, a)| *w += coef * a);
+            }
+        });
+    }
+    Ok(Some(CpuTensor::new(
+        merged,
+        weight.shape(),
+        weight.device(),
+    )?))
+}
+
+/// concatenate the adapters targeting the weight into one pair of a and b.
+pub(crate) fn fuse_weights<'a>(
+    adapters: &[LoraAdapter],
+    layer: usize,
+    target: LoraTarget,
+    weight: &CpuTensor<'a>,
+) -> Result<Option<LoraWeights<CpuTensor<'a>>>> {
+    let pairs = matched_pairs(adapters, layer, target, weight.shape())?;
+    if pairs.is_empty() {
+        return Ok(None);
+    }
+
+    let (n_out, n_in) = (weight.shape()[0], weight.shape()[1]);
+    let rank = pairs.iter().map(|(p, _)| p.rank).sum::<usize>();
+    let padded_rank = rank.div_ceil(32) * 32;
+    let mut a = vec![0.0; padded_rank * n_in];
+    let mut b = vec![0.0; n_out * padded_rank];
+    let mut offset = 0;
+    for (pair, scale) in pairs {
+        a[offset * n_in..(offset + pair.rank) * n_in].copy_from_slice(&pair.a);
+        for o in 0..n_out {
+            for r in 0..pair.rank {
+                b[o * padded_rank + offset + r] = scale * pair.b[o * pair.rank + r];
+            }
+        }
+        offset += pair.rank;
+    }
+
+    let device = weight.device();
+    Ok(Some(LoraWeights {
+        a: CpuTensor::new(a, &[padded_rank, n_in], device.clone())?,
+        b: CpuTensor::new(b, &[n_out, padded_rank], device)?,
+    }))
+}
+
+// the pairs of the adapters on the weight with their scales, the disabled ones are skipped
+fn matched_pairs<'b>(
+    adapters: &'b [LoraAdapter],
+    layer: usize,
+    target: LoraTarget,
+    shape: &[usize],
+) -> Result<Vec<(&'b LoraPair, f32)>> {
+    let mut pairs = vec![];
+    for adapter in adapters {
+        let pair = match adapter.pairs.get(&(layer, target)) {
+            Some(pair) => pair,
+            None => continue,
+        };
+        if pair.a.len() != pair.rank * shape[1] || pair.b.len() != shape[0] * pair.rank {
+            return Err((
+                ErrorKind::ModelError,
+                format!(
+                    "lora {}.{}: the rank {} adapter does not fit the weight of {:?}",
+                    layer,
+                    target.gguf_name(),
+                    pair.rank,
+                    shape
+                ),
+            )
+                .into());
+        }
+        let scale = adapter.pair_scale(pair);
+        if scale != 0.0 {
+            pairs.push((pair, scale));
+        }
+    }
+    Ok(pairs)
+}
+
+struct Tensor2D {
+    data: Vec<f32>,
+    dims: Vec<usize>,
+}
+
+// blk.0.attn_q.weight
+fn parse_gguf_name(name: &str) -> Option<(usize, LoraTarget)> {
+    let rest = name.strip_prefix("blk.")?.strip_suffix(".weight")?;
+    let (layer, target) = rest.split_once('.')?;
+    Some((layer.parse().ok()?, LoraTarget::from_gguf_name(target)?))
+}
+
+// base_model.model.model.layers.0.self_attn.q_proj
+fn parse_peft_name(name: &str) -> Option<(usize, LoraTarget)> {
+    let rest = &name[name.find("layers.")? + "layers.".len()..];
+    let (layer, module) = rest.split_once('.')?;
+    let target = module.rsplit('.').next()?;
+    Some((layer.parse().ok()?, LoraTarget::from_peft_name(target)?))
+}
+
+fn read_peft_alpha(config_path: &Path) -> Option<f32> {
+    let config = std::fs::read(config_path).ok()?;
+    let config: serde_json::Value = serde_json::from_slice(&config).ok()?;
+    config["lora_alpha"].as_f64().map(|a| a as f32)
+}
+
+fn unknown_tensor(name: &str) -> Error {
+    (
+        ErrorKind::ModelError,
+        format!("lora: unsupported tensor {}", name),
+    )
+        .into()
+}
+
+// the safetensors format: a u64 of the header size, a json header of the tensors, then the
+// data of the tensors in little endian
+fn read_safetensors(bytes: &[u8]) -> Result<Vec<(String, Tensor2D)>> {
+    let bad_format = |message: String| -> Error { (ErrorKind::FormatError, message).into() };
+    if bytes.len() < 8 {
+        return Err(bad_format("safetensors: the file is too short".to_string()));
+    }
+    let header_len = u64::from_le_bytes(bytes[..8].try_into().unwrap()) as usize;
+    let data_start = 8 + header_len;
+    if data_start > bytes.len() {
+        return Err(bad_format(
+            "safetensors: the header is truncated".to_string(),
+        ));
+    }
+    let header: serde_json::Value =
+        serde_json::from_slice(&bytes[8..data_start]).map_err(|err| Error {
+            kind: ErrorKind::FormatError,
+            message: "safetensors: failed to parse the header".to_string(),
+            cause: Some(Box::new(err)),
+        })?;
+    let header = header
+        .as_object()
+        .ok_or_else(|| bad_format("safetensors: the header is not an object".to_string()))?;
+
+    let mut tensors = vec![];
+    for (name, info) in header {
+        if name == "__metadata__" {
+            continue;
+        }
+        let dtype = info["dtype"].as_str().unwrap_or_default();
+        let dims = info["shape"]
+            .as_array()
+            .map(|s| s.iter().filter_map(|d| d.as_u64()).map(|d| d as usize))
+            .map(|s| s.collect::<Vec<_>>())
+            .unwrap_or_default();
+        let (start, end) = match info["data_offsets"].as_array().map(|o| o.as_slice()) {
+            Some([start, end]) => (
+                start.as_u64().unwrap_or(u64::MAX) as usize,
+                end.as_u64().unwrap_or(u64::MAX) as usize,
+            ),
+            _ => return Err(bad_format(format!("safetensors: bad offsets of {}", name))),
+        };
+        if start > end || data_start.saturating_add(end) > bytes.len() {
+            return Err(bad_format(format!("safetensors: bad offsets of {}", name)));
+        }
+        let raw = &bytes[data_start + start..data_start + end];
+        let data = match dtype {
+            "F32" => raw
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                .collect::<Vec<_>>(),
+            "F16" => raw
+                .chunks_exact(2)
+                .map(|b| f16::from_le_bytes(b.try_into().unwrap()).to_f32())
+                .collect(),
+            "BF16" => raw
+                .chunks_exact(2)
+                .map(|b| bf16::from_le_bytes(b.try_into().unwrap()).to_f32())
+                .collect(),
+            _ => {
+                return Err(bad_format(format!(
+                    "safetensors: unsupported dtype {} of {}",
+                    dtype, name
+                )));
+            }
+        };
+        if data.len() != dims.iter().product::<usize>() {
+            return Err(bad_format(format!(
+                "safetensors: the shape {:?} of {} does not match its data",
+                dims, name
+            )));
+        }
+        tensors.push((name.clone(), Tensor2D { data, dims }));
+    }
+    Ok(tensors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_names() {
+        assert_eq!(
+            parse_gguf_name("blk.12.ffn_down.weight"),
+            Some((12, LoraTarget::FfnDown))
+        );
+        assert_eq!(parse_gguf_name("output.weight"), None);
+        assert_eq!(
+            parse_peft_name("base_model.model.model.layers.3.self_attn.o_proj"),
+            Some((3, LoraTarget::AttnOutput))
+        );
+        assert_eq!(
+            parse_peft_name("base_model.model.model.layers.0.mlp.gate_proj"),
+            Some((0, LoraTarget::FfnGate))
+        );
+        assert_eq!(parse_peft_name("base_model.model.lm_head"), None);
+    }
+
+    #[test]
+    fn test_merge_and_fuse_weights() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        // w: (2, 3), a: (1, 3), b: (2, 1)
+        let weight = CpuTensor::new(vec![1.0; 6], &[2, 3], device.clone())?;
+        let adapter = LoraAdapter::new()
+            .with_tensor(
+                0,
+                LoraTarget::AttnQ,
+                vec![1.0, 2.0, 3.0],
+                vec![1.0, -1.0],
+                1,
+            )?
+            .with_alpha(2.0)
+            .with_scale(0.5);
+        let adapters = vec![adapter.clone(), adapter.clone().with_scale(0.0)];
+
+        let merged = merge_weight(&adapters, 0, LoraTarget::AttnQ, &weight, None)?.unwrap();
+        let mut got = vec![0.0; 6];
+        merged.export(&mut got)?;
+        assert_eq!(got, vec![2.0, 3.0, 4.0, 0.0, -1.0, -2.0]);
+        assert!(merge_weight(&adapters, 1, LoraTarget::AttnQ, &weight, None)?.is_none());
+
+        // the scales of the rows apply on the weight, not on the delta
+        let scale = CpuTensor::new(vec![2.0, 0.5], &[2], device.clone())?;
+        let merged = merge_weight(&adapters, 0, LoraTarget::AttnQ, &weight, Some(&scale))?;
+        merged.unwrap().export(&mut got)?;
+        assert_eq!(got, vec![3.0, 4.0, 5.0, -0.5, -1.5, -2.5]);
+
+        let fused = fuse_weights(&adapters, 0, LoraTarget::AttnQ, &weight)?.unwrap();
+        assert_eq!(fused.a.shape(), &[32, 3]);
+        assert_eq!(fused.b.shape(), &[2, 32]);
+
+        // the rank does not fit
+        let weight = CpuTensor::new(vec![1.0; 8], &[2, 4], device)?;
+        assert!(merge_weight(&adapters, 0, LoraTarget::AttnQ, &weight, None).is_err());
+        Ok(())
+    }
+}
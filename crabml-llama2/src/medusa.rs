@@ -0,0 +1,450 @@
+use crabml::backends::cpu::CpuTensor;
+use crabml::backends::cpu::CpuTensorDeviceRef;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFile;
+use crabml::sampler::argmax;
+use crabml::sampler::log_sum_exp;
+use crabml::sampler::SamplerChain;
+use crabml::tensor::Tensor;
+
+use crate::llama2::Llama2Runner;
+use crate::model::CpuLlama2Model;
+use crate::speculative::SpeculativeStats;
+
+/// a head of Medusa, a residual block of x + silu(fc(x)) on the last hidden state followed by
+/// its own output projection.
+pub struct MedusaHead<T: Tensor> {
+    pub fc: T,              // (embed_dim, embed_dim)
+    pub fc_bias: Option<T>, // (embed_dim, )
+    pub output: T,          // (vocab_size, embed_dim)
+}
+
+/// the extra heads on the last hidden state of the model, the i-th head guesses the token
+/// i + 1 positions after the one of the lm head.
+pub struct MedusaHeads<T: Tensor> {
+    heads: Vec<MedusaHead<T>>,
+}
+
+impl<T: Tensor> MedusaHeads<T> {
+    pub fn new(heads: Vec<MedusaHead<T>>) -> Self {
+        Self { heads }
+    }
+
+    pub fn len(&self) -> usize {
+        self.heads.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heads.is_empty()
+    }
+
+    // the logits of every head on the hidden state of (1, embed_dim), in (n_heads, vocab_size)
+    fn forward(&self, hidden: &T, vocab_size: usize) -> Result<Vec<f32>> {
+        let mut out = vec![0.0; self.heads.len() * vocab_size];
+        for (head, out) in self.heads.iter().zip(out.chunks_exact_mut(vocab_size)) {
+            let x = head.fc.matmul_vec(hidden)?;
+            let x = match &head.fc_bias {
+                Some(bias) => x.add_inplace(bias)?,
+                None => x,
+            };
+            let x = x.silu_inplace()?.add_inplace(hidden)?;
+            head.output.matmul_vec(&x)?.export(out)?;
+        }
+        Ok(out)
+    }
+}
+
+impl<'a> MedusaHeads<CpuTensor<'a>> {
+    /// load the heads named like `medusa.0.fc.weight`, `medusa.0.fc.bias` and
+    /// `medusa.0.output.weight` from the model file or a separate one, the bias is optional.
+    pub fn load(gf: &'a GGUFFile<'a>, device: CpuTensorDeviceRef<'a>) -> Result<Self> {
+        let mut heads = vec![];
+        loop {
+            let prefix = format!("medusa.{}", heads.len());
+            let fc_name = format!("{}.fc.weight", prefix);
+            if gf.get_tensor_info(&fc_name).is_none() {
+                break;
+            }
+            heads.push(MedusaHead {
+                fc: CpuLlama2Model::load_tensor(gf, &fc_name, device.clone())?,
+                fc_bias: CpuLlama2Model::load_tensor_optional(
+                    gf,
+                    &format!("{}.fc.bias", prefix),
+                    device.clone(),
+                )?,
+                output: CpuLlama2Model::load_tensor(
+                    gf,
+                    &format!("{}.output.weight", prefix),
+                    device.clone(),
+                )?,
+            });
+        }
+        if heads.is_empty() {
+            return Err((
+                ErrorKind::TensorNotFound,
+                "failed to find the medusa heads like medusa.0.fc.weight",
+            )
+                .into());
+        }
+        Ok(Self::new(heads))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MedusaOptions {
+    /// the candidates of each head.
+    pub top_k: usize,
+    /// the paths through the candidates of the heads verified on each step, the most likely
+    /// ones by the heads are taken.
+    pub n_paths: usize,
+}
+
+impl Default for MedusaOptions {
+    fn default() -> Self {
+        Self {
+            top_k: 4,
+            n_paths: 8,
+        }
+    }
+}
+
+/// tree speculative decoding with the Medusa heads: the heads guess the top_k tokens of the
+/// next positions, and the most likely paths through them are merged into a tree verified by
+/// the model in one pass, where each token only attends to its ancestors by the tree
+/// attention mask. the path with the longest prefix agreeing with the model is accepted,
+/// followed by the token the model samples after it, so the output is the same as sampling
+/// on the model alone.
+pub struct MedusaDecoder<'a, T: Tensor> {
+    runner: &'a mut Llama2Runner<T>,
+    heads: &'a MedusaHeads<T>,
+    options: MedusaOptions,
+    // the hidden state of the last token in the kv cache, (1, embed_dim)
+    hidden: Option<T>,
+    // the tokens in the kv cache, and the last sampled token which is not fed yet
+    tokens: Vec<usize>,
+    stats: SpeculativeStats,
+}
+
+impl<'a, T: Tensor> MedusaDecoder<'a, T> {
+    pub fn new(
+        runner: &'a mut Llama2Runner<T>,
+        heads: &'a MedusaHeads<T>,
+        options: MedusaOptions,
+    ) -> Result<Self> {
+        if heads.is_empty() || options.top_k == 0 || options.n_paths == 0 {
+            return Err((
+                ErrorKind::BadInput,
+                "expected at least 1 medusa head, top_k and path",
+            )
+                .into());
+        }
+        Ok(Self {
+            runner,
+            heads,
+            options,
+            hidden: None,
+            tokens: vec![],
+            stats: SpeculativeStats::default(),
+        })
+    }
+
+    pub fn stats(&self) -> SpeculativeStats {
+        self.stats
+    }
+
+    /// reset the kv cache and feed the prompt, return the first token.
+    pub fn prefill(&mut self, prompt: &str, sampler: &mut SamplerChain) -> Result<usize> {
+        self.runner.reset_kv_cache()?;
+        self.stats = SpeculativeStats::default();
+        let prompt_tokens = self.runner.prompt_tokens(prompt)?;
+        for token in prompt_tokens.iter() {
+            sampler.accept(*token);
+        }
+
+        let x = self.runner.forward_hidden(&prompt_tokens, 0)?;
+        let hidden = self.runner.take_rows(&x, &[prompt_tokens.len() - 1])?;
+        let logits = self.runner.classify(&hidden)?;
+        let mut buf = vec![0.0; self.runner.conf().vocab_size];
+        logits.export(&mut buf)?;
+        let token = sampler.sample(&buf)?;
+
+        self.hidden = Some(hidden);
+        self.tokens = prompt_tokens;
+        self.tokens.push(token);
+        Ok(token)
+    }
+
+    /// verify the paths guessed by the heads, return the accepted tokens with the token
+    /// sampled by the model after them. it's empty when the kv cache is full.
+    pub fn step(&mut self, sampler: &mut SamplerChain) -> Result<Vec<usize>> {
+        let hidden = match &self.hidden {
+            None => return Err((ErrorKind::BadInput, "prefill() should be called first").into()),
+            Some(hidden) => hidden,
+        };
+        let pos = self.tokens.len() - 1;
+        let seq_len = self.runner.seq_len();
+        if pos >= seq_len {
+            return Ok(vec![]);
+        }
+        // every node of the tree takes a position in the kv cache until the accepted ones
+        // are kept, fewer heads are used when the tree does not fit
+        let vocab_size = self.runner.conf().vocab_size;
+        let head_logits = self.heads.forward(hidden, vocab_size)?;
+        let mut depth = self.heads.len();
+        let (tree, paths) = loop {
+            let candidates = head_logits
+                .chunks_exact(vocab_size)
+                .take(depth)
+                .map(|logits| top_k_logprobs(logits, self.options.top_k))
+                .collect::<Vec<_>>();
+            let paths = best_paths(&candidates, self.options.n_paths);
+            let tree = PathTree::new(self.tokens[pos], &paths);
+            if pos + tree.tokens.len() <= seq_len || depth == 0 {
+                break (tree, paths);
+            }
+            depth -= 1;
+        };
+
+        let x = self
+            .runner
+            .forward_tree_hidden(&tree.tokens, &tree.parents)?;
+        let mut logits = vec![0.0; tree.tokens.len() * vocab_size];
+        self.runner.classify(&x)?.export(&mut logits)?;
+        let node_logits = |node: usize| &logits[node * vocab_size..(node + 1) * vocab_size];
+
+        // take the path agreeing with the model the longest, and sample along it
+        let n_agreed = |p: usize| {
+            (0..paths[p].len())
+                .take_while(|i| argmax(node_logits(tree.node(p, *i))) == Some(paths[p][*i]))
+                .count()
+        };
+        let best = (0..paths.len()).max_by_key(|p| (n_agreed(*p), usize::MAX - p));
+        let best = best.unwrap();
+        let eos_token = self.runner.tokenizer().eos_token();
+        let mut accepted = Vec::with_capacity(depth + 1);
+        for i in 0..=paths[best].len() {
+            let token = sampler.sample(node_logits(tree.node(best, i)))?;
+            accepted.push(token);
+            if i == paths[best].len() || token != paths[best][i] || token == eos_token {
+                break;
+            }
+        }
+
+        let n_matched = accepted.len() - 1;
+        self.stats.steps += 1;
+        self.stats.drafted += depth;
+        self.stats.accepted += n_matched;
+
+        // the kv cache only keeps the last token and the accepted ones before the new one
+        let kept = (0..=n_matched)
+            .map(|i| tree.node(best, i))
+            .collect::<Vec<_>>();
+        self.runner.keep_tree_nodes(&kept)?;
+        self.hidden = Some(self.runner.take_rows(&x, &[kept[n_matched]])?);
+        self.tokens.extend_from_slice(&accepted);
+        Ok(accepted)
+    }
+
+    /// prefill the prompt and generate up to `steps` tokens, it stops before the eos token.
+    pub fn generate(
+        &mut self,
+        prompt: &str,
+        steps: usize,
+        sampler: &mut SamplerChain,
+    ) -> Result<Vec<usize>> {
+        let eos_token = self.runner.tokenizer().eos_token();
+        let mut output = vec![self.prefill(prompt, sampler)?];
+        while output.len() < steps && output.last() != Some(&eos_token) {
+            let tokens = self.step(sampler)?;
+            if tokens.is_empty() {
+                break;
+            }
+            output.extend(tokens);
+        }
+        if let Some(end) = output.iter().position(|t| *t == eos_token) {
+            output.truncate(end);
+        }
+        output.truncate(steps);
+        Ok(output)
+    }
+}
+
+// the paths merged into a tree by their common prefixes, all from the root token
+struct PathTree {
+    tokens: Vec<usize>,
+    parents: Vec<Option<usize>>,
+    // the nodes of the tokens of each path, the root is not included
+    path_nodes: Vec<Vec<usize>>,
+}
+
+impl PathTree {
+    fn new(root: usize, paths: &[Vec<usize>]) -> Self {
+        let mut tree = Self {
+            tokens: vec![root],
+            parents: vec![None],
+            path_nodes: Vec::with_capacity(paths.len()),
+        };
+        for path in paths {
+            let mut parent = 0;
+            let mut nodes = Vec::with_capacity(path.len());
+            for token in path {
+                let child = (parent + 1..tree.tokens.len())
+                    .find(|n| tree.parents[*n] == Some(parent) && tree.tokens[*n] == *token);
+                parent = child.unwrap_or_else(|| {
+                    tree.tokens.push(*token);
+                    tree.parents.push(Some(parent));
+                    tree.tokens.len() - 1
+                });
+                nodes.push(parent);
+            }
+            tree.path_nodes.push(nodes);
+        }
+        tree
+    }
+
+    // the node before the i-th token of the path, which predicts it
+    fn node(&self, path: usize, i: usize) -> usize {
+        match i {
+            0 => 0,
+            _ => self.path_nodes[path][i - 1],
+        }
+    }
+}
+
+// the k most likely tokens with their log probabilities
+fn top_k_logprobs(logits: &[f32], k: usize) -> Vec<(usize, f32)> {
+    let lse = log_sum_exp(logits);
+    let mut tokens = (0..logits.len()).collect::<Vec<_>>();
+    let k = k.min(tokens.len());
+    tokens.select_nth_unstable_by(k - 1, |a, b| logits[*b].total_cmp(&logits[*a]));
+    tokens.truncate(k);
+    tokens.sort_by(|a, b| logits[*b].total_cmp(&logits[*a]));
+    tokens.into_iter().map(|t| (t, logits[t] - lse)).collect()
+}
+
+// the n most likely paths through the candidates of each position, by the sum of their
+// log probabilities. the paths are expanded position by position keeping the n best ones.
+fn best_paths(candidates: &[Vec<(usize, f32)>], n: usize) -> Vec<Vec<usize>> {
+    let mut paths = vec![(vec![], 0.0f32)];
+    for position in candidates {
+        let mut next = paths
+            .iter()
+            .flat_map(|(path, logprob)| {
+                position.iter().map(move |(token, lp)| {
+                    let mut path = path.clone();
+                    path.push(*token);
+                    (path, logprob + lp)
+                })
+            })
+            .collect::<Vec<_>>();
+        next.sort_by(|a, b| b.1.total_cmp(&a.1));
+        next.truncate(n);
+        paths = next;
+    }
+    paths.into_iter().map(|(path, _)| path).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::gguf::GGMLType;
+    use crabml::gguf::GGUFFileLoader;
+    use crabml::tensor::TensorMetrics;
+
+    use super::*;
+    use crate::testing::GGUFBuilder;
+
+    #[test]
+    fn test_best_paths() {
+        let candidates = vec![vec![(1, -0.1), (2, -1.0)], vec![(3, -0.2), (4, -0.5)]];
+        assert_eq!(best_paths(&candidates, 3), vec![
+            vec![1, 3],
+            vec![1, 4],
+            vec![2, 3]
+        ]);
+        assert_eq!(best_paths(&[], 3), vec![Vec::<usize>::new()]);
+
+        let tree = PathTree::new(9, &best_paths(&candidates, 3));
+        assert_eq!(tree.tokens, vec![9, 1, 3, 4, 2, 3]);
+        assert_eq!(tree.parents, vec![
+            None,
+            Some(0),
+            Some(1),
+            Some(1),
+            Some(0),
+            Some(4)
+        ]);
+        assert_eq!(tree.node(2, 0), 0);
+        assert_eq!(tree.node(2, 2), 5);
+
+        let logits = [0.0, 2.0, 1.0, -1.0];
+        let top = top_k_logprobs(&logits, 2);
+        assert_eq!(top.iter().map(|t| t.0).collect::<Vec<_>>(), vec![1, 2]);
+        assert!((top[0].1 - (2.0 - log_sum_exp(&logits))).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_medusa_decoding() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf = gl.open()?;
+        let device = CpuTensorDevice::new();
+        let lm = CpuLlama2Model::load(&gf, device.clone())?;
+
+        // 3 heads of a zero fc, which pass the hidden state through to the lm head, so they
+        // guess the tokens of the lm head again
+        let embed_dim = lm.conf.embedding_dim;
+        let output = gf
+            .get_tensor_info("output.weight")
+            .or_else(|| gf.get_tensor_info("token_embd.weight"))
+            .unwrap();
+        let mut b = GGUFBuilder::new("medusa");
+        for i in 0..3 {
+            let fc = format!("medusa.{}.fc.weight", i);
+            let zeros = vec![0; embed_dim * embed_dim * 4];
+            b.tensor(&fc, &[embed_dim, embed_dim], GGMLType::F32, zeros)?;
+            let out = format!("medusa.{}.output.weight", i);
+            b.tensor(
+                &out,
+                output.dimensions(),
+                output.typ(),
+                output.data().to_vec(),
+            )?;
+        }
+        let heads_file = b.load("medusa.gguf")?;
+        let gf_heads = heads_file.open()?;
+        let heads = MedusaHeads::load(&gf_heads, device.clone())?;
+        assert_eq!(heads.len(), 3);
+        assert!(MedusaHeads::load(&gf, device.clone()).is_err());
+
+        let metrics = TensorMetrics::default();
+        let mut runner = Llama2Runner::new(&lm, metrics.clone(), 200, GGMLType::F32)?;
+        let expected = {
+            let mut sampler = SamplerChain::new();
+            let (pos, prev_token, token) = runner.prefill("Lily is a cat", &mut sampler)?;
+            let output = runner.generate(pos, prev_token, token, 30, &mut sampler);
+            output.collect::<Result<Vec<_>>>()?.join("")
+        };
+
+        let options = MedusaOptions {
+            top_k: 2,
+            n_paths: 4,
+        };
+        let mut decoder = MedusaDecoder::new(&mut runner, &heads, options)?;
+        let tokens = decoder.generate("Lily is a cat", 31, &mut SamplerChain::new())?;
+        let stats = decoder.stats();
+        assert_eq!(tokens.len(), 31);
+        assert!(stats.steps <= 30, "{:?}", stats);
+
+        // the greedy output is the same as the model alone
+        let prompt_tokens = lm.tokenizer.encode("Lily is a cat", true, false)?;
+        let mut prev_token = *prompt_tokens.last().unwrap();
+        let mut text = String::new();
+        for token in tokens {
+            text.push_str(&lm.tokenizer.decode(prev_token, token)?);
+            prev_token = token;
+        }
+        assert_eq!(text, expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+use crabml::gguf::GGMLType;
+
+use crate::model::Llama2Config;
+
+/// the memory limit of the kv cache and the scratch buffers of a runner, and the fallbacks
+/// it may take instead of failing with an `ErrorKind::OutOfMemory` error. the weights are
+/// not accounted, they are loaded before the runner.
+///
+/// the fallbacks are disabled by default, the caller should opt into them when the
+/// degraded speed or precision is acceptable.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryOptions {
+    /// the bytes of the kv cache plus the scratch of a forward pass, None for no limit. the
+    /// allocation failures are reported as OutOfMemory errors either way.
+    pub budget: Option<usize>,
+    /// forward a long prompt in smaller batches when the scratch of the whole prompt does
+    /// not fit.
+    pub allow_smaller_batch: bool,
+    /// switch the kv cache to q8_0 when it does not fit in the requested dtype.
+    pub allow_q8_0_kv_cache: bool,
+}
+
+impl MemoryOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_budget(mut self, budget: usize) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    pub fn with_smaller_batch(mut self, allow: bool) -> Self {
+        self.allow_smaller_batch = allow;
+        self
+    }
+
+    pub fn with_q8_0_kv_cache(mut self, allow: bool) -> Self {
+        self.allow_q8_0_kv_cache = allow;
+        self
+    }
+}
+
+/// the bytes of the key and value caches of all the layers with the capacity of seq_len.
+pub fn kv_cache_bytes(conf: &Llama2Config, seq_len: usize, dtype: GGMLType) -> usize {
+    let n = conf.n_layers * seq_len * conf.kv_dim();
+    n / dtype.block_size() * dtype.type_size() * 2
+}
+
+/// a rough estimate of the f32 activations of a token in a forward pass: the hidden states,
+/// q/k/v, the attention scores over the whole context, the ffn and the logits.
+pub fn scratch_bytes_per_token(conf: &Llama2Config, seq_len: usize) -> usize {
+    let n = 4 * conf.embedding_dim
+        + 2 * conf.kv_dim()
+        + conf.n_heads * seq_len
+        + 3 * conf.hidden_dim
+        + conf.vocab_size;
+    n * 4
+}
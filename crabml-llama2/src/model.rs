@@ -1,23 +1,68 @@
+use std::collections::HashMap;
 use std::rc::Rc;
-use std::vec;
 
 use crabml::backends::cpu::CpuTensor;
-use crabml::backends::cpu::CpuTensorBuf;
 use crabml::backends::cpu::CpuTensorDeviceRef;
+#[cfg(not(target_os = "wasi"))]
 use crabml::backends::wgpu::WgpuTensor;
+#[cfg(not(target_os = "wasi"))]
 use crabml::backends::wgpu::WgpuTensorDeviceRef;
 use crabml::error::Error;
 use crabml::error::ErrorKind;
 use crabml::error::Result;
 use crabml::gguf::GGMLType;
 use crabml::gguf::GGUFFile;
+use crabml::gguf::GGUFFileLoader;
+use crabml::gguf::GGUFLayerStreamer;
+use crabml::gguf::GGUFLoadMode;
+use crabml::gguf::GGUFLoadOptions;
 use crabml::tensor::Tensor;
 use crabml::tokenizer::BpeTokenizer;
+use crabml::tokenizer::PreTokenizer;
+
+#[cfg(feature = "chat-template")]
+use crate::chat::ChatTemplate;
+use crate::embeddings::Pooling;
+use crate::lora;
+use crate::lora::LoraAdapter;
+use crate::lora::LoraMode;
+use crate::lora::LoraTarget;
+use crate::lora::LoraWeights;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum ModelArchitecture {
     Llama,
     Gemma,
+    Qwen2,
+    Phi2,
+}
+
+/// the architectures supported by the loader, keyed by the `general.architecture` in the
+/// GGUF file. the name is also the prefix of the architecture specific metadata keys, like
+/// `qwen2.context_length`. Mistral has no entry, its GGUF files are `llama` with a
+/// `llama.attention.sliding_window`.
+const ARCHITECTURE_REGISTRY: &[(&str, ModelArchitecture)] = &[
+    ("llama", ModelArchitecture::Llama),
+    ("gemma", ModelArchitecture::Gemma),
+    ("qwen2", ModelArchitecture::Qwen2),
+    ("phi2", ModelArchitecture::Phi2),
+];
+
+impl ModelArchitecture {
+    pub fn from_name(name: &str) -> Option<Self> {
+        ARCHITECTURE_REGISTRY
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, arch)| *arch)
+    }
+
+    pub fn name(&self) -> &'static str {
+        ARCHITECTURE_REGISTRY
+            .iter()
+            .find(|(_, arch)| arch == self)
+            .map(|(n, _)| *n)
+            .unwrap()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -30,8 +75,20 @@ pub struct Llama2Config {
     pub n_kv_heads: usize,
     pub vocab_size: usize,
     pub seq_len: usize,
-    pub rms_norm_eps: f32,
+    pub rms_norm_eps: f32, // the eps of layer norm on Phi-2
     pub rope_dim: Option<usize>,
+    pub rope_freq_base: f32,
+    // the rope of every layer when the metadata varies it by the layer, empty if all the
+    // layers share rope_dim and rope_freq_base
+    pub layer_ropes: Vec<LayerRope>,
+    // the attention only looks back this many positions, like Mistral
+    pub sliding_window: Option<usize>,
+    // the attention scores are capped into (-cap, cap) by cap * tanh(score / cap), like Gemma 2
+    pub attn_soft_cap: Option<f32>,
+    // the default pooling of the embeddings, like the sentence-transformers models
+    pub pooling: Option<Pooling>,
+    // the jinja template to format the chat messages, like `tokenizer_config.json` in HF
+    pub chat_template: Option<String>,
 }
 
 impl Llama2Config {
@@ -42,27 +99,186 @@ impl Llama2Config {
     pub fn head_size(&self) -> usize {
         self.embedding_dim / self.n_heads
     }
+
+    /// the rope of the layer l.
+    pub fn rope(&self, l: usize) -> LayerRope {
+        self.layer_ropes.get(l).copied().unwrap_or(LayerRope {
+            dim: self.rope_dim.unwrap_or(self.head_size()),
+            freq_base: self.rope_freq_base,
+        })
+    }
+}
+
+/// the rotary embedding of a layer, it rotates the first dim of each head, and a dim of 0
+/// leaves the layer without the position encoding, like the NoPE layers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LayerRope {
+    pub dim: usize,
+    pub freq_base: f32,
 }
 
 pub struct Llama2Weights<T: Tensor> {
     // token embedding table
     pub token_embed: T, // (vocab_size, dim)
     // weights for rmsnorms
-    pub rms_att_weight: Vec<T>, // (layer, dim) rmsnorm weights
-    pub rms_ffn_weight: Vec<T>, // (layer, dim)
+    pub rms_att_weight: Vec<T>,         // (layer, dim) rmsnorm weights
+    pub rms_ffn_weight: Vec<Option<T>>, // (layer, dim), None on Phi-2
+    // (optional) biases of the layer norm, on Phi-2
+    pub attn_norm_bias: Vec<Option<T>>, // (layer, dim)
     // weights for matmuls
     pub wq: Vec<T>, // (layer, embedding_dim, embedding_dim)
     pub wk: Vec<T>, // (layer, kv_dim, embedding_dim)
     pub wv: Vec<T>, // (layer, kv_dim, embedding_dim)
     pub wo: Vec<T>, // (layer, embedding_dim, embedding_dim)
+    // (optional) biases for matmuls, on Qwen2 and Phi-2
+    pub bq: Vec<Option<T>>, // (layer, embedding_dim)
+    pub bk: Vec<Option<T>>, // (layer, kv_dim)
+    pub bv: Vec<Option<T>>, // (layer, kv_dim)
+    pub bo: Vec<Option<T>>, // (layer, embedding_dim)
     // weights for ffn
-    pub ffn_gate_weight: Vec<T>, // (layer, hidden_dim, embedding_dim)
-    pub ffn_down_weight: Vec<T>, // (layer, embedding_dim, hidden_dim)
-    pub ffn_up_weight: Vec<T>,   // (layer, hidden_dim, embedding_dim)
+    pub ffn_gate_weight: Vec<Option<T>>, // (layer, hidden_dim, embedding_dim), None on Phi-2
+    pub ffn_down_weight: Vec<T>,         // (layer, embedding_dim, hidden_dim)
+    pub ffn_up_weight: Vec<T>,           // (layer, hidden_dim, embedding_dim)
+    // (optional) biases for ffn, on Phi-2
+    pub ffn_down_bias: Vec<Option<T>>, // (layer, embedding_dim)
+    pub ffn_up_bias: Vec<Option<T>>,   // (layer, hidden_dim)
     // final rmsnorm
-    pub rms_final_weight: T, // (dim, )
+    pub rms_final_weight: T,        // (dim, )
+    pub final_norm_bias: Option<T>, // (dim, ), on Phi-2
     // (optional) classifier weights for the logits, on the last layer
     pub output_weight: Option<T>, // (vocab_size, dim)
+    pub output_bias: Option<T>,   // (vocab_size, ), on Phi-2
+    // (optional) the scales of the output channels of the matmul weights, multiplied into
+    // the output of the matmuls, on the AWQ and GPTQ checkpoints converted with the scales
+    // kept apart from the quantized weights
+    pub scales: Vec<HashMap<LoraTarget, T>>, // (layer, )
+    pub output_scale: Option<T>,             // (vocab_size, )
+    // the lora adapters applied on the fly, empty unless loaded with LoraMode::Fused
+    pub lora: Vec<HashMap<LoraTarget, LoraWeights<T>>>, // (layer, )
+    // keeps only a window of the layers resident, when loaded with the layer streaming
+    pub streamer: Option<GGUFLayerStreamer>,
+}
+
+impl<T: Tensor> Llama2Weights<T> {
+    pub fn lora(&self, l: usize, target: LoraTarget) -> Option<&LoraWeights<T>> {
+        self.lora.get(l)?.get(&target)
+    }
+
+    /// the matmul weight of the target in the layer l, None if the model has no such weight,
+    /// like the ffn_gate of Phi-2.
+    pub fn weight(&self, l: usize, target: LoraTarget) -> Option<&T> {
+        match target {
+            LoraTarget::AttnQ => Some(&self.wq[l]),
+            LoraTarget::AttnK => Some(&self.wk[l]),
+            LoraTarget::AttnV => Some(&self.wv[l]),
+            LoraTarget::AttnOutput => Some(&self.wo[l]),
+            LoraTarget::FfnGate => self.ffn_gate_weight[l].as_ref(),
+            LoraTarget::FfnUp => Some(&self.ffn_up_weight[l]),
+            LoraTarget::FfnDown => Some(&self.ffn_down_weight[l]),
+        }
+    }
+
+    /// the scales of the output channels of the weight, (n_out, ).
+    pub fn scale(&self, l: usize, target: LoraTarget) -> Option<&T> {
+        self.scales.get(l)?.get(&target)
+    }
+
+    /// called before computing the layer l, to prefetch the next layers and drop the ones
+    /// out of the window when the layers are streamed.
+    pub fn enter_layer(&self, l: usize) {
+        if let Some(streamer) = &self.streamer {
+            streamer.enter_layer(l);
+        }
+    }
+
+    /// the loaded tensors by their names in the GGUF file, like `blk.0.attn_q.weight`, so
+    /// the tools can inspect or rewrite the weights without parsing the file again. the fused
+    /// lora weights are named like `blk.0.attn_q.weight.lora_a`, and a packed attn_qkv is
+    /// listed as the views of attn_q, attn_k and attn_v.
+    pub fn named_tensors(&self) -> Vec<(String, &T)> {
+        let mut tensors = vec![("token_embd.weight".to_string(), &self.token_embed)];
+        for l in 0..self.wq.len() {
+            let layer = [
+                ("attn_norm.weight", Some(&self.rms_att_weight[l])),
+                ("attn_norm.bias", self.attn_norm_bias[l].as_ref()),
+                ("attn_q.weight", Some(&self.wq[l])),
+                ("attn_q.bias", self.bq[l].as_ref()),
+                ("attn_k.weight", Some(&self.wk[l])),
+                ("attn_k.bias", self.bk[l].as_ref()),
+                ("attn_v.weight", Some(&self.wv[l])),
+                ("attn_v.bias", self.bv[l].as_ref()),
+                ("attn_output.weight", Some(&self.wo[l])),
+                ("attn_output.bias", self.bo[l].as_ref()),
+                ("ffn_norm.weight", self.rms_ffn_weight[l].as_ref()),
+                ("ffn_gate.weight", self.ffn_gate_weight[l].as_ref()),
+                ("ffn_up.weight", Some(&self.ffn_up_weight[l])),
+                ("ffn_up.bias", self.ffn_up_bias[l].as_ref()),
+                ("ffn_down.weight", Some(&self.ffn_down_weight[l])),
+                ("ffn_down.bias", self.ffn_down_bias[l].as_ref()),
+            ];
+            for (name, tensor) in layer {
+                if let Some(tensor) = tensor {
+                    tensors.push((format!("blk.{}.{}", l, name), tensor));
+                }
+            }
+            for target in LoraTarget::ALL {
+                if let Some(scale) = self.scale(l, target) {
+                    tensors.push((format!("blk.{}.{}.scale", l, target.gguf_name()), scale));
+                }
+                if let Some(lora) = self.lora(l, target) {
+                    let name = format!("blk.{}.{}.weight", l, target.gguf_name());
+                    tensors.push((format!("{}.lora_a", name), &lora.a));
+                    tensors.push((format!("{}.lora_b", name), &lora.b));
+                }
+            }
+        }
+        let tail = [
+            ("output_norm.weight", Some(&self.rms_final_weight)),
+            ("output_norm.bias", self.final_norm_bias.as_ref()),
+            ("output.weight", self.output_weight.as_ref()),
+            ("output.bias", self.output_bias.as_ref()),
+            ("output.scale", self.output_scale.as_ref()),
+        ];
+        for (name, tensor) in tail {
+            if let Some(tensor) = tensor {
+                tensors.push((name.to_string(), tensor));
+            }
+        }
+        tensors
+    }
+
+    pub fn tensor(&self, name: &str) -> Option<&T> {
+        self.named_tensors()
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, t)| t)
+    }
+
+    fn weight_mut(&mut self, l: usize, target: LoraTarget) -> Option<&mut T> {
+        match target {
+            LoraTarget::AttnQ => Some(&mut self.wq[l]),
+            LoraTarget::AttnK => Some(&mut self.wk[l]),
+            LoraTarget::AttnV => Some(&mut self.wv[l]),
+            LoraTarget::AttnOutput => Some(&mut self.wo[l]),
+            LoraTarget::FfnGate => self.ffn_gate_weight[l].as_mut(),
+            LoraTarget::FfnUp => Some(&mut self.ffn_up_weight[l]),
+            LoraTarget::FfnDown => Some(&mut self.ffn_down_weight[l]),
+        }
+    }
+}
+
+impl<'a> Llama2Weights<CpuTensor<'a>> {
+    /// the names and bytes of the tensors which are not the views of the shared map of the
+    /// file, like the merged lora weights or the tensors dequantized on loading. every process
+    /// serving the model holds its own copy of them.
+    pub fn private_tensors(&self, gf: &GGUFFile) -> Vec<(String, usize)> {
+        self.named_tensors()
+            .into_iter()
+            .map(|(name, t)| (name, t.buf().as_bytes()))
+            .filter(|(_, data)| !gf.is_shared_data(data))
+            .map(|(name, data)| (name, data.len()))
+            .collect()
+    }
 }
 
 pub trait Llama2Model {
@@ -75,6 +291,100 @@ pub trait Llama2Model {
     fn weights(&self) -> Rc<Llama2Weights<Self::T>>;
 
     fn tokenizer(&self) -> Rc<BpeTokenizer>;
+
+    /// tells whether a saved session is created from the same model.
+    fn fingerprint(&self) -> u64;
+}
+
+/// the options on loading a model from a gguf file.
+#[derive(Clone, Debug, Default)]
+pub struct ModelLoadOptions {
+    gguf: GGUFLoadOptions,
+    n_layers: Option<usize>,
+    loras: Vec<LoraAdapter>,
+    lora_mode: LoraMode,
+    stream_layers: Option<usize>,
+    shared_weights: bool,
+}
+
+impl ModelLoadOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// load and run only the first `n_layers` transformer layers, with the final norm
+    /// and the output kept. the tensors of the other layers are never touched, like for
+    /// a draft model derived by truncation, or to study the early exit quality.
+    pub fn with_n_layers(mut self, n_layers: usize) -> Self {
+        self.n_layers = Some(n_layers);
+        self
+    }
+
+    /// apply the LoRA adapter on the weights, the adapters are stacked in the order they're
+    /// added, each with its own scale.
+    pub fn with_lora(mut self, adapter: LoraAdapter) -> Self {
+        self.loras.push(adapter);
+        self
+    }
+
+    pub fn with_lora_mode(mut self, mode: LoraMode) -> Self {
+        self.lora_mode = mode;
+        self
+    }
+
+    /// keep only a sliding window of `window` layers' weights resident, prefetching the next
+    /// layers from the file while computing the current one and dropping the ones behind, so
+    /// a model larger than the ram runs slowly instead of failing to load. it requires the
+    /// file to be mapped without mlock.
+    pub fn with_layer_streaming(mut self, window: usize) -> Self {
+        self.stream_layers = Some(window);
+        self
+    }
+
+    /// require all the weights to stay the read-only views of the mapped file, so the
+    /// processes serving the same model share one copy in the page cache instead of each
+    /// holding its own, see `GGUFFile::is_shared()`. the loading fails if the file is read
+    /// into a buffer or a weight is copied out, like by merging a lora adapter.
+    pub fn with_shared_weights(mut self, shared: bool) -> Self {
+        self.shared_weights = shared;
+        self
+    }
+
+    /// map the file to load the tensors lazily on their first access (the default), or read
+    /// it into the memory up front (the default on wasi, which has no mmap).
+    pub fn with_load_mode(mut self, mode: GGUFLoadMode) -> Self {
+        self.gguf = self.gguf.with_mode(mode);
+        self
+    }
+
+    /// pin the weights in the memory so they're never swapped out.
+    pub fn with_mlock(mut self, mlock: bool) -> Self {
+        self.gguf = self.gguf.with_mlock(mlock);
+        self
+    }
+
+    /// report the bytes of the file loaded and the total bytes, like to show the percentage
+    /// of the loading.
+    pub fn with_progress(
+        mut self,
+        progress: impl Fn(usize, usize) + Send + Sync + 'static,
+    ) -> Self {
+        self.gguf = self.gguf.with_progress(progress);
+        self
+    }
+
+    /// open the gguf file by the load mode, mlock and progress options. the model borrows the
+    /// tensors from the loader, pass the options on to `load_with_options()` after it.
+    pub fn open(&self, path: &str) -> Result<GGUFFileLoader> {
+        GGUFFileLoader::new_with_options(path, &self.gguf)
+    }
+}
+
+/// what `reload_tokenizer()` replaced on the model, the parts not in the file are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TokenizerReload {
+    pub tokenizer: bool,
+    pub chat_template: bool,
 }
 
 pub struct CpuLlama2Model<'a> {
@@ -82,6 +392,7 @@ pub struct CpuLlama2Model<'a> {
     pub weights: Rc<Llama2Weights<CpuTensor<'a>>>,
     pub tokenizer: Rc<BpeTokenizer>,
     pub device: CpuTensorDeviceRef<'a>,
+    pub fingerprint: u64,
 }
 
 impl<'a> Llama2Model for &CpuLlama2Model<'a> {
@@ -102,111 +413,434 @@ impl<'a> Llama2Model for &CpuLlama2Model<'a> {
     fn tokenizer(&self) -> Rc<BpeTokenizer> {
         self.tokenizer.clone()
     }
+
+    fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
 }
 
 impl<'a> CpuLlama2Model<'a> {
     pub fn load(gf: &'a GGUFFile<'a>, device: CpuTensorDeviceRef<'a>) -> Result<Self> {
-        let conf = Self::load_config(gf)?;
-        let weights = Self::load_weights(gf, conf.n_layers, device.clone())?;
-        let tokenizer = Self::load_tokenizer(gf);
+        Self::load_with_options(gf, device, ModelLoadOptions::default())
+    }
+
+    pub fn load_with_options(
+        gf: &'a GGUFFile<'a>,
+        device: CpuTensorDeviceRef<'a>,
+        options: ModelLoadOptions,
+    ) -> Result<Self> {
+        Self::load_with_tokenizer(gf, device, options, || Self::load_tokenizer(gf))
+    }
+
+    /// like `load_with_options()`, but the tokenizer comes from `load_tokenizer`, called once
+    /// the weights are set up, like to wait for a tokenizer loaded on another thread.
+    pub(crate) fn load_with_tokenizer(
+        gf: &'a GGUFFile<'a>,
+        device: CpuTensorDeviceRef<'a>,
+        options: ModelLoadOptions,
+        load_tokenizer: impl FnOnce() -> Result<BpeTokenizer>,
+    ) -> Result<Self> {
+        let mut conf = Self::load_config(gf)?;
+        let n_layers_total = conf.n_layers;
+        if let Some(n_layers) = options.n_layers {
+            if n_layers == 0 || n_layers > conf.n_layers {
+                return Err(Error {
+                    kind: ErrorKind::BadInput,
+                    message: format!(
+                        "n_layers must be in 1..={}, got {}",
+                        conf.n_layers, n_layers
+                    ),
+                    cause: None,
+                });
+            }
+            conf.n_layers = n_layers;
+        }
+        let mut weights = Self::load_weights(gf, &conf, device.clone())?;
+        Self::apply_loras(&mut weights, n_layers_total, &options)?;
+        if options.shared_weights {
+            Self::check_shared_weights(gf, &weights)?;
+        }
+        if let Some(window) = options.stream_layers {
+            weights.streamer = Some(gf.layer_streamer(conf.n_layers, window)?);
+        }
+        let tokenizer = load_tokenizer()?;
+        // the weights changed by the adapters invalidate the saved sessions
+        let fingerprint = options
+            .loras
+            .iter()
+            .fold(gf.fingerprint(), |hash, adapter| {
+                adapter
+                    .fingerprint()
+                    .to_le_bytes()
+                    .iter()
+                    .fold(hash, |h, b| (h ^ *b as u64).wrapping_mul(0x100000001b3))
+            });
         Ok(Self {
             conf,
             weights: Rc::new(weights),
             device,
             tokenizer: Rc::new(tokenizer),
+            fingerprint,
         })
     }
 
+    /// reload the tokenizer and the chat template from the metadata of a GGUF file into the
+    /// loaded model, like the model file with a fixed chat template, or a sidecar GGUF with
+    /// only the `tokenizer.*` metadata and no tensors. the weights are not touched, so it's
+    /// quick on any model size.
+    ///
+    /// only the parts found in the file are replaced, and nothing is replaced if any of them
+    /// is broken, like a template which fails to parse or a vocab of another size than the
+    /// embedding. the runners created before keep the old ones until their
+    /// `reload_tokenizer()`.
+    pub fn reload_tokenizer(&mut self, gf: &GGUFFile) -> Result<TokenizerReload> {
+        Self::reload_tokenizer_metadata(gf, &mut self.conf, &mut self.tokenizer)
+    }
+
+    fn reload_tokenizer_metadata(
+        gf: &GGUFFile,
+        conf: &mut Llama2Config,
+        tokenizer: &mut Rc<BpeTokenizer>,
+    ) -> Result<TokenizerReload> {
+        let new_tokenizer = match gf.metadata().get_string_array("tokenizer.ggml.tokens") {
+            Some(_) => Some(Self::load_tokenizer(gf)?),
+            None => None,
+        };
+        if let Some(tk) = new_tokenizer
+            .as_ref()
+            .filter(|tk| tk.vocab().len() != conf.vocab_size)
+        {
+            return Err(Error {
+                kind: ErrorKind::ModelError,
+                message: format!(
+                    "the reloaded tokenizer has {} tokens, but the model has {}",
+                    tk.vocab().len(),
+                    conf.vocab_size
+                ),
+                cause: None,
+            });
+        }
+        let chat_template = gf
+            .metadata()
+            .get_string("tokenizer.chat_template")
+            .map(|s| s.to_string());
+        // the model falls back to a built-in format on a broken template, which is not
+        // what a hot fix wants
+        #[cfg(feature = "chat-template")]
+        if let Some(source) = &chat_template {
+            ChatTemplate::jinja(source)?;
+        }
+        if new_tokenizer.is_none() && chat_template.is_none() {
+            return Err(Error {
+                kind: ErrorKind::BadInput,
+                message: "no tokenizer.ggml.tokens or tokenizer.chat_template in the file"
+                    .to_string(),
+                cause: None,
+            });
+        }
+
+        let reload = TokenizerReload {
+            tokenizer: new_tokenizer.is_some(),
+            chat_template: chat_template.is_some(),
+        };
+        if let Some(tk) = new_tokenizer {
+            *tokenizer = Rc::new(tk);
+        }
+        if chat_template.is_some() {
+            conf.chat_template = chat_template;
+        }
+        Ok(reload)
+    }
+
+    fn check_shared_weights(gf: &GGUFFile, weights: &Llama2Weights<CpuTensor<'a>>) -> Result<()> {
+        if !gf.is_shared() {
+            return Err((
+                ErrorKind::BadInput,
+                "the weights can only be shared when the file is mapped, not read into a buffer",
+            )
+                .into());
+        }
+        if let Some((name, n_bytes)) = weights.private_tensors(gf).first() {
+            return Err(Error {
+                kind: ErrorKind::BadInput,
+                message: format!(
+                    "the weight {} is copied out of the file ({} bytes), it can not be shared",
+                    name, n_bytes
+                ),
+                cause: None,
+            });
+        }
+        Ok(())
+    }
+
+    fn apply_loras(
+        weights: &mut Llama2Weights<CpuTensor<'a>>,
+        n_layers_total: usize,
+        options: &ModelLoadOptions,
+    ) -> Result<()> {
+        let n_layers = weights.wq.len();
+        for (layer, target) in options.loras.iter().flat_map(|a| a.targets()) {
+            let missing = if layer < n_layers {
+                weights.weight_mut(layer, target).is_none()
+            } else {
+                layer >= n_layers_total
+            };
+            if missing {
+                return Err(Error {
+                    kind: ErrorKind::ModelError,
+                    message: format!(
+                        "lora: the model has no weight blk.{}.{}.weight",
+                        layer,
+                        target.gguf_name()
+                    ),
+                    cause: None,
+                });
+            }
+        }
+
+        if options.lora_mode == LoraMode::Fused {
+            weights.lora = (0..n_layers).map(|_| HashMap::new()).collect();
+        }
+        for l in 0..n_layers {
+            for target in LoraTarget::ALL {
+                let scale = weights.scale(l, target).cloned();
+                let weight = match weights.weight_mut(l, target) {
+                    Some(weight) => weight,
+                    None => continue,
+                };
+                match options.lora_mode {
+                    LoraMode::Merge => {
+                        // the scales are folded into the merged weight, the delta of the
+                        // adapters is not scaled
+                        if let Some(merged) =
+                            lora::merge_weight(&options.loras, l, target, weight, scale.as_ref())?
+                        {
+                            *weight = merged;
+                            weights.scales[l].remove(&target);
+                        }
+                    }
+                    LoraMode::Fused => {
+                        if let Some(fused) = lora::fuse_weights(&options.loras, l, target, weight)?
+                        {
+                            weights.lora[l].insert(target, fused);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn load_weights(
         gf: &'a GGUFFile<'a>,
-        n_layers: usize,
+        conf: &Llama2Config,
         device: CpuTensorDeviceRef<'a>,
     ) -> Result<Llama2Weights<CpuTensor<'a>>> {
+        let n_layers = conf.n_layers;
+        let load = |name: String| Self::load_tensor(gf, &name, device.clone());
+        // the norm weights and biases are small, keep them in f32
+        let load_f32_optional = |name: String| {
+            Self::load_tensor_optional(gf, &name, device.clone())?
+                .map(|t| t.dequantize(GGMLType::F32))
+                .transpose()
+        };
+        let load_layers = |name: &str| {
+            (0..n_layers)
+                .map(|l| load(format!("blk.{}.{}", l, name)))
+                .collect::<Result<Vec<_>>>()
+        };
+        let load_layers_f32_optional = |name: &str| {
+            (0..n_layers)
+                .map(|l| load_f32_optional(format!("blk.{}.{}", l, name)))
+                .collect::<Result<Vec<_>>>()
+        };
+
         // [64 (dim), 512 (vocab_size)]
-        let token_embed = Self::load_tensor(gf, "token_embd.weight", device.clone())?;
-        let mut wq = vec![];
-        let mut wk = vec![];
-        let mut wv = vec![];
-        let mut wo = vec![];
-        let mut ffn_gate_weight = vec![];
-        let mut ffn_down_weight = vec![];
-        let mut ffn_up_weight = vec![];
-        let mut rms_att_weight = vec![];
-        let mut rms_ffn_weight = vec![];
-        for layer in 0..n_layers {
-            wq.push(Self::load_tensor(
-                gf,
-                &format!("blk.{}.attn_q.weight", layer),
-                device.clone(),
-            )?);
-            wk.push(Self::load_tensor(
-                gf,
-                &format!("blk.{}.attn_k.weight", layer),
-                device.clone(),
-            )?);
-            wv.push(Self::load_tensor(
-                gf,
-                &format!("blk.{}.attn_v.weight", layer),
-                device.clone(),
-            )?);
-            wo.push(Self::load_tensor(
-                gf,
-                &format!("blk.{}.attn_output.weight", layer),
-                device.clone(),
-            )?);
-            // (hidden_dim:172, embedding_dim:64)
-            ffn_gate_weight.push(Self::load_tensor(
-                gf,
-                &format!("blk.{}.ffn_gate.weight", layer),
-                device.clone(),
-            )?);
-            ffn_down_weight.push(Self::load_tensor(
-                gf,
-                &format!("blk.{}.ffn_down.weight", layer),
-                device.clone(),
-            )?);
-            ffn_up_weight.push(Self::load_tensor(
-                gf,
-                &format!("blk.{}.ffn_up.weight", layer),
-                device.clone(),
-            )?);
-            rms_att_weight.push(
-                Self::load_tensor(
-                    gf,
-                    &format!("blk.{}.attn_norm.weight", layer),
-                    device.clone(),
-                )?
-                .dequantize(GGMLType::F32)?,
-            );
-            rms_ffn_weight.push(
-                Self::load_tensor(
+        let token_embed = load("token_embd.weight".to_string())?;
+        let (mut wq, mut wk, mut wv) = (vec![], vec![], vec![]);
+        let (mut bq, mut bk, mut bv) = (vec![], vec![], vec![]);
+        let qkv_rows = [conf.embedding_dim, conf.kv_dim(), conf.kv_dim()];
+        for l in 0..n_layers {
+            // Phi-2 and some Qwen models pack q, k and v into one attn_qkv tensor, its rows
+            // are split into the views of wq, wk and wv without a copy
+            let name = format!("blk.{}.attn_qkv.weight", l);
+            let [q, k, v] = match Self::load_tensor_split(gf, &name, qkv_rows, device.clone())? {
+                Some(qkv) => qkv,
+                None => [
+                    load(format!("blk.{}.attn_q.weight", l))?,
+                    load(format!("blk.{}.attn_k.weight", l))?,
+                    load(format!("blk.{}.attn_v.weight", l))?,
+                ],
+            };
+            wq.push(q);
+            wk.push(k);
+            wv.push(v);
+
+            let name = format!("blk.{}.attn_qkv.bias", l);
+            let [q, k, v] = match Self::load_tensor_split(gf, &name, qkv_rows, device.clone())? {
+                Some(qkv) => qkv.map(Some),
+                None => [
+                    Self::load_tensor_optional(
+                        gf,
+                        &format!("blk.{}.attn_q.bias", l),
+                        device.clone(),
+                    )?,
+                    Self::load_tensor_optional(
+                        gf,
+                        &format!("blk.{}.attn_k.bias", l),
+                        device.clone(),
+                    )?,
+                    Self::load_tensor_optional(
+                        gf,
+                        &format!("blk.{}.attn_v.bias", l),
+                        device.clone(),
+                    )?,
+                ],
+            };
+            // the biases are small, keep them in f32
+            let to_f32 =
+                |t: Option<CpuTensor<'a>>| t.map(|t| t.dequantize(GGMLType::F32)).transpose();
+            bq.push(to_f32(q)?);
+            bk.push(to_f32(k)?);
+            bv.push(to_f32(v)?);
+        }
+        let wo = load_layers("attn_output.weight")?;
+        let bo = load_layers_f32_optional("attn_output.bias")?;
+        // (hidden_dim:172, embedding_dim:64), Phi-2 has no gate in the ffn
+        let ffn_gate_weight = (0..n_layers)
+            .map(|l| {
+                Self::load_tensor_optional(
                     gf,
-                    &format!("blk.{}.ffn_norm.weight", layer),
+                    &format!("blk.{}.ffn_gate.weight", l),
                     device.clone(),
-                )?
-                .dequantize(GGMLType::F32)?,
-            );
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let ffn_down_weight = load_layers("ffn_down.weight")?;
+        let ffn_up_weight = load_layers("ffn_up.weight")?;
+        let ffn_down_bias = load_layers_f32_optional("ffn_down.bias")?;
+        let ffn_up_bias = load_layers_f32_optional("ffn_up.bias")?;
+        let rms_att_weight = load_layers("attn_norm.weight")?
+            .into_iter()
+            .map(|t| t.dequantize(GGMLType::F32))
+            .collect::<Result<Vec<_>>>()?;
+        let attn_norm_bias = load_layers_f32_optional("attn_norm.bias")?;
+        // Phi-2 runs the attention and the ffn in parallel on the same normalized input, the
+        // other architectures must have the norm before the ffn
+        let rms_ffn_weight = load_layers_f32_optional("ffn_norm.weight")?;
+        if conf.architecture != ModelArchitecture::Phi2 {
+            if let Some(l) = rms_ffn_weight.iter().position(|w| w.is_none()) {
+                return Err(Error {
+                    kind: ErrorKind::ModelError,
+                    message: format!("missing tensor blk.{}.ffn_norm.weight", l),
+                    cause: None,
+                });
+            }
         }
-        let rms_final_weight = Self::load_tensor(gf, "output_norm.weight", device.clone())?
-            .dequantize(GGMLType::F32)?;
+        let rms_final_weight = load("output_norm.weight".to_string())?.dequantize(GGMLType::F32)?;
+        let final_norm_bias = load_f32_optional("output_norm.bias".to_string())?;
 
-        // in Gemma, the output weight is None
-        let output_weight = Self::load_tensor_optional(gf, "output.weight", device)?;
+        // in Gemma and Qwen2, the output weight is None, the token embedding is tied with it
+        let output_weight = Self::load_tensor_optional(gf, "output.weight", device.clone())?;
+        let output_bias = load_f32_optional("output.bias".to_string())?;
 
-        Ok(Llama2Weights {
+        let mut weights = Llama2Weights {
             token_embed,
             wq,
             wk,
             wv,
             wo,
+            bq,
+            bk,
+            bv,
+            bo,
             ffn_gate_weight,
             ffn_down_weight,
             ffn_up_weight,
+            ffn_down_bias,
+            ffn_up_bias,
             rms_att_weight,
             rms_ffn_weight,
+            attn_norm_bias,
             rms_final_weight,
+            final_norm_bias,
             output_weight,
-        })
+            output_bias,
+            scales: vec![],
+            output_scale: None,
+            lora: vec![],
+            streamer: None,
+        };
+        Self::load_scales(gf, &mut weights, device)?;
+        Ok(weights)
+    }
+
+    // the scales of the output channels are named after the weights, like
+    // `blk.0.attn_q.scale`, with one scale for every row of the weight
+    fn load_scales(
+        gf: &'a GGUFFile<'a>,
+        weights: &mut Llama2Weights<CpuTensor<'a>>,
+        device: CpuTensorDeviceRef<'a>,
+    ) -> Result<()> {
+        let load_scale =
+            |name: &str, weight: Option<&CpuTensor<'a>>, scale: Option<CpuTensor<'a>>| {
+                let (weight, scale) = match (weight, scale) {
+                    (_, None) => return Ok(None),
+                    (Some(weight), Some(scale)) => (weight, scale),
+                    (None, Some(_)) => {
+                        return Err(Error {
+                            kind: ErrorKind::ModelError,
+                            message: format!("the scale {} has no weight", name),
+                            cause: None,
+                        });
+                    }
+                };
+                if scale.shape() != [weight.shape()[0]] {
+                    return Err(Error {
+                        kind: ErrorKind::ModelError,
+                        message: format!(
+                            "the scale {} of {:?} does not match the rows of the weight {:?}",
+                            name,
+                            scale.shape(),
+                            weight.shape()
+                        ),
+                        cause: None,
+                    });
+                }
+                // the scales are small, keep them in f32
+                scale.dequantize(GGMLType::F32).map(Some)
+            };
+
+        let mut scales = Vec::with_capacity(weights.wq.len());
+        for l in 0..weights.wq.len() {
+            let mut layer = HashMap::new();
+            let rows = [LoraTarget::AttnQ, LoraTarget::AttnK, LoraTarget::AttnV]
+                .map(|t| weights.weight(l, t).unwrap().shape()[0]);
+            let name = format!("blk.{}.attn_qkv.scale", l);
+            let qkv = Self::load_tensor_split(gf, &name, rows, device.clone())?;
+            for target in LoraTarget::ALL {
+                let name = format!("blk.{}.{}.scale", l, target.gguf_name());
+                let scale = match (&qkv, target) {
+                    (Some([q, _, _]), LoraTarget::AttnQ) => Some(q.clone()),
+                    (Some([_, k, _]), LoraTarget::AttnK) => Some(k.clone()),
+                    (Some([_, _, v]), LoraTarget::AttnV) => Some(v.clone()),
+                    _ => Self::load_tensor_optional(gf, &name, device.clone())?,
+                };
+                if let Some(scale) = load_scale(&name, weights.weight(l, target), scale)? {
+                    layer.insert(target, scale);
+                }
+            }
+            scales.push(layer);
+        }
+        weights.scales = scales;
+
+        let output_weight = weights
+            .output_weight
+            .as_ref()
+            .unwrap_or(&weights.token_embed);
+        let scale = Self::load_tensor_optional(gf, "output.scale", device)?;
+        weights.output_scale = load_scale("output.scale", Some(output_weight), scale)?;
+        Ok(())
     }
 
     pub(crate) fn load_tensor_optional(
@@ -225,6 +859,50 @@ impl<'a> CpuLlama2Model<'a> {
         Ok(Some(tensor))
     }
 
+    /// load the tensor whose rows are the concatenation of several tensors, like the packed
+    /// attn_qkv, as the views of each part in the order of the rows.
+    pub(crate) fn load_tensor_split<const N: usize>(
+        gf: &'a GGUFFile<'a>,
+        name: &str,
+        rows: [usize; N],
+        device: CpuTensorDeviceRef<'a>,
+    ) -> Result<Option<[CpuTensor<'a>; N]>> {
+        let info = match gf.get_tensor_info(name) {
+            None => return Ok(None),
+            Some(info) => info.clone(),
+        };
+        let dims = info.dimensions().iter().rev().copied().collect::<Vec<_>>();
+        let total_rows = rows.iter().sum::<usize>();
+        let data = info.data();
+        if dims[0] != total_rows || data.len() % total_rows != 0 {
+            return Err(Error {
+                kind: ErrorKind::ModelError,
+                message: format!(
+                    "tensor {} of {:?} can not be split into the rows of {:?}",
+                    name, dims, rows
+                ),
+                cause: None,
+            });
+        }
+
+        let row_bytes = data.len() / total_rows;
+        let mut offset = 0;
+        let mut parts = Vec::with_capacity(N);
+        for n in rows {
+            let mut part_dims = dims.clone();
+            part_dims[0] = n;
+            let part = &data[offset * row_bytes..(offset + n) * row_bytes];
+            parts.push(CpuTensor::from_bytes(
+                part,
+                info.typ(),
+                &part_dims,
+                device.clone(),
+            )?);
+            offset += n;
+        }
+        Ok(parts.try_into().ok())
+    }
+
     pub(crate) fn load_tensor(
         gf: &'a GGUFFile<'a>,
         name: &str,
@@ -240,44 +918,147 @@ impl<'a> CpuLlama2Model<'a> {
         }
     }
 
-    fn load_tokenizer(gf: &GGUFFile) -> BpeTokenizer {
+    pub(crate) fn load_tokenizer(gf: &GGUFFile) -> Result<BpeTokenizer> {
+        // the edited sidecar files of reload_tokenizer() come here too, a missing key is an
+        // error instead of a panic
+        let missing = |key: &str| Error {
+            kind: ErrorKind::ModelError,
+            message: format!("{} is missing", key),
+            cause: None,
+        };
         let vocab = gf
             .metadata()
             .get_string_array("tokenizer.ggml.tokens")
-            .unwrap()
+            .ok_or_else(|| missing("tokenizer.ggml.tokens"))?
             .iter()
             .map(|s| s.to_string())
             .collect::<Vec<_>>();
-        let vocab_scores = gf
-            .metadata()
-            .get_f32_array("tokenizer.ggml.scores")
-            .unwrap()
-            .to_vec();
-        let eos_token = gf
-            .metadata()
-            .get_u32("tokenizer.ggml.eos_token_id")
-            .unwrap() as usize;
-        let bos_token = gf
+        let eos_token =
+            gf.metadata()
+                .get_u32("tokenizer.ggml.eos_token_id")
+                .ok_or_else(|| missing("tokenizer.ggml.eos_token_id"))? as usize;
+        let bos_token =
+            gf.metadata()
+                .get_u32("tokenizer.ggml.bos_token_id")
+                .ok_or_else(|| missing("tokenizer.ggml.bos_token_id"))? as usize;
+        if let Some(id) = [bos_token, eos_token]
+            .into_iter()
+            .find(|id| *id >= vocab.len())
+        {
+            return Err(Error {
+                kind: ErrorKind::ModelError,
+                message: format!(
+                    "special token {} is out of the vocab of {}",
+                    id,
+                    vocab.len()
+                ),
+                cause: None,
+            });
+        }
+
+        // the gpt2 style tokenizers (like on Qwen2 and Phi-2) are ranked by the merges
+        // instead of the scores
+        let model = gf
             .metadata()
-            .get_u32("tokenizer.ggml.bos_token_id")
-            .unwrap() as usize;
-        BpeTokenizer::new(vocab, vocab_scores, bos_token, eos_token)
+            .get_string("tokenizer.ggml.model")
+            .unwrap_or("llama");
+        if model == "gpt2" {
+            let merges = match gf.metadata().get_string_array("tokenizer.ggml.merges") {
+                Some(merges) => merges,
+                None => {
+                    return Err(Error {
+                        kind: ErrorKind::ModelError,
+                        message: "tokenizer.ggml.merges is missing".to_string(),
+                        cause: None,
+                    });
+                }
+            };
+            let token_types = gf.metadata().get_i32_array("tokenizer.ggml.token_type");
+            let pre = PreTokenizer::from_name(
+                gf.metadata()
+                    .get_string("tokenizer.ggml.pre")
+                    .unwrap_or("default"),
+            );
+            return BpeTokenizer::new_byte_level(
+                vocab,
+                merges,
+                token_types,
+                pre,
+                bos_token,
+                eos_token,
+            );
+        }
+
+        let vocab_scores = match gf.metadata().get_f32_array("tokenizer.ggml.scores") {
+            Some(scores) => scores.to_vec(),
+            None => {
+                return Err(Error {
+                    kind: ErrorKind::ModelError,
+                    message: format!(
+                        "unsupported tokenizer {}, tokenizer.ggml.scores is missing",
+                        model
+                    ),
+                    cause: None,
+                });
+            }
+        };
+        Ok(BpeTokenizer::new(vocab, vocab_scores, bos_token, eos_token))
     }
 
-    fn load_config(gf: &GGUFFile) -> Result<Llama2Config> {
-        // let rope_dims = gf.metadata().get_u32("llama.rope.dimension_count").unwrap();
-        let (architecture, prefix) = match gf.metadata().get_string("general.architecture").unwrap()
-        {
-            "llama" => (ModelArchitecture::Llama, "llama"),
-            "gemma" => (ModelArchitecture::Gemma, "gemma"),
-            arch => {
+    // the rope dimension_count and freq_base may be arrays of one value per layer instead of
+    // a single value, the layers of a dimension_count of 0 are not rotated. the default fills
+    // the one which is not an array.
+    fn load_layer_ropes(
+        gf: &GGUFFile,
+        prefix: &str,
+        n_layers: usize,
+        default: LayerRope,
+    ) -> Result<Vec<LayerRope>> {
+        let dims_key = format!("{}.rope.dimension_count", prefix);
+        let freq_base_key = format!("{}.rope.freq_base", prefix);
+        let dims = gf.metadata().get_u32_array(&dims_key);
+        let freq_bases = gf.metadata().get_f32_array(&freq_base_key);
+        if dims.is_none() && freq_bases.is_none() {
+            return Ok(vec![]);
+        }
+        for (key, len) in [
+            (&dims_key, dims.map(|v| v.len())),
+            (&freq_base_key, freq_bases.map(|v| v.len())),
+        ] {
+            if let Some(len) = len.filter(|len| *len != n_layers) {
                 return Err(Error {
                     kind: ErrorKind::ModelError,
-                    message: format!("unsupported architecture {}", arch),
+                    message: format!(
+                        "{} has {} values, but there are {} layers",
+                        key, len, n_layers
+                    ),
+                    cause: None,
+                });
+            }
+        }
+
+        let ropes = (0..n_layers)
+            .map(|l| LayerRope {
+                dim: dims.map_or(default.dim, |v| v[l] as usize),
+                freq_base: freq_bases.map_or(default.freq_base, |v| v[l]),
+            })
+            .collect();
+        Ok(ropes)
+    }
+
+    pub(crate) fn load_config(gf: &GGUFFile) -> Result<Llama2Config> {
+        let arch_name = gf.metadata().get_string("general.architecture").unwrap();
+        let architecture = match ModelArchitecture::from_name(arch_name) {
+            Some(arch) => arch,
+            None => {
+                return Err(Error {
+                    kind: ErrorKind::ModelError,
+                    message: format!("unsupported architecture {}", arch_name),
                     cause: None,
                 });
             }
         };
+        let prefix = architecture.name();
 
         let n_heads = gf
             .metadata()
@@ -294,7 +1075,8 @@ impl<'a> CpuLlama2Model<'a> {
         let n_kv_heads = gf
             .metadata()
             .get_u32(&format!("{}.attention.head_count_kv", prefix))
-            .unwrap() as usize;
+            .map(|v| v as usize)
+            .unwrap_or(n_heads);
         let seq_len = gf
             .metadata()
             .get_u32(&format!("{}.context_length", prefix))
@@ -311,11 +1093,40 @@ impl<'a> CpuLlama2Model<'a> {
         let rms_norm_eps = gf
             .metadata()
             .get_f32(&format!("{}.attention.layer_norm_rms_epsilon", prefix))
+            .or_else(|| {
+                gf.metadata()
+                    .get_f32(&format!("{}.attention.layer_norm_epsilon", prefix))
+            })
             .unwrap();
         let n_rot = gf
             .metadata()
             .get_u32(&format!("{}.rope.dimension_count", prefix))
             .map(|v| v as usize);
+        let rope_freq_base = gf
+            .metadata()
+            .get_f32(&format!("{}.rope.freq_base", prefix))
+            .unwrap_or(10000.0);
+        let layer_ropes = Self::load_layer_ropes(gf, prefix, n_layers, LayerRope {
+            dim: n_rot.unwrap_or(embedding_dim / n_heads),
+            freq_base: rope_freq_base,
+        })?;
+        let sliding_window = gf
+            .metadata()
+            .get_u32(&format!("{}.attention.sliding_window", prefix))
+            .map(|v| v as usize)
+            .filter(|v| *v > 0);
+        let attn_soft_cap = gf
+            .metadata()
+            .get_f32(&format!("{}.attn_logit_softcapping", prefix))
+            .filter(|v| *v > 0.0);
+        let pooling = gf
+            .metadata()
+            .get_u32(&format!("{}.pooling_type", prefix))
+            .and_then(Pooling::from_gguf);
+        let chat_template = gf
+            .metadata()
+            .get_string("tokenizer.chat_template")
+            .map(|s| s.to_string());
 
         Ok(Llama2Config {
             architecture,
@@ -328,18 +1139,27 @@ impl<'a> CpuLlama2Model<'a> {
             vocab_size,
             rms_norm_eps,
             rope_dim: n_rot,
+            rope_freq_base,
+            layer_ropes,
+            sliding_window,
+            attn_soft_cap,
+            pooling,
+            chat_template,
         })
     }
 }
 
+#[cfg(not(target_os = "wasi"))]
 #[derive(Clone)]
 pub struct WgpuLlama2Model {
     pub conf: Llama2Config,
     pub weights: Rc<Llama2Weights<WgpuTensor>>,
     pub tokenizer: Rc<BpeTokenizer>,
     pub device: WgpuTensorDeviceRef,
+    pub fingerprint: u64,
 }
 
+#[cfg(not(target_os = "wasi"))]
 impl Llama2Model for &WgpuLlama2Model {
     type T = WgpuTensor;
 
@@ -358,8 +1178,13 @@ impl Llama2Model for &WgpuLlama2Model {
     fn tokenizer(&self) -> Rc<BpeTokenizer> {
         self.tokenizer.clone()
     }
+
+    fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
 }
 
+#[cfg(not(target_os = "wasi"))]
 impl WgpuLlama2Model {
     pub fn from_cpu(cpu_model: &CpuLlama2Model, device: WgpuTensorDeviceRef) -> Result<Self> {
         let weights = Self::convert_cpu_weights(&cpu_model.weights, device.clone())?;
@@ -368,107 +1193,140 @@ impl WgpuLlama2Model {
             weights: Rc::new(weights),
             tokenizer: cpu_model.tokenizer.clone(),
             device,
+            fingerprint: cpu_model.fingerprint,
         })
     }
 
+    /// see `CpuLlama2Model::reload_tokenizer()`.
+    pub fn reload_tokenizer(&mut self, gf: &GGUFFile) -> Result<TokenizerReload> {
+        CpuLlama2Model::reload_tokenizer_metadata(gf, &mut self.conf, &mut self.tokenizer)
+    }
+
     fn convert_cpu_weights(
         weights: &Llama2Weights<CpuTensor>,
         device: WgpuTensorDeviceRef,
     ) -> Result<Llama2Weights<WgpuTensor>> {
-        let token_embedding_table = Self::convert_cpu_tensor(&weights.token_embed, device.clone())?;
-        let wq = weights
-            .wq
-            .iter()
-            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
-            .collect::<Result<Vec<_>>>()?;
-        let wk = weights
-            .wk
-            .iter()
-            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
-            .collect::<Result<Vec<_>>>()?;
-        let wv = weights
-            .wv
-            .iter()
-            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
-            .collect::<Result<Vec<_>>>()?;
-        let wo = weights
-            .wo
-            .iter()
-            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
-            .collect::<Result<Vec<_>>>()?;
-        let w1 = weights
-            .ffn_gate_weight
-            .iter()
-            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
-            .collect::<Result<Vec<_>>>()?;
-        let w2 = weights
-            .ffn_down_weight
-            .iter()
-            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
-            .collect::<Result<Vec<_>>>()?;
-        let w3 = weights
-            .ffn_up_weight
-            .iter()
-            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
-            .collect::<Result<Vec<_>>>()?;
-        let rms_att_weight = weights
-            .rms_att_weight
-            .iter()
-            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
-            .collect::<Result<Vec<_>>>()?;
-        let rms_ffn_weight = weights
-            .rms_ffn_weight
-            .iter()
-            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
-            .collect::<Result<Vec<_>>>()?;
-        let rms_final_weight = Self::convert_cpu_tensor(&weights.rms_final_weight, device.clone())?;
-        let wcls = weights
-            .output_weight
-            .as_ref()
-            .map(|output_weight| Self::convert_cpu_tensor(output_weight, device.clone()).unwrap());
+        // the token embedding is copied by rows and the norms are small, they're in f32 on the
+        // gpu, while the q8_0 and q4_0 matmul weights are kept quantized.
+        let convert = |t: &CpuTensor| Self::convert_cpu_tensor(t, false, device.clone());
+        let convert_matmul = |t: &CpuTensor| Self::convert_cpu_tensor(t, true, device.clone());
+        let convert_optional = |t: &Option<CpuTensor>| t.as_ref().map(convert).transpose();
+        let convert_layers = |ts: &[CpuTensor]| ts.iter().map(convert).collect::<Result<Vec<_>>>();
+        let convert_layers_matmul =
+            |ts: &[CpuTensor]| ts.iter().map(convert_matmul).collect::<Result<Vec<_>>>();
+        let convert_layers_optional =
+            |ts: &[Option<CpuTensor>]| ts.iter().map(convert_optional).collect::<Result<Vec<_>>>();
+
+        // the token embedding doubles as the output weight when they're tied
+        let output_weight = match &weights.output_weight {
+            Some(t) => Some(convert_matmul(t)?),
+            None => None,
+        };
+
         let weights = Llama2Weights {
-            token_embed: token_embedding_table,
-            wq,
-            wk,
-            wv,
-            wo,
-            ffn_gate_weight: w1,
-            ffn_down_weight: w2,
-            ffn_up_weight: w3,
-            rms_att_weight,
-            rms_ffn_weight,
-            rms_final_weight,
-            output_weight: wcls,
+            token_embed: convert(&weights.token_embed)?,
+            wq: convert_layers_matmul(&weights.wq)?,
+            wk: convert_layers_matmul(&weights.wk)?,
+            wv: convert_layers_matmul(&weights.wv)?,
+            wo: convert_layers_matmul(&weights.wo)?,
+            bq: convert_layers_optional(&weights.bq)?,
+            bk: convert_layers_optional(&weights.bk)?,
+            bv: convert_layers_optional(&weights.bv)?,
+            bo: convert_layers_optional(&weights.bo)?,
+            ffn_gate_weight: weights
+                .ffn_gate_weight
+                .iter()
+                .map(|t| t.as_ref().map(convert_matmul).transpose())
+                .collect::<Result<Vec<_>>>()?,
+            ffn_down_weight: convert_layers_matmul(&weights.ffn_down_weight)?,
+            ffn_up_weight: convert_layers_matmul(&weights.ffn_up_weight)?,
+            ffn_down_bias: convert_layers_optional(&weights.ffn_down_bias)?,
+            ffn_up_bias: convert_layers_optional(&weights.ffn_up_bias)?,
+            rms_att_weight: convert_layers(&weights.rms_att_weight)?,
+            rms_ffn_weight: convert_layers_optional(&weights.rms_ffn_weight)?,
+            attn_norm_bias: convert_layers_optional(&weights.attn_norm_bias)?,
+            rms_final_weight: convert(&weights.rms_final_weight)?,
+            final_norm_bias: convert_optional(&weights.final_norm_bias)?,
+            output_weight,
+            output_bias: convert_optional(&weights.output_bias)?,
+            scales: weights
+                .scales
+                .iter()
+                .map(|layer| {
+                    layer
+                        .iter()
+                        .map(|(target, t)| Ok((*target, convert(t)?)))
+                        .collect::<Result<HashMap<_, _>>>()
+                })
+                .collect::<Result<Vec<_>>>()?,
+            output_scale: convert_optional(&weights.output_scale)?,
+            lora: weights
+                .lora
+                .iter()
+                .map(|layer| {
+                    layer
+                        .iter()
+                        .map(|(target, w)| {
+                            Ok((*target, LoraWeights {
+                                a: convert(&w.a)?,
+                                b: convert(&w.b)?,
+                            }))
+                        })
+                        .collect::<Result<HashMap<_, _>>>()
+                })
+                .collect::<Result<Vec<_>>>()?,
+            // the weights are copied onto the gpu, there's nothing to stream
+            streamer: None,
         };
         Ok(weights)
     }
 
-    fn convert_cpu_tensor(tensor: &CpuTensor, device: WgpuTensorDeviceRef) -> Result<WgpuTensor> {
-        let buf = tensor.buf();
-        let buf = match buf {
-            CpuTensorBuf::F32(buf) => buf,
-            _ => {
-                return Err(Error {
-                    kind: ErrorKind::TensorError,
-                    message: format!("unsupported tensor type on gpu {:?}", buf),
-                    cause: None,
-                });
-            }
-        };
-
-        let wgpu_tensor = WgpuTensor::new(buf, tensor.shape(), device.clone())?;
-        Ok(wgpu_tensor)
+    fn convert_cpu_tensor(
+        tensor: &CpuTensor,
+        keep_quantized: bool,
+        device: WgpuTensorDeviceRef,
+    ) -> Result<WgpuTensor> {
+        // the other dtypes have no kernel on the gpu yet, they're dequantized on upload
+        WgpuTensor::from_cpu_tensor(tensor, keep_quantized, device)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use crabml::backends::cpu::CpuTensor;
     use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::error::ErrorKind;
     use crabml::error::Result;
     use crabml::gguf::GGMLType;
     use crabml::gguf::GGUFFileLoader;
+    use crabml::gguf::GGUFLoadMode;
+    use crabml::gguf::GGUFMetadataArray;
+    use crabml::gguf::GGUFMetadataValue;
+    use crabml::gguf::GGUFWriter;
     use crabml::tensor::Tensor;
+    use crabml::tensor::TensorMetrics;
+    use rand::rngs::StdRng;
+    use rand::Rng;
+    use rand::SeedableRng;
 
+    use super::LayerRope;
+    use super::Llama2Config;
+    use super::LoraAdapter;
+    use super::LoraTarget;
+    use super::ModelArchitecture;
+    use super::ModelLoadOptions;
+    use super::TokenizerReload;
+    #[cfg(feature = "chat-template")]
+    use crate::chat::ChatMessage;
+    #[cfg(feature = "chat-template")]
+    use crate::chat::ChatTemplate;
+    use crate::llama2::Llama2Runner;
+    use crate::testing::GGUFBuilder;
+    use crate::testing::TempGGUF;
     use crate::CpuLlama2Model;
 
     #[test]
@@ -481,9 +1339,662 @@ mod tests {
         assert_eq!(lm.conf.vocab_size, 32000);
         assert_eq!(lm.weights.wk[0].dtype(), GGMLType::Q8_0);
         assert_eq!(lm.weights.rms_att_weight[0].dtype(), GGMLType::F32);
-        assert_eq!(lm.weights.rms_ffn_weight[0].dtype(), GGMLType::F32);
+        assert_eq!(
+            lm.weights.rms_ffn_weight[0].as_ref().unwrap().dtype(),
+            GGMLType::F32
+        );
+        assert!(lm.weights.ffn_gate_weight[0].is_some());
+        assert!(lm.weights.bq[0].is_none());
+        assert_eq!(lm.conf.architecture, ModelArchitecture::Llama);
+        assert_eq!(lm.conf.rope_freq_base, 10000.0);
+        assert_eq!(lm.conf.sliding_window, None);
         assert_eq!(lm.weights.rms_final_weight.dtype(), GGMLType::F32);
         assert_eq!(lm.weights.token_embed.dtype(), GGMLType::Q8_0);
         Ok(())
     }
+
+    #[test]
+    fn test_named_tensors() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+
+        let device = CpuTensorDevice::new();
+        let lm = CpuLlama2Model::load(&gf, device)?;
+        let tensors = lm.weights.named_tensors();
+        // every tensor in the file is loaded
+        let mut names = tensors.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+        let mut file_names = gf
+            .tensor_infos()
+            .iter()
+            .map(|t| t.name())
+            .collect::<Vec<_>>();
+        names.sort();
+        file_names.sort();
+        assert_eq!(names, file_names);
+
+        let wq = lm.weights.tensor("blk.1.attn_q.weight").unwrap();
+        assert_eq!(wq.strider().shape(), &[288, 288]);
+        assert_eq!(wq.dtype(), GGMLType::Q8_0);
+        // the raw bytes are the ones in the file
+        let info = gf.get_tensor_info("blk.1.attn_q.weight").unwrap();
+        assert_eq!(wq.buf().as_bytes(), info.data());
+        assert!(lm.weights.tensor("blk.9.attn_q.weight").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_fused_qkv() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+
+        // pack q, k and v of every layer into attn_qkv like Phi-2, the bias of layer 0 too
+        let fused_name = |name: &str| {
+            let (layer, rest) = name.rsplit_once(".attn_")?;
+            let suffix = ["q.weight", "k.weight", "v.weight"].contains(&rest);
+            suffix.then(|| (format!("{}.attn_qkv.weight", layer), rest == "q.weight"))
+        };
+        let mut b = GGUFBuilder::with_metadata_of(&gf);
+        for info in gf.tensor_infos() {
+            match fused_name(info.name()) {
+                Some((name, true)) => {
+                    let dims = info.dimensions();
+                    let kv_rows = lm.conf.kv_dim() * 2;
+                    let layer = &name[..name.len() - ".attn_qkv.weight".len()];
+                    let mut packed = info.data().to_vec();
+                    for part in ["attn_k.weight", "attn_v.weight"] {
+                        let part = gf.get_tensor_info(&format!("{}.{}", layer, part)).unwrap();
+                        packed.extend_from_slice(part.data());
+                    }
+                    b.tensor(&name, &[dims[0], dims[1] + kv_rows], info.typ(), packed)?;
+                }
+                Some((_, false)) => {}
+                None => b.copy_tensor(info)?,
+            }
+        }
+        let bias = (0..lm.conf.embedding_dim + lm.conf.kv_dim() * 2)
+            .flat_map(|i| (i as f32).to_le_bytes())
+            .collect::<Vec<_>>();
+        b.tensor(
+            "blk.0.attn_qkv.bias",
+            &[bias.len() / 4],
+            GGMLType::F32,
+            bias,
+        )?;
+        let fused = b.load("fused-qkv.gguf")?;
+
+        // the views have the same shapes and bytes as the separate tensors
+        let gf_fused = fused.open()?;
+        let lm_fused = CpuLlama2Model::load(&gf_fused, CpuTensorDevice::new())?;
+        for l in 0..lm.conf.n_layers {
+            let pairs = [
+                (&lm.weights.wq[l], &lm_fused.weights.wq[l]),
+                (&lm.weights.wk[l], &lm_fused.weights.wk[l]),
+                (&lm.weights.wv[l], &lm_fused.weights.wv[l]),
+            ];
+            for (a, b) in pairs {
+                assert_eq!(a.strider().shape(), b.strider().shape());
+                assert_eq!(a.buf().as_bytes(), b.buf().as_bytes());
+            }
+        }
+        let bk = lm_fused.weights.bk[0].as_ref().unwrap();
+        let mut buf = vec![0.0; lm.conf.kv_dim()];
+        bk.export(&mut buf)?;
+        assert_eq!(buf[0], lm.conf.embedding_dim as f32);
+        assert!(lm_fused.weights.bq[1].is_none());
+
+        // the rows of a packed tensor should add up
+        let conf = Llama2Config {
+            n_kv_heads: lm.conf.n_kv_heads / 2,
+            ..lm.conf.clone()
+        };
+        assert!(CpuLlama2Model::load_weights(&gf_fused, &conf, CpuTensorDevice::new()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_channel_scales() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let scaled = ["blk.0.attn_q", "blk.1.attn_k", "blk.2.ffn_down", "output"];
+        // the powers of two divide the weights without rounding, the activations of this
+        // model are sensitive to the rounding of the weights
+        let channel_scale = |r: usize| [0.5, 1.0, 2.0, 4.0][r % 4];
+
+        // the scaled weights are stored in f32 in both files, divided by the scales of
+        // their rows in the one with the scales
+        let write = |with_scales: bool, scale_rows: Option<usize>| -> Result<TempGGUF> {
+            let mut b = GGUFBuilder::with_metadata_of(&gf);
+            for info in gf.tensor_infos() {
+                let prefix = info.name().strip_suffix(".weight");
+                let Some(prefix) = prefix.filter(|p| scaled.contains(p)) else {
+                    b.copy_tensor(info)?;
+                    continue;
+                };
+                let (cols, rows) = (info.dimensions()[0], info.dimensions()[1]);
+                let t = CpuTensor::from_bytes(
+                    info.data(),
+                    info.typ(),
+                    &[rows, cols],
+                    CpuTensorDevice::new(),
+                )?;
+                let mut weight = vec![0.0; rows * cols];
+                t.dequantize(GGMLType::F32)?.export(&mut weight)?;
+                if with_scales {
+                    for (r, row) in weight.chunks_mut(cols).enumerate() {
+                        row.iter_mut().for_each(|v| *v /= channel_scale(r));
+                    }
+                    let n = scale_rows.unwrap_or(rows);
+                    let scales = (0..n).flat_map(|r| channel_scale(r).to_le_bytes());
+                    let name = format!("{}.scale", prefix);
+                    b.tensor(&name, &[n], GGMLType::F32, scales.collect())?;
+                }
+                let weight = weight.iter().flat_map(|v| v.to_le_bytes()).collect();
+                b.tensor(info.name(), info.dimensions(), GGMLType::F32, weight)?;
+            }
+            b.load("scales.gguf")
+        };
+
+        let mut logits = vec![];
+        for with_scales in [false, true] {
+            let file = write(with_scales, None)?;
+            let gf = file.open()?;
+            let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+            assert_eq!(
+                lm.weights.scale(0, LoraTarget::AttnQ).is_some(),
+                with_scales
+            );
+            assert_eq!(
+                lm.weights.scale(2, LoraTarget::FfnDown).is_some(),
+                with_scales
+            );
+            assert!(lm.weights.scale(0, LoraTarget::AttnK).is_none());
+            assert_eq!(lm.weights.output_scale.is_some(), with_scales);
+            assert_eq!(
+                lm.weights.tensor("blk.1.attn_k.scale").is_some(),
+                with_scales
+            );
+
+            let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 32, GGMLType::F32)?;
+            logits.push(runner.forward_batch_all(&[1, 365, 931], 0)?);
+        }
+        // the scales applied on the outputs of the matmuls restore the plain weights
+        for (a, b) in logits[0].iter().zip(logits[1].iter()) {
+            assert!((a - b).abs() < 1e-3 * a.abs().max(1.0), "{} != {}", a, b);
+        }
+
+        // a scale for every row of the weight
+        let file = write(true, Some(7))?;
+        let err = CpuLlama2Model::load(&file.open()?, CpuTensorDevice::new())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind, ErrorKind::ModelError);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reload_tokenizer() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let mut lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 32, GGMLType::F32)?;
+
+        // a sidecar of only the metadata, without the tensors
+        let sidecar = |name: &str, metadata: Vec<(&str, GGUFMetadataValue)>| -> Result<_> {
+            let mut b = GGUFBuilder::new("llama");
+            for (k, v) in metadata {
+                b.metadata(k, v);
+            }
+            b.load(&format!("sidecar-{}.gguf", name))
+        };
+
+        // only the chat template
+        let template = "{% for m in messages %}<|{{ m.role }}|>{{ m.content }}{% endfor %}";
+        let gl = sidecar("template", vec![(
+            "tokenizer.chat_template",
+            GGUFMetadataValue::String(template),
+        )])?;
+        let reload = lm.reload_tokenizer(&gl.open()?)?;
+        assert_eq!(reload, TokenizerReload {
+            tokenizer: false,
+            chat_template: true,
+        });
+        assert_eq!(lm.conf.chat_template.as_deref(), Some(template));
+        assert_eq!(runner.conf().chat_template, None);
+        runner.reload_tokenizer(&lm)?;
+        assert_eq!(runner.conf().chat_template.as_deref(), Some(template));
+        #[cfg(feature = "chat-template")]
+        {
+            let prompt = ChatTemplate::from_model(&lm.conf, &lm.tokenizer).render(
+                &[ChatMessage::user("Hi")],
+                None,
+                false,
+            )?;
+            assert_eq!(prompt, "<|user|>Hi");
+
+            // a broken template replaces nothing
+            let gl = sidecar("broken", vec![(
+                "tokenizer.chat_template",
+                GGUFMetadataValue::String("{% for m in messages %}{{ m.role }"),
+            )])?;
+            assert!(lm.reload_tokenizer(&gl.open()?).is_err());
+            assert_eq!(lm.conf.chat_template.as_deref(), Some(template));
+        }
+
+        // the tokenizer of the same vocab with another eos token
+        let tokens = lm.tokenizer.vocab().to_vec();
+        let scores = gf
+            .metadata()
+            .get_f32_array("tokenizer.ggml.scores")
+            .unwrap()
+            .to_vec();
+        let tokenizer_metadata = |n_tokens: usize| {
+            vec![
+                (
+                    "tokenizer.ggml.tokens",
+                    GGUFMetadataValue::Array(GGUFMetadataArray::StringArray(
+                        tokens[..n_tokens].iter().map(|t| t.as_str()).collect(),
+                    )),
+                ),
+                (
+                    "tokenizer.ggml.scores",
+                    GGUFMetadataValue::Array(GGUFMetadataArray::F32Array(
+                        scores[..n_tokens].to_vec().into(),
+                    )),
+                ),
+                ("tokenizer.ggml.bos_token_id", GGUFMetadataValue::U32(1)),
+                ("tokenizer.ggml.eos_token_id", GGUFMetadataValue::U32(13)),
+            ]
+        };
+        let gl = sidecar("tokenizer", tokenizer_metadata(tokens.len()))?;
+        let reload = lm.reload_tokenizer(&gl.open()?)?;
+        assert_eq!(reload, TokenizerReload {
+            tokenizer: true,
+            chat_template: false,
+        });
+        assert_eq!(lm.tokenizer.eos_token(), 13);
+        assert_eq!(lm.conf.chat_template.as_deref(), Some(template));
+        assert_eq!(runner.tokenizer().eos_token(), 2);
+        runner.reload_tokenizer(&lm)?;
+        assert_eq!(runner.tokenizer().eos_token(), 13);
+
+        // a vocab which does not fit the embedding, or nothing to reload
+        let gl = sidecar("small", tokenizer_metadata(64))?;
+        let err = lm.reload_tokenizer(&gl.open()?).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::ModelError);
+        assert_eq!(lm.tokenizer.vocab().len(), tokens.len());
+        let gl = sidecar("empty", vec![])?;
+        let err = lm.reload_tokenizer(&gl.open()?).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadInput);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_layer_ropes() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf")?;
+        let gf = gl.open()?;
+        let conf = CpuLlama2Model::load_config(&gf)?;
+        assert!(conf.layer_ropes.is_empty());
+        assert_eq!(conf.rope(0).dim, conf.head_size());
+
+        let load = |dims: Vec<u32>, freq_bases: Option<Vec<f32>>| {
+            let mut b = GGUFBuilder::with_metadata_of(&gf);
+            b.metadata(
+                "llama.rope.dimension_count",
+                GGUFMetadataValue::Array(GGUFMetadataArray::U32Array(dims.into())),
+            );
+            if let Some(freq_bases) = freq_bases {
+                b.metadata(
+                    "llama.rope.freq_base",
+                    GGUFMetadataValue::Array(GGUFMetadataArray::F32Array(freq_bases.into())),
+                );
+            }
+            let file = b.load("layer-ropes.gguf")?;
+            CpuLlama2Model::load_config(&file.open()?)
+        };
+
+        // the freq_base of every layer falls back to the global one
+        let n_layers = conf.n_layers;
+        let mut dims = vec![4; n_layers];
+        dims[0] = 0;
+        let conf = load(dims.clone(), None)?;
+        assert_eq!(conf.layer_ropes.len(), n_layers);
+        assert_eq!(conf.rope(0).dim, 0);
+        assert_eq!(conf.rope(1), LayerRope {
+            dim: 4,
+            freq_base: 10000.0
+        });
+
+        let freq_bases = (0..n_layers).map(|l| 1000.0 * (l + 1) as f32).collect();
+        let conf = load(dims.clone(), Some(freq_bases))?;
+        assert_eq!(conf.rope(1).freq_base, 2000.0);
+
+        // one value per layer
+        assert!(load(dims[1..].to_vec(), None).is_err());
+        assert!(load(dims, Some(vec![10000.0])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_shared_weights() -> Result<()> {
+        let path = "../testdata/tinyllamas-stories-15m-q8_0.gguf";
+        let options = ModelLoadOptions::new().with_shared_weights(true);
+        let gl = options.open(path)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load_with_options(&gf, CpuTensorDevice::new(), options)?;
+        assert!(lm.weights.private_tensors(&gf).is_empty());
+
+        // a buffered file has its own copy in every process
+        let options = ModelLoadOptions::new()
+            .with_load_mode(GGUFLoadMode::Buffered)
+            .with_shared_weights(true);
+        let gl = options.open(path)?;
+        let gf = gl.open()?;
+        assert!(CpuLlama2Model::load_with_options(&gf, CpuTensorDevice::new(), options).is_err());
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let n_private = lm.weights.private_tensors(&gf).len();
+        assert_eq!(n_private, lm.weights.named_tensors().len());
+
+        // so are the weights merged with a lora adapter
+        let adapter = LoraAdapter::new().with_tensor(
+            0,
+            LoraTarget::AttnQ,
+            vec![0.0; 288],
+            vec![0.0; 288],
+            1,
+        )?;
+        let options = ModelLoadOptions::new()
+            .with_lora(adapter)
+            .with_shared_weights(true);
+        let gl = options.open(path)?;
+        let gf = gl.open()?;
+        let err = CpuLlama2Model::load_with_options(&gf, CpuTensorDevice::new(), options)
+            .err()
+            .unwrap();
+        assert!(err.message.contains("blk.0.attn_q.weight"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_buffered() -> Result<()> {
+        let path = "../testdata/tinyllamas-stories-15m-q8_0.gguf";
+        let loaded = Arc::new(AtomicUsize::new(0));
+        let loaded2 = loaded.clone();
+        let options = ModelLoadOptions::new()
+            .with_load_mode(GGUFLoadMode::Buffered)
+            .with_progress(move |n, total| {
+                assert!(n <= total);
+                loaded2.store(n, Ordering::Relaxed);
+            });
+        let gl = options.open(path)?;
+        assert_eq!(loaded.load(Ordering::Relaxed), gl.len());
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load_with_options(&gf, CpuTensorDevice::new(), options)?;
+
+        // the same model as the mmapped one
+        let gl2 = GGUFFileLoader::new(path)?;
+        let gf2 = gl2.open()?;
+        let lm2 = CpuLlama2Model::load(&gf2, CpuTensorDevice::new())?;
+        assert_eq!(lm.fingerprint, lm2.fingerprint);
+        assert_eq!(
+            lm.weights.wq[3].buf().as_bytes(),
+            lm2.weights.wq[3].buf().as_bytes()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_architecture_registry() {
+        for name in ["llama", "gemma", "qwen2", "phi2"] {
+            let arch = ModelArchitecture::from_name(name).unwrap();
+            assert_eq!(arch.name(), name);
+        }
+        assert_eq!(ModelArchitecture::from_name("gpt2"), None);
+        assert_eq!(ModelArchitecture::from_name("mistral"), None);
+    }
+
+    #[test]
+    fn test_load_first_n_layers() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+
+        let device = CpuTensorDevice::new();
+        let lm = CpuLlama2Model::load_with_options(
+            &gf,
+            device.clone(),
+            ModelLoadOptions::new().with_n_layers(2),
+        )?;
+        assert_eq!(lm.conf.n_layers, 2);
+        assert_eq!(lm.weights.wq.len(), 2);
+        assert_eq!(lm.weights.ffn_gate_weight.len(), 2);
+        assert_eq!(lm.weights.rms_final_weight.dtype(), GGMLType::F32);
+
+        for n_layers in [0, 7] {
+            let result = CpuLlama2Model::load_with_options(
+                &gf,
+                device.clone(),
+                ModelLoadOptions::new().with_n_layers(n_layers),
+            );
+            assert!(result.is_err());
+        }
+        Ok(())
+    }
+
+    // a tiny model of the architecture with the random weights in f32, 2 layers of 128 dims
+    // in 4 heads of 32, 2 of them for the kv except phi2, and a vocab of 64. the llama one attends
+    // to a sliding window of 2 tokens like Mistral.
+    fn random_model(arch: ModelArchitecture) -> Result<Vec<u8>> {
+        random_model_without(arch, &[])
+    }
+
+    // the random model without the tensors of the names, like `ffn_norm.weight`
+    fn random_model_without(arch: ModelArchitecture, missing: &[&str]) -> Result<Vec<u8>> {
+        let (dim, hidden, vocab, n_layers, n_heads) = (128, 256, 64, 2, 4);
+        let n_kv_heads = if arch == ModelArchitecture::Phi2 {
+            4
+        } else {
+            2
+        };
+        let kv_dim = dim / n_heads * n_kv_heads;
+        let prefix = arch.name();
+        let tokens = (0..vocab)
+            .map(|i| match i {
+                0 => "<unk>".to_string(),
+                1 => "<s>".to_string(),
+                2 => "</s>".to_string(),
+                i => format!("t{}", i),
+            })
+            .collect::<Vec<_>>();
+        let scores = (0..vocab).map(|i| -(i as f32)).collect::<Vec<_>>();
+
+        let mut w = GGUFWriter::new(vec![], prefix);
+        let key = |k: &str| format!("{}.{}", prefix, k);
+        for (k, v) in [
+            ("block_count", n_layers),
+            ("context_length", 32),
+            ("embedding_length", dim),
+            ("feed_forward_length", hidden),
+            ("attention.head_count", n_heads),
+            ("attention.head_count_kv", n_kv_heads),
+        ] {
+            w.add_metadata(&key(k), GGUFMetadataValue::U32(v as u32));
+        }
+        if arch == ModelArchitecture::Llama {
+            w.add_metadata(&key("attention.sliding_window"), GGUFMetadataValue::U32(2));
+        }
+        if arch == ModelArchitecture::Phi2 {
+            // rotate half of each head
+            w.add_metadata(&key("rope.dimension_count"), GGUFMetadataValue::U32(16));
+            w.add_metadata(
+                &key("attention.layer_norm_epsilon"),
+                GGUFMetadataValue::F32(1e-5),
+            );
+        } else {
+            w.add_metadata(
+                &key("attention.layer_norm_rms_epsilon"),
+                GGUFMetadataValue::F32(1e-5),
+            );
+        }
+        w.add_metadata(
+            "tokenizer.ggml.tokens",
+            GGUFMetadataValue::Array(GGUFMetadataArray::StringArray(
+                tokens.iter().map(|t| t.as_str()).collect(),
+            )),
+        );
+        w.add_metadata(
+            "tokenizer.ggml.scores",
+            GGUFMetadataValue::Array(GGUFMetadataArray::F32Array(scores.into())),
+        );
+        w.add_metadata("tokenizer.ggml.bos_token_id", GGUFMetadataValue::U32(1));
+        w.add_metadata("tokenizer.ggml.eos_token_id", GGUFMetadataValue::U32(2));
+
+        // (name, dims in the ggml order, whether a norm weight around 1.0)
+        let mut tensors = vec![("token_embd.weight".to_string(), vec![dim, vocab], false)];
+        for l in 0..n_layers {
+            let mut add = |name: &str, dims: Vec<usize>, norm: bool| {
+                tensors.push((format!("blk.{}.{}", l, name), dims, norm))
+            };
+            add("attn_norm.weight", vec![dim], true);
+            if arch == ModelArchitecture::Phi2 {
+                // the packed qkv and the biases everywhere
+                add("attn_norm.bias", vec![dim], false);
+                add("attn_qkv.weight", vec![dim, dim + kv_dim * 2], false);
+                add("attn_qkv.bias", vec![dim + kv_dim * 2], false);
+                add("attn_output.bias", vec![dim], false);
+                add("ffn_up.bias", vec![hidden], false);
+                add("ffn_down.bias", vec![dim], false);
+            } else {
+                add("attn_q.weight", vec![dim, dim], false);
+                add("attn_k.weight", vec![dim, kv_dim], false);
+                add("attn_v.weight", vec![dim, kv_dim], false);
+                add("ffn_norm.weight", vec![dim], true);
+                add("ffn_gate.weight", vec![dim, hidden], false);
+            }
+            if arch == ModelArchitecture::Qwen2 {
+                add("attn_q.bias", vec![dim], false);
+                add("attn_k.bias", vec![kv_dim], false);
+                add("attn_v.bias", vec![kv_dim], false);
+            }
+            add("attn_output.weight", vec![dim, dim], false);
+            add("ffn_up.weight", vec![dim, hidden], false);
+            add("ffn_down.weight", vec![hidden, dim], false);
+        }
+        tensors.push(("output_norm.weight".to_string(), vec![dim], true));
+        match arch {
+            // the output is tied with the token embedding
+            ModelArchitecture::Gemma | ModelArchitecture::Qwen2 => {}
+            ModelArchitecture::Phi2 => {
+                tensors.push(("output_norm.bias".to_string(), vec![dim], false));
+                tensors.push(("output.weight".to_string(), vec![dim, vocab], false));
+                tensors.push(("output.bias".to_string(), vec![vocab], false));
+            }
+            ModelArchitecture::Llama => {
+                tensors.push(("output.weight".to_string(), vec![dim, vocab], false));
+            }
+        }
+
+        tensors.retain(|(name, _, _)| !missing.iter().any(|m| name.ends_with(m)));
+        for (name, dims, _) in tensors.iter() {
+            w.add_tensor_info(name, dims, GGMLType::F32)?;
+        }
+        w.write_header()?;
+        let mut rng = StdRng::seed_from_u64(arch as u64);
+        for (_, dims, norm) in tensors.iter() {
+            let n = dims.iter().product::<usize>();
+            let data = (0..n)
+                .map(|_| {
+                    let v = rng.gen_range(-0.1..0.1);
+                    if *norm { 1.0 + v } else { v }
+                })
+                .flat_map(|v: f32| v.to_le_bytes())
+                .collect::<Vec<_>>();
+            w.write_tensor_data(&data)?;
+        }
+        w.finish()
+    }
+
+    #[test]
+    fn test_architecture_smoke() -> Result<()> {
+        for name in ["llama", "gemma", "qwen2", "phi2"] {
+            let arch = ModelArchitecture::from_name(name).unwrap();
+            let gl = TempGGUF::new(&format!("random-{}.gguf", name), random_model(arch)?)?;
+            let gf = gl.open()?;
+            let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+            assert_eq!(lm.conf.architecture, arch);
+            assert_eq!(lm.conf.vocab_size, 64);
+            let window = (arch == ModelArchitecture::Llama).then_some(2);
+            assert_eq!(lm.conf.sliding_window, window);
+            assert_eq!(lm.weights.wk[1].strider().shape(), &[lm.conf.kv_dim(), 128]);
+
+            // the batched prefill agrees with the tokens forwarded one by one
+            let tokens = [1, 5, 9, 13];
+            let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 32, GGMLType::F32)?;
+            let all = runner.forward_batch_all(&tokens, 0)?;
+            assert_eq!(all.len(), tokens.len() * 64);
+            assert!(all.iter().all(|v| v.is_finite()), "{}", name);
+            runner.reset_kv_cache()?;
+            for (pos, token) in tokens.iter().enumerate() {
+                let logits = runner.forward(*token, pos)?;
+                assert_eq!(logits.len(), 64);
+                let expected = &all[pos * 64..(pos + 1) * 64];
+                for (a, b) in logits.iter().zip(expected) {
+                    assert!((a - b).abs() < 1e-3, "{}: {} != {}", name, a, b);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_require_ffn_norm() -> Result<()> {
+        for name in ["llama", "gemma", "qwen2", "phi2"] {
+            let arch = ModelArchitecture::from_name(name).unwrap();
+            let gl = TempGGUF::new(
+                &format!("no-ffn-norm-{}.gguf", name),
+                random_model_without(arch, &["ffn_norm.weight"])?,
+            )?;
+            let gf = gl.open()?;
+            let result = CpuLlama2Model::load(&gf, CpuTensorDevice::new());
+            // only Phi-2 has no norm before the ffn
+            assert_eq!(result.is_ok(), arch == ModelArchitecture::Phi2, "{}", name);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_q8_0_kv_cache_gqa() -> Result<()> {
+        // 2 query heads share each kv head, and the heads of 32 fit in the q8_0 blocks
+        let gl = TempGGUF::new("random-gqa.gguf", random_model(ModelArchitecture::Llama)?)?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        assert!(lm.conf.n_kv_heads < lm.conf.n_heads);
+        assert_eq!(lm.conf.head_size() % 32, 0);
+
+        let tokens = [1, 5, 9, 13, 17, 21];
+        let mut logits = vec![];
+        for dtype in [GGMLType::F32, GGMLType::Q8_0] {
+            let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 32, dtype)?;
+            assert_eq!(runner.kv_cache_dtype(), dtype);
+            // the prompt in a batch, then the decoding one by one over the cached positions
+            let mut all = runner.forward_batch_all(&tokens[..4], 0)?;
+            for (pos, token) in tokens.iter().enumerate().skip(4) {
+                all.extend_from_slice(runner.forward(*token, pos)?);
+            }
+            logits.push(all);
+        }
+        // the q8_0 rounding moves the logits by about 1% of the largest one, while a query
+        // head attending to the wrong kv head moves them by a third of it
+        let max = logits[0].iter().fold(0.0f32, |m, v| m.max(v.abs()));
+        for (a, b) in logits[0].chunks(64).zip(logits[1].chunks(64)) {
+            let diffs = a
+                .iter()
+                .zip(b)
+                .map(|(a, b)| (a - b).abs())
+                .collect::<Vec<_>>();
+            let mean = diffs.iter().sum::<f32>() / diffs.len() as f32;
+            assert!(mean <= max * 1e-2, "{} vs {}", mean, max);
+            assert!(diffs.iter().all(|d| *d <= max * 5e-2), "{:?}", diffs);
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,101 @@
+use crabml::sampler::log_sum_exp;
+
+#[derive(Debug, Clone)]
+pub struct PerplexityOptions {
+    /// the tokens of each chunk, the kv cache is reset between the chunks so every chunk is
+    /// scored on its own. None takes the capacity of the kv cache.
+    pub n_ctx: Option<usize>,
+    /// the max tokens of a forward pass, the logits of all of them are kept, so it bounds the
+    /// memory of (n_batch, vocab_size). None takes the whole chunk, or the max batch in the
+    /// memory budget.
+    pub n_batch: Option<usize>,
+    /// start every chunk with the bos token, so the first token of the chunk is also scored.
+    pub add_bos: bool,
+}
+
+impl Default for PerplexityOptions {
+    fn default() -> Self {
+        Self {
+            n_ctx: None,
+            n_batch: None,
+            add_bos: true,
+        }
+    }
+}
+
+impl PerplexityOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_n_ctx(mut self, n_ctx: usize) -> Self {
+        self.n_ctx = Some(n_ctx);
+        self
+    }
+
+    pub fn with_n_batch(mut self, n_batch: usize) -> Self {
+        self.n_batch = Some(n_batch);
+        self
+    }
+
+    pub fn with_add_bos(mut self, add_bos: bool) -> Self {
+        self.add_bos = add_bos;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Perplexity {
+    /// the log likelihood of every scored token in the order of the input, a token is not
+    /// scored if it starts a chunk without the bos.
+    pub logprobs: Vec<f32>,
+    /// the negative log likelihood summed over the scored tokens, in f64 to not lose the
+    /// precision over a long text.
+    pub nll: f64,
+}
+
+impl Perplexity {
+    pub fn n_scored(&self) -> usize {
+        self.logprobs.len()
+    }
+
+    /// `exp(nll / n_scored)`, it's 1.0 if no token is scored yet.
+    pub fn perplexity(&self) -> f64 {
+        if self.logprobs.is_empty() {
+            return 1.0;
+        }
+        (self.nll / self.logprobs.len() as f64).exp()
+    }
+
+    pub(crate) fn push(&mut self, logprob: f32) {
+        self.logprobs.push(logprob);
+        self.nll -= logprob as f64;
+    }
+}
+
+/// the log probability of the token in the softmax of the logits.
+pub fn token_logprob(logits: &[f32], token: usize) -> f32 {
+    logits[token] - log_sum_exp(logits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_perplexity() {
+        let mut ppl = Perplexity::default();
+        assert_eq!(ppl.perplexity(), 1.0);
+
+        // uniform over 4 tokens
+        let logits = [0.5; 4];
+        ppl.push(token_logprob(&logits, 1));
+        ppl.push(token_logprob(&logits, 3));
+        assert_eq!(ppl.n_scored(), 2);
+        assert!((ppl.perplexity() - 4.0).abs() < 1e-5);
+
+        let logprob = token_logprob(&[1.0, 2.0, 3.0], 2);
+        let expected = (3.0f32.exp() / (1.0f32.exp() + 2.0f32.exp() + 3.0f32.exp())).ln();
+        assert!((logprob - expected).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,366 @@
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::fence;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use memmap2::MmapMut;
+
+const SESSION_MAGIC: &[u8; 8] = b"CRABSESS";
+const SESSION_VERSION: u32 = 1;
+// magic | version | fingerprint | n_layers, n_kv_heads, head_size, pos
+const SESSION_HEADER_BYTES: usize = 8 + 4 + 8 + 4 * 4;
+
+/// the kv cache a session is restored into, `Session::load_checked()` checks the header of
+/// the file against it before allocating the caches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionShape {
+    pub n_layers: usize,
+    pub n_kv_heads: usize,
+    pub head_size: usize,
+    /// the capacity of the kv cache, a session can not have more positions.
+    pub seq_len: usize,
+}
+
+/// a snapshot of the kv cache of a runner, to resume a conversation without re-running the
+/// whole prompt. create it with `Llama2Runner::session()` and restore it with
+/// `Llama2Runner::restore_session()`, which refuses a session created from another model.
+/// `SharedSession` passes it between the processes through the shared memory.
+///
+/// the file layout (little endian):
+///
+/// ```text
+/// magic "CRABSESS" | version: u32 | fingerprint: u64 | n_layers: u32 | n_kv_heads: u32 |
+/// head_size: u32 | pos: u32 | (key cache, value cache) of every layer in f32
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    pub fingerprint: u64,
+    pub n_layers: usize,
+    pub n_kv_heads: usize,
+    pub head_size: usize,
+    /// the number of positions filled in the kv cache, the next token is forwarded at pos.
+    pub pos: usize,
+    pub key_cache: Vec<Vec<f32>>, // (layer, n_kv_heads, pos, head_size)
+    pub value_cache: Vec<Vec<f32>>, // (layer, n_kv_heads, pos, head_size)
+}
+
+impl Session {
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let file = File::create(path).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to create the session file: {}", path.display()),
+            cause: Some(Box::new(err)),
+        })?;
+        self.write_to(&mut BufWriter::new(file))
+            .map_err(|err| Error {
+                kind: ErrorKind::IOError,
+                message: format!("failed to write the session file: {}", path.display()),
+                cause: Some(Box::new(err)),
+            })
+    }
+
+    /// load a session file, the sizes in the header must agree with the size of the file.
+    /// prefer `load_checked()` or `Llama2Runner::load_session()`, which also check them
+    /// against the model.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::load_file(path.as_ref(), None)
+    }
+
+    /// load a session file created from a model of the shape, a file of another shape is
+    /// refused before reading the caches.
+    pub fn load_checked(path: impl AsRef<Path>, shape: &SessionShape) -> Result<Self> {
+        Self::load_file(path.as_ref(), Some(shape))
+    }
+
+    fn load_file(path: &Path, shape: Option<&SessionShape>) -> Result<Self> {
+        let file = File::open(path).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to open the session file: {}", path.display()),
+            cause: Some(Box::new(err)),
+        })?;
+        let len = file.metadata().map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to stat the session file: {}", path.display()),
+            cause: Some(Box::new(err)),
+        })?;
+        Self::read_from(&mut BufReader::new(file), len.len(), shape)
+    }
+
+    // len is the bytes of the whole session in r
+    fn read_from(r: &mut impl Read, len: u64, shape: Option<&SessionShape>) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        read_exact(r, &mut magic)?;
+        if &magic != SESSION_MAGIC {
+            return Err((ErrorKind::FormatError, "not a session file").into());
+        }
+        let version = read_u32(r)?;
+        if version != SESSION_VERSION {
+            return Err((
+                ErrorKind::FormatError,
+                format!("unsupported session version: {}", version),
+            )
+                .into());
+        }
+
+        let mut fingerprint = [0u8; 8];
+        read_exact(r, &mut fingerprint)?;
+        let fingerprint = u64::from_le_bytes(fingerprint);
+        let n_layers = read_u32(r)? as usize;
+        let n_kv_heads = read_u32(r)? as usize;
+        let head_size = read_u32(r)? as usize;
+        let pos = read_u32(r)? as usize;
+
+        if let Some(shape) = shape {
+            if n_layers != shape.n_layers
+                || n_kv_heads != shape.n_kv_heads
+                || head_size != shape.head_size
+            {
+                return Err((
+                    ErrorKind::BadInput,
+                    "the session is created from a different model",
+                )
+                    .into());
+            }
+            if pos > shape.seq_len {
+                return Err((
+                    ErrorKind::BadInput,
+                    format!(
+                        "the session has {} positions, larger than the seq len {}",
+                        pos, shape.seq_len
+                    ),
+                )
+                    .into());
+            }
+        }
+        // the caches take exactly the rest of the file, a corrupted header can not make it
+        // allocate more than the file holds
+        let n_elems = n_kv_heads
+            .checked_mul(pos)
+            .and_then(|n| n.checked_mul(head_size));
+        let data_bytes = n_elems
+            .and_then(|n| n.checked_mul(n_layers))
+            .and_then(|n| n.checked_mul(2 * 4))
+            .map(|n| n as u64);
+        if data_bytes != Some(len.saturating_sub(SESSION_HEADER_BYTES as u64)) {
+            return Err((
+                ErrorKind::FormatError,
+                format!(
+                    "the session header does not match the {} bytes of the session",
+                    len
+                ),
+            )
+                .into());
+        }
+
+        let n_elems = n_elems.unwrap();
+        let mut key_cache = Vec::with_capacity(n_layers);
+        let mut value_cache = Vec::with_capacity(n_layers);
+        for _ in 0..n_layers {
+            key_cache.push(read_f32s(r, n_elems)?);
+            value_cache.push(read_f32s(r, n_elems)?);
+        }
+
+        Ok(Self {
+            fingerprint,
+            n_layers,
+            n_kv_heads,
+            head_size,
+            pos,
+            key_cache,
+            value_cache,
+        })
+    }
+
+    /// the bytes of the session in the file.
+    pub fn bytes(&self) -> usize {
+        let n_floats: usize = self
+            .key_cache
+            .iter()
+            .chain(self.value_cache.iter())
+            .map(|c| c.len())
+            .sum();
+        SESSION_HEADER_BYTES + n_floats * 4
+    }
+
+    fn write_to(&self, w: &mut impl Write) -> std::io::Result<()> {
+        w.write_all(SESSION_MAGIC)?;
+        w.write_all(&SESSION_VERSION.to_le_bytes())?;
+        w.write_all(&self.fingerprint.to_le_bytes())?;
+        for v in [self.n_layers, self.n_kv_heads, self.head_size, self.pos] {
+            w.write_all(&(v as u32).to_le_bytes())?;
+        }
+        for (k, v) in self.key_cache.iter().zip(self.value_cache.iter()) {
+            for x in k.iter().chain(v.iter()) {
+                w.write_all(&x.to_le_bytes())?;
+            }
+        }
+        w.flush()
+    }
+}
+
+const SHARED_MAGIC: &[u8; 8] = b"CRABSHM1";
+// magic | generation: u64 | len: u64 | the session in the file layout
+const SHARED_HEADER_BYTES: usize = 24;
+// the reads retried on a concurrent store, before giving up on a writer died in the middle
+const SHARED_READ_RETRIES: usize = 1000;
+
+/// a region of memory shared between the processes holding one session, like the kv cache
+/// of a common system prompt, so the processes serving the same model prefill it once and
+/// restore it from there. the region is a file mapped by every process, put it on a tmpfs
+/// like `/dev/shm` to keep it off the disk.
+///
+/// a store bumps the generation to odd, writes the session and bumps it back to even, the
+/// loads retry on an odd or changed generation, so they never see a half written session.
+/// only one process should store at a time.
+pub struct SharedSession {
+    mmap: MmapMut,
+}
+
+impl SharedSession {
+    /// create the region with the room of capacity bytes for the session, replacing the
+    /// file if it exists. `Session::bytes()` tells the room a session takes.
+    pub fn create(path: impl AsRef<Path>, capacity: usize) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .and_then(|file| {
+                file.set_len((SHARED_HEADER_BYTES + capacity) as u64)?;
+                Ok(file)
+            })
+            .map_err(|err| Error {
+                kind: ErrorKind::IOError,
+                message: format!("failed to create the shared session: {}", path.display()),
+                cause: Some(Box::new(err)),
+            })?;
+        let mut region = Self::map(&file, path)?;
+        region.mmap[..8].copy_from_slice(SHARED_MAGIC);
+        Ok(region)
+    }
+
+    /// open the region created by another process.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|err| Error {
+                kind: ErrorKind::IOError,
+                message: format!("failed to open the shared session: {}", path.display()),
+                cause: Some(Box::new(err)),
+            })?;
+        let region = Self::map(&file, path)?;
+        if region.mmap.len() < SHARED_HEADER_BYTES || &region.mmap[..8] != SHARED_MAGIC {
+            return Err((
+                ErrorKind::FormatError,
+                format!("not a shared session: {}", path.display()),
+            )
+                .into());
+        }
+        Ok(region)
+    }
+
+    fn map(file: &File, path: &Path) -> Result<Self> {
+        let mmap = unsafe { MmapMut::map_mut(file) }.map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to mmap the shared session: {}", path.display()),
+            cause: Some(Box::new(err)),
+        })?;
+        Ok(Self { mmap })
+    }
+
+    /// the bytes of the session the region can hold.
+    pub fn capacity(&self) -> usize {
+        self.mmap.len() - SHARED_HEADER_BYTES
+    }
+
+    fn generation(&self) -> &AtomicU64 {
+        // the map is page aligned, so is the u64 after the magic
+        unsafe { &*(self.mmap.as_ptr().add(8) as *const AtomicU64) }
+    }
+
+    pub fn store(&mut self, session: &Session) -> Result<()> {
+        let mut buf = Vec::with_capacity(session.bytes());
+        session.write_to(&mut buf).unwrap();
+        if buf.len() > self.capacity() {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "the session takes {} bytes, larger than the shared region of {} bytes",
+                    buf.len(),
+                    self.capacity()
+                ),
+            )
+                .into());
+        }
+
+        self.generation().fetch_add(1, Ordering::AcqRel);
+        self.mmap[16..24].copy_from_slice(&(buf.len() as u64).to_le_bytes());
+        self.mmap[SHARED_HEADER_BYTES..SHARED_HEADER_BYTES + buf.len()].copy_from_slice(&buf);
+        self.generation().fetch_add(1, Ordering::Release);
+        Ok(())
+    }
+
+    /// the session last stored by any process, None if nothing is stored yet.
+    pub fn load(&self) -> Result<Option<Session>> {
+        for _ in 0..SHARED_READ_RETRIES {
+            let generation = self.generation().load(Ordering::Acquire);
+            if generation % 2 == 1 {
+                std::thread::yield_now();
+                continue;
+            }
+            let len = u64::from_le_bytes(self.mmap[16..24].try_into().unwrap()) as usize;
+            let buf = self.mmap[SHARED_HEADER_BYTES..][..len.min(self.capacity())].to_vec();
+            fence(Ordering::Acquire);
+            if self.generation().load(Ordering::Relaxed) != generation {
+                continue;
+            }
+            if len == 0 {
+                return Ok(None);
+            }
+            return Session::read_from(&mut &buf[..], buf.len() as u64, None).map(Some);
+        }
+        Err((
+            ErrorKind::Unexpected,
+            "the shared session is kept being written, the writer may have died",
+        )
+            .into())
+    }
+}
+
+fn read_exact(r: &mut impl Read, buf: &mut [u8]) -> Result<()> {
+    r.read_exact(buf).map_err(|err| Error {
+        kind: ErrorKind::FormatError,
+        message: "failed to read the session file, it may be truncated".to_string(),
+        cause: Some(Box::new(err)),
+    })
+}
+
+fn read_u32(r: &mut impl Read) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    read_exact(r, &mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_f32s(r: &mut impl Read, n: usize) -> Result<Vec<f32>> {
+    let mut buf = vec![0u8; n * 4];
+    read_exact(r, &mut buf)?;
+    Ok(buf
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect())
+}
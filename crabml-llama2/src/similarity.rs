@@ -0,0 +1,197 @@
+use crabml::backends::cpu::CpuTensor;
+use crabml::backends::cpu::CpuTensorDeviceRef;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::tensor::Tensor;
+
+use crate::embeddings::l2_normalize;
+
+/// the cosine of the angle between two vectors, 0.0 if either one is zero.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let (mut dot, mut norm_a, mut norm_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a.sqrt() * norm_b.sqrt())
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Neighbor {
+    /// the row of the embedding in the index.
+    pub index: usize,
+    pub score: f32,
+}
+
+/// the k highest scores in the descending order, the ties keep the lower index first.
+pub fn top_k(scores: &[f32], k: usize) -> Vec<Neighbor> {
+    let mut neighbors = scores
+        .iter()
+        .enumerate()
+        .map(|(index, score)| Neighbor {
+            index,
+            score: *score,
+        })
+        .collect::<Vec<_>>();
+    let k = k.min(neighbors.len());
+    if k < neighbors.len() && k > 0 {
+        neighbors.select_nth_unstable_by(k - 1, |a, b| {
+            b.score.total_cmp(&a.score).then(a.index.cmp(&b.index))
+        });
+    }
+    neighbors.truncate(k);
+    neighbors.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.index.cmp(&b.index)));
+    neighbors
+}
+
+/// a matrix of the embeddings on the cpu, scored against the queries by the batched matmul
+/// of the cpu backend, for reranking a modest corpus without a vector database. the rows
+/// are normalized, so the scores are the cosine similarities.
+pub struct EmbeddingIndex<'a> {
+    rows: CpuTensor<'a>, // (n_rows padded to a multiple of 4, dim)
+    n_rows: usize,
+    dim: usize,
+    device: CpuTensorDeviceRef<'a>,
+}
+
+impl<'a> EmbeddingIndex<'a> {
+    pub fn new(embeddings: &[Vec<f32>], device: CpuTensorDeviceRef<'a>) -> Result<Self> {
+        let dim = embeddings.first().map(|e| e.len()).unwrap_or(0);
+        if dim == 0 {
+            return Err((
+                ErrorKind::BadInput,
+                "the index needs at least one embedding",
+            )
+                .into());
+        }
+        // the gemv writes the outputs in the groups of 4, the padded rows score 0.0
+        let n_rows = embeddings.len();
+        let mut buf = Self::normalized_rows(embeddings, dim)?;
+        buf.resize(n_rows.next_multiple_of(4) * dim, 0.0);
+        let rows = CpuTensor::new(buf, &[n_rows.next_multiple_of(4), dim], device.clone())?;
+        Ok(Self {
+            rows,
+            n_rows,
+            dim,
+            device,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.n_rows
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    /// the cosine similarities of every query to every row, in (n_queries, n_rows).
+    pub fn scores(&self, queries: &[Vec<f32>]) -> Result<Vec<f32>> {
+        if queries.is_empty() {
+            return Ok(vec![]);
+        }
+        let buf = Self::normalized_rows(queries, self.dim)?;
+        let queries = CpuTensor::new(buf, &[queries.len(), self.dim], self.device.clone())?;
+        // (n_rows, dim) @ (n_queries, dim) => (n_queries, n_rows)
+        let scores = self.rows.matmul_vec(&queries)?;
+        let mut out = vec![0.0; scores.strider().len()];
+        scores.export(&mut out)?;
+        let n_padded = self.rows.strider().shape()[0];
+        Ok(out
+            .chunks(n_padded)
+            .flat_map(|row| &row[..self.n_rows])
+            .copied()
+            .collect())
+    }
+
+    /// the k rows most similar to the query.
+    pub fn search(&self, query: &[f32], k: usize) -> Result<Vec<Neighbor>> {
+        let scores = self.scores(&[query.to_vec()])?;
+        Ok(top_k(&scores, k))
+    }
+
+    /// the k rows most similar to each query, the queries are scored in one matmul.
+    pub fn search_batch(&self, queries: &[Vec<f32>], k: usize) -> Result<Vec<Vec<Neighbor>>> {
+        let scores = self.scores(queries)?;
+        Ok(scores
+            .chunks(self.len())
+            .map(|scores| top_k(scores, k))
+            .collect())
+    }
+
+    fn normalized_rows(vectors: &[Vec<f32>], dim: usize) -> Result<Vec<f32>> {
+        let mut buf = Vec::with_capacity(vectors.len() * dim);
+        for v in vectors {
+            if v.len() != dim {
+                return Err((
+                    ErrorKind::BadInput,
+                    format!("expected the embeddings of {} dims, got {}", dim, v.len()),
+                )
+                    .into());
+            }
+            let start = buf.len();
+            buf.extend_from_slice(v);
+            l2_normalize(&mut buf[start..]);
+        }
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use crabml::backends::cpu::CpuTensorDevice;
+
+    use super::*;
+
+    #[test]
+    fn test_embedding_index() -> Result<()> {
+        assert_relative_eq!(cosine_similarity(&[1.0, 0.0], &[2.0, 2.0]), 0.70710677);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 0.0]), 0.0);
+
+        let top = top_k(&[0.1, 0.9, 0.5, 0.9], 3);
+        let indices = top.iter().map(|n| n.index).collect::<Vec<_>>();
+        assert_eq!(indices, vec![1, 3, 2]);
+        assert_eq!(top_k(&[0.1, 0.2], 5).len(), 2);
+
+        // the corpus of 37 dims is scored the same as one by one
+        let corpus = (0..10)
+            .map(|i| {
+                (0..37)
+                    .map(|j| ((i * 7 + j * 3) % 11) as f32 - 5.0)
+                    .collect()
+            })
+            .collect::<Vec<Vec<f32>>>();
+        let index = EmbeddingIndex::new(&corpus, CpuTensorDevice::new())?;
+        assert_eq!(index.len(), 10);
+        let queries = vec![
+            corpus[3].clone(),
+            corpus[7].iter().map(|x| x * 2.0).collect(),
+        ];
+        let scores = index.scores(&queries)?;
+        assert_eq!(scores.len(), 20);
+        for (q, row) in queries.iter().zip(scores.chunks(10)) {
+            for (e, score) in corpus.iter().zip(row) {
+                assert_relative_eq!(*score, cosine_similarity(q, e), epsilon = 1e-4);
+            }
+        }
+
+        let neighbors = index.search_batch(&queries, 2)?;
+        assert_eq!(neighbors[0][0].index, 3);
+        assert_eq!(neighbors[1][0].index, 7);
+        assert_relative_eq!(neighbors[1][0].score, 1.0, epsilon = 1e-4);
+        assert_eq!(index.search(&corpus[5], 1)?[0].index, 5);
+
+        assert!(index.search(&[1.0, 2.0], 1).is_err());
+        assert!(EmbeddingIndex::new(&[], CpuTensorDevice::new()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,364 @@
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::sampler::argmax;
+use crabml::sampler::SamplerChain;
+use crabml::tensor::Tensor;
+use crabml::tokenizer::BpeTokenizer;
+
+use crate::llama2::Llama2Runner;
+
+/// maps the token ids between the vocabularies of the target and the draft model. they're
+/// compatible if the tokens of one are a prefix of the tokens of the other, like when one
+/// of them is padded to a multiple of 64 or 128 tokens. the padding tokens are never
+/// drafted, and a padding token of the target is fed into the draft as its unk token, which
+/// may only lower the acceptance of the following drafts.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct VocabMapping {
+    // the tokens with the same id in both vocabularies
+    n_shared: usize,
+    n_target: usize,
+    n_draft: usize,
+    draft_unk: usize,
+}
+
+impl VocabMapping {
+    /// check the tokenizers are compatible, it fails on the first token differing between
+    /// them, or if the bos or eos tokens differ.
+    pub fn check(target: &BpeTokenizer, draft: &BpeTokenizer) -> Result<Self> {
+        if target.bos_token() != draft.bos_token() || target.eos_token() != draft.eos_token() {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "the draft model does not share the tokenizer of the target model, the \
+                     bos and eos tokens are {} and {} on the target, {} and {} on the draft",
+                    target.bos_token(),
+                    target.eos_token(),
+                    draft.bos_token(),
+                    draft.eos_token()
+                ),
+            )
+                .into());
+        }
+        let (t, d) = (target.vocab(), draft.vocab());
+        if let Some(i) = t.iter().zip(d.iter()).position(|(a, b)| a != b) {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "the draft model does not share the tokenizer of the target model, the \
+                     token {} is {:?} on the target and {:?} on the draft",
+                    i, t[i], d[i]
+                ),
+            )
+                .into());
+        }
+        let n_shared = t.len().min(d.len());
+        Ok(Self {
+            n_shared,
+            n_target: t.len(),
+            n_draft: d.len(),
+            draft_unk: draft.token_id("<unk>").unwrap_or(0),
+        })
+    }
+
+    /// whether the vocabularies are the same, without padding.
+    pub fn is_identical(&self) -> bool {
+        self.n_target == self.n_draft
+    }
+
+    /// the number of tokens with the same id in both vocabularies.
+    pub fn n_shared(&self) -> usize {
+        self.n_shared
+    }
+
+    pub fn draft_to_target(&self, token: usize) -> Option<usize> {
+        (token < self.n_shared).then_some(token)
+    }
+
+    pub fn target_to_draft(&self, token: usize) -> Option<usize> {
+        (token < self.n_shared).then_some(token)
+    }
+
+    // the target tokens to feed into the draft, the padding tokens are replaced by the unk
+    fn draft_input(&self, tokens: &[usize]) -> Vec<usize> {
+        tokens
+            .iter()
+            .map(|t| self.target_to_draft(*t).unwrap_or(self.draft_unk))
+            .collect()
+    }
+
+    // the logits of the embedding rows beyond the tokens are never drafted either
+    fn limit_logits(&mut self, vocab_size: usize) {
+        self.n_shared = self.n_shared.min(vocab_size);
+    }
+}
+
+/// the counters of the draft tokens, to tell whether the draft model pays off.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct SpeculativeStats {
+    pub steps: usize,
+    pub drafted: usize,
+    pub accepted: usize,
+}
+
+impl SpeculativeStats {
+    pub fn acceptance_rate(&self) -> f32 {
+        if self.drafted == 0 {
+            return 0.0;
+        }
+        self.accepted as f32 / self.drafted as f32
+    }
+}
+
+/// speculative decoding: a small draft model proposes `n_draft` tokens greedily on each
+/// step, and the target model verifies them in a single batched forward pass. the target
+/// samples on every position of the drafts, the drafts are accepted until the first one
+/// differing from the sampled token, which is taken instead. so the output is the same as
+/// sampling on the target alone, but takes a pass of the target for several tokens.
+///
+/// both models must share the same tokenizer, or differ only by the padding tokens, see
+/// `VocabMapping`. the kv caches of the rejected drafts are rolled back on both runners.
+pub struct SpeculativeDecoder<'a, T: Tensor, D: Tensor> {
+    target: &'a mut Llama2Runner<T>,
+    draft: &'a mut Llama2Runner<D>,
+    n_draft: usize,
+    vocab: VocabMapping,
+    // the tokens in the target kv cache, and the last sampled token which is not fed yet
+    tokens: Vec<usize>,
+    stats: SpeculativeStats,
+}
+
+impl<'a, T: Tensor, D: Tensor> SpeculativeDecoder<'a, T, D> {
+    pub fn new(
+        target: &'a mut Llama2Runner<T>,
+        draft: &'a mut Llama2Runner<D>,
+        n_draft: usize,
+    ) -> Result<Self> {
+        let mut vocab = VocabMapping::check(target.tokenizer(), draft.tokenizer())?;
+        vocab.limit_logits(target.conf().vocab_size.min(draft.conf().vocab_size));
+        if n_draft == 0 {
+            return Err((ErrorKind::BadInput, "n_draft should be at least 1").into());
+        }
+        Ok(Self {
+            target,
+            draft,
+            n_draft,
+            vocab,
+            tokens: vec![],
+            stats: SpeculativeStats::default(),
+        })
+    }
+
+    pub fn stats(&self) -> SpeculativeStats {
+        self.stats
+    }
+
+    pub fn vocab_mapping(&self) -> &VocabMapping {
+        &self.vocab
+    }
+
+    /// reset both kv caches and feed the prompt into the target, return the first token.
+    /// the draft catches up with the prompt on the first step.
+    pub fn prefill(&mut self, prompt: &str, sampler: &mut SamplerChain) -> Result<usize> {
+        self.target.reset_kv_cache()?;
+        self.draft.reset_kv_cache()?;
+        self.stats = SpeculativeStats::default();
+
+        let prompt_tokens = self.target.prompt_tokens(prompt)?;
+        if prompt_tokens.len() > self.draft.seq_len() {
+            return Err((
+                ErrorKind::BadInput,
+                format!(
+                    "the prompt of {} tokens does not fit into the draft context of {}",
+                    prompt_tokens.len(),
+                    self.draft.seq_len()
+                ),
+            )
+                .into());
+        }
+        for token in prompt_tokens.iter() {
+            sampler.accept(*token);
+        }
+        let logits = self.target.forward_batch(&prompt_tokens, 0)?;
+        let token = sampler.sample(logits)?;
+        self.tokens = prompt_tokens;
+        self.tokens.push(token);
+        Ok(token)
+    }
+
+    /// draft and verify the next tokens, return the accepted ones with the token sampled by
+    /// the target after them. it's empty when the kv cache is full.
+    pub fn step(&mut self, sampler: &mut SamplerChain) -> Result<Vec<usize>> {
+        if self.tokens.is_empty() {
+            return Err((ErrorKind::BadInput, "prefill() should be called first").into());
+        }
+        let pos = self.tokens.len() - 1;
+        let seq_len = self.target.seq_len().min(self.draft.seq_len());
+        if pos >= seq_len {
+            return Ok(vec![]);
+        }
+        // leave the room for the last token
+        let n_draft = self.n_draft.min(seq_len - pos - 1);
+
+        let mut drafts = Vec::with_capacity(n_draft);
+        if n_draft > 0 {
+            // the draft misses the tokens accepted on the last step
+            let draft_pos = self.draft.kv_cache_len();
+            let n_shared = self.vocab.n_shared();
+            let input = self.vocab.draft_input(&self.tokens[draft_pos..]);
+            let logits = self.draft.forward_batch(&input, draft_pos)?;
+            drafts.push(argmax(&logits[..n_shared]).unwrap());
+            for i in 1..n_draft {
+                let logits = self.draft.forward(drafts[i - 1], pos + i)?;
+                drafts.push(argmax(&logits[..n_shared]).unwrap());
+            }
+        }
+
+        let mut batch = Vec::with_capacity(n_draft + 1);
+        batch.push(self.tokens[pos]);
+        batch.extend_from_slice(&drafts);
+        let logits = self.target.forward_batch_all(&batch, pos)?;
+
+        let vocab_size = self.target.conf().vocab_size;
+        let eos_token = self.target.tokenizer().eos_token();
+        let mut accepted = Vec::with_capacity(n_draft + 1);
+        for (i, logits) in logits.chunks_exact(vocab_size).enumerate() {
+            let token = sampler.sample(logits)?;
+            accepted.push(token);
+            if i == n_draft || token != drafts[i] || token == eos_token {
+                break;
+            }
+        }
+
+        let n_matched = accepted
+            .iter()
+            .zip(drafts.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        self.stats.steps += 1;
+        self.stats.drafted += n_draft;
+        self.stats.accepted += n_matched;
+
+        // the kv caches only keep the last token and the accepted drafts before the new one
+        let len = pos + accepted.len();
+        self.target.truncate_kv_cache(len)?;
+        if self.draft.kv_cache_len() > len {
+            self.draft.truncate_kv_cache(len)?;
+        }
+        self.tokens.extend_from_slice(&accepted);
+        Ok(accepted)
+    }
+
+    /// prefill the prompt and generate up to `steps` tokens, it stops before the eos token.
+    pub fn generate(
+        &mut self,
+        prompt: &str,
+        steps: usize,
+        sampler: &mut SamplerChain,
+    ) -> Result<Vec<usize>> {
+        let eos_token = self.target.tokenizer().eos_token();
+        let mut output = vec![self.prefill(prompt, sampler)?];
+        while output.len() < steps && output.last() != Some(&eos_token) {
+            let tokens = self.step(sampler)?;
+            if tokens.is_empty() {
+                break;
+            }
+            output.extend(tokens);
+        }
+        if let Some(end) = output.iter().position(|t| *t == eos_token) {
+            output.truncate(end);
+        }
+        output.truncate(steps);
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::gguf::GGMLType;
+    use crabml::gguf::GGUFFileLoader;
+    use crabml::tensor::TensorMetrics;
+
+    use super::*;
+    use crate::CpuLlama2Model;
+
+    #[test]
+    fn test_speculative_decoding() -> Result<()> {
+        let gl_target = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf")?;
+        let gf_target = gl_target.open()?;
+        let gl_draft = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf_draft = gl_draft.open()?;
+        let device = CpuTensorDevice::new();
+        let lm_target = CpuLlama2Model::load(&gf_target, device.clone())?;
+        let lm_draft = CpuLlama2Model::load(&gf_draft, device.clone())?;
+
+        let metrics = TensorMetrics::default();
+        let mut target = Llama2Runner::new(&lm_target, metrics.clone(), 200, GGMLType::F32)?;
+        let expected = {
+            let mut sampler = SamplerChain::new();
+            let (pos, prev_token, token) = target.prefill("Lily is a cat", &mut sampler)?;
+            let output = target.generate(pos, prev_token, token, 30, &mut sampler);
+            output.collect::<Result<Vec<_>>>()?.join("")
+        };
+
+        let mut draft = Llama2Runner::new(&lm_draft, metrics.clone(), 200, GGMLType::F32)?;
+        let mut decoder = SpeculativeDecoder::new(&mut target, &mut draft, 4)?;
+        // Llama2Runner::generate() yields the first token besides the steps
+        let tokens = decoder.generate("Lily is a cat", 31, &mut SamplerChain::new())?;
+        let stats = decoder.stats();
+        assert_eq!(tokens.len(), 31);
+        // the q8_0 draft agrees on most of the tokens
+        assert!(stats.steps < 20, "{:?}", stats);
+        assert!(stats.acceptance_rate() > 0.5, "{:?}", stats);
+
+        // the greedy output is the same as the target alone
+        let prompt_tokens = lm_target.tokenizer.encode("Lily is a cat", true, false)?;
+        let mut prev_token = *prompt_tokens.last().unwrap();
+        let mut text = String::new();
+        for token in tokens {
+            text.push_str(&lm_target.tokenizer.decode(prev_token, token)?);
+            prev_token = token;
+        }
+        assert_eq!(text, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_vocab_mapping() -> Result<()> {
+        let tokenizer = |tokens: &[&str], eos: usize| {
+            let tokens = tokens.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+            let scores = vec![0.0; tokens.len()];
+            BpeTokenizer::new(tokens, scores, 1, eos)
+        };
+        let target = tokenizer(&["<unk>", "<s>", "</s>", "a", "b"], 2);
+
+        let vocab =
+            VocabMapping::check(&target, &tokenizer(&["<unk>", "<s>", "</s>", "a", "b"], 2))?;
+        assert!(vocab.is_identical());
+        assert_eq!(vocab.n_shared(), 5);
+
+        // the draft is padded
+        let draft = tokenizer(&["<unk>", "<s>", "</s>", "a", "b", "<pad0>", "<pad1>"], 2);
+        let vocab = VocabMapping::check(&target, &draft)?;
+        assert!(!vocab.is_identical());
+        assert_eq!(vocab.draft_to_target(4), Some(4));
+        assert_eq!(vocab.draft_to_target(5), None);
+
+        // the target is padded, its padding is fed into the draft as the unk
+        let draft = tokenizer(&["<unk>", "<s>", "</s>", "a"], 2);
+        let vocab = VocabMapping::check(&target, &draft)?;
+        assert_eq!(vocab.target_to_draft(4), None);
+        assert_eq!(vocab.draft_input(&[3, 4]), vec![3, 0]);
+
+        // a token or the eos differs
+        let draft = tokenizer(&["<unk>", "<s>", "</s>", "b", "a"], 2);
+        let err = VocabMapping::check(&target, &draft).unwrap_err();
+        assert!(
+            err.message
+                .contains("the token 3 is \"a\" on the target and \"b\"")
+        );
+        let draft = tokenizer(&["<unk>", "<s>", "</s>", "a", "b"], 0);
+        assert!(VocabMapping::check(&target, &draft).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+use std::panic;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use crabml::backends::cpu::CpuTensor;
+use crabml::backends::cpu::CpuTensorDeviceRef;
+use crabml::error::Error;
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::gguf::GGUFFile;
+use crabml::gguf::GGUFWarmup;
+use crabml::tensor::TensorMetrics;
+use crabml::tokenizer::BpeTokenizer;
+
+use crate::chat::ChatMessage;
+use crate::chat::ChatTemplate;
+use crate::llama2;
+use crate::llama2::Llama2Runner;
+use crate::llama2::TokenSampler;
+use crate::model::CpuLlama2Model;
+use crate::model::ModelLoadOptions;
+
+/// the prompt of `load_and_prefill()`.
+#[derive(Debug, Clone)]
+pub enum StartupPrompt {
+    Text(String),
+    /// rendered by the chat template of the model, with the generation prompt appended.
+    Chat(Vec<ChatMessage>),
+}
+
+#[derive(Debug, Clone)]
+pub struct StartupOptions {
+    pub load: ModelLoadOptions,
+    /// the layers paged in from the file in the background, 0 for none.
+    pub warmup_layers: usize,
+    /// None takes the seq_len of the model.
+    pub seq_len: Option<usize>,
+    pub kv_cache_dtype: GGMLType,
+}
+
+impl Default for StartupOptions {
+    fn default() -> Self {
+        Self {
+            load: ModelLoadOptions::default(),
+            warmup_layers: 2,
+            seq_len: None,
+            kv_cache_dtype: GGMLType::F32,
+        }
+    }
+}
+
+impl StartupOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_load_options(mut self, load: ModelLoadOptions) -> Self {
+        self.load = load;
+        self
+    }
+
+    pub fn with_warmup_layers(mut self, warmup_layers: usize) -> Self {
+        self.warmup_layers = warmup_layers;
+        self
+    }
+
+    pub fn with_seq_len(mut self, seq_len: usize) -> Self {
+        self.seq_len = Some(seq_len);
+        self
+    }
+
+    pub fn with_kv_cache_dtype(mut self, kv_cache_dtype: GGMLType) -> Self {
+        self.kv_cache_dtype = kv_cache_dtype;
+        self
+    }
+}
+
+/// the runner with the prompt prefilled and the first token sampled, continue it with
+/// `Llama2Runner::generate(pos, prev_token, token, ..)`.
+pub struct FirstToken<'a> {
+    pub runner: Llama2Runner<CpuTensor<'a>>,
+    /// the prompt after the chat template.
+    pub prompt: String,
+    pub pos: usize,
+    pub prev_token: usize,
+    pub token: usize,
+    /// the time from the start of the loading to the first token.
+    pub elapsed: Duration,
+    /// the warmup still paging in the layers, if the prefill has not touched them all.
+    pub warmup: GGUFWarmup,
+}
+
+/// load the model and prefill the prompt with the least time to the first token. the pages
+/// of the first layers are read from the file in the background, so the forward pass finds
+/// them in the memory instead of faulting them in one by one. the tokenizer is loaded and
+/// the prompt is rendered and tokenized on another thread, while the weights are set up on
+/// the calling thread. it returns as soon as the first token is sampled, the rest of the
+/// warmup goes on in the background.
+pub fn load_and_prefill<'a>(
+    gf: &'a GGUFFile<'a>,
+    device: CpuTensorDeviceRef<'a>,
+    prompt: &StartupPrompt,
+    sampler: &mut impl TokenSampler<CpuTensor<'a>>,
+    options: StartupOptions,
+) -> Result<FirstToken<'a>> {
+    let start_at = Instant::now();
+    let warmup = gf.warmup(options.warmup_layers);
+
+    let (model, prompt, prompt_tokens) = thread::scope(|s| {
+        let encoding = s.spawn(|| encode_prompt(gf, prompt).map_err(|err| (err.kind, err.message)));
+        let mut encoded = None;
+        let model = CpuLlama2Model::load_with_tokenizer(gf, device, options.load, || {
+            let (tokenizer, prompt, prompt_tokens) = encoding
+                .join()
+                .unwrap_or_else(|panic| panic::resume_unwind(panic))
+                .map_err(Error::from)?;
+            encoded = Some((prompt, prompt_tokens));
+            Ok(tokenizer)
+        })?;
+        let (prompt, prompt_tokens) = encoded.unwrap();
+        Ok::<_, Error>((model, prompt, prompt_tokens))
+    })?;
+    let seq_len = options.seq_len.unwrap_or(model.conf.seq_len);
+    let mut runner = Llama2Runner::new(
+        &model,
+        TensorMetrics::default(),
+        seq_len,
+        options.kv_cache_dtype,
+    )?;
+
+    let prompt_tokens = runner.fit_prompt_tokens(&prompt_tokens)?;
+    let (pos, prev_token, logits) = runner.prefill_tokens_logits(prompt_tokens, sampler)?;
+    let token = runner.sample(&logits, sampler)?;
+    Ok(FirstToken {
+        runner,
+        prompt,
+        pos,
+        prev_token,
+        token,
+        elapsed: start_at.elapsed(),
+        warmup,
+    })
+}
+
+// load the tokenizer, render the prompt and encode it. the errors are sent back to the
+// calling thread without their causes, which may not be sent between the threads.
+fn encode_prompt(
+    gf: &GGUFFile,
+    prompt: &StartupPrompt,
+) -> Result<(BpeTokenizer, String, Vec<usize>)> {
+    let tokenizer = CpuLlama2Model::load_tokenizer(gf)?;
+    let prompt = match prompt {
+        StartupPrompt::Text(text) => text.clone(),
+        StartupPrompt::Chat(messages) => {
+            let conf = CpuLlama2Model::load_config(gf)?;
+            ChatTemplate::from_model(&conf, &tokenizer).render(messages, None, true)?
+        }
+    };
+    let prompt_tokens = llama2::encode_prompt(&tokenizer, &prompt)?;
+    Ok((tokenizer, prompt, prompt_tokens))
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::gguf::GGUFFileLoader;
+    use crabml::sampler::SamplerChain;
+
+    use super::*;
+
+    #[test]
+    fn test_load_and_prefill() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let prompt = StartupPrompt::Text("Lily is a cute cat, ".to_string());
+        let mut sampler = SamplerChain::new();
+        let options = StartupOptions::new().with_seq_len(200);
+        let first = load_and_prefill(&gf, CpuTensorDevice::new(), &prompt, &mut sampler, options)?;
+        first.warmup.wait();
+
+        let FirstToken {
+            mut runner,
+            pos,
+            prev_token,
+            token,
+            ..
+        } = first;
+        let output = runner
+            .generate(pos, prev_token, token, 10, &mut sampler)
+            .collect::<Result<Vec<String>>>()?;
+        assert_eq!(output.join(""), "3 years old. She likes to play with her");
+
+        // the chat messages are rendered by the template
+        let prompt = StartupPrompt::Chat(vec![ChatMessage::user("hi")]);
+        let options = StartupOptions::new().with_seq_len(200);
+        let first = load_and_prefill(&gf, CpuTensorDevice::new(), &prompt, &mut sampler, options)?;
+        assert!(first.prompt.contains("hi"));
+        assert!(first.pos > 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,400 @@
+use std::fmt;
+
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use fancy_regex::Regex;
+
+use crate::stream::FinishReason;
+use crate::stream::GeneratedToken;
+use crate::stream::GenerationOptions;
+
+/// decides when to stop the generation on the detokenized text, it's checked after every
+/// generated piece. add them by `GenerationOptions::with_stop_criteria()`, the closures of
+/// `Fn(&str, usize) -> Option<usize>` are stop criteria, too.
+pub trait StopCriteria: Send + Sync {
+    /// `text` is all the text generated so far, and `new_from` is the offset in it where the
+    /// text not checked before starts, like the piece of the last token. returns the offset
+    /// to cut the text at to stop the generation, the text after it is dropped, or None to
+    /// go on.
+    ///
+    /// the stop criteria are not told about the text before they are set in the middle of
+    /// the generation, but they may look back into it, like on a stop string which starts
+    /// before and ends in the new piece.
+    fn check(&self, text: &str, new_from: usize) -> Option<usize>;
+}
+
+impl<F> StopCriteria for F
+where F: Fn(&str, usize) -> Option<usize> + Send + Sync
+{
+    fn check(&self, text: &str, new_from: usize) -> Option<usize> {
+        self(text, new_from)
+    }
+}
+
+impl fmt::Debug for dyn StopCriteria {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StopCriteria")
+    }
+}
+
+/// stops on the first of the strings, which is cut off from the text.
+#[derive(Debug, Clone, Default)]
+pub struct StopStrings(pub Vec<String>);
+
+impl StopStrings {
+    pub fn new(strings: Vec<String>) -> Self {
+        Self(strings)
+    }
+
+    /// the offset of the earliest stop string ending in the new text.
+    pub fn find(strings: &[String], text: &str, new_from: usize) -> Option<usize> {
+        strings
+            .iter()
+            .filter_map(|s| {
+                // a stop string may start in the text before and end in the new text
+                let from = floor_char_boundary(text, new_from.saturating_sub(s.len().max(1) - 1));
+                text[from..].find(s.as_str()).map(|i| from + i)
+            })
+            .min()
+    }
+}
+
+impl StopCriteria for StopStrings {
+    fn check(&self, text: &str, new_from: usize) -> Option<usize> {
+        Self::find(&self.0, text, new_from)
+    }
+}
+
+/// stops on the first match of the regex which ends in the new text, the match is cut off
+/// from the text.
+#[derive(Debug, Clone)]
+pub struct StopRegex(Regex);
+
+impl StopRegex {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let regex = Regex::new(pattern).map_err(|err| Error {
+            kind: ErrorKind::BadInput,
+            message: format!("failed to compile the stop regex {:?}", pattern),
+            cause: Some(Box::new(err)),
+        })?;
+        Ok(Self(regex))
+    }
+}
+
+impl StopCriteria for StopRegex {
+    fn check(&self, text: &str, new_from: usize) -> Option<usize> {
+        // the text is searched from the start, a match may span any number of pieces. an
+        // error of the backtracking limit is taken as no match
+        self.0
+            .find_iter(text)
+            .map_while(|m| m.ok())
+            .find(|m| m.end() > new_from)
+            .map(|m| m.start())
+    }
+}
+
+/// stops right after the first group of delimiters which is closed in the new text, like a
+/// JSON object or a block of code, the text before the group is kept. the delimiters inside
+/// the quotes are not counted, and a closing delimiter without the opening one is ignored.
+#[derive(Debug, Clone)]
+pub struct BalancedDelimiters {
+    pairs: Vec<(char, char)>,
+    quotes: Vec<char>,
+}
+
+impl BalancedDelimiters {
+    pub fn new(pairs: Vec<(char, char)>) -> Self {
+        Self {
+            pairs,
+            quotes: vec![],
+        }
+    }
+
+    /// a JSON object or array, with the strings in the double quotes.
+    pub fn json() -> Self {
+        Self::new(vec![('{', '}'), ('[', ']')]).with_quote('"')
+    }
+
+    /// the delimiters are not counted between this quote and its closing one, a backslash
+    /// escapes the next char.
+    pub fn with_quote(mut self, quote: char) -> Self {
+        self.quotes.push(quote);
+        self
+    }
+}
+
+impl StopCriteria for BalancedDelimiters {
+    fn check(&self, text: &str, new_from: usize) -> Option<usize> {
+        // scanned from the start, the delimiters of the groups still open may be anywhere
+        // before the new text
+        let mut stack = vec![];
+        let mut quote = None;
+        let mut escaped = false;
+        for (i, c) in text.char_indices() {
+            if let Some(q) = quote {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+                continue;
+            }
+            if stack.is_empty() && !self.pairs.iter().any(|(open, _)| *open == c) {
+                continue;
+            }
+            if self.quotes.contains(&c) {
+                quote = Some(c);
+            } else if let Some((_, close)) = self.pairs.iter().find(|(open, _)| *open == c) {
+                stack.push(*close);
+            } else if stack.last() == Some(&c) {
+                stack.pop();
+                let end = i + c.len_utf8();
+                if stack.is_empty() && end > new_from {
+                    return Some(end);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// the earliest cut of the stop strings and the stop criteria of the options, on the text
+/// after a piece appended at new_from.
+pub(crate) fn find_stop(
+    options: &GenerationOptions,
+    text: &str,
+    new_from: usize,
+) -> Option<(usize, FinishReason)> {
+    let by_strings = StopStrings::find(&options.stop_strings, text, new_from)
+        .map(|at| (at, FinishReason::StopString));
+    let by_criteria = options
+        .stop_criteria
+        .iter()
+        .filter_map(|c| c.check(text, new_from))
+        .min()
+        .map(|at| (at, FinishReason::StopCriteria));
+    let (at, reason) = match (by_strings, by_criteria) {
+        (Some(a), Some(b)) => Some(if b.0 < a.0 { b } else { a }),
+        (a, b) => a.or(b),
+    }?;
+    Some((floor_char_boundary(text, at.min(text.len())), reason))
+}
+
+/// the length of the longest end of text[from..] which begins a stop string but is not the
+/// whole of it, the part the next pieces may still complete into a stop string.
+pub(crate) fn partial_stop_len(strings: &[String], text: &str, from: usize) -> usize {
+    strings
+        .iter()
+        .filter_map(|s| {
+            let max = s.len().saturating_sub(1).min(text.len() - from);
+            (1..=max).rev().find(|n| {
+                let at = text.len() - n;
+                text.is_char_boundary(at) && s.starts_with(&text[at..])
+            })
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// the generated tokens whose pieces end in the start of a stop string, they're held back
+/// until the next pieces complete the stop string or rule it out, so the pieces yielded
+/// never leak a part of the stop string. the stop criteria are not held back, a match of
+/// them spanning several pieces can't be told in advance.
+#[derive(Debug, Default)]
+pub(crate) struct HeldTokens {
+    // the tokens with the offsets of their pieces in the text
+    tokens: Vec<(usize, GeneratedToken)>,
+}
+
+impl HeldTokens {
+    /// hold the token whose piece is appended at offset in the text.
+    pub fn push(&mut self, offset: usize, token: GeneratedToken) {
+        self.tokens.push((offset, token));
+    }
+
+    /// release the tokens before the end of the text which may still become a stop string.
+    pub fn release(&mut self, text: &str, stop_strings: &[String]) -> Vec<GeneratedToken> {
+        let Some((from, _)) = self.tokens.first() else {
+            return vec![];
+        };
+        let keep_from = text.len() - partial_stop_len(stop_strings, text, *from);
+        let n = self
+            .tokens
+            .iter()
+            .take_while(|(offset, t)| {
+                keep_from == text.len() || offset + t.piece.len() <= keep_from
+            })
+            .count();
+        self.tokens.drain(..n).map(|(_, t)| t).collect()
+    }
+
+    /// release all the tokens with their pieces cut at the offset in the text.
+    pub fn cut(&mut self, stop_at: usize) -> Vec<GeneratedToken> {
+        self.tokens
+            .drain(..)
+            .map(|(offset, mut t)| {
+                t.piece.truncate(stop_at.saturating_sub(offset));
+                t
+            })
+            .collect()
+    }
+
+    /// release all the tokens as they are, when the generation finishes without a stop.
+    pub fn flush(&mut self) -> Vec<GeneratedToken> {
+        self.tokens.drain(..).map(|(_, t)| t).collect()
+    }
+}
+
+fn floor_char_boundary(s: &str, mut i: usize) -> usize {
+    while !s.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // feed the text piece by piece like the stream, returns the cut text
+    fn run(criteria: &dyn StopCriteria, pieces: &[&str]) -> Option<String> {
+        let mut text = String::new();
+        for piece in pieces {
+            let new_from = text.len();
+            text.push_str(piece);
+            if let Some(at) = criteria.check(&text, new_from) {
+                text.truncate(at);
+                return Some(text);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_stop_strings() {
+        let stop = StopStrings::new(vec!["\n\n".to_string(), "User:".to_string()]);
+        assert_eq!(
+            run(&stop, &["Hi", " there", "\n", "\nok"]),
+            Some("Hi there".into())
+        );
+        assert_eq!(run(&stop, &["Hi ", "Us", "er", ":"]), Some("Hi ".into()));
+        assert_eq!(run(&stop, &["Hi", " there"]), None);
+
+        // the text before new_from is not searched again, except for the spanning part
+        assert_eq!(stop.check("User: hi", 5), None);
+        assert_eq!(stop.check("Use", 2), None);
+        assert_eq!(stop.check("é\n\n", 1), Some(2));
+    }
+
+    #[test]
+    fn test_stop_regex() -> Result<()> {
+        let stop = StopRegex::new(r"\d+\.\s")?;
+        assert_eq!(
+            run(&stop, &["Step 1", "2", ".", " Done"]),
+            Some("Step ".into())
+        );
+        assert_eq!(run(&stop, &["Step 12", "."]), None);
+        // a match which ended before new_from does not stop
+        assert_eq!(stop.check("1. a 2. b", 4), Some(5));
+        assert_eq!(stop.check("1. a", 3), None);
+        assert!(StopRegex::new("(").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_balanced_delimiters() {
+        let json = BalancedDelimiters::json();
+        let pieces = [
+            "Sure: ",
+            "{\"a\": [1, ",
+            "2], \"b\": \"}",
+            "{\\\"\"}",
+            " and more",
+        ];
+        assert_eq!(
+            run(&json, &pieces),
+            Some("Sure: {\"a\": [1, 2], \"b\": \"}{\\\"\"}".into())
+        );
+        // no open group, or a stray closing one
+        assert_eq!(run(&json, &["} no json ]", " here"]), None);
+        assert_eq!(run(&json, &["[1, {", "2}"]), None);
+        // the group closed before new_from does not stop again
+        assert_eq!(json.check("{} {}", 2), Some(5));
+
+        let code = BalancedDelimiters::new(vec![('(', ')')]).with_quote('\'');
+        assert_eq!(run(&code, &["(f '(' ", "x) y"]), Some("(f '(' x)".into()));
+    }
+
+    #[test]
+    fn test_find_stop() {
+        let words = |text: &str, _: usize| text.find(" cat").map(|i| i + 4);
+        let options = GenerationOptions::new(10)
+            .with_stop_string("dog")
+            .with_stop_criteria(words);
+        assert_eq!(
+            find_stop(&options, "a cat and a dog", 0),
+            Some((5, FinishReason::StopCriteria))
+        );
+        assert_eq!(
+            find_stop(&options, "a dog and a cat", 0),
+            Some((2, FinishReason::StopString))
+        );
+        assert_eq!(find_stop(&options, "a bird", 0), None);
+
+        // the cut is clamped into the text and onto a char boundary
+        let options = GenerationOptions::new(10).with_stop_criteria(|_: &str, _: usize| Some(1));
+        assert_eq!(
+            find_stop(&options, "é", 0),
+            Some((0, FinishReason::StopCriteria))
+        );
+        let options = GenerationOptions::new(10).with_stop_criteria(|_: &str, _: usize| Some(9));
+        assert_eq!(
+            find_stop(&options, "ab", 0),
+            Some((2, FinishReason::StopCriteria))
+        );
+    }
+
+    #[test]
+    fn test_held_tokens() {
+        let stop = vec![". She".to_string(), "!!".to_string()];
+        assert_eq!(partial_stop_len(&stop, "old.", 0), 1);
+        assert_eq!(partial_stop_len(&stop, "old. S", 0), 3);
+        assert_eq!(partial_stop_len(&stop, "old. S", 4), 0);
+        assert_eq!(partial_stop_len(&stop, "old!", 0), 1);
+        assert_eq!(partial_stop_len(&stop, "old", 0), 0);
+        assert_eq!(partial_stop_len(&stop, "é", 0), 0);
+
+        let token = |piece: &str| GeneratedToken {
+            token: 0,
+            piece: piece.to_string(),
+            logprob: None,
+            metrics: None,
+            seed: None,
+            elapsed: Default::default(),
+        };
+        let pieces = |ts: Vec<GeneratedToken>| ts.into_iter().map(|t| t.piece).collect::<Vec<_>>();
+
+        // "old." is held until " He" rules the stop string out
+        let mut held = HeldTokens::default();
+        held.push(0, token("old."));
+        assert!(held.release("old.", &stop).is_empty());
+        held.push(4, token(" He"));
+        assert_eq!(pieces(held.release("old. He", &stop)), vec!["old.", " He"]);
+
+        // the tokens before the held end are released
+        held.push(7, token(" is"));
+        held.push(10, token(" old."));
+        assert_eq!(pieces(held.release("old. He is old.", &stop)), vec![" is"]);
+        held.push(15, token(" S"));
+        assert!(held.release("old. He is old. S", &stop).is_empty());
+        held.push(17, token("he"));
+        assert_eq!(pieces(held.cut(14)), vec![" old", "", ""]);
+
+        held.push(0, token("a!"));
+        assert_eq!(pieces(held.flush()), vec!["a!"]);
+        assert!(held.flush().is_empty());
+    }
+}
@@ -0,0 +1,604 @@
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use crabml::error::Result;
+use crabml::tensor::Tensor;
+
+use crate::llama2::Llama2Runner;
+use crate::llama2::TokenSampler;
+use crate::stop::find_stop;
+use crate::stop::HeldTokens;
+use crate::stop::StopCriteria;
+
+/// a flag shared with another thread (like the handler of a web request) to abort the
+/// generation, it's checked before every token.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GenerationOptions {
+    /// the max number of tokens to yield, the generation also stops when the kv cache is full.
+    pub max_tokens: usize,
+    /// the generation stops on sampling these tokens, the eos token is always included.
+    pub stop_tokens: Vec<usize>,
+    /// the generation stops once the generated text contains any of these strings, the
+    /// stop string is cut off from the text.
+    pub stop_strings: Vec<String>,
+    /// the generation stops where any of these cuts the generated text, checked after the
+    /// stop strings on every piece.
+    pub stop_criteria: Vec<Arc<dyn StopCriteria>>,
+    pub cancel: Option<CancellationToken>,
+    /// take the log probability of every generated token from the raw logits, even if the
+    /// sampler does not keep it, for rescoring the generations.
+    pub logprobs: bool,
+    /// take the `StepMetrics` of every generated token from the logits scaled by this
+    /// temperature, None skips them.
+    pub metrics_temperature: Option<f32>,
+}
+
+impl GenerationOptions {
+    pub fn new(max_tokens: usize) -> Self {
+        Self {
+            max_tokens,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_stop_token(mut self, token: usize) -> Self {
+        self.stop_tokens.push(token);
+        self
+    }
+
+    pub fn with_stop_string(mut self, s: impl Into<String>) -> Self {
+        self.stop_strings.push(s.into());
+        self
+    }
+
+    /// stop on a `StopCriteria`, like `StopRegex`, `BalancedDelimiters` or a closure of
+    /// `Fn(&str, usize) -> Option<usize>`.
+    pub fn with_stop_criteria(mut self, criteria: impl StopCriteria + 'static) -> Self {
+        self.stop_criteria.push(Arc::new(criteria));
+        self
+    }
+
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    pub fn with_logprobs(mut self, logprobs: bool) -> Self {
+        self.logprobs = logprobs;
+        self
+    }
+
+    /// surface the `StepMetrics` on every generated token, the temperature should be the one
+    /// the sampler uses, so the metrics describe the distribution the token is sampled from.
+    pub fn with_step_metrics(mut self, temperature: f32) -> Self {
+        self.metrics_temperature = Some(temperature);
+        self
+    }
+}
+
+/// how confident the model is on a step, for rendering a meter without recomputing the
+/// softmax on the client.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StepMetrics {
+    /// the entropy in nats of the distribution, 0 when one token takes all the probability.
+    pub entropy: f32,
+    /// the log probability of the most likely token.
+    pub max_logprob: f32,
+    /// how many tokens are more likely than the generated one, 0 if it's the most likely.
+    pub rank: usize,
+}
+
+impl StepMetrics {
+    /// the metrics of the token in the softmax of the logits divided by the temperature, a
+    /// temperature of 0 or below takes the raw logits, like the greedy sampling does.
+    pub fn from_logits(logits: &[f32], token: usize, temperature: f32) -> Self {
+        let inv_temp = if temperature > 0.0 {
+            1.0 / temperature
+        } else {
+            1.0
+        };
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let sum: f32 = logits.iter().map(|l| ((l - max) * inv_temp).exp()).sum();
+        let lse = max * inv_temp + sum.ln();
+
+        let mut entropy = 0.0;
+        let chosen = logits[token];
+        let mut rank = 0;
+        for &l in logits {
+            let logprob = l * inv_temp - lse;
+            let p = logprob.exp();
+            // the masked tokens of -inf take no probability
+            if p > 0.0 {
+                entropy -= p * logprob;
+            }
+            if l > chosen {
+                rank += 1;
+            }
+        }
+        Self {
+            entropy: entropy.max(0.0),
+            max_logprob: max * inv_temp - lse,
+            rank,
+        }
+    }
+}
+
+// the sampled token with its logprob and metrics
+pub(crate) type SampledToken = (usize, Option<f32>, Option<StepMetrics>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratedToken {
+    pub token: usize,
+    /// the decoded text of the token, truncated before the stop string if it completes one.
+    /// a token whose text may be the start of a stop string is held back until the next
+    /// tokens complete the stop string or rule it out, so the pieces add up to the text
+    /// without the stop string.
+    pub piece: String,
+    /// the log probability in the distribution of the raw logits, only available if the
+    /// sampler keeps it, like `SamplerChain::with_logprobs()`, or on
+    /// `GenerationOptions::with_logprobs()`.
+    pub logprob: Option<f32>,
+    /// only available on `GenerationOptions::with_step_metrics()`.
+    pub metrics: Option<StepMetrics>,
+    /// the seed of the sampler, to reproduce the sequence, None if the sampler has no rng.
+    pub seed: Option<u64>,
+    /// the time spent on producing this token, the prefill is counted in the first one.
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FinishReason {
+    /// max_tokens is reached, or the kv cache is full.
+    Length,
+    StopToken,
+    StopString,
+    /// one of the `GenerationOptions::stop_criteria` cuts the text.
+    StopCriteria,
+    Cancelled,
+    Error,
+}
+
+/// yields the generated tokens one by one, see `Llama2Runner::stream()`.
+///
+/// the stream stops between the tokens, so on any finish the kv cache holds exactly the
+/// prompt and the yielded tokens before the last one, `pos()` tells the next position to
+/// continue the conversation from.
+pub struct GenerationStream<'a, T: Tensor, S: TokenSampler<T>> {
+    runner: &'a mut Llama2Runner<T>,
+    sampler: &'a mut S,
+    options: GenerationOptions,
+    pos: usize,
+    prev_token: usize,
+    // the token sampled on the prefill, which is not yielded yet
+    pending: Option<SampledToken>,
+    started_at: Instant,
+    n_generated: usize,
+    text: String,
+    held: HeldTokens,
+    ready: VecDeque<Result<GeneratedToken>>,
+    finish_reason: Option<FinishReason>,
+}
+
+impl<'a, T: Tensor, S: TokenSampler<T>> GenerationStream<'a, T, S> {
+    pub(crate) fn new(
+        runner: &'a mut Llama2Runner<T>,
+        sampler: &'a mut S,
+        prompt: &str,
+        options: GenerationOptions,
+    ) -> Result<Self> {
+        let started_at = Instant::now();
+        let (pos, prev_token, logits) = runner.prefill_logits(prompt, &mut *sampler)?;
+        // on no max_tokens the prompt is only evaluated into the kv cache, nothing is sampled
+        let pending = match options.max_tokens {
+            0 => None,
+            _ => Some(runner.sample_step(&logits, &mut *sampler, &options)?),
+        };
+        Ok(Self {
+            runner,
+            sampler,
+            options,
+            pos,
+            prev_token,
+            pending,
+            started_at,
+            n_generated: 0,
+            text: String::new(),
+            held: HeldTokens::default(),
+            ready: VecDeque::new(),
+            finish_reason: None,
+        })
+    }
+
+    /// the next position to feed into the kv cache.
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    /// why the stream stopped, it's None while the stream is still running.
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.finish_reason
+    }
+
+    /// all the text generated so far, without the stop string. it may run ahead of the
+    /// pieces yielded, by the text held back as the start of a stop string.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// the sampler of the stream, to change its settings like the temperature or the logit
+    /// biases between the tokens. the kv cache is kept, the change takes effect from the
+    /// next sampled token.
+    ///
+    /// the token after the prompt is sampled on creating the stream, so a change before the
+    /// first `next()` takes effect from the second token.
+    pub fn sampler_mut(&mut self) -> &mut S {
+        self.sampler
+    }
+
+    /// replace the stop strings, they stop on the text generated from now on, the text
+    /// yielded before is not searched again.
+    pub fn set_stop_strings(&mut self, stop_strings: Vec<String>) {
+        // only the new pieces are checked, a stop string may start in the text before and
+        // end in the coming pieces
+        self.options.stop_strings = stop_strings;
+    }
+
+    /// replace the stop criteria, they're checked on the pieces generated from now on.
+    pub fn set_stop_criteria(&mut self, stop_criteria: Vec<Arc<dyn StopCriteria>>) {
+        self.options.stop_criteria = stop_criteria;
+    }
+
+    /// replace the stop tokens, the eos token is always included.
+    pub fn set_stop_tokens(&mut self, stop_tokens: Vec<usize>) {
+        self.options.stop_tokens = stop_tokens;
+    }
+
+    // the tokens held back are released on any finish
+    fn finish(&mut self, reason: FinishReason) {
+        self.finish_reason = Some(reason);
+        self.ready.extend(self.held.flush().into_iter().map(Ok));
+    }
+
+    fn next_token(&mut self) -> Result<SampledToken> {
+        if let Some(pending) = self.pending.take() {
+            return Ok(pending);
+        }
+        let logits = self.runner.forward_logits(&[self.prev_token], self.pos)?;
+        self.pos += 1;
+        self.runner
+            .sample_step(&logits, self.sampler, &self.options)
+    }
+
+    // generate a token, the tokens which can't be held back anymore are moved into ready
+    fn step(&mut self) {
+        if self
+            .options
+            .cancel
+            .as_ref()
+            .is_some_and(|c| c.is_cancelled())
+        {
+            return self.finish(FinishReason::Cancelled);
+        }
+        if self.n_generated >= self.options.max_tokens
+            || (self.pending.is_none() && self.pos >= self.runner.seq_len())
+        {
+            return self.finish(FinishReason::Length);
+        }
+
+        let (token, logprob, metrics) = match self.next_token() {
+            Ok(next) => next,
+            Err(err) => {
+                self.finish(FinishReason::Error);
+                return self.ready.push_back(Err(err));
+            }
+        };
+        if token == self.runner.tokenizer().eos_token() || self.options.stop_tokens.contains(&token)
+        {
+            return self.finish(FinishReason::StopToken);
+        }
+
+        let piece = match self.runner.tokenizer().decode(self.prev_token, token) {
+            Ok(piece) => piece,
+            Err(err) => {
+                self.finish(FinishReason::Error);
+                return self.ready.push_back(Err(err));
+            }
+        };
+        self.prev_token = token;
+        self.n_generated += 1;
+
+        let now = Instant::now();
+        let elapsed = now - self.started_at;
+        self.started_at = now;
+        let offset = self.text.len();
+        self.text.push_str(&piece);
+        self.held.push(offset, GeneratedToken {
+            token,
+            piece,
+            logprob,
+            metrics,
+            seed: self.sampler.seed(),
+            elapsed,
+        });
+
+        // a stop string spanning several tokens is cut off the held tokens as a whole
+        if let Some((stop_at, reason)) = find_stop(&self.options, &self.text, offset) {
+            self.text.truncate(stop_at);
+            self.ready
+                .extend(self.held.cut(stop_at).into_iter().map(Ok));
+            self.finish_reason = Some(reason);
+        } else {
+            let released = self.held.release(&self.text, &self.options.stop_strings);
+            self.ready.extend(released.into_iter().map(Ok));
+        }
+    }
+}
+
+impl<'a, T: Tensor, S: TokenSampler<T>> Iterator for GenerationStream<'a, T, S> {
+    type Item = Result<GeneratedToken>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(token) = self.ready.pop_front() {
+                return Some(token);
+            }
+            if self.finish_reason.is_some() {
+                return None;
+            }
+            self.step();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::backends::cpu::CpuTensorDevice;
+    use crabml::gguf::GGMLType;
+    use crabml::gguf::GGUFFileLoader;
+    use crabml::sampler::Grammar;
+    use crabml::sampler::SamplerChain;
+    use crabml::tensor::TensorMetrics;
+
+    use super::*;
+    use crate::stop::StopRegex;
+    use crate::CpuLlama2Model;
+
+    #[test]
+    fn test_generation_stream() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+
+        // the same text as prefill_and_generate, with the logprobs
+        let mut sampler = SamplerChain::new().with_logprobs(true).with_seed(42);
+        let mut stream = runner.stream(
+            "Lily is a cute cat, ",
+            &mut sampler,
+            GenerationOptions::new(11),
+        )?;
+        let tokens = stream.by_ref().collect::<Result<Vec<_>>>()?;
+        assert_eq!(stream.finish_reason(), Some(FinishReason::Length));
+        assert_eq!(stream.text(), "3 years old. She likes to play with her");
+        assert_eq!(tokens.len(), 11);
+        assert!(tokens.iter().all(|t| t.logprob.unwrap() <= 0.0));
+        assert!(tokens.iter().all(|t| t.seed == Some(42)));
+        // the last yielded token is not fed into the kv cache yet
+        let pos = stream.pos();
+        drop(stream);
+        assert_eq!(runner.kv_cache_len(), pos);
+
+        // the same logprobs from the raw logits when the sampler does not keep them, the
+        // greedy tokens are the most likely ones in the metrics
+        runner.reset_kv_cache()?;
+        let mut sampler = SamplerChain::new();
+        let options = GenerationOptions::new(11)
+            .with_logprobs(true)
+            .with_step_metrics(1.0);
+        let stream = runner.stream("Lily is a cute cat, ", &mut sampler, options)?;
+        let tokens2 = stream.collect::<Result<Vec<_>>>()?;
+        for (a, b) in tokens2.iter().zip(tokens.iter()) {
+            assert!((a.logprob.unwrap() - b.logprob.unwrap()).abs() < 1e-4);
+            let metrics = a.metrics.unwrap();
+            assert_eq!(metrics.rank, 0);
+            assert!((metrics.max_logprob - a.logprob.unwrap()).abs() < 1e-4);
+            assert!(metrics.entropy >= 0.0);
+        }
+        assert!(tokens.iter().all(|t| t.metrics.is_none()));
+
+        // the stop string is cut off
+        runner.reset_kv_cache()?;
+        let mut sampler = SamplerChain::new();
+        let options = GenerationOptions::new(30).with_stop_string(". She");
+        let mut stream = runner.stream("Lily is a cute cat, ", &mut sampler, options)?;
+        let pieces = stream
+            .by_ref()
+            .map(|t| t.map(|t| t.piece))
+            .collect::<Result<Vec<_>>>()?;
+        // "." is held back until " She" completes the stop string
+        assert_eq!(pieces.concat(), stream.text());
+        assert_eq!(stream.text(), "3 years old");
+        assert_eq!(stream.finish_reason(), Some(FinishReason::StopString));
+        drop(stream);
+
+        // the held back start of a stop string is released once it's ruled out, or when the
+        // stream finishes
+        for (max_tokens, text) in [(5, "3 years old. She"), (3, "3 years old")] {
+            runner.reset_kv_cache()?;
+            let options = GenerationOptions::new(max_tokens).with_stop_string("old. He");
+            let mut stream = runner.stream("Lily is a cute cat, ", &mut sampler, options)?;
+            let pieces = stream
+                .by_ref()
+                .map(|t| t.map(|t| t.piece))
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(pieces.len(), max_tokens);
+            assert_eq!(pieces.concat(), text);
+            assert_eq!(stream.text(), text);
+            assert_eq!(stream.finish_reason(), Some(FinishReason::Length));
+            drop(stream);
+        }
+
+        // cancelled from the consumer in the middle
+        runner.reset_kv_cache()?;
+        let cancel = CancellationToken::new();
+        let options = GenerationOptions::new(30).with_cancellation(cancel.clone());
+        let mut stream = runner.stream("Lily is a cute cat, ", &mut sampler, options)?;
+        let mut n_tokens = 0;
+        for token in stream.by_ref() {
+            token?;
+            n_tokens += 1;
+            if n_tokens == 3 {
+                cancel.cancel();
+            }
+        }
+        assert_eq!(n_tokens, 3);
+        assert_eq!(stream.finish_reason(), Some(FinishReason::Cancelled));
+        let pos = stream.pos();
+        drop(stream);
+        assert_eq!(runner.kv_cache_len(), pos);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generation_stream_live_updates() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let prompt = "Lily is a cute cat, ";
+
+        let mut sampler = SamplerChain::new();
+        let stream = runner.stream(prompt, &mut sampler, GenerationOptions::new(6))?;
+        let greedy = stream.collect::<Result<Vec<_>>>()?;
+
+        // ban the 6th greedy token after the 5th one, the kv cache is kept
+        runner.reset_kv_cache()?;
+        let mut sampler = SamplerChain::new();
+        let mut stream = runner.stream(prompt, &mut sampler, GenerationOptions::new(30))?;
+        let first = stream.by_ref().take(5).collect::<Result<Vec<_>>>()?;
+        let tokens = |ts: &[GeneratedToken]| ts.iter().map(|t| t.token).collect::<Vec<_>>();
+        assert_eq!(tokens(&first), tokens(&greedy[..5]));
+        stream
+            .sampler_mut()
+            .set_logit_bias(greedy[5].token, f32::NEG_INFINITY);
+        let next = stream.next().unwrap()?;
+        assert_ne!(next.token, greedy[5].token);
+        stream.sampler_mut().clear_logit_biases();
+
+        // the stop string set in the middle does not stop on the text before
+        assert!(stream.text().starts_with("3 years"));
+        stream.set_stop_strings(vec!["3 years".to_string()]);
+        assert!(stream.next().unwrap().is_ok());
+        assert_eq!(stream.finish_reason(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generation_stream_stop_criteria() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+        let prompt = "Lily is a cute cat, ";
+
+        // the regex match is cut off
+        let mut sampler = SamplerChain::new();
+        let options = GenerationOptions::new(30).with_stop_criteria(StopRegex::new(r"years? old")?);
+        let mut stream = runner.stream(prompt, &mut sampler, options)?;
+        stream.by_ref().collect::<Result<Vec<_>>>()?;
+        assert_eq!(stream.text(), "3 ");
+        assert_eq!(stream.finish_reason(), Some(FinishReason::StopCriteria));
+        drop(stream);
+
+        // a closure keeping the first sentence, the earlier cut wins over the stop string
+        runner.reset_kv_cache()?;
+        let options = GenerationOptions::new(30)
+            .with_stop_string("her")
+            .with_stop_criteria(|text: &str, _: usize| text.find('.').map(|i| i + 1));
+        let mut stream = runner.stream(prompt, &mut sampler, options)?;
+        stream.by_ref().collect::<Result<Vec<_>>>()?;
+        assert_eq!(stream.text(), "3 years old.");
+        assert_eq!(stream.finish_reason(), Some(FinishReason::StopCriteria));
+        drop(stream);
+
+        // set in the middle, it's checked from the next piece on
+        runner.reset_kv_cache()?;
+        let mut stream = runner.stream(prompt, &mut sampler, GenerationOptions::new(30))?;
+        stream.by_ref().take(3).collect::<Result<Vec<_>>>()?;
+        assert_eq!(stream.text(), "3 years old");
+        let criteria: Arc<dyn StopCriteria> = Arc::new(StopRegex::new("[A-Za-z]+ ")?);
+        stream.set_stop_criteria(vec![criteria]);
+        stream.by_ref().collect::<Result<Vec<_>>>()?;
+        // "years " ends before the criteria is set
+        assert_eq!(stream.text(), "3 years old. ");
+        assert_eq!(stream.finish_reason(), Some(FinishReason::StopCriteria));
+        Ok(())
+    }
+
+    #[test]
+    fn test_step_metrics() {
+        // uniform over 4 tokens
+        let metrics = StepMetrics::from_logits(&[1.0, 1.0, 1.0, 1.0], 2, 1.0);
+        assert!((metrics.entropy - 4.0f32.ln()).abs() < 1e-5);
+        assert!((metrics.max_logprob - 0.25f32.ln()).abs() < 1e-5);
+        assert_eq!(metrics.rank, 0);
+
+        // the masked tokens take no probability, a lower temperature sharpens it
+        let logits = [1.0, 3.0, f32::NEG_INFINITY, 2.0];
+        let metrics = StepMetrics::from_logits(&logits, 0, 1.0);
+        assert_eq!(metrics.rank, 2);
+        assert!(metrics.entropy.is_finite());
+        let sharp = StepMetrics::from_logits(&logits, 0, 0.5);
+        assert!(sharp.entropy < metrics.entropy);
+        assert!(sharp.max_logprob > metrics.max_logprob);
+        let expected =
+            (6.0f32.exp() / [2.0f32, 6.0, 4.0].iter().map(|l| l.exp()).sum::<f32>()).ln();
+        assert!((sharp.max_logprob - expected).abs() < 1e-5);
+        assert_eq!(StepMetrics::from_logits(&logits, 0, 0.0), metrics);
+    }
+
+    #[test]
+    fn test_generation_stream_with_grammar() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-q8_0.gguf")?;
+        let gf = gl.open()?;
+        let lm = CpuLlama2Model::load(&gf, CpuTensorDevice::new())?;
+        let mut runner = Llama2Runner::new(&lm, TensorMetrics::default(), 200, GGMLType::F32)?;
+
+        // the eos is forced once the grammar is finished
+        let grammar = Grammar::new(r#"root ::= "She is " [0-9]+ " years old.""#, &lm.tokenizer)?;
+        let mut sampler = SamplerChain::new().with_processor(grammar);
+        let mut stream = runner.stream(
+            "Lily is a cute cat. ",
+            &mut sampler,
+            GenerationOptions::new(30),
+        )?;
+        stream.by_ref().collect::<Result<Vec<_>>>()?;
+        assert_eq!(stream.finish_reason(), Some(FinishReason::StopToken));
+        let text = stream.text().to_string();
+        assert!(text.starts_with("She is ") && text.ends_with(" years old."));
+        let age = &text["She is ".len()..text.len() - " years old.".len()];
+        assert!(!age.is_empty() && age.chars().all(|c| c.is_ascii_digit()));
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+//! the helpers of the tests which write the GGUF and the adapter files to load them.
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::gguf::GGUFFile;
+use crabml::gguf::GGUFFileLoader;
+use crabml::gguf::GGUFMetadataValue;
+use crabml::gguf::GGUFTensorInfo;
+use crabml::gguf::GGUFWriter;
+
+/// a path in the temp dir unique to the process and the call, so the tests running at the
+/// same time never share a file. the file is removed on drop, also when the test fails.
+pub(crate) struct TempPath(PathBuf);
+
+impl TempPath {
+    pub fn new(name: &str) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let file = format!("crabml-test-{}-{}-{}", std::process::id(), n, name);
+        Self(std::env::temp_dir().join(file))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn to_str(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// a GGUF file written into a temp path, it's removed after the loader is dropped.
+pub(crate) struct TempGGUF {
+    loader: GGUFFileLoader,
+    _path: TempPath,
+}
+
+impl TempGGUF {
+    /// write the bytes of a GGUF file into a temp path named after name.
+    pub fn new(name: &str, bytes: Vec<u8>) -> Result<Self> {
+        let path = TempPath::new(name);
+        std::fs::write(path.path(), bytes).unwrap();
+        Ok(Self {
+            loader: GGUFFileLoader::new(path.to_str())?,
+            _path: path,
+        })
+    }
+
+    pub fn open(&self) -> Result<GGUFFile<'_>> {
+        self.loader.open()
+    }
+}
+
+/// builds a GGUF file from scratch, or from the metadata and the tensors of another one with
+/// some of them replaced, and loads it from a temp path.
+pub(crate) struct GGUFBuilder<'a> {
+    writer: GGUFWriter<'a, Vec<u8>>,
+    data: Vec<Vec<u8>>,
+}
+
+impl<'a> GGUFBuilder<'a> {
+    pub fn new(architecture: &'a str) -> Self {
+        Self {
+            writer: GGUFWriter::new(vec![], architecture),
+            data: vec![],
+        }
+    }
+
+    /// with all the metadata of gf, the tensors are not copied.
+    pub fn with_metadata_of(gf: &'a GGUFFile<'a>) -> Self {
+        let mut builder = Self::new(gf.architecture());
+        for (key, value) in gf.metadata().as_hashmap() {
+            builder.metadata(key, value.clone());
+        }
+        builder
+    }
+
+    /// set a metadata value, the existing value of the key is replaced.
+    pub fn metadata(&mut self, key: &str, value: GGUFMetadataValue<'a>) {
+        self.writer.add_metadata(key, value);
+    }
+
+    /// the dimensions are in the ggml order, the first one is the contiguous row.
+    pub fn tensor(
+        &mut self,
+        name: &str,
+        dims: &[usize],
+        typ: GGMLType,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        self.writer.add_tensor_info(name, dims, typ)?;
+        self.data.push(data);
+        Ok(())
+    }
+
+    /// copy the tensor of another file as it is.
+    pub fn copy_tensor(&mut self, info: &GGUFTensorInfo) -> Result<()> {
+        self.tensor(
+            info.name(),
+            info.dimensions(),
+            info.typ(),
+            info.data().to_vec(),
+        )
+    }
+
+    /// write the file into a temp path named after name, and open a loader on it.
+    pub fn load(mut self, name: &str) -> Result<TempGGUF> {
+        self.writer.write_header()?;
+        for data in self.data.iter() {
+            self.writer.write_tensor_data(data)?;
+        }
+        TempGGUF::new(name, self.writer.finish()?)
+    }
+}
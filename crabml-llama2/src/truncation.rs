@@ -0,0 +1,133 @@
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+
+/// what to do when the prompt does not fit into the kv cache.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum TruncationPolicy {
+    /// fail before the forward pass.
+    #[default]
+    Error,
+    /// drop the oldest tokens after the kept prefix, like a sliding chat history.
+    Left,
+    /// drop the tokens in the middle, keeping the head and the tail of the prompt, like a
+    /// long document whose beginning and question at the end both matter.
+    Middle,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TruncationOptions {
+    pub policy: TruncationPolicy,
+    /// the number of tokens after the BOS which are never dropped, like a system prompt.
+    pub keep: usize,
+    /// the number of positions left free for the generation after the prompt.
+    pub reserve: usize,
+}
+
+impl TruncationOptions {
+    pub fn new(policy: TruncationPolicy) -> Self {
+        Self {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    pub fn with_reserve(mut self, reserve: usize) -> Self {
+        self.reserve = reserve;
+        self
+    }
+}
+
+/// fit the prompt tokens into seq_len - reserve positions by the policy. the BOS token at
+/// the start and the `keep` tokens after it always survive.
+pub fn truncate_tokens(
+    tokens: &[usize],
+    seq_len: usize,
+    bos_token: usize,
+    options: &TruncationOptions,
+) -> Result<Vec<usize>> {
+    let budget = seq_len.saturating_sub(options.reserve);
+    if tokens.len() <= budget {
+        return Ok(tokens.to_vec());
+    }
+
+    let n_bos = usize::from(tokens.first() == Some(&bos_token));
+    let n_prefix = (n_bos + options.keep).min(tokens.len());
+    if options.policy == TruncationPolicy::Error || n_prefix >= budget {
+        return Err((
+            ErrorKind::BadInput,
+            format!(
+                "the prompt of {} tokens does not fit into the context of {} tokens, with {} \
+                 reserved for the generation and {} kept at the start",
+                tokens.len(),
+                seq_len,
+                options.reserve,
+                n_prefix
+            ),
+        )
+            .into());
+    }
+
+    let (prefix, rest) = tokens.split_at(n_prefix);
+    let n_rest = budget - n_prefix;
+    let mut out = Vec::with_capacity(budget);
+    out.extend_from_slice(prefix);
+    match options.policy {
+        TruncationPolicy::Left => {
+            out.extend_from_slice(&rest[rest.len() - n_rest..]);
+        }
+        TruncationPolicy::Middle => {
+            let n_head = n_rest / 2;
+            let n_tail = n_rest - n_head;
+            out.extend_from_slice(&rest[..n_head]);
+            out.extend_from_slice(&rest[rest.len() - n_tail..]);
+        }
+        TruncationPolicy::Error => unreachable!(),
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_tokens() -> Result<()> {
+        let tokens = vec![1, 10, 11, 12, 13, 14, 15, 16];
+
+        // fits
+        let options = TruncationOptions::new(TruncationPolicy::Error);
+        assert_eq!(truncate_tokens(&tokens, 8, 1, &options)?, tokens);
+        assert!(truncate_tokens(&tokens, 7, 1, &options).is_err());
+
+        let options = TruncationOptions::new(TruncationPolicy::Left).with_keep(1);
+        assert_eq!(truncate_tokens(&tokens, 5, 1, &options)?, vec![
+            1, 10, 14, 15, 16
+        ]);
+        let options = options.with_reserve(2);
+        assert_eq!(truncate_tokens(&tokens, 5, 1, &options)?, vec![1, 10, 16]);
+
+        // without a BOS, only the keep tokens are kept
+        let options = TruncationOptions::new(TruncationPolicy::Left);
+        assert_eq!(truncate_tokens(&tokens[1..], 3, 1, &options)?, vec![
+            14, 15, 16
+        ]);
+
+        let options = TruncationOptions::new(TruncationPolicy::Middle);
+        assert_eq!(truncate_tokens(&tokens, 6, 1, &options)?, vec![
+            1, 10, 11, 14, 15, 16
+        ]);
+        assert_eq!(truncate_tokens(&tokens, 4, 1, &options)?, vec![
+            1, 10, 15, 16
+        ]);
+
+        // no room after the prefix
+        let options = TruncationOptions::new(TruncationPolicy::Left).with_keep(3);
+        assert!(truncate_tokens(&tokens, 4, 1, &options).is_err());
+        Ok(())
+    }
+}